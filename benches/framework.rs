@@ -0,0 +1,146 @@
+//! Benchmarks of the parts of the framework every chain leans on: sealing blocks by proof of
+//! work, verifying chains, applying batches of transitions, and computing Merkle roots.
+//!
+//! Run them all with `cargo bench`, or some of them by naming their group, as in
+//! `cargo bench -- seal`. Criterion compares each run with the one before, so a regression shows
+//! up as a change in the report.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use diy_blockchain::c1_state_machine::p4_accounted_currency::{
+    AccountedCurrency, AccountingTransaction,
+};
+use diy_blockchain::c1_state_machine::p7_multiasset::{
+    AssetTransaction, MultiAsset, MultiAssetState, NATIVE_ASSET,
+};
+use diy_blockchain::c1_state_machine::parallel::try_apply_all_parallel;
+use diy_blockchain::c1_state_machine::{BlockContext, StateMachine, User};
+use diy_blockchain::merkle::merkle_root;
+use diy_blockchain::{Block, Consensus, Header, PoW};
+use std::collections::HashMap;
+
+const USERS: [User; 5] = [User::Alice, User::Bob, User::Charlie, User::Dave, User::Eve];
+
+type TransferBlock = Block<PoW, MultiAsset>;
+
+/// Sealing takes about as many attempts as the difficulty, whatever the header.
+fn seal(c: &mut Criterion) {
+    let mut group = c.benchmark_group("seal");
+    for difficulty in [16u64, 256, 4096] {
+        let pow = PoW::new(u64::MAX / difficulty);
+        // A new parent every time, so that the average over many headers is measured.
+        let mut parent = 0;
+        group.bench_with_input(BenchmarkId::from_parameter(difficulty), &pow, |b, pow| {
+            b.iter(|| {
+                parent += 1;
+                let partial = Header {
+                    parent,
+                    height: 1,
+                    state_root: 0,
+                    extrinsics_root: 0,
+                    consensus_digest: (),
+                };
+                pow.seal(&0, partial)
+                    .expect("some nonce meets the threshold")
+            })
+        });
+    }
+    group.finish();
+}
+
+/// A chain of the given length on top of the returned genesis block and state, in which every
+/// block moves one unit of the native asset along a ring of users.
+fn transfer_chain(len: usize) -> (TransferBlock, MultiAssetState, Vec<TransferBlock>) {
+    let setup = [
+        AssetTransaction::CreateAsset {
+            creator: User::Alice,
+            asset: NATIVE_ASSET,
+        },
+        AssetTransaction::Mint {
+            issuer: User::Alice,
+            asset: NATIVE_ASSET,
+            amount: 1_000_000,
+        },
+    ];
+    let genesis_state = MultiAsset::try_apply_all(&MultiAssetState::default(), &setup)
+        .expect("Alice creates and mints the native asset");
+    let genesis = TransferBlock::genesis(&genesis_state);
+
+    let mut chain: Vec<TransferBlock> = Vec::with_capacity(len);
+    let mut state = genesis_state.clone();
+    for height in 1..=len {
+        let body = vec![AssetTransaction::Transfer {
+            asset: NATIVE_ASSET,
+            sender: USERS[(height - 1) % USERS.len()],
+            receiver: USERS[height % USERS.len()],
+            amount: 1,
+        }];
+        let context = BlockContext {
+            height: height as u64,
+            ..BlockContext::default()
+        };
+        let parent = chain.last().unwrap_or(&genesis);
+        let block = parent
+            .child(&state, body.clone(), context)
+            .expect("every sender holds a unit");
+        state = MultiAsset::try_apply_all(&state, &body).expect("the block was just built");
+        chain.push(block);
+    }
+    (genesis, genesis_state, chain)
+}
+
+fn verify_sub_chain(c: &mut Criterion) {
+    let mut group = c.benchmark_group("verify_sub_chain");
+    group.sample_size(10);
+    for len in [1_000, 10_000] {
+        let (genesis, genesis_state, chain) = transfer_chain(len);
+        assert!(genesis.verify_sub_chain(&genesis_state, &chain));
+        group.throughput(Throughput::Elements(len as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(len), &chain, |b, chain| {
+            b.iter(|| genesis.verify_sub_chain(&genesis_state, chain))
+        });
+    }
+    group.finish();
+}
+
+/// Applying a batch of transfers between the users, one at a time and in waves of independent
+/// transfers.
+fn apply_batch(c: &mut Criterion) {
+    let balances: HashMap<User, u64> = USERS.iter().map(|u| (*u, 1_000_000)).collect();
+    let transfers = |n: usize| -> Vec<AccountingTransaction> {
+        (0..n)
+            .map(|i| AccountingTransaction::Transfer {
+                sender: USERS[i % USERS.len()],
+                receiver: USERS[(i + 2) % USERS.len()],
+                amount: 1,
+            })
+            .collect()
+    };
+
+    let mut group = c.benchmark_group("apply_batch");
+    for n in [100, 1_000] {
+        let batch = transfers(n);
+        group.throughput(Throughput::Elements(n as u64));
+        group.bench_with_input(BenchmarkId::new("sequential", n), &batch, |b, batch| {
+            b.iter(|| AccountedCurrency::try_apply_all(&balances, batch).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("parallel", n), &batch, |b, batch| {
+            b.iter(|| try_apply_all_parallel::<AccountedCurrency>(&balances, batch).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn merkle(c: &mut Criterion) {
+    let mut group = c.benchmark_group("merkle");
+    for n in [1_000u64, 10_000, 100_000] {
+        let leaves: Vec<u64> = (0..n).collect();
+        group.throughput(Throughput::Elements(n));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &leaves, |b, leaves| {
+            b.iter(|| merkle_root(leaves))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, seal, verify_sub_chain, apply_batch, merkle);
+criterion_main!(benches);
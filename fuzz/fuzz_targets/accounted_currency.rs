@@ -0,0 +1,58 @@
+//! Drive the accounted currency with arbitrary sequences of mints, burns, and transfers.
+//!
+//! Run with `cargo fuzz run accounted_currency` from the repository root.
+
+#![no_main]
+
+use diy_blockchain::c1_state_machine::{
+    p4_accounted_currency::{AccountedCurrency, AccountingTransaction},
+    StateMachine, User,
+};
+use libfuzzer_sys::{
+    arbitrary::{Result, Unstructured},
+    fuzz_target,
+};
+
+fn user(u: &mut Unstructured) -> Result<User> {
+    Ok(match u.int_in_range(0..=2u8)? {
+        0 => User::Alice,
+        1 => User::Bob,
+        _ => User::Charlie,
+    })
+}
+
+fn transaction(u: &mut Unstructured) -> Result<AccountingTransaction> {
+    Ok(match u.int_in_range(0..=2u8)? {
+        0 => AccountingTransaction::Mint {
+            minter: user(u)?,
+            amount: u.arbitrary()?,
+        },
+        1 => AccountingTransaction::Burn {
+            burner: user(u)?,
+            amount: u.arbitrary()?,
+        },
+        _ => AccountingTransaction::Transfer {
+            sender: user(u)?,
+            receiver: user(u)?,
+            amount: u.arbitrary()?,
+        },
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut u = Unstructured::new(data);
+    let mut state = AccountedCurrency::genesis_state(vec![]);
+
+    while !u.is_empty() {
+        let Ok(t) = transaction(&mut u) else {
+            break;
+        };
+        let next = AccountedCurrency::next_state(&state, &t);
+        if let Ok(accepted) = AccountedCurrency::try_next_state(&state, &t) {
+            assert_eq!(accepted, next);
+        }
+        // The existential deposit means empty accounts never linger in storage.
+        assert!(next.values().all(|balance| *balance > 0));
+        state = next;
+    }
+});
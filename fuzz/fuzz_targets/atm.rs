@@ -0,0 +1,94 @@
+//! Drive the ATM with arbitrary sequences of card swipes, key presses, deposits and unlocks.
+//!
+//! Run with `cargo fuzz run atm` from the repository root.
+
+#![no_main]
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+use diy_blockchain::c1_state_machine::{
+    p3_atm::{Action, Atm, AtmEvent, Key},
+    EventfulStateMachine, StateMachine,
+};
+use libfuzzer_sys::{
+    arbitrary::{Result, Unstructured},
+    fuzz_target,
+};
+
+fn digit(u: &mut Unstructured) -> Result<Key> {
+    Ok(Key::DIGITS[u.int_in_range(0..=9usize)?].clone())
+}
+
+/// Hash a pin the same way the ATM does, so that the fuzzer can get past authentication and
+/// reach the withdrawal arithmetic.
+fn pin_hash(pin: &Vec<Key>) -> u64 {
+    let mut s = DefaultHasher::new();
+    pin.hash(&mut s);
+    s.finish()
+}
+
+/// A handful of accounts, so that sessions keep landing on the same few balances.
+fn account(u: &mut Unstructured) -> Result<u64> {
+    u.int_in_range(0..=3u64)
+}
+
+fn action(u: &mut Unstructured) -> Result<Action> {
+    Ok(match u.int_in_range(0..=9u8)? {
+        0 => Action::SwipeCard {
+            account: account(u)?,
+            pin_hash: u.arbitrary()?,
+        },
+        1 => {
+            let len = u.int_in_range(0..=4usize)?;
+            let pin = (0..len).map(|_| digit(u)).collect::<Result<Vec<_>>>()?;
+            Action::SwipeCard {
+                account: account(u)?,
+                pin_hash: pin_hash(&pin),
+            }
+        }
+        2 => Action::PressKey(Key::Enter),
+        3 => Action::Deposit(u.arbitrary()?),
+        4 => Action::PressKey(Key::Cancel),
+        5 => Action::AdminUnlock(account(u)?),
+        _ => Action::PressKey(digit(u)?),
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut u = Unstructured::new(data);
+    let Ok(cash) = u.arbitrary::<u64>() else {
+        return;
+    };
+    let Ok(balances) = (0..4u64)
+        .map(|a| Ok((a, u.arbitrary::<u64>()?)))
+        .collect::<Result<Vec<_>>>()
+    else {
+        return;
+    };
+    let mut state = Atm::genesis_state((cash, balances));
+    let mut remaining = cash;
+
+    while !u.is_empty() {
+        let Ok(t) = action(&mut u) else {
+            break;
+        };
+        let (next, events) = Atm::next_state_with_events(&state, &t);
+        assert_eq!(next, Atm::next_state(&state, &t));
+        if let Ok(accepted) = Atm::try_next_state(&state, &t) {
+            assert_eq!(accepted, next);
+        }
+        for event in events {
+            match event {
+                AtmEvent::CashWithdrawn { amount } => {
+                    remaining = remaining.checked_sub(amount).expect("the ATM paid out more than it held");
+                }
+                AtmEvent::CashDeposited { amount } => {
+                    remaining = remaining.checked_add(amount).expect("the ATM accepted an overflowing deposit");
+                }
+                _ => {}
+            }
+        }
+        state = next;
+    }
+});
@@ -0,0 +1,46 @@
+//! Play arbitrary sequences of moves and resets on the tic-tac-toe board.
+//!
+//! Run with `cargo fuzz run tic_tac_toe` from the repository root.
+
+#![no_main]
+
+use diy_blockchain::c1_state_machine::{
+    p6_open_ended::{TTTSymbol, TicTacToeSystem, Transition},
+    StateMachine,
+};
+use libfuzzer_sys::{
+    arbitrary::{Result, Unstructured},
+    fuzz_target,
+};
+
+fn transition(u: &mut Unstructured) -> Result<Transition> {
+    if u.ratio(1, 16)? {
+        return Ok(Transition::Reset);
+    }
+    let symbol = match u.int_in_range(0..=2u8)? {
+        0 => TTTSymbol::X,
+        1 => TTTSymbol::O,
+        _ => TTTSymbol::Blank,
+    };
+    // Mostly stay on the board, but sometimes stray off it.
+    let row = u.int_in_range(0..=3usize)?;
+    let col = u.int_in_range(0..=3usize)?;
+    Ok(Transition::MarkCell { symbol, row, col })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut u = Unstructured::new(data);
+    let mut state = TicTacToeSystem::genesis_state(());
+
+    while !u.is_empty() {
+        let Ok(t) = transition(&mut u) else {
+            break;
+        };
+        let next = TicTacToeSystem::next_state(&state, &t);
+        if state.is_complete() {
+            assert!(matches!(t, Transition::Reset) || next == state);
+        }
+        assert!(next.history().len() <= 9);
+        state = next;
+    }
+});
@@ -0,0 +1,30 @@
+//! Runs a node, or talks to one. See the `node` module for what each command does.
+
+use diy_blockchain::node::{Command, USAGE};
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    // With RUST_LOG=debug, every span is logged with how long it took when it closes.
+    #[cfg(feature = "tracing")]
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+        .with_writer(std::io::stderr)
+        .init();
+    let command = match Command::parse(std::env::args().skip(1)) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{e:?}\n\n{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    // The node runs until it is killed.
+    let forever = Default::default();
+    match command.execute(&forever, std::io::stdout()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e:?}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,411 @@
+//! Many real-world chains are not fully permissionless. A stablecoin issuer may be the only one
+//! allowed to mint, or a regulator may be allowed to burn. Here we wrap any state machine with an
+//! access control list that records which roles each user holds, and reject transitions whose
+//! origin lacks the role they require.
+//!
+//! The inner machine decides which of its transitions require which role by implementing
+//! `RequiresRole`, along with whose account each of them acts on. A transition acting on an
+//! account must come from that account, whatever roles its origin holds. Admins manage the list
+//! itself by granting and revoking roles.
+//!
+//! The origin is whoever the transition says it is, so on its own the list protects nothing.
+//! Wrapped in `WithNonces`, a transition must be signed by its origin.
+
+use std::collections::{HashMap, HashSet};
+use std::marker::PhantomData;
+
+use super::with_nonces::Owned;
+use super::{BlockContext, ContextualStateMachine, StateMachine, User, Weighted};
+
+/// The permissions a user may hold
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Role {
+    /// May grant and revoke roles
+    Admin,
+    /// May create new money
+    Mint,
+    /// May destroy money
+    Burn,
+}
+
+/// A state machine some of whose transitions may only be made by users holding a certain role.
+pub trait RequiresRole: StateMachine {
+    /// The role the origin of the given transition must hold, if any.
+    fn required_role(t: &Self::Transition) -> Option<Role>;
+
+    /// The account the given transition acts on, which must be its origin, if any. The provided
+    /// method lets anyone holding the required role act on any account.
+    fn owner(_t: &Self::Transition) -> Option<User> {
+        None
+    }
+}
+
+/// A higher-order state machine enforcing per-origin permissions on an inner state machine.
+pub struct Acl<Inner>(PhantomData<Inner>);
+
+/// The state of the inner machine along with the roles each user holds.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AclState<S> {
+    pub inner: S,
+    /// Users holding no roles are not stored.
+    pub roles: HashMap<User, HashSet<Role>>,
+}
+
+impl<S> AclState<S> {
+    /// Whether the given user holds the given role.
+    pub fn has_role(&self, who: &User, role: Role) -> bool {
+        self.roles.get(who).is_some_and(|roles| roles.contains(&role))
+    }
+}
+
+/// Something a user can do to a machine guarded by an access control list
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AclTransition<T> {
+    /// Make a transition on the inner machine
+    Call { origin: User, call: T },
+    /// Give a role to a user. Only admins may do this.
+    Grant { origin: User, who: User, role: Role },
+    /// Take a role away from a user. Only admins may do this.
+    Revoke { origin: User, who: User, role: Role },
+}
+
+/// Every access controlled transition acts for its origin.
+impl<T> Owned for AclTransition<T> {
+    fn owner(&self) -> Option<User> {
+        match self {
+            AclTransition::Call { origin, .. }
+            | AclTransition::Grant { origin, .. }
+            | AclTransition::Revoke { origin, .. } => Some(*origin),
+        }
+    }
+}
+
+/// The reasons an access controlled transition may be rejected
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AclError<E> {
+    /// The origin does not hold the role this transition requires
+    MissingRole { origin: User, role: Role },
+    /// The call acts on another account than its origin's
+    NotOwner { origin: User, owner: User },
+    /// The origin was allowed to make the call, but the inner machine rejected it.
+    Inner(E),
+}
+
+/// Check that the origin of the transition holds whatever role it requires, and that a call
+/// acts on no account but the origin's.
+fn check_role<Inner: RequiresRole>(
+    state: &AclState<Inner::State>,
+    t: &AclTransition<Inner::Transition>,
+) -> Result<(), AclError<Inner::Error>> {
+    if let AclTransition::Call { origin, call } = t {
+        if let Some(owner) = Inner::owner(call).filter(|owner| owner != origin) {
+            return Err(AclError::NotOwner {
+                origin: *origin,
+                owner,
+            });
+        }
+    }
+    let (origin, role) = match t {
+        AclTransition::Call { origin, call } => match Inner::required_role(call) {
+            Some(role) => (origin, role),
+            None => return Ok(()),
+        },
+        AclTransition::Grant { origin, .. } | AclTransition::Revoke { origin, .. } => {
+            (origin, Role::Admin)
+        }
+    };
+    if state.has_role(origin, role) {
+        Ok(())
+    } else {
+        Err(AclError::MissingRole { origin: *origin, role })
+    }
+}
+
+/// Apply the given transition if its origin holds the required role, using `call` to make inner
+/// calls. Otherwise leave the state unchanged.
+fn apply_acl<Inner: RequiresRole<State: Clone>>(
+    starting_state: &AclState<Inner::State>,
+    t: &AclTransition<Inner::Transition>,
+    call: impl FnOnce(&Inner::State, &Inner::Transition) -> Inner::State,
+) -> AclState<Inner::State> {
+    if check_role::<Inner>(starting_state, t).is_err() {
+        return starting_state.clone();
+    }
+    let mut s = starting_state.clone();
+    match t {
+        AclTransition::Call { call: inner_call, .. } => {
+            s.inner = call(&starting_state.inner, inner_call);
+        }
+        AclTransition::Grant { who, role, .. } => {
+            s.roles.entry(*who).or_default().insert(*role);
+        }
+        AclTransition::Revoke { who, role, .. } => {
+            if let Some(roles) = s.roles.get_mut(who) {
+                roles.remove(role);
+                if roles.is_empty() {
+                    s.roles.remove(who);
+                }
+            }
+        }
+    }
+    s
+}
+
+impl<Inner: RequiresRole<State: Clone>> StateMachine for Acl<Inner> {
+    type State = AclState<Inner::State>;
+    type Transition = AclTransition<Inner::Transition>;
+    type Error = AclError<Inner::Error>;
+    /// The inner machine's configuration, and the roles each user starts with
+    type GenesisConfig = (Inner::GenesisConfig, Vec<(User, Role)>);
+
+    fn genesis_state((config, grants): Self::GenesisConfig) -> Self::State {
+        let mut roles: HashMap<User, HashSet<Role>> = HashMap::new();
+        for (who, role) in grants {
+            roles.entry(who).or_default().insert(role);
+        }
+        AclState {
+            inner: Inner::genesis_state(config),
+            roles,
+        }
+    }
+
+    /// Transitions whose origin lacks the required role are a no-op.
+    fn next_state(starting_state: &Self::State, t: &Self::Transition) -> Self::State {
+        apply_acl::<Inner>(starting_state, t, Inner::next_state)
+    }
+
+    fn try_next_state(
+        starting_state: &Self::State,
+        t: &Self::Transition,
+    ) -> Result<Self::State, Self::Error> {
+        check_role::<Inner>(starting_state, t)?;
+        if let AclTransition::Call { call, .. } = t {
+            Inner::try_next_state(&starting_state.inner, call).map_err(AclError::Inner)?;
+        }
+        Ok(Self::next_state(starting_state, t))
+    }
+
+    fn validate_transition(state: &Self::State, t: &Self::Transition) -> bool {
+        check_role::<Inner>(state, t).is_ok()
+            && match t {
+                AclTransition::Call { call, .. } => Inner::validate_transition(&state.inner, call),
+                _ => true,
+            }
+    }
+
+    fn human_name() -> String {
+        format!("Permissioned {}", Inner::human_name())
+    }
+}
+
+impl<Inner> ContextualStateMachine for Acl<Inner>
+where
+    Inner: RequiresRole<State: Clone> + ContextualStateMachine,
+{
+    fn next_state_in_context(
+        starting_state: &Self::State,
+        t: &Self::Transition,
+        context: &BlockContext,
+    ) -> Self::State {
+        apply_acl::<Inner>(starting_state, t, |s, call| {
+            Inner::next_state_in_context(s, call, context)
+        })
+    }
+
+    fn try_next_state_in_context(
+        starting_state: &Self::State,
+        t: &Self::Transition,
+        context: &BlockContext,
+    ) -> Result<Self::State, Self::Error> {
+        check_role::<Inner>(starting_state, t)?;
+        if let AclTransition::Call { call, .. } = t {
+            Inner::try_next_state_in_context(&starting_state.inner, call, context)
+                .map_err(AclError::Inner)?;
+        }
+        Ok(Self::next_state_in_context(starting_state, t, context))
+    }
+}
+
+impl<Inner: RequiresRole<State: Clone> + Weighted> Weighted for Acl<Inner> {
+    fn weight(t: &Self::Transition) -> u64 {
+        match t {
+            AclTransition::Call { call, .. } => Inner::weight(call),
+            AclTransition::Grant { .. } | AclTransition::Revoke { .. } => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+use super::{
+    p4_accounted_currency::{AccountedCurrency, AccountingTransaction, CurrencyError},
+    with_nonces::{NonceError, Nonced, WithNonces},
+};
+
+#[cfg(test)]
+type PermissionedCurrency = Acl<AccountedCurrency>;
+
+#[cfg(test)]
+fn mint(origin: User, amount: u64) -> AclTransition<AccountingTransaction> {
+    AclTransition::Call {
+        origin,
+        call: AccountingTransaction::Mint {
+            minter: origin,
+            amount,
+        },
+    }
+}
+
+#[test]
+fn acl_only_minters_mint() {
+    let start = PermissionedCurrency::genesis_state((vec![], vec![(User::Alice, Role::Mint)]));
+
+    let end = PermissionedCurrency::next_state(&start, &mint(User::Alice, 10));
+    assert_eq!(end.inner, HashMap::from([(User::Alice, 10)]));
+
+    assert_eq!(
+        PermissionedCurrency::try_next_state(&end, &mint(User::Bob, 10)),
+        Err(AclError::MissingRole {
+            origin: User::Bob,
+            role: Role::Mint
+        })
+    );
+    assert_eq!(PermissionedCurrency::next_state(&end, &mint(User::Bob, 10)), end);
+}
+
+#[test]
+fn acl_admins_manage_roles() {
+    let start = PermissionedCurrency::genesis_state((vec![], vec![(User::Alice, Role::Admin)]));
+    let grant = AclTransition::Grant {
+        origin: User::Alice,
+        who: User::Bob,
+        role: Role::Mint,
+    };
+    let revoke = AclTransition::Revoke {
+        origin: User::Alice,
+        who: User::Bob,
+        role: Role::Mint,
+    };
+
+    let granted = PermissionedCurrency::try_next_state(&start, &grant).unwrap();
+    assert!(granted.has_role(&User::Bob, Role::Mint));
+    assert!(PermissionedCurrency::validate_transition(&granted, &mint(User::Bob, 5)));
+
+    let revoked = PermissionedCurrency::try_next_state(&granted, &revoke).unwrap();
+    assert_eq!(revoked, start);
+
+    let self_grant = AclTransition::Grant {
+        origin: User::Bob,
+        who: User::Bob,
+        role: Role::Admin,
+    };
+    assert!(!PermissionedCurrency::validate_transition(&start, &self_grant));
+}
+
+#[test]
+fn acl_anyone_may_transfer() {
+    let start = PermissionedCurrency::genesis_state((vec![(User::Bob, 10)], vec![]));
+    let transfer = AclTransition::Call {
+        origin: User::Bob,
+        call: AccountingTransaction::Transfer {
+            sender: User::Bob,
+            receiver: User::Charlie,
+            amount: 20,
+        },
+    };
+
+    assert_eq!(
+        PermissionedCurrency::try_next_state(&start, &transfer),
+        Err(AclError::Inner(CurrencyError::InsufficientBalance {
+            available: 10,
+            requested: 20
+        }))
+    );
+}
+
+#[test]
+fn acl_origins_only_spend_their_own_money() {
+    let start = PermissionedCurrency::genesis_state((vec![(User::Alice, 100)], vec![]));
+    let theft = AclTransition::Call {
+        origin: User::Bob,
+        call: AccountingTransaction::Transfer {
+            sender: User::Alice,
+            receiver: User::Bob,
+            amount: 50,
+        },
+    };
+
+    assert_eq!(
+        PermissionedCurrency::try_next_state(&start, &theft),
+        Err(AclError::NotOwner {
+            origin: User::Bob,
+            owner: User::Alice
+        })
+    );
+    assert_eq!(PermissionedCurrency::next_state(&start, &theft), start);
+}
+
+#[test]
+fn acl_origins_are_bound_to_signers() {
+    type Chain = WithNonces<PermissionedCurrency>;
+    let start = Chain::genesis_state((vec![(User::Alice, 100)], vec![(User::Alice, Role::Mint)]));
+    let signed_by_bob = |call| Nonced {
+        signer: User::Bob,
+        nonce: 0,
+        call,
+    };
+
+    // Bob claims to be Alice, who may mint.
+    let forged_mint = signed_by_bob(AclTransition::Call {
+        origin: User::Alice,
+        call: AccountingTransaction::Mint {
+            minter: User::Bob,
+            amount: 1000,
+        },
+    });
+    assert_eq!(
+        Chain::try_next_state(&start, &forged_mint),
+        Err(NonceError::WrongSigner {
+            signer: User::Bob,
+            owner: User::Alice
+        })
+    );
+
+    // Bob is honest about who he is, but spends Alice's money.
+    let theft = signed_by_bob(AclTransition::Call {
+        origin: User::Bob,
+        call: AccountingTransaction::Transfer {
+            sender: User::Alice,
+            receiver: User::Bob,
+            amount: 50,
+        },
+    });
+    assert_eq!(
+        Chain::try_next_state(&start, &theft),
+        Err(NonceError::Inner(AclError::NotOwner {
+            origin: User::Bob,
+            owner: User::Alice
+        }))
+    );
+}
+
+#[test]
+fn acl_combines_with_nonces_for_permissioned_minting() {
+    type Chain = WithNonces<PermissionedCurrency>;
+    let start = Chain::genesis_state((vec![], vec![(User::Alice, Role::Mint)]));
+    let signed_mint = Nonced {
+        signer: User::Alice,
+        nonce: 0,
+        call: mint(User::Alice, 10),
+    };
+
+    let end = Chain::try_next_state(&start, &signed_mint).unwrap();
+
+    assert_eq!(end.inner.inner, HashMap::from([(User::Alice, 10)]));
+    assert!(matches!(
+        Chain::try_next_state(&end, &signed_mint),
+        Err(NonceError::Stale { .. })
+    ));
+}
@@ -0,0 +1,206 @@
+//! When a chain is slow, the first question is which transitions it is spending its time on.
+//! Here we wrap any state machine so that every transition it executes is counted and timed,
+//! grouped by the kind of transition, in a process-wide registry that can be inspected later.
+//!
+//! Instrumentation is only compiled in with the `metrics` feature, so that ordinary builds do not
+//! pay for the clock reads and the registry lock.
+
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::{BlockContext, ContextualStateMachine, MerkleState, StateMachine, Weighted};
+
+/// A state machine whose transitions can be grouped into a few named kinds, typically one per
+/// variant of the transition enum.
+pub trait Labelled: StateMachine {
+    /// The kind of the given transition, for example "transfer".
+    fn label(t: &Self::Transition) -> &'static str;
+}
+
+/// What has been recorded for one kind of transition of one machine.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Counters {
+    /// Transitions that were executed and not rejected. `next_state` cannot report a
+    /// rejection, so every transition executed through it counts as applied.
+    pub applied: u64,
+    /// Transitions rejected by `try_next_state` or `try_next_state_in_context`
+    pub rejected: u64,
+    /// The total time spent executing transitions of this kind, whether or not they were
+    /// rejected
+    pub time: Duration,
+}
+
+/// Counters by machine type name and then by transition label
+type CounterMap = BTreeMap<&'static str, BTreeMap<&'static str, Counters>>;
+
+/// The counters of every instrumented machine in this process, keyed by the machine's type name
+/// and then by transition label. Type names are used rather than human names because they are
+/// unique and free to look up.
+pub struct Registry {
+    counters: Mutex<CounterMap>,
+}
+
+static REGISTRY: Registry = Registry {
+    counters: Mutex::new(BTreeMap::new()),
+};
+
+impl Registry {
+    /// The registry that all instrumented machines record into.
+    pub fn global() -> &'static Registry {
+        &REGISTRY
+    }
+
+    /// The counters for the given kind of transition of the given machine, named as by
+    /// `core::any::type_name`. They are all zero
+    /// if nothing has been recorded yet.
+    pub fn get(&self, machine: &str, label: &str) -> Counters {
+        self.lock()
+            .get(machine)
+            .and_then(|labels| labels.get(label))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Every recorded counter, sorted by machine name and then by label.
+    pub fn all(&self) -> Vec<(&'static str, &'static str, Counters)> {
+        self.lock()
+            .iter()
+            .flat_map(|(machine, labels)| {
+                labels
+                    .iter()
+                    .map(move |(label, counters)| (*machine, *label, *counters))
+            })
+            .collect()
+    }
+
+    /// Forget everything recorded so far, for example between two benchmark runs.
+    pub fn reset(&self) {
+        self.lock().clear();
+    }
+
+    fn record(
+        &self,
+        machine: &'static str,
+        label: &'static str,
+        accepted: bool,
+        elapsed: Duration,
+    ) {
+        let mut counters = self.lock();
+        let entry = counters
+            .entry(machine)
+            .or_default()
+            .entry(label)
+            .or_default();
+        if accepted {
+            entry.applied += 1;
+        } else {
+            entry.rejected += 1;
+        }
+        entry.time += elapsed;
+    }
+
+    /// A panic while holding the lock cannot leave the counters in an inconsistent state,
+    /// so a poisoned lock is simply recovered.
+    fn lock(&self) -> std::sync::MutexGuard<'_, CounterMap> {
+        self.counters.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A higher-order state machine that behaves exactly like `Inner`, while recording every
+/// transition it executes in the global `Registry`.
+pub struct Instrumented<Inner>(PhantomData<Inner>);
+
+impl<Inner: Labelled> Instrumented<Inner> {
+    /// Run the given execution of the given transition, and record how it went.
+    fn measure<T, E>(
+        t: &Inner::Transition,
+        execute: impl FnOnce() -> Result<T, E>,
+    ) -> Result<T, E> {
+        let start = Instant::now();
+        let result = execute();
+        Registry::global().record(
+            core::any::type_name::<Inner>(),
+            Inner::label(t),
+            result.is_ok(),
+            start.elapsed(),
+        );
+        result
+    }
+
+    /// Like `measure`, for executions that cannot report a rejection.
+    fn measure_infallible<T>(t: &Inner::Transition, execute: impl FnOnce() -> T) -> T {
+        match Self::measure(t, || Ok::<_, core::convert::Infallible>(execute())) {
+            Ok(result) => result,
+        }
+    }
+}
+
+impl<Inner: Labelled> StateMachine for Instrumented<Inner> {
+    type State = Inner::State;
+    type Transition = Inner::Transition;
+    type Error = Inner::Error;
+    type GenesisConfig = Inner::GenesisConfig;
+
+    fn genesis_state(config: Inner::GenesisConfig) -> Inner::State {
+        Inner::genesis_state(config)
+    }
+
+    fn next_state(starting_state: &Inner::State, t: &Inner::Transition) -> Inner::State {
+        Self::measure_infallible(t, || Inner::next_state(starting_state, t))
+    }
+
+    fn try_next_state(
+        starting_state: &Inner::State,
+        t: &Inner::Transition,
+    ) -> Result<Inner::State, Inner::Error> {
+        Self::measure(t, || Inner::try_next_state(starting_state, t))
+    }
+
+    /// Validation does not execute anything, so it is not recorded.
+    fn validate_transition(state: &Inner::State, t: &Inner::Transition) -> bool {
+        Inner::validate_transition(state, t)
+    }
+
+    fn human_name() -> String {
+        Inner::human_name()
+    }
+}
+
+impl<Inner: Labelled + ContextualStateMachine> ContextualStateMachine for Instrumented<Inner> {
+    fn next_state_in_context(
+        starting_state: &Inner::State,
+        t: &Inner::Transition,
+        context: &BlockContext,
+    ) -> Inner::State {
+        Self::measure_infallible(t, || {
+            Inner::next_state_in_context(starting_state, t, context)
+        })
+    }
+
+    fn try_next_state_in_context(
+        starting_state: &Inner::State,
+        t: &Inner::Transition,
+        context: &BlockContext,
+    ) -> Result<Inner::State, Inner::Error> {
+        Self::measure(t, || {
+            Inner::try_next_state_in_context(starting_state, t, context)
+        })
+    }
+}
+
+impl<Inner: Labelled + Weighted> Weighted for Instrumented<Inner> {
+    fn weight(t: &Inner::Transition) -> u64 {
+        Inner::weight(t)
+    }
+}
+
+impl<Inner: Labelled + MerkleState> MerkleState for Instrumented<Inner> {
+    type Key = Inner::Key;
+    type Value = Inner::Value;
+
+    fn entries(state: &Inner::State) -> Vec<(Inner::Key, Inner::Value)> {
+        Inner::entries(state)
+    }
+}
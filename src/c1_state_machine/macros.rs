@@ -0,0 +1,126 @@
+//! Many state machines are nothing more than a genesis state and a match over the transitions.
+//! Writing the `StateMachine` impl for such a machine by hand is mostly boilerplate, so here is a
+//! macro that writes it for us.
+
+/// Declare a state machine whose logic is a pure match over its transitions.
+///
+/// ```ignore
+/// state_machine! {
+///     /// A counter that can be bumped or reset.
+///     pub struct Counter;
+///     state = u64;
+///     transition = CounterAction;
+///     genesis = 0;
+///     name = "Counter";
+///     next_state(count, t) {
+///         CounterAction::Increment => count + 1,
+///         CounterAction::Reset => 0,
+///     }
+/// }
+/// ```
+///
+/// The two names given to `next_state` are bound to the starting state and the transition, both
+/// by reference, and the arms are matched against the transition. The `name` line is optional.
+///
+/// The generated machine never rejects a transition and needs no genesis configuration. It
+/// also gets an empty `ContextualStateMachine` impl, so it can be used in a blockchain straight
+/// away. Machines with validity rules should implement the traits by hand instead.
+#[macro_export]
+macro_rules! state_machine {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident;
+        state = $state:ty;
+        transition = $transition:ty;
+        genesis = $genesis:expr;
+        $(name = $human_name:expr;)?
+        next_state($s:ident, $t:ident) { $($arms:tt)* }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name;
+
+        impl $crate::c1_state_machine::StateMachine for $name {
+            type State = $state;
+            type Transition = $transition;
+            type Error = core::convert::Infallible;
+            type GenesisConfig = ();
+
+            fn genesis_state(_: ()) -> $state {
+                $genesis
+            }
+
+            #[allow(unused_variables)]
+            fn next_state($s: &$state, $t: &$transition) -> $state {
+                match $t {
+                    $($arms)*
+                }
+            }
+
+            $(
+                fn human_name() -> String {
+                    $human_name.into()
+                }
+            )?
+        }
+
+        impl $crate::c1_state_machine::ContextualStateMachine for $name {}
+    };
+}
+
+#[cfg(test)]
+use super::StateMachine;
+
+#[cfg(test)]
+enum CounterAction {
+    Increment,
+    Add(u64),
+    Reset,
+}
+
+#[cfg(test)]
+state_machine! {
+    /// A counter that can be bumped or reset.
+    struct Counter;
+    state = u64;
+    transition = CounterAction;
+    genesis = 0;
+    name = "Counter";
+    next_state(count, t) {
+        CounterAction::Increment => count + 1,
+        CounterAction::Add(n) => count.saturating_add(*n),
+        CounterAction::Reset => 0,
+    }
+}
+
+#[cfg(test)]
+state_machine! {
+    struct Unnamed;
+    state = bool;
+    transition = ();
+    genesis = true;
+    next_state(s, t) {
+        () => !s,
+    }
+}
+
+#[test]
+fn macro_generates_genesis_and_transitions() {
+    let start = Counter::genesis_state(());
+    let ts = [
+        CounterAction::Increment,
+        CounterAction::Add(5),
+        CounterAction::Reset,
+        CounterAction::Increment,
+    ];
+
+    assert_eq!(start, 0);
+    assert_eq!(Counter::next_state(&start, &CounterAction::Add(5)), 5);
+    assert_eq!(Counter::apply_all(&start, &ts), 1);
+}
+
+#[test]
+fn macro_human_name_is_optional() {
+    assert_eq!(Counter::human_name(), "Counter");
+    assert_eq!(Unnamed::human_name(), "Unnamed state machine");
+    assert!(!Unnamed::next_state(&Unnamed::genesis_state(()), &()));
+}
@@ -1,12 +1,36 @@
 //! This module is all about modeling phenomena and systems as state machines. We begin with a few simple
 //! examples, and then proceed to build bigger and more complex state machines all implementing the same simple interface.
 
-mod p1_switches;
+pub mod p1_switches;
 mod p2_laundry_machine;
-mod p3_atm;
-mod p4_accounted_currency;
-mod p5_digital_cash;
-mod p6_open_ended;
+pub mod p3_atm;
+pub mod p4_accounted_currency;
+pub mod p5_digital_cash;
+pub mod p6_open_ended;
+pub mod p7_multiasset;
+pub mod p8_staking;
+pub mod p9_governance;
+pub mod p10_auction;
+pub mod p11_name_service;
+pub mod p12_escrow;
+pub mod p13_vesting;
+pub mod p14_web_of_trust;
+pub mod p15_tcr;
+pub mod p16_utility;
+pub mod p17_motor_vehicles;
+pub mod p18_payment_channel;
+pub mod p19_k_in_a_row;
+pub mod acl;
+#[cfg(feature = "metrics")]
+pub mod instrumented;
+mod macros;
+pub mod pair;
+pub mod parallel;
+pub mod randomness;
+pub mod upgrade;
+pub mod with_nonces;
+
+use crate::codec::{Decode, Encode};
 
 /// A state machine - Generic over the transition type
 pub trait StateMachine {
@@ -16,9 +40,70 @@ pub trait StateMachine {
     /// The transitions that can be made between states
     type Transition;
 
+    /// The reasons this machine may reject a transition. Machines that accept every
+    /// transition can use `core::convert::Infallible`.
+    type Error: core::fmt::Debug;
+
+    /// The configuration needed to build this machine's genesis state, such as initial
+    /// balances. Machines that always start from the same state can use `()`.
+    type GenesisConfig;
+
+    /// Build this machine's canonical starting state from the given configuration.
+    fn genesis_state(config: Self::GenesisConfig) -> Self::State;
+
     /// Calculate the resulting state when this state undergoes the given transition
     fn next_state(starting_state: &Self::State, t: &Self::Transition) -> Self::State;
 
+    /// Calculate the resulting state, or the reason the transition was rejected.
+    ///
+    /// `next_state` models an invalid transition as a no-op (or a reset), so its callers
+    /// cannot tell a rejected transition apart from one that legitimately left the state
+    /// unchanged. Blockchain clients need to know the difference, because a block containing
+    /// a rejected transition is invalid. The provided implementation never rejects anything;
+    /// machines with validity rules should override it.
+    fn try_next_state(
+        starting_state: &Self::State,
+        t: &Self::Transition,
+    ) -> Result<Self::State, Self::Error> {
+        Ok(Self::next_state(starting_state, t))
+    }
+
+    /// Check whether the given transition would be accepted in the given state, without
+    /// necessarily calculating the resulting state.
+    ///
+    /// Transaction pools use this to cheaply pre-check transitions before a block builder
+    /// executes them. The provided implementation simply attempts the transition with
+    /// `try_next_state`; machines whose validity rules can be checked without building the
+    /// whole resulting state should override it.
+    fn validate_transition(state: &Self::State, t: &Self::Transition) -> bool {
+        Self::try_next_state(state, t).is_ok()
+    }
+
+    /// Calculate the resulting state when this state undergoes each of the given transitions
+    /// in order. This is exactly what happens when a block's body is executed.
+    ///
+    /// The provided implementation simply folds `next_state` over the batch. Machines that can
+    /// process a whole batch more cheaply than one transition at a time may override it.
+    fn apply_all(starting_state: &Self::State, ts: &[Self::Transition]) -> Self::State
+    where
+        Self::State: Clone,
+    {
+        ts.iter()
+            .fold(starting_state.clone(), |s, t| Self::next_state(&s, t))
+    }
+
+    /// Like `apply_all`, but stops at the first rejected transition and returns its error.
+    fn try_apply_all(
+        starting_state: &Self::State,
+        ts: &[Self::Transition],
+    ) -> Result<Self::State, Self::Error>
+    where
+        Self::State: Clone,
+    {
+        ts.iter()
+            .try_fold(starting_state.clone(), |s, t| Self::try_next_state(&s, t))
+    }
+
     /// A human-readable name for this state machine. This may be used in user-facing
     /// programs such as the repl described below. This is not in any way related to
     /// the correctness of the state machine.
@@ -27,12 +112,388 @@ pub trait StateMachine {
     }
 }
 
+/// A state machine whose transitions can be described as compact diffs against the state.
+///
+/// Returning a fully cloned state from every transition is simple, but expensive when the state
+/// is large and each transition only touches a small part of it. A diff records just the part
+/// that changed. Storing one diff per transition makes it cheap to keep a record of every
+/// historical state, and to reconstruct any of them by replaying the diffs from a known state.
+pub trait DiffStateMachine: StateMachine {
+    /// A compact description of the changes a single transition makes to the state
+    type StateDiff: Clone + core::fmt::Debug;
+
+    /// Calculate the diff that the given transition makes to the given state, or the reason
+    /// the transition was rejected.
+    fn state_diff(
+        starting_state: &Self::State,
+        t: &Self::Transition,
+    ) -> Result<Self::StateDiff, Self::Error>;
+
+    /// Apply a previously calculated diff to the state in place.
+    fn apply_diff(state: &mut Self::State, diff: &Self::StateDiff);
+}
+
+/// A state machine whose transitions can be undone.
+///
+/// When a client re-orgs away from blocks it has already executed, it must roll its state back
+/// to the common ancestor before executing the new fork. If transitions can be undone, this is
+/// possible without keeping a copy of every historical state around.
+pub trait InvertibleStateMachine: StateMachine {
+    /// Calculate the state before the given transition was applied, given the state after it.
+    ///
+    /// This only needs to be correct for transitions that were accepted by `try_next_state`.
+    /// Undoing a transition that was never applied has no meaningful result.
+    fn undo(ending_state: &Self::State, t: &Self::Transition) -> Self::State;
+
+    /// Undo a batch of transitions that was applied with `apply_all`, by undoing each of them
+    /// in reverse order.
+    fn undo_all(ending_state: &Self::State, ts: &[Self::Transition]) -> Self::State
+    where
+        Self::State: Clone,
+    {
+        ts.iter()
+            .rev()
+            .fold(ending_state.clone(), |s, t| Self::undo(&s, t))
+    }
+}
+
+/// A state machine that reports what happened during each transition as a list of typed events.
+///
+/// A new state alone says little about how it came to be. Events such as "Alice withdrew 20"
+/// let wallets and explorers follow along without diffing whole states, much like the event
+/// logs of real-world chains.
+pub trait EventfulStateMachine: StateMachine {
+    /// The things that can happen during a transition
+    type Event: Clone + core::fmt::Debug;
+
+    /// Calculate the resulting state like `next_state` does, along with the events that the
+    /// transition emitted. A transition that is rejected emits no events.
+    fn next_state_with_events(
+        starting_state: &Self::State,
+        t: &Self::Transition,
+    ) -> (Self::State, Vec<Self::Event>);
+
+    /// Like `next_state_with_events`, for a transition executed in a block with the given
+    /// context. The provided implementation ignores the context, so machines that are also
+    /// contextual must override it to execute the way `next_state_in_context` does.
+    fn next_state_with_events_in_context(
+        starting_state: &Self::State,
+        t: &Self::Transition,
+        _context: &BlockContext,
+    ) -> (Self::State, Vec<Self::Event>) {
+        Self::next_state_with_events(starting_state, t)
+    }
+
+    /// Apply each of the given transitions in order, collecting all of the emitted events.
+    fn apply_all_with_events(
+        starting_state: &Self::State,
+        ts: &[Self::Transition],
+    ) -> (Self::State, Vec<Self::Event>)
+    where
+        Self::State: Clone,
+    {
+        let mut events = Vec::new();
+        let end = ts.iter().fold(starting_state.clone(), |s, t| {
+            let (next, mut emitted) = Self::next_state_with_events(&s, t);
+            events.append(&mut emitted);
+            next
+        });
+        (end, events)
+    }
+}
+
+/// Information about the block a transition is being executed in.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BlockContext {
+    /// The height of the block
+    pub height: u64,
+    /// When the block was authored, in milliseconds since the unix epoch
+    pub timestamp: u64,
+    /// Who authored the block. The genesis block has no author.
+    pub author: Option<User>,
+    /// The hash of the parent block's header. The genesis block has no parent, so this is 0.
+    pub parent_hash: u64,
+}
+
+/// The fields in the order they are declared.
+impl Encode for BlockContext {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.height.encode_to(dest);
+        self.timestamp.encode_to(dest);
+        self.author.encode_to(dest);
+        self.parent_hash.encode_to(dest);
+    }
+}
+
+impl Decode for BlockContext {
+    fn decode_from(input: &mut &[u8]) -> Option<Self> {
+        Some(BlockContext {
+            height: u64::decode_from(input)?,
+            timestamp: u64::decode_from(input)?,
+            author: Option::decode_from(input)?,
+            parent_hash: u64::decode_from(input)?,
+        })
+    }
+}
+
+/// A state machine whose transitions may depend on the block they are executed in.
+///
+/// Some machines need to know the current block height or time, for example to release vested
+/// funds or to run a slot lottery. Blockchain clients execute every transition through this
+/// trait, passing along the context of the block being built or imported.
+///
+/// The provided methods ignore the context and defer to `next_state` and `try_next_state`, so
+/// a machine that does not care about blocks can opt in with an empty impl. Machines that do
+/// depend on the context must override both `next_state_in_context` and
+/// `try_next_state_in_context`.
+pub trait ContextualStateMachine: StateMachine {
+    /// Calculate the resulting state when this state undergoes the given transition in a block
+    /// with the given context.
+    fn next_state_in_context(
+        starting_state: &Self::State,
+        t: &Self::Transition,
+        _context: &BlockContext,
+    ) -> Self::State {
+        Self::next_state(starting_state, t)
+    }
+
+    /// Calculate the resulting state in the given block context, or the reason the transition
+    /// was rejected.
+    fn try_next_state_in_context(
+        starting_state: &Self::State,
+        t: &Self::Transition,
+        _context: &BlockContext,
+    ) -> Result<Self::State, Self::Error> {
+        Self::try_next_state(starting_state, t)
+    }
+
+    /// Execute a whole block body in the given context.
+    fn apply_all_in_context(
+        starting_state: &Self::State,
+        ts: &[Self::Transition],
+        context: &BlockContext,
+    ) -> Self::State
+    where
+        Self::State: Clone,
+    {
+        ts.iter().fold(starting_state.clone(), |s, t| {
+            Self::next_state_in_context(&s, t, context)
+        })
+    }
+
+    /// Like `apply_all_in_context`, but stops at the first rejected transition.
+    fn try_apply_all_in_context(
+        starting_state: &Self::State,
+        ts: &[Self::Transition],
+        context: &BlockContext,
+    ) -> Result<Self::State, Self::Error>
+    where
+        Self::State: Clone,
+    {
+        ts.iter().try_fold(starting_state.clone(), |s, t| {
+            Self::try_next_state_in_context(&s, t, context)
+        })
+    }
+}
+
+/// A state machine whose state can be viewed as a set of key/value entries and committed to with
+/// a Merkle root.
+///
+/// Hashing the whole state works, but the root has to be recalculated from scratch after every
+/// block, and nobody can prove a single entry, such as their balance, without revealing the
+/// entire state. A Merkle root over the entries fixes both problems.
+pub trait MerkleState: StateMachine {
+    type Key: core::hash::Hash;
+    type Value: core::hash::Hash;
+
+    /// Every entry in the given state, in any order.
+    fn entries(state: &Self::State) -> Vec<(Self::Key, Self::Value)>;
+
+    /// The Merkle leaves of the given state. There is one leaf per entry, ordered by the hash
+    /// of the entry's key so that the order entries are listed in does not matter.
+    fn leaves(state: &Self::State) -> Vec<u64> {
+        let mut keyed: Vec<(u64, u64)> = Self::entries(state)
+            .iter()
+            .map(|(k, v)| (crate::hash(k), crate::hash(&(k, v))))
+            .collect();
+        keyed.sort_unstable();
+        keyed.into_iter().map(|(_, leaf)| leaf).collect()
+    }
+
+    /// The Merkle tree over the given state's leaves. Keep this around to update the root
+    /// cheaply when only a few entries change value.
+    fn state_tree(state: &Self::State) -> crate::merkle::MerkleTree {
+        crate::merkle::MerkleTree::from_leaves(Self::leaves(state))
+    }
+
+    /// The commitment to the given state that goes into block headers.
+    fn state_root(state: &Self::State) -> u64 {
+        crate::merkle::merkle_root(&Self::leaves(state))
+    }
+
+    /// Prove the value of the given key in the given state, to somebody who knows only the
+    /// state root. Returns None if the state has no entry for the key.
+    fn prove(state: &Self::State, key: &Self::Key) -> Option<StateProof<Self::Key, Self::Value>>
+    where
+        Self::Key: PartialEq,
+    {
+        // The same order as `leaves`, keeping each entry next to its leaf.
+        let mut keyed: Vec<(u64, u64, Self::Key, Self::Value)> = Self::entries(state)
+            .into_iter()
+            .map(|(k, v)| (crate::hash(&k), crate::hash(&(&k, &v)), k, v))
+            .collect();
+        keyed.sort_unstable_by_key(|(key_hash, leaf, ..)| (*key_hash, *leaf));
+        let index = keyed.iter().position(|(_, _, k, _)| k == key)?;
+        let tree = crate::merkle::MerkleTree::from_leaves(keyed.iter().map(|e| e.1).collect());
+        let proof = tree.prove(index)?;
+        let (_, _, key, value) = keyed.swap_remove(index);
+        Some(StateProof { key, value, proof })
+    }
+}
+
+/// A proof that a key has a value in a state with a given Merkle root: the entry, and the proof
+/// that its leaf is in the tree. Anybody who trusts a header committing to the root, such as a
+/// light client, can check it without knowing anything else about the state.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StateProof<Key, Value> {
+    pub key: Key,
+    pub value: Value,
+    pub proof: crate::merkle::MerkleProof,
+}
+
+impl<Key: core::hash::Hash, Value: core::hash::Hash> StateProof<Key, Value> {
+    /// The root of the state in which the proof places its entry, or None if the proof does not
+    /// fit the shape of the tree it claims to be from.
+    pub fn state_root(&self) -> Option<u64> {
+        self.proof
+            .root_with(crate::hash(&(&self.key, &self.value)))
+    }
+
+    /// Whether the key has the value in the state with the given root.
+    pub fn verify(&self, state_root: u64) -> bool {
+        self.state_root() == Some(state_root)
+    }
+}
+
+/// The entry first, then the Merkle proof, so that proofs can be sent between nodes.
+impl<Key: Encode, Value: Encode> Encode for StateProof<Key, Value> {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.key.encode_to(dest);
+        self.value.encode_to(dest);
+        self.proof.encode_to(dest);
+    }
+}
+
+impl<Key: Decode, Value: Decode> Decode for StateProof<Key, Value> {
+    fn decode_from(input: &mut &[u8]) -> Option<Self> {
+        Some(StateProof {
+            key: Key::decode_from(input)?,
+            value: Value::decode_from(input)?,
+            proof: Decode::decode_from(input)?,
+        })
+    }
+}
+
+/// A state machine whose transitions each have a cost, or weight.
+///
+/// Blocks can only hold so much work. Weights let a block builder decide how many transitions
+/// fit into a single block, and give fee markets something to charge for.
+pub trait Weighted: StateMachine {
+    /// The cost of executing the given transition. It must not depend on the state, so that it
+    /// can be known before the transition is executed.
+    fn weight(t: &Self::Transition) -> u64;
+
+    /// The combined weight of a batch of transitions.
+    fn total_weight(ts: &[Self::Transition]) -> u64 {
+        ts.iter().map(Self::weight).fold(0, u64::saturating_add)
+    }
+}
+
+/// A state machine that pays block authors.
+///
+/// Every block of a chain running such a machine starts with a coinbase: a transition, injected
+/// by the block's builder rather than sent by any user, that credits the block's author with the
+/// block reward plus the fees the rest of the body pays. Importers re-derive the coinbase from
+/// the author and the body, and refuse blocks whose coinbase differs, or that have a coinbase
+/// anywhere else.
+pub trait Rewarded: ContextualStateMachine {
+    /// The coinbase crediting the given author with the given amount.
+    fn coinbase(author: User, amount: u64) -> Self::Transition;
+
+    /// Whether the given transition is a coinbase.
+    fn is_coinbase(t: &Self::Transition) -> bool;
+
+    /// The fee the given transition pays towards the coinbase. It must not depend on the state,
+    /// so that importers can add up the fees before executing the block. The provided
+    /// implementation charges nothing.
+    fn fee(_t: &Self::Transition) -> u64 {
+        0
+    }
+
+    /// The amount the coinbase of a block with the given body and reward should credit. A leading
+    /// coinbase in the body is not counted.
+    fn coinbase_amount(body: &[Self::Transition], reward: u64) -> u64 {
+        body.iter()
+            .filter(|t| !Self::is_coinbase(t))
+            .map(Self::fee)
+            .fold(reward, u64::saturating_add)
+    }
+}
+
+/// A state machine whose states and transitions can be serialized, for example to write test
+/// fixtures, answer RPC queries, or persist a chain to disk.
+///
+/// There is nothing to implement here. Every state machine whose states and transitions
+/// support serde automatically implements this trait.
+#[cfg(feature = "serde")]
+pub trait SerdeStateMachine:
+    StateMachine<
+    State: serde::Serialize + serde::de::DeserializeOwned,
+    Transition: serde::Serialize + serde::de::DeserializeOwned,
+>
+{
+}
+
+#[cfg(feature = "serde")]
+impl<SM> SerdeStateMachine for SM where
+    SM: StateMachine<
+        State: serde::Serialize + serde::de::DeserializeOwned,
+        Transition: serde::Serialize + serde::de::DeserializeOwned,
+    >
+{
+}
+
 /// A set of play users for experimenting with the multi-user state machines
-#[derive(Hash, Eq, PartialEq, Debug, Clone, Copy)]
+#[derive(Hash, Eq, PartialEq, Ord, PartialOrd, Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum User {
     Alice,
     Bob,
     Charlie,
+    Dave,
+    Eve,
+}
+
+/// Each user is encoded as their position in the enum.
+impl Encode for User {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        dest.push(*self as u8);
+    }
+}
+
+impl Decode for User {
+    fn decode_from(input: &mut &[u8]) -> Option<Self> {
+        match u8::decode_from(input)? {
+            0 => Some(User::Alice),
+            1 => Some(User::Bob),
+            2 => Some(User::Charlie),
+            3 => Some(User::Dave),
+            4 => Some(User::Eve),
+            _ => None,
+        }
+    }
 }
 
 //TODO Some kind of main program that allows users to interact with their state machine in a repl-like way.
@@ -0,0 +1,519 @@
+//! Everything on a public blockchain is public, including the bids in an auction. If bids were
+//! submitted in the clear, the last bidder could simply outbid everyone by the smallest possible
+//! amount. Sealed-bid auctions avoid this with the commit-reveal pattern.
+//!
+//! During the commit phase, each bidder publishes only a commitment: the hash of their bid and a
+//! secret salt. Nobody can learn the bid from the commitment, and the bidder cannot change their
+//! bid later, because they could not find another bid and salt with the same hash. During the
+//! reveal phase, bidders publish their bids and salts, and the chain checks them against the
+//! commitments. Finally the auction is settled and the highest revealed bid wins.
+//!
+//! Bids must be backed by funds, but escrowing exactly the bid would reveal it. Instead each
+//! bidder escrows a deposit of their choosing, which must turn out to cover their bid. Bidders who
+//! never reveal forfeit their deposit to the seller, so nobody can commit to several bids and only
+//! reveal whichever suits them.
+
+use std::collections::HashMap;
+
+use super::{BlockContext, ContextualStateMachine, StateMachine, User, Weighted};
+use crate::hash;
+
+/// This state machine models sealed-bid, first-price auctions.
+pub struct SealedBidAuction;
+
+/// Auctions are numbered in the order they were created, starting from 0.
+pub type AuctionId = usize;
+
+/// The commitment a bidder publishes for the given bid and salt
+pub fn commitment(bid: u64, salt: u64) -> u64 {
+    hash(&(bid, salt))
+}
+
+/// The phases of an auction, determined by the current block height
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AuctionPhase {
+    /// Bidders may commit to bids
+    Commit,
+    /// Bidders may reveal their bids
+    Reveal,
+    /// The auction may be settled
+    Settle,
+    /// The auction has been settled and all funds paid out
+    Settled,
+}
+
+/// A bidder's sealed bid
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SealedBid {
+    pub commitment: u64,
+    /// The funds escrowed behind the bid
+    pub deposit: u64,
+    /// The bid, once it has been revealed
+    pub revealed: Option<u64>,
+}
+
+/// A single auction
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Auction {
+    pub seller: User,
+    /// The first block height of the reveal phase
+    pub commit_end: u64,
+    /// The first block height at which the auction may be settled
+    pub reveal_end: u64,
+    pub bids: HashMap<User, SealedBid>,
+    /// Bidders in the order they revealed. Earlier reveals win ties.
+    pub reveal_order: Vec<User>,
+    /// The winner and their bid, once settled. Settled auctions without valid bids have no
+    /// winner.
+    pub winner: Option<(User, u64)>,
+    pub settled: bool,
+}
+
+impl Auction {
+    /// The phase this auction is in at the given height
+    pub fn phase(&self, height: u64) -> AuctionPhase {
+        match height {
+            _ if self.settled => AuctionPhase::Settled,
+            h if h < self.commit_end => AuctionPhase::Commit,
+            h if h < self.reveal_end => AuctionPhase::Reveal,
+            _ => AuctionPhase::Settle,
+        }
+    }
+
+    /// The highest revealed bid and its bidder, if any bid was revealed
+    pub fn highest_bid(&self) -> Option<(User, u64)> {
+        let mut highest: Option<(User, u64)> = None;
+        for bidder in &self.reveal_order {
+            let bid = self.bids[bidder].revealed.unwrap_or(0);
+            if highest.is_none_or(|(_, best)| bid > best) {
+                highest = Some((*bidder, bid));
+            }
+        }
+        highest
+    }
+}
+
+/// The state of the auction house
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AuctionState {
+    /// Funds not escrowed in any auction. Zero balances are not stored.
+    pub balances: HashMap<User, u64>,
+    pub auctions: Vec<Auction>,
+}
+
+/// The state transitions that users can make in the auction house
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AuctionTransaction {
+    /// Open a new auction with the given phase boundaries
+    Create {
+        seller: User,
+        commit_end: u64,
+        reveal_end: u64,
+    },
+    /// Commit to a bid, escrowing the given deposit
+    Commit {
+        bidder: User,
+        auction: AuctionId,
+        commitment: u64,
+        deposit: u64,
+    },
+    /// Reveal the bid and salt behind an earlier commitment
+    Reveal {
+        bidder: User,
+        auction: AuctionId,
+        bid: u64,
+        salt: u64,
+    },
+    /// Pay the winning bid to the seller and refund everyone else. Anyone may do this.
+    Settle { auction: AuctionId },
+}
+
+/// The reasons a transaction may be rejected by the auction house
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuctionError {
+    /// The phases must be non-empty and must not have started already
+    InvalidSchedule,
+    /// No auction with this id exists
+    UnknownAuction(AuctionId),
+    /// The auction is not in the phase this transaction requires
+    WrongPhase {
+        expected: AuctionPhase,
+        actual: AuctionPhase,
+    },
+    /// The bidder does not have enough free funds for the deposit
+    InsufficientBalance { available: u64, requested: u64 },
+    /// Sellers may not bid in their own auctions
+    SellerCannotBid,
+    /// Each bidder may only commit once per auction
+    AlreadyCommitted,
+    /// The bidder never committed to a bid in this auction
+    NoCommitment,
+    /// The bid and salt do not match the commitment, or have already been revealed
+    InvalidReveal,
+    /// The revealed bid is larger than the escrowed deposit
+    BidExceedsDeposit { bid: u64, deposit: u64 },
+}
+
+impl StateMachine for SealedBidAuction {
+    type State = AuctionState;
+    type Transition = AuctionTransaction;
+    type Error = AuctionError;
+    /// The initial free funds of each user
+    type GenesisConfig = Vec<(User, u64)>;
+
+    fn genesis_state(endowments: Vec<(User, u64)>) -> AuctionState {
+        let mut state = AuctionState::default();
+        for (who, amount) in endowments {
+            credit(&mut state, who, amount);
+        }
+        state
+    }
+
+    fn next_state(starting_state: &AuctionState, t: &AuctionTransaction) -> AuctionState {
+        Self::next_state_in_context(starting_state, t, &BlockContext::default())
+    }
+
+    fn try_next_state(
+        starting_state: &AuctionState,
+        t: &AuctionTransaction,
+    ) -> Result<AuctionState, AuctionError> {
+        Self::try_next_state_in_context(starting_state, t, &BlockContext::default())
+    }
+
+    fn human_name() -> String {
+        "Sealed-bid auction".into()
+    }
+}
+
+/// The phase every transaction is checked against is determined by the current height.
+impl ContextualStateMachine for SealedBidAuction {
+    fn next_state_in_context(
+        starting_state: &AuctionState,
+        t: &AuctionTransaction,
+        context: &BlockContext,
+    ) -> AuctionState {
+        Self::try_next_state_in_context(starting_state, t, context)
+            .unwrap_or_else(|_| starting_state.clone())
+    }
+
+    fn try_next_state_in_context(
+        starting_state: &AuctionState,
+        t: &AuctionTransaction,
+        context: &BlockContext,
+    ) -> Result<AuctionState, AuctionError> {
+        let height = context.height;
+        let mut s = starting_state.clone();
+        match t {
+            AuctionTransaction::Create {
+                seller,
+                commit_end,
+                reveal_end,
+            } => {
+                if *commit_end <= height || reveal_end <= commit_end {
+                    return Err(AuctionError::InvalidSchedule);
+                }
+                s.auctions.push(Auction {
+                    seller: *seller,
+                    commit_end: *commit_end,
+                    reveal_end: *reveal_end,
+                    bids: HashMap::new(),
+                    reveal_order: Vec::new(),
+                    winner: None,
+                    settled: false,
+                });
+            }
+            AuctionTransaction::Commit {
+                bidder,
+                auction,
+                commitment,
+                deposit,
+            } => {
+                let available = s.balances.get(bidder).copied().unwrap_or(0);
+                let a = auction_in_phase(&mut s, *auction, AuctionPhase::Commit, height)?;
+                if a.seller == *bidder {
+                    return Err(AuctionError::SellerCannotBid);
+                }
+                if a.bids.contains_key(bidder) {
+                    return Err(AuctionError::AlreadyCommitted);
+                }
+                if available < *deposit {
+                    return Err(AuctionError::InsufficientBalance {
+                        available,
+                        requested: *deposit,
+                    });
+                }
+                a.bids.insert(
+                    *bidder,
+                    SealedBid {
+                        commitment: *commitment,
+                        deposit: *deposit,
+                        revealed: None,
+                    },
+                );
+                debit(&mut s, *bidder, *deposit);
+            }
+            AuctionTransaction::Reveal {
+                bidder,
+                auction,
+                bid,
+                salt,
+            } => {
+                let a = auction_in_phase(&mut s, *auction, AuctionPhase::Reveal, height)?;
+                let sealed = a.bids.get_mut(bidder).ok_or(AuctionError::NoCommitment)?;
+                if sealed.revealed.is_some() || sealed.commitment != commitment(*bid, *salt) {
+                    return Err(AuctionError::InvalidReveal);
+                }
+                if *bid > sealed.deposit {
+                    return Err(AuctionError::BidExceedsDeposit {
+                        bid: *bid,
+                        deposit: sealed.deposit,
+                    });
+                }
+                sealed.revealed = Some(*bid);
+                a.reveal_order.push(*bidder);
+            }
+            AuctionTransaction::Settle { auction } => {
+                let a = auction_in_phase(&mut s, *auction, AuctionPhase::Settle, height)?;
+                a.settled = true;
+                a.winner = a.highest_bid();
+                let seller = a.seller;
+                let winner = a.winner;
+                // Unrevealed deposits are forfeited to the seller, the winner gets back whatever
+                // their deposit exceeded their bid by, and everyone else is refunded in full.
+                let mut payouts: Vec<(User, u64)> = a
+                    .bids
+                    .iter()
+                    .map(|(bidder, sealed)| match (sealed.revealed, winner) {
+                        (None, _) => (seller, sealed.deposit),
+                        (Some(_), Some((w, bid))) if w == *bidder => (w, sealed.deposit - bid),
+                        (Some(_), _) => (*bidder, sealed.deposit),
+                    })
+                    .collect();
+                if let Some((_, bid)) = winner {
+                    payouts.push((seller, bid));
+                }
+                for (who, amount) in payouts {
+                    credit(&mut s, who, amount);
+                }
+            }
+        }
+        Ok(s)
+    }
+}
+
+/// Every auction transaction costs the same.
+impl Weighted for SealedBidAuction {
+    fn weight(_: &AuctionTransaction) -> u64 {
+        1
+    }
+}
+
+/// The given auction, provided it is in the given phase at the given height.
+fn auction_in_phase(
+    state: &mut AuctionState,
+    id: AuctionId,
+    expected: AuctionPhase,
+    height: u64,
+) -> Result<&mut Auction, AuctionError> {
+    let auction = state
+        .auctions
+        .get_mut(id)
+        .ok_or(AuctionError::UnknownAuction(id))?;
+    let actual = auction.phase(height);
+    if actual != expected {
+        return Err(AuctionError::WrongPhase { expected, actual });
+    }
+    Ok(auction)
+}
+
+/// Add free funds to an account. No balance can overflow, because no funds are ever created
+/// after genesis.
+fn credit(state: &mut AuctionState, who: User, amount: u64) {
+    if amount > 0 {
+        let balance = state.balances.entry(who).or_insert(0);
+        *balance = balance.saturating_add(amount);
+    }
+}
+
+/// Remove free funds from an account. The caller must have checked the balance.
+fn debit(state: &mut AuctionState, who: User, amount: u64) {
+    if let Some(balance) = state.balances.get_mut(&who) {
+        *balance -= amount;
+        if *balance == 0 {
+            state.balances.remove(&who);
+        }
+    }
+}
+
+#[cfg(test)]
+fn at_height(height: u64) -> BlockContext {
+    BlockContext {
+        height,
+        ..BlockContext::default()
+    }
+}
+
+#[cfg(test)]
+fn open_auction() -> AuctionState {
+    let start = SealedBidAuction::genesis_state(vec![(User::Bob, 100), (User::Charlie, 100)]);
+    let create = AuctionTransaction::Create {
+        seller: User::Alice,
+        commit_end: 10,
+        reveal_end: 20,
+    };
+    SealedBidAuction::try_next_state(&start, &create).unwrap()
+}
+
+#[cfg(test)]
+fn commit(bidder: User, bid: u64, deposit: u64) -> AuctionTransaction {
+    AuctionTransaction::Commit {
+        bidder,
+        auction: 0,
+        commitment: commitment(bid, 42),
+        deposit,
+    }
+}
+
+#[cfg(test)]
+fn reveal(bidder: User, bid: u64) -> AuctionTransaction {
+    AuctionTransaction::Reveal {
+        bidder,
+        auction: 0,
+        bid,
+        salt: 42,
+    }
+}
+
+#[cfg(test)]
+fn run(state: &AuctionState, ts: &[AuctionTransaction], height: u64) -> AuctionState {
+    SealedBidAuction::try_apply_all_in_context(state, ts, &at_height(height)).unwrap()
+}
+
+#[test]
+fn sm_10_highest_revealed_bid_wins() {
+    let committed = run(
+        &open_auction(),
+        &[commit(User::Bob, 30, 50), commit(User::Charlie, 40, 40)],
+        5,
+    );
+    assert_eq!(committed.balances.get(&User::Bob), Some(&50));
+    assert_eq!(committed.balances.get(&User::Charlie), Some(&60));
+
+    let revealed = run(
+        &committed,
+        &[reveal(User::Bob, 30), reveal(User::Charlie, 40)],
+        15,
+    );
+    let settled = run(&revealed, &[AuctionTransaction::Settle { auction: 0 }], 20);
+
+    assert_eq!(settled.auctions[0].winner, Some((User::Charlie, 40)));
+    assert_eq!(settled.balances.get(&User::Alice), Some(&40));
+    assert_eq!(settled.balances.get(&User::Bob), Some(&100));
+    assert_eq!(settled.balances.get(&User::Charlie), Some(&60));
+}
+
+#[test]
+fn sm_10_unrevealed_deposit_is_forfeited() {
+    let committed = run(
+        &open_auction(),
+        &[commit(User::Bob, 30, 50), commit(User::Charlie, 40, 40)],
+        5,
+    );
+    let revealed = run(&committed, &[reveal(User::Bob, 30)], 15);
+    let settled = run(&revealed, &[AuctionTransaction::Settle { auction: 0 }], 25);
+
+    assert_eq!(settled.auctions[0].winner, Some((User::Bob, 30)));
+    assert_eq!(settled.balances.get(&User::Alice), Some(&70));
+    assert_eq!(settled.balances.get(&User::Bob), Some(&70));
+    assert_eq!(settled.balances.get(&User::Charlie), Some(&60));
+}
+
+#[test]
+fn sm_10_reveal_must_match_commitment() {
+    let committed = run(&open_auction(), &[commit(User::Bob, 30, 50)], 5);
+
+    assert_eq!(
+        SealedBidAuction::try_next_state_in_context(
+            &committed,
+            &reveal(User::Bob, 31),
+            &at_height(15)
+        ),
+        Err(AuctionError::InvalidReveal)
+    );
+}
+
+#[test]
+fn sm_10_bid_must_be_covered_by_deposit() {
+    let committed = run(&open_auction(), &[commit(User::Bob, 60, 50)], 5);
+
+    assert_eq!(
+        SealedBidAuction::try_next_state_in_context(
+            &committed,
+            &reveal(User::Bob, 60),
+            &at_height(15)
+        ),
+        Err(AuctionError::BidExceedsDeposit {
+            bid: 60,
+            deposit: 50
+        })
+    );
+}
+
+#[test]
+fn sm_10_phases_are_enforced() {
+    let state = open_auction();
+
+    assert_eq!(
+        SealedBidAuction::try_next_state_in_context(
+            &state,
+            &commit(User::Bob, 30, 50),
+            &at_height(10)
+        ),
+        Err(AuctionError::WrongPhase {
+            expected: AuctionPhase::Commit,
+            actual: AuctionPhase::Reveal
+        })
+    );
+    assert_eq!(
+        SealedBidAuction::try_next_state_in_context(
+            &state,
+            &AuctionTransaction::Settle { auction: 0 },
+            &at_height(19)
+        ),
+        Err(AuctionError::WrongPhase {
+            expected: AuctionPhase::Settle,
+            actual: AuctionPhase::Reveal
+        })
+    );
+
+    let settled = run(&state, &[AuctionTransaction::Settle { auction: 0 }], 20);
+    assert_eq!(settled.auctions[0].phase(20), AuctionPhase::Settled);
+    assert_eq!(settled.auctions[0].winner, None);
+}
+
+#[test]
+fn sm_10_commit_rules() {
+    let state = open_auction();
+
+    assert_eq!(
+        SealedBidAuction::try_next_state(&state, &commit(User::Alice, 1, 0)),
+        Err(AuctionError::SellerCannotBid)
+    );
+    assert_eq!(
+        SealedBidAuction::try_next_state(&state, &commit(User::Bob, 1, 101)),
+        Err(AuctionError::InsufficientBalance {
+            available: 100,
+            requested: 101
+        })
+    );
+    let committed = SealedBidAuction::next_state(&state, &commit(User::Bob, 1, 1));
+    assert_eq!(
+        SealedBidAuction::try_next_state(&committed, &commit(User::Bob, 2, 2)),
+        Err(AuctionError::AlreadyCommitted)
+    );
+}
@@ -0,0 +1,499 @@
+//! Account identifiers are hard for humans to remember, so many chains run a name service that
+//! maps readable names like "alice" to accounts, much like DNS maps domain names to addresses.
+//!
+//! Names are not owned forever. A registration lasts a fixed number of blocks, after which the
+//! name stops resolving. The owner then has a grace period during which only they may renew it,
+//! so that forgetting to renew on time does not immediately hand the name to a squatter. Only
+//! once the grace period is over can somebody else register the name.
+//!
+//! Registering or renewing a name costs a fee, which is burned rather than paid to anyone.
+//! Burning makes holding many names expensive without giving anyone an incentive to keep
+//! names scarce.
+
+use std::collections::HashMap;
+
+use super::{BlockContext, ContextualStateMachine, StateMachine, User, Weighted};
+use crate::codec::{Decode, Encode};
+
+/// The number of blocks a registration or renewal lasts
+pub const REGISTRATION_PERIOD: u64 = 100;
+
+/// The number of blocks after expiry during which only the previous owner may renew a name
+pub const GRACE_PERIOD: u64 = 20;
+
+/// The fee, burned on every registration and renewal
+pub const FEE: u64 = 10;
+
+/// The longest name that may be registered, in bytes
+pub const MAX_NAME_LENGTH: usize = 32;
+
+/// This state machine models a name registry.
+pub struct NameService;
+
+/// A registered name
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NameRecord {
+    pub owner: User,
+    /// The account the name resolves to, which need not be the owner
+    pub target: User,
+    /// The first block height at which the name no longer resolves
+    pub expires_at: u64,
+}
+
+impl NameRecord {
+    /// Whether the name resolves at the given height
+    pub fn is_active(&self, height: u64) -> bool {
+        height < self.expires_at
+    }
+
+    /// Whether someone other than the owner may register the name at the given height
+    pub fn is_available(&self, height: u64) -> bool {
+        height >= self.expires_at.saturating_add(GRACE_PERIOD)
+    }
+}
+
+/// The state of the name service
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NameServiceState {
+    /// Funds available to pay fees. Zero balances are not stored.
+    pub balances: HashMap<User, u64>,
+    /// Every name that has ever been registered. Names are not removed when they expire, only
+    /// overwritten when somebody registers them again.
+    pub names: HashMap<String, NameRecord>,
+    /// The total fees burned so far
+    pub burned: u64,
+}
+
+/// The maps are hashed in key order, so that equal states hash alike, and chains can commit to
+/// them.
+impl core::hash::Hash for NameServiceState {
+    fn hash<H: core::hash::Hasher>(&self, hasher: &mut H) {
+        let mut balances: Vec<_> = self.balances.iter().collect();
+        balances.sort();
+        balances.hash(hasher);
+        let mut names: Vec<_> = self.names.iter().collect();
+        names.sort_by_key(|(name, _)| *name);
+        names.hash(hasher);
+        self.burned.hash(hasher);
+    }
+}
+
+impl NameServiceState {
+    /// The account the given name resolves to at the given height, if any
+    pub fn resolve(&self, name: &str, height: u64) -> Option<User> {
+        self.names
+            .get(name)
+            .filter(|record| record.is_active(height))
+            .map(|record| record.target)
+    }
+}
+
+/// The state transitions that users can make in the name service
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NameTransaction {
+    /// Register an available name, resolving to the registrant
+    Register { who: User, name: String },
+    /// Extend the owner's registration by another period
+    Renew { who: User, name: String },
+    /// Hand the name over to another owner. The target is left unchanged.
+    Transfer { who: User, name: String, to: User },
+    /// Point the name at a different account
+    SetTarget {
+        who: User,
+        name: String,
+        target: User,
+    },
+}
+
+/// The reasons a transaction may be rejected by the name service
+/// A tag byte for the variant, followed by its fields in the order they are declared.
+impl Encode for NameTransaction {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        match self {
+            NameTransaction::Register { who, name } => {
+                dest.push(0);
+                who.encode_to(dest);
+                name.encode_to(dest);
+            }
+            NameTransaction::Renew { who, name } => {
+                dest.push(1);
+                who.encode_to(dest);
+                name.encode_to(dest);
+            }
+            NameTransaction::Transfer { who, name, to } => {
+                dest.push(2);
+                who.encode_to(dest);
+                name.encode_to(dest);
+                to.encode_to(dest);
+            }
+            NameTransaction::SetTarget { who, name, target } => {
+                dest.push(3);
+                who.encode_to(dest);
+                name.encode_to(dest);
+                target.encode_to(dest);
+            }
+        }
+    }
+}
+
+impl Decode for NameTransaction {
+    fn decode_from(input: &mut &[u8]) -> Option<Self> {
+        match u8::decode_from(input)? {
+            0 => Some(NameTransaction::Register {
+                who: User::decode_from(input)?,
+                name: String::decode_from(input)?,
+            }),
+            1 => Some(NameTransaction::Renew {
+                who: User::decode_from(input)?,
+                name: String::decode_from(input)?,
+            }),
+            2 => Some(NameTransaction::Transfer {
+                who: User::decode_from(input)?,
+                name: String::decode_from(input)?,
+                to: User::decode_from(input)?,
+            }),
+            3 => Some(NameTransaction::SetTarget {
+                who: User::decode_from(input)?,
+                name: String::decode_from(input)?,
+                target: User::decode_from(input)?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NameError {
+    /// Names must be non-empty, at most `MAX_NAME_LENGTH` bytes long, and consist only of
+    /// lowercase ascii letters, digits, and hyphens
+    InvalidName,
+    /// The name is registered, or in its grace period, until the given height
+    NameTaken { available_at: u64 },
+    /// Nobody has ever registered this name
+    UnknownName,
+    /// Only the owner may manage a name
+    NotOwner,
+    /// The registration has expired. If it is still in its grace period, the owner may renew it.
+    Expired,
+    /// The registration's grace period has passed, so it can no longer be renewed
+    GracePeriodOver,
+    /// The user cannot afford the fee
+    InsufficientBalance { available: u64, requested: u64 },
+}
+
+impl StateMachine for NameService {
+    type State = NameServiceState;
+    type Transition = NameTransaction;
+    type Error = NameError;
+    /// The initial funds of each user
+    type GenesisConfig = Vec<(User, u64)>;
+
+    fn genesis_state(endowments: Vec<(User, u64)>) -> NameServiceState {
+        let mut state = NameServiceState::default();
+        for (who, amount) in endowments {
+            if amount > 0 {
+                let balance = state.balances.entry(who).or_insert(0);
+                *balance = balance.saturating_add(amount);
+            }
+        }
+        state
+    }
+
+    fn next_state(starting_state: &NameServiceState, t: &NameTransaction) -> NameServiceState {
+        Self::next_state_in_context(starting_state, t, &BlockContext::default())
+    }
+
+    fn try_next_state(
+        starting_state: &NameServiceState,
+        t: &NameTransaction,
+    ) -> Result<NameServiceState, NameError> {
+        Self::try_next_state_in_context(starting_state, t, &BlockContext::default())
+    }
+
+    fn human_name() -> String {
+        "Name service".into()
+    }
+}
+
+/// Expiry and grace periods are checked against the current height.
+impl ContextualStateMachine for NameService {
+    fn next_state_in_context(
+        starting_state: &NameServiceState,
+        t: &NameTransaction,
+        context: &BlockContext,
+    ) -> NameServiceState {
+        Self::try_next_state_in_context(starting_state, t, context)
+            .unwrap_or_else(|_| starting_state.clone())
+    }
+
+    fn try_next_state_in_context(
+        starting_state: &NameServiceState,
+        t: &NameTransaction,
+        context: &BlockContext,
+    ) -> Result<NameServiceState, NameError> {
+        let height = context.height;
+        let mut s = starting_state.clone();
+        match t {
+            NameTransaction::Register { who, name } => {
+                if !is_valid_name(name) {
+                    return Err(NameError::InvalidName);
+                }
+                if let Some(record) = s.names.get(name) {
+                    if !record.is_available(height) {
+                        return Err(NameError::NameTaken {
+                            available_at: record.expires_at.saturating_add(GRACE_PERIOD),
+                        });
+                    }
+                }
+                burn_fee(&mut s, *who)?;
+                s.names.insert(
+                    name.clone(),
+                    NameRecord {
+                        owner: *who,
+                        target: *who,
+                        expires_at: height.saturating_add(REGISTRATION_PERIOD),
+                    },
+                );
+            }
+            NameTransaction::Renew { who, name } => {
+                let record = owned_record(&s, name, *who)?;
+                if record.is_available(height) {
+                    return Err(NameError::GracePeriodOver);
+                }
+                // Renewing late does not gain any blocks, but renewing early does not lose any.
+                let expires_at = record
+                    .expires_at
+                    .max(height)
+                    .saturating_add(REGISTRATION_PERIOD);
+                burn_fee(&mut s, *who)?;
+                if let Some(record) = s.names.get_mut(name) {
+                    record.expires_at = expires_at;
+                }
+            }
+            NameTransaction::Transfer { who, name, to } => {
+                active_record(&mut s, name, *who, height)?.owner = *to;
+            }
+            NameTransaction::SetTarget { who, name, target } => {
+                active_record(&mut s, name, *who, height)?.target = *target;
+            }
+        }
+        Ok(s)
+    }
+}
+
+/// Every name service transaction costs the same.
+impl Weighted for NameService {
+    fn weight(_: &NameTransaction) -> u64 {
+        1
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+}
+
+/// The record of the given name, provided it is owned by the given user.
+fn owned_record<'a>(
+    state: &'a NameServiceState,
+    name: &str,
+    who: User,
+) -> Result<&'a NameRecord, NameError> {
+    let record = state.names.get(name).ok_or(NameError::UnknownName)?;
+    if record.owner != who {
+        return Err(NameError::NotOwner);
+    }
+    Ok(record)
+}
+
+/// The record of the given name, provided it is owned by the given user and has not expired.
+fn active_record<'a>(
+    state: &'a mut NameServiceState,
+    name: &str,
+    who: User,
+    height: u64,
+) -> Result<&'a mut NameRecord, NameError> {
+    if !owned_record(state, name, who)?.is_active(height) {
+        return Err(NameError::Expired);
+    }
+    state.names.get_mut(name).ok_or(NameError::UnknownName)
+}
+
+/// Take the fee from the given user and burn it.
+fn burn_fee(state: &mut NameServiceState, who: User) -> Result<(), NameError> {
+    let available = state.balances.get(&who).copied().unwrap_or(0);
+    if available < FEE {
+        return Err(NameError::InsufficientBalance {
+            available,
+            requested: FEE,
+        });
+    }
+    if available == FEE {
+        state.balances.remove(&who);
+    } else {
+        state.balances.insert(who, available - FEE);
+    }
+    state.burned = state.burned.saturating_add(FEE);
+    Ok(())
+}
+
+#[cfg(test)]
+fn at_height(height: u64) -> BlockContext {
+    BlockContext {
+        height,
+        ..BlockContext::default()
+    }
+}
+
+#[cfg(test)]
+fn register(who: User) -> NameTransaction {
+    NameTransaction::Register {
+        who,
+        name: "alice".into(),
+    }
+}
+
+#[cfg(test)]
+fn renew(who: User) -> NameTransaction {
+    NameTransaction::Renew {
+        who,
+        name: "alice".into(),
+    }
+}
+
+#[cfg(test)]
+fn registered() -> NameServiceState {
+    let start = NameService::genesis_state(vec![(User::Alice, 30), (User::Bob, 30)]);
+    NameService::try_next_state(&start, &register(User::Alice)).unwrap()
+}
+
+#[test]
+fn sm_11_register_burns_fee_and_resolves() {
+    let state = registered();
+
+    assert_eq!(state.balances.get(&User::Alice), Some(&20));
+    assert_eq!(state.burned, FEE);
+    assert_eq!(state.resolve("alice", 0), Some(User::Alice));
+    assert_eq!(state.resolve("alice", REGISTRATION_PERIOD), None);
+    assert_eq!(state.resolve("bob", 0), None);
+}
+
+#[test]
+fn sm_11_invalid_names() {
+    let start = NameService::genesis_state(vec![(User::Alice, 30)]);
+    for name in ["", "Alice", "al ice", &"a".repeat(MAX_NAME_LENGTH + 1)] {
+        let t = NameTransaction::Register {
+            who: User::Alice,
+            name: name.into(),
+        };
+        assert_eq!(
+            NameService::try_next_state(&start, &t),
+            Err(NameError::InvalidName)
+        );
+    }
+}
+
+#[test]
+fn sm_11_name_is_reserved_during_grace_period() {
+    let state = registered();
+    let available_at = REGISTRATION_PERIOD + GRACE_PERIOD;
+
+    assert_eq!(
+        NameService::try_next_state_in_context(
+            &state,
+            &register(User::Bob),
+            &at_height(available_at - 1)
+        ),
+        Err(NameError::NameTaken { available_at })
+    );
+
+    let taken = NameService::try_next_state_in_context(
+        &state,
+        &register(User::Bob),
+        &at_height(available_at),
+    )
+    .unwrap();
+    assert_eq!(taken.resolve("alice", available_at), Some(User::Bob));
+}
+
+#[test]
+fn sm_11_renew_extends_expiry() {
+    let state = registered();
+
+    let early = NameService::try_next_state_in_context(&state, &renew(User::Alice), &at_height(50))
+        .unwrap();
+    assert_eq!(early.names["alice"].expires_at, 2 * REGISTRATION_PERIOD);
+
+    let late = NameService::try_next_state_in_context(
+        &state,
+        &renew(User::Alice),
+        &at_height(REGISTRATION_PERIOD + 5),
+    )
+    .unwrap();
+    assert_eq!(late.names["alice"].expires_at, 2 * REGISTRATION_PERIOD + 5);
+    assert_eq!(late.burned, 2 * FEE);
+
+    assert_eq!(
+        NameService::try_next_state_in_context(
+            &state,
+            &renew(User::Alice),
+            &at_height(REGISTRATION_PERIOD + GRACE_PERIOD)
+        ),
+        Err(NameError::GracePeriodOver)
+    );
+    assert_eq!(
+        NameService::try_next_state(&state, &renew(User::Bob)),
+        Err(NameError::NotOwner)
+    );
+}
+
+#[test]
+fn sm_11_transfer_and_set_target() {
+    let ts = [
+        NameTransaction::SetTarget {
+            who: User::Alice,
+            name: "alice".into(),
+            target: User::Charlie,
+        },
+        NameTransaction::Transfer {
+            who: User::Alice,
+            name: "alice".into(),
+            to: User::Bob,
+        },
+    ];
+    let state = NameService::try_apply_all(&registered(), &ts).unwrap();
+
+    assert_eq!(state.names["alice"].owner, User::Bob);
+    assert_eq!(state.resolve("alice", 0), Some(User::Charlie));
+    assert_eq!(
+        NameService::try_next_state_in_context(&state, &ts[0], &at_height(REGISTRATION_PERIOD)),
+        Err(NameError::NotOwner)
+    );
+    assert_eq!(
+        NameService::try_next_state_in_context(
+            &registered(),
+            &ts[1],
+            &at_height(REGISTRATION_PERIOD)
+        ),
+        Err(NameError::Expired)
+    );
+}
+
+#[test]
+fn sm_11_register_requires_fee() {
+    let start = NameService::genesis_state(vec![(User::Alice, FEE - 1)]);
+
+    assert_eq!(
+        NameService::try_next_state(&start, &register(User::Alice)),
+        Err(NameError::InsufficientBalance {
+            available: FEE - 1,
+            requested: FEE
+        })
+    );
+}
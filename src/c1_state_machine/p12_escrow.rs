@@ -0,0 +1,476 @@
+//! When strangers trade, somebody has to go first. If the buyer pays up front the seller may never
+//! deliver, and if the seller delivers first the buyer may never pay. An escrow solves this by
+//! holding the buyer's payment until the trade is complete.
+//!
+//! The buyer opens an escrow and funds it, naming the seller and an arbiter both parties trust.
+//! The seller marks the goods as delivered, and the buyer releases the funds to the seller. If
+//! either party is unhappy they may raise a dispute, after which only the arbiter can decide how
+//! the funds are split between them.
+//!
+//! Neither party can hold the funds hostage by going silent. Every escrow has a deadline, after
+//! which anyone may close it: undisputed escrows pay the seller if they delivered, and refund the
+//! buyer if not. Disputed escrows wait for the arbiter.
+
+use std::collections::HashMap;
+
+use super::{BlockContext, ContextualStateMachine, StateMachine, User, Weighted};
+
+/// This state machine models escrowed payments with arbitration.
+pub struct Escrow;
+
+/// Escrows are numbered in the order they were opened, starting from 0.
+pub type EscrowId = usize;
+
+/// The lifecycle of an escrow
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EscrowStatus {
+    /// The buyer has paid and is waiting for delivery
+    Funded,
+    /// The seller claims to have delivered
+    Delivered,
+    /// One of the parties has raised a dispute for the arbiter to resolve
+    Disputed,
+    /// The funds have been paid out, the given amount of them to the seller and the rest back to
+    /// the buyer
+    Closed { to_seller: u64 },
+}
+
+/// A single escrowed payment
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EscrowAccount {
+    pub buyer: User,
+    pub seller: User,
+    pub arbiter: User,
+    pub amount: u64,
+    /// The first block height at which an undisputed escrow may be closed by anyone
+    pub deadline: u64,
+    pub status: EscrowStatus,
+}
+
+/// The state of the escrow service
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EscrowState {
+    /// Funds not held in any escrow. Zero balances are not stored.
+    pub balances: HashMap<User, u64>,
+    pub escrows: Vec<EscrowAccount>,
+}
+
+/// The state transitions that users can make in the escrow service
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EscrowTransaction {
+    /// The buyer opens a new escrow, moving the amount out of their balance
+    Open {
+        buyer: User,
+        seller: User,
+        arbiter: User,
+        amount: u64,
+        deadline: u64,
+    },
+    /// The seller declares the goods delivered
+    Deliver { seller: User, escrow: EscrowId },
+    /// The buyer pays the whole amount to the seller
+    Release { buyer: User, escrow: EscrowId },
+    /// The buyer or the seller asks the arbiter to step in
+    Dispute { who: User, escrow: EscrowId },
+    /// The arbiter pays the given amount to the seller and refunds the rest to the buyer
+    Resolve {
+        arbiter: User,
+        escrow: EscrowId,
+        to_seller: u64,
+    },
+    /// Close an undisputed escrow whose deadline has passed. Anyone may do this.
+    Timeout { escrow: EscrowId },
+}
+
+/// The reasons a transaction may be rejected by the escrow service
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EscrowError {
+    /// The buyer, seller, and arbiter must be three different users
+    PartiesNotDistinct,
+    /// The deadline must not have passed already
+    InvalidDeadline,
+    /// The buyer does not have enough free funds
+    InsufficientBalance { available: u64, requested: u64 },
+    /// No escrow with this id exists
+    UnknownEscrow(EscrowId),
+    /// The user does not play the role this transaction requires
+    NotAuthorized,
+    /// The escrow's status does not allow this transaction
+    WrongStatus(EscrowStatus),
+    /// Parties may no longer act on their own once the deadline has passed
+    DeadlinePassed,
+    /// The escrow cannot time out before its deadline
+    DeadlineNotReached { deadline: u64 },
+    /// The arbiter cannot pay out more than was escrowed
+    SplitExceedsAmount { to_seller: u64, amount: u64 },
+}
+
+impl StateMachine for Escrow {
+    type State = EscrowState;
+    type Transition = EscrowTransaction;
+    type Error = EscrowError;
+    /// The initial free funds of each user
+    type GenesisConfig = Vec<(User, u64)>;
+
+    fn genesis_state(endowments: Vec<(User, u64)>) -> EscrowState {
+        let mut state = EscrowState::default();
+        for (who, amount) in endowments {
+            credit(&mut state, who, amount);
+        }
+        state
+    }
+
+    fn next_state(starting_state: &EscrowState, t: &EscrowTransaction) -> EscrowState {
+        Self::next_state_in_context(starting_state, t, &BlockContext::default())
+    }
+
+    fn try_next_state(
+        starting_state: &EscrowState,
+        t: &EscrowTransaction,
+    ) -> Result<EscrowState, EscrowError> {
+        Self::try_next_state_in_context(starting_state, t, &BlockContext::default())
+    }
+
+    fn human_name() -> String {
+        "Escrow".into()
+    }
+}
+
+/// Deadlines are checked against the current height.
+impl ContextualStateMachine for Escrow {
+    fn next_state_in_context(
+        starting_state: &EscrowState,
+        t: &EscrowTransaction,
+        context: &BlockContext,
+    ) -> EscrowState {
+        Self::try_next_state_in_context(starting_state, t, context)
+            .unwrap_or_else(|_| starting_state.clone())
+    }
+
+    fn try_next_state_in_context(
+        starting_state: &EscrowState,
+        t: &EscrowTransaction,
+        context: &BlockContext,
+    ) -> Result<EscrowState, EscrowError> {
+        let height = context.height;
+        let mut s = starting_state.clone();
+        match t {
+            EscrowTransaction::Open {
+                buyer,
+                seller,
+                arbiter,
+                amount,
+                deadline,
+            } => {
+                if buyer == seller || arbiter == buyer || arbiter == seller {
+                    return Err(EscrowError::PartiesNotDistinct);
+                }
+                if *deadline <= height {
+                    return Err(EscrowError::InvalidDeadline);
+                }
+                let available = s.balances.get(buyer).copied().unwrap_or(0);
+                if available < *amount {
+                    return Err(EscrowError::InsufficientBalance {
+                        available,
+                        requested: *amount,
+                    });
+                }
+                debit(&mut s, *buyer, *amount);
+                s.escrows.push(EscrowAccount {
+                    buyer: *buyer,
+                    seller: *seller,
+                    arbiter: *arbiter,
+                    amount: *amount,
+                    deadline: *deadline,
+                    status: EscrowStatus::Funded,
+                });
+            }
+            EscrowTransaction::Deliver { seller, escrow } => {
+                let e = escrow_before_deadline(&mut s, *escrow, height)?;
+                if e.seller != *seller {
+                    return Err(EscrowError::NotAuthorized);
+                }
+                if e.status != EscrowStatus::Funded {
+                    return Err(EscrowError::WrongStatus(e.status));
+                }
+                e.status = EscrowStatus::Delivered;
+            }
+            EscrowTransaction::Release { buyer, escrow } => {
+                let e = escrow_before_deadline(&mut s, *escrow, height)?;
+                if e.buyer != *buyer {
+                    return Err(EscrowError::NotAuthorized);
+                }
+                if !matches!(e.status, EscrowStatus::Funded | EscrowStatus::Delivered) {
+                    return Err(EscrowError::WrongStatus(e.status));
+                }
+                let to_seller = e.amount;
+                close(&mut s, *escrow, to_seller);
+            }
+            EscrowTransaction::Dispute { who, escrow } => {
+                let e = escrow_before_deadline(&mut s, *escrow, height)?;
+                if e.buyer != *who && e.seller != *who {
+                    return Err(EscrowError::NotAuthorized);
+                }
+                if !matches!(e.status, EscrowStatus::Funded | EscrowStatus::Delivered) {
+                    return Err(EscrowError::WrongStatus(e.status));
+                }
+                e.status = EscrowStatus::Disputed;
+            }
+            EscrowTransaction::Resolve {
+                arbiter,
+                escrow,
+                to_seller,
+            } => {
+                // The arbiter is not bound by the deadline, otherwise a dispute raised just
+                // before it could never be resolved.
+                let e = s
+                    .escrows
+                    .get(*escrow)
+                    .ok_or(EscrowError::UnknownEscrow(*escrow))?;
+                if e.arbiter != *arbiter {
+                    return Err(EscrowError::NotAuthorized);
+                }
+                if e.status != EscrowStatus::Disputed {
+                    return Err(EscrowError::WrongStatus(e.status));
+                }
+                if *to_seller > e.amount {
+                    return Err(EscrowError::SplitExceedsAmount {
+                        to_seller: *to_seller,
+                        amount: e.amount,
+                    });
+                }
+                close(&mut s, *escrow, *to_seller);
+            }
+            EscrowTransaction::Timeout { escrow } => {
+                let e = s
+                    .escrows
+                    .get(*escrow)
+                    .ok_or(EscrowError::UnknownEscrow(*escrow))?;
+                if height < e.deadline {
+                    return Err(EscrowError::DeadlineNotReached {
+                        deadline: e.deadline,
+                    });
+                }
+                let to_seller = match e.status {
+                    EscrowStatus::Funded => 0,
+                    EscrowStatus::Delivered => e.amount,
+                    status => return Err(EscrowError::WrongStatus(status)),
+                };
+                close(&mut s, *escrow, to_seller);
+            }
+        }
+        Ok(s)
+    }
+}
+
+/// Every escrow transaction costs the same.
+impl Weighted for Escrow {
+    fn weight(_: &EscrowTransaction) -> u64 {
+        1
+    }
+}
+
+/// The given escrow, provided its deadline has not passed at the given height.
+fn escrow_before_deadline(
+    state: &mut EscrowState,
+    id: EscrowId,
+    height: u64,
+) -> Result<&mut EscrowAccount, EscrowError> {
+    let escrow = state
+        .escrows
+        .get_mut(id)
+        .ok_or(EscrowError::UnknownEscrow(id))?;
+    if height >= escrow.deadline {
+        return Err(EscrowError::DeadlinePassed);
+    }
+    Ok(escrow)
+}
+
+/// Pay out an escrow, the given amount to the seller and the rest to the buyer. The caller must
+/// have checked that the escrow exists, is still open, and holds at least the given amount.
+fn close(state: &mut EscrowState, id: EscrowId, to_seller: u64) {
+    let escrow = &mut state.escrows[id];
+    escrow.status = EscrowStatus::Closed { to_seller };
+    let (buyer, seller, to_buyer) = (escrow.buyer, escrow.seller, escrow.amount - to_seller);
+    credit(state, seller, to_seller);
+    credit(state, buyer, to_buyer);
+}
+
+/// Add free funds to an account. No balance can overflow, because no funds are ever created
+/// after genesis.
+fn credit(state: &mut EscrowState, who: User, amount: u64) {
+    if amount > 0 {
+        let balance = state.balances.entry(who).or_insert(0);
+        *balance = balance.saturating_add(amount);
+    }
+}
+
+/// Remove free funds from an account. The caller must have checked the balance.
+fn debit(state: &mut EscrowState, who: User, amount: u64) {
+    if let Some(balance) = state.balances.get_mut(&who) {
+        *balance -= amount;
+        if *balance == 0 {
+            state.balances.remove(&who);
+        }
+    }
+}
+
+#[cfg(test)]
+fn at_height(height: u64) -> BlockContext {
+    BlockContext {
+        height,
+        ..BlockContext::default()
+    }
+}
+
+#[cfg(test)]
+fn funded() -> EscrowState {
+    let start = Escrow::genesis_state(vec![(User::Alice, 100)]);
+    let open = EscrowTransaction::Open {
+        buyer: User::Alice,
+        seller: User::Bob,
+        arbiter: User::Charlie,
+        amount: 60,
+        deadline: 10,
+    };
+    Escrow::try_next_state(&start, &open).unwrap()
+}
+
+#[cfg(test)]
+fn deliver() -> EscrowTransaction {
+    EscrowTransaction::Deliver {
+        seller: User::Bob,
+        escrow: 0,
+    }
+}
+
+#[test]
+fn sm_12_open_moves_funds_into_escrow() {
+    let state = funded();
+
+    assert_eq!(state.balances.get(&User::Alice), Some(&40));
+    assert_eq!(state.escrows[0].status, EscrowStatus::Funded);
+
+    let too_much = EscrowTransaction::Open {
+        buyer: User::Alice,
+        seller: User::Bob,
+        arbiter: User::Charlie,
+        amount: 41,
+        deadline: 10,
+    };
+    assert_eq!(
+        Escrow::try_next_state(&state, &too_much),
+        Err(EscrowError::InsufficientBalance {
+            available: 40,
+            requested: 41
+        })
+    );
+}
+
+#[test]
+fn sm_12_open_requires_distinct_parties_and_future_deadline() {
+    let start = Escrow::genesis_state(vec![(User::Alice, 100)]);
+    let open = |arbiter, deadline| EscrowTransaction::Open {
+        buyer: User::Alice,
+        seller: User::Bob,
+        arbiter,
+        amount: 10,
+        deadline,
+    };
+
+    assert_eq!(
+        Escrow::try_next_state(&start, &open(User::Bob, 10)),
+        Err(EscrowError::PartiesNotDistinct)
+    );
+    assert_eq!(
+        Escrow::try_next_state_in_context(&start, &open(User::Charlie, 10), &at_height(10)),
+        Err(EscrowError::InvalidDeadline)
+    );
+}
+
+#[test]
+fn sm_12_happy_path_pays_seller() {
+    let release = EscrowTransaction::Release {
+        buyer: User::Alice,
+        escrow: 0,
+    };
+    let state = Escrow::try_apply_all(&funded(), &[deliver(), release.clone()]).unwrap();
+
+    assert_eq!(state.balances.get(&User::Bob), Some(&60));
+    assert_eq!(
+        state.escrows[0].status,
+        EscrowStatus::Closed { to_seller: 60 }
+    );
+    assert_eq!(
+        Escrow::try_next_state(&state, &release),
+        Err(EscrowError::WrongStatus(EscrowStatus::Closed {
+            to_seller: 60
+        }))
+    );
+}
+
+#[test]
+fn sm_12_arbiter_splits_disputed_funds() {
+    let dispute = EscrowTransaction::Dispute {
+        who: User::Alice,
+        escrow: 0,
+    };
+    let disputed = Escrow::try_apply_all(&funded(), &[deliver(), dispute]).unwrap();
+    let resolve = |arbiter, to_seller| EscrowTransaction::Resolve {
+        arbiter,
+        escrow: 0,
+        to_seller,
+    };
+
+    assert_eq!(
+        Escrow::try_next_state(&disputed, &resolve(User::Bob, 60)),
+        Err(EscrowError::NotAuthorized)
+    );
+    assert_eq!(
+        Escrow::try_next_state(&disputed, &resolve(User::Charlie, 61)),
+        Err(EscrowError::SplitExceedsAmount {
+            to_seller: 61,
+            amount: 60
+        })
+    );
+
+    // Disputes do not time out, but the arbiter may still resolve them after the deadline.
+    assert_eq!(
+        Escrow::try_next_state_in_context(
+            &disputed,
+            &EscrowTransaction::Timeout { escrow: 0 },
+            &at_height(10)
+        ),
+        Err(EscrowError::WrongStatus(EscrowStatus::Disputed))
+    );
+    let resolved =
+        Escrow::try_next_state_in_context(&disputed, &resolve(User::Charlie, 20), &at_height(50))
+            .unwrap();
+    assert_eq!(resolved.balances.get(&User::Alice), Some(&80));
+    assert_eq!(resolved.balances.get(&User::Bob), Some(&20));
+}
+
+#[test]
+fn sm_12_timeout_releases_funds() {
+    let timeout = EscrowTransaction::Timeout { escrow: 0 };
+
+    assert_eq!(
+        Escrow::try_next_state_in_context(&funded(), &timeout, &at_height(9)),
+        Err(EscrowError::DeadlineNotReached { deadline: 10 })
+    );
+
+    let refunded = Escrow::try_next_state_in_context(&funded(), &timeout, &at_height(10)).unwrap();
+    assert_eq!(refunded.balances.get(&User::Alice), Some(&100));
+
+    let delivered = Escrow::try_next_state(&funded(), &deliver()).unwrap();
+    let paid = Escrow::try_next_state_in_context(&delivered, &timeout, &at_height(10)).unwrap();
+    assert_eq!(paid.balances.get(&User::Bob), Some(&60));
+    assert_eq!(
+        Escrow::try_next_state_in_context(&funded(), &deliver(), &at_height(10)),
+        Err(EscrowError::DeadlinePassed)
+    );
+}
@@ -0,0 +1,396 @@
+//! Tokens granted to a project's team or investors are usually not handed over all at once.
+//! Instead they vest over time, so that recipients stay invested in the project's future. This
+//! machine locks up grants in vesting schedules that release funds linearly with block height.
+//!
+//! Nothing vests before the schedule's cliff. From then on the vested amount grows as if it had
+//! been vesting linearly since the start, until the whole grant has vested at the end of the
+//! schedule. A schedule with no duration is a plain timelock, releasing everything at once.
+//!
+//! Beneficiaries claim vested funds whenever they like. Grantors may revoke a schedule, taking
+//! back whatever has not vested yet, but never what already has.
+
+use std::collections::HashMap;
+
+use super::{BlockContext, ContextualStateMachine, StateMachine, User, Weighted};
+
+/// This state machine models grants that vest over time.
+pub struct Vesting;
+
+/// Schedules are numbered in the order they were created, starting from 0.
+pub type ScheduleId = usize;
+
+/// A grant that vests linearly over a range of block heights
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VestingSchedule {
+    pub grantor: User,
+    pub beneficiary: User,
+    pub total: u64,
+    /// The height vesting is measured from
+    pub start: u64,
+    /// The number of blocks after the start before anything vests
+    pub cliff: u64,
+    /// The number of blocks after the start at which the whole grant has vested
+    pub duration: u64,
+    /// How much the beneficiary has claimed so far
+    pub claimed: u64,
+    /// The height at which the grantor revoked the schedule, after which nothing more vests
+    pub revoked_at: Option<u64>,
+}
+
+impl VestingSchedule {
+    /// How much of the grant has vested at the given height, whether claimed or not
+    pub fn vested(&self, height: u64) -> u64 {
+        let height = self
+            .revoked_at
+            .map_or(height, |revoked| revoked.min(height));
+        let elapsed = height.saturating_sub(self.start);
+        if height < self.start || elapsed < self.cliff {
+            0
+        } else if elapsed >= self.duration {
+            self.total
+        } else {
+            // The product may not fit in a u64, but the quotient is less than the total.
+            (self.total as u128 * elapsed as u128 / self.duration as u128) as u64
+        }
+    }
+
+    /// How much the beneficiary could claim at the given height
+    pub fn claimable(&self, height: u64) -> u64 {
+        self.vested(height) - self.claimed
+    }
+}
+
+/// The state of the vesting machine
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VestingState {
+    /// Funds not locked in any schedule. Zero balances are not stored.
+    pub balances: HashMap<User, u64>,
+    pub schedules: Vec<VestingSchedule>,
+}
+
+/// The state transitions that users can make in the vesting machine
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum VestingTransaction {
+    /// Lock up some of the grantor's funds in a new schedule for the beneficiary
+    CreateSchedule {
+        grantor: User,
+        beneficiary: User,
+        amount: u64,
+        start: u64,
+        cliff: u64,
+        duration: u64,
+    },
+    /// Move everything that has vested but not been claimed to the beneficiary's balance
+    Claim {
+        beneficiary: User,
+        schedule: ScheduleId,
+    },
+    /// Stop vesting and return the unvested remainder to the grantor
+    Revoke { grantor: User, schedule: ScheduleId },
+}
+
+/// The reasons a transaction may be rejected by the vesting machine
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VestingError {
+    /// Grants must be non-empty, and the cliff may not come after the end of the schedule
+    InvalidSchedule,
+    /// The grantor does not have enough free funds
+    InsufficientBalance { available: u64, requested: u64 },
+    /// No schedule with this id exists
+    UnknownSchedule(ScheduleId),
+    /// The user does not play the role this transaction requires
+    NotAuthorized,
+    /// Nothing has vested since the last claim
+    NothingToClaim,
+    /// The schedule has already been revoked
+    AlreadyRevoked,
+}
+
+impl StateMachine for Vesting {
+    type State = VestingState;
+    type Transition = VestingTransaction;
+    type Error = VestingError;
+    /// The initial free funds of each user
+    type GenesisConfig = Vec<(User, u64)>;
+
+    fn genesis_state(endowments: Vec<(User, u64)>) -> VestingState {
+        let mut state = VestingState::default();
+        for (who, amount) in endowments {
+            credit(&mut state, who, amount);
+        }
+        state
+    }
+
+    fn next_state(starting_state: &VestingState, t: &VestingTransaction) -> VestingState {
+        Self::next_state_in_context(starting_state, t, &BlockContext::default())
+    }
+
+    fn try_next_state(
+        starting_state: &VestingState,
+        t: &VestingTransaction,
+    ) -> Result<VestingState, VestingError> {
+        Self::try_next_state_in_context(starting_state, t, &BlockContext::default())
+    }
+
+    fn human_name() -> String {
+        "Vesting".into()
+    }
+}
+
+/// How much has vested depends on the current height.
+impl ContextualStateMachine for Vesting {
+    fn next_state_in_context(
+        starting_state: &VestingState,
+        t: &VestingTransaction,
+        context: &BlockContext,
+    ) -> VestingState {
+        Self::try_next_state_in_context(starting_state, t, context)
+            .unwrap_or_else(|_| starting_state.clone())
+    }
+
+    fn try_next_state_in_context(
+        starting_state: &VestingState,
+        t: &VestingTransaction,
+        context: &BlockContext,
+    ) -> Result<VestingState, VestingError> {
+        let height = context.height;
+        let mut s = starting_state.clone();
+        match t {
+            VestingTransaction::CreateSchedule {
+                grantor,
+                beneficiary,
+                amount,
+                start,
+                cliff,
+                duration,
+            } => {
+                if *amount == 0 || cliff > duration {
+                    return Err(VestingError::InvalidSchedule);
+                }
+                let available = s.balances.get(grantor).copied().unwrap_or(0);
+                if available < *amount {
+                    return Err(VestingError::InsufficientBalance {
+                        available,
+                        requested: *amount,
+                    });
+                }
+                debit(&mut s, *grantor, *amount);
+                s.schedules.push(VestingSchedule {
+                    grantor: *grantor,
+                    beneficiary: *beneficiary,
+                    total: *amount,
+                    start: *start,
+                    cliff: *cliff,
+                    duration: *duration,
+                    claimed: 0,
+                    revoked_at: None,
+                });
+            }
+            VestingTransaction::Claim {
+                beneficiary,
+                schedule,
+            } => {
+                let v = schedule_mut(&mut s, *schedule)?;
+                if v.beneficiary != *beneficiary {
+                    return Err(VestingError::NotAuthorized);
+                }
+                let amount = v.claimable(height);
+                if amount == 0 {
+                    return Err(VestingError::NothingToClaim);
+                }
+                v.claimed += amount;
+                credit(&mut s, *beneficiary, amount);
+            }
+            VestingTransaction::Revoke { grantor, schedule } => {
+                let v = schedule_mut(&mut s, *schedule)?;
+                if v.grantor != *grantor {
+                    return Err(VestingError::NotAuthorized);
+                }
+                if v.revoked_at.is_some() {
+                    return Err(VestingError::AlreadyRevoked);
+                }
+                let unvested = v.total - v.vested(height);
+                v.revoked_at = Some(height);
+                credit(&mut s, *grantor, unvested);
+            }
+        }
+        Ok(s)
+    }
+}
+
+/// Every vesting transaction costs the same.
+impl Weighted for Vesting {
+    fn weight(_: &VestingTransaction) -> u64 {
+        1
+    }
+}
+
+fn schedule_mut(
+    state: &mut VestingState,
+    id: ScheduleId,
+) -> Result<&mut VestingSchedule, VestingError> {
+    state
+        .schedules
+        .get_mut(id)
+        .ok_or(VestingError::UnknownSchedule(id))
+}
+
+/// Add free funds to an account. No balance can overflow, because no funds are ever created
+/// after genesis.
+fn credit(state: &mut VestingState, who: User, amount: u64) {
+    if amount > 0 {
+        let balance = state.balances.entry(who).or_insert(0);
+        *balance = balance.saturating_add(amount);
+    }
+}
+
+/// Remove free funds from an account. The caller must have checked the balance.
+fn debit(state: &mut VestingState, who: User, amount: u64) {
+    if let Some(balance) = state.balances.get_mut(&who) {
+        *balance -= amount;
+        if *balance == 0 {
+            state.balances.remove(&who);
+        }
+    }
+}
+
+#[cfg(test)]
+fn at_height(height: u64) -> BlockContext {
+    BlockContext {
+        height,
+        ..BlockContext::default()
+    }
+}
+
+#[cfg(test)]
+fn granted(amount: u64, start: u64, cliff: u64, duration: u64) -> VestingState {
+    let start_state = Vesting::genesis_state(vec![(User::Alice, amount)]);
+    let create = VestingTransaction::CreateSchedule {
+        grantor: User::Alice,
+        beneficiary: User::Bob,
+        amount,
+        start,
+        cliff,
+        duration,
+    };
+    Vesting::try_next_state(&start_state, &create).unwrap()
+}
+
+#[cfg(test)]
+const CLAIM: VestingTransaction = VestingTransaction::Claim {
+    beneficiary: User::Bob,
+    schedule: 0,
+};
+
+#[cfg(test)]
+const REVOKE: VestingTransaction = VestingTransaction::Revoke {
+    grantor: User::Alice,
+    schedule: 0,
+};
+
+#[test]
+fn sm_13_linear_vesting_with_cliff() {
+    let schedule = &granted(1000, 10, 20, 100).schedules[0];
+
+    assert_eq!(schedule.vested(0), 0);
+    assert_eq!(schedule.vested(29), 0);
+    assert_eq!(schedule.vested(30), 200);
+    assert_eq!(schedule.vested(60), 500);
+    assert_eq!(schedule.vested(110), 1000);
+    assert_eq!(schedule.vested(u64::MAX), 1000);
+}
+
+#[test]
+fn sm_13_zero_duration_is_a_timelock() {
+    let schedule = &granted(1000, 50, 0, 0).schedules[0];
+
+    assert_eq!(schedule.vested(49), 0);
+    assert_eq!(schedule.vested(50), 1000);
+}
+
+#[test]
+fn sm_13_vesting_large_amounts_does_not_overflow() {
+    let schedule = &granted(u64::MAX, 0, 0, u64::MAX).schedules[0];
+
+    assert_eq!(schedule.vested(u64::MAX / 2), u64::MAX / 2);
+    assert_eq!(schedule.vested(u64::MAX - 1), u64::MAX - 1);
+    assert_eq!(schedule.vested(u64::MAX), u64::MAX);
+}
+
+#[test]
+fn sm_13_claims_only_what_has_vested() {
+    let state = granted(1000, 0, 0, 100);
+
+    assert_eq!(
+        Vesting::try_next_state(&state, &CLAIM),
+        Err(VestingError::NothingToClaim)
+    );
+
+    let claimed = Vesting::try_next_state_in_context(&state, &CLAIM, &at_height(25)).unwrap();
+    assert_eq!(claimed.balances.get(&User::Bob), Some(&250));
+    assert_eq!(
+        Vesting::try_next_state_in_context(&claimed, &CLAIM, &at_height(25)),
+        Err(VestingError::NothingToClaim)
+    );
+
+    let claimed = Vesting::try_next_state_in_context(&claimed, &CLAIM, &at_height(200)).unwrap();
+    assert_eq!(claimed.balances.get(&User::Bob), Some(&1000));
+}
+
+#[test]
+fn sm_13_revoke_returns_unvested_funds() {
+    let state = granted(1000, 0, 0, 100);
+    let revoked = Vesting::try_next_state_in_context(&state, &REVOKE, &at_height(40)).unwrap();
+
+    assert_eq!(revoked.balances.get(&User::Alice), Some(&600));
+    assert_eq!(
+        Vesting::try_next_state_in_context(&revoked, &REVOKE, &at_height(50)),
+        Err(VestingError::AlreadyRevoked)
+    );
+
+    // What vested before the revocation can still be claimed, but nothing more vests.
+    let claimed = Vesting::try_next_state_in_context(&revoked, &CLAIM, &at_height(100)).unwrap();
+    assert_eq!(claimed.balances.get(&User::Bob), Some(&400));
+}
+
+#[test]
+fn sm_13_invalid_schedules_and_roles() {
+    let state = granted(1000, 0, 0, 100);
+    let create = |amount, cliff| VestingTransaction::CreateSchedule {
+        grantor: User::Alice,
+        beneficiary: User::Bob,
+        amount,
+        start: 0,
+        cliff,
+        duration: 10,
+    };
+
+    assert_eq!(
+        Vesting::try_next_state(&state, &create(0, 0)),
+        Err(VestingError::InvalidSchedule)
+    );
+    assert_eq!(
+        Vesting::try_next_state(&state, &create(1, 11)),
+        Err(VestingError::InvalidSchedule)
+    );
+    assert_eq!(
+        Vesting::try_next_state(&state, &create(1, 0)),
+        Err(VestingError::InsufficientBalance {
+            available: 0,
+            requested: 1
+        })
+    );
+    assert_eq!(
+        Vesting::try_next_state(
+            &state,
+            &VestingTransaction::Revoke {
+                grantor: User::Bob,
+                schedule: 0
+            }
+        ),
+        Err(VestingError::NotAuthorized)
+    );
+}
@@ -0,0 +1,399 @@
+//! Blockchains know nothing about who their users are. A web of trust builds reputation out of
+//! the users themselves: each account may endorse others, and trust flows along those
+//! endorsements from a few well-known root accounts.
+//!
+//! Each attestation carries a weight, the percentage of its own trust the attester is willing to
+//! pass on. An account's trust score is that of its best-trusted path from a root, each hop
+//! scaling the score down by the attestation's weight. Trust therefore fades with every hop, and
+//! nobody can boost their score by attesting to themselves through a ring of fake accounts.
+//!
+//! Old attestations are worth less than fresh ones. Every `DECAY_PERIOD` blocks, the weight of
+//! every attestation halves, so users must keep vouching for each other to keep trust alive.
+//!
+//! Scores are kept in the state rather than computed on demand. Each attestation or revocation
+//! only updates the scores it can affect, but when a new decay period begins all scores are
+//! recomputed from scratch, because every attestation has decayed at once.
+
+use std::collections::{HashMap, HashSet};
+
+use super::{BlockContext, ContextualStateMachine, StateMachine, User, Weighted};
+
+/// The largest weight an attestation may carry. Weights are percentages.
+pub const MAX_WEIGHT: u8 = 100;
+
+/// The trust score of the roots. Everyone else scores less.
+pub const FULL_TRUST: u64 = 1_000_000;
+
+/// The number of blocks after which attestation weights halve
+pub const DECAY_PERIOD: u64 = 100;
+
+/// This state machine models a web of trust.
+pub struct WebOfTrust;
+
+/// One user's endorsement of another
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Attestation {
+    /// The weight when fresh, between 1 and `MAX_WEIGHT`
+    pub weight: u8,
+    /// The height at which the attestation was made
+    pub at: u64,
+}
+
+impl Attestation {
+    /// The weight after decay, in the given decay period
+    pub fn effective_weight(&self, period: u64) -> u64 {
+        let halvings = period.saturating_sub(self.at / DECAY_PERIOD);
+        (self.weight as u64)
+            .checked_shr(u32::try_from(halvings).unwrap_or(u32::MAX))
+            .unwrap_or(0)
+    }
+}
+
+/// The state of the web of trust
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TrustState {
+    /// Fully trusted accounts, fixed at genesis
+    pub roots: HashSet<User>,
+    /// Attestations, keyed by attester and then subject
+    pub attestations: HashMap<(User, User), Attestation>,
+    /// Every user's trust score, as of the decay period below. Zero scores are not stored.
+    pub scores: HashMap<User, u64>,
+    /// The decay period the scores were computed for
+    pub period: u64,
+}
+
+impl TrustState {
+    /// The given user's trust score as of the last transaction
+    pub fn trust(&self, who: User) -> u64 {
+        self.scores.get(&who).copied().unwrap_or(0)
+    }
+
+    /// The given user's trust score at the given height. Scores only change when a transaction
+    /// executes or a new decay period begins, so this only has to recompute anything in the
+    /// latter case.
+    pub fn trust_at(&self, who: User, height: u64) -> u64 {
+        if height / DECAY_PERIOD == self.period {
+            return self.trust(who);
+        }
+        let mut state = self.clone();
+        decay(&mut state, height);
+        state.trust(who)
+    }
+}
+
+/// The state transitions that users can make in the web of trust
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TrustTransaction {
+    /// Endorse another user, replacing any earlier attestation to them
+    Attest { from: User, to: User, weight: u8 },
+    /// Withdraw an attestation
+    Revoke { from: User, to: User },
+}
+
+/// The reasons a transaction may be rejected by the web of trust
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TrustError {
+    /// Users may not attest to themselves
+    SelfAttestation,
+    /// Weights must be between 1 and `MAX_WEIGHT`
+    InvalidWeight(u8),
+    /// There is no attestation to revoke
+    NoAttestation,
+}
+
+impl StateMachine for WebOfTrust {
+    type State = TrustState;
+    type Transition = TrustTransaction;
+    type Error = TrustError;
+    /// The root accounts
+    type GenesisConfig = Vec<User>;
+
+    fn genesis_state(roots: Vec<User>) -> TrustState {
+        let mut state = TrustState {
+            roots: roots.into_iter().collect(),
+            ..TrustState::default()
+        };
+        recompute(&mut state);
+        state
+    }
+
+    fn next_state(starting_state: &TrustState, t: &TrustTransaction) -> TrustState {
+        Self::next_state_in_context(starting_state, t, &BlockContext::default())
+    }
+
+    fn try_next_state(
+        starting_state: &TrustState,
+        t: &TrustTransaction,
+    ) -> Result<TrustState, TrustError> {
+        Self::try_next_state_in_context(starting_state, t, &BlockContext::default())
+    }
+
+    fn human_name() -> String {
+        "Web of trust".into()
+    }
+}
+
+/// Attestations are timestamped with, and decay according to, the current height.
+impl ContextualStateMachine for WebOfTrust {
+    fn next_state_in_context(
+        starting_state: &TrustState,
+        t: &TrustTransaction,
+        context: &BlockContext,
+    ) -> TrustState {
+        Self::try_next_state_in_context(starting_state, t, context)
+            .unwrap_or_else(|_| starting_state.clone())
+    }
+
+    fn try_next_state_in_context(
+        starting_state: &TrustState,
+        t: &TrustTransaction,
+        context: &BlockContext,
+    ) -> Result<TrustState, TrustError> {
+        let mut s = starting_state.clone();
+        decay(&mut s, context.height);
+        match t {
+            TrustTransaction::Attest { from, to, weight } => {
+                if from == to {
+                    return Err(TrustError::SelfAttestation);
+                }
+                if *weight == 0 || *weight > MAX_WEIGHT {
+                    return Err(TrustError::InvalidWeight(*weight));
+                }
+                let attestation = Attestation {
+                    weight: *weight,
+                    at: context.height,
+                };
+                let new_weight = attestation.effective_weight(s.period);
+                let old = s.attestations.insert((*from, *to), attestation);
+                match old.map(|a| a.effective_weight(s.period)) {
+                    Some(old_weight) if old_weight > new_weight => withdraw(&mut s, *to),
+                    _ => propagate(&mut s, vec![*from]),
+                }
+            }
+            TrustTransaction::Revoke { from, to } => {
+                if s.attestations.remove(&(*from, *to)).is_none() {
+                    return Err(TrustError::NoAttestation);
+                }
+                withdraw(&mut s, *to);
+            }
+        }
+        Ok(s)
+    }
+}
+
+/// Every trust transaction costs the same.
+impl Weighted for WebOfTrust {
+    fn weight(_: &TrustTransaction) -> u64 {
+        1
+    }
+}
+
+/// Bring the scores up to date with the decay period of the given height.
+fn decay(state: &mut TrustState, height: u64) {
+    let period = height / DECAY_PERIOD;
+    if period != state.period {
+        state.period = period;
+        recompute(state);
+    }
+}
+
+/// Compute every score from scratch.
+fn recompute(state: &mut TrustState) {
+    state.scores = state.roots.iter().map(|root| (*root, FULL_TRUST)).collect();
+    let roots = state.roots.iter().copied().collect();
+    propagate(state, roots);
+}
+
+/// Pass trust on from the given users along their attestations, for as long as that raises
+/// anybody's score. This is all that is needed when a path has become more trusted.
+fn propagate(state: &mut TrustState, mut pending: Vec<User>) {
+    while let Some(from) = pending.pop() {
+        let score = state.trust(from);
+        let raised: Vec<(User, u64)> = state
+            .attestations
+            .iter()
+            .filter(|((attester, to), _)| *attester == from && !state.roots.contains(to))
+            .map(|((_, to), a)| {
+                (
+                    *to,
+                    score * a.effective_weight(state.period) / MAX_WEIGHT as u64,
+                )
+            })
+            .filter(|(to, candidate)| *candidate > state.trust(*to))
+            .collect();
+        for (to, candidate) in raised {
+            state.scores.insert(to, candidate);
+            pending.push(to);
+        }
+    }
+}
+
+/// Recompute the scores that may have depended on a path into the given user that has become
+/// less trusted. Only users downstream of them are affected. Their scores are cleared, then
+/// rebuilt from the attestations reaching them from everyone else.
+fn withdraw(state: &mut TrustState, from: User) {
+    let mut affected = HashSet::new();
+    let mut pending = vec![from];
+    while let Some(user) = pending.pop() {
+        if state.roots.contains(&user) || !affected.insert(user) {
+            continue;
+        }
+        pending.extend(
+            state
+                .attestations
+                .keys()
+                .filter(|(attester, _)| *attester == user)
+                .map(|(_, to)| *to),
+        );
+    }
+    for user in &affected {
+        state.scores.remove(user);
+    }
+    let sources = state
+        .attestations
+        .keys()
+        .filter(|(attester, to)| !affected.contains(attester) && affected.contains(to))
+        .map(|(attester, _)| *attester)
+        .collect();
+    propagate(state, sources);
+}
+
+#[cfg(test)]
+fn at_height(height: u64) -> BlockContext {
+    BlockContext {
+        height,
+        ..BlockContext::default()
+    }
+}
+
+#[cfg(test)]
+fn attest(from: User, to: User, weight: u8) -> TrustTransaction {
+    TrustTransaction::Attest { from, to, weight }
+}
+
+#[cfg(test)]
+fn chain_of_trust() -> TrustState {
+    let start = WebOfTrust::genesis_state(vec![User::Alice]);
+    let ts = [
+        attest(User::Alice, User::Bob, 50),
+        attest(User::Bob, User::Charlie, 50),
+    ];
+    WebOfTrust::try_apply_all(&start, &ts).unwrap()
+}
+
+#[test]
+fn sm_14_trust_fades_along_paths() {
+    let state = chain_of_trust();
+
+    assert_eq!(state.trust(User::Alice), FULL_TRUST);
+    assert_eq!(state.trust(User::Bob), FULL_TRUST / 2);
+    assert_eq!(state.trust(User::Charlie), FULL_TRUST / 4);
+}
+
+#[test]
+fn sm_14_best_path_wins() {
+    let direct =
+        WebOfTrust::try_next_state(&chain_of_trust(), &attest(User::Alice, User::Charlie, 30))
+            .unwrap();
+    assert_eq!(direct.trust(User::Charlie), FULL_TRUST * 3 / 10);
+
+    let weaker =
+        WebOfTrust::try_next_state(&chain_of_trust(), &attest(User::Alice, User::Charlie, 10))
+            .unwrap();
+    assert_eq!(weaker.trust(User::Charlie), FULL_TRUST / 4);
+}
+
+#[test]
+fn sm_14_cycles_do_not_inflate_trust() {
+    let ts = [
+        attest(User::Charlie, User::Bob, 100),
+        attest(User::Bob, User::Alice, 100),
+    ];
+    let state = WebOfTrust::try_apply_all(&chain_of_trust(), &ts).unwrap();
+
+    assert_eq!(state.trust(User::Alice), FULL_TRUST);
+    assert_eq!(state.trust(User::Bob), FULL_TRUST / 2);
+    assert_eq!(state.trust(User::Charlie), FULL_TRUST / 4);
+}
+
+#[test]
+fn sm_14_revocation_and_weakening_update_downstream() {
+    let revoke = TrustTransaction::Revoke {
+        from: User::Alice,
+        to: User::Bob,
+    };
+    let revoked = WebOfTrust::try_next_state(&chain_of_trust(), &revoke).unwrap();
+    assert_eq!(revoked.trust(User::Bob), 0);
+    assert_eq!(revoked.trust(User::Charlie), 0);
+    assert_eq!(
+        WebOfTrust::try_next_state(&revoked, &revoke),
+        Err(TrustError::NoAttestation)
+    );
+
+    let weakened =
+        WebOfTrust::try_next_state(&chain_of_trust(), &attest(User::Alice, User::Bob, 10)).unwrap();
+    assert_eq!(weakened.trust(User::Bob), FULL_TRUST / 10);
+    assert_eq!(weakened.trust(User::Charlie), FULL_TRUST / 20);
+}
+
+#[test]
+fn sm_14_incremental_scores_match_recomputation() {
+    let ts = [
+        attest(User::Alice, User::Charlie, 20),
+        attest(User::Charlie, User::Bob, 90),
+        TrustTransaction::Revoke {
+            from: User::Alice,
+            to: User::Bob,
+        },
+        attest(User::Bob, User::Charlie, 100),
+        attest(User::Charlie, User::Bob, 40),
+    ];
+    let mut state = chain_of_trust();
+    for t in &ts {
+        state = WebOfTrust::try_next_state(&state, t).unwrap();
+        let mut recomputed = state.clone();
+        recompute(&mut recomputed);
+        assert_eq!(state, recomputed);
+    }
+}
+
+#[test]
+fn sm_14_attestations_decay() {
+    let state = chain_of_trust();
+
+    assert_eq!(state.trust_at(User::Bob, DECAY_PERIOD - 1), FULL_TRUST / 2);
+    assert_eq!(state.trust_at(User::Bob, DECAY_PERIOD), FULL_TRUST / 4);
+    assert_eq!(state.trust_at(User::Charlie, DECAY_PERIOD), FULL_TRUST / 16);
+    assert_eq!(state.trust_at(User::Charlie, 100 * DECAY_PERIOD), 0);
+
+    // Renewing an attestation restores its full weight.
+    let renewed = WebOfTrust::try_next_state_in_context(
+        &state,
+        &attest(User::Alice, User::Bob, 50),
+        &at_height(DECAY_PERIOD),
+    )
+    .unwrap();
+    assert_eq!(renewed.trust(User::Bob), FULL_TRUST / 2);
+    assert_eq!(renewed.trust(User::Charlie), FULL_TRUST / 8);
+}
+
+#[test]
+fn sm_14_invalid_attestations() {
+    let state = chain_of_trust();
+
+    assert_eq!(
+        WebOfTrust::try_next_state(&state, &attest(User::Bob, User::Bob, 50)),
+        Err(TrustError::SelfAttestation)
+    );
+    assert_eq!(
+        WebOfTrust::try_next_state(&state, &attest(User::Bob, User::Alice, 0)),
+        Err(TrustError::InvalidWeight(0))
+    );
+    assert_eq!(
+        WebOfTrust::try_next_state(&state, &attest(User::Bob, User::Alice, MAX_WEIGHT + 1)),
+        Err(TrustError::InvalidWeight(MAX_WEIGHT + 1))
+    );
+}
@@ -0,0 +1,667 @@
+//! A token-curated registry is a list whose contents are decided by token holders rather than by
+//! an owner. Anyone may apply to have an entry listed, but must put down a deposit. Anyone else
+//! may challenge an entry by matching that deposit, and token holders then vote on whether the
+//! entry belongs on the list. The losing side's deposit is slashed and paid to the winners.
+//!
+//! Entries that nobody challenges during the application period are listed automatically. Listed
+//! entries may still be challenged later, and their owners may withdraw them while unchallenged.
+//!
+//! Votes are cast with the same commit-reveal pattern as the sealed-bid auction, so that nobody
+//! can see which way the vote is going and pile onto the winning side. Voters lock up tokens
+//! behind their votes, which decide how much each vote counts. Voters who back the winning side
+//! share part of the slashed deposit in proportion to their stake, and every voter gets their
+//! stake back once the challenge is resolved.
+
+use std::collections::HashMap;
+
+use super::{BlockContext, ContextualStateMachine, StateMachine, User, Weighted};
+use crate::hash;
+
+/// The smallest deposit an application may carry
+pub const MIN_DEPOSIT: u64 = 100;
+
+/// The number of blocks after applying during which an entry is not yet listed
+pub const APPLICATION_PERIOD: u64 = 10;
+
+/// The number of blocks after a challenge during which votes may be committed
+pub const COMMIT_PERIOD: u64 = 10;
+
+/// The number of blocks after the commit period during which votes may be revealed
+pub const REVEAL_PERIOD: u64 = 10;
+
+/// The percentage of the slashed deposit paid to the winning party. The rest is shared among the
+/// voters who sided with them.
+pub const DISPENSATION_PERCENT: u64 = 50;
+
+/// This state machine models a token-curated registry.
+pub struct TokenCuratedRegistry;
+
+/// Challenges are numbered in the order they were made, starting from 0.
+pub type ChallengeId = usize;
+
+/// The commitment a voter publishes for the given vote and salt
+pub fn vote_commitment(keep: bool, salt: u64) -> u64 {
+    hash(&(keep, salt))
+}
+
+/// An entry that has applied to, or made it onto, the registry
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Listing {
+    pub owner: User,
+    pub deposit: u64,
+    /// The first block height at which the entry is listed, if unchallenged
+    pub application_end: u64,
+    /// Whether the entry has been listed, either by outlasting its application period or by
+    /// surviving a challenge during it
+    pub accepted: bool,
+    /// The challenge currently open against this entry
+    pub challenge: Option<ChallengeId>,
+}
+
+impl Listing {
+    /// Whether the entry is on the registry at the given height. Entries stay listed while they
+    /// are being challenged.
+    pub fn is_listed(&self, height: u64) -> bool {
+        self.accepted || (self.challenge.is_none() && height >= self.application_end)
+    }
+}
+
+/// The phases of a challenge, determined by the current block height
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ChallengePhase {
+    /// Voters may commit to votes
+    Commit,
+    /// Voters may reveal their votes
+    Reveal,
+    /// The challenge may be resolved
+    Resolve,
+    /// The challenge has been resolved and all deposits paid out
+    Resolved,
+}
+
+/// A voter's sealed vote
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SealedVote {
+    pub commitment: u64,
+    /// The tokens locked behind the vote
+    pub stake: u64,
+    /// Whether the voter wants to keep the entry, once revealed
+    pub revealed: Option<bool>,
+}
+
+/// A challenge to an entry
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Challenge {
+    pub listing: String,
+    pub challenger: User,
+    /// The challenger's deposit, which matches the entry's
+    pub deposit: u64,
+    /// The first block height of the reveal phase
+    pub commit_end: u64,
+    /// The first block height at which the challenge may be resolved
+    pub reveal_end: u64,
+    pub votes: HashMap<User, SealedVote>,
+    /// Whether the entry survived, once resolved
+    pub outcome: Option<bool>,
+}
+
+impl Challenge {
+    /// The phase this challenge is in at the given height
+    pub fn phase(&self, height: u64) -> ChallengePhase {
+        match height {
+            _ if self.outcome.is_some() => ChallengePhase::Resolved,
+            h if h < self.commit_end => ChallengePhase::Commit,
+            h if h < self.reveal_end => ChallengePhase::Reveal,
+            _ => ChallengePhase::Resolve,
+        }
+    }
+
+    /// The total revealed stake for keeping the entry and for removing it
+    pub fn tally(&self) -> (u128, u128) {
+        let mut keep = 0;
+        let mut remove = 0;
+        for vote in self.votes.values() {
+            match vote.revealed {
+                Some(true) => keep += vote.stake as u128,
+                Some(false) => remove += vote.stake as u128,
+                None => {}
+            }
+        }
+        (keep, remove)
+    }
+}
+
+/// The state of the registry
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RegistryState {
+    /// Tokens not locked in any deposit or vote. Zero balances are not stored.
+    pub balances: HashMap<User, u64>,
+    /// Entries that have applied and not yet been removed
+    pub listings: HashMap<String, Listing>,
+    pub challenges: Vec<Challenge>,
+}
+
+/// The state transitions that users can make in the registry
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RegistryTransaction {
+    /// Apply for an entry to be listed, locking up the given deposit
+    Apply {
+        owner: User,
+        listing: String,
+        deposit: u64,
+    },
+    /// Challenge an entry, locking up a deposit equal to its own
+    Challenge { challenger: User, listing: String },
+    /// Commit to a vote on a challenge, locking up the given stake
+    CommitVote {
+        voter: User,
+        challenge: ChallengeId,
+        commitment: u64,
+        stake: u64,
+    },
+    /// Reveal the vote and salt behind an earlier commitment
+    RevealVote {
+        voter: User,
+        challenge: ChallengeId,
+        keep: bool,
+        salt: u64,
+    },
+    /// Count the votes and pay out the deposits. Anyone may do this.
+    Resolve { challenge: ChallengeId },
+    /// Remove an unchallenged entry and refund its deposit
+    Exit { owner: User, listing: String },
+}
+
+/// The reasons a transaction may be rejected by the registry
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RegistryError {
+    /// Applications must carry at least `MIN_DEPOSIT`
+    DepositTooLow,
+    /// An entry with this name has already applied
+    AlreadyListed,
+    /// No entry with this name has applied
+    UnknownListing,
+    /// Entries can only face one challenge at a time, and cannot exit while challenged
+    AlreadyChallenged,
+    /// Only the owner may withdraw an entry
+    NotOwner,
+    /// The user does not have enough free tokens
+    InsufficientBalance { available: u64, requested: u64 },
+    /// No challenge with this id exists
+    UnknownChallenge(ChallengeId),
+    /// The challenge is not in the phase this transaction requires
+    WrongPhase {
+        expected: ChallengePhase,
+        actual: ChallengePhase,
+    },
+    /// Votes must be backed by some stake
+    ZeroStake,
+    /// Each voter may only commit once per challenge
+    AlreadyCommitted,
+    /// The voter never committed to a vote on this challenge
+    NoCommitment,
+    /// The vote and salt do not match the commitment, or have already been revealed
+    InvalidReveal,
+}
+
+impl StateMachine for TokenCuratedRegistry {
+    type State = RegistryState;
+    type Transition = RegistryTransaction;
+    type Error = RegistryError;
+    /// The initial free tokens of each user
+    type GenesisConfig = Vec<(User, u64)>;
+
+    fn genesis_state(endowments: Vec<(User, u64)>) -> RegistryState {
+        let mut state = RegistryState::default();
+        for (who, amount) in endowments {
+            credit(&mut state, who, amount);
+        }
+        state
+    }
+
+    fn next_state(starting_state: &RegistryState, t: &RegistryTransaction) -> RegistryState {
+        Self::next_state_in_context(starting_state, t, &BlockContext::default())
+    }
+
+    fn try_next_state(
+        starting_state: &RegistryState,
+        t: &RegistryTransaction,
+    ) -> Result<RegistryState, RegistryError> {
+        Self::try_next_state_in_context(starting_state, t, &BlockContext::default())
+    }
+
+    fn human_name() -> String {
+        "Token-curated registry".into()
+    }
+}
+
+/// Application periods and voting phases are measured from the current height.
+impl ContextualStateMachine for TokenCuratedRegistry {
+    fn next_state_in_context(
+        starting_state: &RegistryState,
+        t: &RegistryTransaction,
+        context: &BlockContext,
+    ) -> RegistryState {
+        Self::try_next_state_in_context(starting_state, t, context)
+            .unwrap_or_else(|_| starting_state.clone())
+    }
+
+    fn try_next_state_in_context(
+        starting_state: &RegistryState,
+        t: &RegistryTransaction,
+        context: &BlockContext,
+    ) -> Result<RegistryState, RegistryError> {
+        let height = context.height;
+        let mut s = starting_state.clone();
+        match t {
+            RegistryTransaction::Apply {
+                owner,
+                listing,
+                deposit,
+            } => {
+                if *deposit < MIN_DEPOSIT {
+                    return Err(RegistryError::DepositTooLow);
+                }
+                if s.listings.contains_key(listing) {
+                    return Err(RegistryError::AlreadyListed);
+                }
+                lock(&mut s, *owner, *deposit)?;
+                s.listings.insert(
+                    listing.clone(),
+                    Listing {
+                        owner: *owner,
+                        deposit: *deposit,
+                        application_end: height.saturating_add(APPLICATION_PERIOD),
+                        accepted: false,
+                        challenge: None,
+                    },
+                );
+            }
+            RegistryTransaction::Challenge {
+                challenger,
+                listing,
+            } => {
+                let entry = s
+                    .listings
+                    .get(listing)
+                    .ok_or(RegistryError::UnknownListing)?;
+                if entry.challenge.is_some() {
+                    return Err(RegistryError::AlreadyChallenged);
+                }
+                let deposit = entry.deposit;
+                lock(&mut s, *challenger, deposit)?;
+                let commit_end = height.saturating_add(COMMIT_PERIOD);
+                s.challenges.push(Challenge {
+                    listing: listing.clone(),
+                    challenger: *challenger,
+                    deposit,
+                    commit_end,
+                    reveal_end: commit_end.saturating_add(REVEAL_PERIOD),
+                    votes: HashMap::new(),
+                    outcome: None,
+                });
+                let id = s.challenges.len() - 1;
+                let entry = s
+                    .listings
+                    .get_mut(listing)
+                    .ok_or(RegistryError::UnknownListing)?;
+                entry.accepted |= entry.is_listed(height);
+                entry.challenge = Some(id);
+            }
+            RegistryTransaction::CommitVote {
+                voter,
+                challenge,
+                commitment,
+                stake,
+            } => {
+                if *stake == 0 {
+                    return Err(RegistryError::ZeroStake);
+                }
+                let c = challenge_in_phase(&mut s, *challenge, ChallengePhase::Commit, height)?;
+                if c.votes.contains_key(voter) {
+                    return Err(RegistryError::AlreadyCommitted);
+                }
+                c.votes.insert(
+                    *voter,
+                    SealedVote {
+                        commitment: *commitment,
+                        stake: *stake,
+                        revealed: None,
+                    },
+                );
+                lock(&mut s, *voter, *stake)?;
+            }
+            RegistryTransaction::RevealVote {
+                voter,
+                challenge,
+                keep,
+                salt,
+            } => {
+                let c = challenge_in_phase(&mut s, *challenge, ChallengePhase::Reveal, height)?;
+                let vote = c.votes.get_mut(voter).ok_or(RegistryError::NoCommitment)?;
+                if vote.revealed.is_some() || vote.commitment != vote_commitment(*keep, *salt) {
+                    return Err(RegistryError::InvalidReveal);
+                }
+                vote.revealed = Some(*keep);
+            }
+            RegistryTransaction::Resolve { challenge } => {
+                let c = challenge_in_phase(&mut s, *challenge, ChallengePhase::Resolve, height)?;
+                // Ties go to the entry. It is up to the challenger to convince the voters.
+                let (keep, remove) = c.tally();
+                let kept = keep >= remove;
+                let winning_stake = if kept { keep } else { remove };
+                c.outcome = Some(kept);
+
+                // Every voter gets their stake back. Each winning voter's share of the slashed
+                // deposit rounds down, and the winning party collects the remainder.
+                let voter_pool = c.deposit - c.deposit * DISPENSATION_PERCENT / 100;
+                let mut party_reward = c.deposit;
+                let mut payouts = Vec::new();
+                for (voter, vote) in &c.votes {
+                    let mut payout = vote.stake;
+                    if vote.revealed == Some(kept) {
+                        let share = voter_pool as u128 * vote.stake as u128 / winning_stake;
+                        payout += share as u64;
+                        party_reward -= share as u64;
+                    }
+                    payouts.push((*voter, payout));
+                }
+                let (challenger, deposit, listing) = (c.challenger, c.deposit, c.listing.clone());
+                if kept {
+                    let entry = s
+                        .listings
+                        .get_mut(&listing)
+                        .ok_or(RegistryError::UnknownListing)?;
+                    entry.challenge = None;
+                    entry.accepted = true;
+                    payouts.push((entry.owner, party_reward));
+                } else {
+                    s.listings.remove(&listing);
+                    payouts.push((challenger, deposit + party_reward));
+                }
+                for (who, amount) in payouts {
+                    credit(&mut s, who, amount);
+                }
+            }
+            RegistryTransaction::Exit { owner, listing } => {
+                let entry = s
+                    .listings
+                    .get(listing)
+                    .ok_or(RegistryError::UnknownListing)?;
+                if entry.owner != *owner {
+                    return Err(RegistryError::NotOwner);
+                }
+                if entry.challenge.is_some() {
+                    return Err(RegistryError::AlreadyChallenged);
+                }
+                let deposit = entry.deposit;
+                s.listings.remove(listing);
+                credit(&mut s, *owner, deposit);
+            }
+        }
+        Ok(s)
+    }
+}
+
+/// Every registry transaction costs the same.
+impl Weighted for TokenCuratedRegistry {
+    fn weight(_: &RegistryTransaction) -> u64 {
+        1
+    }
+}
+
+/// The given challenge, provided it is in the given phase at the given height.
+fn challenge_in_phase(
+    state: &mut RegistryState,
+    id: ChallengeId,
+    expected: ChallengePhase,
+    height: u64,
+) -> Result<&mut Challenge, RegistryError> {
+    let challenge = state
+        .challenges
+        .get_mut(id)
+        .ok_or(RegistryError::UnknownChallenge(id))?;
+    let actual = challenge.phase(height);
+    if actual != expected {
+        return Err(RegistryError::WrongPhase { expected, actual });
+    }
+    Ok(challenge)
+}
+
+/// Add free tokens to an account. No balance can overflow, because no tokens are ever created
+/// after genesis.
+fn credit(state: &mut RegistryState, who: User, amount: u64) {
+    if amount > 0 {
+        let balance = state.balances.entry(who).or_insert(0);
+        *balance = balance.saturating_add(amount);
+    }
+}
+
+/// Remove free tokens from an account, to be locked in a deposit or vote.
+fn lock(state: &mut RegistryState, who: User, amount: u64) -> Result<(), RegistryError> {
+    let available = state.balances.get(&who).copied().unwrap_or(0);
+    if available < amount {
+        return Err(RegistryError::InsufficientBalance {
+            available,
+            requested: amount,
+        });
+    }
+    if available == amount {
+        state.balances.remove(&who);
+    } else {
+        state.balances.insert(who, available - amount);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+fn at_height(height: u64) -> BlockContext {
+    BlockContext {
+        height,
+        ..BlockContext::default()
+    }
+}
+
+#[cfg(test)]
+fn run(state: &RegistryState, ts: &[RegistryTransaction], height: u64) -> RegistryState {
+    TokenCuratedRegistry::try_apply_all_in_context(state, ts, &at_height(height)).unwrap()
+}
+
+#[cfg(test)]
+fn applied() -> RegistryState {
+    let start = TokenCuratedRegistry::genesis_state(vec![
+        (User::Alice, 1000),
+        (User::Bob, 1000),
+        (User::Charlie, 1000),
+    ]);
+    let apply = RegistryTransaction::Apply {
+        owner: User::Alice,
+        listing: "example.com".into(),
+        deposit: 100,
+    };
+    run(&start, &[apply], 0)
+}
+
+#[cfg(test)]
+fn challenged(height: u64) -> RegistryState {
+    let challenge = RegistryTransaction::Challenge {
+        challenger: User::Bob,
+        listing: "example.com".into(),
+    };
+    run(&applied(), &[challenge], height)
+}
+
+#[cfg(test)]
+fn commit(voter: User, keep: bool, stake: u64) -> RegistryTransaction {
+    RegistryTransaction::CommitVote {
+        voter,
+        challenge: 0,
+        commitment: vote_commitment(keep, 7),
+        stake,
+    }
+}
+
+#[cfg(test)]
+fn reveal(voter: User, keep: bool) -> RegistryTransaction {
+    RegistryTransaction::RevealVote {
+        voter,
+        challenge: 0,
+        keep,
+        salt: 7,
+    }
+}
+
+#[test]
+fn sm_15_unchallenged_application_is_listed() {
+    let state = applied();
+    let listing = &state.listings["example.com"];
+
+    assert_eq!(state.balances.get(&User::Alice), Some(&900));
+    assert!(!listing.is_listed(APPLICATION_PERIOD - 1));
+    assert!(listing.is_listed(APPLICATION_PERIOD));
+
+    let apply = RegistryTransaction::Apply {
+        owner: User::Bob,
+        listing: "example.com".into(),
+        deposit: 100,
+    };
+    assert_eq!(
+        TokenCuratedRegistry::try_next_state(&state, &apply),
+        Err(RegistryError::AlreadyListed)
+    );
+}
+
+#[test]
+fn sm_15_successful_challenge_removes_entry() {
+    let committed = run(
+        &challenged(5),
+        &[
+            commit(User::Charlie, false, 300),
+            commit(User::Alice, true, 200),
+        ],
+        5,
+    );
+    let revealed = run(
+        &committed,
+        &[reveal(User::Charlie, false), reveal(User::Alice, true)],
+        15,
+    );
+    let resolved = run(
+        &revealed,
+        &[RegistryTransaction::Resolve { challenge: 0 }],
+        25,
+    );
+
+    assert!(!resolved.listings.contains_key("example.com"));
+    assert_eq!(resolved.challenges[0].outcome, Some(false));
+    // Alice loses her deposit. Bob gets his back plus half of hers, and Charlie gets the rest.
+    assert_eq!(resolved.balances.get(&User::Alice), Some(&900));
+    assert_eq!(resolved.balances.get(&User::Bob), Some(&1050));
+    assert_eq!(resolved.balances.get(&User::Charlie), Some(&1050));
+}
+
+#[test]
+fn sm_15_failed_challenge_slashes_challenger() {
+    let committed = run(&challenged(5), &[commit(User::Charlie, true, 300)], 5);
+    let revealed = run(&committed, &[reveal(User::Charlie, true)], 15);
+    let resolved = run(
+        &revealed,
+        &[RegistryTransaction::Resolve { challenge: 0 }],
+        25,
+    );
+
+    let listing = &resolved.listings["example.com"];
+    assert!(listing.accepted);
+    assert_eq!(listing.challenge, None);
+    assert_eq!(resolved.balances.get(&User::Alice), Some(&950));
+    assert_eq!(resolved.balances.get(&User::Bob), Some(&900));
+    assert_eq!(resolved.balances.get(&User::Charlie), Some(&1050));
+}
+
+#[test]
+fn sm_15_unrevealed_votes_do_not_count() {
+    let committed = run(
+        &challenged(5),
+        &[
+            commit(User::Charlie, false, 300),
+            commit(User::Alice, true, 10),
+        ],
+        5,
+    );
+    let revealed = run(&committed, &[reveal(User::Alice, true)], 15);
+    let resolved = run(
+        &revealed,
+        &[RegistryTransaction::Resolve { challenge: 0 }],
+        25,
+    );
+
+    assert_eq!(resolved.challenges[0].outcome, Some(true));
+    // Charlie's stake is returned, but earns nothing.
+    assert_eq!(resolved.balances.get(&User::Charlie), Some(&1000));
+    assert_eq!(resolved.balances.get(&User::Alice), Some(&1000));
+}
+
+#[test]
+fn sm_15_challenged_listed_entry_stays_listed() {
+    let state = challenged(APPLICATION_PERIOD);
+    let listing = &state.listings["example.com"];
+    assert!(listing.is_listed(APPLICATION_PERIOD));
+
+    let early = challenged(APPLICATION_PERIOD - 1);
+    assert!(!early.listings["example.com"].is_listed(APPLICATION_PERIOD));
+
+    let exit = RegistryTransaction::Exit {
+        owner: User::Alice,
+        listing: "example.com".into(),
+    };
+    assert_eq!(
+        TokenCuratedRegistry::try_next_state(&state, &exit),
+        Err(RegistryError::AlreadyChallenged)
+    );
+    let exited = TokenCuratedRegistry::try_next_state(&applied(), &exit).unwrap();
+    assert!(exited.listings.is_empty());
+    assert_eq!(exited.balances.get(&User::Alice), Some(&1000));
+}
+
+#[test]
+fn sm_15_phases_are_enforced() {
+    let state = challenged(0);
+
+    assert_eq!(
+        TokenCuratedRegistry::try_next_state_in_context(
+            &state,
+            &commit(User::Charlie, true, 1),
+            &at_height(COMMIT_PERIOD)
+        ),
+        Err(RegistryError::WrongPhase {
+            expected: ChallengePhase::Commit,
+            actual: ChallengePhase::Reveal
+        })
+    );
+    assert_eq!(
+        TokenCuratedRegistry::try_next_state_in_context(
+            &state,
+            &RegistryTransaction::Resolve { challenge: 0 },
+            &at_height(COMMIT_PERIOD)
+        ),
+        Err(RegistryError::WrongPhase {
+            expected: ChallengePhase::Resolve,
+            actual: ChallengePhase::Reveal
+        })
+    );
+    let committed = run(&state, &[commit(User::Charlie, true, 1)], 0);
+    assert_eq!(
+        TokenCuratedRegistry::try_next_state_in_context(
+            &committed,
+            &reveal(User::Charlie, false),
+            &at_height(COMMIT_PERIOD)
+        ),
+        Err(RegistryError::InvalidReveal)
+    );
+}
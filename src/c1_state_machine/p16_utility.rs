@@ -0,0 +1,409 @@
+//! This is one of the ideas from the open-ended part: a public utility provider. Customers open
+//! accounts and consume the utility, and are billed for it periodically. The provider sets the
+//! price per unit, and may change it whenever it likes. Consumption is priced when it is billed,
+//! not when it happens, so customers pay whatever the price is at the end of the billing period.
+//!
+//! Bills are due within a grace period. Customers still in arrears after that may be
+//! disconnected, and are reconnected once they have paid everything they owe.
+
+use std::collections::HashMap;
+
+use super::{BlockContext, ContextualStateMachine, StateMachine, User, Weighted};
+
+/// The number of blocks between bills
+pub const BILLING_PERIOD: u64 = 30;
+
+/// The number of blocks after a bill within which it must be paid
+pub const GRACE_PERIOD: u64 = 10;
+
+/// This state machine models a utility provider billing its customers.
+pub struct UtilityProvider;
+
+/// A customer's account with the provider
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UtilityAccount {
+    /// Units consumed since the last bill
+    pub metered: u64,
+    /// The total of all unpaid bills
+    pub owed: u64,
+    /// The height by which the oldest unpaid bill must be paid
+    pub due_at: Option<u64>,
+    /// The height of the last bill, or of opening the account
+    pub last_billed: u64,
+    pub disconnected: bool,
+}
+
+impl UtilityAccount {
+    /// Whether the customer may be disconnected at the given height
+    pub fn in_arrears(&self, height: u64) -> bool {
+        self.due_at.is_some_and(|due_at| height >= due_at)
+    }
+}
+
+/// The state of the utility provider
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UtilityState {
+    /// The provider, who sets the price and is paid the bills
+    pub admin: User,
+    /// The current price per unit consumed
+    pub price: u64,
+    /// Funds available to pay bills. Zero balances are not stored.
+    pub balances: HashMap<User, u64>,
+    pub accounts: HashMap<User, UtilityAccount>,
+}
+
+/// The state transitions that users can make with the utility provider
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum UtilityTransaction {
+    /// Become a customer
+    OpenAccount { customer: User },
+    /// A meter reading, adding to the customer's consumption this billing period
+    Consume { customer: User, units: u64 },
+    /// Bill the customer for their consumption since the last bill. Anyone may do this, once per
+    /// billing period.
+    Bill { customer: User },
+    /// Pay towards the customer's outstanding bills
+    Pay { customer: User, amount: u64 },
+    /// Change the price per unit
+    SetPrice { admin: User, price: u64 },
+    /// Cut off a customer whose bills are overdue. Anyone may do this.
+    Disconnect { customer: User },
+}
+
+/// The reasons a transaction may be rejected by the utility provider
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UtilityError {
+    /// Only the provider may set the price
+    NotAdmin,
+    /// The user is already a customer
+    AccountExists,
+    /// The user is not a customer
+    NoAccount,
+    /// Disconnected customers cannot consume the utility
+    Disconnected,
+    /// The customer was billed less than a billing period ago
+    BillingTooEarly { next_bill_at: u64 },
+    /// Payments may not exceed what is owed
+    Overpayment { owed: u64 },
+    /// The customer does not have enough funds
+    InsufficientBalance { available: u64, requested: u64 },
+    /// The customer has no overdue bills
+    NotInArrears,
+}
+
+impl StateMachine for UtilityProvider {
+    type State = UtilityState;
+    type Transition = UtilityTransaction;
+    type Error = UtilityError;
+    /// The provider, the initial price, and the initial funds of each user
+    type GenesisConfig = (User, u64, Vec<(User, u64)>);
+
+    fn genesis_state((admin, price, endowments): Self::GenesisConfig) -> UtilityState {
+        let mut state = UtilityState {
+            admin,
+            price,
+            balances: HashMap::new(),
+            accounts: HashMap::new(),
+        };
+        for (who, amount) in endowments {
+            credit(&mut state, who, amount);
+        }
+        state
+    }
+
+    fn next_state(starting_state: &UtilityState, t: &UtilityTransaction) -> UtilityState {
+        Self::next_state_in_context(starting_state, t, &BlockContext::default())
+    }
+
+    fn try_next_state(
+        starting_state: &UtilityState,
+        t: &UtilityTransaction,
+    ) -> Result<UtilityState, UtilityError> {
+        Self::try_next_state_in_context(starting_state, t, &BlockContext::default())
+    }
+
+    fn human_name() -> String {
+        "Utility provider".into()
+    }
+}
+
+/// Billing periods and grace periods are measured in blocks.
+impl ContextualStateMachine for UtilityProvider {
+    fn next_state_in_context(
+        starting_state: &UtilityState,
+        t: &UtilityTransaction,
+        context: &BlockContext,
+    ) -> UtilityState {
+        Self::try_next_state_in_context(starting_state, t, context)
+            .unwrap_or_else(|_| starting_state.clone())
+    }
+
+    fn try_next_state_in_context(
+        starting_state: &UtilityState,
+        t: &UtilityTransaction,
+        context: &BlockContext,
+    ) -> Result<UtilityState, UtilityError> {
+        let height = context.height;
+        let mut s = starting_state.clone();
+        match t {
+            UtilityTransaction::OpenAccount { customer } => {
+                if s.accounts.contains_key(customer) {
+                    return Err(UtilityError::AccountExists);
+                }
+                s.accounts.insert(
+                    *customer,
+                    UtilityAccount {
+                        last_billed: height,
+                        ..UtilityAccount::default()
+                    },
+                );
+            }
+            UtilityTransaction::Consume { customer, units } => {
+                let account = account_mut(&mut s, *customer)?;
+                if account.disconnected {
+                    return Err(UtilityError::Disconnected);
+                }
+                account.metered = account.metered.saturating_add(*units);
+            }
+            UtilityTransaction::Bill { customer } => {
+                let price = s.price;
+                let account = account_mut(&mut s, *customer)?;
+                let next_bill_at = account.last_billed.saturating_add(BILLING_PERIOD);
+                if height < next_bill_at {
+                    return Err(UtilityError::BillingTooEarly { next_bill_at });
+                }
+                let amount = account.metered.saturating_mul(price);
+                account.metered = 0;
+                account.last_billed = height;
+                if amount > 0 {
+                    account.owed = account.owed.saturating_add(amount);
+                    // Only the oldest unpaid bill's deadline matters.
+                    account
+                        .due_at
+                        .get_or_insert(height.saturating_add(GRACE_PERIOD));
+                }
+            }
+            UtilityTransaction::Pay { customer, amount } => {
+                let available = s.balances.get(customer).copied().unwrap_or(0);
+                let admin = s.admin;
+                let account = account_mut(&mut s, *customer)?;
+                if *amount > account.owed {
+                    return Err(UtilityError::Overpayment { owed: account.owed });
+                }
+                if available < *amount {
+                    return Err(UtilityError::InsufficientBalance {
+                        available,
+                        requested: *amount,
+                    });
+                }
+                account.owed -= amount;
+                if account.owed == 0 {
+                    account.due_at = None;
+                    account.disconnected = false;
+                }
+                debit(&mut s, *customer, *amount);
+                credit(&mut s, admin, *amount);
+            }
+            UtilityTransaction::SetPrice { admin, price } => {
+                if *admin != s.admin {
+                    return Err(UtilityError::NotAdmin);
+                }
+                s.price = *price;
+            }
+            UtilityTransaction::Disconnect { customer } => {
+                let account = account_mut(&mut s, *customer)?;
+                if account.disconnected {
+                    return Err(UtilityError::Disconnected);
+                }
+                if !account.in_arrears(height) {
+                    return Err(UtilityError::NotInArrears);
+                }
+                account.disconnected = true;
+            }
+        }
+        Ok(s)
+    }
+}
+
+/// Every utility transaction costs the same.
+impl Weighted for UtilityProvider {
+    fn weight(_: &UtilityTransaction) -> u64 {
+        1
+    }
+}
+
+fn account_mut(state: &mut UtilityState, who: User) -> Result<&mut UtilityAccount, UtilityError> {
+    state.accounts.get_mut(&who).ok_or(UtilityError::NoAccount)
+}
+
+/// Add funds to an account. No balance can overflow, because no funds are ever created after
+/// genesis.
+fn credit(state: &mut UtilityState, who: User, amount: u64) {
+    if amount > 0 {
+        let balance = state.balances.entry(who).or_insert(0);
+        *balance = balance.saturating_add(amount);
+    }
+}
+
+/// Remove funds from an account. The caller must have checked the balance.
+fn debit(state: &mut UtilityState, who: User, amount: u64) {
+    if let Some(balance) = state.balances.get_mut(&who) {
+        *balance -= amount;
+        if *balance == 0 {
+            state.balances.remove(&who);
+        }
+    }
+}
+
+#[cfg(test)]
+fn at_height(height: u64) -> BlockContext {
+    BlockContext {
+        height,
+        ..BlockContext::default()
+    }
+}
+
+#[cfg(test)]
+fn run(state: &UtilityState, ts: &[UtilityTransaction], height: u64) -> UtilityState {
+    UtilityProvider::try_apply_all_in_context(state, ts, &at_height(height)).unwrap()
+}
+
+#[cfg(test)]
+const BILL: UtilityTransaction = UtilityTransaction::Bill {
+    customer: User::Bob,
+};
+
+#[cfg(test)]
+fn billed() -> UtilityState {
+    let start = UtilityProvider::genesis_state((User::Alice, 2, vec![(User::Bob, 100)]));
+    let opened = run(
+        &start,
+        &[
+            UtilityTransaction::OpenAccount {
+                customer: User::Bob,
+            },
+            UtilityTransaction::Consume {
+                customer: User::Bob,
+                units: 10,
+            },
+        ],
+        0,
+    );
+    run(&opened, &[BILL], BILLING_PERIOD)
+}
+
+#[cfg(test)]
+fn pay(amount: u64) -> UtilityTransaction {
+    UtilityTransaction::Pay {
+        customer: User::Bob,
+        amount,
+    }
+}
+
+#[test]
+fn sm_16_bill_at_current_price() {
+    let state = billed();
+    let account = &state.accounts[&User::Bob];
+
+    assert_eq!(account.owed, 20);
+    assert_eq!(account.metered, 0);
+    assert_eq!(account.due_at, Some(BILLING_PERIOD + GRACE_PERIOD));
+
+    let ts = [
+        UtilityTransaction::Consume {
+            customer: User::Bob,
+            units: 5,
+        },
+        UtilityTransaction::SetPrice {
+            admin: User::Alice,
+            price: 3,
+        },
+    ];
+    let repriced = run(&state, &ts, BILLING_PERIOD + 1);
+    let rebilled = run(&repriced, &[BILL], 2 * BILLING_PERIOD);
+    assert_eq!(rebilled.accounts[&User::Bob].owed, 35);
+    // The first bill's deadline still applies.
+    assert_eq!(
+        rebilled.accounts[&User::Bob].due_at,
+        Some(BILLING_PERIOD + GRACE_PERIOD)
+    );
+}
+
+#[test]
+fn sm_16_billing_is_periodic() {
+    assert_eq!(
+        UtilityProvider::try_next_state_in_context(
+            &billed(),
+            &BILL,
+            &at_height(2 * BILLING_PERIOD - 1)
+        ),
+        Err(UtilityError::BillingTooEarly {
+            next_bill_at: 2 * BILLING_PERIOD
+        })
+    );
+}
+
+#[test]
+fn sm_16_paying_goes_to_provider() {
+    let partly = run(&billed(), &[pay(15)], BILLING_PERIOD);
+    assert_eq!(partly.accounts[&User::Bob].owed, 5);
+    assert!(partly.accounts[&User::Bob].due_at.is_some());
+
+    let paid = run(&partly, &[pay(5)], BILLING_PERIOD);
+    assert_eq!(paid.balances.get(&User::Alice), Some(&20));
+    assert_eq!(paid.balances.get(&User::Bob), Some(&80));
+    assert_eq!(paid.accounts[&User::Bob].due_at, None);
+
+    assert_eq!(
+        UtilityProvider::try_next_state(&paid, &pay(1)),
+        Err(UtilityError::Overpayment { owed: 0 })
+    );
+}
+
+#[test]
+fn sm_16_disconnect_for_arrears() {
+    let state = billed();
+    let disconnect = UtilityTransaction::Disconnect {
+        customer: User::Bob,
+    };
+    let due_at = BILLING_PERIOD + GRACE_PERIOD;
+
+    assert_eq!(
+        UtilityProvider::try_next_state_in_context(&state, &disconnect, &at_height(due_at - 1)),
+        Err(UtilityError::NotInArrears)
+    );
+
+    let cut_off = run(&state, &[disconnect], due_at);
+    assert!(cut_off.accounts[&User::Bob].disconnected);
+    assert_eq!(
+        UtilityProvider::try_next_state_in_context(
+            &cut_off,
+            &UtilityTransaction::Consume {
+                customer: User::Bob,
+                units: 1
+            },
+            &at_height(due_at)
+        ),
+        Err(UtilityError::Disconnected)
+    );
+
+    let reconnected = run(&cut_off, &[pay(20)], due_at);
+    assert!(!reconnected.accounts[&User::Bob].disconnected);
+}
+
+#[test]
+fn sm_16_only_admin_sets_price() {
+    assert_eq!(
+        UtilityProvider::try_next_state(
+            &billed(),
+            &UtilityTransaction::SetPrice {
+                admin: User::Bob,
+                price: 0
+            }
+        ),
+        Err(UtilityError::NotAdmin)
+    );
+}
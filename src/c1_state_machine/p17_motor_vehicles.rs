@@ -0,0 +1,508 @@
+//! This is another of the ideas from the open-ended part: a bureau of motor vehicles. It keeps
+//! two registries side by side, the drivers' licenses and the vehicle registrations, and a log
+//! of traffic violations that refers to both.
+//!
+//! Licenses are issued by a bureau officer and expire unless renewed. Every violation recorded
+//! against a driver adds penalty points to their license, and a driver who collects too many is
+//! suspended automatically for a while. Officers may also suspend licenses directly.
+//!
+//! Vehicles are registered to an owner, who may transfer them to someone else. Owning a vehicle
+//! does not require a license, but driving one does, so violations can only be recorded against
+//! licensed drivers in registered vehicles.
+
+use std::collections::HashMap;
+
+use super::{BlockContext, ContextualStateMachine, StateMachine, User, Weighted};
+
+/// The number of blocks a license is valid for after being issued or renewed
+pub const LICENSE_VALIDITY: u64 = 365;
+
+/// The number of penalty points that trigger an automatic suspension
+pub const SUSPENSION_POINTS: u32 = 12;
+
+/// The number of blocks an automatic suspension lasts
+pub const SUSPENSION_PERIOD: u64 = 100;
+
+/// This state machine models a bureau of motor vehicles.
+pub struct MotorVehicles;
+
+/// Vehicles are identified by their vehicle identification number.
+pub type Vin = String;
+
+/// The kinds of traffic violation
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Offence {
+    Speeding,
+    RunningRedLight,
+    RecklessDriving,
+}
+
+impl Offence {
+    /// The penalty points the offence adds to the driver's license
+    pub fn points(&self) -> u32 {
+        match self {
+            Offence::Speeding => 3,
+            Offence::RunningRedLight => 4,
+            Offence::RecklessDriving => 6,
+        }
+    }
+}
+
+/// Whether a license may be used at a given height
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LicenseStatus {
+    Valid,
+    Expired,
+    /// Suspended until the given height
+    Suspended(u64),
+}
+
+/// A driver's license
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct License {
+    /// The first block height at which the license is no longer valid
+    pub expires_at: u64,
+    /// Penalty points collected since the last automatic suspension
+    pub points: u32,
+    /// The first block height at which the license is no longer suspended
+    pub suspended_until: Option<u64>,
+}
+
+impl License {
+    /// The license's status at the given height. Suspension takes precedence over expiry.
+    pub fn status(&self, height: u64) -> LicenseStatus {
+        match self.suspended_until {
+            Some(until) if height < until => LicenseStatus::Suspended(until),
+            _ if height >= self.expires_at => LicenseStatus::Expired,
+            _ => LicenseStatus::Valid,
+        }
+    }
+}
+
+/// A recorded traffic violation
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Violation {
+    pub driver: User,
+    pub vin: Vin,
+    pub offence: Offence,
+    /// The height at which the violation was recorded
+    pub at: u64,
+}
+
+/// The state of the bureau
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BureauState {
+    /// The officer who issues licenses and records violations
+    pub officer: User,
+    pub licenses: HashMap<User, License>,
+    /// The owner of each registered vehicle
+    pub vehicles: HashMap<Vin, User>,
+    /// Every violation ever recorded, oldest first
+    pub violations: Vec<Violation>,
+}
+
+impl BureauState {
+    /// The vehicles registered to the given owner, in no particular order
+    pub fn vehicles_of(&self, owner: User) -> Vec<&Vin> {
+        self.vehicles
+            .iter()
+            .filter(|(_, o)| **o == owner)
+            .map(|(vin, _)| vin)
+            .collect()
+    }
+
+    /// The violations recorded against the given driver, oldest first
+    pub fn violations_of(&self, driver: User) -> Vec<&Violation> {
+        self.violations
+            .iter()
+            .filter(|v| v.driver == driver)
+            .collect()
+    }
+}
+
+/// The state transitions that users can make at the bureau
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BureauTransaction {
+    /// The officer issues a license to a driver who has never held one
+    IssueLicense { officer: User, driver: User },
+    /// The driver extends their license, which must not be suspended
+    RenewLicense { driver: User },
+    /// The officer suspends a license until the given height
+    SuspendLicense {
+        officer: User,
+        driver: User,
+        until: u64,
+    },
+    /// Register a new vehicle to its first owner
+    RegisterVehicle { owner: User, vin: Vin },
+    /// The owner hands their vehicle over to someone else
+    TransferVehicle { owner: User, vin: Vin, to: User },
+    /// The officer records a violation by a driver in a vehicle
+    RecordViolation {
+        officer: User,
+        driver: User,
+        vin: Vin,
+        offence: Offence,
+    },
+}
+
+/// The reasons a transaction may be rejected by the bureau
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BureauError {
+    /// Only the officer may issue and suspend licenses and record violations
+    NotOfficer,
+    /// The driver already holds a license, which they should renew instead
+    AlreadyLicensed,
+    /// The driver has never held a license
+    NoLicense,
+    /// The license is suspended until the given height
+    Suspended(u64),
+    /// Suspensions must end in the future
+    InvalidSuspension,
+    /// A vehicle with this identification number is already registered
+    VehicleExists,
+    /// No vehicle with this identification number is registered
+    UnknownVehicle,
+    /// Only the owner may transfer a vehicle
+    NotOwner,
+}
+
+impl StateMachine for MotorVehicles {
+    type State = BureauState;
+    type Transition = BureauTransaction;
+    type Error = BureauError;
+    /// The officer
+    type GenesisConfig = User;
+
+    fn genesis_state(officer: User) -> BureauState {
+        BureauState {
+            officer,
+            licenses: HashMap::new(),
+            vehicles: HashMap::new(),
+            violations: Vec::new(),
+        }
+    }
+
+    fn next_state(starting_state: &BureauState, t: &BureauTransaction) -> BureauState {
+        Self::next_state_in_context(starting_state, t, &BlockContext::default())
+    }
+
+    fn try_next_state(
+        starting_state: &BureauState,
+        t: &BureauTransaction,
+    ) -> Result<BureauState, BureauError> {
+        Self::try_next_state_in_context(starting_state, t, &BlockContext::default())
+    }
+
+    fn human_name() -> String {
+        "Bureau of motor vehicles".into()
+    }
+}
+
+/// License validity and suspensions are measured in blocks.
+impl ContextualStateMachine for MotorVehicles {
+    fn next_state_in_context(
+        starting_state: &BureauState,
+        t: &BureauTransaction,
+        context: &BlockContext,
+    ) -> BureauState {
+        Self::try_next_state_in_context(starting_state, t, context)
+            .unwrap_or_else(|_| starting_state.clone())
+    }
+
+    fn try_next_state_in_context(
+        starting_state: &BureauState,
+        t: &BureauTransaction,
+        context: &BlockContext,
+    ) -> Result<BureauState, BureauError> {
+        let height = context.height;
+        let mut s = starting_state.clone();
+        match t {
+            BureauTransaction::IssueLicense { officer, driver } => {
+                check_officer(&s, *officer)?;
+                if s.licenses.contains_key(driver) {
+                    return Err(BureauError::AlreadyLicensed);
+                }
+                s.licenses.insert(
+                    *driver,
+                    License {
+                        expires_at: height.saturating_add(LICENSE_VALIDITY),
+                        points: 0,
+                        suspended_until: None,
+                    },
+                );
+            }
+            BureauTransaction::RenewLicense { driver } => {
+                let license = s.licenses.get_mut(driver).ok_or(BureauError::NoLicense)?;
+                if let LicenseStatus::Suspended(until) = license.status(height) {
+                    return Err(BureauError::Suspended(until));
+                }
+                license.expires_at = license
+                    .expires_at
+                    .max(height)
+                    .saturating_add(LICENSE_VALIDITY);
+            }
+            BureauTransaction::SuspendLicense {
+                officer,
+                driver,
+                until,
+            } => {
+                check_officer(&s, *officer)?;
+                if *until <= height {
+                    return Err(BureauError::InvalidSuspension);
+                }
+                let license = s.licenses.get_mut(driver).ok_or(BureauError::NoLicense)?;
+                suspend(license, *until);
+            }
+            BureauTransaction::RegisterVehicle { owner, vin } => {
+                if s.vehicles.contains_key(vin) {
+                    return Err(BureauError::VehicleExists);
+                }
+                s.vehicles.insert(vin.clone(), *owner);
+            }
+            BureauTransaction::TransferVehicle { owner, vin, to } => {
+                let current = s.vehicles.get_mut(vin).ok_or(BureauError::UnknownVehicle)?;
+                if current != owner {
+                    return Err(BureauError::NotOwner);
+                }
+                *current = *to;
+            }
+            BureauTransaction::RecordViolation {
+                officer,
+                driver,
+                vin,
+                offence,
+            } => {
+                check_officer(&s, *officer)?;
+                if !s.vehicles.contains_key(vin) {
+                    return Err(BureauError::UnknownVehicle);
+                }
+                let license = s.licenses.get_mut(driver).ok_or(BureauError::NoLicense)?;
+                license.points += offence.points();
+                if license.points >= SUSPENSION_POINTS {
+                    suspend(license, height.saturating_add(SUSPENSION_PERIOD));
+                    license.points = 0;
+                }
+                s.violations.push(Violation {
+                    driver: *driver,
+                    vin: vin.clone(),
+                    offence: *offence,
+                    at: height,
+                });
+            }
+        }
+        Ok(s)
+    }
+}
+
+/// Every bureau transaction costs the same.
+impl Weighted for MotorVehicles {
+    fn weight(_: &BureauTransaction) -> u64 {
+        1
+    }
+}
+
+fn check_officer(state: &BureauState, who: User) -> Result<(), BureauError> {
+    if who != state.officer {
+        return Err(BureauError::NotOfficer);
+    }
+    Ok(())
+}
+
+/// Suspend a license until the given height, unless it is already suspended for longer.
+fn suspend(license: &mut License, until: u64) {
+    license.suspended_until = Some(license.suspended_until.map_or(until, |u| u.max(until)));
+}
+
+#[cfg(test)]
+fn at_height(height: u64) -> BlockContext {
+    BlockContext {
+        height,
+        ..BlockContext::default()
+    }
+}
+
+#[cfg(test)]
+fn licensed() -> BureauState {
+    let ts = [
+        BureauTransaction::IssueLicense {
+            officer: User::Alice,
+            driver: User::Bob,
+        },
+        BureauTransaction::RegisterVehicle {
+            owner: User::Charlie,
+            vin: "VIN1".into(),
+        },
+    ];
+    MotorVehicles::try_apply_all(&MotorVehicles::genesis_state(User::Alice), &ts).unwrap()
+}
+
+#[cfg(test)]
+fn violation(offence: Offence) -> BureauTransaction {
+    BureauTransaction::RecordViolation {
+        officer: User::Alice,
+        driver: User::Bob,
+        vin: "VIN1".into(),
+        offence,
+    }
+}
+
+#[test]
+fn sm_17_license_expires_and_renews() {
+    let state = licensed();
+    let license = &state.licenses[&User::Bob];
+
+    assert_eq!(license.status(LICENSE_VALIDITY - 1), LicenseStatus::Valid);
+    assert_eq!(license.status(LICENSE_VALIDITY), LicenseStatus::Expired);
+
+    let renewed = MotorVehicles::try_next_state_in_context(
+        &state,
+        &BureauTransaction::RenewLicense { driver: User::Bob },
+        &at_height(LICENSE_VALIDITY + 10),
+    )
+    .unwrap();
+    assert_eq!(
+        renewed.licenses[&User::Bob].expires_at,
+        2 * LICENSE_VALIDITY + 10
+    );
+}
+
+#[test]
+fn sm_17_violations_trigger_suspension() {
+    let ts = [
+        violation(Offence::Speeding),
+        violation(Offence::RunningRedLight),
+    ];
+    let warned = MotorVehicles::try_apply_all_in_context(&licensed(), &ts, &at_height(5)).unwrap();
+    assert_eq!(warned.licenses[&User::Bob].points, 7);
+    assert_eq!(warned.licenses[&User::Bob].status(5), LicenseStatus::Valid);
+
+    let suspended = MotorVehicles::try_next_state_in_context(
+        &warned,
+        &violation(Offence::RecklessDriving),
+        &at_height(5),
+    )
+    .unwrap();
+    let license = &suspended.licenses[&User::Bob];
+    assert_eq!(license.points, 0);
+    assert_eq!(
+        license.status(5),
+        LicenseStatus::Suspended(5 + SUSPENSION_PERIOD)
+    );
+    assert_eq!(license.status(5 + SUSPENSION_PERIOD), LicenseStatus::Valid);
+    assert_eq!(suspended.violations_of(User::Bob).len(), 3);
+
+    assert_eq!(
+        MotorVehicles::try_next_state(
+            &suspended,
+            &BureauTransaction::RenewLicense { driver: User::Bob }
+        ),
+        Err(BureauError::Suspended(5 + SUSPENSION_PERIOD))
+    );
+}
+
+#[test]
+fn sm_17_violations_need_license_and_vehicle() {
+    let state = licensed();
+
+    assert_eq!(
+        MotorVehicles::try_next_state(
+            &state,
+            &BureauTransaction::RecordViolation {
+                officer: User::Alice,
+                driver: User::Charlie,
+                vin: "VIN1".into(),
+                offence: Offence::Speeding,
+            }
+        ),
+        Err(BureauError::NoLicense)
+    );
+    assert_eq!(
+        MotorVehicles::try_next_state(
+            &state,
+            &BureauTransaction::RecordViolation {
+                officer: User::Alice,
+                driver: User::Bob,
+                vin: "VIN2".into(),
+                offence: Offence::Speeding,
+            }
+        ),
+        Err(BureauError::UnknownVehicle)
+    );
+}
+
+#[test]
+fn sm_17_vehicle_transfer() {
+    let transfer = |owner| BureauTransaction::TransferVehicle {
+        owner,
+        vin: "VIN1".into(),
+        to: User::Bob,
+    };
+    let state = licensed();
+
+    assert_eq!(
+        MotorVehicles::try_next_state(&state, &transfer(User::Bob)),
+        Err(BureauError::NotOwner)
+    );
+    let transferred = MotorVehicles::try_next_state(&state, &transfer(User::Charlie)).unwrap();
+    assert_eq!(transferred.vehicles_of(User::Bob), vec!["VIN1"]);
+    assert!(transferred.vehicles_of(User::Charlie).is_empty());
+
+    assert_eq!(
+        MotorVehicles::try_next_state(
+            &transferred,
+            &BureauTransaction::RegisterVehicle {
+                owner: User::Alice,
+                vin: "VIN1".into()
+            }
+        ),
+        Err(BureauError::VehicleExists)
+    );
+}
+
+#[test]
+fn sm_17_only_officer_issues_and_suspends() {
+    let state = licensed();
+
+    assert_eq!(
+        MotorVehicles::try_next_state(
+            &state,
+            &BureauTransaction::IssueLicense {
+                officer: User::Bob,
+                driver: User::Charlie
+            }
+        ),
+        Err(BureauError::NotOfficer)
+    );
+    assert_eq!(
+        MotorVehicles::try_next_state(
+            &state,
+            &BureauTransaction::IssueLicense {
+                officer: User::Alice,
+                driver: User::Bob
+            }
+        ),
+        Err(BureauError::AlreadyLicensed)
+    );
+
+    let suspend = |until| BureauTransaction::SuspendLicense {
+        officer: User::Alice,
+        driver: User::Bob,
+        until,
+    };
+    assert_eq!(
+        MotorVehicles::try_next_state(&state, &suspend(0)),
+        Err(BureauError::InvalidSuspension)
+    );
+    let suspended = MotorVehicles::try_next_state(&state, &suspend(50)).unwrap();
+    assert_eq!(
+        suspended.licenses[&User::Bob].status(49),
+        LicenseStatus::Suspended(50)
+    );
+}
@@ -0,0 +1,558 @@
+//! Every transaction on a blockchain costs fees and has to wait for a block. Two parties who pay
+//! each other often can avoid both with a payment channel: they lock up deposits on chain once,
+//! then pay each other by exchanging signed balance updates off chain, and only return to the
+//! chain to close the channel.
+//!
+//! Each balance update carries a nonce, and both parties sign it. The chain never sees most of
+//! them. When the parties are done they close the channel together with an update marked as
+//! final, and the chain pays out the balances in it.
+//!
+//! If one party stops cooperating, the other may close the channel unilaterally with the latest
+//! update they hold. That starts a challenge period, during which the counterparty may submit
+//! any newer update, so nobody can get away with closing on an old balance that suited them
+//! better. Once the period is over, anyone may settle the channel.
+//!
+//! Like the rest of this tutorial, signatures here are stand-ins. The signer is trusted, as it is
+//! for nonced transactions, but the digest ties each signature to one exact update, so that a
+//! signature on one update cannot be passed off as a signature on another.
+
+use std::collections::HashMap;
+
+use super::{BlockContext, ContextualStateMachine, StateMachine, User, Weighted};
+use crate::hash;
+
+/// The number of blocks after a unilateral close during which newer updates may be submitted
+pub const CHALLENGE_PERIOD: u64 = 20;
+
+/// This state machine models two-party payment channels.
+pub struct PaymentChannels;
+
+/// Channels are numbered in the order they were opened, starting from 0.
+pub type ChannelId = usize;
+
+/// A split of a channel's deposits between its two parties, agreed off chain
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BalanceUpdate {
+    pub channel: ChannelId,
+    /// Later updates have higher nonces. The opening balances have nonce 0.
+    pub nonce: u64,
+    pub balance_a: u64,
+    pub balance_b: u64,
+    /// Whether the parties agree to close the channel at these balances
+    pub closing: bool,
+}
+
+impl BalanceUpdate {
+    /// Sign this update as the given user
+    pub fn sign(&self, signer: User) -> Signature {
+        Signature {
+            signer,
+            digest: hash(&(signer, self)),
+        }
+    }
+}
+
+/// A user's signature on a balance update
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Signature {
+    pub signer: User,
+    pub digest: u64,
+}
+
+/// A balance update signed by both parties
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SignedUpdate {
+    pub update: BalanceUpdate,
+    pub by_a: Signature,
+    pub by_b: Signature,
+}
+
+/// The lifecycle of a channel
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ChannelStatus {
+    Open,
+    /// Closed unilaterally, and open to challenges until the given height
+    Closing {
+        closes_at: u64,
+    },
+    /// The balances have been paid out
+    Closed,
+}
+
+/// A payment channel between two parties
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Channel {
+    pub party_a: User,
+    pub party_b: User,
+    /// The latest balance update the chain has seen
+    pub latest: BalanceUpdate,
+    pub status: ChannelStatus,
+}
+
+/// The state of all payment channels
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChannelState {
+    /// Funds not locked in any channel. Zero balances are not stored.
+    pub balances: HashMap<User, u64>,
+    pub channels: Vec<Channel>,
+}
+
+/// The state transitions that users can make with payment channels
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ChannelTransaction {
+    /// Open a channel, locking up both parties' deposits. Both parties sign this transaction.
+    Open {
+        party_a: User,
+        party_b: User,
+        deposit_a: u64,
+        deposit_b: u64,
+    },
+    /// Close the channel immediately with a final update
+    CooperativeClose(SignedUpdate),
+    /// Start closing the channel on the latest update the closer holds, or on the opening
+    /// balances if the parties never exchanged any
+    UnilateralClose {
+        who: User,
+        channel: ChannelId,
+        update: Option<SignedUpdate>,
+    },
+    /// Replace the balances of a closing channel with a newer update
+    Challenge { who: User, update: SignedUpdate },
+    /// Pay out a channel whose challenge period is over. Anyone may do this.
+    Settle { channel: ChannelId },
+}
+
+/// The reasons a transaction may be rejected by the payment channels
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChannelError {
+    /// A channel needs two different parties
+    SameParty,
+    /// A party does not have enough free funds for their deposit
+    InsufficientBalance { available: u64, requested: u64 },
+    /// No channel with this id exists
+    UnknownChannel(ChannelId),
+    /// Only the channel's parties may close or challenge it
+    NotParticipant,
+    /// The update is not signed by both parties
+    InvalidSignature,
+    /// The update's balances do not add up to the channel's deposits
+    WrongTotal { expected: u128, found: u128 },
+    /// Cooperative closes need an update marked as closing
+    NotFinal,
+    /// The update is not newer than the latest one the chain has seen
+    StaleUpdate { latest: u64 },
+    /// The channel's status does not allow this transaction
+    WrongStatus(ChannelStatus),
+    /// The challenge period ends at the given height
+    ChallengePeriod { closes_at: u64 },
+}
+
+impl StateMachine for PaymentChannels {
+    type State = ChannelState;
+    type Transition = ChannelTransaction;
+    type Error = ChannelError;
+    /// The initial free funds of each user
+    type GenesisConfig = Vec<(User, u64)>;
+
+    fn genesis_state(endowments: Vec<(User, u64)>) -> ChannelState {
+        let mut state = ChannelState::default();
+        for (who, amount) in endowments {
+            credit(&mut state, who, amount);
+        }
+        state
+    }
+
+    fn next_state(starting_state: &ChannelState, t: &ChannelTransaction) -> ChannelState {
+        Self::next_state_in_context(starting_state, t, &BlockContext::default())
+    }
+
+    fn try_next_state(
+        starting_state: &ChannelState,
+        t: &ChannelTransaction,
+    ) -> Result<ChannelState, ChannelError> {
+        Self::try_next_state_in_context(starting_state, t, &BlockContext::default())
+    }
+
+    fn human_name() -> String {
+        "Payment channels".into()
+    }
+}
+
+/// Challenge periods are measured in blocks.
+impl ContextualStateMachine for PaymentChannels {
+    fn next_state_in_context(
+        starting_state: &ChannelState,
+        t: &ChannelTransaction,
+        context: &BlockContext,
+    ) -> ChannelState {
+        Self::try_next_state_in_context(starting_state, t, context)
+            .unwrap_or_else(|_| starting_state.clone())
+    }
+
+    fn try_next_state_in_context(
+        starting_state: &ChannelState,
+        t: &ChannelTransaction,
+        context: &BlockContext,
+    ) -> Result<ChannelState, ChannelError> {
+        let height = context.height;
+        let mut s = starting_state.clone();
+        match t {
+            ChannelTransaction::Open {
+                party_a,
+                party_b,
+                deposit_a,
+                deposit_b,
+            } => {
+                if party_a == party_b {
+                    return Err(ChannelError::SameParty);
+                }
+                lock(&mut s, *party_a, *deposit_a)?;
+                lock(&mut s, *party_b, *deposit_b)?;
+                let channel = s.channels.len();
+                s.channels.push(Channel {
+                    party_a: *party_a,
+                    party_b: *party_b,
+                    latest: BalanceUpdate {
+                        channel,
+                        nonce: 0,
+                        balance_a: *deposit_a,
+                        balance_b: *deposit_b,
+                        closing: false,
+                    },
+                    status: ChannelStatus::Open,
+                });
+            }
+            ChannelTransaction::CooperativeClose(signed) => {
+                let c = verified_channel(&mut s, signed)?;
+                if !signed.update.closing {
+                    return Err(ChannelError::NotFinal);
+                }
+                if c.status != ChannelStatus::Open {
+                    return Err(ChannelError::WrongStatus(c.status));
+                }
+                c.latest = signed.update.clone();
+                settle(&mut s, signed.update.channel);
+            }
+            ChannelTransaction::UnilateralClose {
+                who,
+                channel,
+                update,
+            } => {
+                if let Some(signed) = update {
+                    if signed.update.channel != *channel {
+                        return Err(ChannelError::UnknownChannel(signed.update.channel));
+                    }
+                    newer_update(&mut s, signed)?;
+                }
+                let c = channel_mut(&mut s, *channel)?;
+                check_participant(c, *who)?;
+                if c.status != ChannelStatus::Open {
+                    return Err(ChannelError::WrongStatus(c.status));
+                }
+                if let Some(signed) = update {
+                    c.latest = signed.update.clone();
+                }
+                c.status = ChannelStatus::Closing {
+                    closes_at: height.saturating_add(CHALLENGE_PERIOD),
+                };
+            }
+            ChannelTransaction::Challenge { who, update } => {
+                let c = newer_update(&mut s, update)?;
+                check_participant(c, *who)?;
+                match c.status {
+                    ChannelStatus::Closing { closes_at } if height < closes_at => {}
+                    ChannelStatus::Closing { closes_at } => {
+                        return Err(ChannelError::ChallengePeriod { closes_at })
+                    }
+                    status => return Err(ChannelError::WrongStatus(status)),
+                }
+                c.latest = update.update.clone();
+            }
+            ChannelTransaction::Settle { channel } => {
+                let c = channel_mut(&mut s, *channel)?;
+                match c.status {
+                    ChannelStatus::Closing { closes_at } if height < closes_at => {
+                        return Err(ChannelError::ChallengePeriod { closes_at })
+                    }
+                    ChannelStatus::Closing { .. } => {}
+                    status => return Err(ChannelError::WrongStatus(status)),
+                }
+                settle(&mut s, *channel);
+            }
+        }
+        Ok(s)
+    }
+}
+
+/// Every channel transaction costs the same, which is the point of keeping most of them off
+/// chain.
+impl Weighted for PaymentChannels {
+    fn weight(_: &ChannelTransaction) -> u64 {
+        1
+    }
+}
+
+fn channel_mut(state: &mut ChannelState, id: ChannelId) -> Result<&mut Channel, ChannelError> {
+    state
+        .channels
+        .get_mut(id)
+        .ok_or(ChannelError::UnknownChannel(id))
+}
+
+fn check_participant(channel: &Channel, who: User) -> Result<(), ChannelError> {
+    if who != channel.party_a && who != channel.party_b {
+        return Err(ChannelError::NotParticipant);
+    }
+    Ok(())
+}
+
+/// The channel the update belongs to, provided both of its parties signed the update and the
+/// balances add up to its deposits.
+fn verified_channel<'a>(
+    state: &'a mut ChannelState,
+    signed: &SignedUpdate,
+) -> Result<&'a mut Channel, ChannelError> {
+    let update = &signed.update;
+    let channel = channel_mut(state, update.channel)?;
+    if signed.by_a != update.sign(channel.party_a) || signed.by_b != update.sign(channel.party_b) {
+        return Err(ChannelError::InvalidSignature);
+    }
+    let expected = channel.latest.balance_a as u128 + channel.latest.balance_b as u128;
+    let found = update.balance_a as u128 + update.balance_b as u128;
+    if found != expected {
+        return Err(ChannelError::WrongTotal { expected, found });
+    }
+    Ok(channel)
+}
+
+/// Like `verified_channel`, but also requiring the update to be newer than the latest one the
+/// chain has seen.
+fn newer_update<'a>(
+    state: &'a mut ChannelState,
+    signed: &SignedUpdate,
+) -> Result<&'a mut Channel, ChannelError> {
+    let channel = verified_channel(state, signed)?;
+    if signed.update.nonce <= channel.latest.nonce {
+        return Err(ChannelError::StaleUpdate {
+            latest: channel.latest.nonce,
+        });
+    }
+    Ok(channel)
+}
+
+/// Pay out the channel's latest balances. The caller must have checked that it is not closed.
+fn settle(state: &mut ChannelState, id: ChannelId) {
+    let channel = &mut state.channels[id];
+    channel.status = ChannelStatus::Closed;
+    let payouts = [
+        (channel.party_a, channel.latest.balance_a),
+        (channel.party_b, channel.latest.balance_b),
+    ];
+    for (who, amount) in payouts {
+        credit(state, who, amount);
+    }
+}
+
+/// Add free funds to an account. No balance can overflow, because no funds are ever created
+/// after genesis.
+fn credit(state: &mut ChannelState, who: User, amount: u64) {
+    if amount > 0 {
+        let balance = state.balances.entry(who).or_insert(0);
+        *balance = balance.saturating_add(amount);
+    }
+}
+
+/// Remove free funds from an account, to be locked in a channel.
+fn lock(state: &mut ChannelState, who: User, amount: u64) -> Result<(), ChannelError> {
+    let available = state.balances.get(&who).copied().unwrap_or(0);
+    if available < amount {
+        return Err(ChannelError::InsufficientBalance {
+            available,
+            requested: amount,
+        });
+    }
+    if available == amount {
+        state.balances.remove(&who);
+    } else {
+        state.balances.insert(who, available - amount);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+fn at_height(height: u64) -> BlockContext {
+    BlockContext {
+        height,
+        ..BlockContext::default()
+    }
+}
+
+#[cfg(test)]
+fn opened() -> ChannelState {
+    let start = PaymentChannels::genesis_state(vec![(User::Alice, 100), (User::Bob, 50)]);
+    let open = ChannelTransaction::Open {
+        party_a: User::Alice,
+        party_b: User::Bob,
+        deposit_a: 60,
+        deposit_b: 40,
+    };
+    PaymentChannels::try_next_state(&start, &open).unwrap()
+}
+
+#[cfg(test)]
+fn signed(nonce: u64, balance_a: u64, balance_b: u64, closing: bool) -> SignedUpdate {
+    let update = BalanceUpdate {
+        channel: 0,
+        nonce,
+        balance_a,
+        balance_b,
+        closing,
+    };
+    SignedUpdate {
+        by_a: update.sign(User::Alice),
+        by_b: update.sign(User::Bob),
+        update,
+    }
+}
+
+#[cfg(test)]
+fn close_with(update: Option<SignedUpdate>) -> ChannelTransaction {
+    ChannelTransaction::UnilateralClose {
+        who: User::Alice,
+        channel: 0,
+        update,
+    }
+}
+
+#[test]
+fn sm_18_cooperative_close_pays_out_immediately() {
+    let state = opened();
+    assert_eq!(state.balances.get(&User::Alice), Some(&40));
+    assert_eq!(state.balances.get(&User::Bob), Some(&10));
+
+    let close = ChannelTransaction::CooperativeClose(signed(7, 25, 75, true));
+    let closed = PaymentChannels::try_next_state(&state, &close).unwrap();
+    assert_eq!(closed.channels[0].status, ChannelStatus::Closed);
+    assert_eq!(closed.balances.get(&User::Alice), Some(&65));
+    assert_eq!(closed.balances.get(&User::Bob), Some(&85));
+
+    assert_eq!(
+        PaymentChannels::try_next_state(
+            &state,
+            &ChannelTransaction::CooperativeClose(signed(7, 25, 75, false))
+        ),
+        Err(ChannelError::NotFinal)
+    );
+}
+
+#[test]
+fn sm_18_updates_must_be_signed_by_both_and_balanced() {
+    let state = opened();
+
+    let mut forged = signed(1, 100, 0, false);
+    forged.by_b = signed(1, 0, 100, false).by_b;
+    assert_eq!(
+        PaymentChannels::try_next_state(&state, &close_with(Some(forged))),
+        Err(ChannelError::InvalidSignature)
+    );
+    assert_eq!(
+        PaymentChannels::try_next_state(&state, &close_with(Some(signed(1, 100, 100, false)))),
+        Err(ChannelError::WrongTotal {
+            expected: 100,
+            found: 200
+        })
+    );
+}
+
+#[test]
+fn sm_18_challenge_replaces_stale_close() {
+    let state = opened();
+    let closing = PaymentChannels::try_next_state_in_context(
+        &state,
+        &close_with(Some(signed(1, 90, 10, false))),
+        &at_height(5),
+    )
+    .unwrap();
+    assert_eq!(
+        closing.channels[0].status,
+        ChannelStatus::Closing {
+            closes_at: 5 + CHALLENGE_PERIOD
+        }
+    );
+
+    let challenge = |update| ChannelTransaction::Challenge {
+        who: User::Bob,
+        update,
+    };
+    assert_eq!(
+        PaymentChannels::try_next_state_in_context(
+            &closing,
+            &challenge(signed(1, 20, 80, false)),
+            &at_height(6)
+        ),
+        Err(ChannelError::StaleUpdate { latest: 1 })
+    );
+    let challenged = PaymentChannels::try_next_state_in_context(
+        &closing,
+        &challenge(signed(2, 20, 80, false)),
+        &at_height(6),
+    )
+    .unwrap();
+
+    let settle = ChannelTransaction::Settle { channel: 0 };
+    assert_eq!(
+        PaymentChannels::try_next_state_in_context(&challenged, &settle, &at_height(24)),
+        Err(ChannelError::ChallengePeriod { closes_at: 25 })
+    );
+    let settled =
+        PaymentChannels::try_next_state_in_context(&challenged, &settle, &at_height(25)).unwrap();
+    assert_eq!(settled.balances.get(&User::Alice), Some(&60));
+    assert_eq!(settled.balances.get(&User::Bob), Some(&90));
+}
+
+#[test]
+fn sm_18_close_without_updates_returns_deposits() {
+    let closing = PaymentChannels::try_next_state(&opened(), &close_with(None)).unwrap();
+    let settled = PaymentChannels::try_next_state_in_context(
+        &closing,
+        &ChannelTransaction::Settle { channel: 0 },
+        &at_height(CHALLENGE_PERIOD),
+    )
+    .unwrap();
+
+    assert_eq!(settled.balances.get(&User::Alice), Some(&100));
+    assert_eq!(settled.balances.get(&User::Bob), Some(&50));
+    assert_eq!(
+        PaymentChannels::try_next_state_in_context(
+            &settled,
+            &ChannelTransaction::Challenge {
+                who: User::Bob,
+                update: signed(1, 0, 100, false)
+            },
+            &at_height(CHALLENGE_PERIOD)
+        ),
+        Err(ChannelError::WrongStatus(ChannelStatus::Closed))
+    );
+}
+
+#[test]
+fn sm_18_only_parties_may_close() {
+    assert_eq!(
+        PaymentChannels::try_next_state(
+            &opened(),
+            &ChannelTransaction::UnilateralClose {
+                who: User::Charlie,
+                channel: 0,
+                update: None
+            }
+        ),
+        Err(ChannelError::NotParticipant)
+    );
+}
@@ -0,0 +1,366 @@
+//! Tic-tac-toe is the smallest of a family of games in which two players take turns marking
+//! cells of a grid, and whoever first lines up `K` of their marks in a row, column, or diagonal
+//! wins. This machine plays any member of the family, with the board size and the win length as
+//! const parameters.
+//!
+//! Some games in the family add gravity. In Connect Four the board stands upright, so a player
+//! only chooses a column and their piece falls to the lowest empty cell in it. The machine takes
+//! a fourth const parameter saying whether pieces fall, which decides the kind of move it accepts.
+//!
+//! Win detection is shared with the tic-tac-toe machine of part 6 through its `Board` type.
+
+use super::p6_open_ended::Board;
+use super::{ContextualStateMachine, StateMachine, Weighted};
+
+/// This state machine models a game of `K` in a row on a board with `ROWS` rows and `COLS`
+/// columns. Row 0 is the top of the board, so when `GRAVITY` is set pieces fall towards the last
+/// row.
+pub struct KInARow<const ROWS: usize, const COLS: usize, const K: usize, const GRAVITY: bool>;
+
+/// Tic-tac-toe, played by the generic machine.
+pub type TicTacToe = KInARow<3, 3, 3, false>;
+
+/// Gomoku, five in a row on a 15 by 15 board.
+pub type Gomoku = KInARow<15, 15, 5, false>;
+
+/// Connect Four, played on an upright board of six rows and seven columns.
+pub type ConnectFour = KInARow<6, 7, 4, true>;
+
+/// The two players. `First` makes the opening move.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Player {
+    First,
+    Second,
+}
+
+impl Player {
+    /// The player who moves after this one
+    pub fn other(self) -> Player {
+        match self {
+            Player::First => Player::Second,
+            Player::Second => Player::First,
+        }
+    }
+}
+
+/// The state of a game in progress
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct GameState<const ROWS: usize, const COLS: usize> {
+    /// Empty cells hold `None`.
+    pub board: Board<Option<Player>, ROWS, COLS>,
+    /// The cells marked so far, oldest first, as (row, column) pairs.
+    pub history: Vec<(usize, usize)>,
+    /// The player who lined up `K` marks, if any.
+    pub winner: Option<Player>,
+}
+
+impl<const ROWS: usize, const COLS: usize> Default for GameState<ROWS, COLS> {
+    fn default() -> Self {
+        Self {
+            board: Board::new([[None; COLS]; ROWS]),
+            history: Vec::new(),
+            winner: None,
+        }
+    }
+}
+
+impl<const ROWS: usize, const COLS: usize> GameState<ROWS, COLS> {
+    /// The player whose turn it is. Players alternate, starting with `First`.
+    pub fn to_move(&self) -> Player {
+        if self.history.len().is_multiple_of(2) {
+            Player::First
+        } else {
+            Player::Second
+        }
+    }
+
+    /// Whether the game is over, either because somebody won or because the board is full.
+    pub fn is_over(&self) -> bool {
+        self.winner.is_some() || self.history.len() == ROWS * COLS
+    }
+}
+
+/// The moves players can make
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum KInARowMove {
+    /// Mark the given cell. Only allowed in games without gravity.
+    Place {
+        player: Player,
+        row: usize,
+        col: usize,
+    },
+    /// Drop a piece into the given column, where it lands on the lowest empty cell. Only
+    /// allowed in games with gravity.
+    Drop { player: Player, col: usize },
+}
+
+/// The reasons a move may be rejected
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KInARowError {
+    /// The game has already been won or the board is full
+    GameOver,
+    /// It is the other player's turn
+    OutOfTurn,
+    /// A `Drop` in a game without gravity, or a `Place` in a game with it
+    WrongMoveKind,
+    /// The move refers to a cell or column that is not on the board
+    OffBoard,
+    /// The cell is already marked
+    CellTaken,
+    /// Every cell of the column is already marked
+    ColumnFull,
+}
+
+impl<const ROWS: usize, const COLS: usize, const K: usize, const GRAVITY: bool> StateMachine
+    for KInARow<ROWS, COLS, K, GRAVITY>
+{
+    type State = GameState<ROWS, COLS>;
+    type Transition = KInARowMove;
+    type Error = KInARowError;
+    type GenesisConfig = ();
+
+    /// Every game starts from an empty board.
+    fn genesis_state(_: ()) -> Self::State {
+        GameState::default()
+    }
+
+    /// An invalid move leaves the state unchanged.
+    fn next_state(starting_state: &Self::State, t: &Self::Transition) -> Self::State {
+        Self::try_next_state(starting_state, t).unwrap_or_else(|_| starting_state.clone())
+    }
+
+    fn try_next_state(
+        starting_state: &Self::State,
+        t: &Self::Transition,
+    ) -> Result<Self::State, Self::Error> {
+        if starting_state.is_over() {
+            return Err(KInARowError::GameOver);
+        }
+
+        let (player, row, col) = match *t {
+            KInARowMove::Place { player, row, col } if !GRAVITY => {
+                match starting_state.board.get(row, col) {
+                    None => return Err(KInARowError::OffBoard),
+                    Some(Some(_)) => return Err(KInARowError::CellTaken),
+                    Some(None) => (player, row, col),
+                }
+            }
+            KInARowMove::Drop { player, col } if GRAVITY => {
+                if col >= COLS {
+                    return Err(KInARowError::OffBoard);
+                }
+                let row = (0..ROWS)
+                    .rev()
+                    .find(|&row| starting_state.board.get(row, col) == Some(&None))
+                    .ok_or(KInARowError::ColumnFull)?;
+                (player, row, col)
+            }
+            _ => return Err(KInARowError::WrongMoveKind),
+        };
+        if player != starting_state.to_move() {
+            return Err(KInARowError::OutOfTurn);
+        }
+
+        let mut state = starting_state.clone();
+        state.board.set(row, col, Some(player));
+        state.history.push((row, col));
+        state.winner = state.board.k_in_a_row(K, &None).copied().flatten();
+        Ok(state)
+    }
+}
+
+impl<const ROWS: usize, const COLS: usize, const K: usize, const GRAVITY: bool>
+    ContextualStateMachine for KInARow<ROWS, COLS, K, GRAVITY>
+{
+}
+
+impl<const ROWS: usize, const COLS: usize, const K: usize, const GRAVITY: bool> Weighted
+    for KInARow<ROWS, COLS, K, GRAVITY>
+{
+    /// Every move costs the same.
+    fn weight(_: &KInARowMove) -> u64 {
+        1
+    }
+}
+
+#[cfg(test)]
+use Player::{First, Second};
+
+/// Drop pieces into the given columns, the players taking turns from the start.
+#[cfg(test)]
+fn drops(cols: &[usize]) -> Vec<KInARowMove> {
+    let mut player = First;
+    cols.iter()
+        .map(|&col| {
+            let t = KInARowMove::Drop { player, col };
+            player = player.other();
+            t
+        })
+        .collect()
+}
+
+#[test]
+fn sm_19_pieces_fall_to_lowest_empty_cell() {
+    let end = ConnectFour::apply_all(&ConnectFour::genesis_state(()), &drops(&[3, 3, 4]));
+
+    assert_eq!(end.board.get(5, 3), Some(&Some(First)));
+    assert_eq!(end.board.get(4, 3), Some(&Some(Second)));
+    assert_eq!(end.board.get(5, 4), Some(&Some(First)));
+    assert_eq!(end.history, vec![(5, 3), (4, 3), (5, 4)]);
+    assert_eq!(end.to_move(), Second);
+}
+
+#[test]
+fn sm_19_connect_four_vertical_win() {
+    let end = ConnectFour::apply_all(
+        &ConnectFour::genesis_state(()),
+        &drops(&[0, 1, 0, 1, 0, 1, 0]),
+    );
+
+    assert_eq!(end.winner, Some(First));
+    assert!(end.is_over());
+    assert_eq!(
+        ConnectFour::try_next_state(
+            &end,
+            &KInARowMove::Drop {
+                player: Second,
+                col: 1
+            }
+        ),
+        Err(KInARowError::GameOver)
+    );
+}
+
+#[test]
+fn sm_19_connect_four_diagonal_win() {
+    // Second builds a staircase that First climbs.
+    let end = ConnectFour::apply_all(
+        &ConnectFour::genesis_state(()),
+        &drops(&[0, 1, 1, 2, 2, 3, 2, 3, 3, 6, 3]),
+    );
+
+    assert_eq!(end.winner, Some(First));
+    assert_eq!(end.board.get(2, 3), Some(&Some(First)));
+}
+
+#[test]
+fn sm_19_three_in_a_row_does_not_win_connect_four() {
+    let end = ConnectFour::apply_all(&ConnectFour::genesis_state(()), &drops(&[0, 0, 1, 1, 2]));
+
+    assert_eq!(end.winner, None);
+    assert!(!end.is_over());
+}
+
+#[test]
+fn sm_19_full_column_is_rejected() {
+    let full = ConnectFour::apply_all(&ConnectFour::genesis_state(()), &drops(&[2; 6]));
+
+    assert_eq!(full.winner, None);
+    assert_eq!(
+        ConnectFour::try_next_state(
+            &full,
+            &KInARowMove::Drop {
+                player: First,
+                col: 2
+            }
+        ),
+        Err(KInARowError::ColumnFull)
+    );
+    assert_eq!(
+        ConnectFour::try_next_state(
+            &full,
+            &KInARowMove::Drop {
+                player: First,
+                col: 7
+            }
+        ),
+        Err(KInARowError::OffBoard)
+    );
+}
+
+#[test]
+fn sm_19_move_kind_must_match_gravity() {
+    let place = KInARowMove::Place {
+        player: First,
+        row: 0,
+        col: 0,
+    };
+    let drop = KInARowMove::Drop {
+        player: First,
+        col: 0,
+    };
+
+    assert_eq!(
+        ConnectFour::try_next_state(&ConnectFour::genesis_state(()), &place),
+        Err(KInARowError::WrongMoveKind)
+    );
+    assert_eq!(
+        TicTacToe::try_next_state(&TicTacToe::genesis_state(()), &drop),
+        Err(KInARowError::WrongMoveKind)
+    );
+}
+
+#[test]
+fn sm_19_players_alternate() {
+    let start = TicTacToe::genesis_state(());
+    let second_first = KInARowMove::Place {
+        player: Second,
+        row: 1,
+        col: 1,
+    };
+
+    assert_eq!(
+        TicTacToe::try_next_state(&start, &second_first),
+        Err(KInARowError::OutOfTurn)
+    );
+    assert_eq!(TicTacToe::next_state(&start, &second_first), start);
+}
+
+#[test]
+fn sm_19_tic_tac_toe_anti_diagonal_win() {
+    let moves = [(0, 2), (0, 0), (1, 1), (0, 1), (2, 0)];
+    let mut player = First;
+    let mut state = TicTacToe::genesis_state(());
+    for (row, col) in moves {
+        let t = KInARowMove::Place { player, row, col };
+        state = TicTacToe::try_next_state(&state, &t).unwrap();
+        player = player.other();
+    }
+
+    assert_eq!(state.winner, Some(First));
+}
+
+#[test]
+fn sm_19_taken_cell_is_rejected() {
+    let place = |player, row, col| KInARowMove::Place { player, row, col };
+    let state = TicTacToe::next_state(&TicTacToe::genesis_state(()), &place(First, 1, 1));
+
+    assert_eq!(
+        TicTacToe::try_next_state(&state, &place(Second, 1, 1)),
+        Err(KInARowError::CellTaken)
+    );
+    assert_eq!(
+        TicTacToe::try_next_state(&state, &place(Second, 3, 0)),
+        Err(KInARowError::OffBoard)
+    );
+}
+
+#[test]
+fn sm_19_gomoku_needs_five() {
+    let mut state = Gomoku::genesis_state(());
+    for col in 0..5 {
+        for (player, row) in [(First, 7), (Second, 0)] {
+            if state.is_over() {
+                break;
+            }
+            let t = KInARowMove::Place { player, row, col };
+            state = Gomoku::try_next_state(&state, &t).unwrap();
+        }
+        assert_eq!(state.winner.is_some(), col == 4);
+    }
+
+    assert_eq!(state.winner, Some(First));
+    assert_eq!(state.history.len(), 9);
+}
@@ -2,7 +2,8 @@
 //! In these examples, we use actually switch boards as the state machine. The state is,
 //! well, just the state of the switches.
 
-use super::StateMachine;
+use super::upgrade::Migrate;
+use super::{ContextualStateMachine, InvertibleStateMachine, StateMachine, Weighted};
 
 /// This state machine models a single light switch.
 /// The internal state, a bool, represents whether the switch is on or not.
@@ -13,6 +14,13 @@ pub struct LightSwitch;
 impl StateMachine for LightSwitch {
     type State = bool;
     type Transition = ();
+    type Error = core::convert::Infallible;
+    type GenesisConfig = ();
+
+    /// The light starts off.
+    fn genesis_state(_: ()) -> bool {
+        false
+    }
 
 	fn next_state(starting_state: &bool, t: &()) -> bool {
 		match starting_state {
@@ -22,18 +30,36 @@ impl StateMachine for LightSwitch {
 	}
 }
 
+impl ContextualStateMachine for LightSwitch {}
+
+/// Toggling a switch is its own inverse.
+impl InvertibleStateMachine for LightSwitch {
+    fn undo(ending_state: &bool, t: &()) -> bool {
+        LightSwitch::next_state(ending_state, t)
+    }
+}
+
+/// Every toggle takes the same effort.
+impl Weighted for LightSwitch {
+    fn weight(_: &()) -> u64 {
+        1
+    }
+}
+
 /// This second  state machine models two light switches with one weird property.
 /// Whenever switch one is turned off, switch two also goes off.
 pub struct WeirdSwitchMachine;
 
 /// The state is now two switches instead of one so we use a struct.
 #[derive(PartialEq, Eq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct TwoSwitches {
     first_switch: bool,
     second_switch: bool,
 }
 
 /// Now there are two switches so we need a proper type for the transition.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Toggle {
     FirstSwitch,
     SecondSwitch,
@@ -43,6 +69,16 @@ pub enum Toggle {
 impl StateMachine for WeirdSwitchMachine {
     type State = TwoSwitches;
     type Transition = Toggle;
+    type Error = core::convert::Infallible;
+    type GenesisConfig = ();
+
+    /// Both switches start off.
+    fn genesis_state(_: ()) -> TwoSwitches {
+        TwoSwitches {
+            first_switch: false,
+            second_switch: false,
+        }
+    }
 
     fn next_state(starting_state: &TwoSwitches, t: &Toggle) -> TwoSwitches {
 		let mut s = starting_state.clone();
@@ -75,6 +111,23 @@ impl StateMachine for WeirdSwitchMachine {
 	}
 }
 
+impl ContextualStateMachine for WeirdSwitchMachine {}
+
+/// When the single light switch is upgraded to the weird two-switch board at block 5, the
+/// existing switch becomes the first switch and the new second switch starts off.
+pub struct AddSecondSwitch;
+
+impl Migrate<LightSwitch, WeirdSwitchMachine> for AddSecondSwitch {
+    const FORK_HEIGHT: u64 = 5;
+
+    fn migrate(state: &bool) -> TwoSwitches {
+        TwoSwitches {
+            first_switch: *state,
+            second_switch: false,
+        }
+    }
+}
+
 #[test]
 fn sm_1_light_switch_toggles_off() {
     assert!(!LightSwitch::next_state(&true, &()));
@@ -166,3 +219,114 @@ fn sm_1_two_switches_second_goes_off() {
         }
     );
 }
+
+#[test]
+fn sm_1_light_switch_apply_all() {
+    assert!(LightSwitch::apply_all(&false, &[(), (), ()]));
+    assert!(!LightSwitch::apply_all(&false, &[(), ()]));
+    assert!(!LightSwitch::apply_all(&false, &[]));
+}
+
+#[test]
+fn sm_1_two_switches_apply_all() {
+    let state = TwoSwitches {
+        first_switch: false,
+        second_switch: false,
+    };
+
+    assert_eq!(
+        WeirdSwitchMachine::apply_all(
+            &state,
+            &[Toggle::SecondSwitch, Toggle::FirstSwitch, Toggle::FirstSwitch]
+        ),
+        TwoSwitches {
+            first_switch: false,
+            second_switch: false,
+        }
+    );
+}
+
+#[test]
+fn sm_1_light_switch_undo() {
+    assert!(LightSwitch::undo(&LightSwitch::next_state(&true, &()), &()));
+    assert!(!LightSwitch::undo_all(
+        &LightSwitch::apply_all(&false, &[(), (), ()]),
+        &[(), (), ()]
+    ));
+}
+
+#[test]
+fn sm_1_genesis_states_are_off() {
+    assert!(!LightSwitch::genesis_state(()));
+    assert_eq!(
+        WeirdSwitchMachine::genesis_state(()),
+        TwoSwitches {
+            first_switch: false,
+            second_switch: false,
+        }
+    );
+}
+
+#[cfg(test)]
+use super::{
+    pair::Either,
+    upgrade::{Upgrade, UpgradeError, Versioned},
+    BlockContext,
+};
+
+#[cfg(test)]
+type UpgradedSwitch = Upgrade<LightSwitch, WeirdSwitchMachine, AddSecondSwitch>;
+
+#[cfg(test)]
+fn at_height(height: u64) -> BlockContext {
+    BlockContext {
+        height,
+        ..BlockContext::default()
+    }
+}
+
+#[test]
+fn sm_1_upgrade_runs_old_rules_before_fork() {
+    let start = UpgradedSwitch::genesis_state(());
+    let end = UpgradedSwitch::try_apply_all_in_context(&start, &[Either::Left(())], &at_height(4));
+
+    assert_eq!(end, Ok(Versioned::Old(true)));
+}
+
+#[test]
+fn sm_1_upgrade_migrates_state_at_fork() {
+    let end = UpgradedSwitch::try_apply_all_in_context(
+        &Versioned::Old(true),
+        &[Either::Right(Toggle::SecondSwitch)],
+        &at_height(5),
+    );
+
+    assert_eq!(
+        end,
+        Ok(Versioned::New(TwoSwitches {
+            first_switch: true,
+            second_switch: true,
+        }))
+    );
+}
+
+#[test]
+fn sm_1_upgrade_migrates_state_in_empty_block() {
+    let end = UpgradedSwitch::apply_all_in_context(&Versioned::Old(false), &[], &at_height(7));
+
+    assert_eq!(end, Versioned::New(WeirdSwitchMachine::genesis_state(())));
+}
+
+#[test]
+fn sm_1_upgrade_rejects_transitions_for_wrong_version() {
+    let start = Versioned::Old(false);
+
+    assert_eq!(
+        UpgradedSwitch::try_next_state_in_context(&start, &Either::Right(Toggle::FirstSwitch), &at_height(4)),
+        Err(UpgradeError::WrongVersion)
+    );
+    assert_eq!(
+        UpgradedSwitch::try_next_state_in_context(&start, &Either::Left(()), &at_height(5)),
+        Err(UpgradeError::WrongVersion)
+    );
+}
@@ -2,7 +2,7 @@
 //! ready to be worn again. Or course washing and wearing clothes takes its toll on the clothes, and
 //! eventually they get tattered.
 
-use super::StateMachine;
+use super::{ContextualStateMachine, StateMachine};
 
 /// This state machine models the typical life cycle of clothes as they make their way through the laundry
 /// cycle several times before ultimately becoming tattered.
@@ -10,6 +10,7 @@ pub struct ClothesMachine;
 
 /// Models a piece of clothing throughout its lifecycle.
 #[derive(PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ClothesState {
     /// Clean clothes ready to be worn. With some given life left.
     Clean(u64),
@@ -24,6 +25,7 @@ pub enum ClothesState {
 }
 
 /// Something you can do with clothes
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ClothesAction {
     /// Wearing clothes decreases their life by 1 and makes them dirty.
     Wear,
@@ -38,6 +40,14 @@ pub enum ClothesAction {
 impl StateMachine for ClothesMachine {
     type State = ClothesState;
     type Transition = ClothesAction;
+    type Error = core::convert::Infallible;
+    /// How much life brand new clothes have
+    type GenesisConfig = u64;
+
+    /// Brand new clothes are clean.
+    fn genesis_state(life: u64) -> ClothesState {
+        ClothesState::Clean(life)
+    }
 
     fn next_state(starting_state: &ClothesState, t: &ClothesAction) -> ClothesState {
 
@@ -130,6 +140,8 @@ impl StateMachine for ClothesMachine {
 	}
 }
 
+impl ContextualStateMachine for ClothesMachine {}
+
 #[test]
 fn sm_2_wear_clean_clothes() {
     let start = ClothesState::Clean(4);
@@ -297,3 +309,8 @@ fn sm_2_dry_dirty_until_tattered() {
     let expected = ClothesState::Tattered;
     assert_eq!(end, expected);
 }
+
+#[test]
+fn sm_2_new_clothes_are_clean() {
+    assert_eq!(ClothesMachine::genesis_state(5), ClothesState::Clean(5));
+}
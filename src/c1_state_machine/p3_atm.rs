@@ -1,48 +1,156 @@
 //! The automated teller machine gives you cash after you swipe your card and enter your pin.
 //! The atm may fail to give you cash if it is empty or you haven't swiped your card, or you have
 //! entered the wrong pin.
+//!
+//! Each card belongs to an account whose balance the ATM keeps track of. A withdrawal must be
+//! covered both by the cash inside the machine and by the balance of the swiped card's account,
+//! and cash deposited into the machine is credited to that account.
+//!
+//! Keying in the wrong pin too many times in a row locks the card. The ATM refuses a locked card
+//! until the bank's staff unlock it again.
 
-use super::StateMachine;
+use std::collections::HashMap;
+
+use super::{ContextualStateMachine, EventfulStateMachine, StateMachine};
+
+/// Identifies the account a card belongs to
+pub type AccountId = u64;
+
+/// How many wrong pins in a row lock a card
+pub const MAX_PIN_ATTEMPTS: u32 = 3;
 
 /// The keys on the ATM keypad
 #[derive(Hash, Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Key {
+    Zero,
     One,
     Two,
     Three,
     Four,
+    Five,
+    Six,
+    Seven,
+    Eight,
+    Nine,
     Enter,
+    Cancel,
+}
+
+impl Key {
+    /// The digit keys, in order from `Zero` to `Nine`.
+    pub const DIGITS: [Key; 10] = [
+        Key::Zero,
+        Key::One,
+        Key::Two,
+        Key::Three,
+        Key::Four,
+        Key::Five,
+        Key::Six,
+        Key::Seven,
+        Key::Eight,
+        Key::Nine,
+    ];
+
+    /// The value of a digit key, or `None` for `Enter` and `Cancel`.
+    pub fn digit(&self) -> Option<u64> {
+        Key::DIGITS.iter().position(|d| d == self).map(|d| d as u64)
+    }
+}
+
+/// Interpret a sequence of keys as a decimal number, most significant digit first. An empty
+/// sequence is zero. Returns `None` if a key is not a digit or the number does not fit in a `u64`.
+pub fn parse_decimal(keys: &[Key]) -> Option<u64> {
+    keys.iter().try_fold(0u64, |number, key| {
+        number.checked_mul(10)?.checked_add(key.digit()?)
+    })
 }
 
 /// Something you can do to the ATM
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Action {
-    /// Swipe your card at the ATM. The attached value is the hash of the pin
-    /// that should be keyed in on the keypad next.
-    SwipeCard(u64),
-    /// Press a key on the keypad
+    /// Swipe your card at the ATM. The card carries the account it belongs to and
+    /// the hash of the pin that should be keyed in on the keypad next.
+    SwipeCard { account: AccountId, pin_hash: u64 },
+    /// Press a key on the keypad. `Cancel` returns the card and ends the session.
     PressKey(Key),
+    /// Feed cash into the ATM, crediting the authenticated account. Like a withdrawal,
+    /// this ends the session.
+    Deposit(u64),
+    /// The bank's staff unlock a card that was locked after too many wrong pins.
+    AdminUnlock(AccountId),
 }
 
 /// The various states of authentication possible with the ATM
 #[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 enum Auth {
     /// No session has begun yet. Waiting for the user to swipe their card
     Waiting,
-    /// The user has swiped their card, providing the enclosed PIN hash.
-    /// Waiting for the user to key in their pin
-    Authenticating(u64),
-    /// The user has authenticated. Waiting for them to key in the amount
-    /// of cash to withdraw
-    Authenticated,
+    /// The user has swiped the card of the enclosed account, providing the enclosed
+    /// PIN hash. Waiting for the user to key in their pin
+    Authenticating(AccountId, u64),
+    /// The user has authenticated as the enclosed account. Waiting for them to key
+    /// in the amount of cash to withdraw, or to deposit some
+    Authenticated(AccountId),
 }
 
-/// The ATM. When a card is swiped, the ATM learns the correct pin's hash.
-/// It waits for you to key in your pin. You can press as many numeric keys as
+/// The reasons the ATM may reject an action
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum AtmError {
+    /// A key was pressed before any card was swiped
+    NoCardSwiped,
+    /// The keyed in pin does not match the swiped card
+    WrongPin,
+    /// The swiped card was locked after too many wrong pins
+    CardLocked,
+    /// An unlock was requested for a card that is not locked
+    CardNotLocked,
+    /// The keyed in withdrawal does not fit in a `u64`
+    AmountTooLarge,
+    /// The requested withdrawal exceeds the cash inside the machine
+    InsufficientCash,
+    /// The requested withdrawal exceeds the balance of the swiped card's account
+    InsufficientFunds,
+    /// Cash was deposited before the pin was accepted
+    NotAuthenticated,
+    /// The deposit would overflow the cash inside the machine or the account balance
+    DepositTooLarge,
+}
+
+/// The things the ATM reports while it is being used
+#[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AtmEvent {
+    /// A card was swiped and the ATM is waiting for its pin
+    CardSwiped,
+    /// The keyed in pin matched the swiped card
+    PinAccepted,
+    /// The keyed in pin did not match the swiped card, so the card was returned
+    PinRejected,
+    /// Cash was handed out
+    CashWithdrawn { amount: u64 },
+    /// A withdrawal was requested but the ATM or the account did not hold enough cash.
+    /// Amounts too large to represent are reported as `u64::MAX`.
+    WithdrawalDeclined { requested: u64 },
+    /// Cash was fed into the machine and credited to the account
+    CashDeposited { amount: u64 },
+    /// The session was cancelled and the card returned
+    SessionCancelled,
+    /// Another wrong pin locked the card
+    CardLocked,
+    /// A locked card was unlocked by the bank's staff
+    CardUnlocked,
+}
+
+/// The ATM. When a card is swiped, the ATM learns the card's account and the correct
+/// pin's hash. It waits for you to key in your pin. You can press as many numeric keys as
 /// you like followed by enter. If the pin is incorrect, your card is returned
 /// and the ATM automatically goes back to the main menu. If your pin is correct,
-/// the ATM waits for you to key in an amount of money to withdraw. Withdraws
-/// are bounded only by the cash in the machine (there is no account balance).
+/// the ATM waits for you to key in an amount of money to withdraw, or to deposit cash.
+/// Withdraws are bounded by both the cash in the machine and the account balance.
 #[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Atm {
     /// How much money is in the ATM
     cash_inside: u64,
@@ -50,111 +158,288 @@ pub struct Atm {
     expected_pin_hash: Auth,
     /// All the keys that have been pressed since the last `Enter`
     keystroke_register: Vec<Key>,
+    /// The balance of every account. Zero balances are not stored.
+    accounts: HashMap<AccountId, u64>,
+    /// How many wrong pins have been keyed in a row for each card. Cards with no
+    /// failed attempts are not stored.
+    failed_attempts: HashMap<AccountId, u32>,
+}
+
+impl Atm {
+    /// The balance of the given account.
+    pub fn balance(&self, account: AccountId) -> u64 {
+        self.accounts.get(&account).copied().unwrap_or(0)
+    }
+
+    /// Whether the card of the given account has been locked after too many wrong pins.
+    pub fn is_locked(&self, account: AccountId) -> bool {
+        self.failed_attempts.get(&account).is_some_and(|n| *n >= MAX_PIN_ATTEMPTS)
+    }
+}
+
+/// Set an account's balance, dropping the entry when it reaches zero.
+fn set_balance(accounts: &mut HashMap<AccountId, u64>, account: AccountId, balance: u64) {
+	if balance == 0 {
+		accounts.remove(&account);
+	} else {
+		accounts.insert(account, balance);
+	}
 }
 
 impl StateMachine for Atm {
     // Notice that we are using the same type for the state as we are using for the machine this time.
     type State = Self;
     type Transition = Action;
+    type Error = AtmError;
+    /// How much cash the ATM is loaded with, and the opening balance of each account
+    type GenesisConfig = (u64, Vec<(AccountId, u64)>);
+
+    /// A freshly loaded ATM waiting for its first card.
+    fn genesis_state((cash_inside, balances): (u64, Vec<(AccountId, u64)>)) -> Self::State {
+        let mut accounts = HashMap::new();
+        for (account, balance) in balances {
+            let total = accounts.get(&account).copied().unwrap_or(0u64).saturating_add(balance);
+            set_balance(&mut accounts, account, total);
+        }
+        Atm {
+            cash_inside,
+            expected_pin_hash: Auth::Waiting,
+            keystroke_register: Vec::new(),
+            accounts,
+            failed_attempts: HashMap::new(),
+        }
+    }
 
     fn next_state(starting_state: &Self::State, t: &Self::Transition) -> Self::State {
-		
+
 		match t {
-			 
-			 Action::SwipeCard(pin) => {
-				return  Atm{cash_inside:starting_state.cash_inside, expected_pin_hash:Auth::Authenticating(*pin), keystroke_register: starting_state.keystroke_register.clone()};
+
+			 Action::SwipeCard { account, pin_hash } => {
+				if starting_state.is_locked(*account) {
+					return starting_state.clone();
+				}
+				Atm{expected_pin_hash:Auth::Authenticating(*account, *pin_hash), ..starting_state.clone()}
+			 },
+
+			 Action::AdminUnlock(account) => {
+				let mut failed_attempts = starting_state.failed_attempts.clone();
+				failed_attempts.remove(account);
+				Atm{failed_attempts, ..starting_state.clone()}
+			 },
+
+			 Action::Deposit(amount) => {
+				if let Auth::Authenticated(account) = starting_state.expected_pin_hash {
+					let cash_inside = starting_state.cash_inside.checked_add(*amount);
+					let balance = starting_state.balance(account).checked_add(*amount);
+					if let (Some(cash_inside), Some(balance)) = (cash_inside, balance) {
+						let mut accounts = starting_state.accounts.clone();
+						set_balance(&mut accounts, account, balance);
+						return Atm {
+							cash_inside,
+							expected_pin_hash:Auth::Waiting,
+							keystroke_register: Vec::<Key>::new(),
+							accounts,
+							..starting_state.clone()
+						};
+					}
+				}
+				starting_state.clone()
 			 },
 
 			 Action::PressKey(key) => {
 				match key {
-					
+
 					Key::Enter =>{
 						let hash:u64  = crate::hash(&starting_state.keystroke_register);
-						if Auth::Authenticating(hash) == starting_state.expected_pin_hash {
+						if let Auth::Authenticating(account, pin_hash) = starting_state.expected_pin_hash {
+							let mut failed_attempts = starting_state.failed_attempts.clone();
+							if pin_hash == hash {
+								failed_attempts.remove(&account);
+								return  Atm {
+									expected_pin_hash:Auth::Authenticated(account),
+									keystroke_register: Vec::<Key>::new(),
+									failed_attempts,
+									..starting_state.clone()
+								};
+							}
+
+							let attempts = failed_attempts.entry(account).or_insert(0);
+							*attempts = attempts.saturating_add(1);
 							return  Atm {
-								cash_inside:starting_state.cash_inside,
-								expected_pin_hash:Auth::Authenticated,
-								keystroke_register: Vec::<Key>::new()
+								expected_pin_hash:Auth::Waiting,
+								keystroke_register: Vec::<Key>::new(),
+								failed_attempts,
+								..starting_state.clone()
 							};
 						}
 
-						if Auth::Authenticated == starting_state.expected_pin_hash {
+						if let Auth::Authenticated(account) = starting_state.expected_pin_hash {
 							let mut new_cash_amount = starting_state.cash_inside as u64;
+							let mut accounts = starting_state.accounts.clone();
 
-							
-							let mut withdrawal = 0;
-							let mut d:u64 = starting_state.keystroke_register.len() as u64;
-							
-							for n in starting_state.keystroke_register.iter() {
-								withdrawal += match *n {
-									Key::One   => 1,
-									Key::Three => 3,
-									Key::Two   => 2,
-									Key::Four  => 4,
-									_ => 0
-								} * 10u64.wrapping_pow((d-1u64) as u32);
-								d -= 1;
-								
-							}
+							let balance = starting_state.balance(account);
 
-							new_cash_amount = if withdrawal > new_cash_amount {
-								new_cash_amount 
+							match parse_decimal(&starting_state.keystroke_register) {
+								Some(withdrawal) if withdrawal <= new_cash_amount && withdrawal <= balance => {
+									set_balance(&mut accounts, account, balance - withdrawal);
+									new_cash_amount -= withdrawal;
+								},
+								_ => {},
 							}
-							else {
-								new_cash_amount -= withdrawal;
-								new_cash_amount
-							};
 
 							return  Atm {
 									cash_inside:new_cash_amount,
 									expected_pin_hash:Auth::Waiting,
-									keystroke_register: Vec::<Key>::new()
+									keystroke_register: Vec::<Key>::new(),
+									accounts,
+									..starting_state.clone()
 								};
 						}
-						
-						return  Atm {
-							cash_inside:starting_state.cash_inside,
+
+						starting_state.clone()
+					},
+
+					Key::Cancel => {
+						Atm {
 							expected_pin_hash:Auth::Waiting,
-							keystroke_register:Vec::<Key>::new()
-						};
+							keystroke_register:Vec::<Key>::new(),
+							..starting_state.clone()
+						}
 					},
 
 					_ => {
 
 						match starting_state.expected_pin_hash {
-							Auth::Authenticated => {
-								
-								let mut sr = starting_state.keystroke_register.clone() ;
-								sr.push(key.clone());
-								return  Atm {
-										cash_inside:starting_state.cash_inside,
-									    expected_pin_hash:starting_state.expected_pin_hash.clone(),
-										keystroke_register:sr
-									};
-							},
-
 							Auth::Waiting => {
-								return  Atm {
-									cash_inside:starting_state.cash_inside,
-									expected_pin_hash:starting_state.expected_pin_hash.clone(),
-									keystroke_register:starting_state.keystroke_register.clone() 
-								};
+								starting_state.clone()
 							},
 
-							Auth::Authenticating(_) =>{
+							Auth::Authenticated(_) | Auth::Authenticating(..) =>{
 								let mut ksr  = starting_state.keystroke_register.clone();
 								ksr.push(key.clone());
-								return  Atm {
-									cash_inside:starting_state.cash_inside,
-									expected_pin_hash:starting_state.expected_pin_hash.clone(),keystroke_register:ksr };
+								Atm {
+									keystroke_register:ksr,
+									..starting_state.clone()
+								}
 							}
 						}
 					},
 
 				}
-			},	
+			},
 		}
 	}
+
+	fn try_next_state(starting_state: &Self::State, t: &Self::Transition) -> Result<Self::State, Self::Error> {
+		check_action(starting_state, t)?;
+		Ok(Self::next_state(starting_state, t))
+	}
+
+	/// Unlike `try_next_state`, this never copies the keystroke register.
+	fn validate_transition(state: &Self::State, t: &Self::Transition) -> bool {
+		check_action(state, t).is_ok()
+	}
+}
+
+impl ContextualStateMachine for Atm {}
+
+/// Check whether the ATM would accept the given action in its current state.
+fn check_action(starting_state: &Atm, t: &Action) -> Result<(), AtmError> {
+	match (t, &starting_state.expected_pin_hash) {
+		(Action::SwipeCard { account, .. }, _) => {
+			if starting_state.is_locked(*account) {
+				return Err(AtmError::CardLocked);
+			}
+		},
+		(Action::AdminUnlock(account), _) => {
+			if !starting_state.is_locked(*account) {
+				return Err(AtmError::CardNotLocked);
+			}
+		},
+		(_, Auth::Waiting) => return Err(AtmError::NoCardSwiped),
+		(Action::Deposit(_), Auth::Authenticating(..)) => return Err(AtmError::NotAuthenticated),
+		(Action::Deposit(amount), Auth::Authenticated(account)) => {
+			if starting_state.cash_inside.checked_add(*amount).is_none()
+				|| starting_state.balance(*account).checked_add(*amount).is_none()
+			{
+				return Err(AtmError::DepositTooLarge);
+			}
+		},
+		(Action::PressKey(key), Auth::Authenticating(_, pin_hash)) => {
+			if *key == Key::Enter && crate::hash(&starting_state.keystroke_register) != *pin_hash {
+				return Err(AtmError::WrongPin);
+			}
+		},
+		(Action::PressKey(key), Auth::Authenticated(account)) => {
+			if *key == Key::Enter {
+				let requested = parse_decimal(&starting_state.keystroke_register).ok_or(AtmError::AmountTooLarge)?;
+				if requested > starting_state.cash_inside {
+					return Err(AtmError::InsufficientCash);
+				}
+				if requested > starting_state.balance(*account) {
+					return Err(AtmError::InsufficientFunds);
+				}
+			}
+		},
+	}
+	Ok(())
+}
+
+impl EventfulStateMachine for Atm {
+	type Event = AtmEvent;
+
+	fn next_state_with_events(starting_state: &Atm, t: &Action) -> (Atm, Vec<AtmEvent>) {
+		let end = Atm::next_state(starting_state, t);
+		let mut events = Vec::new();
+		match (t, &starting_state.expected_pin_hash) {
+			(Action::SwipeCard { account, .. }, _) if !starting_state.is_locked(*account) => {
+				events.push(AtmEvent::CardSwiped);
+			},
+			(Action::AdminUnlock(account), _) if starting_state.is_locked(*account) => {
+				events.push(AtmEvent::CardUnlocked);
+			},
+			(Action::Deposit(amount), Auth::Authenticated(_)) => {
+				if end.cash_inside > starting_state.cash_inside {
+					events.push(AtmEvent::CashDeposited { amount: *amount });
+				}
+			},
+			(Action::PressKey(Key::Cancel), Auth::Authenticating(..) | Auth::Authenticated(_)) => {
+				events.push(AtmEvent::SessionCancelled);
+			},
+			(Action::PressKey(Key::Enter), Auth::Authenticating(account, _)) => {
+				if matches!(end.expected_pin_hash, Auth::Authenticated(_)) {
+					events.push(AtmEvent::PinAccepted);
+				} else {
+					events.push(AtmEvent::PinRejected);
+					if end.is_locked(*account) && !starting_state.is_locked(*account) {
+						events.push(AtmEvent::CardLocked);
+					}
+				}
+			},
+			(Action::PressKey(Key::Enter), Auth::Authenticated(_)) => {
+				let requested = parse_decimal(&starting_state.keystroke_register).unwrap_or(u64::MAX);
+				if end.cash_inside < starting_state.cash_inside {
+					events.push(AtmEvent::CashWithdrawn { amount: starting_state.cash_inside - end.cash_inside });
+				} else if check_action(starting_state, t).is_err() {
+					events.push(AtmEvent::WithdrawalDeclined { requested });
+				}
+			},
+			_ => {},
+		}
+		(end, events)
+	}
+}
+
+/// The account every card in the tests below belongs to.
+#[cfg(test)]
+const ACCOUNT: AccountId = 7;
+
+/// The test account holding the given balance.
+#[cfg(test)]
+fn funded(balance: u64) -> HashMap<AccountId, u64> {
+    let mut accounts = HashMap::new();
+    set_balance(&mut accounts, ACCOUNT, balance);
+    accounts
 }
 
 #[test]
@@ -163,12 +448,16 @@ fn sm_3_simple_swipe_card() {
         cash_inside: 10,
         expected_pin_hash: Auth::Waiting,
         keystroke_register: Vec::new(),
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
     };
-    let end = Atm::next_state(&start, &Action::SwipeCard(1234));
+    let end = Atm::next_state(&start, &Action::SwipeCard { account: ACCOUNT, pin_hash: 1234 });
     let expected = Atm {
         cash_inside: 10,
-        expected_pin_hash: Auth::Authenticating(1234),
+        expected_pin_hash: Auth::Authenticating(ACCOUNT, 1234),
         keystroke_register: Vec::new(),
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
     };
 
     assert_eq!(end, expected);
@@ -178,28 +467,36 @@ fn sm_3_simple_swipe_card() {
 fn sm_3_swipe_card_again_part_way_through() {
     let start = Atm {
         cash_inside: 10,
-        expected_pin_hash: Auth::Authenticating(1234),
+        expected_pin_hash: Auth::Authenticating(ACCOUNT, 1234),
         keystroke_register: Vec::new(),
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
     };
-    let end = Atm::next_state(&start, &Action::SwipeCard(1234));
+    let end = Atm::next_state(&start, &Action::SwipeCard { account: ACCOUNT, pin_hash: 1234 });
     let expected = Atm {
         cash_inside: 10,
-        expected_pin_hash: Auth::Authenticating(1234),
+        expected_pin_hash: Auth::Authenticating(ACCOUNT, 1234),
         keystroke_register: Vec::new(),
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
     };
 
     assert_eq!(end, expected);
 
     let start = Atm {
         cash_inside: 10,
-        expected_pin_hash: Auth::Authenticating(1234),
+        expected_pin_hash: Auth::Authenticating(ACCOUNT, 1234),
         keystroke_register: vec![Key::One, Key::Three],
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
     };
-    let end = Atm::next_state(&start, &Action::SwipeCard(1234));
+    let end = Atm::next_state(&start, &Action::SwipeCard { account: ACCOUNT, pin_hash: 1234 });
     let expected = Atm {
         cash_inside: 10,
-        expected_pin_hash: Auth::Authenticating(1234),
+        expected_pin_hash: Auth::Authenticating(ACCOUNT, 1234),
         keystroke_register: vec![Key::One, Key::Three],
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
     };
 
     assert_eq!(end, expected);
@@ -211,12 +508,16 @@ fn sm_3_press_key_before_card_swipe() {
         cash_inside: 10,
         expected_pin_hash: Auth::Waiting,
         keystroke_register: Vec::new(),
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
     };
     let end = Atm::next_state(&start, &Action::PressKey(Key::One));
     let expected = Atm {
         cash_inside: 10,
         expected_pin_hash: Auth::Waiting,
         keystroke_register: Vec::new(),
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
     };
 
     assert_eq!(end, expected);
@@ -226,28 +527,36 @@ fn sm_3_press_key_before_card_swipe() {
 fn sm_3_enter_single_digit_of_pin() {
     let start = Atm {
         cash_inside: 10,
-        expected_pin_hash: Auth::Authenticating(1234),
+        expected_pin_hash: Auth::Authenticating(ACCOUNT, 1234),
         keystroke_register: Vec::new(),
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
     };
     let end = Atm::next_state(&start, &Action::PressKey(Key::One));
     let expected = Atm {
         cash_inside: 10,
-        expected_pin_hash: Auth::Authenticating(1234),
+        expected_pin_hash: Auth::Authenticating(ACCOUNT, 1234),
         keystroke_register: vec![Key::One],
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
     };
 
     assert_eq!(end, expected);
 
     let start = Atm {
         cash_inside: 10,
-        expected_pin_hash: Auth::Authenticating(1234),
+        expected_pin_hash: Auth::Authenticating(ACCOUNT, 1234),
         keystroke_register: vec![Key::One],
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
     };
     let end1 = Atm::next_state(&start, &Action::PressKey(Key::Two));
     let expected1 = Atm {
         cash_inside: 10,
-        expected_pin_hash: Auth::Authenticating(1234),
+        expected_pin_hash: Auth::Authenticating(ACCOUNT, 1234),
         keystroke_register: vec![Key::One, Key::Two],
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
     };
 
     assert_eq!(end1, expected1);
@@ -261,14 +570,18 @@ fn sm_3_enter_wrong_pin() {
 
     let start = Atm {
         cash_inside: 10,
-        expected_pin_hash: Auth::Authenticating(pin_hash),
+        expected_pin_hash: Auth::Authenticating(ACCOUNT, pin_hash),
         keystroke_register: vec![Key::Three, Key::Three, Key::Three, Key::Three],
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
     };
     let end = Atm::next_state(&start, &Action::PressKey(Key::Enter));
     let expected = Atm {
         cash_inside: 10,
         expected_pin_hash: Auth::Waiting,
         keystroke_register: Vec::new(),
+        accounts: funded(20),
+        failed_attempts: HashMap::from([(ACCOUNT, 1)]),
     };
 
     assert_eq!(end, expected);
@@ -282,14 +595,18 @@ fn sm_3_enter_correct_pin() {
 
     let start = Atm {
         cash_inside: 10,
-        expected_pin_hash: Auth::Authenticating(pin_hash),
+        expected_pin_hash: Auth::Authenticating(ACCOUNT, pin_hash),
         keystroke_register: vec![Key::One, Key::Two, Key::Three, Key::Four],
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
     };
     let end = Atm::next_state(&start, &Action::PressKey(Key::Enter));
     let expected = Atm {
         cash_inside: 10,
-        expected_pin_hash: Auth::Authenticated,
+        expected_pin_hash: Auth::Authenticated(ACCOUNT),
         keystroke_register: Vec::new(),
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
     };
 
     assert_eq!(end, expected);
@@ -299,28 +616,36 @@ fn sm_3_enter_correct_pin() {
 fn sm_3_enter_single_digit_of_withdraw_amount() {
     let start = Atm {
         cash_inside: 10,
-        expected_pin_hash: Auth::Authenticated,
+        expected_pin_hash: Auth::Authenticated(ACCOUNT),
         keystroke_register: Vec::new(),
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
     };
     let end = Atm::next_state(&start, &Action::PressKey(Key::One));
     let expected = Atm {
         cash_inside: 10,
-        expected_pin_hash: Auth::Authenticated,
+        expected_pin_hash: Auth::Authenticated(ACCOUNT),
         keystroke_register: vec![Key::One],
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
     };
 
     assert_eq!(end, expected);
 
     let start = Atm {
         cash_inside: 10,
-        expected_pin_hash: Auth::Authenticated,
+        expected_pin_hash: Auth::Authenticated(ACCOUNT),
         keystroke_register: vec![Key::One],
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
     };
     let end1 = Atm::next_state(&start, &Action::PressKey(Key::Four));
     let expected1 = Atm {
         cash_inside: 10,
-        expected_pin_hash: Auth::Authenticated,
+        expected_pin_hash: Auth::Authenticated(ACCOUNT),
         keystroke_register: vec![Key::One, Key::Four],
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
     };
 
     assert_eq!(end1, expected1);
@@ -330,14 +655,18 @@ fn sm_3_enter_single_digit_of_withdraw_amount() {
 fn sm_3_try_to_withdraw_too_much() {
     let start = Atm {
         cash_inside: 10,
-        expected_pin_hash: Auth::Authenticated,
+        expected_pin_hash: Auth::Authenticated(ACCOUNT),
         keystroke_register: vec![Key::One, Key::Four],
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
     };
     let end = Atm::next_state(&start, &Action::PressKey(Key::Enter));
     let expected = Atm {
         cash_inside: 10,
         expected_pin_hash: Auth::Waiting,
         keystroke_register: Vec::new(),
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
     };
 
     assert_eq!(end, expected);
@@ -347,15 +676,438 @@ fn sm_3_try_to_withdraw_too_much() {
 fn sm_3_withdraw_acceptable_amount() {
     let start = Atm {
         cash_inside: 10,
-        expected_pin_hash: Auth::Authenticated,
+        expected_pin_hash: Auth::Authenticated(ACCOUNT),
         keystroke_register: vec![Key::One],
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
     };
     let end = Atm::next_state(&start, &Action::PressKey(Key::Enter));
     let expected = Atm {
         cash_inside: 9,
         expected_pin_hash: Auth::Waiting,
         keystroke_register: Vec::new(),
+        accounts: funded(19),
+        failed_attempts: HashMap::new(),
     };
 
     assert_eq!(end, expected);
 }
+
+#[test]
+fn sm_3_try_key_before_card_swipe_is_rejected() {
+    let start = Atm {
+        cash_inside: 10,
+        expected_pin_hash: Auth::Waiting,
+        keystroke_register: Vec::new(),
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
+    };
+    let end = Atm::try_next_state(&start, &Action::PressKey(Key::One));
+
+    assert_eq!(end, Err(AtmError::NoCardSwiped));
+}
+
+#[test]
+fn sm_3_try_wrong_pin_is_rejected() {
+    let pin_hash = crate::hash(&vec![Key::One, Key::Two, Key::Three, Key::Four]);
+    let start = Atm {
+        cash_inside: 10,
+        expected_pin_hash: Auth::Authenticating(ACCOUNT, pin_hash),
+        keystroke_register: vec![Key::Three, Key::Three, Key::Three, Key::Three],
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
+    };
+    let end = Atm::try_next_state(&start, &Action::PressKey(Key::Enter));
+
+    assert_eq!(end, Err(AtmError::WrongPin));
+}
+
+#[test]
+fn sm_3_try_withdraw_too_much_is_rejected() {
+    let start = Atm {
+        cash_inside: 10,
+        expected_pin_hash: Auth::Authenticated(ACCOUNT),
+        keystroke_register: vec![Key::One, Key::Four],
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
+    };
+    let end = Atm::try_next_state(&start, &Action::PressKey(Key::Enter));
+
+    assert_eq!(end, Err(AtmError::InsufficientCash));
+}
+
+#[test]
+fn sm_3_try_withdraw_acceptable_amount() {
+    let start = Atm {
+        cash_inside: 10,
+        expected_pin_hash: Auth::Authenticated(ACCOUNT),
+        keystroke_register: vec![Key::One],
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
+    };
+    let end = Atm::try_next_state(&start, &Action::PressKey(Key::Enter));
+    let expected = Atm {
+        cash_inside: 9,
+        expected_pin_hash: Auth::Waiting,
+        keystroke_register: Vec::new(),
+        accounts: funded(19),
+        failed_attempts: HashMap::new(),
+    };
+
+    assert_eq!(end, Ok(expected));
+}
+
+#[test]
+fn sm_3_genesis_atm_is_waiting() {
+    let expected = Atm {
+        cash_inside: 10,
+        expected_pin_hash: Auth::Waiting,
+        keystroke_register: Vec::new(),
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
+    };
+
+    assert_eq!(Atm::genesis_state((10, vec![(ACCOUNT, 20)])), expected);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn sm_3_atm_round_trips_through_json() {
+    let start = Atm {
+        cash_inside: 10,
+        expected_pin_hash: Auth::Authenticating(ACCOUNT, 1234),
+        keystroke_register: vec![Key::One, Key::Three],
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
+    };
+    let json = serde_json::to_string(&start).unwrap();
+
+    assert_eq!(serde_json::from_str::<Atm>(&json).unwrap(), start);
+}
+
+#[test]
+fn sm_3_withdrawal_session_emits_events() {
+    let start = Atm::genesis_state((10, vec![(ACCOUNT, 20)]));
+    let pin_hash = crate::hash(&vec![Key::One, Key::Two]);
+    let actions = [
+        Action::SwipeCard { account: ACCOUNT, pin_hash },
+        Action::PressKey(Key::One),
+        Action::PressKey(Key::Two),
+        Action::PressKey(Key::Enter),
+        Action::PressKey(Key::Three),
+        Action::PressKey(Key::Enter),
+    ];
+
+    let (end, events) = Atm::apply_all_with_events(&start, &actions);
+
+    assert_eq!(end.cash_inside, 7);
+    assert_eq!(end.balance(ACCOUNT), 17);
+    assert_eq!(
+        events,
+        vec![
+            AtmEvent::CardSwiped,
+            AtmEvent::PinAccepted,
+            AtmEvent::CashWithdrawn { amount: 3 },
+        ]
+    );
+}
+
+#[test]
+fn sm_3_failed_withdrawals_emit_events() {
+    let start = Atm {
+        cash_inside: 10,
+        expected_pin_hash: Auth::Authenticated(ACCOUNT),
+        keystroke_register: vec![Key::Four, Key::Four],
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
+    };
+    let (_, events) = Atm::next_state_with_events(&start, &Action::PressKey(Key::Enter));
+    assert_eq!(events, vec![AtmEvent::WithdrawalDeclined { requested: 44 }]);
+
+    let start = Atm {
+        cash_inside: 10,
+        expected_pin_hash: Auth::Authenticating(ACCOUNT, 1234),
+        keystroke_register: vec![Key::One],
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
+    };
+    let (_, events) = Atm::next_state_with_events(&start, &Action::PressKey(Key::Enter));
+    assert_eq!(events, vec![AtmEvent::PinRejected]);
+}
+
+#[test]
+fn sm_3_very_long_withdrawal_is_declined() {
+    let start = Atm {
+        cash_inside: 10,
+        expected_pin_hash: Auth::Authenticated(ACCOUNT),
+        keystroke_register: vec![Key::Four; 40],
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
+    };
+    let end = Atm::next_state(&start, &Action::PressKey(Key::Enter));
+    let expected = Atm {
+        cash_inside: 10,
+        expected_pin_hash: Auth::Waiting,
+        keystroke_register: Vec::new(),
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
+    };
+
+    assert_eq!(end, expected);
+    assert_eq!(
+        Atm::try_next_state(&start, &Action::PressKey(Key::Enter)),
+        Err(AtmError::AmountTooLarge)
+    );
+}
+
+#[test]
+fn sm_3_validate_transition_rejects_wrong_pin() {
+    let start = Atm {
+        cash_inside: 10,
+        expected_pin_hash: Auth::Authenticating(ACCOUNT, 1234),
+        keystroke_register: vec![Key::One],
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
+    };
+
+    assert!(Atm::validate_transition(&start, &Action::PressKey(Key::Two)));
+    assert!(!Atm::validate_transition(&start, &Action::PressKey(Key::Enter)));
+    assert!(!Atm::validate_transition(&Atm::genesis_state((10, vec![(ACCOUNT, 20)])), &Action::PressKey(Key::One)));
+}
+
+#[test]
+fn sm_3_withdrawal_is_bounded_by_account_balance() {
+    let start = Atm {
+        cash_inside: 10,
+        expected_pin_hash: Auth::Authenticated(ACCOUNT),
+        keystroke_register: vec![Key::Four],
+        accounts: funded(3),
+        failed_attempts: HashMap::new(),
+    };
+    let (end, events) = Atm::next_state_with_events(&start, &Action::PressKey(Key::Enter));
+    let expected = Atm {
+        cash_inside: 10,
+        expected_pin_hash: Auth::Waiting,
+        keystroke_register: Vec::new(),
+        accounts: funded(3),
+        failed_attempts: HashMap::new(),
+    };
+
+    assert_eq!(end, expected);
+    assert_eq!(events, vec![AtmEvent::WithdrawalDeclined { requested: 4 }]);
+    assert_eq!(
+        Atm::try_next_state(&start, &Action::PressKey(Key::Enter)),
+        Err(AtmError::InsufficientFunds)
+    );
+}
+
+#[test]
+fn sm_3_withdrawing_whole_balance_removes_account_entry() {
+    let start = Atm {
+        cash_inside: 10,
+        expected_pin_hash: Auth::Authenticated(ACCOUNT),
+        keystroke_register: vec![Key::Three],
+        accounts: funded(3),
+        failed_attempts: HashMap::new(),
+    };
+    let end = Atm::try_next_state(&start, &Action::PressKey(Key::Enter)).unwrap();
+
+    assert_eq!(end.cash_inside, 7);
+    assert_eq!(end.balance(ACCOUNT), 0);
+    assert!(end.accounts.is_empty());
+}
+
+#[test]
+fn sm_3_withdrawals_only_touch_the_swiped_account() {
+    let start = Atm::genesis_state((100, vec![(ACCOUNT, 20), (8, 30)]));
+    let pin_hash = crate::hash(&vec![Key::One]);
+    let actions = [
+        Action::SwipeCard { account: 8, pin_hash },
+        Action::PressKey(Key::One),
+        Action::PressKey(Key::Enter),
+        Action::PressKey(Key::Two),
+        Action::PressKey(Key::Four),
+        Action::PressKey(Key::Enter),
+    ];
+
+    let end = Atm::apply_all(&start, &actions);
+
+    assert_eq!(end.cash_inside, 76);
+    assert_eq!(end.balance(ACCOUNT), 20);
+    assert_eq!(end.balance(8), 6);
+}
+
+#[test]
+fn sm_3_deposit_credits_account() {
+    let start = Atm {
+        cash_inside: 10,
+        expected_pin_hash: Auth::Authenticated(ACCOUNT),
+        keystroke_register: vec![Key::One],
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
+    };
+    let (end, events) = Atm::next_state_with_events(&start, &Action::Deposit(5));
+    let expected = Atm {
+        cash_inside: 15,
+        expected_pin_hash: Auth::Waiting,
+        keystroke_register: Vec::new(),
+        accounts: funded(25),
+        failed_attempts: HashMap::new(),
+    };
+
+    assert_eq!(end, expected);
+    assert_eq!(events, vec![AtmEvent::CashDeposited { amount: 5 }]);
+    assert_eq!(Atm::try_next_state(&start, &Action::Deposit(5)), Ok(expected));
+}
+
+#[test]
+fn sm_3_deposit_opens_new_account() {
+    let start = Atm {
+        cash_inside: 10,
+        expected_pin_hash: Auth::Authenticated(ACCOUNT),
+        keystroke_register: Vec::new(),
+        accounts: HashMap::new(),
+        failed_attempts: HashMap::new(),
+    };
+    let end = Atm::next_state(&start, &Action::Deposit(5));
+
+    assert_eq!(end.accounts, funded(5));
+}
+
+#[test]
+fn sm_3_deposit_before_pin_is_rejected() {
+    let waiting = Atm::genesis_state((10, vec![(ACCOUNT, 20)]));
+    assert_eq!(Atm::try_next_state(&waiting, &Action::Deposit(5)), Err(AtmError::NoCardSwiped));
+    assert_eq!(Atm::next_state(&waiting, &Action::Deposit(5)), waiting);
+
+    let authenticating = Atm {
+        cash_inside: 10,
+        expected_pin_hash: Auth::Authenticating(ACCOUNT, 1234),
+        keystroke_register: vec![Key::One],
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
+    };
+    assert_eq!(
+        Atm::try_next_state(&authenticating, &Action::Deposit(5)),
+        Err(AtmError::NotAuthenticated)
+    );
+    assert_eq!(Atm::next_state(&authenticating, &Action::Deposit(5)), authenticating);
+}
+
+#[test]
+fn sm_3_overflowing_deposit_is_rejected() {
+    let start = Atm {
+        cash_inside: 10,
+        expected_pin_hash: Auth::Authenticated(ACCOUNT),
+        keystroke_register: Vec::new(),
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
+    };
+    let (end, events) = Atm::next_state_with_events(&start, &Action::Deposit(u64::MAX));
+
+    assert_eq!(end, start);
+    assert!(events.is_empty());
+    assert_eq!(
+        Atm::try_next_state(&start, &Action::Deposit(u64::MAX)),
+        Err(AtmError::DepositTooLarge)
+    );
+}
+
+#[test]
+fn sm_3_parse_decimal() {
+    assert_eq!(parse_decimal(&[]), Some(0));
+    assert_eq!(parse_decimal(&[Key::Zero, Key::Nine, Key::Five]), Some(95));
+    assert_eq!(parse_decimal(&Key::DIGITS), Some(123_456_789));
+    assert_eq!(parse_decimal(&[Key::One, Key::Enter]), None);
+
+    let max: Vec<Key> = u64::MAX
+        .to_string()
+        .bytes()
+        .map(|b| Key::DIGITS[(b - b'0') as usize].clone())
+        .collect();
+    assert_eq!(parse_decimal(&max), Some(u64::MAX));
+    assert_eq!(parse_decimal(&[max.as_slice(), &[Key::Zero]].concat()), None);
+}
+
+#[test]
+fn sm_3_cancel_ends_session() {
+    let start = Atm {
+        cash_inside: 10,
+        expected_pin_hash: Auth::Authenticated(ACCOUNT),
+        keystroke_register: vec![Key::Five],
+        accounts: funded(20),
+        failed_attempts: HashMap::new(),
+    };
+    let (end, events) = Atm::next_state_with_events(&start, &Action::PressKey(Key::Cancel));
+
+    assert_eq!(end, Atm::genesis_state((10, vec![(ACCOUNT, 20)])));
+    assert_eq!(events, vec![AtmEvent::SessionCancelled]);
+    assert_eq!(
+        Atm::try_next_state(&end, &Action::PressKey(Key::Cancel)),
+        Err(AtmError::NoCardSwiped)
+    );
+}
+
+#[test]
+fn sm_3_wrong_pins_lock_card_until_admin_unlock() {
+    let pin_hash = crate::hash(&vec![Key::Nine, Key::Zero]);
+    let wrong_attempt = [
+        Action::SwipeCard { account: ACCOUNT, pin_hash },
+        Action::PressKey(Key::Eight),
+        Action::PressKey(Key::Enter),
+    ];
+    let mut state = Atm::genesis_state((10, vec![(ACCOUNT, 20)]));
+    let mut events = Vec::new();
+    for _ in 0..MAX_PIN_ATTEMPTS {
+        let (end, mut emitted) = Atm::apply_all_with_events(&state, &wrong_attempt);
+        state = end;
+        events.append(&mut emitted);
+    }
+
+    assert!(state.is_locked(ACCOUNT));
+    assert_eq!(events.last(), Some(&AtmEvent::CardLocked));
+    assert_eq!(events.iter().filter(|e| **e == AtmEvent::CardLocked).count(), 1);
+
+    let swipe = Action::SwipeCard { account: ACCOUNT, pin_hash };
+    assert_eq!(Atm::try_next_state(&state, &swipe), Err(AtmError::CardLocked));
+    assert_eq!(Atm::next_state_with_events(&state, &swipe), (state.clone(), vec![]));
+
+    let (state, events) = Atm::next_state_with_events(&state, &Action::AdminUnlock(ACCOUNT));
+    assert!(!state.is_locked(ACCOUNT));
+    assert_eq!(events, vec![AtmEvent::CardUnlocked]);
+    assert_eq!(
+        Atm::try_next_state(&state, &Action::AdminUnlock(ACCOUNT)),
+        Err(AtmError::CardNotLocked)
+    );
+    assert!(Atm::validate_transition(&state, &swipe));
+}
+
+#[test]
+fn sm_3_correct_pin_resets_failed_attempts() {
+    let pin_hash = crate::hash(&vec![Key::Six]);
+    let actions = [
+        Action::SwipeCard { account: ACCOUNT, pin_hash },
+        Action::PressKey(Key::Seven),
+        Action::PressKey(Key::Enter),
+        Action::SwipeCard { account: ACCOUNT, pin_hash },
+        Action::PressKey(Key::Seven),
+        Action::PressKey(Key::Enter),
+        Action::SwipeCard { account: ACCOUNT, pin_hash },
+        Action::PressKey(Key::Six),
+        Action::PressKey(Key::Enter),
+    ];
+    let end = Atm::apply_all(&Atm::genesis_state((10, vec![(ACCOUNT, 20)])), &actions);
+
+    assert_eq!(end.expected_pin_hash, Auth::Authenticated(ACCOUNT));
+    assert!(end.failed_attempts.is_empty());
+}
+
+#[test]
+fn sm_3_lockout_is_per_card() {
+    let mut start = Atm::genesis_state((10, vec![(ACCOUNT, 20), (8, 20)]));
+    start.failed_attempts.insert(ACCOUNT, MAX_PIN_ATTEMPTS);
+
+    assert!(start.is_locked(ACCOUNT));
+    assert!(!start.is_locked(8));
+    assert!(Atm::validate_transition(&start, &Action::SwipeCard { account: 8, pin_hash: 1 }));
+}
@@ -8,7 +8,15 @@
 //! In this module we design a state machine that tracks the currency balances of several users.
 //! Each user is associated with an account balance and users are able to send money to other users.
 
-use super::{StateMachine, User};
+use super::acl::{RequiresRole, Role};
+#[cfg(feature = "metrics")]
+use super::instrumented::Labelled;
+use super::parallel::{try_apply_all_parallel, Access, ParallelStateMachine};
+use super::with_nonces::Owned;
+use super::{
+    BlockContext, ContextualStateMachine, DiffStateMachine, EventfulStateMachine, InvertibleStateMachine,
+    MerkleState, StateMachine, User, Weighted,
+};
 use std::collections::HashMap;
 
 /// This state machine models a multi-user currency system. It tracks the balance of each
@@ -24,6 +32,7 @@ pub struct AccountedCurrency;
 type Balances = HashMap<User, u64>;
 
 /// The state transitions that users can make in an accounted currency system
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum AccountingTransaction {
     /// Create some new money for the given minter in the given amount
     Mint { minter: User, amount: u64 },
@@ -39,12 +48,54 @@ pub enum AccountingTransaction {
     },
 }
 
+/// The things that happen to balances in an accounted currency system
+#[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum CurrencyEvent {
+    /// New money was created for the given user
+    Minted { who: User, amount: u64 },
+    /// Money was destroyed from the given user's account. This is the amount actually burned,
+    /// which may be less than requested.
+    Burned { who: User, amount: u64 },
+    /// Money moved from one account to another
+    Transferred { from: User, to: User, amount: u64 },
+}
+
+/// The reasons a transaction may be rejected by the accounted currency system
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum CurrencyError {
+    /// The account being debited does not exist
+    UnknownAccount(User),
+    /// The account being debited does not hold enough funds
+    InsufficientBalance { available: u64, requested: u64 },
+    /// The transaction would push the given account's balance past `u64::MAX`
+    BalanceOverflow(User),
+}
+
 /// We model this system as a state machine with three possible transitions
 impl StateMachine for AccountedCurrency {
     type State = Balances;
     type Transition = AccountingTransaction;
+    type Error = CurrencyError;
+    /// The initial endowment of each user
+    type GenesisConfig = Vec<(User, u64)>;
+
+    /// Every endowed user starts with their endowment. Empty endowments are ignored
+    /// because of the existential deposit.
+    fn genesis_state(endowments: Vec<(User, u64)>) -> Balances {
+        let mut balances = Balances::new();
+        for (user, amount) in endowments {
+            if amount > 0 {
+                *balances.entry(user).or_insert(0) += amount;
+            }
+        }
+        balances
+    }
 
     fn next_state(starting_state: &Balances, t: &AccountingTransaction) -> Balances {
+		if overflowed_account(starting_state, t).is_some() {
+			return starting_state.clone();
+		}
 		
 		match t {
 
@@ -84,6 +135,12 @@ impl StateMachine for AccountedCurrency {
 				s
 			},
 
+			// Sending money to yourself leaves every balance as it was, even when it is all
+			// the money you have.
+			AccountingTransaction::Transfer{sender, receiver, ..} if sender == receiver => {
+				starting_state.clone()
+			},
+
 			AccountingTransaction::Transfer{sender, receiver, amount} => {
 				let mut s = starting_state.clone(); 
 				let amount_value_sender   = s.get(sender);
@@ -105,11 +162,11 @@ impl StateMachine for AccountedCurrency {
 					(Some(value_s),None) => {
 						if amount < value_s {
 							s.entry(*sender).and_modify(|v|  -> (){*v -= *amount});
-							s.insert(*receiver, *amount);
+							credit(&mut s, receiver, *amount);
 						}
 						else if *value_s == *amount { // remove the sender
 							s.remove_entry(sender);
-							s.insert(*receiver,  *amount);
+							credit(&mut s, receiver, *amount);
 						} 
 					}
 					_ => { 
@@ -121,6 +178,267 @@ impl StateMachine for AccountedCurrency {
 			},
 		}
 	}
+
+	fn try_next_state(starting_state: &Balances, t: &AccountingTransaction) -> Result<Balances, CurrencyError> {
+		check_transaction(starting_state, t)?;
+		Ok(Self::next_state(starting_state, t))
+	}
+
+	/// Unlike `try_next_state`, this never clones the balances.
+	fn validate_transition(state: &Balances, t: &AccountingTransaction) -> bool {
+		check_transaction(state, t).is_ok()
+	}
+}
+
+/// The currency ignores the block context, so blocks execute through the parallel executor, with
+/// transfers between unrelated accounts running at the same time.
+impl ContextualStateMachine for AccountedCurrency {
+	fn apply_all_in_context(
+		starting_state: &Balances,
+		ts: &[AccountingTransaction],
+		context: &BlockContext,
+	) -> Balances {
+		Self::try_apply_all_in_context(starting_state, ts, context)
+			.unwrap_or_else(|_| Self::apply_all(starting_state, ts))
+	}
+
+	fn try_apply_all_in_context(
+		starting_state: &Balances,
+		ts: &[AccountingTransaction],
+		_: &BlockContext,
+	) -> Result<Balances, CurrencyError> {
+		try_apply_all_parallel::<Self>(starting_state, ts)
+	}
+}
+
+/// Check whether the given transaction would be accepted in the given state.
+fn check_transaction(starting_state: &Balances, t: &AccountingTransaction) -> Result<(), CurrencyError> {
+	if let Some(user) = overflowed_account(starting_state, t) {
+		return Err(CurrencyError::BalanceOverflow(user));
+	}
+	match t {
+		AccountingTransaction::Mint { .. } => (),
+		AccountingTransaction::Burn { burner, .. } => {
+			if !starting_state.contains_key(burner) {
+				return Err(CurrencyError::UnknownAccount(*burner));
+			}
+		},
+		AccountingTransaction::Transfer { sender, amount, .. } => {
+			match starting_state.get(sender) {
+				None => return Err(CurrencyError::UnknownAccount(*sender)),
+				Some(available) if available < amount => {
+					return Err(CurrencyError::InsufficientBalance { available: *available, requested: *amount });
+				},
+				Some(_) => (),
+			}
+		},
+	}
+	Ok(())
+}
+
+/// The account whose balance the given transaction would push past `u64::MAX`, if any.
+fn overflowed_account(balances: &Balances, t: &AccountingTransaction) -> Option<User> {
+	let (user, amount) = match t {
+		AccountingTransaction::Mint { minter, amount } => (minter, amount),
+		AccountingTransaction::Transfer { sender, receiver, amount } if sender != receiver => (receiver, amount),
+		_ => return None,
+	};
+	let balance = balances.get(user).copied().unwrap_or(0);
+	balance.checked_add(*amount).is_none().then_some(*user)
+}
+
+/// A diff against the balances. Each touched account is mapped to its new balance, or to `None`
+/// if the account was removed because its balance fell to zero.
+pub type BalancesDiff = Vec<(User, Option<u64>)>;
+
+impl DiffStateMachine for AccountedCurrency {
+	type StateDiff = BalancesDiff;
+
+	fn state_diff(starting_state: &Balances, t: &AccountingTransaction) -> Result<BalancesDiff, CurrencyError> {
+		if let Some(user) = overflowed_account(starting_state, t) {
+			return Err(CurrencyError::BalanceOverflow(user));
+		}
+		let balance = |u: &User| starting_state.get(u).copied().unwrap_or(0);
+		let credited = |u: &User, amount: u64| {
+			balance(u).checked_add(amount).ok_or(CurrencyError::BalanceOverflow(*u))
+		};
+		// Respect the existential deposit: empty accounts are removed entirely.
+		let existing = |amount: u64| if amount == 0 { None } else { Some(amount) };
+
+		match t {
+			AccountingTransaction::Mint { minter, amount } => {
+				Ok(vec![(*minter, existing(credited(minter, *amount)?))])
+			},
+			AccountingTransaction::Burn { burner, amount } => {
+				if !starting_state.contains_key(burner) {
+					return Err(CurrencyError::UnknownAccount(*burner));
+				}
+				Ok(vec![(*burner, existing(balance(burner).saturating_sub(*amount)))])
+			},
+			AccountingTransaction::Transfer { sender, receiver, amount } => {
+				let available = match starting_state.get(sender) {
+					None => return Err(CurrencyError::UnknownAccount(*sender)),
+					Some(available) => *available,
+				};
+				if available < *amount {
+					return Err(CurrencyError::InsufficientBalance { available, requested: *amount });
+				}
+				if sender == receiver {
+					return Ok(vec![]);
+				}
+				Ok(vec![
+					(*sender, existing(available - amount)),
+					(*receiver, existing(credited(receiver, *amount)?)),
+				])
+			},
+		}
+	}
+
+	fn apply_diff(state: &mut Balances, diff: &BalancesDiff) {
+		for (user, balance) in diff {
+			match balance {
+				Some(b) => state.insert(*user, *b),
+				None => state.remove(user),
+			};
+		}
+	}
+}
+
+/// Undoing a transaction moves the funds back where they came from.
+///
+/// Burning more than an account holds destroys the information about how much it held, so
+/// such a burn is undone as if exactly the requested amount had been burned. Clients that
+/// need exact rollback for those burns should record state diffs instead.
+impl InvertibleStateMachine for AccountedCurrency {
+	fn undo(ending_state: &Balances, t: &AccountingTransaction) -> Balances {
+		let mut s = ending_state.clone();
+		match t {
+			AccountingTransaction::Mint { minter, amount } => debit(&mut s, minter, *amount),
+			AccountingTransaction::Burn { burner, amount } => credit(&mut s, burner, *amount),
+			AccountingTransaction::Transfer { sender, receiver, amount } => {
+				debit(&mut s, receiver, *amount);
+				credit(&mut s, sender, *amount);
+			},
+		}
+		s
+	}
+}
+
+/// A mint or burn touches a single account while a transfer touches two, so it costs twice
+/// as much.
+impl Weighted for AccountedCurrency {
+	fn weight(t: &AccountingTransaction) -> u64 {
+		match t {
+			AccountingTransaction::Mint { .. } | AccountingTransaction::Burn { .. } => 1,
+			AccountingTransaction::Transfer { .. } => 2,
+		}
+	}
+}
+
+/// Transfers and burns spend their sender's or burner's money. A mint spends nobody's.
+impl Owned for AccountingTransaction {
+	fn owner(&self) -> Option<User> {
+		match self {
+			AccountingTransaction::Mint { .. } => None,
+			AccountingTransaction::Burn { burner, .. } => Some(*burner),
+			AccountingTransaction::Transfer { sender, .. } => Some(*sender),
+		}
+	}
+}
+
+/// Only minters may mint, and only burners may burn, from their own account. Anyone may transfer
+/// their own money, and nobody may spend anyone else's.
+impl RequiresRole for AccountedCurrency {
+	fn required_role(t: &AccountingTransaction) -> Option<Role> {
+		match t {
+			AccountingTransaction::Mint { .. } => Some(Role::Mint),
+			AccountingTransaction::Burn { .. } => Some(Role::Burn),
+			AccountingTransaction::Transfer { .. } => None,
+		}
+	}
+
+	fn owner(t: &AccountingTransaction) -> Option<User> {
+		t.owner()
+	}
+}
+
+#[cfg(feature = "metrics")]
+impl Labelled for AccountedCurrency {
+	fn label(t: &AccountingTransaction) -> &'static str {
+		match t {
+			AccountingTransaction::Mint { .. } => "mint",
+			AccountingTransaction::Burn { .. } => "burn",
+			AccountingTransaction::Transfer { .. } => "transfer",
+		}
+	}
+}
+
+/// Each account is an entry, so the root commits to every balance individually.
+impl MerkleState for AccountedCurrency {
+	type Key = User;
+	type Value = u64;
+
+	fn entries(state: &Balances) -> Vec<(User, u64)> {
+		state.iter().map(|(user, balance)| (*user, *balance)).collect()
+	}
+}
+
+/// Every transaction only touches the accounts it names.
+impl ParallelStateMachine for AccountedCurrency {
+	type Key = User;
+
+	fn access(t: &AccountingTransaction) -> Access<User> {
+		let writes = match t {
+			AccountingTransaction::Mint { minter, .. } => vec![*minter],
+			AccountingTransaction::Burn { burner, .. } => vec![*burner],
+			AccountingTransaction::Transfer { sender, receiver, .. } => vec![*sender, *receiver],
+		};
+		Access { reads: vec![], writes }
+	}
+}
+
+impl EventfulStateMachine for AccountedCurrency {
+	type Event = CurrencyEvent;
+
+	fn next_state_with_events(
+		starting_state: &Balances,
+		t: &AccountingTransaction,
+	) -> (Balances, Vec<CurrencyEvent>) {
+		let Ok(end) = AccountedCurrency::try_next_state(starting_state, t) else {
+			return (AccountedCurrency::next_state(starting_state, t), vec![]);
+		};
+		let event = match t {
+			AccountingTransaction::Mint { minter, amount } => {
+				CurrencyEvent::Minted { who: *minter, amount: *amount }
+			},
+			AccountingTransaction::Burn { burner, .. } => {
+				let before = starting_state.get(burner).copied().unwrap_or(0);
+				let after = end.get(burner).copied().unwrap_or(0);
+				CurrencyEvent::Burned { who: *burner, amount: before - after }
+			},
+			AccountingTransaction::Transfer { sender, receiver, amount } => {
+				CurrencyEvent::Transferred { from: *sender, to: *receiver, amount: *amount }
+			},
+		};
+		(end, vec![event])
+	}
+}
+
+/// Add funds to an account, creating it if necessary.
+fn credit(balances: &mut Balances, user: &User, amount: u64) {
+	if amount > 0 {
+		*balances.entry(*user).or_insert(0) += amount;
+	}
+}
+
+/// Remove funds from an account, removing the account entirely if it empties.
+fn debit(balances: &mut Balances, user: &User, amount: u64) {
+	if let Some(balance) = balances.get_mut(user) {
+		*balance = balance.saturating_sub(amount);
+		if *balance == 0 {
+			balances.remove(user);
+		}
+	}
 }
 
 #[test]
@@ -318,6 +636,19 @@ fn sm_4_send_to_same_user() {
     assert_eq!(end, expected);
 }
 
+#[test]
+fn sm_4_send_whole_balance_to_same_user() {
+    let start = HashMap::from([(User::Alice, 10)]);
+    let transfer = AccountingTransaction::Transfer {
+        sender: User::Alice,
+        receiver: User::Alice,
+        amount: 10,
+    };
+
+    assert_eq!(AccountedCurrency::next_state(&start, &transfer), start);
+    assert_eq!(AccountedCurrency::try_next_state(&start, &transfer), Ok(start));
+}
+
 #[test]
 fn sm_4_insufficient_balance_transfer() {
     let start = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
@@ -397,3 +728,406 @@ fn sm_4_transfer() {
 
     assert_eq!(end, expected);
 }
+
+#[test]
+fn sm_4_try_burn_unknown_account_is_rejected() {
+    let start = HashMap::from([(User::Alice, 100)]);
+    let end = AccountedCurrency::try_next_state(
+        &start,
+        &AccountingTransaction::Burn {
+            burner: User::Bob,
+            amount: 50,
+        },
+    );
+
+    assert_eq!(end, Err(CurrencyError::UnknownAccount(User::Bob)));
+}
+
+#[test]
+fn sm_4_try_insufficient_balance_transfer_is_rejected() {
+    let start = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
+    let end = AccountedCurrency::try_next_state(
+        &start,
+        &AccountingTransaction::Transfer {
+            sender: User::Bob,
+            receiver: User::Alice,
+            amount: 60,
+        },
+    );
+
+    assert_eq!(
+        end,
+        Err(CurrencyError::InsufficientBalance {
+            available: 50,
+            requested: 60
+        })
+    );
+}
+
+#[test]
+fn sm_4_try_simple_transfer() {
+    let start = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
+    let end = AccountedCurrency::try_next_state(
+        &start,
+        &AccountingTransaction::Transfer {
+            sender: User::Alice,
+            receiver: User::Bob,
+            amount: 10,
+        },
+    );
+    let expected = HashMap::from([(User::Alice, 90), (User::Bob, 60)]);
+
+    assert_eq!(end, Ok(expected));
+}
+
+#[test]
+fn sm_4_try_apply_all_stops_at_first_rejection() {
+    let start = HashMap::from([(User::Alice, 100)]);
+    let end = AccountedCurrency::try_apply_all(
+        &start,
+        &[
+            AccountingTransaction::Transfer {
+                sender: User::Alice,
+                receiver: User::Bob,
+                amount: 60,
+            },
+            AccountingTransaction::Transfer {
+                sender: User::Alice,
+                receiver: User::Bob,
+                amount: 60,
+            },
+        ],
+    );
+
+    assert_eq!(
+        end,
+        Err(CurrencyError::InsufficientBalance {
+            available: 40,
+            requested: 60
+        })
+    );
+}
+
+#[test]
+fn sm_4_try_apply_all() {
+    let start = HashMap::from([(User::Alice, 100)]);
+    let end = AccountedCurrency::try_apply_all(
+        &start,
+        &[
+            AccountingTransaction::Mint {
+                minter: User::Bob,
+                amount: 10,
+            },
+            AccountingTransaction::Transfer {
+                sender: User::Alice,
+                receiver: User::Bob,
+                amount: 60,
+            },
+        ],
+    );
+    let expected = HashMap::from([(User::Alice, 40), (User::Bob, 70)]);
+
+    assert_eq!(end, Ok(expected));
+}
+
+#[test]
+fn sm_4_transfer_diff_only_touches_two_accounts() {
+    let start = HashMap::from([(User::Alice, 100), (User::Bob, 50), (User::Charlie, 7)]);
+    let diff = AccountedCurrency::state_diff(
+        &start,
+        &AccountingTransaction::Transfer {
+            sender: User::Bob,
+            receiver: User::Alice,
+            amount: 50,
+        },
+    );
+
+    assert_eq!(diff, Ok(vec![(User::Bob, None), (User::Alice, Some(150))]));
+}
+
+#[test]
+fn sm_4_applying_diffs_matches_next_state() {
+    let transactions = [
+        AccountingTransaction::Mint {
+            minter: User::Alice,
+            amount: 100,
+        },
+        AccountingTransaction::Transfer {
+            sender: User::Alice,
+            receiver: User::Bob,
+            amount: 30,
+        },
+        AccountingTransaction::Burn {
+            burner: User::Bob,
+            amount: 10,
+        },
+        AccountingTransaction::Transfer {
+            sender: User::Bob,
+            receiver: User::Bob,
+            amount: 5,
+        },
+        AccountingTransaction::Transfer {
+            sender: User::Alice,
+            receiver: User::Charlie,
+            amount: 70,
+        },
+    ];
+
+    let mut state = HashMap::new();
+    for t in transactions.iter() {
+        let diff = AccountedCurrency::state_diff(&state, t).unwrap();
+        AccountedCurrency::apply_diff(&mut state, &diff);
+    }
+
+    assert_eq!(state, AccountedCurrency::apply_all(&HashMap::new(), &transactions));
+}
+
+#[test]
+fn sm_4_diffs_agree_with_try_next_state_everywhere() {
+    let users = [User::Alice, User::Bob];
+    let balances = [None, Some(1), Some(10), Some(u64::MAX)];
+    let amounts = [0, 1, 10, 11, u64::MAX];
+
+    let mut states = Vec::new();
+    for alice in balances {
+        for bob in balances {
+            let mut state = HashMap::new();
+            state.extend(alice.map(|b| (User::Alice, b)));
+            state.extend(bob.map(|b| (User::Bob, b)));
+            states.push(state);
+        }
+    }
+    let mut transactions = Vec::new();
+    for amount in amounts {
+        for a in users {
+            transactions.push(AccountingTransaction::Mint { minter: a, amount });
+            transactions.push(AccountingTransaction::Burn { burner: a, amount });
+            for b in users {
+                transactions.push(AccountingTransaction::Transfer { sender: a, receiver: b, amount });
+            }
+        }
+    }
+
+    for state in &states {
+        for (i, t) in transactions.iter().enumerate() {
+            let diffed = AccountedCurrency::state_diff(state, t).map(|diff| {
+                let mut s = state.clone();
+                AccountedCurrency::apply_diff(&mut s, &diff);
+                s
+            });
+            assert_eq!(
+                diffed,
+                AccountedCurrency::try_next_state(state, t),
+                "transaction {i} on {state:?}"
+            );
+        }
+    }
+}
+
+#[test]
+fn sm_4_rejected_transition_has_no_diff() {
+    let start = HashMap::from([(User::Alice, 100)]);
+    let diff = AccountedCurrency::state_diff(
+        &start,
+        &AccountingTransaction::Burn {
+            burner: User::Bob,
+            amount: 1,
+        },
+    );
+
+    assert_eq!(diff, Err(CurrencyError::UnknownAccount(User::Bob)));
+}
+
+#[test]
+fn sm_4_undo_transfer_restores_emptied_sender() {
+    let start = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
+    let t = AccountingTransaction::Transfer {
+        sender: User::Bob,
+        receiver: User::Charlie,
+        amount: 50,
+    };
+    let end = AccountedCurrency::next_state(&start, &t);
+
+    assert_eq!(AccountedCurrency::undo(&end, &t), start);
+}
+
+#[test]
+fn sm_4_undo_all_restores_pre_state() {
+    let start = HashMap::from([(User::Alice, 100)]);
+    let transactions = [
+        AccountingTransaction::Mint {
+            minter: User::Bob,
+            amount: 10,
+        },
+        AccountingTransaction::Transfer {
+            sender: User::Alice,
+            receiver: User::Bob,
+            amount: 60,
+        },
+        AccountingTransaction::Burn {
+            burner: User::Bob,
+            amount: 70,
+        },
+        AccountingTransaction::Transfer {
+            sender: User::Alice,
+            receiver: User::Alice,
+            amount: 10,
+        },
+    ];
+    let end = AccountedCurrency::apply_all(&start, &transactions);
+
+    assert_eq!(end, HashMap::from([(User::Alice, 40)]));
+    assert_eq!(AccountedCurrency::undo_all(&end, &transactions), start);
+}
+
+#[test]
+fn sm_4_genesis_endowments() {
+    let genesis = AccountedCurrency::genesis_state(vec![
+        (User::Alice, 100),
+        (User::Bob, 0),
+        (User::Alice, 5),
+    ]);
+
+    assert_eq!(genesis, HashMap::from([(User::Alice, 105)]));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn sm_4_balances_and_transactions_round_trip_through_json() {
+    fn round_trip<SM: super::SerdeStateMachine>(
+        state: &SM::State,
+        t: &SM::Transition,
+    ) -> (SM::State, SM::Transition) {
+        let state_json = serde_json::to_string(state).unwrap();
+        let t_json = serde_json::to_string(t).unwrap();
+        (
+            serde_json::from_str(&state_json).unwrap(),
+            serde_json::from_str(&t_json).unwrap(),
+        )
+    }
+
+    let start = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
+    let t = AccountingTransaction::Transfer {
+        sender: User::Alice,
+        receiver: User::Bob,
+        amount: 10,
+    };
+    let (decoded_state, decoded_t) = round_trip::<AccountedCurrency>(&start, &t);
+
+    assert_eq!(decoded_state, start);
+    assert_eq!(
+        AccountedCurrency::next_state(&decoded_state, &decoded_t),
+        AccountedCurrency::next_state(&start, &t)
+    );
+}
+
+#[test]
+fn sm_4_transfers_weigh_more_than_mints() {
+    let ts = vec![
+        AccountingTransaction::Mint { minter: User::Alice, amount: 100 },
+        AccountingTransaction::Transfer { sender: User::Alice, receiver: User::Bob, amount: 10 },
+        AccountingTransaction::Burn { burner: User::Bob, amount: 10 },
+    ];
+    assert_eq!(AccountedCurrency::total_weight(&ts), 4);
+}
+
+#[test]
+fn sm_4_events_report_what_happened() {
+    let start = HashMap::from([(User::Alice, 10)]);
+    let ts = vec![
+        AccountingTransaction::Transfer { sender: User::Alice, receiver: User::Bob, amount: 4 },
+        AccountingTransaction::Burn { burner: User::Bob, amount: 100 },
+        AccountingTransaction::Transfer { sender: User::Charlie, receiver: User::Bob, amount: 1 },
+    ];
+
+    let (end, events) = AccountedCurrency::apply_all_with_events(&start, &ts);
+
+    assert_eq!(end, HashMap::from([(User::Alice, 6)]));
+    assert_eq!(
+        events,
+        vec![
+            CurrencyEvent::Transferred { from: User::Alice, to: User::Bob, amount: 4 },
+            CurrencyEvent::Burned { who: User::Bob, amount: 4 },
+        ]
+    );
+}
+
+#[test]
+fn sm_4_overflowing_balances_are_rejected() {
+    let start = HashMap::from([(User::Alice, u64::MAX), (User::Bob, 1)]);
+    let mint = AccountingTransaction::Mint { minter: User::Alice, amount: 1 };
+    let transfer = AccountingTransaction::Transfer { sender: User::Bob, receiver: User::Alice, amount: 1 };
+
+    assert_eq!(AccountedCurrency::next_state(&start, &mint), start);
+    assert_eq!(AccountedCurrency::next_state(&start, &transfer), start);
+    assert_eq!(
+        AccountedCurrency::try_next_state(&start, &transfer),
+        Err(CurrencyError::BalanceOverflow(User::Alice))
+    );
+}
+
+#[test]
+fn sm_4_empty_transfer_does_not_create_account() {
+    let start = HashMap::from([(User::Alice, 10)]);
+    let end = AccountedCurrency::next_state(
+        &start,
+        &AccountingTransaction::Transfer { sender: User::Alice, receiver: User::Bob, amount: 0 },
+    );
+    assert_eq!(end, start);
+}
+
+#[test]
+fn sm_4_validate_transition_matches_try_next_state() {
+    let start = HashMap::from([(User::Alice, 10)]);
+    let ts = [
+        AccountingTransaction::Mint { minter: User::Bob, amount: 1 },
+        AccountingTransaction::Burn { burner: User::Bob, amount: 1 },
+        AccountingTransaction::Transfer { sender: User::Alice, receiver: User::Bob, amount: 10 },
+        AccountingTransaction::Transfer { sender: User::Alice, receiver: User::Bob, amount: 11 },
+    ];
+
+    let valid: Vec<bool> = ts.iter().map(|t| AccountedCurrency::validate_transition(&start, t)).collect();
+
+    assert_eq!(valid, vec![true, false, true, false]);
+    for t in ts.iter() {
+        assert_eq!(
+            AccountedCurrency::validate_transition(&start, t),
+            AccountedCurrency::try_next_state(&start, t).is_ok()
+        );
+    }
+}
+
+#[test]
+fn sm_4_state_root_commits_to_every_balance() {
+    let a = HashMap::from([(User::Alice, 10), (User::Bob, 5)]);
+    let b = HashMap::from([(User::Bob, 5), (User::Alice, 10)]);
+    let c = HashMap::from([(User::Alice, 10), (User::Bob, 6)]);
+
+    assert_eq!(AccountedCurrency::state_root(&a), AccountedCurrency::state_root(&b));
+    assert_ne!(AccountedCurrency::state_root(&a), AccountedCurrency::state_root(&c));
+    assert_eq!(AccountedCurrency::state_tree(&a).root(), AccountedCurrency::state_root(&a));
+}
+
+#[cfg(all(test, feature = "metrics"))]
+use super::instrumented::{Instrumented, Registry};
+
+#[cfg(feature = "metrics")]
+#[test]
+fn sm_4_instrumented_counts_per_transaction_kind() {
+    let name = core::any::type_name::<AccountedCurrency>();
+    let before = Registry::global().get(name, "transfer");
+    let start = HashMap::from([(User::Alice, 10)]);
+    let transfer = |amount| AccountingTransaction::Transfer {
+        sender: User::Alice,
+        receiver: User::Bob,
+        amount,
+    };
+
+    let end = Instrumented::<AccountedCurrency>::try_next_state(&start, &transfer(4)).unwrap();
+    assert!(Instrumented::<AccountedCurrency>::try_next_state(&end, &transfer(7)).is_err());
+
+    let after = Registry::global().get(name, "transfer");
+    assert_eq!(after.applied - before.applied, 1);
+    assert_eq!(after.rejected - before.rejected, 1);
+    assert!(Registry::global().all().iter().any(|(machine, label, _)| *machine == name && *label == "transfer"));
+}
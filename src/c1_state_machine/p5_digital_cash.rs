@@ -2,8 +2,12 @@
 //! accounts, but rather, is modelled after a paper cash system. The system tracks individual
 //! cash bills. Each bill has an amount and an owner, and can be spent in its entirety.
 //! When a state transition spends bills, new bills are created in lesser or equal amount.
+//!
+//! Spends may also pay a fee to the author of the block that includes them, and hand any value
+//! left over back to the spender as change, so that wallets do not have to work out change bills
+//! and their serial numbers themselves.
 
-use super::{StateMachine, User};
+use super::{BlockContext, ContextualStateMachine, StateMachine, User, Weighted};
 use std::collections::HashSet;
 
 /// This state machine models a multi-user currency system. It tracks a set of bills in
@@ -14,15 +18,31 @@ pub struct DigitalCashSystem;
 /// it and an amount that it is worth. It also has serial number to ensure that each bill
 /// is unique.
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Bill {
     owner: User,
     amount: u64,
     serial: u64,
 }
 
+impl Bill {
+    pub fn owner(&self) -> User {
+        self.owner
+    }
+
+    pub fn amount(&self) -> u64 {
+        self.amount
+    }
+
+    pub fn serial(&self) -> u64 {
+        self.serial
+    }
+}
+
 /// The State of a digital cash system. Primarily just the set of currently circulating bills.,
 /// but also a counter for the next serial number.
 #[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct State {
     /// The set of currently circulating bills
     bills: HashSet<Bill>,
@@ -54,6 +74,40 @@ impl State {
         self.bills.insert(elem);
         self.increment_serial()
     }
+
+    /// Print a new bill with the next serial number.
+    fn print_bill(&mut self, owner: User, amount: u64) {
+        let serial = self.next_serial();
+        self.add_bill(Bill { owner, amount, serial });
+    }
+
+    /// The total value of all bills in circulation
+    pub fn total_value(&self) -> u128 {
+        self.bills.iter().map(|b| b.amount as u128).sum()
+    }
+
+    /// Choose which of the owner's bills to spend to raise at least the target amount, or `None`
+    /// if they do not own enough.
+    ///
+    /// If a single bill covers the target, the smallest such bill is chosen, to keep change
+    /// small. Otherwise the largest bills are taken first, to keep the transaction small.
+    pub fn select_coins(&self, owner: User, target: u64) -> Option<Vec<Bill>> {
+        let mut owned: Vec<&Bill> = self.bills.iter().filter(|b| b.owner == owner).collect();
+        owned.sort_by_key(|b| (b.amount, b.serial));
+        if let Some(bill) = owned.iter().find(|b| b.amount >= target) {
+            return Some(vec![(*bill).clone()]);
+        }
+        let mut selected = Vec::new();
+        let mut raised = 0u128;
+        for bill in owned.into_iter().rev() {
+            if raised >= target as u128 {
+                break;
+            }
+            raised += bill.amount as u128;
+            selected.push(bill.clone());
+        }
+        (raised >= target as u128).then_some(selected)
+    }
 }
 
 impl FromIterator<Bill> for State {
@@ -74,6 +128,7 @@ impl<const N: usize> From<[Bill; N]> for State {
 }
 
 /// The state transitions that users can make in a digital cash system
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum CashTransaction {
     /// Mint a single new bill owned by the minter
     Mint { minter: User, amount: u64 },
@@ -86,70 +141,211 @@ pub enum CashTransaction {
         spends: Vec<Bill>,
         receives: Vec<Bill>,
     },
+    /// Spend some bills to pay the given amounts to the given users, and the fee to the block
+    /// author. Whatever is left over is returned to the change owner as a new bill. Nothing is
+    /// destroyed, except for the fee when spending outside of a block. The system assigns serial
+    /// numbers to the new bills: payments first, in order, then change, then the fee.
+    Spend {
+        spends: Vec<Bill>,
+        payments: Vec<(User, u64)>,
+        fee: u64,
+        change: User,
+    },
+}
+
+/// The reasons a transaction may be rejected by the digital cash system
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CashError {
+    /// A transfer must spend at least one bill
+    NoSpends,
+    /// The same bill appears more than once in the spends
+    DuplicateSpend(u64),
+    /// A spent bill is not currently in circulation
+    UnknownBill(u64),
+    /// A received bill reuses a serial number that is already taken or reserved
+    InvalidSerial(u64),
+    /// A bill carries an amount that is zero or too large to account for
+    InvalidAmount(u64),
+    /// The bills received are worth more than the bills spent
+    OutputsExceedInputs,
 }
 
 /// We model this system as a state machine with two possible transitions
 impl StateMachine for DigitalCashSystem {
     type State = State;
     type Transition = CashTransaction;
+    type Error = CashError;
+    /// The bills to print at genesis, as (owner, amount) pairs
+    type GenesisConfig = Vec<(User, u64)>;
+
+    /// One bill is printed for each entry, with serial numbers in order.
+    fn genesis_state(bills: Vec<(User, u64)>) -> Self::State {
+        let mut state = State::new();
+        for (owner, amount) in bills {
+            state.print_bill(owner, amount);
+        }
+        state
+    }
 
     fn next_state(starting_state: &Self::State, t: &Self::Transition) -> Self::State {
+        Self::try_next_state(starting_state, t).unwrap_or_else(|_| starting_state.clone())
+    }
+
+    /// Outside of a block, fees are destroyed, because there is no author to pay them to.
+    fn try_next_state(starting_state: &Self::State, t: &Self::Transition) -> Result<Self::State, Self::Error> {
         match t {
 
 			CashTransaction::Mint {minter,amount} => {
 				let b = Bill{owner:*minter,amount:*amount,serial:starting_state.next_serial()};
 				let mut s = starting_state.clone();
 				s.add_bill(b);
-				s
+				Ok(s)
 			},
 
 			CashTransaction::Transfer {spends,receives} => {
-				if spends.is_empty()   { return starting_state.clone() };
+				if spends.is_empty()   { return Err(CashError::NoSpends) };
 
 				
 				for b in starting_state.bills.iter() {
 					for r in receives {
-						if r.serial == b.serial { return starting_state.clone() } // not valid serials in r bills.
+						if r.serial == b.serial { return Err(CashError::InvalidSerial(r.serial)) } // not valid serials in r bills.
 					}
 				}
 
 				let mut output_state = starting_state.clone();
-
-				let mut s_tot_amount = 0;
-				let mut s_serials:HashSet<u64> = HashSet::new();
-
-				for s in spends{
-					//check if the sender bills are not repeated.
-					if s_serials.contains(&s.serial) { return starting_state.clone() }
-					s_serials.insert( s.serial);
-
-					if s.amount == u64::MAX { return starting_state.clone() }
-					if s.serial == u64::MAX { return starting_state.clone() }
-					if !starting_state.bills.contains(s) { return starting_state.clone() }
-					s_tot_amount += s.amount;
-
-					output_state.bills.remove(s);
-
-				}
+				let s_tot_amount = take_spends(&mut output_state, spends)?;
 
 				let mut r_tot_amount = 0;
 				for r in receives{
-					if r.amount == u64::MAX || r.amount == 0 { return starting_state.clone() }
-					if r.serial == u64::MAX { return starting_state.clone() }
-					r_tot_amount += r.amount;
+					if r.amount == u64::MAX || r.amount == 0 { return Err(CashError::InvalidAmount(r.amount)) }
+					if r.serial == u64::MAX { return Err(CashError::InvalidSerial(r.serial)) }
+					r_tot_amount += r.amount as u128;
 					output_state.add_bill(r.clone());
 				}
 				
-				if r_tot_amount > s_tot_amount { return starting_state.clone() }
+				if r_tot_amount > s_tot_amount { return Err(CashError::OutputsExceedInputs) }
 								
-				output_state
+				Ok(output_state)
+
+			}
 
+			CashTransaction::Spend { spends, payments, fee, change } => {
+				spend(starting_state, spends, payments, *fee, *change, None)
 			}
 
 		}
     }
 }
 
+/// Fees are paid to the block author.
+impl ContextualStateMachine for DigitalCashSystem {
+    fn next_state_in_context(
+        starting_state: &State,
+        t: &CashTransaction,
+        context: &BlockContext,
+    ) -> State {
+        Self::try_next_state_in_context(starting_state, t, context)
+            .unwrap_or_else(|_| starting_state.clone())
+    }
+
+    fn try_next_state_in_context(
+        starting_state: &State,
+        t: &CashTransaction,
+        context: &BlockContext,
+    ) -> Result<State, CashError> {
+        match t {
+            CashTransaction::Spend {
+                spends,
+                payments,
+                fee,
+                change,
+            } => spend(starting_state, spends, payments, *fee, *change, context.author),
+            _ => Self::try_next_state(starting_state, t),
+        }
+    }
+}
+
+/// Remove the spent bills from circulation, returning their total value. The spends must be
+/// distinct bills that are currently in circulation.
+fn take_spends(state: &mut State, spends: &[Bill]) -> Result<u128, CashError> {
+    let mut total = 0;
+    let mut serials = HashSet::new();
+    for s in spends {
+        if !serials.insert(s.serial) {
+            return Err(CashError::DuplicateSpend(s.serial));
+        }
+        if s.amount == u64::MAX {
+            return Err(CashError::InvalidAmount(s.amount));
+        }
+        if s.serial == u64::MAX {
+            return Err(CashError::InvalidSerial(s.serial));
+        }
+        if !state.bills.remove(s) {
+            return Err(CashError::UnknownBill(s.serial));
+        }
+        total += s.amount as u128;
+    }
+    Ok(total)
+}
+
+/// Execute a spend, paying the fee to the given author, or destroying it if there is none.
+fn spend(
+    starting_state: &State,
+    spends: &[Bill],
+    payments: &[(User, u64)],
+    fee: u64,
+    change: User,
+    author: Option<User>,
+) -> Result<State, CashError> {
+    if spends.is_empty() {
+        return Err(CashError::NoSpends);
+    }
+    let mut s = starting_state.clone();
+    let inputs = take_spends(&mut s, spends)?;
+    let mut outputs = fee as u128;
+    if fee == u64::MAX {
+        return Err(CashError::InvalidAmount(fee));
+    }
+    for (_, amount) in payments {
+        if *amount == 0 || *amount == u64::MAX {
+            return Err(CashError::InvalidAmount(*amount));
+        }
+        outputs += *amount as u128;
+    }
+    if outputs > inputs {
+        return Err(CashError::OutputsExceedInputs);
+    }
+    for (to, amount) in payments {
+        s.print_bill(*to, *amount);
+    }
+    // Bills worth u64::MAX cannot be spent, so change too large for one bill is split up.
+    let mut left_over = inputs - outputs;
+    while left_over > 0 {
+        let amount = left_over.min(u64::MAX as u128 - 1);
+        s.print_bill(change, amount as u64);
+        left_over -= amount;
+    }
+    if let (Some(author), true) = (author, fee > 0) {
+        s.print_bill(author, fee);
+    }
+    Ok(s)
+}
+
+/// Every bill a transaction spends or creates has to be looked up or stored, so the weight
+/// grows with the number of bills involved.
+impl Weighted for DigitalCashSystem {
+    fn weight(t: &CashTransaction) -> u64 {
+        match t {
+            CashTransaction::Mint { .. } => 1,
+            CashTransaction::Transfer { spends, receives } => (spends.len() + receives.len()) as u64,
+            // Counting the change and fee bills, which are usually created
+            CashTransaction::Spend {
+                spends, payments, ..
+            } => (spends.len() + payments.len() + 2) as u64,
+        }
+    }
+}
+
 #[test]
 fn sm_5_mint_new_cash() {
     let start = State::new();
@@ -731,3 +927,259 @@ fn sm_5_spending_from_charlie_to_all() {
     expected.set_serial(62);
     assert_eq!(end, expected);
 }
+
+#[test]
+fn sm_5_try_spending_non_existent_bill_is_rejected() {
+    let start = State::from([Bill {
+        owner: User::Alice,
+        amount: 32,
+        serial: 0,
+    }]);
+    let end = DigitalCashSystem::try_next_state(
+        &start,
+        &CashTransaction::Transfer {
+            spends: vec![Bill {
+                owner: User::Bob,
+                amount: 1000,
+                serial: 32,
+            }],
+            receives: vec![Bill {
+                owner: User::Bob,
+                amount: 1000,
+                serial: 33,
+            }],
+        },
+    );
+    assert_eq!(end, Err(CashError::UnknownBill(32)));
+}
+
+#[test]
+fn sm_5_try_spending_more_than_bill_is_rejected() {
+    let start = State::from([Bill {
+        owner: User::Alice,
+        amount: 40,
+        serial: 0,
+    }]);
+    let end = DigitalCashSystem::try_next_state(
+        &start,
+        &CashTransaction::Transfer {
+            spends: vec![Bill {
+                owner: User::Alice,
+                amount: 40,
+                serial: 0,
+            }],
+            receives: vec![Bill {
+                owner: User::Bob,
+                amount: 41,
+                serial: 1,
+            }],
+        },
+    );
+    assert_eq!(end, Err(CashError::OutputsExceedInputs));
+}
+
+#[test]
+fn sm_5_genesis_bills() {
+    let genesis = DigitalCashSystem::genesis_state(vec![(User::Alice, 20), (User::Bob, 5)]);
+    let expected = State::from([
+        Bill {
+            owner: User::Alice,
+            amount: 20,
+            serial: 0,
+        },
+        Bill {
+            owner: User::Bob,
+            amount: 5,
+            serial: 1,
+        },
+    ]);
+
+    assert_eq!(genesis, expected);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn sm_5_state_round_trips_through_json() {
+    let mut start = State::from([
+        Bill {
+            owner: User::Alice,
+            amount: 20,
+            serial: 0,
+        },
+        Bill {
+            owner: User::Charlie,
+            amount: 3,
+            serial: 7,
+        },
+    ]);
+    start.set_serial(8);
+    let json = serde_json::to_string(&start).unwrap();
+
+    assert_eq!(serde_json::from_str::<State>(&json).unwrap(), start);
+}
+
+#[test]
+fn sm_5_transfer_weight_counts_bills() {
+    let bill = Bill {
+        owner: User::Alice,
+        amount: 20,
+        serial: 0,
+    };
+    let t = CashTransaction::Transfer {
+        spends: vec![bill.clone()],
+        receives: vec![bill.clone(), bill],
+    };
+    assert_eq!(DigitalCashSystem::weight(&t), 3);
+}
+
+#[cfg(test)]
+fn authored_by(author: User) -> BlockContext {
+    BlockContext {
+        author: Some(author),
+        ..BlockContext::default()
+    }
+}
+
+#[cfg(test)]
+fn wallet() -> State {
+    DigitalCashSystem::genesis_state(vec![
+        (User::Alice, 20),
+        (User::Alice, 5),
+        (User::Alice, 50),
+        (User::Bob, 7),
+    ])
+}
+
+#[test]
+fn sm_5_spend_pays_fee_to_author_and_returns_change() {
+    let start = wallet();
+    let t = CashTransaction::Spend {
+        spends: start.select_coins(User::Alice, 33).unwrap(),
+        payments: vec![(User::Bob, 30)],
+        fee: 3,
+        change: User::Alice,
+    };
+    let end =
+        DigitalCashSystem::try_next_state_in_context(&start, &t, &authored_by(User::Charlie))
+            .unwrap();
+
+    let mut expected = State::from([
+        Bill {
+            owner: User::Alice,
+            amount: 20,
+            serial: 0,
+        },
+        Bill {
+            owner: User::Alice,
+            amount: 5,
+            serial: 1,
+        },
+        Bill {
+            owner: User::Bob,
+            amount: 7,
+            serial: 3,
+        },
+        Bill {
+            owner: User::Bob,
+            amount: 30,
+            serial: 4,
+        },
+        Bill {
+            owner: User::Alice,
+            amount: 17,
+            serial: 5,
+        },
+        Bill {
+            owner: User::Charlie,
+            amount: 3,
+            serial: 6,
+        },
+    ]);
+    expected.set_serial(7);
+    assert_eq!(end, expected);
+}
+
+#[test]
+fn sm_5_spend_outside_block_destroys_fee() {
+    let start = wallet();
+    let t = CashTransaction::Spend {
+        spends: start.select_coins(User::Bob, 7).unwrap(),
+        payments: vec![],
+        fee: 7,
+        change: User::Bob,
+    };
+    let end = DigitalCashSystem::try_next_state(&start, &t).unwrap();
+
+    assert_eq!(end.total_value(), start.total_value() - 7);
+    assert_eq!(end.select_coins(User::Bob, 1), None);
+}
+
+#[test]
+fn sm_5_spends_never_inflate() {
+    let mut state = wallet();
+    let total = state.total_value();
+    let context = authored_by(User::Charlie);
+    let payments = [
+        (User::Alice, User::Bob, 40, 1),
+        (User::Bob, User::Charlie, 39, 2),
+        (User::Charlie, User::Alice, 40, 0),
+        (User::Alice, User::Alice, 1, 4),
+    ];
+
+    for (from, to, amount, fee) in payments {
+        let t = CashTransaction::Spend {
+            spends: state.select_coins(from, amount + fee).unwrap(),
+            payments: vec![(to, amount)],
+            fee,
+            change: from,
+        };
+        state = DigitalCashSystem::try_next_state_in_context(&state, &t, &context).unwrap();
+        assert_eq!(state.total_value(), total);
+    }
+
+    let overspend = CashTransaction::Spend {
+        spends: state.select_coins(User::Bob, 1).unwrap(),
+        payments: vec![(User::Bob, u64::MAX - 1)],
+        fee: 0,
+        change: User::Bob,
+    };
+    assert_eq!(
+        DigitalCashSystem::try_next_state_in_context(&state, &overspend, &context),
+        Err(CashError::OutputsExceedInputs)
+    );
+}
+
+#[test]
+fn sm_5_large_change_is_split() {
+    let start =
+        DigitalCashSystem::genesis_state(vec![(User::Alice, u64::MAX - 1), (User::Alice, 2)]);
+    let t = CashTransaction::Spend {
+        spends: start.bills.iter().cloned().collect(),
+        payments: vec![],
+        fee: 0,
+        change: User::Alice,
+    };
+    let end = DigitalCashSystem::try_next_state(&start, &t).unwrap();
+
+    assert_eq!(end.total_value(), start.total_value());
+    assert_eq!(end.select_coins(User::Alice, u64::MAX - 1).unwrap().len(), 1);
+    assert_eq!(end.bills.len(), 2);
+}
+
+#[test]
+fn sm_5_select_coins() {
+    let state = wallet();
+    let amounts = |target| {
+        state
+            .select_coins(User::Alice, target)
+            .map(|bills| bills.iter().map(Bill::amount).collect::<Vec<_>>())
+    };
+
+    // The smallest single bill that covers the target
+    assert_eq!(amounts(5), Some(vec![5]));
+    assert_eq!(amounts(21), Some(vec![50]));
+    // Otherwise the largest bills first
+    assert_eq!(amounts(60), Some(vec![50, 20]));
+    assert_eq!(amounts(75), Some(vec![50, 20, 5]));
+    assert_eq!(amounts(76), None);
+}
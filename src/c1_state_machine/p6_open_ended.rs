@@ -19,13 +19,17 @@
 //!   * Social Graph
 //!   * Web of Trust
 //!   * Reputation System
+//!
+//! If your machine is just a starting state and a match over the transitions, the
+//! `state_machine!` macro can write the `StateMachine` impl for you.
 
-use super::StateMachine;
+use super::{ContextualStateMachine, StateMachine};
 
 pub struct TicTacToeSystem;
 
 
-#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TTTSymbol {
 	X,
 	O,
@@ -34,7 +38,7 @@ pub enum TTTSymbol {
 }
 
 
-#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Hash)]
 pub struct Board<T, const ROWS: usize, const COLS: usize>{
 	data:[[T; COLS]; ROWS],
 }
@@ -43,247 +47,338 @@ impl<T, const ROWS: usize, const COLS: usize> Board<T, ROWS, COLS> {
     pub fn new(data: [[T; COLS]; ROWS]) -> Self {
         Self { data }
     }
+
+    /// The cell at the given position, or `None` if it is off the board.
+    pub fn get(&self, row: usize, col: usize) -> Option<&T> {
+        self.data.get(row)?.get(col)
+    }
+
+    /// Overwrite the cell at the given position. Returns false, leaving the board unchanged,
+    /// if the position is off the board.
+    pub fn set(&mut self, row: usize, col: usize, value: T) -> bool {
+        match self.data.get_mut(row).and_then(|r| r.get_mut(col)) {
+            Some(cell) => {
+                *cell = value;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl<T: PartialEq, const ROWS: usize, const COLS: usize> Board<T, ROWS, COLS> {
+    /// The value that fills `k` consecutive cells of some row, column, or diagonal, ignoring
+    /// `blank` cells. If several values do, the one found first scanning row by row is returned.
+    pub fn k_in_a_row(&self, k: usize, blank: &T) -> Option<&T> {
+        if k == 0 {
+            return None;
+        }
+        // Right, down, down-right, and down-left. The other four directions are covered by
+        // starting from the other end of the line.
+        const DIRECTIONS: [(usize, isize); 4] = [(0, 1), (1, 0), (1, 1), (1, -1)];
+        for row in 0..ROWS {
+            for col in 0..COLS {
+                let first = &self.data[row][col];
+                if first == blank {
+                    continue;
+                }
+                let found = DIRECTIONS.iter().any(|&(down, right)| {
+                    (1..k).all(|step| {
+                        let r = row + down * step;
+                        let c = col.checked_add_signed(right * step as isize);
+                        c.and_then(|c| self.get(r, c)) == Some(first)
+                    })
+                });
+                if found {
+                    return Some(first);
+                }
+            }
+        }
+        None
+    }
 }
 
 const N_ROWS:usize  = 3;
 const N_COLS:usize  = 3;
 type TTTBoard = Board<TTTSymbol,N_ROWS,N_COLS>;
 
+/// A mark that was placed on the board.
+#[derive(Eq, PartialEq, Debug, Clone, Copy, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Move {
+	pub symbol: TTTSymbol,
+	pub row: usize,
+	pub col: usize,
+}
 
-#[derive(Eq, PartialEq, Debug, Clone, Copy)]
+/// A match in progress. This is a plain value, so it can be cloned, compared, and hashed into a
+/// block's state root like any other machine's state.
+#[derive(Eq, PartialEq, Debug, Clone, Hash)]
 pub struct State {
 	board: TTTBoard,
-	num_transitions: u8,  // from 0 to 8
-	last_mover     : TTTSymbol,
-	match_complete:  bool,
+	/// Every move of the current match, oldest first.
+	history: Vec<Move>,
+	/// The symbol that completed a line, if any.
+	winner: Option<TTTSymbol>,
 }
 
+impl Default for State {
+	fn default() -> Self {
+		Self::new()
+	}
+}
 
 impl  State {
 
 	pub fn new() -> Self{
-		let newd = State{ 
-								board:TTTBoard { data:[
-									[TTTSymbol::Blank,TTTSymbol::Blank,TTTSymbol::Blank],
-									[TTTSymbol::Blank,TTTSymbol::Blank,TTTSymbol::Blank],
-									[TTTSymbol::Blank,TTTSymbol::Blank,TTTSymbol::Blank],
-									]
-								},
-								last_mover: TTTSymbol::Blank,
-								num_transitions:0,
-								match_complete:false,
-							} ;
-		return newd;
+		State{
+			board:TTTBoard { data:[[TTTSymbol::Blank; N_COLS]; N_ROWS] },
+			history: Vec::new(),
+			winner: None,
+		}
 	}
 
 	pub fn reset(&mut self) {
-		for r in self.board.data.iter_mut() {
-			for c in r {
-				*c = TTTSymbol::Blank;
-			}
-		}
-		self.num_transitions = 0;
-		self.match_complete = false;
-		self.last_mover     = TTTSymbol::Blank;
+		*self = State::new();
+	}
+
+	/// The moves of the current match, oldest first.
+	pub fn history(&self) -> &[Move] {
+		&self.history
 	}
-	
+
+	/// The symbol that completed a line, if any.
+	pub fn winner(&self) -> Option<TTTSymbol> {
+		self.winner
+	}
+
+	/// The symbol that moved last, or `Blank` before the first move.
+	pub fn last_mover(&self) -> TTTSymbol {
+		self.history.last().map_or(TTTSymbol::Blank, |m| m.symbol)
+	}
+
+	/// Whether the match is over, either because somebody won or because the board is full.
+	pub fn is_complete(&self) -> bool {
+		self.winner.is_some() || self.history.len() == N_ROWS * N_COLS
+	}
+
+	/// The symbol that fills a whole row, column, or diagonal, if any.
+	fn find_winner(&self) -> Option<TTTSymbol> {
+		self.board.k_in_a_row(N_ROWS, &TTTSymbol::Blank).copied()
+	}
+
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Transition {
-	MarkCell{symbol:TTTSymbol, row:usize, col:usize},  
+	MarkCell{symbol:TTTSymbol, row:usize, col:usize},
 	Reset
 }
 
-use std::cell::RefCell;
-use std::rc::Rc;
-
 impl StateMachine for TicTacToeSystem {
-	type State = Rc<RefCell<State>>;
+	type State = State;
 	type Transition = Transition;
+	type Error = core::convert::Infallible;
+	type GenesisConfig = ();
+
+	/// Every match starts from an empty board.
+	fn genesis_state(_: ()) -> Self::State {
+		State::new()
+	}
 
+	/// Moves that are out of turn, off the board, onto a marked cell, or made after the match
+	/// is over leave the state unchanged.
 	fn next_state(starting: &Self::State, t: &Self::Transition) -> Self::State {
-		
-		if starting.borrow().match_complete {
-			let new_state = Rc::clone(starting);
+
+		let mut new_state = starting.clone();
+		if starting.is_complete() {
 			return new_state;
 		}
 
-		let new_state = Rc::clone(starting);
 		match t {
 
 			Transition::MarkCell{symbol, row, col } => {
-				
-				if *symbol == starting.borrow().last_mover {
-					return  new_state;
+
+				if *symbol == starting.last_mover() || *symbol == TTTSymbol::Blank {
+					return new_state;
 				}
-				
-				if *row < N_ROWS && *col < N_COLS {
-					let cs = new_state.borrow().board.data[*row][*col];
-					if cs == TTTSymbol::Blank && *symbol != TTTSymbol::Blank {
-						starting.borrow_mut().board.data[*row][*col] = *symbol;
-						starting.borrow_mut().num_transitions += 1;
-						starting.borrow_mut().last_mover = *symbol;
-					}
-
-					// horizontal wins
-					if 	!starting.borrow_mut().match_complete {
-						for r in starting.borrow_mut().board.data {
-							if r.iter().all(|&x|  x == TTTSymbol::X) 
-							|| 
-							r.iter().all(|&x|  x == TTTSymbol::O) {
-								starting.borrow_mut().match_complete = true;
-							}
-						}
-					}
-
-					// vertical wins
-					if 	!starting.borrow_mut().match_complete {
-						for r_index in 0..N_ROWS {
-							if  starting.borrow_mut().board.data.iter().all( |&x|  x[r_index] == TTTSymbol::X )  
-								|| starting.borrow_mut().board.data.iter().all( |&x|  x[r_index] == TTTSymbol::O ) {
-									starting.borrow_mut().match_complete = true;
-							} 
-						}
-					}
-
-					// diagonal wins
-					if 	!starting.borrow_mut().match_complete {
-						let values  = [TTTSymbol::X,TTTSymbol::O];
-						for sym in values.iter() {
-							if     starting.borrow_mut().board.data[0][0] == *sym
-								&& starting.borrow_mut().board.data[1][1] == *sym
-								&& starting.borrow_mut().board.data[2][2] == *sym {
-									starting.borrow_mut().match_complete = true;
-								}
-
-							else if starting.borrow_mut().board.data[0][2] == *sym 
-								 && starting.borrow_mut().board.data[1][1] == *sym
-								 && starting.borrow_mut().board.data[2][0] == *sym {
-									starting.borrow_mut().match_complete = true;
-								}
-						}
-					}
-					
-					//no winners
-					if !starting.borrow_mut().match_complete && starting.borrow_mut().num_transitions == (N_COLS * N_ROWS) as u8 {
-						starting.borrow_mut().match_complete = true;
-					}
-					
+
+				if *row < N_ROWS && *col < N_COLS && starting.board.data[*row][*col] == TTTSymbol::Blank {
+					new_state.board.data[*row][*col] = *symbol;
+					new_state.history.push(Move { symbol: *symbol, row: *row, col: *col });
+					new_state.winner = new_state.find_winner();
 				}
 			},
 
 			Transition::Reset => {
-				starting.borrow_mut().reset()
+				new_state.reset()
 			}
 
 
 		}
-		
-		return new_state;
+
+		new_state
 
 	}
 }
 
+impl ContextualStateMachine for TicTacToeSystem {}
 
 
+/// Play the given moves from an empty board.
+#[cfg(test)]
+fn play(moves: &[(TTTSymbol, usize, usize)]) -> State {
+	let ts: Vec<Transition> = moves
+		.iter()
+		.map(|&(symbol, row, col)| Transition::MarkCell { symbol, row, col })
+		.collect();
+	TicTacToeSystem::apply_all(&State::new(), &ts)
+}
+
 #[test]
 fn reset_new_dashboard() {
-	let start = <TicTacToeSystem as StateMachine>::State::new(RefCell::new(State::new()));
+	let start = TicTacToeSystem::genesis_state(());
 	let end   = TicTacToeSystem::next_state(&start,&Transition::Reset);
 	assert_eq!(end, start);
 }
 
 #[test]
 fn test_xxin00() {
-	let start = <TicTacToeSystem as StateMachine>::State::new(RefCell::new(State::new()));
+	let start = TicTacToeSystem::genesis_state(());
 	let end   = TicTacToeSystem::next_state(&start,&Transition::MarkCell { symbol: TTTSymbol::X, row: 0, col: 0 });
-	let expected = <TicTacToeSystem as StateMachine>::State::new(RefCell::new(State{ 
+	let expected = State{
 			board:TTTBoard { data:[
 				[TTTSymbol::X,TTTSymbol::Blank,TTTSymbol::Blank],
 				[TTTSymbol::Blank,TTTSymbol::Blank,TTTSymbol::Blank],
 				[TTTSymbol::Blank,TTTSymbol::Blank,TTTSymbol::Blank],
 				]
 			},
-			num_transitions:1,
-			last_mover:TTTSymbol::X,
-			match_complete:false,
-	}));
+			history: vec![Move { symbol: TTTSymbol::X, row: 0, col: 0 }],
+			winner: None,
+	};
 	assert_eq!(end, expected);
+	assert_eq!(end.last_mover(), TTTSymbol::X);
 }
 
 #[test]
 fn test_insertBlankFails() {
-	let start = <TicTacToeSystem as StateMachine>::State::new(RefCell::new(State::new()));
+	let start = TicTacToeSystem::genesis_state(());
 	let end   = TicTacToeSystem::next_state(&start,&Transition::MarkCell { symbol: TTTSymbol::Blank, row: 0, col: 0 });
-	let expected = <TicTacToeSystem as StateMachine>::State::new(RefCell::new(State{ 
-			board:TTTBoard { data:[
-				[TTTSymbol::Blank,TTTSymbol::Blank,TTTSymbol::Blank],
-				[TTTSymbol::Blank,TTTSymbol::Blank,TTTSymbol::Blank],
-				[TTTSymbol::Blank,TTTSymbol::Blank,TTTSymbol::Blank],
-				]
-			},
-			num_transitions:0,
-			last_mover:TTTSymbol::Blank,
-			match_complete:false,
-	}));
-	assert_eq!(end, expected);
+	assert_eq!(end, State::new());
 }
 
 
 #[test]
 fn test_diagonal_wis() {
-	let start = <TicTacToeSystem as StateMachine>::State::new(RefCell::new(State{ 
-		board:TTTBoard { data:[
-			[TTTSymbol::X,TTTSymbol::O,TTTSymbol::Blank],
-			[TTTSymbol::O,TTTSymbol::X,TTTSymbol::Blank],
-			[TTTSymbol::Blank,TTTSymbol::Blank,TTTSymbol::Blank],
-			]
-		},
-		num_transitions:2,
-		last_mover:TTTSymbol::O,
-		match_complete:false,
-	}));
+	let start = play(&[
+		(TTTSymbol::X, 0, 0),
+		(TTTSymbol::O, 0, 1),
+		(TTTSymbol::X, 1, 1),
+		(TTTSymbol::O, 1, 0),
+	]);
 	let end   = TicTacToeSystem::next_state(&start,&Transition::MarkCell { symbol: TTTSymbol::X, row: 2, col: 2 });
 
-	let expected = <TicTacToeSystem as StateMachine>::State::new(RefCell::new(State{ 
-			board:TTTBoard { data:[
-				[TTTSymbol::X,TTTSymbol::O,TTTSymbol::Blank],
-				[TTTSymbol::O,TTTSymbol::X,TTTSymbol::Blank],
-				[TTTSymbol::Blank,TTTSymbol::Blank,TTTSymbol::X],
-				]
-			},
-			num_transitions:3,
-			last_mover:TTTSymbol::X,
-			match_complete:true,
-	}));
-	assert_eq!(end, expected);
+	assert_eq!(end.board, TTTBoard { data:[
+		[TTTSymbol::X,TTTSymbol::O,TTTSymbol::Blank],
+		[TTTSymbol::O,TTTSymbol::X,TTTSymbol::Blank],
+		[TTTSymbol::Blank,TTTSymbol::Blank,TTTSymbol::X],
+		]
+	});
+	assert_eq!(end.history().len(), 5);
+	assert_eq!(end.winner(), Some(TTTSymbol::X));
+	assert!(end.is_complete());
 }
 
 #[test]
 fn test_matchCompleteNoWinner() {
-	let start = <TicTacToeSystem as StateMachine>::State::new(RefCell::new(State{ 
-		board:TTTBoard { data:[
-			[TTTSymbol::X,TTTSymbol::O,TTTSymbol::O],
-			[TTTSymbol::O,TTTSymbol::X,TTTSymbol::X],
-			[TTTSymbol::X,TTTSymbol::O,TTTSymbol::Blank],
-			]
-		},
-		num_transitions:8,
-		last_mover:TTTSymbol::X,
-		match_complete:false,
-	}));
-	let end   = TicTacToeSystem::next_state(&start,&Transition::MarkCell { symbol: TTTSymbol::O, row: 2, col: 2 });
-
-	let expected = <TicTacToeSystem as StateMachine>::State::new(RefCell::new(State{ 
-			board:TTTBoard { data:[
-				[TTTSymbol::X,TTTSymbol::O,TTTSymbol::O],
-				[TTTSymbol::O,TTTSymbol::X,TTTSymbol::X],
-				[TTTSymbol::X,TTTSymbol::O,TTTSymbol::O],
-				]
-			},
-			num_transitions:9,
-			last_mover:TTTSymbol::O,
-			match_complete:true,
-	}));
-	assert_eq!(end, expected);
-}
\ No newline at end of file
+	let start = play(&[
+		(TTTSymbol::X, 0, 0),
+		(TTTSymbol::O, 0, 1),
+		(TTTSymbol::X, 1, 1),
+		(TTTSymbol::O, 0, 2),
+		(TTTSymbol::X, 1, 2),
+		(TTTSymbol::O, 1, 0),
+		(TTTSymbol::X, 2, 0),
+		(TTTSymbol::O, 2, 2),
+	]);
+	assert!(!start.is_complete());
+	let end   = TicTacToeSystem::next_state(&start,&Transition::MarkCell { symbol: TTTSymbol::X, row: 2, col: 1 });
+
+	assert_eq!(end.board, TTTBoard { data:[
+		[TTTSymbol::X,TTTSymbol::O,TTTSymbol::O],
+		[TTTSymbol::O,TTTSymbol::X,TTTSymbol::X],
+		[TTTSymbol::X,TTTSymbol::X,TTTSymbol::O],
+		]
+	});
+	assert_eq!(end.winner(), None);
+	assert!(end.is_complete());
+}
+#[test]
+fn test_horizontal_win() {
+	let start = play(&[
+		(TTTSymbol::X, 0, 0),
+		(TTTSymbol::O, 1, 0),
+		(TTTSymbol::X, 0, 1),
+		(TTTSymbol::O, 1, 1),
+	]);
+	let end   = TicTacToeSystem::next_state(&start,&Transition::MarkCell { symbol: TTTSymbol::X, row: 0, col: 2 });
+
+	assert!(end.is_complete());
+	assert_eq!(end.winner(), Some(TTTSymbol::X));
+}
+
+#[test]
+fn sm_6_vertical_win_for_o() {
+	let end = play(&[
+		(TTTSymbol::X, 0, 0),
+		(TTTSymbol::O, 0, 2),
+		(TTTSymbol::X, 1, 1),
+		(TTTSymbol::O, 1, 2),
+		(TTTSymbol::X, 2, 1),
+		(TTTSymbol::O, 2, 2),
+	]);
+
+	assert_eq!(end.winner(), Some(TTTSymbol::O));
+}
+
+#[test]
+fn sm_6_next_state_leaves_starting_state_untouched() {
+	let start = play(&[(TTTSymbol::X, 1, 1)]);
+	let before = start.clone();
+	let end = TicTacToeSystem::next_state(&start, &Transition::MarkCell { symbol: TTTSymbol::O, row: 0, col: 0 });
+
+	assert_eq!(start, before);
+	assert_ne!(end, start);
+	assert_eq!(end.history(), &[
+		Move { symbol: TTTSymbol::X, row: 1, col: 1 },
+		Move { symbol: TTTSymbol::O, row: 0, col: 0 },
+	]);
+	assert_ne!(crate::hash(&end), crate::hash(&start));
+}
+
+#[test]
+fn sm_6_rejected_moves_are_not_recorded() {
+	let start = play(&[(TTTSymbol::X, 1, 1)]);
+	let rejected = [
+		Transition::MarkCell { symbol: TTTSymbol::X, row: 0, col: 0 },
+		Transition::MarkCell { symbol: TTTSymbol::O, row: 1, col: 1 },
+		Transition::MarkCell { symbol: TTTSymbol::O, row: 3, col: 0 },
+	];
+
+	assert_eq!(TicTacToeSystem::apply_all(&start, &rejected), start);
+}
+
+#[test]
+fn sm_6_moves_after_win_are_ignored() {
+	let won = play(&[
+		(TTTSymbol::X, 0, 0),
+		(TTTSymbol::O, 1, 0),
+		(TTTSymbol::X, 0, 1),
+		(TTTSymbol::O, 1, 1),
+		(TTTSymbol::X, 0, 2),
+	]);
+	let end = TicTacToeSystem::next_state(&won, &Transition::MarkCell { symbol: TTTSymbol::O, row: 1, col: 2 });
+
+	assert_eq!(end, won);
+	assert_eq!(end.winner(), Some(TTTSymbol::X));
+}
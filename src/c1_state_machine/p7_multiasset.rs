@@ -0,0 +1,908 @@
+//! The accounted currency from part 4 tracks a single token. Real chains usually host many of
+//! them: stablecoins, governance tokens, game items, and so on. Rather than deploying one
+//! currency machine per token, a multi-asset machine keeps them all in one state.
+//!
+//! Anyone may create a new asset and becomes its issuer. Only the issuer can mint new units of an
+//! asset, while every holder can burn or transfer their own units. The machine keeps track of each
+//! asset's total supply, which must always equal the sum of all balances of that asset.
+//!
+//! Asset 0 is the chain's native asset, in which block authors are paid. The coinbase that
+//! starts each block mints the block reward into the author's account, however the native asset
+//! was issued. Transactions pay no fees, so the reward is all the coinbase credits. A coinbase
+//! is only valid in a block whose context names the author it pays, so users cannot pay
+//! themselves by submitting one, and outside a block it is never valid.
+
+use std::collections::HashMap;
+
+#[cfg(feature = "metrics")]
+use super::instrumented::Labelled;
+use super::{
+    BlockContext, ContextualStateMachine, MerkleState, Rewarded, StateMachine, User, Weighted,
+};
+use crate::codec::{Decode, Encode};
+
+/// This state machine models many fungible tokens side by side.
+pub struct MultiAsset;
+
+/// Assets are identified by a number chosen by their creator.
+pub type AssetId = u32;
+
+/// The asset block authors are paid in
+pub const NATIVE_ASSET: AssetId = 0;
+
+/// What the chain knows about an asset besides its balances
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AssetDetails {
+    /// The only account allowed to mint this asset
+    pub issuer: User,
+    /// The number of units of this asset in existence
+    pub supply: u64,
+}
+
+/// The state of the multi-asset machine.
+///
+/// Like the accounted currency, there is an existential deposit of 1. A balance that falls to
+/// 0 is removed from the map entirely.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MultiAssetState {
+    pub assets: HashMap<AssetId, AssetDetails>,
+    #[cfg_attr(feature = "serde", serde(with = "balance_pairs"))]
+    pub balances: HashMap<(AssetId, User), u64>,
+}
+
+/// JSON only has string keys, so balances are written as a list of `[[asset, who], balance]`
+/// pairs instead of a map, in key order.
+#[cfg(feature = "serde")]
+mod balance_pairs {
+    use super::{AssetId, HashMap, User};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        balances: &HashMap<(AssetId, User), u64>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut pairs: Vec<_> = balances.iter().collect();
+        pairs.sort_by_key(|(key, _)| **key);
+        serializer.collect_seq(pairs)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<(AssetId, User), u64>, D::Error> {
+        let pairs = Vec::<((AssetId, User), u64)>::deserialize(deserializer)?;
+        Ok(pairs.into_iter().collect())
+    }
+}
+
+/// The maps are hashed in key order, so that equal states hash alike however their maps were
+/// built. Nodes rely on this to agree on state roots.
+impl core::hash::Hash for MultiAssetState {
+    fn hash<H: core::hash::Hasher>(&self, hasher: &mut H) {
+        let mut assets: Vec<_> = self.assets.iter().collect();
+        assets.sort_by_key(|(asset, _)| **asset);
+        assets.hash(hasher);
+        let mut balances: Vec<_> = self.balances.iter().collect();
+        balances.sort_by_key(|(key, _)| **key);
+        balances.hash(hasher);
+    }
+}
+
+impl MultiAssetState {
+    /// The given account's balance of the given asset
+    pub fn balance(&self, asset: AssetId, who: User) -> u64 {
+        self.balances.get(&(asset, who)).copied().unwrap_or(0)
+    }
+
+    /// The total supply of the given asset, or None if it has not been created
+    pub fn total_supply(&self, asset: AssetId) -> Option<u64> {
+        self.assets.get(&asset).map(|details| details.supply)
+    }
+
+    /// Check the invariants every reachable state upholds: each asset's supply equals the
+    /// sum of its balances, no balance is 0, and no balance belongs to an unknown asset.
+    pub fn invariants_hold(&self) -> bool {
+        let mut sums: HashMap<AssetId, u128> = HashMap::new();
+        for ((asset, _), balance) in &self.balances {
+            if *balance == 0 || !self.assets.contains_key(asset) {
+                return false;
+            }
+            *sums.entry(*asset).or_insert(0) += u128::from(*balance);
+        }
+        self.assets.iter().all(|(asset, details)| {
+            sums.get(asset).copied().unwrap_or(0) == u128::from(details.supply)
+        })
+    }
+}
+
+/// The state transitions that users can make in a multi-asset system
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum AssetTransaction {
+    /// Create a new asset with no supply, issued by the creator
+    CreateAsset { creator: User, asset: AssetId },
+    /// Create new units of an asset in the issuer's own account
+    Mint {
+        issuer: User,
+        asset: AssetId,
+        amount: u64,
+    },
+    /// Destroy units of an asset from the burner's own account
+    Burn {
+        burner: User,
+        asset: AssetId,
+        amount: u64,
+    },
+    /// Send units of an asset from one account to another
+    Transfer {
+        asset: AssetId,
+        sender: User,
+        receiver: User,
+        amount: u64,
+    },
+    /// Pay the block's author in the native asset. Only valid as the first transition of a block.
+    Coinbase { author: User, amount: u64 },
+}
+
+/// A tag byte for the variant, followed by its fields in the order they are declared.
+impl Encode for AssetTransaction {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        match self {
+            AssetTransaction::CreateAsset { creator, asset } => {
+                dest.push(0);
+                creator.encode_to(dest);
+                asset.encode_to(dest);
+            }
+            AssetTransaction::Mint {
+                issuer,
+                asset,
+                amount,
+            } => {
+                dest.push(1);
+                issuer.encode_to(dest);
+                asset.encode_to(dest);
+                amount.encode_to(dest);
+            }
+            AssetTransaction::Burn {
+                burner,
+                asset,
+                amount,
+            } => {
+                dest.push(2);
+                burner.encode_to(dest);
+                asset.encode_to(dest);
+                amount.encode_to(dest);
+            }
+            AssetTransaction::Transfer {
+                asset,
+                sender,
+                receiver,
+                amount,
+            } => {
+                dest.push(3);
+                asset.encode_to(dest);
+                sender.encode_to(dest);
+                receiver.encode_to(dest);
+                amount.encode_to(dest);
+            }
+            AssetTransaction::Coinbase { author, amount } => {
+                dest.push(4);
+                author.encode_to(dest);
+                amount.encode_to(dest);
+            }
+        }
+    }
+}
+
+impl Decode for AssetTransaction {
+    fn decode_from(input: &mut &[u8]) -> Option<Self> {
+        match u8::decode_from(input)? {
+            0 => Some(AssetTransaction::CreateAsset {
+                creator: User::decode_from(input)?,
+                asset: AssetId::decode_from(input)?,
+            }),
+            1 => Some(AssetTransaction::Mint {
+                issuer: User::decode_from(input)?,
+                asset: AssetId::decode_from(input)?,
+                amount: u64::decode_from(input)?,
+            }),
+            2 => Some(AssetTransaction::Burn {
+                burner: User::decode_from(input)?,
+                asset: AssetId::decode_from(input)?,
+                amount: u64::decode_from(input)?,
+            }),
+            3 => Some(AssetTransaction::Transfer {
+                asset: AssetId::decode_from(input)?,
+                sender: User::decode_from(input)?,
+                receiver: User::decode_from(input)?,
+                amount: u64::decode_from(input)?,
+            }),
+            4 => Some(AssetTransaction::Coinbase {
+                author: User::decode_from(input)?,
+                amount: u64::decode_from(input)?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// The fields in the order they are declared.
+impl Encode for AssetDetails {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.issuer.encode_to(dest);
+        self.supply.encode_to(dest);
+    }
+}
+
+impl Decode for AssetDetails {
+    fn decode_from(input: &mut &[u8]) -> Option<Self> {
+        Some(AssetDetails {
+            issuer: User::decode_from(input)?,
+            supply: u64::decode_from(input)?,
+        })
+    }
+}
+
+/// Both maps as lists of pairs in key order, like they are hashed, so that equal states encode
+/// alike. Nodes send states to each other when one starts from another's snapshot.
+impl Encode for MultiAssetState {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        let mut assets: Vec<_> = self.assets.iter().collect();
+        assets.sort_by_key(|(asset, _)| **asset);
+        assets.encode_to(dest);
+        let mut balances: Vec<_> = self.balances.iter().collect();
+        balances.sort_by_key(|(key, _)| **key);
+        balances.encode_to(dest);
+    }
+}
+
+impl Decode for MultiAssetState {
+    fn decode_from(input: &mut &[u8]) -> Option<Self> {
+        Some(MultiAssetState {
+            assets: Vec::<(AssetId, AssetDetails)>::decode_from(input)?
+                .into_iter()
+                .collect(),
+            balances: Vec::<((AssetId, User), u64)>::decode_from(input)?
+                .into_iter()
+                .collect(),
+        })
+    }
+}
+
+/// The reasons a transaction may be rejected by the multi-asset system
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AssetError {
+    /// An asset with this id already exists
+    AssetExists(AssetId),
+    /// No asset with this id exists
+    UnknownAsset(AssetId),
+    /// Only the issuer may mint an asset
+    NotIssuer { asset: AssetId, who: User },
+    /// The account being debited does not hold enough of the asset
+    InsufficientBalance { available: u64, requested: u64 },
+    /// Minting would push the asset's supply past `u64::MAX`
+    SupplyOverflow(AssetId),
+    /// A coinbase may only pay the author of the block it is in
+    NotAuthor(User),
+}
+
+impl StateMachine for MultiAsset {
+    type State = MultiAssetState;
+    type Transition = AssetTransaction;
+    type Error = AssetError;
+    /// Each asset that exists at genesis, with its issuer and initial endowments
+    type GenesisConfig = Vec<(AssetId, User, Vec<(User, u64)>)>;
+
+    /// Every listed asset exists, with a supply equal to its endowments. Empty endowments are
+    /// ignored because of the existential deposit. If an asset is listed twice, the first
+    /// listing's issuer wins and the endowments are combined.
+    fn genesis_state(config: Self::GenesisConfig) -> MultiAssetState {
+        let mut state = MultiAssetState::default();
+        for (asset, issuer, endowments) in config {
+            state
+                .assets
+                .entry(asset)
+                .or_insert(AssetDetails { issuer, supply: 0 });
+            for (who, amount) in endowments {
+                mint(&mut state, asset, who, amount);
+            }
+        }
+        state
+    }
+
+    fn next_state(starting_state: &MultiAssetState, t: &AssetTransaction) -> MultiAssetState {
+        Self::try_next_state(starting_state, t).unwrap_or_else(|_| starting_state.clone())
+    }
+
+    /// Without a block there is no author, so a coinbase is rejected.
+    fn try_next_state(
+        starting_state: &MultiAssetState,
+        t: &AssetTransaction,
+    ) -> Result<MultiAssetState, AssetError> {
+        execute(starting_state, t, None)
+    }
+
+    /// Unlike `try_next_state`, this never clones the state.
+    fn validate_transition(state: &MultiAssetState, t: &AssetTransaction) -> bool {
+        check_transaction(state, t, None).is_ok()
+    }
+
+    fn human_name() -> String {
+        "Multi-asset tokens".into()
+    }
+}
+
+/// A coinbase is accepted only if it pays the author the block context names.
+impl ContextualStateMachine for MultiAsset {
+    fn next_state_in_context(
+        starting_state: &MultiAssetState,
+        t: &AssetTransaction,
+        context: &BlockContext,
+    ) -> MultiAssetState {
+        execute(starting_state, t, context.author).unwrap_or_else(|_| starting_state.clone())
+    }
+
+    fn try_next_state_in_context(
+        starting_state: &MultiAssetState,
+        t: &AssetTransaction,
+        context: &BlockContext,
+    ) -> Result<MultiAssetState, AssetError> {
+        execute(starting_state, t, context.author)
+    }
+}
+
+/// Execute the given transaction in a block by the given author, if any.
+fn execute(
+    starting_state: &MultiAssetState,
+    t: &AssetTransaction,
+    author: Option<User>,
+) -> Result<MultiAssetState, AssetError> {
+    check_transaction(starting_state, t, author)?;
+    let mut s = starting_state.clone();
+    match t {
+        AssetTransaction::CreateAsset { creator, asset } => {
+            s.assets.insert(
+                *asset,
+                AssetDetails {
+                    issuer: *creator,
+                    supply: 0,
+                },
+            );
+        }
+        AssetTransaction::Mint {
+            issuer,
+            asset,
+            amount,
+        } => mint(&mut s, *asset, *issuer, *amount),
+        AssetTransaction::Burn {
+            burner,
+            asset,
+            amount,
+        } => {
+            debit(&mut s, *asset, *burner, *amount);
+            if let Some(details) = s.assets.get_mut(asset) {
+                details.supply -= amount;
+            }
+        }
+        AssetTransaction::Transfer {
+            asset,
+            sender,
+            receiver,
+            amount,
+        } => {
+            debit(&mut s, *asset, *sender, *amount);
+            credit(&mut s, *asset, *receiver, *amount);
+        }
+        AssetTransaction::Coinbase { author, amount } => {
+            mint(&mut s, NATIVE_ASSET, *author, *amount)
+        }
+    }
+    Ok(s)
+}
+
+/// Check whether the given transaction would be accepted in the given state, in a block by the
+/// given author, if any.
+fn check_transaction(
+    state: &MultiAssetState,
+    t: &AssetTransaction,
+    author: Option<User>,
+) -> Result<(), AssetError> {
+    let details = |asset: &AssetId| {
+        state
+            .assets
+            .get(asset)
+            .ok_or(AssetError::UnknownAsset(*asset))
+    };
+    let has_funds = |asset: &AssetId, who: &User, requested: u64| {
+        let available = state.balance(*asset, *who);
+        if available < requested {
+            return Err(AssetError::InsufficientBalance {
+                available,
+                requested,
+            });
+        }
+        Ok(())
+    };
+
+    match t {
+        AssetTransaction::CreateAsset { asset, .. } => {
+            if state.assets.contains_key(asset) {
+                return Err(AssetError::AssetExists(*asset));
+            }
+        }
+        AssetTransaction::Mint {
+            issuer,
+            asset,
+            amount,
+        } => {
+            let details = details(asset)?;
+            if details.issuer != *issuer {
+                return Err(AssetError::NotIssuer {
+                    asset: *asset,
+                    who: *issuer,
+                });
+            }
+            if details.supply.checked_add(*amount).is_none() {
+                return Err(AssetError::SupplyOverflow(*asset));
+            }
+        }
+        AssetTransaction::Burn {
+            burner,
+            asset,
+            amount,
+        } => {
+            details(asset)?;
+            has_funds(asset, burner, *amount)?;
+        }
+        AssetTransaction::Transfer {
+            asset,
+            sender,
+            amount,
+            ..
+        } => {
+            details(asset)?;
+            has_funds(asset, sender, *amount)?;
+        }
+        AssetTransaction::Coinbase {
+            author: paid,
+            amount,
+        } => {
+            if author != Some(*paid) {
+                return Err(AssetError::NotAuthor(*paid));
+            }
+            if details(&NATIVE_ASSET)?
+                .supply
+                .checked_add(*amount)
+                .is_none()
+            {
+                return Err(AssetError::SupplyOverflow(NATIVE_ASSET));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Create new units of an asset in the given account. The caller must have checked that the
+/// asset exists and that its supply does not overflow.
+fn mint(state: &mut MultiAssetState, asset: AssetId, who: User, amount: u64) {
+    if let Some(details) = state.assets.get_mut(&asset) {
+        details.supply += amount;
+        credit(state, asset, who, amount);
+    }
+}
+
+/// Add units of an asset to an account, creating the balance if necessary. No balance can
+/// overflow, because every balance is at most the asset's supply.
+fn credit(state: &mut MultiAssetState, asset: AssetId, who: User, amount: u64) {
+    if amount > 0 {
+        *state.balances.entry((asset, who)).or_insert(0) += amount;
+    }
+}
+
+/// Remove units of an asset from an account, removing the balance entirely if it empties.
+fn debit(state: &mut MultiAssetState, asset: AssetId, who: User, amount: u64) {
+    if let Some(balance) = state.balances.get_mut(&(asset, who)) {
+        *balance = balance.saturating_sub(amount);
+        if *balance == 0 {
+            state.balances.remove(&(asset, who));
+        }
+    }
+}
+
+/// Authors are paid in the native asset.
+impl Rewarded for MultiAsset {
+    fn coinbase(author: User, amount: u64) -> AssetTransaction {
+        AssetTransaction::Coinbase { author, amount }
+    }
+
+    fn is_coinbase(t: &AssetTransaction) -> bool {
+        matches!(t, AssetTransaction::Coinbase { .. })
+    }
+}
+
+/// A transfer touches two balances while everything else touches at most one.
+impl Weighted for MultiAsset {
+    fn weight(t: &AssetTransaction) -> u64 {
+        match t {
+            AssetTransaction::Transfer { .. } => 2,
+            _ => 1,
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl Labelled for MultiAsset {
+    fn label(t: &AssetTransaction) -> &'static str {
+        match t {
+            AssetTransaction::CreateAsset { .. } => "create_asset",
+            AssetTransaction::Mint { .. } => "mint",
+            AssetTransaction::Burn { .. } => "burn",
+            AssetTransaction::Transfer { .. } => "transfer",
+            AssetTransaction::Coinbase { .. } => "coinbase",
+        }
+    }
+}
+
+/// A key in the Merkle view of the multi-asset state. Each asset's details and each balance is
+/// a separate entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AssetKey {
+    Details(AssetId),
+    Balance(AssetId, User),
+}
+
+/// A value in the Merkle view of the multi-asset state
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AssetValue {
+    Details(AssetDetails),
+    Balance(u64),
+}
+
+impl MerkleState for MultiAsset {
+    type Key = AssetKey;
+    type Value = AssetValue;
+
+    fn entries(state: &MultiAssetState) -> Vec<(AssetKey, AssetValue)> {
+        let details = state
+            .assets
+            .iter()
+            .map(|(asset, d)| (AssetKey::Details(*asset), AssetValue::Details(*d)));
+        let balances = state.balances.iter().map(|((asset, who), balance)| {
+            (
+                AssetKey::Balance(*asset, *who),
+                AssetValue::Balance(*balance),
+            )
+        });
+        details.chain(balances).collect()
+    }
+}
+
+#[cfg(test)]
+const GOLD: AssetId = 1;
+#[cfg(test)]
+const SILVER: AssetId = 2;
+
+#[cfg(test)]
+fn gold_and_silver() -> MultiAssetState {
+    MultiAsset::genesis_state(vec![
+        (GOLD, User::Alice, vec![(User::Alice, 100), (User::Bob, 20)]),
+        (SILVER, User::Bob, vec![(User::Bob, 50)]),
+    ])
+}
+
+#[test]
+fn sm_7_genesis_supply_matches_endowments() {
+    let state = gold_and_silver();
+
+    assert_eq!(state.total_supply(GOLD), Some(120));
+    assert_eq!(state.total_supply(SILVER), Some(50));
+    assert_eq!(state.total_supply(3), None);
+    assert!(state.invariants_hold());
+}
+
+#[test]
+fn sm_7_create_asset() {
+    let t = AssetTransaction::CreateAsset {
+        creator: User::Charlie,
+        asset: 3,
+    };
+    let end = MultiAsset::try_next_state(&gold_and_silver(), &t).unwrap();
+
+    assert_eq!(
+        end.assets.get(&3),
+        Some(&AssetDetails {
+            issuer: User::Charlie,
+            supply: 0
+        })
+    );
+    assert_eq!(
+        MultiAsset::try_next_state(&end, &t),
+        Err(AssetError::AssetExists(3))
+    );
+}
+
+#[test]
+fn sm_7_only_issuer_mints() {
+    let start = gold_and_silver();
+    let mint = |issuer| AssetTransaction::Mint {
+        issuer,
+        asset: GOLD,
+        amount: 30,
+    };
+
+    let end = MultiAsset::try_next_state(&start, &mint(User::Alice)).unwrap();
+    assert_eq!(end.balance(GOLD, User::Alice), 130);
+    assert_eq!(end.total_supply(GOLD), Some(150));
+
+    assert_eq!(
+        MultiAsset::try_next_state(&start, &mint(User::Bob)),
+        Err(AssetError::NotIssuer {
+            asset: GOLD,
+            who: User::Bob
+        })
+    );
+    assert_eq!(MultiAsset::next_state(&start, &mint(User::Bob)), start);
+}
+
+#[test]
+fn sm_7_mint_rejects_supply_overflow() {
+    let start = gold_and_silver();
+    let t = AssetTransaction::Mint {
+        issuer: User::Bob,
+        asset: SILVER,
+        amount: u64::MAX,
+    };
+
+    assert_eq!(
+        MultiAsset::try_next_state(&start, &t),
+        Err(AssetError::SupplyOverflow(SILVER))
+    );
+}
+
+#[test]
+fn sm_7_burn_reduces_supply() {
+    let t = AssetTransaction::Burn {
+        burner: User::Bob,
+        asset: GOLD,
+        amount: 20,
+    };
+    let end = MultiAsset::try_next_state(&gold_and_silver(), &t).unwrap();
+
+    assert_eq!(end.balance(GOLD, User::Bob), 0);
+    assert!(!end.balances.contains_key(&(GOLD, User::Bob)));
+    assert_eq!(end.total_supply(GOLD), Some(100));
+    assert!(end.invariants_hold());
+}
+
+#[test]
+fn sm_7_coinbase_mints_the_native_asset_for_the_author() {
+    let native = MultiAsset::genesis_state(vec![(NATIVE_ASSET, User::Alice, vec![])]);
+    let t = MultiAsset::coinbase(User::Charlie, 10);
+    assert!(MultiAsset::is_coinbase(&t));
+    assert_eq!(MultiAsset::coinbase_amount(std::slice::from_ref(&t), 10), 10);
+
+    let by = |author| BlockContext {
+        author,
+        ..BlockContext::default()
+    };
+    let end = MultiAsset::try_next_state_in_context(&native, &t, &by(Some(User::Charlie))).unwrap();
+    assert_eq!(end.balance(NATIVE_ASSET, User::Charlie), 10);
+    assert_eq!(end.total_supply(NATIVE_ASSET), Some(10));
+    assert!(end.invariants_hold());
+    // Without a native asset there is nothing to pay authors in.
+    assert_eq!(
+        MultiAsset::try_next_state_in_context(&gold_and_silver(), &t, &by(Some(User::Charlie))),
+        Err(AssetError::UnknownAsset(NATIVE_ASSET))
+    );
+}
+
+#[test]
+fn sm_7_only_the_block_author_is_paid_a_coinbase() {
+    let native = MultiAsset::genesis_state(vec![(NATIVE_ASSET, User::Alice, vec![])]);
+    let t = MultiAsset::coinbase(User::Charlie, 10);
+    let by = |author| BlockContext {
+        author,
+        ..BlockContext::default()
+    };
+
+    // Users cannot pay themselves, in a block or in the pool.
+    for author in [Some(User::Alice), None] {
+        assert_eq!(
+            MultiAsset::try_next_state_in_context(&native, &t, &by(author)),
+            Err(AssetError::NotAuthor(User::Charlie))
+        );
+    }
+    assert_eq!(
+        MultiAsset::try_next_state(&native, &t),
+        Err(AssetError::NotAuthor(User::Charlie))
+    );
+    assert!(!MultiAsset::validate_transition(&native, &t));
+}
+
+#[test]
+fn sm_7_transfer_moves_only_the_given_asset() {
+    let t = AssetTransaction::Transfer {
+        asset: SILVER,
+        sender: User::Bob,
+        receiver: User::Charlie,
+        amount: 10,
+    };
+    let end = MultiAsset::try_next_state(&gold_and_silver(), &t).unwrap();
+
+    assert_eq!(end.balance(SILVER, User::Bob), 40);
+    assert_eq!(end.balance(SILVER, User::Charlie), 10);
+    assert_eq!(end.balance(GOLD, User::Bob), 20);
+    assert_eq!(end.total_supply(SILVER), Some(50));
+}
+
+#[test]
+fn sm_7_transfer_rejects_insufficient_balance() {
+    let t = AssetTransaction::Transfer {
+        asset: SILVER,
+        sender: User::Alice,
+        receiver: User::Bob,
+        amount: 1,
+    };
+
+    assert_eq!(
+        MultiAsset::try_next_state(&gold_and_silver(), &t),
+        Err(AssetError::InsufficientBalance {
+            available: 0,
+            requested: 1
+        })
+    );
+    assert!(!MultiAsset::validate_transition(&gold_and_silver(), &t));
+}
+
+#[test]
+fn sm_7_unknown_asset() {
+    let t = AssetTransaction::Burn {
+        burner: User::Alice,
+        asset: 9,
+        amount: 0,
+    };
+
+    assert_eq!(
+        MultiAsset::try_next_state(&gold_and_silver(), &t),
+        Err(AssetError::UnknownAsset(9))
+    );
+}
+
+#[test]
+fn sm_7_invariants_hold_after_every_transaction() {
+    let ts = [
+        AssetTransaction::CreateAsset {
+            creator: User::Charlie,
+            asset: 3,
+        },
+        AssetTransaction::Mint {
+            issuer: User::Charlie,
+            asset: 3,
+            amount: 7,
+        },
+        AssetTransaction::Transfer {
+            asset: 3,
+            sender: User::Charlie,
+            receiver: User::Alice,
+            amount: 7,
+        },
+        AssetTransaction::Transfer {
+            asset: GOLD,
+            sender: User::Bob,
+            receiver: User::Bob,
+            amount: 20,
+        },
+        AssetTransaction::Burn {
+            burner: User::Alice,
+            asset: GOLD,
+            amount: 100,
+        },
+    ];
+
+    let mut state = gold_and_silver();
+    for t in &ts {
+        state = MultiAsset::try_next_state(&state, t).unwrap();
+        assert!(state.invariants_hold());
+    }
+    assert_eq!(state.balance(3, User::Alice), 7);
+    assert_eq!(state.total_supply(GOLD), Some(20));
+}
+
+#[test]
+fn sm_7_state_root_commits_to_assets_and_balances() {
+    let start = gold_and_silver();
+    let mut other_issuer = start.clone();
+    other_issuer.assets.get_mut(&SILVER).unwrap().issuer = User::Charlie;
+    let mut other_balance = start.clone();
+    other_balance.balances.insert((SILVER, User::Bob), 51);
+
+    assert_ne!(
+        MultiAsset::state_root(&start),
+        MultiAsset::state_root(&other_issuer)
+    );
+    assert_ne!(
+        MultiAsset::state_root(&start),
+        MultiAsset::state_root(&other_balance)
+    );
+}
+
+#[test]
+fn sm_7_transactions_round_trip_through_the_codec() {
+    let transactions = [
+        AssetTransaction::CreateAsset {
+            creator: User::Charlie,
+            asset: 3,
+        },
+        AssetTransaction::Mint {
+            issuer: User::Alice,
+            asset: GOLD,
+            amount: 30,
+        },
+        AssetTransaction::Burn {
+            burner: User::Bob,
+            asset: SILVER,
+            amount: 5,
+        },
+        AssetTransaction::Transfer {
+            asset: GOLD,
+            sender: User::Alice,
+            receiver: User::Eve,
+            amount: u64::MAX,
+        },
+        AssetTransaction::Coinbase {
+            author: User::Dave,
+            amount: 7,
+        },
+    ];
+    for t in transactions {
+        assert_eq!(AssetTransaction::decode(&t.encode()), Some(t));
+    }
+    assert_eq!(AssetTransaction::decode(&[5]), None);
+}
+
+#[test]
+fn sm_7_state_round_trips_through_the_codec() {
+    let state = gold_and_silver();
+    assert_eq!(
+        MultiAssetState::decode(&state.encode()),
+        Some(state.clone())
+    );
+    // However the maps were built.
+    let mut balances: Vec<_> = state.balances.clone().into_iter().collect();
+    balances.sort_by_key(|(key, _)| std::cmp::Reverse(*key));
+    let reversed = MultiAssetState {
+        balances: balances.into_iter().collect(),
+        ..state.clone()
+    };
+    assert_eq!(reversed.encode(), state.encode());
+    let encoded = state.encode();
+    assert_eq!(MultiAssetState::decode(&encoded[..encoded.len() - 1]), None);
+}
+
+#[test]
+fn sm_7_equal_states_hash_alike() {
+    // The same balances, inserted in descending order.
+    let mut balances: Vec<_> = gold_and_silver().balances.into_iter().collect();
+    balances.sort_by_key(|(key, _)| std::cmp::Reverse(*key));
+    let reversed = MultiAssetState {
+        assets: gold_and_silver().assets,
+        balances: balances.into_iter().collect(),
+    };
+    assert_eq!(reversed, gold_and_silver());
+    assert_eq!(crate::hash(&reversed), crate::hash(&gold_and_silver()));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn sm_7_state_round_trips_through_json() {
+    let json = serde_json::to_value(gold_and_silver()).unwrap();
+    assert_eq!(
+        json["balances"][0],
+        serde_json::json!([[GOLD, "Alice"], 100])
+    );
+    assert_eq!(
+        serde_json::from_value::<MultiAssetState>(json).unwrap(),
+        gold_and_silver()
+    );
+}
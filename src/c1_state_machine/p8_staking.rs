@@ -0,0 +1,560 @@
+//! Proof of Stake chains pick their block authors according to how much money they have put at
+//! stake. Here we model the staking system itself, leaving the consensus engine that reads from
+//! it for later.
+//!
+//! Users start with free funds. They may bond some of them to become a validator candidate, or
+//! delegate some of them to an existing candidate, adding to that candidate's weight. Bonded and
+//! delegated funds cannot simply be taken back, though. If they could, a validator could
+//! misbehave and withdraw their stake before anyone had a chance to punish them. Instead, unbonded
+//! funds are locked for an unbonding period, measured in blocks, before they can be withdrawn.
+//! Validators caught misbehaving within that period are slashed: part of everything staked with
+//! them, bonded or still unbonding, is burned.
+
+use std::collections::{HashMap, HashSet};
+
+use super::{BlockContext, ContextualStateMachine, StateMachine, User, Weighted};
+
+/// The number of blocks unbonded funds stay locked before they can be withdrawn
+pub const UNBONDING_PERIOD: u64 = 28;
+
+/// The percentage of a validator's stake burned for each offence
+pub const SLASH_PERCENT: u64 = 10;
+
+/// This state machine models a staking system with validators and delegators.
+pub struct Staking;
+
+/// Funds that have been unbonded and are waiting out the unbonding period
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UnbondingChunk {
+    pub who: User,
+    pub amount: u64,
+    /// The first block height at which these funds can be withdrawn
+    pub unlocks_at: u64,
+}
+
+/// The state of the staking system.
+///
+/// No funds are ever created, only moved between free, bonded, delegated, and unbonding, or
+/// destroyed by slashing. Since nobody can hold more than their genesis endowment, no balance can
+/// overflow.
+/// Zero entries are removed from the maps.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StakingState {
+    /// Funds each user can bond, delegate, or spend
+    pub free: HashMap<User, u64>,
+    /// Each validator candidate's own stake
+    pub bonded: HashMap<User, u64>,
+    /// Funds delegated, keyed by delegator and then validator
+    pub delegations: HashMap<(User, User), u64>,
+    /// Funds waiting out the unbonding period, in the order they were unbonded
+    pub unbonding: Vec<UnbondingChunk>,
+    /// The offences already punished, by offender and the slot they were committed in
+    pub offences: HashSet<(User, u64)>,
+}
+
+impl StakingState {
+    /// The total stake backing the given validator: their own bond plus everything delegated
+    /// to them. Users who have not bonded anything themselves are not validators, and have no
+    /// stake even if delegations to them are still outstanding.
+    pub fn stake_of(&self, validator: &User) -> u128 {
+        let Some(own) = self.bonded.get(validator) else {
+            return 0;
+        };
+        let delegated: u128 = self
+            .delegations
+            .iter()
+            .filter(|((_, v), _)| v == validator)
+            .map(|(_, amount)| u128::from(*amount))
+            .sum();
+        u128::from(*own) + delegated
+    }
+
+    /// The `n` validators with the most stake, most staked first, along with their stake.
+    /// Ties are broken in favour of the validator that comes first in `User`'s order.
+    pub fn top_stakers(&self, n: usize) -> Vec<(User, u128)> {
+        let mut stakers: Vec<(User, u128)> =
+            self.bonded.keys().map(|v| (*v, self.stake_of(v))).collect();
+        stakers.sort_by(|(a, a_stake), (b, b_stake)| b_stake.cmp(a_stake).then(a.cmp(b)));
+        stakers.truncate(n);
+        stakers
+    }
+
+    /// The given user's funds that have finished unbonding at the given height
+    pub fn withdrawable(&self, who: &User, height: u64) -> u64 {
+        self.unbonding
+            .iter()
+            .filter(|chunk| chunk.who == *who && chunk.unlocks_at <= height)
+            .map(|chunk| chunk.amount)
+            .sum()
+    }
+}
+
+/// The state transitions that users can make in a staking system
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum StakingTransaction {
+    /// Lock free funds as the user's own stake, making them a validator candidate
+    Bond { who: User, amount: u64 },
+    /// Start unbonding some of the user's own stake
+    Unbond { who: User, amount: u64 },
+    /// Lock free funds behind an existing validator candidate
+    Delegate {
+        delegator: User,
+        validator: User,
+        amount: u64,
+    },
+    /// Start unbonding some funds delegated to the given validator
+    Undelegate {
+        delegator: User,
+        validator: User,
+        amount: u64,
+    },
+    /// Return all of the user's funds that have finished unbonding to their free balance
+    Withdraw { who: User },
+    /// Punish a validator for an offence committed in the given slot, burning `SLASH_PERCENT` of
+    /// their own stake, of the stake delegated to them, and of their funds still unbonding. The
+    /// staking system takes the offence on trust, so blocks should only include this once the
+    /// offence is proven, as with `c3_consensus::equivocation::EquivocationProof`.
+    Slash { offender: User, slot: u64 },
+}
+
+/// The reasons a transaction may be rejected by the staking system
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StakingError {
+    /// The user does not have enough free funds
+    InsufficientFree { available: u64, requested: u64 },
+    /// The user has not bonded or delegated enough to unbond the requested amount
+    InsufficientStake { available: u64, requested: u64 },
+    /// Funds can only be delegated to a user who has bonded some of their own
+    NotAValidator(User),
+    /// None of the user's unbonding funds have unlocked yet
+    NothingToWithdraw,
+    /// The offence has already been punished
+    AlreadySlashed { offender: User, slot: u64 },
+}
+
+impl StateMachine for Staking {
+    type State = StakingState;
+    type Transition = StakingTransaction;
+    type Error = StakingError;
+    /// The initial free funds of each user
+    type GenesisConfig = Vec<(User, u64)>;
+
+    /// Every endowed user starts with their endowment free. Nobody is staking yet.
+    fn genesis_state(endowments: Vec<(User, u64)>) -> StakingState {
+        let mut state = StakingState::default();
+        for (who, amount) in endowments {
+            add(&mut state.free, who, amount);
+        }
+        state
+    }
+
+    fn next_state(starting_state: &StakingState, t: &StakingTransaction) -> StakingState {
+        Self::next_state_in_context(starting_state, t, &BlockContext::default())
+    }
+
+    fn try_next_state(
+        starting_state: &StakingState,
+        t: &StakingTransaction,
+    ) -> Result<StakingState, StakingError> {
+        Self::try_next_state_in_context(starting_state, t, &BlockContext::default())
+    }
+
+    fn human_name() -> String {
+        "Staking".into()
+    }
+}
+
+/// Unbonding periods are measured from the height of the block that unbonds, and withdrawals
+/// only release the funds that have unlocked by the current height.
+impl ContextualStateMachine for Staking {
+    fn next_state_in_context(
+        starting_state: &StakingState,
+        t: &StakingTransaction,
+        context: &BlockContext,
+    ) -> StakingState {
+        Self::try_next_state_in_context(starting_state, t, context)
+            .unwrap_or_else(|_| starting_state.clone())
+    }
+
+    fn try_next_state_in_context(
+        starting_state: &StakingState,
+        t: &StakingTransaction,
+        context: &BlockContext,
+    ) -> Result<StakingState, StakingError> {
+        let mut s = starting_state.clone();
+        let unlocks_at = context.height.saturating_add(UNBONDING_PERIOD);
+        match t {
+            StakingTransaction::Bond { who, amount } => {
+                take_free(&mut s, *who, *amount)?;
+                add(&mut s.bonded, *who, *amount);
+            }
+            StakingTransaction::Unbond { who, amount } => {
+                take(&mut s.bonded, *who, *amount)
+                    .map_err(|available| insufficient_stake(available, *amount))?;
+                start_unbonding(&mut s, *who, *amount, unlocks_at);
+            }
+            StakingTransaction::Delegate {
+                delegator,
+                validator,
+                amount,
+            } => {
+                if !s.bonded.contains_key(validator) {
+                    return Err(StakingError::NotAValidator(*validator));
+                }
+                take_free(&mut s, *delegator, *amount)?;
+                add(&mut s.delegations, (*delegator, *validator), *amount);
+            }
+            StakingTransaction::Undelegate {
+                delegator,
+                validator,
+                amount,
+            } => {
+                take(&mut s.delegations, (*delegator, *validator), *amount)
+                    .map_err(|available| insufficient_stake(available, *amount))?;
+                start_unbonding(&mut s, *delegator, *amount, unlocks_at);
+            }
+            StakingTransaction::Withdraw { who } => {
+                let amount = s.withdrawable(who, context.height);
+                if amount == 0 {
+                    return Err(StakingError::NothingToWithdraw);
+                }
+                s.unbonding
+                    .retain(|chunk| chunk.who != *who || chunk.unlocks_at > context.height);
+                add(&mut s.free, *who, amount);
+            }
+            StakingTransaction::Slash { offender, slot } => {
+                let has_stake = s.bonded.contains_key(offender)
+                    || s.unbonding.iter().any(|chunk| chunk.who == *offender);
+                if !has_stake {
+                    return Err(StakingError::NotAValidator(*offender));
+                }
+                if !s.offences.insert((*offender, *slot)) {
+                    return Err(StakingError::AlreadySlashed {
+                        offender: *offender,
+                        slot: *slot,
+                    });
+                }
+                slash(&mut s.bonded, |v| v == offender);
+                slash(&mut s.delegations, |(_, v)| v == offender);
+                for chunk in s.unbonding.iter_mut().filter(|c| c.who == *offender) {
+                    chunk.amount -= slashed_part(chunk.amount);
+                }
+                s.unbonding.retain(|chunk| chunk.amount > 0);
+            }
+        }
+        Ok(s)
+    }
+}
+
+/// Every staking operation touches a bounded number of entries.
+impl Weighted for Staking {
+    fn weight(_: &StakingTransaction) -> u64 {
+        1
+    }
+}
+
+fn insufficient_stake(available: u64, requested: u64) -> StakingError {
+    StakingError::InsufficientStake {
+        available,
+        requested,
+    }
+}
+
+/// Add to an entry of a map of amounts, creating it if necessary. Zero amounts are not stored.
+fn add<K: Eq + core::hash::Hash>(amounts: &mut HashMap<K, u64>, key: K, amount: u64) {
+    if amount > 0 {
+        let entry = amounts.entry(key).or_insert(0);
+        *entry = entry.saturating_add(amount);
+    }
+}
+
+/// Take from an entry of a map of amounts, removing it if it empties. Fails with the available
+/// amount if there is not enough.
+fn take<K: Eq + core::hash::Hash>(
+    amounts: &mut HashMap<K, u64>,
+    key: K,
+    amount: u64,
+) -> Result<(), u64> {
+    let available = amounts.get(&key).copied().unwrap_or(0);
+    if available < amount {
+        return Err(available);
+    }
+    if available == amount {
+        amounts.remove(&key);
+    } else {
+        amounts.insert(key, available - amount);
+    }
+    Ok(())
+}
+
+/// The part of the given amount a slash burns
+fn slashed_part(amount: u64) -> u64 {
+    // The product fits in a u128, and the quotient is at most the amount.
+    (u128::from(amount) * u128::from(SLASH_PERCENT) / 100) as u64
+}
+
+/// Burn the slashed part of every entry of a map of amounts whose key matches.
+fn slash<K: Eq + core::hash::Hash + Copy>(
+    amounts: &mut HashMap<K, u64>,
+    matches: impl Fn(&K) -> bool,
+) {
+    let keys: Vec<K> = amounts.keys().copied().filter(|k| matches(k)).collect();
+    for key in keys {
+        let amount = amounts[&key];
+        // Taking less than the entry holds cannot fail.
+        let _ = take(amounts, key, slashed_part(amount));
+    }
+}
+
+fn take_free(state: &mut StakingState, who: User, amount: u64) -> Result<(), StakingError> {
+    take(&mut state.free, who, amount).map_err(|available| StakingError::InsufficientFree {
+        available,
+        requested: amount,
+    })
+}
+
+fn start_unbonding(state: &mut StakingState, who: User, amount: u64, unlocks_at: u64) {
+    if amount > 0 {
+        state.unbonding.push(UnbondingChunk {
+            who,
+            amount,
+            unlocks_at,
+        });
+    }
+}
+
+#[cfg(test)]
+fn at_height(height: u64) -> BlockContext {
+    BlockContext {
+        height,
+        ..BlockContext::default()
+    }
+}
+
+#[cfg(test)]
+fn endowed() -> StakingState {
+    Staking::genesis_state(vec![
+        (User::Alice, 100),
+        (User::Bob, 100),
+        (User::Charlie, 100),
+    ])
+}
+
+#[test]
+fn sm_8_bond_moves_free_funds() {
+    let end = Staking::try_next_state(
+        &endowed(),
+        &StakingTransaction::Bond {
+            who: User::Alice,
+            amount: 40,
+        },
+    )
+    .unwrap();
+
+    assert_eq!(end.free.get(&User::Alice), Some(&60));
+    assert_eq!(end.bonded.get(&User::Alice), Some(&40));
+}
+
+#[test]
+fn sm_8_bond_rejects_insufficient_free() {
+    assert_eq!(
+        Staking::try_next_state(
+            &endowed(),
+            &StakingTransaction::Bond {
+                who: User::Alice,
+                amount: 101,
+            },
+        ),
+        Err(StakingError::InsufficientFree {
+            available: 100,
+            requested: 101
+        })
+    );
+}
+
+#[test]
+fn sm_8_delegate_requires_validator() {
+    let t = StakingTransaction::Delegate {
+        delegator: User::Bob,
+        validator: User::Alice,
+        amount: 10,
+    };
+
+    assert_eq!(
+        Staking::try_next_state(&endowed(), &t),
+        Err(StakingError::NotAValidator(User::Alice))
+    );
+
+    let bonded = Staking::next_state(
+        &endowed(),
+        &StakingTransaction::Bond {
+            who: User::Alice,
+            amount: 1,
+        },
+    );
+    let end = Staking::try_next_state(&bonded, &t).unwrap();
+    assert_eq!(end.stake_of(&User::Alice), 11);
+    assert_eq!(end.stake_of(&User::Bob), 0);
+}
+
+#[test]
+fn sm_8_unbonded_funds_unlock_after_period() {
+    let bonded = Staking::next_state(
+        &endowed(),
+        &StakingTransaction::Bond {
+            who: User::Alice,
+            amount: 40,
+        },
+    );
+    let unbonding = Staking::try_next_state_in_context(
+        &bonded,
+        &StakingTransaction::Unbond {
+            who: User::Alice,
+            amount: 30,
+        },
+        &at_height(10),
+    )
+    .unwrap();
+    let withdraw = StakingTransaction::Withdraw { who: User::Alice };
+
+    assert_eq!(unbonding.bonded.get(&User::Alice), Some(&10));
+    assert_eq!(
+        Staking::try_next_state_in_context(
+            &unbonding,
+            &withdraw,
+            &at_height(10 + UNBONDING_PERIOD - 1)
+        ),
+        Err(StakingError::NothingToWithdraw)
+    );
+
+    let end = Staking::try_next_state_in_context(
+        &unbonding,
+        &withdraw,
+        &at_height(10 + UNBONDING_PERIOD),
+    )
+    .unwrap();
+    assert_eq!(end.free.get(&User::Alice), Some(&90));
+    assert!(end.unbonding.is_empty());
+}
+
+#[test]
+fn sm_8_undelegate_starts_unbonding() {
+    let ts = [
+        StakingTransaction::Bond {
+            who: User::Alice,
+            amount: 10,
+        },
+        StakingTransaction::Delegate {
+            delegator: User::Bob,
+            validator: User::Alice,
+            amount: 50,
+        },
+        StakingTransaction::Undelegate {
+            delegator: User::Bob,
+            validator: User::Alice,
+            amount: 20,
+        },
+    ];
+    let end = Staking::try_apply_all_in_context(&endowed(), &ts, &at_height(3)).unwrap();
+
+    assert_eq!(end.stake_of(&User::Alice), 40);
+    assert_eq!(
+        end.unbonding,
+        vec![UnbondingChunk {
+            who: User::Bob,
+            amount: 20,
+            unlocks_at: 3 + UNBONDING_PERIOD
+        }]
+    );
+    assert_eq!(
+        Staking::try_next_state(
+            &end,
+            &StakingTransaction::Undelegate {
+                delegator: User::Bob,
+                validator: User::Alice,
+                amount: 31,
+            }
+        ),
+        Err(StakingError::InsufficientStake {
+            available: 30,
+            requested: 31
+        })
+    );
+}
+
+#[test]
+fn sm_8_top_stakers() {
+    let ts = [
+        StakingTransaction::Bond {
+            who: User::Alice,
+            amount: 10,
+        },
+        StakingTransaction::Bond {
+            who: User::Bob,
+            amount: 30,
+        },
+        StakingTransaction::Bond {
+            who: User::Charlie,
+            amount: 5,
+        },
+        StakingTransaction::Delegate {
+            delegator: User::Charlie,
+            validator: User::Alice,
+            amount: 20,
+        },
+    ];
+    let end = Staking::try_apply_all(&endowed(), &ts).unwrap();
+
+    assert_eq!(end.top_stakers(2), vec![(User::Alice, 30), (User::Bob, 30)]);
+    assert_eq!(end.top_stakers(5).len(), 3);
+}
+
+#[test]
+fn sm_8_slash_burns_stake_once() {
+    let ts = [
+        StakingTransaction::Bond {
+            who: User::Alice,
+            amount: 50,
+        },
+        StakingTransaction::Delegate {
+            delegator: User::Bob,
+            validator: User::Alice,
+            amount: 30,
+        },
+        StakingTransaction::Unbond {
+            who: User::Alice,
+            amount: 20,
+        },
+    ];
+    let staked = Staking::try_apply_all(&endowed(), &ts).unwrap();
+    let slash = StakingTransaction::Slash {
+        offender: User::Alice,
+        slot: 7,
+    };
+    let end = Staking::try_next_state(&staked, &slash).unwrap();
+
+    assert_eq!(end.bonded.get(&User::Alice), Some(&27));
+    assert_eq!(end.delegations.get(&(User::Bob, User::Alice)), Some(&27));
+    assert_eq!(end.unbonding[0].amount, 18);
+    assert_eq!(end.free, staked.free);
+    assert_eq!(
+        Staking::try_next_state(&end, &slash),
+        Err(StakingError::AlreadySlashed {
+            offender: User::Alice,
+            slot: 7
+        })
+    );
+    assert_eq!(
+        Staking::try_next_state(
+            &end,
+            &StakingTransaction::Slash {
+                offender: User::Charlie,
+                slot: 7
+            }
+        ),
+        Err(StakingError::NotAValidator(User::Charlie))
+    );
+}
@@ -0,0 +1,638 @@
+//! A chain's rules cannot stay fixed forever, but who gets to change them? Many chains answer
+//! that question on chain: token holders vote on proposals, and proposals that pass are enacted
+//! automatically.
+//!
+//! Here we model referenda that change a chain parameter, such as the PoW difficulty. Votes are
+//! weighted by the voter's balance, as it was when the referendum was proposed. Weighing votes
+//! by current balances instead would let a user vote, send their tokens to a friend, and have
+//! the friend vote with the same tokens again.
+
+use std::collections::HashMap;
+
+use super::{
+    BlockContext, ContextualStateMachine, EventfulStateMachine, StateMachine, User, Weighted,
+};
+use crate::codec::{Decode, Encode};
+
+/// The number of blocks a referendum accepts votes for
+pub const VOTING_PERIOD: u64 = 10;
+
+/// This state machine models token-weighted referenda on chain parameters.
+pub struct Governance;
+
+/// Referenda are numbered in the order they were proposed, starting from 0.
+pub type ReferendumId = usize;
+
+/// The chain parameters governance can change
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Parameter {
+    /// The most weight a single block may hold
+    MaxBlockWeight,
+    /// The threshold below which a PoW seal's hash must fall
+    PowThreshold,
+}
+
+/// A change to a single chain parameter
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ParameterChange {
+    pub parameter: Parameter,
+    pub value: u64,
+}
+
+/// Where a referendum is in its lifecycle
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ReferendumStatus {
+    /// Accepting votes until the deadline
+    Voting,
+    /// Closed with more aye than nay weight, and waiting to be enacted
+    Passed,
+    /// Closed without more aye than nay weight. Ties are rejected.
+    Rejected,
+    /// Passed and applied
+    Enacted,
+}
+
+/// A single proposed parameter change and the votes on it
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Referendum {
+    pub proposer: User,
+    pub change: ParameterChange,
+    /// Every balance at the time the referendum was proposed. Votes are weighted by these.
+    pub snapshot: HashMap<User, u64>,
+    /// Each voter's latest vote. True means aye.
+    pub votes: HashMap<User, bool>,
+    /// The first block height at which votes are no longer accepted
+    pub deadline: u64,
+    pub status: ReferendumStatus,
+}
+
+impl Referendum {
+    /// The total aye and nay weight so far
+    pub fn tally(&self) -> (u128, u128) {
+        let mut ayes = 0;
+        let mut nays = 0;
+        for (voter, aye) in &self.votes {
+            let weight = u128::from(self.snapshot.get(voter).copied().unwrap_or(0));
+            if *aye {
+                ayes += weight;
+            } else {
+                nays += weight;
+            }
+        }
+        (ayes, nays)
+    }
+}
+
+/// The maps are hashed in key order, so that equal referenda hash alike.
+impl core::hash::Hash for Referendum {
+    fn hash<H: core::hash::Hasher>(&self, hasher: &mut H) {
+        self.proposer.hash(hasher);
+        self.change.hash(hasher);
+        let mut snapshot: Vec<_> = self.snapshot.iter().collect();
+        snapshot.sort();
+        snapshot.hash(hasher);
+        let mut votes: Vec<_> = self.votes.iter().collect();
+        votes.sort();
+        votes.hash(hasher);
+        self.deadline.hash(hasher);
+        self.status.hash(hasher);
+    }
+}
+
+/// The state of the governance system
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct GovernanceState {
+    /// The balance of the governance token held by each user. Zero balances are not stored.
+    pub balances: HashMap<User, u64>,
+    /// The current value of every parameter that has ever been set
+    pub parameters: HashMap<Parameter, u64>,
+    pub referenda: Vec<Referendum>,
+}
+
+/// The maps are hashed in key order, so that equal states hash alike, and chains can commit to
+/// them.
+impl core::hash::Hash for GovernanceState {
+    fn hash<H: core::hash::Hasher>(&self, hasher: &mut H) {
+        let mut balances: Vec<_> = self.balances.iter().collect();
+        balances.sort();
+        balances.hash(hasher);
+        let mut parameters: Vec<_> = self.parameters.iter().collect();
+        parameters.sort();
+        parameters.hash(hasher);
+        self.referenda.hash(hasher);
+    }
+}
+
+/// The state transitions that users can make in the governance system
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum GovernanceTransaction {
+    /// Send governance tokens to another user
+    Transfer {
+        sender: User,
+        receiver: User,
+        amount: u64,
+    },
+    /// Start a referendum on the given change
+    Propose {
+        proposer: User,
+        change: ParameterChange,
+    },
+    /// Vote on an open referendum, replacing the voter's earlier vote if any
+    Vote {
+        voter: User,
+        referendum: ReferendumId,
+        aye: bool,
+    },
+    /// Count the votes of a referendum whose deadline has passed. Anyone may do this.
+    Close { referendum: ReferendumId },
+    /// Apply the change of a passed referendum. Anyone may do this.
+    Enact { referendum: ReferendumId },
+}
+
+/// A tag byte for the parameter.
+impl Encode for Parameter {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        dest.push(match self {
+            Parameter::MaxBlockWeight => 0,
+            Parameter::PowThreshold => 1,
+        });
+    }
+}
+
+impl Decode for Parameter {
+    fn decode_from(input: &mut &[u8]) -> Option<Self> {
+        match u8::decode_from(input)? {
+            0 => Some(Parameter::MaxBlockWeight),
+            1 => Some(Parameter::PowThreshold),
+            _ => None,
+        }
+    }
+}
+
+impl Encode for ParameterChange {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.parameter.encode_to(dest);
+        self.value.encode_to(dest);
+    }
+}
+
+impl Decode for ParameterChange {
+    fn decode_from(input: &mut &[u8]) -> Option<Self> {
+        Some(ParameterChange {
+            parameter: Parameter::decode_from(input)?,
+            value: u64::decode_from(input)?,
+        })
+    }
+}
+
+/// A tag byte for the variant, followed by its fields in the order they are declared.
+impl Encode for GovernanceTransaction {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        match self {
+            GovernanceTransaction::Transfer {
+                sender,
+                receiver,
+                amount,
+            } => {
+                dest.push(0);
+                sender.encode_to(dest);
+                receiver.encode_to(dest);
+                amount.encode_to(dest);
+            }
+            GovernanceTransaction::Propose { proposer, change } => {
+                dest.push(1);
+                proposer.encode_to(dest);
+                change.encode_to(dest);
+            }
+            GovernanceTransaction::Vote {
+                voter,
+                referendum,
+                aye,
+            } => {
+                dest.push(2);
+                voter.encode_to(dest);
+                referendum.encode_to(dest);
+                aye.encode_to(dest);
+            }
+            GovernanceTransaction::Close { referendum } => {
+                dest.push(3);
+                referendum.encode_to(dest);
+            }
+            GovernanceTransaction::Enact { referendum } => {
+                dest.push(4);
+                referendum.encode_to(dest);
+            }
+        }
+    }
+}
+
+impl Decode for GovernanceTransaction {
+    fn decode_from(input: &mut &[u8]) -> Option<Self> {
+        match u8::decode_from(input)? {
+            0 => Some(GovernanceTransaction::Transfer {
+                sender: User::decode_from(input)?,
+                receiver: User::decode_from(input)?,
+                amount: u64::decode_from(input)?,
+            }),
+            1 => Some(GovernanceTransaction::Propose {
+                proposer: User::decode_from(input)?,
+                change: ParameterChange::decode_from(input)?,
+            }),
+            2 => Some(GovernanceTransaction::Vote {
+                voter: User::decode_from(input)?,
+                referendum: ReferendumId::decode_from(input)?,
+                aye: bool::decode_from(input)?,
+            }),
+            3 => Some(GovernanceTransaction::Close {
+                referendum: ReferendumId::decode_from(input)?,
+            }),
+            4 => Some(GovernanceTransaction::Enact {
+                referendum: ReferendumId::decode_from(input)?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// The things that happen in the governance system
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum GovernanceEvent {
+    Proposed {
+        referendum: ReferendumId,
+        change: ParameterChange,
+    },
+    Voted {
+        referendum: ReferendumId,
+        voter: User,
+        aye: bool,
+    },
+    Closed {
+        referendum: ReferendumId,
+        passed: bool,
+    },
+    /// A parameter took on a new value. Clients watch for this to reconfigure themselves.
+    ParameterChanged(ParameterChange),
+}
+
+/// The reasons a transaction may be rejected by the governance system
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GovernanceError {
+    /// The sender does not hold enough tokens
+    InsufficientBalance { available: u64, requested: u64 },
+    /// No referendum with this id exists
+    UnknownReferendum(ReferendumId),
+    /// The voter held no tokens when the referendum was proposed
+    NoVotingPower(User),
+    /// The referendum's deadline has passed, so it accepts no more votes
+    VotingClosed,
+    /// The referendum is still accepting votes until the given height
+    StillVoting { deadline: u64 },
+    /// The referendum is not in the status this transaction requires
+    WrongStatus(ReferendumStatus),
+}
+
+impl StateMachine for Governance {
+    type State = GovernanceState;
+    type Transition = GovernanceTransaction;
+    type Error = GovernanceError;
+    /// The initial token balances, followed by the initial parameter values
+    type GenesisConfig = (Vec<(User, u64)>, Vec<ParameterChange>);
+
+    fn genesis_state((endowments, parameters): Self::GenesisConfig) -> GovernanceState {
+        let mut state = GovernanceState::default();
+        for (who, amount) in endowments {
+            if amount > 0 {
+                let balance = state.balances.entry(who).or_insert(0);
+                *balance = balance.saturating_add(amount);
+            }
+        }
+        for change in parameters {
+            state.parameters.insert(change.parameter, change.value);
+        }
+        state
+    }
+
+    fn next_state(starting_state: &GovernanceState, t: &GovernanceTransaction) -> GovernanceState {
+        Self::next_state_in_context(starting_state, t, &BlockContext::default())
+    }
+
+    fn try_next_state(
+        starting_state: &GovernanceState,
+        t: &GovernanceTransaction,
+    ) -> Result<GovernanceState, GovernanceError> {
+        Self::try_next_state_in_context(starting_state, t, &BlockContext::default())
+    }
+
+    fn human_name() -> String {
+        "Governance".into()
+    }
+}
+
+/// Deadlines are measured from the height of the block that proposes, and votes and closes
+/// are checked against the current height.
+impl ContextualStateMachine for Governance {
+    fn next_state_in_context(
+        starting_state: &GovernanceState,
+        t: &GovernanceTransaction,
+        context: &BlockContext,
+    ) -> GovernanceState {
+        Self::try_next_state_in_context(starting_state, t, context)
+            .unwrap_or_else(|_| starting_state.clone())
+    }
+
+    fn try_next_state_in_context(
+        starting_state: &GovernanceState,
+        t: &GovernanceTransaction,
+        context: &BlockContext,
+    ) -> Result<GovernanceState, GovernanceError> {
+        execute(starting_state, t, context.height).map(|(s, _)| s)
+    }
+}
+
+impl EventfulStateMachine for Governance {
+    type Event = GovernanceEvent;
+
+    fn next_state_with_events(
+        starting_state: &GovernanceState,
+        t: &GovernanceTransaction,
+    ) -> (GovernanceState, Vec<GovernanceEvent>) {
+        Self::next_state_with_events_in_context(starting_state, t, &BlockContext::default())
+    }
+
+    fn next_state_with_events_in_context(
+        starting_state: &GovernanceState,
+        t: &GovernanceTransaction,
+        context: &BlockContext,
+    ) -> (GovernanceState, Vec<GovernanceEvent>) {
+        match execute(starting_state, t, context.height) {
+            Ok((s, event)) => (s, event.into_iter().collect()),
+            Err(_) => (starting_state.clone(), vec![]),
+        }
+    }
+}
+
+/// Proposing copies every balance, so it costs more than anything else.
+impl Weighted for Governance {
+    fn weight(t: &GovernanceTransaction) -> u64 {
+        match t {
+            GovernanceTransaction::Propose { .. } => 10,
+            _ => 1,
+        }
+    }
+}
+
+/// Execute the given transaction at the given height, returning the resulting state along with
+/// the event it emitted, if any.
+fn execute(
+    starting_state: &GovernanceState,
+    t: &GovernanceTransaction,
+    height: u64,
+) -> Result<(GovernanceState, Option<GovernanceEvent>), GovernanceError> {
+    let mut s = starting_state.clone();
+    let event = match t {
+        GovernanceTransaction::Transfer {
+            sender,
+            receiver,
+            amount,
+        } => {
+            let available = s.balances.get(sender).copied().unwrap_or(0);
+            if available < *amount {
+                return Err(GovernanceError::InsufficientBalance {
+                    available,
+                    requested: *amount,
+                });
+            }
+            if available == *amount {
+                s.balances.remove(sender);
+            } else {
+                s.balances.insert(*sender, available - amount);
+            }
+            if *amount > 0 {
+                let balance = s.balances.entry(*receiver).or_insert(0);
+                *balance = balance.saturating_add(*amount);
+            }
+            None
+        }
+        GovernanceTransaction::Propose { proposer, change } => {
+            s.referenda.push(Referendum {
+                proposer: *proposer,
+                change: *change,
+                snapshot: s.balances.clone(),
+                votes: HashMap::new(),
+                deadline: height.saturating_add(VOTING_PERIOD),
+                status: ReferendumStatus::Voting,
+            });
+            Some(GovernanceEvent::Proposed {
+                referendum: s.referenda.len() - 1,
+                change: *change,
+            })
+        }
+        GovernanceTransaction::Vote {
+            voter,
+            referendum,
+            aye,
+        } => {
+            let r = open_referendum(&mut s, *referendum, ReferendumStatus::Voting)?;
+            if height >= r.deadline {
+                return Err(GovernanceError::VotingClosed);
+            }
+            if !r.snapshot.contains_key(voter) {
+                return Err(GovernanceError::NoVotingPower(*voter));
+            }
+            r.votes.insert(*voter, *aye);
+            Some(GovernanceEvent::Voted {
+                referendum: *referendum,
+                voter: *voter,
+                aye: *aye,
+            })
+        }
+        GovernanceTransaction::Close { referendum } => {
+            let r = open_referendum(&mut s, *referendum, ReferendumStatus::Voting)?;
+            if height < r.deadline {
+                return Err(GovernanceError::StillVoting {
+                    deadline: r.deadline,
+                });
+            }
+            let (ayes, nays) = r.tally();
+            let passed = ayes > nays;
+            r.status = if passed {
+                ReferendumStatus::Passed
+            } else {
+                ReferendumStatus::Rejected
+            };
+            Some(GovernanceEvent::Closed {
+                referendum: *referendum,
+                passed,
+            })
+        }
+        GovernanceTransaction::Enact { referendum } => {
+            let r = open_referendum(&mut s, *referendum, ReferendumStatus::Passed)?;
+            r.status = ReferendumStatus::Enacted;
+            let change = r.change;
+            s.parameters.insert(change.parameter, change.value);
+            Some(GovernanceEvent::ParameterChanged(change))
+        }
+    };
+    Ok((s, event))
+}
+
+/// The given referendum, provided it has the given status.
+fn open_referendum(
+    state: &mut GovernanceState,
+    id: ReferendumId,
+    status: ReferendumStatus,
+) -> Result<&mut Referendum, GovernanceError> {
+    let r = state
+        .referenda
+        .get_mut(id)
+        .ok_or(GovernanceError::UnknownReferendum(id))?;
+    if r.status != status {
+        return Err(GovernanceError::WrongStatus(r.status));
+    }
+    Ok(r)
+}
+
+#[cfg(test)]
+fn at_height(height: u64) -> BlockContext {
+    BlockContext {
+        height,
+        ..BlockContext::default()
+    }
+}
+
+#[cfg(test)]
+const EASIER_POW: ParameterChange = ParameterChange {
+    parameter: Parameter::PowThreshold,
+    value: u64::MAX / 2,
+};
+
+#[cfg(test)]
+fn holders() -> GovernanceState {
+    Governance::genesis_state((
+        vec![(User::Alice, 60), (User::Bob, 30), (User::Charlie, 20)],
+        vec![ParameterChange {
+            parameter: Parameter::PowThreshold,
+            value: u64::MAX / 4,
+        }],
+    ))
+}
+
+#[cfg(test)]
+fn vote(voter: User, aye: bool) -> GovernanceTransaction {
+    GovernanceTransaction::Vote {
+        voter,
+        referendum: 0,
+        aye,
+    }
+}
+
+#[cfg(test)]
+fn propose() -> GovernanceTransaction {
+    GovernanceTransaction::Propose {
+        proposer: User::Bob,
+        change: EASIER_POW,
+    }
+}
+
+#[test]
+fn sm_9_referendum_passes_and_is_enacted() {
+    let voted = Governance::try_apply_all_in_context(
+        &holders(),
+        &[propose(), vote(User::Alice, true), vote(User::Bob, false)],
+        &at_height(5),
+    )
+    .unwrap();
+    assert_eq!(voted.referenda[0].tally(), (60, 30));
+
+    let close = GovernanceTransaction::Close { referendum: 0 };
+    let closed = Governance::try_next_state_in_context(&voted, &close, &at_height(15)).unwrap();
+    assert_eq!(closed.referenda[0].status, ReferendumStatus::Passed);
+
+    let (enacted, events) = Governance::next_state_with_events_in_context(
+        &closed,
+        &GovernanceTransaction::Enact { referendum: 0 },
+        &at_height(15),
+    );
+    assert_eq!(events, vec![GovernanceEvent::ParameterChanged(EASIER_POW)]);
+    assert_eq!(
+        enacted.parameters.get(&Parameter::PowThreshold),
+        Some(&EASIER_POW.value)
+    );
+    assert_eq!(enacted.referenda[0].status, ReferendumStatus::Enacted);
+}
+
+#[test]
+fn sm_9_ties_are_rejected() {
+    let ts = [
+        propose(),
+        vote(User::Bob, true),
+        vote(User::Charlie, true),
+        vote(User::Alice, false),
+        GovernanceTransaction::Close { referendum: 0 },
+    ];
+    let end = Governance::try_apply_all_in_context(&holders(), &ts[..4], &at_height(0))
+        .and_then(|s| Governance::try_apply_all_in_context(&s, &ts[4..], &at_height(10)))
+        .unwrap();
+
+    assert_eq!(end.referenda[0].status, ReferendumStatus::Rejected);
+    assert_eq!(
+        Governance::try_next_state(&end, &GovernanceTransaction::Enact { referendum: 0 }),
+        Err(GovernanceError::WrongStatus(ReferendumStatus::Rejected))
+    );
+}
+
+#[test]
+fn sm_9_votes_use_balance_snapshot() {
+    let ts = [
+        propose(),
+        GovernanceTransaction::Transfer {
+            sender: User::Alice,
+            receiver: User::Bob,
+            amount: 60,
+        },
+        vote(User::Alice, false),
+        vote(User::Bob, true),
+    ];
+    let end = Governance::try_apply_all(&holders(), &ts).unwrap();
+
+    // Alice still votes with the 60 tokens she held at proposal time, and Bob cannot use them.
+    assert_eq!(end.referenda[0].tally(), (30, 60));
+}
+
+#[test]
+fn sm_9_only_holders_at_proposal_time_vote() {
+    let start = Governance::genesis_state((vec![(User::Alice, 1)], vec![]));
+    let proposed = Governance::next_state(&start, &propose());
+
+    assert_eq!(
+        Governance::try_next_state(&proposed, &vote(User::Bob, true)),
+        Err(GovernanceError::NoVotingPower(User::Bob))
+    );
+}
+
+#[test]
+fn sm_9_voting_respects_deadline() {
+    let proposed =
+        Governance::try_next_state_in_context(&holders(), &propose(), &at_height(3)).unwrap();
+    let close = GovernanceTransaction::Close { referendum: 0 };
+
+    assert_eq!(
+        Governance::try_next_state_in_context(&proposed, &close, &at_height(12)),
+        Err(GovernanceError::StillVoting { deadline: 13 })
+    );
+    assert_eq!(
+        Governance::try_next_state_in_context(&proposed, &vote(User::Alice, true), &at_height(13)),
+        Err(GovernanceError::VotingClosed)
+    );
+    assert_eq!(
+        Governance::try_next_state(&proposed, &GovernanceTransaction::Close { referendum: 1 }),
+        Err(GovernanceError::UnknownReferendum(1))
+    );
+}
@@ -0,0 +1,298 @@
+//! Real blockchains rarely run a single state machine. They run a currency next to governance
+//! next to staking and so on. Rather than writing a bespoke combined machine every time, we can
+//! compose existing machines.
+//!
+//! Here we write the simplest such composition: a pair of machines that run side by side. Each
+//! transition is routed to exactly one of them, and the other is left untouched.
+
+use std::marker::PhantomData;
+
+use crate::codec::{Decode, Encode};
+
+use super::{
+    BlockContext, ContextualStateMachine, DiffStateMachine, EventfulStateMachine,
+    InvertibleStateMachine, StateMachine, Weighted,
+};
+
+/// A state machine made of two independent state machines. Its state is a tuple of both states.
+pub struct Pair<A, B>(PhantomData<(A, B)>);
+
+/// A value that belongs to either the left or the right machine of a `Pair`. Used to route
+/// transitions, and to report which machine rejected a transition or produced a diff.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Either<L, R> {
+    Left(L),
+    Right(R),
+}
+
+/// A 0 byte for the left side or a 1 byte for the right, followed by the value.
+impl<L: Encode, R: Encode> Encode for Either<L, R> {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        match self {
+            Either::Left(l) => {
+                dest.push(0);
+                l.encode_to(dest);
+            }
+            Either::Right(r) => {
+                dest.push(1);
+                r.encode_to(dest);
+            }
+        }
+    }
+}
+
+impl<L: Decode, R: Decode> Decode for Either<L, R> {
+    fn decode_from(input: &mut &[u8]) -> Option<Self> {
+        match u8::decode_from(input)? {
+            0 => Some(Either::Left(L::decode_from(input)?)),
+            1 => Some(Either::Right(R::decode_from(input)?)),
+            _ => None,
+        }
+    }
+}
+
+impl<A, B> StateMachine for Pair<A, B>
+where
+    A: StateMachine<State: Clone>,
+    B: StateMachine<State: Clone>,
+{
+    type State = (A::State, B::State);
+    type Transition = Either<A::Transition, B::Transition>;
+    type Error = Either<A::Error, B::Error>;
+    type GenesisConfig = (A::GenesisConfig, B::GenesisConfig);
+
+    fn genesis_state((a, b): Self::GenesisConfig) -> Self::State {
+        (A::genesis_state(a), B::genesis_state(b))
+    }
+
+    fn next_state((a, b): &Self::State, t: &Self::Transition) -> Self::State {
+        match t {
+            Either::Left(t) => (A::next_state(a, t), b.clone()),
+            Either::Right(t) => (a.clone(), B::next_state(b, t)),
+        }
+    }
+
+    fn try_next_state(
+        (a, b): &Self::State,
+        t: &Self::Transition,
+    ) -> Result<Self::State, Self::Error> {
+        match t {
+            Either::Left(t) => Ok((A::try_next_state(a, t).map_err(Either::Left)?, b.clone())),
+            Either::Right(t) => Ok((a.clone(), B::try_next_state(b, t).map_err(Either::Right)?)),
+        }
+    }
+
+    fn validate_transition((a, b): &Self::State, t: &Self::Transition) -> bool {
+        match t {
+            Either::Left(t) => A::validate_transition(a, t),
+            Either::Right(t) => B::validate_transition(b, t),
+        }
+    }
+
+    fn human_name() -> String {
+        format!("{} and {}", A::human_name(), B::human_name())
+    }
+}
+
+impl<A, B> ContextualStateMachine for Pair<A, B>
+where
+    A: ContextualStateMachine<State: Clone>,
+    B: ContextualStateMachine<State: Clone>,
+{
+    fn next_state_in_context(
+        (a, b): &Self::State,
+        t: &Self::Transition,
+        context: &BlockContext,
+    ) -> Self::State {
+        match t {
+            Either::Left(t) => (A::next_state_in_context(a, t, context), b.clone()),
+            Either::Right(t) => (a.clone(), B::next_state_in_context(b, t, context)),
+        }
+    }
+
+    fn try_next_state_in_context(
+        (a, b): &Self::State,
+        t: &Self::Transition,
+        context: &BlockContext,
+    ) -> Result<Self::State, Self::Error> {
+        match t {
+            Either::Left(t) => {
+                let a = A::try_next_state_in_context(a, t, context).map_err(Either::Left)?;
+                Ok((a, b.clone()))
+            }
+            Either::Right(t) => {
+                let b = B::try_next_state_in_context(b, t, context).map_err(Either::Right)?;
+                Ok((a.clone(), b))
+            }
+        }
+    }
+}
+
+impl<A, B> DiffStateMachine for Pair<A, B>
+where
+    A: DiffStateMachine<State: Clone>,
+    B: DiffStateMachine<State: Clone>,
+{
+    type StateDiff = Either<A::StateDiff, B::StateDiff>;
+
+    fn state_diff(
+        (a, b): &Self::State,
+        t: &Self::Transition,
+    ) -> Result<Self::StateDiff, Self::Error> {
+        match t {
+            Either::Left(t) => A::state_diff(a, t).map(Either::Left).map_err(Either::Left),
+            Either::Right(t) => B::state_diff(b, t).map(Either::Right).map_err(Either::Right),
+        }
+    }
+
+    fn apply_diff((a, b): &mut Self::State, diff: &Self::StateDiff) {
+        match diff {
+            Either::Left(d) => A::apply_diff(a, d),
+            Either::Right(d) => B::apply_diff(b, d),
+        }
+    }
+}
+
+impl<A, B> InvertibleStateMachine for Pair<A, B>
+where
+    A: InvertibleStateMachine<State: Clone>,
+    B: InvertibleStateMachine<State: Clone>,
+{
+    fn undo((a, b): &Self::State, t: &Self::Transition) -> Self::State {
+        match t {
+            Either::Left(t) => (A::undo(a, t), b.clone()),
+            Either::Right(t) => (a.clone(), B::undo(b, t)),
+        }
+    }
+}
+
+impl<A, B> EventfulStateMachine for Pair<A, B>
+where
+    A: EventfulStateMachine<State: Clone>,
+    B: EventfulStateMachine<State: Clone>,
+{
+    type Event = Either<A::Event, B::Event>;
+
+    fn next_state_with_events(
+        (a, b): &Self::State,
+        t: &Self::Transition,
+    ) -> (Self::State, Vec<Self::Event>) {
+        match t {
+            Either::Left(t) => {
+                let (a, events) = A::next_state_with_events(a, t);
+                ((a, b.clone()), events.into_iter().map(Either::Left).collect())
+            }
+            Either::Right(t) => {
+                let (b, events) = B::next_state_with_events(b, t);
+                ((a.clone(), b), events.into_iter().map(Either::Right).collect())
+            }
+        }
+    }
+
+    fn next_state_with_events_in_context(
+        (a, b): &Self::State,
+        t: &Self::Transition,
+        context: &BlockContext,
+    ) -> (Self::State, Vec<Self::Event>) {
+        match t {
+            Either::Left(t) => {
+                let (a, events) = A::next_state_with_events_in_context(a, t, context);
+                ((a, b.clone()), events.into_iter().map(Either::Left).collect())
+            }
+            Either::Right(t) => {
+                let (b, events) = B::next_state_with_events_in_context(b, t, context);
+                ((a.clone(), b), events.into_iter().map(Either::Right).collect())
+            }
+        }
+    }
+}
+
+impl<A, B> Weighted for Pair<A, B>
+where
+    A: Weighted<State: Clone>,
+    B: Weighted<State: Clone>,
+{
+    fn weight(t: &Self::Transition) -> u64 {
+        match t {
+            Either::Left(t) => A::weight(t),
+            Either::Right(t) => B::weight(t),
+        }
+    }
+}
+
+#[cfg(test)]
+use super::{
+    p1_switches::LightSwitch,
+    p4_accounted_currency::{AccountedCurrency, AccountingTransaction, CurrencyError},
+    User,
+};
+#[cfg(test)]
+use std::collections::HashMap;
+
+#[cfg(test)]
+type CurrencyAndSwitch = Pair<AccountedCurrency, LightSwitch>;
+
+#[test]
+fn pair_routes_transitions_to_one_side() {
+    let start = CurrencyAndSwitch::genesis_state((vec![(User::Alice, 10)], ()));
+    let mint = Either::Left(AccountingTransaction::Mint {
+        minter: User::Bob,
+        amount: 5,
+    });
+
+    let end = CurrencyAndSwitch::apply_all(&start, &[mint, Either::Right(())]);
+
+    assert_eq!(
+        end,
+        (HashMap::from([(User::Alice, 10), (User::Bob, 5)]), true)
+    );
+}
+
+#[test]
+fn pair_reports_which_side_rejected() {
+    let start = (HashMap::new(), false);
+    let t = Either::Left(AccountingTransaction::Transfer {
+        sender: User::Alice,
+        receiver: User::Bob,
+        amount: 5,
+    });
+
+    assert_eq!(
+        CurrencyAndSwitch::try_next_state(&start, &t),
+        Err(Either::Left(CurrencyError::UnknownAccount(User::Alice)))
+    );
+}
+
+#[test]
+fn pair_undo_and_weight_delegate() {
+    let start = (HashMap::from([(User::Alice, 10)]), false);
+    let ts = vec![
+        Either::Left(AccountingTransaction::Transfer {
+            sender: User::Alice,
+            receiver: User::Bob,
+            amount: 4,
+        }),
+        Either::Right(()),
+    ];
+
+    let end = CurrencyAndSwitch::apply_all(&start, &ts);
+
+    assert_eq!(CurrencyAndSwitch::undo_all(&end, &ts), start);
+    assert_eq!(CurrencyAndSwitch::total_weight(&ts), 3);
+}
+
+#[test]
+fn pair_transitions_round_trip_through_the_codec() {
+    let ts: Vec<Either<u64, Either<bool, u8>>> = vec![
+        Either::Left(7),
+        Either::Right(Either::Left(true)),
+        Either::Right(Either::Right(3)),
+    ];
+
+    let encoded = ts.encode();
+
+    assert_eq!(&encoded[8..10], &[0, 7]);
+    assert_eq!(Vec::decode(&encoded), Some(ts));
+    assert_eq!(Either::<u8, u8>::decode(&[2, 0]), None);
+}
@@ -0,0 +1,185 @@
+//! Executing a block's transitions one at a time leaves every core but one idle. Yet most
+//! transitions in a typical block have nothing to do with each other: Alice paying Bob does not
+//! affect Charlie paying Dave. If we know which parts of the state each transition touches, we
+//! can safely execute the independent ones at the same time.
+//!
+//! Here each transition declares the keys it reads and writes. The executor splits a batch into
+//! waves of transitions that do not conflict with each other, calculates the diffs of each wave
+//! in parallel, and then applies them in order. When a transition conflicts with one already in
+//! the current wave, the wave is closed and a new one begins, so conflicting transitions still
+//! execute sequentially, in their original order, exactly as `try_apply_all` would.
+//!
+//! The diffs are calculated with rayon when the `parallel` feature is enabled, and sequentially
+//! otherwise.
+//!
+//! The executor ignores the block context, so it only suits machines that do too. Those execute
+//! their blocks through it by running `try_apply_all_in_context` here, as the accounted currency
+//! does, and then building, importing and verifying blocks all use it. `Block::execute_parallel`
+//! runs a single block's body through it directly.
+
+use std::collections::HashSet;
+use std::hash::Hash;
+
+use super::DiffStateMachine;
+
+/// The parts of the state a transition reads and writes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Access<K> {
+    pub reads: Vec<K>,
+    pub writes: Vec<K>,
+}
+
+/// A state machine whose transitions declare the parts of the state they touch, so that
+/// independent transitions can be executed in parallel.
+pub trait ParallelStateMachine:
+    DiffStateMachine<State: Clone + Sync, Transition: Sync, StateDiff: Send, Error: Send>
+{
+    /// Identifies a part of the state, such as an account
+    type Key: Eq + Hash + Clone;
+
+    /// The keys the given transition reads and writes. It must not depend on the state, and
+    /// must include every key the transition could possibly touch. A key that is written need
+    /// not also be listed as read.
+    fn access(t: &Self::Transition) -> Access<Self::Key>;
+}
+
+/// Split the transitions into consecutive waves, none of which contain two conflicting
+/// transitions. Two transitions conflict when one writes a key the other reads or writes.
+pub fn schedule<SM: ParallelStateMachine>(ts: &[SM::Transition]) -> Vec<&[SM::Transition]> {
+    let mut waves = Vec::new();
+    let mut start = 0;
+    let mut reads = HashSet::new();
+    let mut writes = HashSet::new();
+
+    for (i, t) in ts.iter().enumerate() {
+        let access = SM::access(t);
+        let conflicts = access.writes.iter().any(|k| reads.contains(k) || writes.contains(k))
+            || access.reads.iter().any(|k| writes.contains(k));
+        if conflicts {
+            waves.push(&ts[start..i]);
+            start = i;
+            reads.clear();
+            writes.clear();
+        }
+        reads.extend(access.reads);
+        writes.extend(access.writes);
+    }
+    if start < ts.len() {
+        waves.push(&ts[start..]);
+    }
+    waves
+}
+
+/// Calculate the diffs of one wave of independent transitions against the same state.
+#[cfg(feature = "parallel")]
+fn wave_diffs<SM: ParallelStateMachine>(
+    state: &SM::State,
+    wave: &[SM::Transition],
+) -> Vec<Result<SM::StateDiff, SM::Error>> {
+    use rayon::prelude::*;
+    wave.par_iter().map(|t| SM::state_diff(state, t)).collect()
+}
+
+#[cfg(not(feature = "parallel"))]
+fn wave_diffs<SM: ParallelStateMachine>(
+    state: &SM::State,
+    wave: &[SM::Transition],
+) -> Vec<Result<SM::StateDiff, SM::Error>> {
+    wave.iter().map(|t| SM::state_diff(state, t)).collect()
+}
+
+/// Apply each of the given transitions, executing independent transitions in parallel. The
+/// result is the same as that of `try_apply_all`, including which error is reported when
+/// several transitions would be rejected.
+pub fn try_apply_all_parallel<SM: ParallelStateMachine>(
+    starting_state: &SM::State,
+    ts: &[SM::Transition],
+) -> Result<SM::State, SM::Error> {
+    let mut state = starting_state.clone();
+    for wave in schedule::<SM>(ts) {
+        for diff in wave_diffs::<SM>(&state, wave) {
+            SM::apply_diff(&mut state, &diff?);
+        }
+    }
+    Ok(state)
+}
+
+#[cfg(test)]
+use super::{
+    p4_accounted_currency::{AccountedCurrency, AccountingTransaction, CurrencyError},
+    StateMachine, User,
+};
+#[cfg(test)]
+use std::collections::HashMap;
+
+#[cfg(test)]
+fn transfer(sender: User, receiver: User, amount: u64) -> AccountingTransaction {
+    AccountingTransaction::Transfer {
+        sender,
+        receiver,
+        amount,
+    }
+}
+
+#[test]
+fn par_independent_transitions_share_a_wave() {
+    let ts = [
+        AccountingTransaction::Mint { minter: User::Alice, amount: 1 },
+        AccountingTransaction::Burn { burner: User::Bob, amount: 1 },
+        transfer(User::Charlie, User::Alice, 1),
+        transfer(User::Bob, User::Charlie, 1),
+    ];
+
+    let waves: Vec<usize> = schedule::<AccountedCurrency>(&ts).iter().map(|w| w.len()).collect();
+
+    assert_eq!(waves, vec![2, 1, 1]);
+}
+
+#[test]
+fn par_matches_sequential_execution() {
+    let start = HashMap::from([(User::Alice, 100), (User::Bob, 50)]);
+    let ts = [
+        transfer(User::Alice, User::Charlie, 10),
+        AccountingTransaction::Mint { minter: User::Bob, amount: 5 },
+        transfer(User::Charlie, User::Bob, 10),
+        AccountingTransaction::Burn { burner: User::Alice, amount: 90 },
+        transfer(User::Bob, User::Alice, 65),
+    ];
+
+    assert_eq!(
+        try_apply_all_parallel::<AccountedCurrency>(&start, &ts),
+        AccountedCurrency::try_apply_all(&start, &ts)
+    );
+}
+
+#[test]
+fn par_rejects_overflowing_balances_like_sequential_execution() {
+    let start = HashMap::from([(User::Alice, 1)]);
+    let ts = [
+        AccountingTransaction::Mint { minter: User::Alice, amount: u64::MAX },
+        transfer(User::Bob, User::Alice, 1),
+    ];
+
+    assert_eq!(
+        try_apply_all_parallel::<AccountedCurrency>(&start, &ts),
+        Err(CurrencyError::BalanceOverflow(User::Alice))
+    );
+    assert_eq!(
+        try_apply_all_parallel::<AccountedCurrency>(&start, &ts),
+        AccountedCurrency::try_apply_all(&start, &ts)
+    );
+}
+
+#[test]
+fn par_reports_first_rejection() {
+    let start = HashMap::from([(User::Alice, 10)]);
+    let ts = [
+        transfer(User::Bob, User::Charlie, 1),
+        transfer(User::Alice, User::Alice, 100),
+    ];
+
+    assert_eq!(
+        try_apply_all_parallel::<AccountedCurrency>(&start, &ts),
+        Err(CurrencyError::UnknownAccount(User::Bob))
+    );
+}
@@ -0,0 +1,86 @@
+//! Lotteries, prediction markets, and games all need random numbers. A state machine cannot just
+//! call into an operating system RNG, though. Every node executes every block, and all of them
+//! must reach exactly the same state, so any randomness must be derived from data that every node
+//! agrees on.
+//!
+//! Here the randomness for a block is seeded from the hash of its parent and its height, as its
+//! header records them. Machines see them through the block context, which importers check
+//! against the header, so the block's own author has no say in the seed. It is reproducible,
+//! and nobody knows it before the parent block exists. It is not unbiasable: the author of the
+//! parent could try several blocks and publish the one whose hash suits them. That is acceptable
+//! for play money, but real chains use schemes like VRFs or commit-reveal.
+
+use super::BlockContext;
+use crate::hash;
+
+/// A deterministic source of pseudorandom numbers that contextual state machines can draw from.
+pub trait Randomness {
+    /// The seed all of this source's draws are derived from.
+    fn random_seed(&self) -> u64;
+
+    /// A pseudorandom number for the given subject. Machines should use a different subject
+    /// for each independent draw in the same block, for example the lottery id, since drawing
+    /// twice for the same subject gives the same number.
+    fn random<S: core::hash::Hash>(&self, subject: &S) -> u64 {
+        hash(&(self.random_seed(), subject))
+    }
+
+    /// A pseudorandom number below the given bound, which must not be 0. The result is very
+    /// slightly biased towards small numbers unless the bound is a power of two.
+    fn random_below<S: core::hash::Hash>(&self, subject: &S, bound: u64) -> u64 {
+        self.random(subject) % bound
+    }
+}
+
+/// The seed of the block with the given parent and height.
+pub fn block_seed(parent_hash: u64, height: u64) -> u64 {
+    hash(&(parent_hash, height))
+}
+
+/// Each block draws from its parent's hash and its own height.
+impl Randomness for BlockContext {
+    fn random_seed(&self) -> u64 {
+        block_seed(self.parent_hash, self.height)
+    }
+}
+
+#[cfg(test)]
+fn context(parent_hash: u64, height: u64) -> BlockContext {
+    BlockContext {
+        height,
+        parent_hash,
+        ..BlockContext::default()
+    }
+}
+
+#[test]
+fn randomness_is_reproducible() {
+    assert_eq!(context(7, 3).random(&"lottery"), context(7, 3).random(&"lottery"));
+}
+
+#[test]
+fn randomness_depends_on_parent_height_and_subject() {
+    let draw = context(7, 3).random(&"lottery");
+
+    assert_ne!(draw, context(8, 3).random(&"lottery"));
+    assert_ne!(draw, context(7, 4).random(&"lottery"));
+    assert_ne!(draw, context(7, 3).random(&"raffle"));
+}
+
+#[test]
+fn randomness_ignores_author_and_timestamp() {
+    let other = BlockContext {
+        timestamp: 6_000,
+        author: Some(super::User::Bob),
+        ..context(7, 3)
+    };
+
+    assert_eq!(other.random(&"lottery"), context(7, 3).random(&"lottery"));
+}
+
+#[test]
+fn randomness_below_bound() {
+    for height in 0..100 {
+        assert!(context(7, height).random_below(&"die", 6) < 6);
+    }
+}
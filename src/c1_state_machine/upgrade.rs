@@ -0,0 +1,181 @@
+//! Blockchains are expected to run forever, but their rules are not. Sooner or later a chain
+//! needs to change its state machine: to fix a bug, add a feature, or change the state layout.
+//! Real chains do this with runtime upgrades. Everyone agrees on a fork height. Blocks below
+//! it execute under the old rules, and blocks from it onwards execute under the new rules.
+//!
+//! Switching rules is not enough on its own, because the new machine may store its state
+//! differently. The existing state has to be migrated into the new layout exactly once, at the
+//! boundary. This is the state machine counterpart of `c3_consensus::p6_forking`.
+//!
+//! Nodes need no special support to follow a chain through an upgrade. The fork height is part
+//! of the machine, so every node migrates at the same block, including when it re-executes
+//! blocks after a reorg.
+
+use std::marker::PhantomData;
+
+use super::{pair::Either, BlockContext, ContextualStateMachine, StateMachine};
+
+/// A one-off migration from the state of machine `From` to the state of machine `To`, along
+/// with the height at which it happens.
+pub trait Migrate<From: StateMachine, To: StateMachine> {
+    /// The first block height executed under `To`'s rules.
+    const FORK_HEIGHT: u64;
+
+    /// Convert the final state under the old rules into the first state under the new rules.
+    fn migrate(state: &From::State) -> To::State;
+}
+
+/// A higher-order state machine that runs `From` until `M::FORK_HEIGHT`, migrates the state with
+/// `M`, and runs `To` from then on.
+pub struct Upgrade<From, To, M>(PhantomData<(From, To, M)>);
+
+/// The state of an upgradeable machine. It is always exactly one version or the other.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Versioned<Old, New> {
+    Old(Old),
+    New(New),
+}
+
+/// The reasons a transition may be rejected by an upgradeable machine.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UpgradeError<OldError, NewError> {
+    /// The transition was written for the other version of the machine.
+    WrongVersion,
+    Old(OldError),
+    New(NewError),
+}
+
+impl<From, To, M> Upgrade<From, To, M>
+where
+    From: StateMachine,
+    To: StateMachine<State: Clone>,
+    M: Migrate<From, To>,
+{
+    /// The state a block with the given context starts executing from. The state is migrated
+    /// by the first block at or above the fork height, even if that block is empty.
+    fn upgraded(
+        state: &Versioned<From::State, To::State>,
+        context: &BlockContext,
+    ) -> Versioned<From::State, To::State>
+    where
+        From::State: Clone,
+    {
+        match state {
+            Versioned::Old(old) if context.height >= M::FORK_HEIGHT => {
+                Versioned::New(M::migrate(old))
+            }
+            s => s.clone(),
+        }
+    }
+}
+
+impl<From, To, M> StateMachine for Upgrade<From, To, M>
+where
+    From: StateMachine<State: Clone>,
+    To: StateMachine<State: Clone>,
+    M: Migrate<From, To>,
+{
+    type State = Versioned<From::State, To::State>;
+    type Transition = Either<From::Transition, To::Transition>;
+    type Error = UpgradeError<From::Error, To::Error>;
+    type GenesisConfig = From::GenesisConfig;
+
+    /// Chains launch under the old rules. Genesis is never migrated, even if the fork height
+    /// is 0; the migration happens when block 0's body, if any, executes.
+    fn genesis_state(config: From::GenesisConfig) -> Self::State {
+        Versioned::Old(From::genesis_state(config))
+    }
+
+    /// Without a block context there is no height to compare against the fork, so the state
+    /// stays in whatever version it is. A transition for the other version is a no-op.
+    fn next_state(starting_state: &Self::State, t: &Self::Transition) -> Self::State {
+        match (starting_state, t) {
+            (Versioned::Old(s), Either::Left(t)) => Versioned::Old(From::next_state(s, t)),
+            (Versioned::New(s), Either::Right(t)) => Versioned::New(To::next_state(s, t)),
+            (s, _) => s.clone(),
+        }
+    }
+
+    fn try_next_state(
+        starting_state: &Self::State,
+        t: &Self::Transition,
+    ) -> Result<Self::State, Self::Error> {
+        match (starting_state, t) {
+            (Versioned::Old(s), Either::Left(t)) => From::try_next_state(s, t)
+                .map(Versioned::Old)
+                .map_err(UpgradeError::Old),
+            (Versioned::New(s), Either::Right(t)) => To::try_next_state(s, t)
+                .map(Versioned::New)
+                .map_err(UpgradeError::New),
+            _ => Err(UpgradeError::WrongVersion),
+        }
+    }
+
+    fn human_name() -> String {
+        format!("{} upgraded to {}", From::human_name(), To::human_name())
+    }
+}
+
+impl<From, To, M> ContextualStateMachine for Upgrade<From, To, M>
+where
+    From: ContextualStateMachine<State: Clone>,
+    To: ContextualStateMachine<State: Clone>,
+    M: Migrate<From, To>,
+{
+    fn next_state_in_context(
+        starting_state: &Self::State,
+        t: &Self::Transition,
+        context: &BlockContext,
+    ) -> Self::State {
+        match (Self::upgraded(starting_state, context), t) {
+            (Versioned::Old(s), Either::Left(t)) => {
+                Versioned::Old(From::next_state_in_context(&s, t, context))
+            }
+            (Versioned::New(s), Either::Right(t)) => {
+                Versioned::New(To::next_state_in_context(&s, t, context))
+            }
+            (s, _) => s,
+        }
+    }
+
+    fn try_next_state_in_context(
+        starting_state: &Self::State,
+        t: &Self::Transition,
+        context: &BlockContext,
+    ) -> Result<Self::State, Self::Error> {
+        match (Self::upgraded(starting_state, context), t) {
+            (Versioned::Old(s), Either::Left(t)) => From::try_next_state_in_context(&s, t, context)
+                .map(Versioned::Old)
+                .map_err(UpgradeError::Old),
+            (Versioned::New(s), Either::Right(t)) => To::try_next_state_in_context(&s, t, context)
+                .map(Versioned::New)
+                .map_err(UpgradeError::New),
+            _ => Err(UpgradeError::WrongVersion),
+        }
+    }
+
+    /// Migrate before executing the body, so that an empty block at the fork height still
+    /// upgrades the state.
+    fn apply_all_in_context(
+        starting_state: &Self::State,
+        ts: &[Self::Transition],
+        context: &BlockContext,
+    ) -> Self::State {
+        ts.iter()
+            .fold(Self::upgraded(starting_state, context), |s, t| {
+                Self::next_state_in_context(&s, t, context)
+            })
+    }
+
+    fn try_apply_all_in_context(
+        starting_state: &Self::State,
+        ts: &[Self::Transition],
+        context: &BlockContext,
+    ) -> Result<Self::State, Self::Error> {
+        ts.iter()
+            .try_fold(Self::upgraded(starting_state, context), |s, t| {
+                Self::try_next_state_in_context(&s, t, context)
+            })
+    }
+}
@@ -0,0 +1,281 @@
+//! Without replay protection, anyone who has seen a transaction can submit it again, and it will
+//! happily execute again. Alice's transfer to Bob could be replayed until Alice is broke.
+//!
+//! Here we wrap any state machine so that every transition is signed by an account and carries
+//! that account's next nonce. A transition is only accepted if its nonce is exactly the next one
+//! expected from its signer, so each transaction executes at most once and in the order its
+//! signer intended.
+//!
+//! A nonce only protects the account that signed it, so the signer must be the account the
+//! transition acts for. Otherwise Bob could sign Alice's transfer to him with his own nonce. The
+//! inner transitions say which account that is by implementing `Owned`.
+
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+use super::{
+    BlockContext, ContextualStateMachine, InvertibleStateMachine, StateMachine, User, Weighted,
+};
+
+/// A higher-order state machine adding per-account nonces to an inner state machine.
+pub struct WithNonces<Inner>(PhantomData<Inner>);
+
+/// The state of the inner machine along with the next nonce expected from each account.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NoncedState<S> {
+    pub inner: S,
+    /// Accounts that have never transacted are not stored. Their next nonce is 0.
+    pub nonces: HashMap<User, u64>,
+}
+
+impl<S> NoncedState<S> {
+    /// The nonce the given account must use for its next transition.
+    pub fn expected_nonce(&self, account: &User) -> u64 {
+        self.nonces.get(account).copied().unwrap_or(0)
+    }
+}
+
+/// A transition that acts for an account, such as the sender of a transfer, and so must be
+/// signed by it.
+pub trait Owned {
+    /// The account the transition acts for. A transition acting for nobody in particular may be
+    /// signed by anyone.
+    fn owner(&self) -> Option<User>;
+}
+
+/// An inner transition signed by an account and tagged with that account's nonce.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Nonced<T> {
+    pub signer: User,
+    pub nonce: u64,
+    pub call: T,
+}
+
+/// The reasons a nonced transition may be rejected
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NonceError<E> {
+    /// The nonce has already been used. This transition is most likely a replay.
+    Stale { expected: u64, found: u64 },
+    /// The nonce skips ahead. Some earlier transition from this signer has not executed yet.
+    Future { expected: u64, found: u64 },
+    /// The transition acts for another account than the one that signed it.
+    WrongSigner { signer: User, owner: User },
+    /// The nonce was fine, but the inner machine rejected the transition.
+    Inner(E),
+}
+
+/// Check that the transition acts for its signer, and carries exactly the next nonce expected
+/// from it.
+fn check_nonce<S, T: Owned, E>(
+    state: &NoncedState<S>,
+    t: &Nonced<T>,
+) -> Result<(), NonceError<E>> {
+    if let Some(owner) = t.call.owner().filter(|owner| *owner != t.signer) {
+        return Err(NonceError::WrongSigner {
+            signer: t.signer,
+            owner,
+        });
+    }
+    let expected = state.expected_nonce(&t.signer);
+    match t.nonce {
+        found if found < expected => Err(NonceError::Stale { expected, found }),
+        found if found > expected => Err(NonceError::Future { expected, found }),
+        _ => Ok(()),
+    }
+}
+
+/// The state after the given transition used up its nonce and the inner call produced `inner`.
+fn use_nonce<S, T>(starting_state: &NoncedState<S>, t: &Nonced<T>, inner: S) -> NoncedState<S> {
+    let mut nonces = starting_state.nonces.clone();
+    nonces.insert(t.signer, t.nonce + 1);
+    NoncedState { inner, nonces }
+}
+
+impl<Inner: StateMachine<State: Clone, Transition: Owned>> StateMachine for WithNonces<Inner> {
+    type State = NoncedState<Inner::State>;
+    type Transition = Nonced<Inner::Transition>;
+    type Error = NonceError<Inner::Error>;
+    type GenesisConfig = Inner::GenesisConfig;
+
+    /// Nobody has transacted at genesis, so no nonces are stored.
+    fn genesis_state(config: Inner::GenesisConfig) -> Self::State {
+        NoncedState {
+            inner: Inner::genesis_state(config),
+            nonces: HashMap::new(),
+        }
+    }
+
+    /// A transition with the wrong nonce, or signed by the wrong account, is a no-op. A
+    /// transition with the right nonce always uses up that nonce, even if the inner machine
+    /// treats the call itself as a no-op.
+    fn next_state(starting_state: &Self::State, t: &Self::Transition) -> Self::State {
+        if check_nonce::<_, _, Inner::Error>(starting_state, t).is_err() {
+            return starting_state.clone();
+        }
+        use_nonce(starting_state, t, Inner::next_state(&starting_state.inner, &t.call))
+    }
+
+    fn try_next_state(
+        starting_state: &Self::State,
+        t: &Self::Transition,
+    ) -> Result<Self::State, Self::Error> {
+        check_nonce(starting_state, t)?;
+        Inner::try_next_state(&starting_state.inner, &t.call).map_err(NonceError::Inner)?;
+        Ok(Self::next_state(starting_state, t))
+    }
+
+    fn validate_transition(state: &Self::State, t: &Self::Transition) -> bool {
+        check_nonce::<_, _, Inner::Error>(state, t).is_ok()
+            && Inner::validate_transition(&state.inner, &t.call)
+    }
+
+    fn human_name() -> String {
+        format!("{} with nonces", Inner::human_name())
+    }
+}
+
+impl<Inner: ContextualStateMachine<State: Clone, Transition: Owned>> ContextualStateMachine
+    for WithNonces<Inner>
+{
+    fn next_state_in_context(
+        starting_state: &Self::State,
+        t: &Self::Transition,
+        context: &BlockContext,
+    ) -> Self::State {
+        if check_nonce::<_, _, Inner::Error>(starting_state, t).is_err() {
+            return starting_state.clone();
+        }
+        let inner = Inner::next_state_in_context(&starting_state.inner, &t.call, context);
+        use_nonce(starting_state, t, inner)
+    }
+
+    fn try_next_state_in_context(
+        starting_state: &Self::State,
+        t: &Self::Transition,
+        context: &BlockContext,
+    ) -> Result<Self::State, Self::Error> {
+        check_nonce(starting_state, t)?;
+        let inner = Inner::try_next_state_in_context(&starting_state.inner, &t.call, context)
+            .map_err(NonceError::Inner)?;
+        Ok(use_nonce(starting_state, t, inner))
+    }
+}
+
+impl<Inner: InvertibleStateMachine<State: Clone, Transition: Owned>> InvertibleStateMachine
+    for WithNonces<Inner>
+{
+    fn undo(ending_state: &Self::State, t: &Self::Transition) -> Self::State {
+        let mut nonces = ending_state.nonces.clone();
+        if t.nonce == 0 {
+            nonces.remove(&t.signer);
+        } else {
+            nonces.insert(t.signer, t.nonce);
+        }
+        NoncedState {
+            inner: Inner::undo(&ending_state.inner, &t.call),
+            nonces,
+        }
+    }
+}
+
+impl<Inner: Weighted<State: Clone, Transition: Owned>> Weighted for WithNonces<Inner> {
+    fn weight(t: &Self::Transition) -> u64 {
+        Inner::weight(&t.call)
+    }
+}
+
+#[cfg(test)]
+use super::p4_accounted_currency::{AccountedCurrency, AccountingTransaction, CurrencyError};
+
+#[cfg(test)]
+type NoncedCurrency = WithNonces<AccountedCurrency>;
+
+#[cfg(test)]
+fn transfer(nonce: u64, amount: u64) -> Nonced<AccountingTransaction> {
+    Nonced {
+        signer: User::Alice,
+        nonce,
+        call: AccountingTransaction::Transfer {
+            sender: User::Alice,
+            receiver: User::Bob,
+            amount,
+        },
+    }
+}
+
+#[test]
+fn nonces_accept_transitions_in_order() {
+    let start = NoncedCurrency::genesis_state(vec![(User::Alice, 10)]);
+
+    let end = NoncedCurrency::try_apply_all(&start, &[transfer(0, 1), transfer(1, 2)]).unwrap();
+
+    assert_eq!(end.inner, HashMap::from([(User::Alice, 7), (User::Bob, 3)]));
+    assert_eq!(end.expected_nonce(&User::Alice), 2);
+    assert_eq!(end.expected_nonce(&User::Bob), 0);
+}
+
+#[test]
+fn nonces_reject_replays() {
+    let start = NoncedCurrency::genesis_state(vec![(User::Alice, 10)]);
+    let once = NoncedCurrency::next_state(&start, &transfer(0, 1));
+
+    assert_eq!(
+        NoncedCurrency::try_next_state(&once, &transfer(0, 1)),
+        Err(NonceError::Stale { expected: 1, found: 0 })
+    );
+    assert_eq!(NoncedCurrency::next_state(&once, &transfer(0, 1)), once);
+}
+
+#[test]
+fn nonces_reject_skipped_nonces() {
+    let start = NoncedCurrency::genesis_state(vec![(User::Alice, 10)]);
+
+    assert_eq!(
+        NoncedCurrency::try_next_state(&start, &transfer(1, 1)),
+        Err(NonceError::Future { expected: 0, found: 1 })
+    );
+    assert!(!NoncedCurrency::validate_transition(&start, &transfer(1, 1)));
+}
+
+#[test]
+fn nonces_report_inner_rejections() {
+    let start = NoncedCurrency::genesis_state(vec![(User::Alice, 10)]);
+
+    assert_eq!(
+        NoncedCurrency::try_next_state(&start, &transfer(0, 11)),
+        Err(NonceError::Inner(CurrencyError::InsufficientBalance {
+            available: 10,
+            requested: 11
+        }))
+    );
+}
+
+#[test]
+fn nonces_reject_transitions_signed_for_someone_else() {
+    let start = NoncedCurrency::genesis_state(vec![(User::Alice, 10)]);
+    let stolen = Nonced {
+        signer: User::Bob,
+        ..transfer(0, 10)
+    };
+
+    assert_eq!(
+        NoncedCurrency::try_next_state(&start, &stolen),
+        Err(NonceError::WrongSigner {
+            signer: User::Bob,
+            owner: User::Alice
+        })
+    );
+    assert_eq!(NoncedCurrency::next_state(&start, &stolen), start);
+}
+
+#[test]
+fn nonces_undo_restores_nonce() {
+    let start = NoncedCurrency::genesis_state(vec![(User::Alice, 10)]);
+    let ts = [transfer(0, 1), transfer(1, 2)];
+
+    let end = NoncedCurrency::apply_all(&start, &ts);
+
+    assert_eq!(NoncedCurrency::undo_all(&end, &ts), start);
+}
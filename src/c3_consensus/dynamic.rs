@@ -0,0 +1,208 @@
+//! The client is generic over its consensus engine, so a node that should run PoW on one chain
+//! and PoA on another has to be compiled once for each. To pick the engine at runtime, say from
+//! a chain specification file, the engine has to be a trait object. `Consensus` cannot be one.
+//! Each engine has its own digest type, and some methods, like `create_default_instance`, have no
+//! `self` to dispatch on.
+//!
+//! `DynConsensus` is the object-safe part of `Consensus`. Digests cross it in their encoded form,
+//! and every engine whose digest can be encoded and decoded implements it automatically. A boxed
+//! `DynConsensus` is in turn a `Consensus` engine whose digests are bytes, so it can be plugged
+//! into anything generic over `Consensus`.
+
+use super::p1_pow::{PoW, Sha256};
+use super::p3_poa::SimplePoa;
+use super::{Consensus, Header};
+use crate::codec::{Decode, Encode};
+
+/// A digest in its encoded form
+pub type OpaqueDigest = Vec<u8>;
+
+/// The object-safe part of `Consensus`, with digests in their encoded form. Digests that fail to
+/// decode are invalid.
+pub trait DynConsensus {
+    /// Like `Consensus::validate_with_body`.
+    fn validate_opaque(
+        &self,
+        parent_digest: &[u8],
+        header: &Header<OpaqueDigest>,
+        body: &[Vec<u8>],
+    ) -> bool;
+
+    /// Like `Consensus::seal_with_body`.
+    fn seal_opaque(
+        &self,
+        parent_digest: &[u8],
+        partial_header: Header<()>,
+        body: &[Vec<u8>],
+    ) -> Option<Header<OpaqueDigest>>;
+
+    /// Like `Consensus::human_name`.
+    fn engine_name(&self) -> String;
+}
+
+fn with_digest<A, B>(header: &Header<A>, consensus_digest: B) -> Header<B> {
+    Header {
+        parent: header.parent,
+        height: header.height,
+        state_root: header.state_root,
+        extrinsics_root: header.extrinsics_root,
+        consensus_digest,
+    }
+}
+
+impl<C> DynConsensus for C
+where
+    C: Consensus,
+    C::Digest: Encode + Decode,
+{
+    fn validate_opaque(
+        &self,
+        parent_digest: &[u8],
+        header: &Header<OpaqueDigest>,
+        body: &[Vec<u8>],
+    ) -> bool {
+        let (Some(parent), Some(digest)) = (
+            C::Digest::decode(parent_digest),
+            C::Digest::decode(&header.consensus_digest),
+        ) else {
+            return false;
+        };
+        self.validate_with_body(&parent, &with_digest(header, digest), body)
+    }
+
+    fn seal_opaque(
+        &self,
+        parent_digest: &[u8],
+        partial_header: Header<()>,
+        body: &[Vec<u8>],
+    ) -> Option<Header<OpaqueDigest>> {
+        let parent = C::Digest::decode(parent_digest)?;
+        let sealed = self.seal_with_body(&parent, partial_header, body)?;
+        Some(with_digest(&sealed, sealed.consensus_digest.encode()))
+    }
+
+    fn engine_name(&self) -> String {
+        C::human_name()
+    }
+}
+
+/// An engine chosen at runtime
+pub type BoxedConsensus = Box<dyn DynConsensus>;
+
+/// Being a `Consensus` engine with encodable digests, the box is also a `DynConsensus` itself,
+/// whose digests are encoded twice. Call `DynConsensus` methods on its contents instead.
+impl Consensus for BoxedConsensus {
+    type Digest = OpaqueDigest;
+
+    fn validate(&self, parent_digest: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        (**self).validate_opaque(parent_digest, header, &[])
+    }
+
+    fn seal(
+        &self,
+        parent_digest: &Self::Digest,
+        partial_header: Header<()>,
+    ) -> Option<Header<Self::Digest>> {
+        (**self).seal_opaque(parent_digest, partial_header, &[])
+    }
+
+    fn validate_with_body(
+        &self,
+        parent_digest: &Self::Digest,
+        header: &Header<Self::Digest>,
+        body: &[Vec<u8>],
+    ) -> bool {
+        (**self).validate_opaque(parent_digest, header, body)
+    }
+
+    fn seal_with_body(
+        &self,
+        parent_digest: &Self::Digest,
+        partial_header: Header<()>,
+        body: &[Vec<u8>],
+    ) -> Option<Header<Self::Digest>> {
+        (**self).seal_opaque(parent_digest, partial_header, body)
+    }
+
+    fn human_name() -> String {
+        "Chosen at runtime".into()
+    }
+
+    /// The trivial engine that accepts every block.
+    fn create_default_instance() -> Self {
+        Box::new(())
+    }
+}
+
+/// The default instance of the engine with the given name, as it would appear in a chain
+/// specification: `"pow"`, `"poa"`, or `"none"`. Returns None for any other name.
+pub fn engine_by_name(name: &str) -> Option<BoxedConsensus> {
+    match name {
+        "pow" => Some(Box::new(PoW::<Sha256>::create_default_instance())),
+        "poa" => Some(Box::new(SimplePoa::create_default_instance())),
+        "none" => Some(Box::new(())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+use super::ConsensusAuthority;
+
+#[cfg(test)]
+fn partial_header() -> Header<()> {
+    Header {
+        parent: 0,
+        height: 1,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: (),
+    }
+}
+
+#[test]
+fn test_engines_picked_by_name_seal_and_validate() {
+    let genesis_digests = [
+        ("pow", 0u64.encode()),
+        ("poa", ConsensusAuthority::Alice.encode()),
+        ("none", Vec::new()),
+    ];
+    for (name, genesis) in genesis_digests {
+        let engine = engine_by_name(name).unwrap();
+        let header = engine.seal(&genesis, partial_header()).unwrap();
+
+        assert!(engine.validate(&genesis, &header), "{name}");
+    }
+    assert!(engine_by_name("proof of vibes").is_none());
+}
+
+#[test]
+fn test_boxed_engine_agrees_with_the_engine_it_wraps() {
+    let pow = PoW::new(u64::MAX / 10);
+    let header = pow.seal(&0, partial_header()).unwrap();
+    let boxed: BoxedConsensus = Box::new(pow.clone());
+
+    let opaque = with_digest(&header, header.consensus_digest.encode());
+    assert!(boxed.validate(&0u64.encode(), &opaque));
+    assert_eq!(boxed.as_ref().engine_name(), PoW::<Sha256>::human_name());
+
+    let mut forged = opaque.clone();
+    forged.consensus_digest = (header.consensus_digest + 1).encode();
+    assert_eq!(
+        boxed.validate(&0u64.encode(), &forged),
+        pow.validate(&0, &with_digest(&header, header.consensus_digest + 1))
+    );
+}
+
+#[test]
+fn test_undecodable_digests_are_invalid() {
+    let boxed: BoxedConsensus = Box::new(SimplePoa::create_default_instance());
+    let header = boxed
+        .seal(&ConsensusAuthority::Alice.encode(), partial_header())
+        .unwrap();
+
+    assert!(!boxed.validate(&vec![9], &header));
+    let mut garbled = header.clone();
+    garbled.consensus_digest.push(0);
+    assert!(!boxed.validate(&ConsensusAuthority::Alice.encode(), &garbled));
+    assert_eq!(boxed.seal(&vec![], partial_header()), None);
+}
@@ -0,0 +1,340 @@
+//! Clients, explorers and slashing logic keep asking the same two questions about a block: who
+//! sealed it, and when. Every engine that knows the answers has been keeping them in a digest
+//! struct of its own, each with its own field names, and engines like PoW keep neither.
+//!
+//! `DigestEnvelope` is a standard place for them. It wraps an engine's own digest, adding an
+//! optional author and timestamp. `DigestMetadata` reads them out of any digest, enveloped or
+//! not, so code that only cares about who and when need not know which engine sealed the block.
+//! The `Enveloped` engine stamps the blocks of any other engine, so that even PoW blocks say who
+//! mined them and when.
+
+use super::p10_signalled_fork::SignalDigest;
+use super::p11_vrf_election::VrfDigest;
+use super::p12_proof_of_stake::PosDigest;
+use super::p13_tendermint::Commit;
+use super::p14_checkpoints::CheckpointDigest;
+use super::p16_epochs::EpochDigest;
+use super::p17_uncles::UncleDigest;
+use super::p18_poet::PoetDigest;
+use super::p19_threshold_poa::MultiSigDigest;
+use super::p5_interleave::InterleavedDigest;
+use super::p8_retargeting_pow::RetargetDigest;
+use super::{Consensus, ConsensusAuthority, Header, SystemClock, TimeProvider};
+
+/// An engine's own digest, with an optional author and timestamp
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DigestEnvelope<D> {
+    pub inner: D,
+    pub author: Option<ConsensusAuthority>,
+    /// Milliseconds since the unix epoch
+    pub timestamp: Option<u64>,
+}
+
+impl<D> DigestEnvelope<D> {
+    /// The given digest, saying nothing about who sealed it or when.
+    pub fn new(inner: D) -> Self {
+        DigestEnvelope {
+            inner,
+            author: None,
+            timestamp: None,
+        }
+    }
+
+    pub fn with_author(mut self, author: ConsensusAuthority) -> Self {
+        self.author = Some(author);
+        self
+    }
+
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+}
+
+/// Who sealed a block and when, as far as its digest says
+pub trait DigestMetadata {
+    fn author(&self) -> Option<ConsensusAuthority> {
+        None
+    }
+
+    /// When the block was sealed, in milliseconds since the unix epoch
+    fn timestamp(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// What the envelope says, or failing that, what the digest inside it says.
+impl<D: DigestMetadata> DigestMetadata for DigestEnvelope<D> {
+    fn author(&self) -> Option<ConsensusAuthority> {
+        self.author.or_else(|| self.inner.author())
+    }
+
+    fn timestamp(&self) -> Option<u64> {
+        self.timestamp.or_else(|| self.inner.timestamp())
+    }
+}
+
+impl DigestMetadata for () {}
+
+/// A PoW nonce says nothing about the miner.
+impl DigestMetadata for u64 {}
+
+/// A PoA digest is the authority's signature.
+impl DigestMetadata for ConsensusAuthority {
+    fn author(&self) -> Option<ConsensusAuthority> {
+        Some(*self)
+    }
+}
+
+impl DigestMetadata for RetargetDigest {
+    fn timestamp(&self) -> Option<u64> {
+        Some(self.timestamp)
+    }
+}
+
+impl DigestMetadata for VrfDigest {
+    fn author(&self) -> Option<ConsensusAuthority> {
+        Some(self.author)
+    }
+}
+
+impl DigestMetadata for PosDigest {
+    fn author(&self) -> Option<ConsensusAuthority> {
+        Some(self.author)
+    }
+}
+
+impl DigestMetadata for EpochDigest {
+    fn author(&self) -> Option<ConsensusAuthority> {
+        Some(self.author)
+    }
+}
+
+impl DigestMetadata for UncleDigest {
+    fn author(&self) -> Option<ConsensusAuthority> {
+        Some(self.miner)
+    }
+}
+
+impl DigestMetadata for PoetDigest {
+    fn author(&self) -> Option<ConsensusAuthority> {
+        Some(self.author)
+    }
+
+    fn timestamp(&self) -> Option<u64> {
+        Some(self.sealed_at)
+    }
+}
+
+/// An opaque digest could be anything, so it says nothing.
+impl DigestMetadata for Vec<u8> {}
+
+/// A commit is signed by a quorum of validators, none of whom sealed the block alone.
+impl DigestMetadata for Commit {}
+
+/// A threshold signature is from several authorities, none of whom sealed the block alone.
+impl DigestMetadata for MultiSigDigest {}
+
+/// A signal says whatever the digest it was added to says.
+impl<D: DigestMetadata> DigestMetadata for SignalDigest<D> {
+    fn author(&self) -> Option<ConsensusAuthority> {
+        self.inner.author()
+    }
+
+    fn timestamp(&self) -> Option<u64> {
+        self.inner.timestamp()
+    }
+}
+
+/// Checkpoint signatures are added to the digest of the engine that sealed the block.
+impl<D: DigestMetadata> DigestMetadata for CheckpointDigest<D> {
+    fn author(&self) -> Option<ConsensusAuthority> {
+        self.inner.author()
+    }
+
+    fn timestamp(&self) -> Option<u64> {
+        self.inner.timestamp()
+    }
+}
+
+/// Whichever engine's turn it was sealed the block.
+impl<DA: DigestMetadata, DB: DigestMetadata> DigestMetadata for InterleavedDigest<DA, DB> {
+    fn author(&self) -> Option<ConsensusAuthority> {
+        match self {
+            InterleavedDigest::A { digest, .. } => digest.author(),
+            InterleavedDigest::B { digest, .. } => digest.author(),
+        }
+    }
+
+    fn timestamp(&self) -> Option<u64> {
+        match self {
+            InterleavedDigest::A { digest, .. } => digest.timestamp(),
+            InterleavedDigest::B { digest, .. } => digest.timestamp(),
+        }
+    }
+}
+
+/// The same header with another digest
+fn with_digest<X, Y>(header: &Header<X>, consensus_digest: Y) -> Header<Y> {
+    Header {
+        parent: header.parent,
+        height: header.height,
+        state_root: header.state_root,
+        extrinsics_root: header.extrinsics_root,
+        consensus_digest,
+    }
+}
+
+/// A higher-order consensus engine that stamps every block the inner engine seals with the time
+/// and its author. The author is whoever the inner digest names, or failing that, `author`.
+pub struct Enveloped<Inner, Clock = SystemClock> {
+    pub inner: Inner,
+    /// Who this node seals as, for inner engines whose digests do not say
+    pub author: Option<ConsensusAuthority>,
+    clock: Clock,
+}
+
+impl<Inner, Clock> Enveloped<Inner, Clock> {
+    pub fn new(inner: Inner, clock: Clock) -> Self {
+        Enveloped {
+            inner,
+            author: None,
+            clock,
+        }
+    }
+
+    /// Stamp blocks as sealed by the given authority, unless the inner digest names another.
+    pub fn sealing_as(mut self, author: ConsensusAuthority) -> Self {
+        self.author = Some(author);
+        self
+    }
+}
+
+impl<Inner, Clock> Consensus for Enveloped<Inner, Clock>
+where
+    Inner: Consensus,
+    Inner::Digest: DigestMetadata,
+    Clock: TimeProvider + Default,
+{
+    type Digest = DigestEnvelope<Inner::Digest>;
+
+    /// Check the header by the inner engine's rules, that it is stamped with a time no earlier
+    /// than its parent's and not in the future, and that its author agrees with the inner digest.
+    fn validate(&self, parent_digest: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        let digest = &header.consensus_digest;
+        let author_agrees = match (digest.author, digest.inner.author()) {
+            (Some(stamped), Some(sealed)) => stamped == sealed,
+            _ => true,
+        };
+        let in_order = digest.timestamp.is_some_and(|timestamp| {
+            timestamp >= parent_digest.timestamp.unwrap_or(0) && timestamp <= self.clock.now()
+        });
+        author_agrees
+            && in_order
+            && self.inner.validate(
+                &parent_digest.inner,
+                &with_digest(header, digest.inner.clone()),
+            )
+    }
+
+    /// Seal the header with the inner engine, and stamp it with the current time and its author.
+    /// Returns None if the clock is behind the parent's timestamp.
+    fn seal(
+        &self,
+        parent_digest: &Self::Digest,
+        partial_header: Header<()>,
+    ) -> Option<Header<Self::Digest>> {
+        let timestamp = self.clock.now();
+        if timestamp < parent_digest.timestamp.unwrap_or(0) {
+            return None;
+        }
+        let sealed = self.inner.seal(&parent_digest.inner, partial_header)?;
+        let inner = sealed.consensus_digest.clone();
+        let envelope = DigestEnvelope {
+            author: inner.author().or(self.author),
+            timestamp: Some(timestamp),
+            inner,
+        };
+        Some(with_digest(&sealed, envelope))
+    }
+
+    fn human_name() -> String {
+        format!("{} with authors and timestamps", Inner::human_name())
+    }
+
+    fn create_default_instance() -> Self {
+        Enveloped::new(Inner::create_default_instance(), Clock::default())
+    }
+}
+
+#[cfg(test)]
+use super::MockClock;
+#[cfg(test)]
+use ConsensusAuthority::{Alice, Bob};
+
+#[cfg(test)]
+fn partial_header() -> Header<()> {
+    Header {
+        parent: 0,
+        height: 1,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: (),
+    }
+}
+
+#[test]
+fn test_envelope_falls_back_to_the_inner_digest() {
+    let bare = DigestEnvelope::new(Bob);
+    assert_eq!(bare.author(), Some(Bob));
+    assert_eq!(bare.timestamp(), None);
+
+    let stamped = DigestEnvelope::new(7u64)
+        .with_author(Alice)
+        .with_timestamp(1000);
+    assert_eq!(stamped.author(), Some(Alice));
+    assert_eq!(stamped.timestamp(), Some(1000));
+}
+
+#[test]
+fn test_enveloped_pow_says_who_and_when() {
+    use super::p1_pow::PoW;
+
+    let clock = MockClock::new(5000);
+    let engine = Enveloped::new(PoW::new(u64::MAX / 4), clock.clone()).sealing_as(Alice);
+    let genesis = DigestEnvelope::new(0).with_timestamp(0);
+    let header = engine.seal(&genesis, partial_header()).unwrap();
+
+    assert_eq!(header.consensus_digest.author(), Some(Alice));
+    assert_eq!(header.consensus_digest.timestamp(), Some(5000));
+    assert!(engine.validate(&genesis, &header));
+
+    // Blocks from the future, or from before their parent, are rejected.
+    let later = DigestEnvelope::new(0).with_timestamp(6000);
+    assert!(!engine.validate(&later, &header));
+    assert_eq!(engine.seal(&later, partial_header()), None);
+    clock.set(4000);
+    assert!(!engine.validate(&genesis, &header));
+}
+
+#[test]
+fn test_stamped_author_must_agree_with_the_seal() {
+    use super::p3_poa::SimplePoa;
+
+    let clock = MockClock::new(5000);
+    // The PoA digest names its signer, which takes precedence over the node's own setting.
+    let engine = Enveloped::new(SimplePoa::create_default_instance(), clock).sealing_as(Alice);
+    let genesis = DigestEnvelope::new(Alice);
+    let header = engine.seal(&genesis, partial_header()).unwrap();
+    assert_eq!(
+        header.consensus_digest.author,
+        Some(header.consensus_digest.inner)
+    );
+    assert!(engine.validate(&genesis, &header));
+
+    let mut impostor = header.clone();
+    impostor.consensus_digest.author = Some(Bob);
+    assert_ne!(header.consensus_digest.inner, Bob);
+    assert!(!engine.validate(&genesis, &impostor));
+}
@@ -0,0 +1,222 @@
+//! In slot-based engines each slot has its authors, and an honest author seals one block per slot.
+//! An author who seals two different blocks for the same slot is equivocating. Each block may be
+//! valid on its own, but together they let the author split the network between two forks at no
+//! cost. Nothing in `validate` can prevent this, since each block is checked on its own.
+//!
+//! Instead, the two headers are proof of the offence. Any node that sees both can put them in an
+//! `EquivocationProof`, and anyone can check the proof with nothing but the engine's rules. A
+//! verified proof becomes a `Slash` transaction for the staking state machine, so that the
+//! offender loses part of their stake.
+
+use std::collections::HashMap;
+
+use super::{ConsensusAuthority, Header};
+use crate::c1_state_machine::p8_staking::StakingTransaction;
+use crate::hash;
+
+/// Engines in which an authority seals at most one block per slot. They expose which authority
+/// sealed a header and in which slot, which is all it takes to check an equivocation proof.
+pub trait SlotAuthorship<Digest> {
+    /// The slot the header was sealed in and the authority that sealed it, if the header is
+    /// sealed by an authority entitled to seal in that slot.
+    fn slot_author(&self, header: &Header<Digest>) -> Option<(u64, ConsensusAuthority)>;
+}
+
+/// Two different headers sealed by the same authority for the same slot
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EquivocationProof<Digest> {
+    pub first: Header<Digest>,
+    pub second: Header<Digest>,
+}
+
+/// An authority that equivocated, and the slot it did so in
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Offence {
+    pub offender: ConsensusAuthority,
+    pub slot: u64,
+}
+
+impl Offence {
+    /// The staking transaction that punishes the offence.
+    pub fn slash(&self) -> StakingTransaction {
+        StakingTransaction::Slash {
+            offender: self.offender.into(),
+            slot: self.slot,
+        }
+    }
+}
+
+impl<Digest: std::hash::Hash> EquivocationProof<Digest> {
+    /// The offence the proof proves according to the given engine's rules, if any. Both headers
+    /// must be genuinely sealed, by the same authority, for the same slot, and differ.
+    pub fn verify(&self, engine: &impl SlotAuthorship<Digest>) -> Option<Offence> {
+        let first = engine.slot_author(&self.first)?;
+        let second = engine.slot_author(&self.second)?;
+        (first == second && hash(&self.first) != hash(&self.second)).then_some(Offence {
+            slot: first.0,
+            offender: first.1,
+        })
+    }
+
+    /// The transaction that slashes the offender, if the proof holds up.
+    pub fn slashing_transaction(
+        &self,
+        engine: &impl SlotAuthorship<Digest>,
+    ) -> Option<StakingTransaction> {
+        self.verify(engine).map(|offence| offence.slash())
+    }
+}
+
+/// Watches the headers a node imports, remembering the first header each authority sealed in
+/// each slot, and produces a proof as soon as it sees a second.
+#[derive(Clone, Debug)]
+pub struct EquivocationDetector<Digest> {
+    seen: HashMap<(u64, ConsensusAuthority), Header<Digest>>,
+}
+
+impl<Digest> Default for EquivocationDetector<Digest> {
+    fn default() -> Self {
+        EquivocationDetector {
+            seen: HashMap::new(),
+        }
+    }
+}
+
+impl<Digest: Clone + std::hash::Hash> EquivocationDetector<Digest> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the given header, returning a proof if its author already sealed a different header
+    /// for the same slot. Headers that are not genuinely sealed are ignored.
+    pub fn observe(
+        &mut self,
+        engine: &impl SlotAuthorship<Digest>,
+        header: &Header<Digest>,
+    ) -> Option<EquivocationProof<Digest>> {
+        let key = engine.slot_author(header)?;
+        match self.seen.get(&key) {
+            Some(first) if hash(first) != hash(header) => Some(EquivocationProof {
+                first: first.clone(),
+                second: header.clone(),
+            }),
+            Some(_) => None,
+            None => {
+                self.seen.insert(key, header.clone());
+                None
+            }
+        }
+    }
+
+    /// Forget the headers of slots before the given one, once offences in them can no longer be
+    /// punished.
+    pub fn forget_before(&mut self, slot: u64) {
+        self.seen.retain(|(s, _), _| *s >= slot);
+    }
+}
+
+#[cfg(test)]
+use super::p12_proof_of_stake::{PosDigest, StakeDistribution, StakeWeightedPos};
+#[cfg(test)]
+use crate::c1_state_machine::{
+    p8_staking::{Staking, StakingTransaction::Bond},
+    StateMachine, User,
+};
+
+#[cfg(test)]
+fn pos() -> StakeWeightedPos<fn(&StakeDistribution) -> StakeDistribution> {
+    StakeWeightedPos::new(10, |stakes: &StakeDistribution| stakes.clone())
+}
+
+/// Two headers for height 1 sealed by the only staker, Alice, on top of different parents.
+#[cfg(test)]
+fn conflicting_headers() -> (Header<PosDigest>, Header<PosDigest>) {
+    use super::StatefulConsensus;
+
+    let stakes = vec![(ConsensusAuthority::Alice, 100)];
+    let engine = pos();
+    let genesis = engine.genesis_digest(&stakes);
+    let partial = |parent| Header {
+        parent,
+        height: 1,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: (),
+    };
+    let seal = |parent| {
+        engine
+            .seal_with_state(&stakes, &genesis, partial(parent))
+            .unwrap()
+    };
+    (seal(1), seal(2))
+}
+
+#[test]
+fn test_detector_proves_double_sealing() {
+    let engine = pos();
+    let (first, second) = conflicting_headers();
+    let mut detector = EquivocationDetector::new();
+
+    assert_eq!(detector.observe(&engine, &first), None);
+    assert_eq!(detector.observe(&engine, &first), None);
+    let proof = detector.observe(&engine, &second).unwrap();
+
+    assert_eq!(
+        proof.verify(&engine),
+        Some(Offence {
+            offender: ConsensusAuthority::Alice,
+            slot: 1
+        })
+    );
+}
+
+#[test]
+fn test_bogus_proofs_are_rejected() {
+    let engine = pos();
+    let (first, second) = conflicting_headers();
+
+    let same_twice = EquivocationProof {
+        first: first.clone(),
+        second: first.clone(),
+    };
+    assert_eq!(same_twice.verify(&engine), None);
+
+    let mut other_height = second.clone();
+    other_height.height = 2;
+    let different_slots = EquivocationProof {
+        first: first.clone(),
+        second: other_height,
+    };
+    assert_eq!(different_slots.verify(&engine), None);
+
+    // Bob was not elected, so a header claiming he sealed it proves nothing about him.
+    let mut framed = second;
+    framed.consensus_digest.author = ConsensusAuthority::Bob;
+    let framing = EquivocationProof {
+        first,
+        second: framed,
+    };
+    assert_eq!(framing.verify(&engine), None);
+}
+
+#[test]
+fn test_proof_slashes_the_offender() {
+    let engine = pos();
+    let (first, second) = conflicting_headers();
+    let proof = EquivocationProof { first, second };
+    let staked = Staking::next_state(
+        &Staking::genesis_state(vec![(User::Alice, 100)]),
+        &Bond {
+            who: User::Alice,
+            amount: 100,
+        },
+    );
+
+    let slash = proof.slashing_transaction(&engine).unwrap();
+    let slashed = Staking::try_next_state(&staked, &slash).unwrap();
+
+    assert_eq!(slashed.stake_of(&User::Alice), 90);
+    assert!(Staking::try_next_state(&slashed, &slash).is_err());
+}
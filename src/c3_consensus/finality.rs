@@ -0,0 +1,427 @@
+//! The engines of this chapter decide who may author a block, but never make a block final. A
+//! longer PoW fork can always come along and replace any number of blocks. Polkadot separates the
+//! two concerns: blocks are authored by one engine, and a finality gadget called GRANDPA runs
+//! alongside it, in which a fixed set of voters vote on the blocks they have seen. Once at least
+//! two thirds of them agree on a block, it is final, and no client will ever revert it.
+//!
+//! Voting happens in rounds of two steps. Each voter first prevotes for the best block it knows
+//! of, then precommits. A vote for a block also counts as a vote for each of its ancestors, so
+//! voters who disagree about the tip still agree about most of the chain. A block is finalized
+//! once at least two thirds of the voters prevoted and precommitted for it or its descendants in
+//! the same round.
+//!
+//! The gadget wraps any consensus engine, which it leaves in charge of sealing and validating
+//! headers.
+
+use std::collections::HashMap;
+
+use super::{Consensus, ConsensusAuthority, Header};
+use crate::hash;
+
+/// The two voting steps of a round
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum VoteKind {
+    Prevote,
+    Precommit,
+}
+
+/// A voter's vote for a block, and so for all of its ancestors
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Vote {
+    pub round: u64,
+    pub kind: VoteKind,
+    /// The hash of the header voted for
+    pub block: u64,
+    pub voter: ConsensusAuthority,
+}
+
+/// A header's height and hash
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BlockId {
+    pub height: u64,
+    pub hash: u64,
+}
+
+/// The reasons a header, vote, or revert may be refused
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FinalityError {
+    /// The vote was not cast by one of the voters
+    NotAVoter(ConsensusAuthority),
+    /// The vote is for a header the gadget has not imported
+    UnknownBlock(u64),
+    /// The header's parent has not been imported
+    UnknownParent(u64),
+    /// The voter already cast a different vote of the same kind in the same round
+    Equivocation(Vote),
+    /// The header is not a descendant of the finalized block
+    ConflictsWithFinalized(BlockId),
+    /// Reverting down to the given height would revert the finalized block
+    WouldRevertFinalized(BlockId),
+}
+
+/// The height and parent of an imported header
+struct BlockInfo {
+    height: u64,
+    parent: u64,
+}
+
+/// A GRANDPA-style finality gadget running alongside the `Inner` consensus engine.
+pub struct FinalityGadget<Inner> {
+    inner: Inner,
+    voters: Vec<ConsensusAuthority>,
+    /// Every imported header, by hash
+    blocks: HashMap<u64, BlockInfo>,
+    /// The block each voter voted for, by round and step
+    votes: HashMap<(u64, VoteKind), HashMap<ConsensusAuthority, u64>>,
+    finalized: Option<BlockId>,
+}
+
+impl<Inner> FinalityGadget<Inner> {
+    pub fn new(inner: Inner, voters: Vec<ConsensusAuthority>) -> Self {
+        FinalityGadget {
+            inner,
+            voters,
+            blocks: HashMap::new(),
+            votes: HashMap::new(),
+            finalized: None,
+        }
+    }
+
+    pub fn inner(&self) -> &Inner {
+        &self.inner
+    }
+
+    /// The highest finalized block. The first header imported is final from the start.
+    pub fn finalized(&self) -> Option<BlockId> {
+        self.finalized
+    }
+
+    /// Record a header so that votes for it and its descendants can be counted. The first header
+    /// imported is taken to be genesis. Every later one must extend an imported header and
+    /// descend from the finalized block.
+    pub fn import_header<D: std::hash::Hash>(
+        &mut self,
+        header: &Header<D>,
+    ) -> Result<BlockId, FinalityError> {
+        let id = BlockId {
+            height: header.height,
+            hash: hash(header),
+        };
+        let Some(finalized) = self.finalized else {
+            self.finalized = Some(id);
+            self.insert(id, header.parent);
+            return Ok(id);
+        };
+        if !self.blocks.contains_key(&header.parent) {
+            return Err(FinalityError::UnknownParent(header.parent));
+        }
+        let descends = id.height > finalized.height
+            && self.ancestor_at(header.parent, finalized.height) == Some(finalized.hash);
+        if !descends && id != finalized {
+            return Err(FinalityError::ConflictsWithFinalized(id));
+        }
+        self.insert(id, header.parent);
+        Ok(id)
+    }
+
+    fn insert(&mut self, id: BlockId, parent: u64) {
+        self.blocks.insert(
+            id.hash,
+            BlockInfo {
+                height: id.height,
+                parent,
+            },
+        );
+    }
+
+    /// The hash of the ancestor of the given block at the given height, which may be the block
+    /// itself.
+    fn ancestor_at(&self, mut block: u64, height: u64) -> Option<u64> {
+        loop {
+            let info = self.blocks.get(&block)?;
+            if info.height <= height {
+                return (info.height == height).then_some(block);
+            }
+            block = info.parent;
+        }
+    }
+
+    /// Whether `ancestor` is `block` or one of its ancestors.
+    pub fn is_ancestor(&self, ancestor: u64, block: u64) -> bool {
+        self.blocks
+            .get(&ancestor)
+            .is_some_and(|info| self.ancestor_at(block, info.height) == Some(ancestor))
+    }
+
+    /// Whether at least two thirds of the voters voted for the given block or its descendants.
+    fn has_supermajority(&self, block: u64, round: u64, kind: VoteKind) -> bool {
+        let support = self.votes.get(&(round, kind)).map_or(0, |votes| {
+            votes
+                .values()
+                .filter(|target| self.is_ancestor(block, **target))
+                .count()
+        });
+        3 * support >= 2 * self.voters.len()
+    }
+
+    /// Count the given vote, returning the newly finalized block if it made one final.
+    pub fn import_vote(&mut self, vote: Vote) -> Result<Option<BlockId>, FinalityError> {
+        if !self.voters.contains(&vote.voter) {
+            return Err(FinalityError::NotAVoter(vote.voter));
+        }
+        if !self.blocks.contains_key(&vote.block) {
+            return Err(FinalityError::UnknownBlock(vote.block));
+        }
+        let votes = self.votes.entry((vote.round, vote.kind)).or_default();
+        match votes.get(&vote.voter) {
+            Some(block) if *block != vote.block => return Err(FinalityError::Equivocation(vote)),
+            Some(_) => return Ok(None),
+            None => {
+                votes.insert(vote.voter, vote.block);
+            }
+        }
+
+        let Some(finalized) = self.finalized else {
+            return Ok(None);
+        };
+        let newly_finalized = self
+            .blocks
+            .iter()
+            .filter(|(hash, info)| {
+                info.height > finalized.height
+                    && self.is_ancestor(finalized.hash, **hash)
+                    && self.has_supermajority(**hash, vote.round, VoteKind::Prevote)
+                    && self.has_supermajority(**hash, vote.round, VoteKind::Precommit)
+            })
+            .map(|(hash, info)| BlockId {
+                height: info.height,
+                hash: *hash,
+            })
+            .max_by_key(|id| id.height);
+        if newly_finalized.is_some() {
+            self.finalized = newly_finalized;
+        }
+        Ok(newly_finalized)
+    }
+
+    /// Check that blocks from the given height upwards may be reverted, which they may unless the
+    /// finalized block is among them.
+    pub fn ensure_revertible(&self, from_height: u64) -> Result<(), FinalityError> {
+        match self.finalized {
+            Some(finalized) if finalized.height >= from_height => {
+                Err(FinalityError::WouldRevertFinalized(finalized))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<Inner: Consensus> Consensus for FinalityGadget<Inner> {
+    type Digest = Inner::Digest;
+
+    /// Headers are valid if the inner engine says so. Finality is about which valid headers are
+    /// final, not which are valid.
+    fn validate(&self, parent_digest: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        self.inner.validate(parent_digest, header)
+    }
+
+    fn seal(
+        &self,
+        parent_digest: &Self::Digest,
+        partial_header: Header<()>,
+    ) -> Option<Header<Self::Digest>> {
+        self.inner.seal(parent_digest, partial_header)
+    }
+
+    fn human_name() -> String {
+        format!("{} with BFT finality", Inner::human_name())
+    }
+
+    /// Alice, Bob and Charlie vote on the inner engine's default instance's blocks.
+    fn create_default_instance() -> Self {
+        FinalityGadget::new(
+            Inner::create_default_instance(),
+            vec![
+                ConsensusAuthority::Alice,
+                ConsensusAuthority::Bob,
+                ConsensusAuthority::Charlie,
+            ],
+        )
+    }
+}
+
+#[cfg(test)]
+use ConsensusAuthority::{Alice, Bob, Charlie};
+
+/// A gadget over the trivial engine that has imported a chain of the given length, including
+/// genesis, along with the chain's block ids.
+#[cfg(test)]
+fn gadget_with_chain(len: u64) -> (FinalityGadget<()>, Vec<BlockId>) {
+    let mut gadget = FinalityGadget::new((), vec![Alice, Bob, Charlie]);
+    let ids = extend(&mut gadget, 0, 0, len, 0);
+    (gadget, ids)
+}
+
+/// Import `n` headers on top of the given parent, distinguished from other forks by their state
+/// roots, and return their ids.
+#[cfg(test)]
+fn extend(
+    gadget: &mut FinalityGadget<()>,
+    mut parent: u64,
+    first_height: u64,
+    n: u64,
+    fork: u64,
+) -> Vec<BlockId> {
+    (first_height..first_height + n)
+        .map(|height| {
+            let header = Header {
+                parent,
+                height,
+                state_root: fork,
+                extrinsics_root: 0,
+                consensus_digest: (),
+            };
+            let id = gadget.import_header(&header).unwrap();
+            parent = id.hash;
+            id
+        })
+        .collect()
+}
+
+#[cfg(test)]
+fn vote(round: u64, kind: VoteKind, block: BlockId, voter: ConsensusAuthority) -> Vote {
+    Vote {
+        round,
+        kind,
+        block: block.hash,
+        voter,
+    }
+}
+
+#[test]
+fn test_two_thirds_finalize() {
+    let (mut gadget, chain) = gadget_with_chain(5);
+    assert_eq!(gadget.finalized(), Some(chain[0]));
+
+    for voter in [Alice, Bob] {
+        gadget
+            .import_vote(vote(1, VoteKind::Prevote, chain[4], voter))
+            .unwrap();
+    }
+    assert_eq!(
+        gadget.import_vote(vote(1, VoteKind::Precommit, chain[4], Alice)),
+        Ok(None)
+    );
+    // Two of three is enough, but only once both steps have it.
+    assert_eq!(
+        gadget.import_vote(vote(1, VoteKind::Precommit, chain[4], Bob)),
+        Ok(Some(chain[4]))
+    );
+    assert_eq!(gadget.finalized(), Some(chain[4]));
+}
+
+#[test]
+fn test_votes_count_for_ancestors() {
+    let (mut gadget, chain) = gadget_with_chain(6);
+
+    // Everyone agrees on the chain up to block 3, even though their tips differ.
+    let tips = [(Alice, 5), (Bob, 3), (Charlie, 4)];
+    for (voter, tip) in tips {
+        gadget
+            .import_vote(vote(1, VoteKind::Prevote, chain[tip], voter))
+            .unwrap();
+    }
+    let mut finalized = None;
+    for (voter, tip) in tips {
+        finalized = gadget
+            .import_vote(vote(1, VoteKind::Precommit, chain[tip], voter))
+            .unwrap()
+            .or(finalized);
+    }
+
+    // Only Alice and Charlie voted for block 4 or later, and only Alice for block 5.
+    assert_eq!(finalized, Some(chain[4]));
+}
+
+#[test]
+fn test_votes_must_be_in_the_same_round() {
+    let (mut gadget, chain) = gadget_with_chain(3);
+
+    for voter in [Alice, Bob, Charlie] {
+        gadget
+            .import_vote(vote(1, VoteKind::Prevote, chain[2], voter))
+            .unwrap();
+        gadget
+            .import_vote(vote(2, VoteKind::Precommit, chain[2], voter))
+            .unwrap();
+    }
+
+    assert_eq!(gadget.finalized(), Some(chain[0]));
+}
+
+#[test]
+fn test_invalid_votes_are_refused() {
+    let (mut gadget, chain) = gadget_with_chain(3);
+    let mut gadget_without_charlie = FinalityGadget::new((), vec![Alice, Bob]);
+    extend(&mut gadget_without_charlie, 0, 0, 1, 0);
+
+    assert_eq!(
+        gadget_without_charlie.import_vote(vote(1, VoteKind::Prevote, chain[0], Charlie)),
+        Err(FinalityError::NotAVoter(Charlie))
+    );
+    assert_eq!(
+        gadget.import_vote(Vote {
+            block: 42,
+            ..vote(1, VoteKind::Prevote, chain[0], Alice)
+        }),
+        Err(FinalityError::UnknownBlock(42))
+    );
+
+    gadget
+        .import_vote(vote(1, VoteKind::Prevote, chain[1], Alice))
+        .unwrap();
+    let double = vote(1, VoteKind::Prevote, chain[2], Alice);
+    assert_eq!(
+        gadget.import_vote(double),
+        Err(FinalityError::Equivocation(double))
+    );
+}
+
+#[test]
+fn test_finalized_blocks_are_never_reverted() {
+    let (mut gadget, chain) = gadget_with_chain(4);
+    for kind in [VoteKind::Prevote, VoteKind::Precommit] {
+        for voter in [Alice, Bob, Charlie] {
+            gadget.import_vote(vote(1, kind, chain[2], voter)).unwrap();
+        }
+    }
+
+    // A fork from block 1 would revert the finalized block 2.
+    let mut fork_header = Header {
+        parent: chain[1].hash,
+        height: 2,
+        state_root: 1,
+        extrinsics_root: 0,
+        consensus_digest: (),
+    };
+    let fork_id = BlockId {
+        height: 2,
+        hash: hash(&fork_header),
+    };
+    assert_eq!(
+        gadget.import_header(&fork_header),
+        Err(FinalityError::ConflictsWithFinalized(fork_id))
+    );
+    fork_header.parent = chain[2].hash;
+    fork_header.height = 3;
+    assert!(gadget.import_header(&fork_header).is_ok());
+
+    assert_eq!(
+        gadget.ensure_revertible(2),
+        Err(FinalityError::WouldRevertFinalized(chain[2]))
+    );
+    assert_eq!(gadget.ensure_revertible(3), Ok(()));
+}
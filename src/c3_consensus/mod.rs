@@ -3,12 +3,38 @@
 //! previous module, then look at PoA, and other consensus engines all implementing the same simple
 //! interface.
 
-mod p1_pow;
+pub mod dynamic;
+pub mod envelope;
+pub mod equivocation;
+pub mod finality;
+pub mod p1_pow;
 mod p2_dictator;
 mod p3_poa; // exercise: dictator is a special case of poa. Create dictator in terms of PoA.
 mod p4_even_only;
-mod p5_interleave;
+pub mod p5_interleave;
 mod p6_forking;
+pub mod p7_dynamic_authorities;
+pub mod p8_retargeting_pow;
+pub mod p9_fork_schedule;
+pub mod p10_signalled_fork;
+pub mod p11_vrf_election;
+pub mod p12_proof_of_stake;
+pub mod p13_tendermint;
+pub mod p14_checkpoints;
+pub mod p15_max_extrinsics;
+pub mod p16_epochs;
+pub mod p17_uncles;
+pub mod p18_poet;
+pub mod p19_threshold_poa;
+pub mod spec;
+
+use envelope::DigestMetadata;
+use crate::c1_state_machine::p9_governance::ParameterChange;
+use crate::c1_state_machine::User;
+use crate::codec::{Decode, Encode};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 type Hash = u64;
 
@@ -21,6 +47,7 @@ type Hash = u64;
 /// which means they can operate entirely at the header level. They never need to touch
 /// the complete blocks.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Header<Digest> {
 	pub parent: Hash,
 	pub height: u64,
@@ -28,12 +55,39 @@ pub struct Header<Digest> {
 	pub extrinsics_root: Hash,
 	pub consensus_digest: Digest,
 }
+
+/// The fields in the order they are declared, so that consensus engines hashing headers with a
+/// real hash function all agree on the bytes being hashed.
+impl<Digest: Encode> Encode for Header<Digest> {
+	fn encode_to(&self, dest: &mut Vec<u8>) {
+		self.parent.encode_to(dest);
+		self.height.encode_to(dest);
+		self.state_root.encode_to(dest);
+		self.extrinsics_root.encode_to(dest);
+		self.consensus_digest.encode_to(dest);
+	}
+}
+
+impl<Digest: Decode> Decode for Header<Digest> {
+	fn decode_from(input: &mut &[u8]) -> Option<Self> {
+		Some(Header {
+			parent: Hash::decode_from(input)?,
+			height: u64::decode_from(input)?,
+			state_root: Hash::decode_from(input)?,
+			extrinsics_root: Hash::decode_from(input)?,
+			consensus_digest: Digest::decode_from(input)?,
+		})
+	}
+}
+
 /// A Consensus Engine. Responsible for Sealing blocks and verifying their seals
 ///
 /// Consensus exists independently of execution logic, and therefore operates
 /// only on the block headers.
 pub trait Consensus {
-	type Digest: Clone + core::fmt::Debug + Eq + PartialEq + std::hash::Hash;
+	/// What the engine seals into headers. Its metadata says who sealed the block, if anyone
+	/// in particular did.
+	type Digest: Clone + core::fmt::Debug + Eq + PartialEq + std::hash::Hash + DigestMetadata;
 
 	/// Validates that a header is valid according to consensus rules. This
 	/// function checks ONLY consensus-related aspects such as the signature
@@ -64,6 +118,30 @@ pub trait Consensus {
 	// NOTE TO SELF. For slot-based PoA etc, just look at the system time. It's what real-world aura
 	// does
 
+	/// Like `validate`, for engines whose rules also depend on the block's body, such as a limit
+	/// on the number of extrinsics. The body is given as the encoded extrinsics, since consensus
+	/// knows nothing about the state machine they are for. The provided implementation ignores
+	/// the body.
+	fn validate_with_body(
+		&self,
+		parent_digest: &Self::Digest,
+		header: &Header<Self::Digest>,
+		_body: &[Vec<u8>],
+	) -> bool {
+		self.validate(parent_digest, header)
+	}
+
+	/// Like `seal`, for engines whose rules also depend on the block's body. The provided
+	/// implementation ignores the body.
+	fn seal_with_body(
+		&self,
+		parent_digest: &Self::Digest,
+		partial_header: Header<()>,
+		_body: &[Vec<u8>],
+	) -> Option<Header<Self::Digest>> {
+		self.seal(parent_digest, partial_header)
+	}
+
 	/// Verify that all the given headers are valid according to the consensus rules.
 	///
 	/// This method assumes that the parent_digest is valid, and verifies all the
@@ -125,11 +203,172 @@ impl Consensus for () {
 	}
 }
 
+/// A consensus engine whose configuration can change while the chain is running, for example
+/// because on-chain governance voted to change the PoW difficulty.
+pub trait Configurable: Consensus {
+	/// Apply the given parameter change to this engine. Returns false, leaving the engine
+	/// unchanged, if this engine has no such parameter.
+	fn apply_parameter_change(&mut self, change: &ParameterChange) -> bool;
+}
+
+/// A consensus engine whose rules depend on the chain's state, for example because its
+/// authorities are elected on chain. The state passed in is the post-state of the parent block,
+/// which is the state the new block is built on.
+///
+/// Every plain `Consensus` engine is also a stateful one that ignores the state.
+pub trait StatefulConsensus<State> {
+	type Digest: Clone + core::fmt::Debug + Eq + PartialEq + std::hash::Hash;
+
+	/// Like `Consensus::validate`, but the rules may depend on the parent block's post-state.
+	fn validate_with_state(
+		&self,
+		parent_state: &State,
+		parent_digest: &Self::Digest,
+		header: &Header<Self::Digest>,
+	) -> bool;
+
+	/// Like `Consensus::seal`, but the rules may depend on the parent block's post-state.
+	fn seal_with_state(
+		&self,
+		parent_state: &State,
+		parent_digest: &Self::Digest,
+		partial_header: Header<()>,
+	) -> Option<Header<Self::Digest>>;
+}
+
+impl<C: Consensus, State> StatefulConsensus<State> for C {
+	type Digest = C::Digest;
+
+	fn validate_with_state(&self, _: &State, parent_digest: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+		self.validate(parent_digest, header)
+	}
+
+	fn seal_with_state(
+		&self,
+		_: &State,
+		parent_digest: &Self::Digest,
+		partial_header: Header<()>,
+	) -> Option<Header<Self::Digest>> {
+		self.seal(parent_digest, partial_header)
+	}
+}
+
 /// A set of consensus authority accounts that can be used in
 /// identity-based consensus algorithms.
 #[derive(Hash, Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum ConsensusAuthority {
 	Alice,
 	Bob,
 	Charlie,
+	Dave,
+	Eve,
+}
+
+/// Each authority is encoded as its position in the enum.
+impl Encode for ConsensusAuthority {
+	fn encode_to(&self, dest: &mut Vec<u8>) {
+		dest.push(*self as u8);
+	}
+}
+
+impl Decode for ConsensusAuthority {
+	fn decode_from(input: &mut &[u8]) -> Option<Self> {
+		match u8::decode_from(input)? {
+			0 => Some(ConsensusAuthority::Alice),
+			1 => Some(ConsensusAuthority::Bob),
+			2 => Some(ConsensusAuthority::Charlie),
+			3 => Some(ConsensusAuthority::Dave),
+			4 => Some(ConsensusAuthority::Eve),
+			_ => None,
+		}
+	}
+}
+
+/// Users of the state machines in chapter 1 act as the authorities of the same name, so that
+/// authorities can be elected on chain.
+impl From<User> for ConsensusAuthority {
+	fn from(user: User) -> Self {
+		match user {
+			User::Alice => ConsensusAuthority::Alice,
+			User::Bob => ConsensusAuthority::Bob,
+			User::Charlie => ConsensusAuthority::Charlie,
+			User::Dave => ConsensusAuthority::Dave,
+			User::Eve => ConsensusAuthority::Eve,
+		}
+	}
+}
+
+impl From<ConsensusAuthority> for User {
+	fn from(authority: ConsensusAuthority) -> Self {
+		match authority {
+			ConsensusAuthority::Alice => User::Alice,
+			ConsensusAuthority::Bob => User::Bob,
+			ConsensusAuthority::Charlie => User::Charlie,
+			ConsensusAuthority::Dave => User::Dave,
+			ConsensusAuthority::Eve => User::Eve,
+		}
+	}
+}
+
+/// A source of the current time, in milliseconds since the unix epoch. Engines that look at the
+/// clock take one of these, so that tests can control time rather than wait for it.
+pub trait TimeProvider {
+	fn now(&self) -> u64;
+}
+
+/// The system's wall clock.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct SystemClock;
+
+impl TimeProvider for SystemClock {
+	fn now(&self) -> u64 {
+		SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map_or(0, |d| u64::try_from(d.as_millis()).unwrap_or(u64::MAX))
+	}
+}
+
+/// A clock that only moves when told to. Clones share the same time, so a test can keep one and
+/// hand another to an engine.
+#[derive(Clone, Debug, Default)]
+pub struct MockClock(Arc<AtomicU64>);
+
+impl MockClock {
+	pub fn new(now: u64) -> Self {
+		MockClock(Arc::new(AtomicU64::new(now)))
+	}
+
+	pub fn set(&self, now: u64) {
+		self.0.store(now, Ordering::SeqCst);
+	}
+
+	pub fn advance(&self, millis: u64) {
+		self.0.fetch_add(millis, Ordering::SeqCst);
+	}
+}
+
+impl TimeProvider for MockClock {
+	fn now(&self) -> u64 {
+		self.0.load(Ordering::SeqCst)
+	}
+}
+
+/// A flag telling a long-running seal to give up, for example because a competing block arrived
+/// and the header being sealed is no longer worth finishing. Clones share the same flag.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	pub fn cancel(&self) {
+		self.0.store(true, Ordering::Relaxed);
+	}
+
+	pub fn is_cancelled(&self) -> bool {
+		self.0.load(Ordering::Relaxed)
+	}
 }
@@ -0,0 +1,360 @@
+//! The forks of parts 6 and 9 activate at heights chosen in advance. Bitcoin's soft forks are
+//! instead activated by the miners themselves: each block signals whether its author is ready for
+//! the new rules, and the new rules take over once a supermajority of recent blocks signal.
+//!
+//! Here we write such a fork. Every digest carries its author's signal, the signals of the
+//! blocks in the current window, and whether the fork has activated. All of it is checked
+//! against the parent's digest, so importers can still validate headers one at a time. The
+//! block that brings the tally to the threshold marks the fork as activated, and its children
+//! are the first to follow the new rules.
+//!
+//! The inner engines only see the header they seal. To stop anyone flipping a block's signal
+//! without redoing its seal, the signal is committed to in the extrinsics root the inner engines
+//! are shown.
+
+use std::marker::PhantomData;
+
+use super::{envelope::DigestMetadata, Consensus, Header};
+use crate::hash;
+
+/// The longest window of recent blocks whose signals can be tallied
+pub const MAX_SIGNAL_WINDOW: u32 = 128;
+
+/// The digest of a block under a signalled fork, wrapping the digest of whichever engine sealed it
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SignalDigest<D> {
+    pub inner: D,
+    /// Whether the block's author is ready for the new rules
+    pub signal: bool,
+    /// The signals of the blocks in the window ending with this one, this block's in the lowest
+    /// bit.
+    pub recent_signals: u128,
+    /// Whether enough blocks have signalled for this block's children to follow the new rules
+    pub activated: bool,
+}
+
+impl<D> SignalDigest<D> {
+    /// The digest of a genesis block, before any block has signalled.
+    pub fn genesis(inner: D) -> Self {
+        SignalDigest {
+            inner,
+            signal: false,
+            recent_signals: 0,
+            activated: false,
+        }
+    }
+}
+
+/// A higher-order consensus engine that follows the `Before` engine's rules until a supermajority
+/// of the last `window` blocks signal for the fork, and the `After` engine's rules from then on.
+/// Both engines' digests must convert to and from `D`, as with `Forked`.
+pub struct SignalledFork<D, Before, After> {
+    before: Before,
+    after: After,
+    /// How many of the most recent blocks are tallied
+    window: u32,
+    /// How many blocks of the window must signal for the fork to activate
+    threshold: u32,
+    /// Whether blocks sealed by this engine signal
+    signal: bool,
+    phdata: PhantomData<D>,
+}
+
+impl<D, Before, After> SignalledFork<D, Before, After> {
+    /// A fork that activates once `threshold` of the last `window` blocks signal for it. The
+    /// window is capped at `MAX_SIGNAL_WINDOW` blocks and the threshold at the window. Blocks
+    /// sealed by the new engine do not signal until `signalling` is called.
+    pub fn new(before: Before, after: After, window: u32, threshold: u32) -> Self {
+        let window = window.clamp(1, MAX_SIGNAL_WINDOW);
+        SignalledFork {
+            before,
+            after,
+            window,
+            threshold: threshold.clamp(1, window),
+            signal: false,
+            phdata: PhantomData,
+        }
+    }
+
+    /// Set whether the blocks this engine seals signal readiness for the fork.
+    pub fn signalling(mut self, signal: bool) -> Self {
+        self.signal = signal;
+        self
+    }
+
+    /// The signals and activation flag that must follow the given parent digest in a block with
+    /// the given signal.
+    fn tally(&self, parent: &SignalDigest<D>, signal: bool) -> (u128, bool) {
+        let mask = u128::MAX >> (MAX_SIGNAL_WINDOW - self.window);
+        let recent = (parent.recent_signals << 1 | u128::from(signal)) & mask;
+        (
+            recent,
+            parent.activated || recent.count_ones() >= self.threshold,
+        )
+    }
+}
+
+/// The header shown to an inner engine, whose extrinsics root also commits to the signal.
+fn inner_header<X>(header: &Header<impl Sized>, signal: bool, digest: X) -> Header<X> {
+    Header {
+        parent: header.parent,
+        height: header.height,
+        state_root: header.state_root,
+        extrinsics_root: hash(&(header.extrinsics_root, signal)),
+        consensus_digest: digest,
+    }
+}
+
+/// Validate a header by the given engine's rules.
+fn validate_inner<D, C>(engine: &C, parent: &D, header: &Header<SignalDigest<D>>) -> bool
+where
+    C: Consensus,
+    D: Clone + Into<C::Digest>,
+{
+    let digest = &header.consensus_digest;
+    let inner = inner_header(header, digest.signal, digest.inner.clone().into());
+    engine.validate(&parent.clone().into(), &inner)
+}
+
+/// Seal a header by the given engine's rules, returning it with its digest converted back.
+fn seal_inner<D, C>(
+    engine: &C,
+    parent: &D,
+    partial_header: Header<()>,
+    signal: bool,
+) -> Option<Header<D>>
+where
+    C: Consensus,
+    D: Clone + Into<C::Digest>,
+    C::Digest: Into<D>,
+{
+    let sealed = engine.seal(
+        &parent.clone().into(),
+        inner_header(&partial_header, signal, ()),
+    )?;
+    Some(Header {
+        parent: sealed.parent,
+        height: sealed.height,
+        state_root: sealed.state_root,
+        extrinsics_root: partial_header.extrinsics_root,
+        consensus_digest: sealed.consensus_digest.into(),
+    })
+}
+
+impl<D, B, A> Consensus for SignalledFork<D, B, A>
+where
+    D: Clone + core::fmt::Debug + Eq + PartialEq + std::hash::Hash + DigestMetadata,
+    B: Consensus,
+    A: Consensus,
+    D: Into<B::Digest> + Into<A::Digest>,
+    B::Digest: Into<D>,
+    A::Digest: Into<D>,
+{
+    type Digest = SignalDigest<D>;
+
+    /// Check that the tally and activation flag follow from the parent's, and that the header is
+    /// sealed by the engine whose rules were in force after the parent.
+    fn validate(&self, parent_digest: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        let digest = &header.consensus_digest;
+        if (digest.recent_signals, digest.activated) != self.tally(parent_digest, digest.signal) {
+            return false;
+        }
+        if parent_digest.activated {
+            validate_inner(&self.after, &parent_digest.inner, header)
+        } else {
+            validate_inner(&self.before, &parent_digest.inner, header)
+        }
+    }
+
+    /// Seal the header by the rules in force after the parent, signalling if this engine is
+    /// configured to.
+    fn seal(
+        &self,
+        parent_digest: &Self::Digest,
+        partial_header: Header<()>,
+    ) -> Option<Header<Self::Digest>> {
+        let sealed = if parent_digest.activated {
+            seal_inner(
+                &self.after,
+                &parent_digest.inner,
+                partial_header,
+                self.signal,
+            )
+        } else {
+            seal_inner(
+                &self.before,
+                &parent_digest.inner,
+                partial_header,
+                self.signal,
+            )
+        }?;
+        let (recent_signals, activated) = self.tally(parent_digest, self.signal);
+        Some(Header {
+            parent: sealed.parent,
+            height: sealed.height,
+            state_root: sealed.state_root,
+            extrinsics_root: sealed.extrinsics_root,
+            consensus_digest: SignalDigest {
+                inner: sealed.consensus_digest,
+                signal: self.signal,
+                recent_signals,
+                activated,
+            },
+        })
+    }
+
+    fn human_name() -> String {
+        "Signalled Fork".into()
+    }
+
+    /// Activate once 95 of the last 100 blocks signal, like Bitcoin's original soft fork
+    /// deployments. Sealed blocks do not signal.
+    fn create_default_instance() -> Self {
+        SignalledFork::new(
+            B::create_default_instance(),
+            A::create_default_instance(),
+            100,
+            95,
+        )
+    }
+}
+
+#[cfg(test)]
+use super::{p1_pow::PoW, p4_even_only::EvenOnly};
+
+/// Anything goes before the fork, and state roots must be even after it.
+#[cfg(test)]
+type EvenSoftFork = SignalledFork<u64, PoW, EvenOnly<PoW>>;
+
+#[cfg(test)]
+fn even_soft_fork(signal: bool) -> EvenSoftFork {
+    SignalledFork::new(
+        PoW::new(u64::MAX),
+        EvenOnly {
+            inner_c: PoW::new(u64::MAX),
+        },
+        4,
+        3,
+    )
+    .signalling(signal)
+}
+
+/// Extend the chain by one block per given engine, each with an odd state root, and check every
+/// new block is valid.
+#[cfg(test)]
+fn extend(chain: &mut Vec<Header<SignalDigest<u64>>>, authors: &[&EvenSoftFork]) {
+    for author in authors {
+        let parent = chain.last().unwrap();
+        let partial = Header {
+            parent: hash(parent),
+            height: parent.height + 1,
+            state_root: 1,
+            extrinsics_root: 0,
+            consensus_digest: (),
+        };
+        let header = author.seal(&parent.consensus_digest, partial).unwrap();
+        assert!(author.validate(&parent.consensus_digest, &header));
+        chain.push(header);
+    }
+}
+
+#[cfg(test)]
+fn genesis() -> Vec<Header<SignalDigest<u64>>> {
+    vec![Header {
+        parent: 0,
+        height: 0,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: SignalDigest::genesis(0),
+    }]
+}
+
+#[test]
+fn test_fork_activates_after_supermajority() {
+    let ready = even_soft_fork(true);
+    let mut chain = genesis();
+    extend(&mut chain, &[&ready; 5]);
+
+    let activated: Vec<bool> = chain.iter().map(|h| h.consensus_digest.activated).collect();
+    assert_eq!(activated, vec![false, false, false, true, true, true]);
+    // Block 3 completed the tally but was sealed under the old rules. Its children were not.
+    let roots: Vec<u64> = chain.iter().map(|h| h.state_root).collect();
+    assert_eq!(roots, vec![0, 1, 1, 1, 2, 2]);
+}
+
+#[test]
+fn test_fork_needs_signals_within_the_window() {
+    let ready = even_soft_fork(true);
+    let not_ready = even_soft_fork(false);
+    let mut chain = genesis();
+    // Never more than two signals in any four consecutive blocks.
+    extend(
+        &mut chain,
+        &[
+            &ready, &not_ready, &ready, &not_ready, &not_ready, &ready, &ready, &not_ready,
+        ],
+    );
+
+    assert!(chain.iter().all(|h| !h.consensus_digest.activated));
+    assert_eq!(
+        chain.last().unwrap().consensus_digest.recent_signals,
+        0b0110
+    );
+
+    extend(&mut chain, &[&ready]);
+    assert!(chain.last().unwrap().consensus_digest.activated);
+}
+
+#[test]
+fn test_activation_is_permanent() {
+    let ready = even_soft_fork(true);
+    let not_ready = even_soft_fork(false);
+    let mut chain = genesis();
+    extend(&mut chain, &[&ready, &ready, &ready]);
+    extend(&mut chain, &[&not_ready; 6]);
+
+    assert!(chain.last().unwrap().consensus_digest.activated);
+    assert_eq!(chain.last().unwrap().consensus_digest.recent_signals, 0);
+    assert_eq!(chain.last().unwrap().state_root, 2);
+}
+
+#[test]
+fn test_forged_tally_is_rejected() {
+    let ready = even_soft_fork(true);
+    let mut chain = genesis();
+    extend(&mut chain, &[&ready, &ready]);
+    let parent = &chain[1].consensus_digest;
+
+    // Claiming activation one block early
+    let mut early = chain[2].clone();
+    early.consensus_digest.activated = true;
+    assert!(!ready.validate(parent, &early));
+
+    // Dropping a signal from the window
+    let mut forgotten = chain[2].clone();
+    forgotten.consensus_digest.recent_signals = 0b01;
+    assert!(!ready.validate(parent, &forgotten));
+}
+
+#[test]
+fn test_signal_is_covered_by_the_seal() {
+    let pow = PoW::new(u64::MAX / 1000);
+    let fork = SignalledFork::<u64, PoW, PoW>::new(pow.clone(), pow, 4, 3).signalling(true);
+    let genesis = SignalDigest::genesis(0);
+    let partial = Header {
+        parent: 0,
+        height: 1,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: (),
+    };
+    let header = fork.seal(&genesis, partial).unwrap();
+    assert!(fork.validate(&genesis, &header));
+
+    // A consistent tally without the signal still lacks the work for a block without it.
+    let mut unsignalled = header.clone();
+    unsignalled.consensus_digest.signal = false;
+    unsignalled.consensus_digest.recent_signals = 0;
+    assert!(!fork.validate(&genesis, &unsignalled));
+}
@@ -0,0 +1,324 @@
+//! The round robin engines announce every slot's author long in advance, which tells an attacker
+//! exactly whom to knock offline to stall the chain. Ouroboros Praos and BABE elect leaders
+//! privately instead. In every slot each authority evaluates a verifiable random function (VRF) on
+//! the slot number with its secret key. If the output is below a threshold, the authority may seal
+//! the slot's block, and nobody else finds out until the block is published with the output and a
+//! proof that it was computed correctly. Some slots end up with no leader, and some with several.
+//!
+//! As elsewhere in this chapter we skip real cryptography. Our VRF is keyed by the authority's
+//! identity rather than a secret key, so anyone could compute anyone's output. The digest still
+//! carries an output and a proof, and validation checks them the way a real VRF would be checked.
+
+use super::p1_pow::{PowHasher, Sha256, Target};
+use super::{Consensus, ConsensusAuthority, Header, SystemClock, TimeProvider};
+use crate::codec::Encode;
+
+/// The result of evaluating the VRF, together with the proof that it was evaluated correctly
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VrfOutput {
+    pub output: [u8; 32],
+    pub proof: [u8; 32],
+}
+
+impl VrfOutput {
+    /// Evaluate the VRF as the given authority on the given slot.
+    pub fn evaluate(authority: ConsensusAuthority, slot: u64) -> Self {
+        let proof = Sha256::hash(&(b"vrf-proof", (authority, slot)).encode());
+        VrfOutput {
+            output: Sha256::hash(&(b"vrf-output", proof).encode()),
+            proof,
+        }
+    }
+
+    /// Whether this is what the given authority gets by evaluating the VRF on the given slot.
+    pub fn verify(&self, authority: ConsensusAuthority, slot: u64) -> bool {
+        *self == VrfOutput::evaluate(authority, slot)
+    }
+}
+
+/// The digest of a block sealed by a VRF-elected leader
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VrfDigest {
+    pub slot: u64,
+    pub author: ConsensusAuthority,
+    pub vrf: VrfOutput,
+}
+
+impl VrfDigest {
+    /// The digest of a genesis block, in slot 0. Genesis is never validated, so its author and
+    /// VRF output are never checked.
+    pub fn genesis() -> Self {
+        VrfDigest {
+            slot: 0,
+            author: ConsensusAuthority::Alice,
+            vrf: VrfOutput::evaluate(ConsensusAuthority::Alice, 0),
+        }
+    }
+}
+
+/// A consensus engine that elects each slot's leaders by VRF. Slots are derived from the clock as
+/// in the round robin by slot engine of part 3.
+pub struct VrfElection<Clock = SystemClock> {
+    authorities: Vec<ConsensusAuthority>,
+    /// The length of a slot in milliseconds. Always at least one.
+    slot_duration: u64,
+    /// An authority may seal a slot when its VRF output does not exceed this.
+    threshold: Target,
+    /// The authority this node seals as, if it is one
+    local_authority: Option<ConsensusAuthority>,
+    clock: Clock,
+}
+
+impl<Clock> VrfElection<Clock> {
+    /// An election among the given authorities in which each is a leader of about one slot in
+    /// as many as there are authorities.
+    pub fn new(authorities: Vec<ConsensusAuthority>, slot_duration: u64, clock: Clock) -> Self {
+        let threshold = Target::from_difficulty(authorities.len() as u64);
+        VrfElection {
+            authorities,
+            slot_duration: slot_duration.max(1),
+            threshold,
+            local_authority: None,
+            clock,
+        }
+    }
+
+    /// Set the threshold VRF outputs must not exceed. Higher thresholds elect more leaders.
+    pub fn with_threshold(mut self, threshold: Target) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Seal only as the given authority. Without one, blocks are sealed as whichever authority is
+    /// listed first among the slot's leaders.
+    pub fn sealing_as(mut self, authority: ConsensusAuthority) -> Self {
+        self.local_authority = Some(authority);
+        self
+    }
+
+    /// The VRF output that makes the given authority a leader of the given slot, if it is one.
+    pub fn claim_slot(&self, authority: ConsensusAuthority, slot: u64) -> Option<VrfOutput> {
+        if !self.authorities.contains(&authority) {
+            return None;
+        }
+        let vrf = VrfOutput::evaluate(authority, slot);
+        self.threshold.is_met_by(&vrf.output).then_some(vrf)
+    }
+
+    /// Every authority that may seal the given slot, in the order they are listed.
+    pub fn leaders(&self, slot: u64) -> Vec<ConsensusAuthority> {
+        self.authorities
+            .iter()
+            .copied()
+            .filter(|a| self.claim_slot(*a, slot).is_some())
+            .collect()
+    }
+}
+
+impl<Clock: TimeProvider> VrfElection<Clock> {
+    /// The slot the clock is in now.
+    pub fn current_slot(&self) -> u64 {
+        self.clock.now() / self.slot_duration
+    }
+}
+
+impl<Clock: TimeProvider + Default> Consensus for VrfElection<Clock> {
+    type Digest = VrfDigest;
+
+    /// Check that the slot comes after the parent's and has already started, and that the
+    /// author's VRF output for it is genuine and below the threshold.
+    fn validate(&self, parent_digest: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        let digest = &header.consensus_digest;
+        digest.slot > parent_digest.slot
+            && digest.slot <= self.current_slot()
+            && digest.vrf.verify(digest.author, digest.slot)
+            && self.claim_slot(digest.author, digest.slot).is_some()
+    }
+
+    /// Seal the block in the current slot if this node's authority is one of its leaders.
+    fn seal(
+        &self,
+        parent_digest: &Self::Digest,
+        partial_header: Header<()>,
+    ) -> Option<Header<Self::Digest>> {
+        let slot = self.current_slot();
+        if slot <= parent_digest.slot {
+            return None;
+        }
+        let candidates = match self.local_authority {
+            Some(local) => vec![local],
+            None => self.authorities.clone(),
+        };
+        let (author, vrf) = candidates
+            .into_iter()
+            .find_map(|a| Some((a, self.claim_slot(a, slot)?)))?;
+
+        Some(Header {
+            parent: partial_header.parent,
+            height: partial_header.height,
+            state_root: partial_header.state_root,
+            extrinsics_root: partial_header.extrinsics_root,
+            consensus_digest: VrfDigest { slot, author, vrf },
+        })
+    }
+
+    fn human_name() -> String {
+        "VRF Leader Election".into()
+    }
+
+    /// Alice, Bob and Charlie in slots of six seconds.
+    fn create_default_instance() -> Self {
+        VrfElection::new(
+            vec![
+                ConsensusAuthority::Alice,
+                ConsensusAuthority::Bob,
+                ConsensusAuthority::Charlie,
+            ],
+            6000,
+            Clock::default(),
+        )
+    }
+}
+
+#[cfg(test)]
+use super::MockClock;
+#[cfg(test)]
+use ConsensusAuthority::{Alice, Bob, Charlie};
+
+#[cfg(test)]
+fn election(clock: &MockClock) -> VrfElection<MockClock> {
+    VrfElection::new(vec![Alice, Bob, Charlie], 1000, clock.clone())
+}
+
+#[cfg(test)]
+fn partial_header(height: u64) -> Header<()> {
+    Header {
+        parent: 0,
+        height,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: (),
+    }
+}
+
+/// The first slot after the given one with the given authority among its leaders
+#[cfg(test)]
+fn next_slot_led_by(
+    engine: &VrfElection<MockClock>,
+    authority: ConsensusAuthority,
+    after: u64,
+) -> u64 {
+    (after + 1..)
+        .find(|slot| engine.leaders(*slot).contains(&authority))
+        .unwrap()
+}
+
+#[test]
+fn test_leader_seals_and_others_validate() {
+    let clock = MockClock::new(0);
+    let bob = election(&clock).sealing_as(Bob);
+    let slot = next_slot_led_by(&bob, Bob, 0);
+    clock.set(slot * 1000);
+
+    let header = bob.seal(&VrfDigest::genesis(), partial_header(1)).unwrap();
+    assert_eq!(header.consensus_digest.slot, slot);
+    assert_eq!(header.consensus_digest.author, Bob);
+    assert!(election(&clock)
+        .sealing_as(Charlie)
+        .validate(&VrfDigest::genesis(), &header));
+}
+
+#[test]
+fn test_about_one_leader_per_slot() {
+    let clock = MockClock::new(0);
+    let engine = election(&clock);
+    let leaders: usize = (1..=300).map(|slot| engine.leaders(slot).len()).sum();
+    let empty = (1..=300)
+        .filter(|slot| engine.leaders(*slot).is_empty())
+        .count();
+
+    assert!((240..=360).contains(&leaders), "{leaders} leaders");
+    // With each authority elected a third of the time, (2/3)^3 of slots have no leader.
+    assert!((60..=120).contains(&empty), "{empty} empty slots");
+}
+
+#[test]
+fn test_non_leader_cannot_seal() {
+    let clock = MockClock::new(0);
+    let alice = election(&clock).sealing_as(Alice);
+    let slot = (1..).find(|s| !alice.leaders(*s).contains(&Alice)).unwrap();
+    clock.set(slot * 1000);
+
+    assert_eq!(alice.seal(&VrfDigest::genesis(), partial_header(1)), None);
+
+    // Publishing her genuine but losing output does not help.
+    let claimed = Header {
+        parent: 0,
+        height: 1,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: VrfDigest {
+            slot,
+            author: Alice,
+            vrf: VrfOutput::evaluate(Alice, slot),
+        },
+    };
+    assert!(!alice.validate(&VrfDigest::genesis(), &claimed));
+}
+
+#[test]
+fn test_forged_vrf_is_rejected() {
+    let clock = MockClock::new(0);
+    let engine = election(&clock);
+    let slot = next_slot_led_by(&engine, Bob, 0);
+    clock.set(slot * 1000);
+    let header = engine
+        .sealing_as(Bob)
+        .seal(&VrfDigest::genesis(), partial_header(1))
+        .unwrap();
+    let engine = election(&clock);
+
+    // Charlie cannot pass off Bob's output as his own.
+    let mut stolen = header.clone();
+    stolen.consensus_digest.author = Charlie;
+    assert!(!engine.validate(&VrfDigest::genesis(), &stolen));
+
+    // Nor can an output below the threshold be made up.
+    let mut made_up = header.clone();
+    made_up.consensus_digest.vrf.output = [0; 32];
+    assert!(!engine.validate(&VrfDigest::genesis(), &made_up));
+}
+
+#[test]
+fn test_slots_must_increase_and_have_started() {
+    let clock = MockClock::new(0);
+    let engine = election(&clock);
+    let first = next_slot_led_by(&engine, Alice, 0);
+    let second = next_slot_led_by(&engine, Alice, first);
+    let alice = engine.sealing_as(Alice);
+    clock.set(first * 1000);
+
+    let header = alice
+        .seal(&VrfDigest::genesis(), partial_header(1))
+        .unwrap();
+    let parent = header.consensus_digest;
+    assert_eq!(parent.slot, first);
+    // Alice's next slot has not started yet.
+    clock.set(second * 1000 - 1);
+    assert_eq!(alice.seal(&parent, partial_header(2)), None);
+
+    let early = Header {
+        consensus_digest: VrfDigest {
+            slot: second,
+            author: Alice,
+            vrf: VrfOutput::evaluate(Alice, second),
+        },
+        ..header.clone()
+    };
+    assert!(!alice.validate(&parent, &early));
+    clock.advance(1);
+    assert!(alice.validate(&parent, &early));
+    assert!(!alice.validate(&early.consensus_digest, &header));
+}
@@ -0,0 +1,296 @@
+//! The dynamic PoA engine of part 7 gives every staker an equal turn, however little they staked.
+//! In a Proof of Stake chain, a validator's chance of authoring a block is instead proportional to
+//! its stake, so that controlling the chain costs as much as owning the stake behind it.
+//!
+//! Reading the stake afresh for every block has a problem: a validator who sees that bonding a
+//! little more would make them the author of the next block can do exactly that. Real chains
+//! therefore take a snapshot of the stake distribution at the start of every epoch and elect all
+//! of the epoch's authors from it. Here the snapshot is carried in each block's digest. The first
+//! block of an epoch records the distribution in its parent state, and every later block of the
+//! epoch must carry the same one.
+
+use crate::c1_state_machine::p8_staking::StakingState;
+use crate::hash;
+
+use super::equivocation::SlotAuthorship;
+use super::{ConsensusAuthority, Header, StatefulConsensus};
+
+/// Each validator and the stake behind it
+pub type StakeDistribution = Vec<(ConsensusAuthority, u128)>;
+
+/// The digest of a stake-weighted PoS block
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PosDigest {
+    pub author: ConsensusAuthority,
+    /// The stake distribution the block's epoch elects its authors from
+    pub snapshot: StakeDistribution,
+}
+
+/// A Proof of Stake engine that elects each block's author at random, weighted by the stake in
+/// the epoch's snapshot. Stake is read from the chain's state by the given lookup function.
+pub struct StakeWeightedPos<Lookup> {
+    /// How many blocks make up an epoch. Always at least one.
+    epoch_length: u64,
+    pub stakes_of: Lookup,
+}
+
+impl<Lookup> StakeWeightedPos<Lookup> {
+    pub fn new(epoch_length: u64, stakes_of: Lookup) -> Self {
+        StakeWeightedPos {
+            epoch_length: epoch_length.max(1),
+            stakes_of,
+        }
+    }
+
+    /// The epoch the block at the given height belongs to.
+    pub fn epoch_of(&self, height: u64) -> u64 {
+        height / self.epoch_length
+    }
+
+    /// The digest of a genesis block with the given state, whose distribution starts epoch 0.
+    pub fn genesis_digest<State>(&self, genesis_state: &State) -> PosDigest
+    where
+        Lookup: Fn(&State) -> StakeDistribution,
+    {
+        let snapshot = (self.stakes_of)(genesis_state);
+        PosDigest {
+            author: snapshot
+                .first()
+                .map_or(ConsensusAuthority::Alice, |(a, _)| *a),
+            snapshot,
+        }
+    }
+
+    /// The snapshot the block at the given height must elect its author from.
+    fn snapshot_for<State>(
+        &self,
+        parent_state: &State,
+        parent_digest: &PosDigest,
+        height: u64,
+    ) -> StakeDistribution
+    where
+        Lookup: Fn(&State) -> StakeDistribution,
+    {
+        if height.is_multiple_of(self.epoch_length) {
+            (self.stakes_of)(parent_state)
+        } else {
+            parent_digest.snapshot.clone()
+        }
+    }
+}
+
+impl StakeWeightedPos<fn(&StakingState) -> StakeDistribution> {
+    /// A Proof of Stake engine over the staking state machine's validators.
+    pub fn staked(epoch_length: u64) -> Self {
+        StakeWeightedPos::new(epoch_length, staked_distribution)
+    }
+}
+
+/// The stake behind every validator of the given staking state, most staked first.
+pub fn staked_distribution(state: &StakingState) -> StakeDistribution {
+    state
+        .top_stakers(usize::MAX)
+        .into_iter()
+        .filter(|(_, stake)| *stake > 0)
+        .map(|(validator, stake)| (validator.into(), stake))
+        .collect()
+}
+
+/// The author of the block at the given height, picked with probability proportional to stake.
+/// Returns None if there is no stake at all.
+pub fn weighted_author(snapshot: &StakeDistribution, height: u64) -> Option<ConsensusAuthority> {
+    let total: u128 = snapshot.iter().map(|(_, stake)| stake).sum();
+    if total == 0 {
+        return None;
+    }
+    let mut ticket = u128::from(hash(&(b"pos-election", height))) % total;
+    for (authority, stake) in snapshot {
+        if ticket < *stake {
+            return Some(*authority);
+        }
+        ticket -= stake;
+    }
+    unreachable!("the ticket is below the total stake")
+}
+
+impl<State, Lookup> StatefulConsensus<State> for StakeWeightedPos<Lookup>
+where
+    Lookup: Fn(&State) -> StakeDistribution,
+{
+    type Digest = PosDigest;
+
+    /// Check that the header carries its epoch's snapshot and is signed by the author elected
+    /// from it.
+    fn validate_with_state(
+        &self,
+        parent_state: &State,
+        parent_digest: &Self::Digest,
+        header: &Header<Self::Digest>,
+    ) -> bool {
+        let snapshot = self.snapshot_for(parent_state, parent_digest, header.height);
+        header.consensus_digest.snapshot == snapshot
+            && weighted_author(&snapshot, header.height) == Some(header.consensus_digest.author)
+    }
+
+    /// Sign the given partial header by its elected author.
+    fn seal_with_state(
+        &self,
+        parent_state: &State,
+        parent_digest: &Self::Digest,
+        partial_header: Header<()>,
+    ) -> Option<Header<Self::Digest>> {
+        let snapshot = self.snapshot_for(parent_state, parent_digest, partial_header.height);
+        let author = weighted_author(&snapshot, partial_header.height)?;
+        Some(Header {
+            parent: partial_header.parent,
+            height: partial_header.height,
+            state_root: partial_header.state_root,
+            extrinsics_root: partial_header.extrinsics_root,
+            consensus_digest: PosDigest { author, snapshot },
+        })
+    }
+}
+
+/// Every height is a slot with a single elected author. The snapshot the author was elected from
+/// is in the digest, so the election can be checked without the chain's state.
+impl<Lookup> SlotAuthorship<PosDigest> for StakeWeightedPos<Lookup> {
+    fn slot_author(&self, header: &Header<PosDigest>) -> Option<(u64, ConsensusAuthority)> {
+        let digest = &header.consensus_digest;
+        (weighted_author(&digest.snapshot, header.height) == Some(digest.author))
+            .then_some((header.height, digest.author))
+    }
+}
+
+#[cfg(test)]
+use crate::c1_state_machine::{
+    p8_staking::{Staking, StakingTransaction::Bond},
+    StateMachine, User,
+};
+
+#[cfg(test)]
+fn partial_header(height: u64) -> Header<()> {
+    Header {
+        parent: 0,
+        height,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: (),
+    }
+}
+
+/// Everyone endowed with 1000, Alice bonding 300 and Bob 100.
+#[cfg(test)]
+fn three_to_one() -> StakingState {
+    let genesis = Staking::genesis_state(vec![
+        (User::Alice, 1000),
+        (User::Bob, 1000),
+        (User::Charlie, 1000),
+    ]);
+    Staking::apply_all(
+        &genesis,
+        &[
+            Bond {
+                who: User::Alice,
+                amount: 300,
+            },
+            Bond {
+                who: User::Bob,
+                amount: 100,
+            },
+        ],
+    )
+}
+
+#[test]
+fn test_authorship_is_weighted_by_stake() {
+    let pos = StakeWeightedPos::staked(1000);
+    let state = three_to_one();
+    let genesis = pos.genesis_digest(&state);
+
+    let by_alice = (1..=400)
+        .map(|h| pos.seal_with_state(&state, &genesis, partial_header(h)))
+        .filter(|h| h.as_ref().unwrap().consensus_digest.author == ConsensusAuthority::Alice)
+        .count();
+
+    assert!((250..=350).contains(&by_alice), "Alice authored {by_alice}");
+}
+
+#[test]
+fn test_mid_epoch_stake_changes_wait_for_next_epoch() {
+    let pos = StakeWeightedPos::staked(10);
+    let before = three_to_one();
+    let after = Staking::next_state(
+        &before,
+        &Bond {
+            who: User::Charlie,
+            amount: 1000,
+        },
+    );
+    let genesis = pos.genesis_digest(&before);
+
+    // Charlie bonded during epoch 0, but its blocks are still elected from the genesis snapshot.
+    for height in 1..10 {
+        let sealed = pos
+            .seal_with_state(&after, &genesis, partial_header(height))
+            .unwrap();
+        assert_eq!(sealed.consensus_digest.snapshot, genesis.snapshot);
+        assert_ne!(sealed.consensus_digest.author, ConsensusAuthority::Charlie);
+        assert!(pos.validate_with_state(&before, &genesis, &sealed));
+    }
+
+    // The first block of epoch 1 takes a new snapshot, which includes Charlie.
+    let sealed = pos
+        .seal_with_state(&after, &genesis, partial_header(10))
+        .unwrap();
+    assert_eq!(
+        sealed.consensus_digest.snapshot[0],
+        (ConsensusAuthority::Charlie, 1000)
+    );
+    assert!(!pos.validate_with_state(&before, &genesis, &sealed));
+}
+
+#[test]
+fn test_wrong_author_or_snapshot_is_rejected() {
+    let pos = StakeWeightedPos::staked(10);
+    let state = three_to_one();
+    let genesis = pos.genesis_digest(&state);
+    let header = pos
+        .seal_with_state(&state, &genesis, partial_header(3))
+        .unwrap();
+
+    let mut impostor = header.clone();
+    impostor.consensus_digest.author = ConsensusAuthority::Charlie;
+    assert!(!pos.validate_with_state(&state, &genesis, &impostor));
+
+    // Claiming the whole stake mid-epoch
+    let mut rewritten = header.clone();
+    rewritten.consensus_digest.snapshot = vec![(header.consensus_digest.author, 1)];
+    assert!(!pos.validate_with_state(&state, &genesis, &rewritten));
+}
+
+#[test]
+fn test_no_stake_no_blocks() {
+    let pos = StakeWeightedPos::staked(10);
+    let nobody_staked = Staking::genesis_state(vec![(User::Alice, 100)]);
+    let genesis = pos.genesis_digest(&nobody_staked);
+
+    assert_eq!(
+        pos.seal_with_state(&nobody_staked, &genesis, partial_header(1)),
+        None
+    );
+}
+
+#[test]
+fn test_custom_stake_lookup() {
+    let pos = StakeWeightedPos::new(5, |weights: &StakeDistribution| weights.clone());
+    let only_bob = vec![(ConsensusAuthority::Bob, 7)];
+    let genesis = pos.genesis_digest(&only_bob);
+    let header = pos
+        .seal_with_state(&only_bob, &genesis, partial_header(1))
+        .unwrap();
+
+    assert_eq!(header.consensus_digest.author, ConsensusAuthority::Bob);
+    assert_eq!(pos.epoch_of(header.height), 0);
+    assert_eq!(pos.epoch_of(5), 1);
+}
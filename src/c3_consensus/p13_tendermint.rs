@@ -0,0 +1,829 @@
+//! The engines so far seal a block first and let the chain decide later whether it sticks. In
+//! Tendermint the validators agree on every block before it is added, so a block is final as soon
+//! as it exists. Agreement on each height takes one or more rounds. In each round a rotating
+//! proposer proposes a block, and the validators prevote and then precommit for it. A block with
+//! precommits from more than two thirds of the validators is decided. If a round fails, because
+//! the proposer was offline or the votes split, the validators time out and start the next round
+//! with the next proposer.
+//!
+//! Each validator runs the protocol as a state machine in the sense of chapter 1. Its inputs are
+//! the messages it receives and the timeouts it scheduled, and its events are the messages it
+//! broadcasts, the timeouts it schedules, and the blocks it decides. Nothing in the machine knows
+//! about sockets or clocks, so validators can be wired together by any network, real or simulated.
+//! The rules follow "The latest gossip on BFT consensus" by Buchman, Kwon, and Milosevic.
+//!
+//! The values the validators agree on are the hashes of partial headers. A header sealed by this
+//! engine carries the commit of the round that decided it.
+
+use std::collections::{BTreeMap, HashSet};
+
+use crate::c1_state_machine::{EventfulStateMachine, StateMachine};
+
+use super::{Consensus, ConsensusAuthority, Header};
+
+/// The hash of the partial header being agreed on
+pub type Value = u64;
+
+/// The steps of a round, in the order they happen
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Step {
+    Propose,
+    Prevote,
+    Precommit,
+}
+
+/// The messages validators send each other
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Message {
+    /// The round's proposer proposes a value. `valid_round` is the round in which the proposer
+    /// saw more than two thirds prevote for it, if it is re-proposing an earlier value.
+    Proposal {
+        height: u64,
+        round: u64,
+        value: Value,
+        valid_round: Option<u64>,
+        from: ConsensusAuthority,
+    },
+    /// A vote for the round's proposal, or for nothing
+    Prevote {
+        height: u64,
+        round: u64,
+        value: Option<Value>,
+        from: ConsensusAuthority,
+    },
+    /// A vote to decide the round's proposal, or nothing
+    Precommit {
+        height: u64,
+        round: u64,
+        value: Option<Value>,
+        from: ConsensusAuthority,
+    },
+}
+
+impl Message {
+    /// The height the message is for.
+    pub fn height(&self) -> u64 {
+        match self {
+            Message::Proposal { height, .. }
+            | Message::Prevote { height, .. }
+            | Message::Precommit { height, .. } => *height,
+        }
+    }
+
+    /// The round the message is for.
+    pub fn round(&self) -> u64 {
+        match self {
+            Message::Proposal { round, .. }
+            | Message::Prevote { round, .. }
+            | Message::Precommit { round, .. } => *round,
+        }
+    }
+
+    /// The validator who sent the message.
+    pub fn from(&self) -> ConsensusAuthority {
+        match self {
+            Message::Proposal { from, .. }
+            | Message::Prevote { from, .. }
+            | Message::Precommit { from, .. } => *from,
+        }
+    }
+}
+
+/// The inputs a validator reacts to
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TendermintInput {
+    /// Start the current round. Validators are started once, after which rounds and heights
+    /// follow from the messages and timeouts they receive.
+    Start,
+    /// Propose the given value whenever this validator is the proposer and has no value from
+    /// an earlier round to re-propose. Candidates are cleared once their height is decided.
+    SetCandidate(Value),
+    Receive(Message),
+    /// A timeout scheduled earlier has expired.
+    Timeout {
+        height: u64,
+        round: u64,
+        step: Step,
+    },
+}
+
+/// The things a validator does in response to an input
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum TendermintEvent {
+    /// Send the message to every other validator.
+    Broadcast(Message),
+    /// Feed back a `Timeout` input with the same fields after a while.
+    ScheduleTimeout { height: u64, round: u64, step: Step },
+    /// The value was decided at the given height, with the given commit.
+    Decided {
+        height: u64,
+        value: Value,
+        commit: Commit,
+    },
+}
+
+/// The reasons an input may be rejected
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TendermintError {
+    /// The message was sent by someone who is not a validator
+    NotAValidator(ConsensusAuthority),
+    /// The message is for a height that has already been decided
+    StaleMessage,
+    /// The timeout is for a step that has already passed
+    StaleTimeout,
+    /// The sender already sent a different message of the same kind for the same round
+    Equivocation(Message),
+    /// Validators can only be started once
+    AlreadyStarted,
+}
+
+/// Proof that a value was decided: the round it was decided in and the validators who
+/// precommitted for it
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Commit {
+    pub round: u64,
+    pub signers: Vec<ConsensusAuthority>,
+}
+
+/// Rules that only fire once per round
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum OnceRule {
+    PrevoteTimeout,
+    PrecommitTimeout,
+    Lock,
+}
+
+/// A single validator's view of the protocol
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TendermintState {
+    /// The validator running this state
+    pub me: ConsensusAuthority,
+    pub validators: Vec<ConsensusAuthority>,
+    /// The height being agreed on
+    pub height: u64,
+    pub round: u64,
+    pub step: Step,
+    pub started: bool,
+    /// The value this validator proposes when it has nothing better
+    pub candidate: Option<Value>,
+    /// The value this validator precommitted for, and the round it did so in. It will only
+    /// prevote for other values once more than two thirds prevote for them in a later round.
+    pub locked: Option<(u64, Value)>,
+    /// The latest value more than two thirds prevoted for, and the round they did so in
+    pub valid: Option<(u64, Value)>,
+    /// The messages received for the current height and later ones
+    messages: Vec<Message>,
+    fired: HashSet<(u64, OnceRule)>,
+    /// The value decided at each height
+    pub decisions: BTreeMap<u64, Value>,
+}
+
+impl TendermintState {
+    /// The validator that proposes in the given round at the given height. The proposer rotates
+    /// with both, so a different validator gets the first chance at each height.
+    pub fn proposer(&self, height: u64, round: u64) -> ConsensusAuthority {
+        let n = self.validators.len() as u64;
+        // The remainder is less than the number of validators, so it fits in a usize.
+        self.validators[(height.wrapping_add(round) % n) as usize]
+    }
+
+    /// How many validators make more than two thirds
+    pub fn quorum(&self) -> usize {
+        2 * self.validators.len() / 3 + 1
+    }
+
+    /// How many validators make more than a third, enough to include an honest one
+    fn weak_quorum(&self) -> usize {
+        self.validators.len() / 3 + 1
+    }
+
+    fn proposal(&self, round: u64) -> Option<(Value, Option<u64>)> {
+        let proposer = self.proposer(self.height, round);
+        self.messages.iter().find_map(|m| match *m {
+            Message::Proposal {
+                height,
+                round: r,
+                value,
+                valid_round,
+                from,
+            } if height == self.height && r == round && from == proposer => {
+                Some((value, valid_round))
+            }
+            _ => None,
+        })
+    }
+
+    /// The validators who prevoted (or precommitted) in the given round, with their votes
+    fn votes(
+        &self,
+        step: Step,
+        round: u64,
+    ) -> impl Iterator<Item = (ConsensusAuthority, Option<Value>)> + '_ {
+        self.messages.iter().filter_map(move |m| match (*m, step) {
+            (
+                Message::Prevote {
+                    height,
+                    round: r,
+                    value,
+                    from,
+                },
+                Step::Prevote,
+            )
+            | (
+                Message::Precommit {
+                    height,
+                    round: r,
+                    value,
+                    from,
+                },
+                Step::Precommit,
+            ) if height == self.height && r == round => Some((from, value)),
+            _ => None,
+        })
+    }
+
+    fn vote_count(&self, step: Step, round: u64, value: Option<Option<Value>>) -> usize {
+        self.votes(step, round)
+            .filter(|(_, v)| value.is_none_or(|value| *v == value))
+            .count()
+    }
+
+    fn record(&mut self, message: Message) -> Result<(), TendermintError> {
+        if !self.validators.contains(&message.from()) {
+            return Err(TendermintError::NotAValidator(message.from()));
+        }
+        if message.height() < self.height {
+            return Err(TendermintError::StaleMessage);
+        }
+        let same_slot = |m: &Message| {
+            std::mem::discriminant(m) == std::mem::discriminant(&message)
+                && m.height() == message.height()
+                && m.round() == message.round()
+                && m.from() == message.from()
+        };
+        match self.messages.iter().find(|m| same_slot(m)) {
+            Some(m) if *m == message => Ok(()),
+            Some(_) => Err(TendermintError::Equivocation(message)),
+            None => {
+                self.messages.push(message);
+                Ok(())
+            }
+        }
+    }
+
+    fn broadcast(&mut self, message: Message, events: &mut Vec<TendermintEvent>) {
+        self.messages.push(message);
+        events.push(TendermintEvent::Broadcast(message));
+    }
+
+    fn vote(&mut self, step: Step, value: Option<Value>, events: &mut Vec<TendermintEvent>) {
+        let (height, round, from) = (self.height, self.round, self.me);
+        let message = match step {
+            Step::Prevote => Message::Prevote {
+                height,
+                round,
+                value,
+                from,
+            },
+            _ => Message::Precommit {
+                height,
+                round,
+                value,
+                from,
+            },
+        };
+        self.broadcast(message, events);
+        self.step = step;
+    }
+
+    fn fire_once(&mut self, rule: OnceRule) -> bool {
+        self.fired.insert((self.round, rule))
+    }
+
+    fn start_round(&mut self, round: u64, events: &mut Vec<TendermintEvent>) {
+        self.round = round;
+        self.step = Step::Propose;
+        let value = self.valid.map(|(_, v)| v).or(self.candidate);
+        match value {
+            Some(value) if self.proposer(self.height, round) == self.me => {
+                let message = Message::Proposal {
+                    height: self.height,
+                    round,
+                    value,
+                    valid_round: self.valid.map(|(r, _)| r),
+                    from: self.me,
+                };
+                self.broadcast(message, events);
+            }
+            _ => events.push(TendermintEvent::ScheduleTimeout {
+                height: self.height,
+                round,
+                step: Step::Propose,
+            }),
+        }
+    }
+
+    /// Apply the first rule whose conditions hold, returning whether there was one.
+    fn apply_rule(&mut self, events: &mut Vec<TendermintEvent>) -> bool {
+        let round = self.round;
+        let quorum = self.quorum();
+
+        // A value with a precommit quorum in any round is decided.
+        let decided = self.messages.iter().find_map(|m| match *m {
+            Message::Proposal {
+                height,
+                round: r,
+                value,
+                ..
+            } if height == self.height
+                && self.proposal(r).map(|(v, _)| v) == Some(value)
+                && self.vote_count(Step::Precommit, r, Some(Some(value))) >= quorum =>
+            {
+                Some((r, value))
+            }
+            _ => None,
+        });
+        if let Some((r, value)) = decided {
+            let signers = self
+                .votes(Step::Precommit, r)
+                .filter(|(_, v)| *v == Some(value))
+                .map(|(from, _)| from)
+                .collect();
+            events.push(TendermintEvent::Decided {
+                height: self.height,
+                value,
+                commit: Commit { round: r, signers },
+            });
+            self.decisions.insert(self.height, value);
+            self.height += 1;
+            self.locked = None;
+            self.valid = None;
+            self.candidate = None;
+            self.fired.clear();
+            let height = self.height;
+            self.messages.retain(|m| m.height() >= height);
+            self.start_round(0, events);
+            return true;
+        }
+
+        // More than a third of the validators are in a later round, so at least one honest
+        // validator is. Catch up with them.
+        let later_round = self
+            .messages
+            .iter()
+            .filter(|m| m.height() == self.height && m.round() > round)
+            .map(|m| m.round())
+            .find(|r| {
+                let senders: HashSet<_> = self
+                    .messages
+                    .iter()
+                    .filter(|m| m.height() == self.height && m.round() == *r)
+                    .map(|m| m.from())
+                    .collect();
+                senders.len() >= self.weak_quorum()
+            });
+        if let Some(r) = later_round {
+            self.start_round(r, events);
+            return true;
+        }
+
+        let proposal = self.proposal(round);
+        if self.step == Step::Propose {
+            if let Some((value, valid_round)) = proposal {
+                let acceptable = match valid_round {
+                    None => self.locked.is_none_or(|(_, locked)| locked == value),
+                    Some(vr)
+                        if vr < round
+                            && self.vote_count(Step::Prevote, vr, Some(Some(value))) >= quorum =>
+                    {
+                        self.locked
+                            .is_none_or(|(lr, locked)| lr <= vr || locked == value)
+                    }
+                    // Wait for the prevotes that justify re-proposing the value.
+                    Some(_) => return false,
+                };
+                self.vote(Step::Prevote, acceptable.then_some(value), events);
+                return true;
+            }
+        }
+
+        if self.step >= Step::Prevote {
+            if let Some((value, _)) = proposal {
+                if self.vote_count(Step::Prevote, round, Some(Some(value))) >= quorum
+                    && self.fire_once(OnceRule::Lock)
+                {
+                    if self.step == Step::Prevote {
+                        self.locked = Some((round, value));
+                        self.vote(Step::Precommit, Some(value), events);
+                    }
+                    self.valid = Some((round, value));
+                    return true;
+                }
+            }
+        }
+
+        if self.step == Step::Prevote {
+            if self.vote_count(Step::Prevote, round, Some(None)) >= quorum {
+                self.vote(Step::Precommit, None, events);
+                return true;
+            }
+            if self.vote_count(Step::Prevote, round, None) >= quorum
+                && self.fire_once(OnceRule::PrevoteTimeout)
+            {
+                events.push(TendermintEvent::ScheduleTimeout {
+                    height: self.height,
+                    round,
+                    step: Step::Prevote,
+                });
+                return true;
+            }
+        }
+
+        if self.vote_count(Step::Precommit, round, None) >= quorum
+            && self.fire_once(OnceRule::PrecommitTimeout)
+        {
+            events.push(TendermintEvent::ScheduleTimeout {
+                height: self.height,
+                round,
+                step: Step::Precommit,
+            });
+            return true;
+        }
+
+        false
+    }
+}
+
+/// The Tendermint protocol, as run by a single validator
+pub struct Tendermint;
+
+impl StateMachine for Tendermint {
+    type State = TendermintState;
+    type Transition = TendermintInput;
+    type Error = TendermintError;
+    /// The validator running the machine, and every validator including it
+    type GenesisConfig = (ConsensusAuthority, Vec<ConsensusAuthority>);
+
+    /// A validator that has not started yet, about to agree on height 1.
+    fn genesis_state((me, validators): Self::GenesisConfig) -> Self::State {
+        TendermintState {
+            me,
+            validators,
+            height: 1,
+            round: 0,
+            step: Step::Propose,
+            started: false,
+            candidate: None,
+            locked: None,
+            valid: None,
+            messages: Vec::new(),
+            fired: HashSet::new(),
+            decisions: BTreeMap::new(),
+        }
+    }
+
+    /// Rejected inputs leave the state unchanged.
+    fn next_state(starting_state: &Self::State, t: &Self::Transition) -> Self::State {
+        Self::try_next_state(starting_state, t).unwrap_or_else(|_| starting_state.clone())
+    }
+
+    fn try_next_state(
+        starting_state: &Self::State,
+        t: &Self::Transition,
+    ) -> Result<Self::State, Self::Error> {
+        Self::try_next_state_with_events(starting_state, t).map(|(state, _)| state)
+    }
+}
+
+impl Tendermint {
+    /// Like `try_next_state`, along with what the validator does in response.
+    pub fn try_next_state_with_events(
+        starting_state: &TendermintState,
+        t: &TendermintInput,
+    ) -> Result<(TendermintState, Vec<TendermintEvent>), TendermintError> {
+        let mut state = starting_state.clone();
+        let mut events = Vec::new();
+
+        match *t {
+            TendermintInput::Start if state.started => return Err(TendermintError::AlreadyStarted),
+            TendermintInput::Start => {
+                state.started = true;
+                let round = state.round;
+                state.start_round(round, &mut events);
+            }
+            TendermintInput::SetCandidate(value) => {
+                state.candidate = Some(value);
+                // A proposer that had nothing to propose when the round started proposes now.
+                let round = state.round;
+                if state.started
+                    && state.step == Step::Propose
+                    && state.proposer(state.height, round) == state.me
+                    && state.proposal(round).is_none()
+                {
+                    state.start_round(round, &mut events);
+                }
+            }
+            TendermintInput::Receive(message) => state.record(message)?,
+            TendermintInput::Timeout {
+                height,
+                round,
+                step,
+            } => {
+                if height != state.height || round != state.round {
+                    return Err(TendermintError::StaleTimeout);
+                }
+                match step {
+                    Step::Propose if state.step == Step::Propose => {
+                        state.vote(Step::Prevote, None, &mut events)
+                    }
+                    Step::Prevote if state.step == Step::Prevote => {
+                        state.vote(Step::Precommit, None, &mut events)
+                    }
+                    Step::Precommit => state.start_round(round + 1, &mut events),
+                    _ => return Err(TendermintError::StaleTimeout),
+                }
+            }
+        }
+
+        if state.started {
+            while state.apply_rule(&mut events) {}
+        }
+        Ok((state, events))
+    }
+}
+
+impl EventfulStateMachine for Tendermint {
+    type Event = TendermintEvent;
+
+    fn next_state_with_events(
+        starting_state: &Self::State,
+        t: &Self::Transition,
+    ) -> (Self::State, Vec<Self::Event>) {
+        Self::try_next_state_with_events(starting_state, t)
+            .unwrap_or_else(|_| (starting_state.clone(), Vec::new()))
+    }
+}
+
+/// A consensus engine accepting headers decided by Tendermint among the given validators.
+/// Headers are not sealed by a single author but by running the protocol, so `seal` always
+/// returns None. Use `seal_with_commit` with the commit of the round that decided the header.
+pub struct TendermintCommits {
+    pub validators: Vec<ConsensusAuthority>,
+}
+
+impl TendermintCommits {
+    /// Attach the given commit to the partial header.
+    pub fn seal_with_commit(&self, partial_header: Header<()>, commit: Commit) -> Header<Commit> {
+        Header {
+            parent: partial_header.parent,
+            height: partial_header.height,
+            state_root: partial_header.state_root,
+            extrinsics_root: partial_header.extrinsics_root,
+            consensus_digest: commit,
+        }
+    }
+}
+
+impl Consensus for TendermintCommits {
+    type Digest = Commit;
+
+    /// Check that more than two thirds of the validators signed the commit.
+    fn validate(&self, _: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        let signers: HashSet<_> = header.consensus_digest.signers.iter().collect();
+        signers.len() == header.consensus_digest.signers.len()
+            && signers.iter().all(|s| self.validators.contains(s))
+            && signers.len() > 2 * self.validators.len() / 3
+    }
+
+    fn seal(&self, _: &Self::Digest, _: Header<()>) -> Option<Header<Self::Digest>> {
+        None
+    }
+
+    fn human_name() -> String {
+        "Tendermint".into()
+    }
+
+    fn create_default_instance() -> Self {
+        TendermintCommits {
+            validators: vec![
+                ConsensusAuthority::Alice,
+                ConsensusAuthority::Bob,
+                ConsensusAuthority::Charlie,
+            ],
+        }
+    }
+}
+
+#[cfg(test)]
+use ConsensusAuthority::{Alice, Bob, Charlie};
+
+/// Validators wired together by an in-memory network that delivers every broadcast to every
+/// other validator, and fires timeouts only once the network is quiet.
+#[cfg(test)]
+struct Network {
+    nodes: Vec<TendermintState>,
+    decided: Vec<Vec<(u64, Value, Commit)>>,
+}
+
+#[cfg(test)]
+impl Network {
+    fn new(validators: &[ConsensusAuthority]) -> Self {
+        Network {
+            nodes: validators
+                .iter()
+                .map(|me| Tendermint::genesis_state((*me, validators.to_vec())))
+                .collect(),
+            decided: vec![Vec::new(); validators.len()],
+        }
+    }
+
+    /// Feed the inputs to the given nodes and everything that follows from them, until no
+    /// messages or timeouts are left or the given height is decided by everyone.
+    fn run(&mut self, inputs: Vec<(usize, TendermintInput)>, until_height: u64) {
+        let mut queue = std::collections::VecDeque::from(inputs);
+        let mut timeouts = Vec::new();
+        loop {
+            if self.nodes.iter().all(|n| n.height > until_height) {
+                return;
+            }
+            let Some((i, input)) = queue.pop_front() else {
+                if timeouts.is_empty() {
+                    return;
+                }
+                queue.extend(timeouts.drain(..));
+                continue;
+            };
+            let Ok((state, events)) =
+                Tendermint::try_next_state_with_events(&self.nodes[i], &input)
+            else {
+                continue;
+            };
+            self.nodes[i] = state;
+            for event in events {
+                match event {
+                    TendermintEvent::Broadcast(m) => {
+                        for j in (0..self.nodes.len()).filter(|j| *j != i) {
+                            queue.push_back((j, TendermintInput::Receive(m)));
+                        }
+                    }
+                    TendermintEvent::ScheduleTimeout {
+                        height,
+                        round,
+                        step,
+                    } => timeouts.push((
+                        i,
+                        TendermintInput::Timeout {
+                            height,
+                            round,
+                            step,
+                        },
+                    )),
+                    TendermintEvent::Decided {
+                        height,
+                        value,
+                        commit,
+                    } => self.decided[i].push((height, value, commit)),
+                }
+            }
+        }
+    }
+
+    fn start(&mut self, candidates: &[Option<Value>], until_height: u64) {
+        let mut inputs = Vec::new();
+        for (i, candidate) in candidates.iter().enumerate() {
+            if let Some(value) = candidate {
+                inputs.push((i, TendermintInput::SetCandidate(*value)));
+            }
+        }
+        inputs.extend((0..self.nodes.len()).map(|i| (i, TendermintInput::Start)));
+        self.run(inputs, until_height);
+    }
+}
+
+#[test]
+fn test_single_validator_decides_alone() {
+    let state = Tendermint::genesis_state((Alice, vec![Alice]));
+    let state = Tendermint::next_state(&state, &TendermintInput::SetCandidate(7));
+    let (state, events) =
+        Tendermint::try_next_state_with_events(&state, &TendermintInput::Start).unwrap();
+
+    assert_eq!(state.decisions.get(&1), Some(&7));
+    assert_eq!(state.height, 2);
+    assert!(events.contains(&TendermintEvent::Decided {
+        height: 1,
+        value: 7,
+        commit: Commit {
+            round: 0,
+            signers: vec![Alice]
+        },
+    }));
+}
+
+#[test]
+fn test_validators_agree_on_the_proposal() {
+    let mut network = Network::new(&[Alice, Bob, Charlie]);
+    network.start(&[Some(10), Some(11), Some(12)], 1);
+
+    // Height 1 round 0 is Bob's to propose.
+    for decided in &network.decided {
+        assert_eq!(decided[0].0, 1);
+        assert_eq!(decided[0].1, 11);
+        assert_eq!(decided[0].2.round, 0);
+        assert_eq!(decided[0].2.signers.len(), 3);
+    }
+    assert!(network.nodes.iter().all(|n| n.decisions[&1] == 11));
+}
+
+#[test]
+fn test_silent_proposer_is_skipped() {
+    let mut network = Network::new(&[Alice, Bob, Charlie]);
+    // Bob has nothing to propose, so round 0 times out and Charlie proposes in round 1.
+    network.start(&[Some(10), None, Some(12)], 1);
+
+    for decided in &network.decided {
+        assert_eq!((decided[0].1, decided[0].2.round), (12, 1));
+    }
+}
+
+#[test]
+fn test_consecutive_heights_rotate_proposers() {
+    let mut network = Network::new(&[Alice, Bob, Charlie]);
+    network.start(&[Some(10), Some(11), Some(12)], 1);
+    let next = (0..3)
+        .map(|i| (i, TendermintInput::SetCandidate(20 + i as u64)))
+        .collect();
+    network.run(next, 2);
+
+    // Height 2 round 0 is Charlie's to propose.
+    assert!(network
+        .nodes
+        .iter()
+        .all(|n| n.decisions.get(&2) == Some(&22)));
+}
+
+#[test]
+fn test_invalid_inputs_are_rejected() {
+    let state = Tendermint::genesis_state((Alice, vec![Alice, Bob]));
+    let prevote = |value, from| {
+        TendermintInput::Receive(Message::Prevote {
+            height: 1,
+            round: 0,
+            value,
+            from,
+        })
+    };
+
+    assert_eq!(
+        Tendermint::try_next_state(&state, &prevote(Some(1), Charlie)),
+        Err(TendermintError::NotAValidator(Charlie))
+    );
+    let state = Tendermint::next_state(&state, &prevote(Some(1), Bob));
+    assert!(Tendermint::try_next_state(&state, &prevote(Some(1), Bob)).is_ok());
+    assert!(matches!(
+        Tendermint::try_next_state(&state, &prevote(None, Bob)),
+        Err(TendermintError::Equivocation(_))
+    ));
+    assert_eq!(
+        Tendermint::try_next_state(
+            &state,
+            &TendermintInput::Timeout {
+                height: 1,
+                round: 3,
+                step: Step::Precommit
+            }
+        ),
+        Err(TendermintError::StaleTimeout)
+    );
+}
+
+#[test]
+fn test_commit_needs_two_thirds_of_validators() {
+    let engine = TendermintCommits::create_default_instance();
+    let partial = Header {
+        parent: 0,
+        height: 1,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: (),
+    };
+    let commit = |signers: Vec<ConsensusAuthority>| Commit { round: 0, signers };
+    let genesis = commit(vec![]);
+
+    assert!(engine.validate(
+        &genesis,
+        &engine.seal_with_commit(partial.clone(), commit(vec![Alice, Bob, Charlie]))
+    ));
+    assert!(!engine.validate(
+        &genesis,
+        &engine.seal_with_commit(partial.clone(), commit(vec![Alice, Bob]))
+    ));
+    assert!(!engine.validate(
+        &genesis,
+        &engine.seal_with_commit(partial.clone(), commit(vec![Alice, Alice, Bob]))
+    ));
+    assert_eq!(engine.seal(&genesis, partial), None);
+}
@@ -0,0 +1,312 @@
+//! A finality gadget like the one in `finality` needs its voters online all the time. A lighter
+//! alternative, used by early PoS chains and by the Casper FFG proposal for Ethereum, is to only
+//! finalize every Nth block. Those blocks are checkpoints, and each must be signed by more than
+//! two thirds of a fixed set of checkpoint authorities. Blocks in between are authored by any
+//! engine as usual.
+//!
+//! Clients may also be told about checkpoints out of band, the way Bitcoin clients ship with the
+//! hashes of a few old blocks built in. A chain that puts a different block at the height of a
+//! known checkpoint is rejected outright, however much work it carries. Everything before the
+//! latest checkpoint is settled, so clients may prune the forks that branch off before it.
+//!
+//! Checkpoint signatures cover the header as the inner engine sees it, so a checkpoint is
+//! identified by the hash of that header, whoever signed it.
+
+use std::collections::{BTreeMap, HashSet};
+
+use super::finality::BlockId;
+use super::{Consensus, ConsensusAuthority, Header};
+use crate::hash;
+
+/// The digest of a block under a checkpointing engine, wrapping the digest of the inner engine
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CheckpointDigest<D> {
+    pub inner: D,
+    /// The checkpoint authorities who signed the block. Empty unless the block is a checkpoint.
+    pub signatures: Vec<ConsensusAuthority>,
+}
+
+impl<D> CheckpointDigest<D> {
+    /// The digest of a genesis block, which is trusted without signatures.
+    pub fn genesis(inner: D) -> Self {
+        CheckpointDigest {
+            inner,
+            signatures: Vec::new(),
+        }
+    }
+}
+
+/// The header the inner engine sealed, and the checkpoint authorities sign
+fn inner_header<D: Clone>(header: &Header<CheckpointDigest<D>>) -> Header<D> {
+    Header {
+        parent: header.parent,
+        height: header.height,
+        state_root: header.state_root,
+        extrinsics_root: header.extrinsics_root,
+        consensus_digest: header.consensus_digest.inner.clone(),
+    }
+}
+
+/// The checkpoint a header stands for, if it were one.
+pub fn checkpoint_of<D: Clone + std::hash::Hash>(header: &Header<CheckpointDigest<D>>) -> BlockId {
+    BlockId {
+        height: header.height,
+        hash: hash(&inner_header(header)),
+    }
+}
+
+/// A higher-order consensus engine that makes every `interval`th block a checkpoint, which must
+/// be signed by more than two thirds of the checkpoint authorities. The inner engine seals and
+/// validates every block.
+pub struct Checkpointed<Inner> {
+    pub inner: Inner,
+    /// How many blocks apart checkpoints are. Always at least one.
+    interval: u64,
+    authorities: Vec<ConsensusAuthority>,
+    /// Checkpoints learned out of band, by height
+    known: BTreeMap<u64, u64>,
+}
+
+impl<Inner> Checkpointed<Inner> {
+    /// Checkpoint every `interval`th block, signed by the given authorities. Blocks sealed by
+    /// this engine are signed by all of them.
+    pub fn new(inner: Inner, interval: u64, authorities: Vec<ConsensusAuthority>) -> Self {
+        Checkpointed {
+            inner,
+            interval: interval.max(1),
+            authorities,
+            known: BTreeMap::new(),
+        }
+    }
+
+    /// Trust the given checkpoint, rejecting any chain with a different block at its height.
+    pub fn with_checkpoint(mut self, checkpoint: BlockId) -> Self {
+        self.known.insert(checkpoint.height, checkpoint.hash);
+        self
+    }
+
+    /// Whether the block at the given height is a checkpoint.
+    pub fn is_checkpoint_height(&self, height: u64) -> bool {
+        height != 0 && height.is_multiple_of(self.interval)
+    }
+
+    /// How many distinct authorities make more than two thirds
+    fn quorum(&self) -> usize {
+        2 * self.authorities.len() / 3 + 1
+    }
+
+    /// Whether the given signatures are from more than two thirds of the authorities, each
+    /// signing at most once.
+    fn enough_signatures(&self, signatures: &[ConsensusAuthority]) -> bool {
+        let distinct: HashSet<_> = signatures.iter().collect();
+        distinct.len() == signatures.len()
+            && signatures.iter().all(|s| self.authorities.contains(s))
+            && signatures.len() >= self.quorum()
+    }
+
+    /// Whether the header agrees with the known checkpoint at its height, if there is one.
+    fn agrees_with_known<D: Clone + std::hash::Hash>(
+        &self,
+        header: &Header<CheckpointDigest<D>>,
+    ) -> bool {
+        self.known
+            .get(&header.height)
+            .is_none_or(|known| *known == checkpoint_of(header).hash)
+    }
+
+    /// The latest checkpoint in the given chain, either signed or known. Forks branching off
+    /// before it can be pruned.
+    pub fn latest_checkpoint<D: Clone + std::hash::Hash>(
+        &self,
+        chain: &[Header<CheckpointDigest<D>>],
+    ) -> Option<BlockId> {
+        chain
+            .iter()
+            .rev()
+            .find(|h| {
+                self.known.contains_key(&h.height)
+                    || (self.is_checkpoint_height(h.height)
+                        && self.enough_signatures(&h.consensus_digest.signatures))
+            })
+            .map(checkpoint_of)
+    }
+}
+
+impl<Inner: Consensus> Consensus for Checkpointed<Inner> {
+    type Digest = CheckpointDigest<Inner::Digest>;
+
+    /// Check the header by the inner engine's rules, that checkpoints carry enough signatures and
+    /// other blocks none, and that it agrees with any known checkpoint at its height.
+    fn validate(&self, parent_digest: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        let signatures = &header.consensus_digest.signatures;
+        let signed_correctly = if self.is_checkpoint_height(header.height) {
+            self.enough_signatures(signatures)
+        } else {
+            signatures.is_empty()
+        };
+        signed_correctly
+            && self.agrees_with_known(header)
+            && self
+                .inner
+                .validate(&parent_digest.inner, &inner_header(header))
+    }
+
+    /// Seal the header with the inner engine, and sign it as every authority if it is a
+    /// checkpoint.
+    fn seal(
+        &self,
+        parent_digest: &Self::Digest,
+        partial_header: Header<()>,
+    ) -> Option<Header<Self::Digest>> {
+        let sealed = self.inner.seal(&parent_digest.inner, partial_header)?;
+        let signatures = if self.is_checkpoint_height(sealed.height) {
+            self.authorities.clone()
+        } else {
+            Vec::new()
+        };
+        Some(Header {
+            parent: sealed.parent,
+            height: sealed.height,
+            state_root: sealed.state_root,
+            extrinsics_root: sealed.extrinsics_root,
+            consensus_digest: CheckpointDigest {
+                inner: sealed.consensus_digest,
+                signatures,
+            },
+        })
+    }
+
+    /// Like the provided method, but also checks the first header against the known
+    /// checkpoints, so a chain cannot sneak a contradicting block in as its starting point.
+    fn verify_sub_chain(&self, _: &Self::Digest, chain: &[Header<Self::Digest>]) -> bool {
+        chain.len() != 1
+            && chain.iter().all(|h| self.agrees_with_known(h))
+            && chain
+                .windows(2)
+                .all(|pair| self.validate(&pair[0].consensus_digest, &pair[1]))
+    }
+
+    fn human_name() -> String {
+        format!("Checkpointed {}", Inner::human_name())
+    }
+
+    /// A checkpoint every 100 blocks, signed by Alice, Bob and Charlie.
+    fn create_default_instance() -> Self {
+        Checkpointed::new(
+            Inner::create_default_instance(),
+            100,
+            vec![
+                ConsensusAuthority::Alice,
+                ConsensusAuthority::Bob,
+                ConsensusAuthority::Charlie,
+            ],
+        )
+    }
+}
+
+#[cfg(test)]
+use ConsensusAuthority::{Alice, Bob, Charlie};
+
+#[cfg(test)]
+fn every_fourth() -> Checkpointed<()> {
+    Checkpointed::new((), 4, vec![Alice, Bob, Charlie])
+}
+
+/// A chain of the given length sealed by the given engine, each block's state root the given
+/// value so that forks can be told apart.
+#[cfg(test)]
+fn chain(
+    engine: &Checkpointed<()>,
+    len: u64,
+    state_root: u64,
+) -> Vec<Header<CheckpointDigest<()>>> {
+    let mut chain = vec![Header {
+        parent: 0,
+        height: 0,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: CheckpointDigest::genesis(()),
+    }];
+    for height in 1..len {
+        let parent = chain.last().unwrap();
+        let partial = Header {
+            parent: hash(parent),
+            height,
+            state_root,
+            extrinsics_root: 0,
+            consensus_digest: (),
+        };
+        let header = engine.seal(&parent.consensus_digest, partial).unwrap();
+        chain.push(header);
+    }
+    chain
+}
+
+#[test]
+fn test_checkpoints_are_signed_every_interval() {
+    let engine = every_fourth();
+    let chain = chain(&engine, 10, 1);
+
+    let signed: Vec<u64> = chain
+        .iter()
+        .filter(|h| !h.consensus_digest.signatures.is_empty())
+        .map(|h| h.height)
+        .collect();
+    assert_eq!(signed, vec![4, 8]);
+    assert!(engine.verify_sub_chain(&chain[0].consensus_digest, &chain));
+    assert_eq!(
+        engine.latest_checkpoint(&chain),
+        Some(checkpoint_of(&chain[8]))
+    );
+}
+
+#[test]
+fn test_checkpoint_needs_two_thirds_of_signatures() {
+    let engine = every_fourth();
+    let chain = chain(&engine, 5, 1);
+    let parent = &chain[3].consensus_digest;
+
+    let mut too_few = chain[4].clone();
+    too_few.consensus_digest.signatures = vec![Alice];
+    assert!(!engine.validate(parent, &too_few));
+
+    let mut repeated = chain[4].clone();
+    repeated.consensus_digest.signatures = vec![Alice, Alice, Bob];
+    assert!(!engine.validate(parent, &repeated));
+
+    let mut two_of_three = chain[4].clone();
+    two_of_three.consensus_digest.signatures = vec![Charlie, Alice];
+    assert!(!engine.validate(parent, &two_of_three));
+
+    // Signatures anywhere else are rejected too.
+    let mut unexpected = chain[3].clone();
+    unexpected.consensus_digest.signatures = vec![Alice, Bob, Charlie];
+    assert!(!engine.validate(&chain[2].consensus_digest, &unexpected));
+}
+
+#[test]
+fn test_chain_contradicting_known_checkpoint_is_rejected() {
+    let honest = chain(&every_fourth(), 10, 1);
+    let engine = every_fourth().with_checkpoint(checkpoint_of(&honest[4]));
+    let fork = chain(&engine, 10, 2);
+
+    assert!(engine.verify_sub_chain(&honest[0].consensus_digest, &honest));
+    assert!(!engine.verify_sub_chain(&fork[0].consensus_digest, &fork));
+    // Starting the chain at the contradicting block does not help.
+    assert!(!engine.verify_sub_chain(&fork[3].consensus_digest, &fork[4..]));
+}
+
+#[test]
+fn test_known_checkpoint_counts_without_signatures() {
+    let engine = every_fourth();
+    let chain = chain(&engine, 7, 1);
+    let trusting = Checkpointed::new((), 4, vec![]).with_checkpoint(checkpoint_of(&chain[2]));
+
+    // Nobody can sign for the trusting engine, so only the known checkpoint counts.
+    assert_eq!(
+        trusting.latest_checkpoint(&chain),
+        Some(checkpoint_of(&chain[2]))
+    );
+    assert_eq!(engine.latest_checkpoint(&chain[..4]), None);
+}
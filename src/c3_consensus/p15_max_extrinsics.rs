@@ -0,0 +1,120 @@
+//! Every engine so far judges a block by its header alone. Some rules need the body too. Ethereum
+//! caps how much work a block may contain, and many chains let a block's author claim the fees
+//! of the extrinsics it includes. An engine enforcing such rules has to see the extrinsics while
+//! sealing and validating, which `seal_with_body` and `validate_with_body` allow.
+//!
+//! Here we write the simplest such rule, a cap on the number of extrinsics per block, as a
+//! higher-order engine. Headers checked without their body, as light clients do, are only
+//! checked by the inner engine's rules.
+
+use super::{Consensus, Header};
+
+/// A higher-order consensus engine that refuses blocks with more than `max` extrinsics.
+pub struct MaxExtrinsics<Inner> {
+    pub inner: Inner,
+    pub max: usize,
+}
+
+impl<Inner: Consensus> Consensus for MaxExtrinsics<Inner> {
+    type Digest = Inner::Digest;
+
+    /// Check the header by the inner engine's rules. The cap can only be checked with the body.
+    fn validate(&self, parent_digest: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        self.inner.validate(parent_digest, header)
+    }
+
+    /// Seal the header with the inner engine, without checking the cap.
+    fn seal(
+        &self,
+        parent_digest: &Self::Digest,
+        partial_header: Header<()>,
+    ) -> Option<Header<Self::Digest>> {
+        self.inner.seal(parent_digest, partial_header)
+    }
+
+    /// Check that the body is within the cap, and the header by the inner engine's rules.
+    fn validate_with_body(
+        &self,
+        parent_digest: &Self::Digest,
+        header: &Header<Self::Digest>,
+        body: &[Vec<u8>],
+    ) -> bool {
+        body.len() <= self.max && self.inner.validate_with_body(parent_digest, header, body)
+    }
+
+    /// Seal the header with the inner engine, unless the body exceeds the cap.
+    fn seal_with_body(
+        &self,
+        parent_digest: &Self::Digest,
+        partial_header: Header<()>,
+        body: &[Vec<u8>],
+    ) -> Option<Header<Self::Digest>> {
+        if body.len() > self.max {
+            return None;
+        }
+        self.inner
+            .seal_with_body(parent_digest, partial_header, body)
+    }
+
+    fn human_name() -> String {
+        format!(
+            "{} with at most a fixed number of extrinsics",
+            Inner::human_name()
+        )
+    }
+
+    /// At most 1000 extrinsics per block.
+    fn create_default_instance() -> Self {
+        MaxExtrinsics {
+            inner: Inner::create_default_instance(),
+            max: 1000,
+        }
+    }
+}
+
+#[cfg(test)]
+fn partial_header() -> Header<()> {
+    Header {
+        parent: 0,
+        height: 1,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: (),
+    }
+}
+
+#[test]
+fn test_body_over_the_cap_is_not_sealed() {
+    let engine = MaxExtrinsics { inner: (), max: 2 };
+
+    assert!(engine
+        .seal_with_body(&(), partial_header(), &[vec![1], vec![2]])
+        .is_some());
+    assert_eq!(
+        engine.seal_with_body(&(), partial_header(), &[vec![1], vec![2], vec![3]]),
+        None
+    );
+}
+
+#[test]
+fn test_body_over_the_cap_is_invalid() {
+    let engine = MaxExtrinsics { inner: (), max: 1 };
+    let header = engine.seal(&(), partial_header()).unwrap();
+
+    assert!(engine.validate_with_body(&(), &header, &[vec![7]]));
+    assert!(!engine.validate_with_body(&(), &header, &[vec![7], vec![8]]));
+    // Without the body only the inner engine's rules apply.
+    assert!(engine.validate(&(), &header));
+}
+
+#[test]
+fn test_plain_engines_ignore_the_body() {
+    use super::p1_pow::PoW;
+
+    let pow = PoW::new(u64::MAX / 10);
+    let header = pow
+        .seal_with_body(&0, partial_header(), &vec![vec![1]; 5000])
+        .unwrap();
+
+    assert!(pow.validate_with_body(&0, &header, &[]));
+}
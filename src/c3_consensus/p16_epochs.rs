@@ -0,0 +1,258 @@
+//! The PoA engines so far either have one authority set forever, or read it afresh from the state
+//! for every block. Aura and BABE sit in between. Time is divided into epochs of a fixed number
+//! of blocks, and the authority set only changes from one epoch to the next.
+//!
+//! Nodes must agree on the next set before the epoch that uses it begins, or they would disagree
+//! about who may author its first block. So the first block of every epoch commits the set of the
+//! epoch after it, and every later block of the epoch repeats that commitment, so that any header
+//! can be checked against its parent alone. When the next epoch begins, its first block must take
+//! over the committed set. A light client following the headers thus learns every handoff a whole
+//! epoch before it happens.
+
+use super::equivocation::SlotAuthorship;
+use super::{Consensus, ConsensusAuthority, Header};
+
+/// The digest of a block under epoch-based authority rotation
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EpochDigest {
+    pub author: ConsensusAuthority,
+    /// The authorities of the block's epoch, taking turns by height in this order
+    pub authorities: Vec<ConsensusAuthority>,
+    /// The authorities of the following epoch, committed by the first block of this one
+    pub next_authorities: Vec<ConsensusAuthority>,
+}
+
+/// A Proof of Authority engine whose authority set changes every `epoch_length` blocks. The
+/// epochs cycle through the given sets, each epoch committing the next set in the cycle.
+#[derive(Clone, Debug)]
+pub struct EpochPoa {
+    /// How many blocks make up an epoch. Always at least one.
+    epoch_length: u64,
+    pub sets: Vec<Vec<ConsensusAuthority>>,
+}
+
+impl EpochPoa {
+    pub fn new(epoch_length: u64, sets: Vec<Vec<ConsensusAuthority>>) -> Self {
+        EpochPoa {
+            epoch_length: epoch_length.max(1),
+            sets,
+        }
+    }
+
+    /// Alice, Bob and Charlie, with each of them sitting out every third epoch in turn.
+    pub fn rotating(epoch_length: u64) -> Self {
+        use ConsensusAuthority::{Alice, Bob, Charlie};
+
+        EpochPoa::new(
+            epoch_length,
+            vec![vec![Bob, Charlie], vec![Alice, Charlie], vec![Alice, Bob]],
+        )
+    }
+
+    /// The epoch the block at the given height belongs to.
+    pub fn epoch_of(&self, height: u64) -> u64 {
+        height / self.epoch_length
+    }
+
+    /// The authorities of the given epoch, according to the cycle.
+    pub fn set_of_epoch(&self, epoch: u64) -> Vec<ConsensusAuthority> {
+        if self.sets.is_empty() {
+            return Vec::new();
+        }
+        // The remainder is less than the number of sets, so it fits in a usize.
+        self.sets[(epoch % self.sets.len() as u64) as usize].clone()
+    }
+
+    /// The digest of a genesis block, which starts epoch 0 and commits the set of epoch 1.
+    pub fn genesis_digest(&self) -> EpochDigest {
+        let authorities = self.set_of_epoch(0);
+        EpochDigest {
+            author: authorities
+                .first()
+                .copied()
+                .unwrap_or(ConsensusAuthority::Alice),
+            authorities,
+            next_authorities: self.set_of_epoch(1),
+        }
+    }
+
+    /// The sets a block at the given height must carry on top of the given parent: the current
+    /// one and the committed next one.
+    fn sets_for(
+        &self,
+        parent_digest: &EpochDigest,
+        height: u64,
+    ) -> (Vec<ConsensusAuthority>, Vec<ConsensusAuthority>) {
+        if height.is_multiple_of(self.epoch_length) {
+            (
+                parent_digest.next_authorities.clone(),
+                self.set_of_epoch(self.epoch_of(height) + 1),
+            )
+        } else {
+            (
+                parent_digest.authorities.clone(),
+                parent_digest.next_authorities.clone(),
+            )
+        }
+    }
+}
+
+/// The authority whose turn it is at the given height, or None if the set is empty.
+fn author_in(authorities: &[ConsensusAuthority], height: u64) -> Option<ConsensusAuthority> {
+    if authorities.is_empty() {
+        return None;
+    }
+    // The remainder is less than the number of authorities, so it fits in a usize.
+    Some(authorities[(height % authorities.len() as u64) as usize])
+}
+
+impl Consensus for EpochPoa {
+    type Digest = EpochDigest;
+
+    /// Check that the header carries the sets of its epoch, taking over the set its parent
+    /// committed if it starts a new epoch, and that it is signed by the authority whose turn it
+    /// is.
+    fn validate(&self, parent_digest: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        let digest = &header.consensus_digest;
+        let (authorities, next_authorities) = self.sets_for(parent_digest, header.height);
+        digest.authorities == authorities
+            && digest.next_authorities == next_authorities
+            && author_in(&authorities, header.height) == Some(digest.author)
+    }
+
+    /// Sign the given partial header by the authority whose turn it is in its epoch.
+    fn seal(
+        &self,
+        parent_digest: &Self::Digest,
+        partial_header: Header<()>,
+    ) -> Option<Header<Self::Digest>> {
+        let (authorities, next_authorities) = self.sets_for(parent_digest, partial_header.height);
+        let author = author_in(&authorities, partial_header.height)?;
+        Some(Header {
+            parent: partial_header.parent,
+            height: partial_header.height,
+            state_root: partial_header.state_root,
+            extrinsics_root: partial_header.extrinsics_root,
+            consensus_digest: EpochDigest {
+                author,
+                authorities,
+                next_authorities,
+            },
+        })
+    }
+
+    fn human_name() -> String {
+        "Epoch-Rotating PoA".into()
+    }
+
+    /// Epochs of 10 blocks, rotating as in `rotating`.
+    fn create_default_instance() -> Self {
+        EpochPoa::rotating(10)
+    }
+}
+
+/// Every height is a slot with a single author. The epoch's set is in the digest, so the turn can
+/// be checked without the parent.
+impl SlotAuthorship<EpochDigest> for EpochPoa {
+    fn slot_author(&self, header: &Header<EpochDigest>) -> Option<(u64, ConsensusAuthority)> {
+        let digest = &header.consensus_digest;
+        (author_in(&digest.authorities, header.height) == Some(digest.author))
+            .then_some((header.height, digest.author))
+    }
+}
+
+#[cfg(test)]
+use ConsensusAuthority::{Alice, Bob, Charlie};
+
+/// A chain of the given length sealed by the given engine, starting from its genesis.
+#[cfg(test)]
+fn chain(engine: &EpochPoa, len: u64) -> Vec<Header<EpochDigest>> {
+    let mut chain = vec![Header {
+        parent: 0,
+        height: 0,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: engine.genesis_digest(),
+    }];
+    for height in 1..len {
+        let parent = chain.last().unwrap();
+        let partial = Header {
+            parent: crate::hash(parent),
+            height,
+            state_root: 0,
+            extrinsics_root: 0,
+            consensus_digest: (),
+        };
+        let header = engine.seal(&parent.consensus_digest, partial).unwrap();
+        chain.push(header);
+    }
+    chain
+}
+
+#[test]
+fn test_authorities_change_at_epoch_boundaries() {
+    let engine = EpochPoa::rotating(3);
+    let chain = chain(&engine, 9);
+
+    let authors: Vec<_> = chain[1..]
+        .iter()
+        .map(|h| h.consensus_digest.author)
+        .collect();
+    // Alice rests in epoch 0, Bob in epoch 1 and Charlie in epoch 2.
+    assert_eq!(
+        authors,
+        vec![Charlie, Bob, Charlie, Alice, Charlie, Alice, Bob, Alice]
+    );
+    assert!(engine.verify_sub_chain(&chain[0].consensus_digest, &chain));
+}
+
+#[test]
+fn test_next_set_is_committed_an_epoch_ahead() {
+    let engine = EpochPoa::rotating(3);
+    let chain = chain(&engine, 7);
+
+    for header in &chain[3..6] {
+        assert_eq!(header.consensus_digest.authorities, vec![Alice, Charlie]);
+        assert_eq!(header.consensus_digest.next_authorities, vec![Alice, Bob]);
+    }
+    assert_eq!(chain[6].consensus_digest.authorities, vec![Alice, Bob]);
+}
+
+#[test]
+fn test_handoff_cannot_be_rewritten() {
+    let engine = EpochPoa::rotating(3);
+    let chain = chain(&engine, 4);
+
+    // Changing the committed set in the middle of an epoch
+    let mut rewritten = chain[2].clone();
+    rewritten.consensus_digest.next_authorities = vec![Charlie];
+    assert!(!engine.validate(&chain[1].consensus_digest, &rewritten));
+
+    // Starting an epoch with a set other than the committed one
+    let mut usurped = chain[3].clone();
+    usurped.consensus_digest.authorities = vec![Charlie];
+    usurped.consensus_digest.author = Charlie;
+    assert!(!engine.validate(&chain[2].consensus_digest, &usurped));
+
+    // Sealing out of turn
+    let mut out_of_turn = chain[3].clone();
+    out_of_turn.consensus_digest.author = Alice;
+    assert!(!engine.validate(&chain[2].consensus_digest, &out_of_turn));
+    assert_eq!(engine.slot_author(&out_of_turn), None);
+}
+
+#[test]
+fn test_empty_epoch_cannot_be_sealed() {
+    let engine = EpochPoa::new(2, vec![vec![Alice], vec![]]);
+    let chain = chain(&engine, 2);
+    let partial = Header {
+        parent: crate::hash(&chain[1]),
+        height: 2,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: (),
+    };
+
+    assert_eq!(engine.seal(&chain[1].consensus_digest, partial), None);
+}
@@ -0,0 +1,270 @@
+//! When blocks are found faster than they can spread through the network, miners often find
+//! blocks at the same height, and all but one of them end up off the best chain. Under the
+//! longest chain rule that work is simply wasted, and miners with good connectivity waste the
+//! least of it, which pushes mining towards a few large, well-connected pools.
+//!
+//! Ethereum's answer was to let blocks include references to such stale blocks, called uncles
+//! (or ommers). An uncle's miner still receives most of a block reward, the block including it
+//! receives a little extra, and a fork choice that counts the uncles' work no longer ignores the
+//! losing side of every race. An uncle must be recent, within a few blocks of the block
+//! including it, so that old blocks cannot be dug up for their rewards.
+//!
+//! The engine can only check what the header says about its uncles. Whether the uncles really
+//! exist, and branch off the chain where they claim to, takes the client's tree of headers.
+
+use super::p1_pow::{PoW, PowHasher, Sha256};
+use super::{Consensus, ConsensusAuthority, Hash, Header};
+use crate::hash;
+
+/// The reward for mining a block on the best chain
+pub const BLOCK_REWARD: u128 = 32;
+
+/// A block that lost a race, as referenced by a later block on the best chain
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UncleRef {
+    pub hash: Hash,
+    pub height: u64,
+    pub miner: ConsensusAuthority,
+}
+
+/// The digest of a PoW block that may include uncles
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UncleDigest {
+    pub nonce: u64,
+    /// Who receives the block's rewards
+    pub miner: ConsensusAuthority,
+    pub uncles: Vec<UncleRef>,
+}
+
+impl UncleDigest {
+    /// The digest of a genesis block, which is not mined.
+    pub fn genesis() -> Self {
+        UncleDigest {
+            nonce: 0,
+            miner: ConsensusAuthority::Alice,
+            uncles: Vec::new(),
+        }
+    }
+}
+
+/// A PoW engine whose blocks may include up to `max_uncles` uncles, each at most `max_depth`
+/// blocks older than the block including it. The work covers the miner and the uncles, so
+/// neither can be changed once the block is sealed.
+#[derive(Clone, Debug)]
+pub struct UnclePow<H = Sha256> {
+    pub pow: PoW<H>,
+    /// Who this node mines for
+    pub miner: ConsensusAuthority,
+    max_depth: u64,
+    max_uncles: usize,
+}
+
+impl<H> UnclePow<H> {
+    pub fn new(pow: PoW<H>, miner: ConsensusAuthority, max_depth: u64, max_uncles: usize) -> Self {
+        UnclePow {
+            pow,
+            miner,
+            max_depth,
+            max_uncles,
+        }
+    }
+
+    /// How many blocks older than the block including it an uncle may be
+    pub fn max_depth(&self) -> u64 {
+        self.max_depth
+    }
+
+    /// Whether the header's uncles are few enough, distinct, and recent enough.
+    fn uncles_within_limits(&self, header: &Header<UncleDigest>) -> bool {
+        let uncles = &header.consensus_digest.uncles;
+        uncles.len() <= self.max_uncles
+            && uncles
+                .iter()
+                .enumerate()
+                .all(|(i, uncle)| uncles[..i].iter().all(|other| other.hash != uncle.hash))
+            && uncles.iter().all(|uncle| {
+                uncle.height < header.height && header.height - uncle.height <= self.max_depth
+            })
+    }
+
+    /// The rewards for the given block: the block reward for its miner plus a 32nd of it for
+    /// every uncle, and for every uncle's miner a share of the block reward that shrinks the
+    /// older the uncle is. Clients only pay them out for blocks whose uncles they have checked.
+    pub fn rewards(&self, header: &Header<UncleDigest>) -> Vec<(ConsensusAuthority, u128)> {
+        let digest = &header.consensus_digest;
+        let uncles = digest.uncles.len() as u128;
+        let mut rewards = vec![(digest.miner, BLOCK_REWARD + uncles * BLOCK_REWARD / 32)];
+        for uncle in &digest.uncles {
+            let depth = u128::from(header.height - uncle.height);
+            let scale = u128::from(self.max_depth) + 2;
+            rewards.push((uncle.miner, (scale - depth) * BLOCK_REWARD / scale));
+        }
+        rewards
+    }
+}
+
+/// The header the PoW seal covers. Its extrinsics root also commits to the miner and the uncles.
+fn mined_header(header: &Header<UncleDigest>) -> Header<u64> {
+    let digest = &header.consensus_digest;
+    Header {
+        parent: header.parent,
+        height: header.height,
+        state_root: header.state_root,
+        extrinsics_root: hash(&(header.extrinsics_root, digest.miner, &digest.uncles)),
+        consensus_digest: digest.nonce,
+    }
+}
+
+impl<H: PowHasher> UnclePow<H> {
+    /// Mine the given partial header with the given uncles. Returns None if the uncles break the
+    /// engine's limits.
+    pub fn seal_with_uncles(
+        &self,
+        partial_header: Header<()>,
+        uncles: Vec<UncleRef>,
+    ) -> Option<Header<UncleDigest>> {
+        let mut header = Header {
+            parent: partial_header.parent,
+            height: partial_header.height,
+            state_root: partial_header.state_root,
+            extrinsics_root: partial_header.extrinsics_root,
+            consensus_digest: UncleDigest {
+                nonce: 0,
+                miner: self.miner,
+                uncles,
+            },
+        };
+        if !self.uncles_within_limits(&header) {
+            return None;
+        }
+        let mined = mined_header(&header);
+        let partial = Header {
+            parent: mined.parent,
+            height: mined.height,
+            state_root: mined.state_root,
+            extrinsics_root: mined.extrinsics_root,
+            consensus_digest: (),
+        };
+        header.consensus_digest.nonce = self.pow.seal(&0, partial)?.consensus_digest;
+        Some(header)
+    }
+}
+
+impl<H: PowHasher> Consensus for UnclePow<H> {
+    type Digest = UncleDigest;
+
+    /// Check that the uncles are within the engine's limits and that the work covers them.
+    fn validate(&self, _: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        self.uncles_within_limits(header) && self.pow.validate(&0, &mined_header(header))
+    }
+
+    /// Mine the given partial header without uncles. Use `seal_with_uncles` to include some.
+    fn seal(&self, _: &Self::Digest, partial_header: Header<()>) -> Option<Header<Self::Digest>> {
+        self.seal_with_uncles(partial_header, Vec::new())
+    }
+
+    fn human_name() -> String {
+        "PoW with Uncles".into()
+    }
+
+    /// Ethereum's limits: at most two uncles, at most six blocks old.
+    fn create_default_instance() -> Self {
+        UnclePow::new(
+            PoW::create_default_instance(),
+            ConsensusAuthority::Alice,
+            6,
+            2,
+        )
+    }
+}
+
+#[cfg(test)]
+use ConsensusAuthority::{Alice, Bob};
+
+#[cfg(test)]
+fn engine(miner: ConsensusAuthority) -> UnclePow {
+    UnclePow::new(PoW::new(u64::MAX / 1000), miner, 2, 2)
+}
+
+#[cfg(test)]
+fn partial_header(height: u64) -> Header<()> {
+    Header {
+        parent: 0,
+        height,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: (),
+    }
+}
+
+#[cfg(test)]
+fn uncle(height: u64) -> UncleRef {
+    UncleRef {
+        hash: height,
+        height,
+        miner: Bob,
+    }
+}
+
+#[test]
+fn test_uncles_are_covered_by_the_work() {
+    let engine = engine(Alice);
+    let header = engine
+        .seal_with_uncles(partial_header(5), vec![uncle(4)])
+        .unwrap();
+    assert!(engine.validate(&UncleDigest::genesis(), &header));
+
+    let mut dropped = header.clone();
+    dropped.consensus_digest.uncles.clear();
+    let mut redirected = header.clone();
+    redirected.consensus_digest.miner = Bob;
+    assert!(!engine.validate(&UncleDigest::genesis(), &dropped));
+    assert!(!engine.validate(&UncleDigest::genesis(), &redirected));
+}
+
+#[test]
+fn test_uncles_must_be_recent_few_and_distinct() {
+    let engine = engine(Alice);
+
+    assert!(engine
+        .seal_with_uncles(partial_header(5), vec![uncle(3)])
+        .is_some());
+    assert_eq!(
+        engine.seal_with_uncles(partial_header(5), vec![uncle(2)]),
+        None
+    );
+    assert_eq!(
+        engine.seal_with_uncles(partial_header(5), vec![uncle(5)]),
+        None
+    );
+    assert_eq!(
+        engine.seal_with_uncles(partial_header(5), vec![uncle(4), uncle(4)]),
+        None
+    );
+    assert_eq!(
+        engine.seal_with_uncles(
+            partial_header(5),
+            vec![uncle(3), uncle(4), {
+                let mut other = uncle(4);
+                other.hash = 99;
+                other
+            }]
+        ),
+        None
+    );
+}
+
+#[test]
+fn test_uncle_rewards_shrink_with_depth() {
+    let engine = engine(Alice);
+    let header = engine
+        .seal_with_uncles(partial_header(5), vec![uncle(4), uncle(3)])
+        .unwrap();
+
+    assert_eq!(
+        engine.rewards(&header),
+        vec![(Alice, 34), (Bob, 24), (Bob, 16)]
+    );
+}
@@ -0,0 +1,255 @@
+//! Proof of Work picks a random author by making everyone burn energy until someone wins.
+//! Intel's Proof of Elapsed Time (PoET) gets a similar lottery without the waste. Every authority
+//! asks a trusted enclave in its CPU for a random wait time, and whoever's timer runs out first
+//! seals the block, along with the enclave's attestation that it really drew that wait and really
+//! waited. The lottery is only as fair as the enclave is trustworthy, so PoET swaps PoW's
+//! assumption about the distribution of hash power for trust in a hardware vendor.
+//!
+//! We have no enclave, so we simulate one. Each authority's wait is drawn from the deterministic
+//! randomness of the block being sealed, and the digest carries the draw. Validators recompute
+//! every authority's draw, which plays the role of checking the attestation, and reject blocks
+//! sealed by anyone but the lowest waiter, or sealed before their wait was over.
+
+use super::{Consensus, ConsensusAuthority, Header, SystemClock, TimeProvider};
+use crate::c1_state_machine::randomness::Randomness;
+use crate::c1_state_machine::BlockContext;
+
+/// The digest of a block sealed under simulated PoET
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PoetDigest {
+    pub author: ConsensusAuthority,
+    /// The wait the author drew, in milliseconds
+    pub wait: u64,
+    /// When the block was sealed, in milliseconds since the unix epoch. The next block's wait
+    /// starts from here.
+    pub sealed_at: u64,
+}
+
+impl PoetDigest {
+    /// The digest of a genesis block created at the given time. Genesis is never validated, so
+    /// its author and wait are never checked.
+    pub fn genesis(sealed_at: u64) -> Self {
+        PoetDigest {
+            author: ConsensusAuthority::Alice,
+            wait: 0,
+            sealed_at,
+        }
+    }
+}
+
+/// A simulated Proof of Elapsed Time engine, in which each of the authorities draws a wait of
+/// less than `max_wait` milliseconds for every block.
+pub struct Poet<Clock = SystemClock> {
+    authorities: Vec<ConsensusAuthority>,
+    /// The bound on wait times, in milliseconds. Always at least one.
+    max_wait: u64,
+    /// The authority this node seals as, if it is one
+    local_authority: Option<ConsensusAuthority>,
+    clock: Clock,
+}
+
+impl<Clock> Poet<Clock> {
+    pub fn new(authorities: Vec<ConsensusAuthority>, max_wait: u64, clock: Clock) -> Self {
+        Poet {
+            authorities,
+            max_wait: max_wait.max(1),
+            local_authority: None,
+            clock,
+        }
+    }
+
+    /// Seal only as the given authority. Without one, blocks are sealed as whichever authority
+    /// wins.
+    pub fn sealing_as(mut self, authority: ConsensusAuthority) -> Self {
+        self.local_authority = Some(authority);
+        self
+    }
+
+    /// The wait the given authority draws for the block at the given height on top of the given
+    /// parent.
+    pub fn wait_time(&self, authority: ConsensusAuthority, parent: u64, height: u64) -> u64 {
+        let context = BlockContext {
+            height,
+            parent_hash: parent,
+            ..BlockContext::default()
+        };
+        context.random_below(&(b"poet-wait", authority), self.max_wait)
+    }
+
+    /// The authority with the lowest wait for the block at the given height on top of the given
+    /// parent, and its wait. Ties go to the authority listed first. None if there are no
+    /// authorities.
+    pub fn winner(&self, parent: u64, height: u64) -> Option<(ConsensusAuthority, u64)> {
+        self.authorities
+            .iter()
+            .map(|a| (*a, self.wait_time(*a, parent, height)))
+            .reduce(|best, next| if next.1 < best.1 { next } else { best })
+    }
+}
+
+impl<Clock: TimeProvider + Default> Consensus for Poet<Clock> {
+    type Digest = PoetDigest;
+
+    /// Check that the author drew the lowest wait, that the digest carries that draw, and that
+    /// the block was sealed after the wait was over but not in the future.
+    fn validate(&self, parent_digest: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        let digest = &header.consensus_digest;
+        self.winner(header.parent, header.height) == Some((digest.author, digest.wait))
+            && digest.sealed_at >= parent_digest.sealed_at.saturating_add(digest.wait)
+            && digest.sealed_at <= self.clock.now()
+    }
+
+    /// Seal the block now if this node's authority drew the lowest wait and has waited it out.
+    fn seal(
+        &self,
+        parent_digest: &Self::Digest,
+        partial_header: Header<()>,
+    ) -> Option<Header<Self::Digest>> {
+        let (author, wait) = self.winner(partial_header.parent, partial_header.height)?;
+        let now = self.clock.now();
+        if self.local_authority.is_some_and(|local| local != author)
+            || now < parent_digest.sealed_at.saturating_add(wait)
+        {
+            return None;
+        }
+        Some(Header {
+            parent: partial_header.parent,
+            height: partial_header.height,
+            state_root: partial_header.state_root,
+            extrinsics_root: partial_header.extrinsics_root,
+            consensus_digest: PoetDigest {
+                author,
+                wait,
+                sealed_at: now,
+            },
+        })
+    }
+
+    fn human_name() -> String {
+        "Simulated Proof of Elapsed Time".into()
+    }
+
+    /// Alice, Bob and Charlie, each waiting up to ten seconds.
+    fn create_default_instance() -> Self {
+        Poet::new(
+            vec![
+                ConsensusAuthority::Alice,
+                ConsensusAuthority::Bob,
+                ConsensusAuthority::Charlie,
+            ],
+            10_000,
+            Clock::default(),
+        )
+    }
+}
+
+#[cfg(test)]
+use super::MockClock;
+#[cfg(test)]
+use ConsensusAuthority::{Alice, Bob, Charlie};
+
+#[cfg(test)]
+fn poet(clock: &MockClock) -> Poet<MockClock> {
+    Poet::new(vec![Alice, Bob, Charlie], 1000, clock.clone())
+}
+
+#[cfg(test)]
+fn partial_header(height: u64) -> Header<()> {
+    Header {
+        parent: 7,
+        height,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: (),
+    }
+}
+
+#[test]
+fn test_lowest_waiter_seals_once_its_wait_is_over() {
+    let clock = MockClock::new(0);
+    let engine = poet(&clock);
+    let (winner, wait) = engine.winner(7, 1).unwrap();
+    let genesis = PoetDigest::genesis(0);
+
+    clock.set(wait.saturating_sub(1));
+    if wait > 0 {
+        assert_eq!(engine.seal(&genesis, partial_header(1)), None);
+    }
+    clock.set(wait);
+    let header = engine.seal(&genesis, partial_header(1)).unwrap();
+
+    assert_eq!(header.consensus_digest.author, winner);
+    assert!(poet(&clock).validate(&genesis, &header));
+}
+
+#[test]
+fn test_only_the_winner_may_seal() {
+    let clock = MockClock::new(10_000);
+    let engine = poet(&clock);
+    let (winner, _) = engine.winner(7, 1).unwrap();
+    let loser = [Alice, Bob, Charlie]
+        .into_iter()
+        .find(|a| *a != winner)
+        .unwrap();
+    let genesis = PoetDigest::genesis(0);
+
+    assert_eq!(
+        poet(&clock)
+            .sealing_as(loser)
+            .seal(&genesis, partial_header(1)),
+        None
+    );
+
+    // Publishing the loser's genuine but losing draw does not help.
+    let claimed = Header {
+        parent: 7,
+        height: 1,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: PoetDigest {
+            author: loser,
+            wait: engine.wait_time(loser, 7, 1),
+            sealed_at: 10_000,
+        },
+    };
+    assert!(!engine.validate(&genesis, &claimed));
+}
+
+#[test]
+fn test_forged_waits_are_rejected() {
+    let clock = MockClock::new(10_000);
+    let engine = poet(&clock);
+    let genesis = PoetDigest::genesis(0);
+    let header = engine.seal(&genesis, partial_header(1)).unwrap();
+
+    // A wait other than the one drawn
+    let mut shortened = header.clone();
+    shortened.consensus_digest.wait = header.consensus_digest.wait + 1;
+    assert!(!engine.validate(&genesis, &shortened));
+
+    // Sealing before the wait is over
+    let mut early = header.clone();
+    early.consensus_digest.sealed_at = header.consensus_digest.wait.saturating_sub(1);
+    let parent_started = PoetDigest::genesis(1);
+    assert!(!engine.validate(&parent_started, &early));
+
+    // Sealing in the future
+    let mut future = header;
+    future.consensus_digest.sealed_at = 20_000;
+    assert!(!engine.validate(&genesis, &future));
+}
+
+#[test]
+fn test_winners_vary_from_block_to_block() {
+    let clock = MockClock::new(0);
+    let engine = poet(&clock);
+    let winners: Vec<_> = (1..=60)
+        .map(|height| engine.winner(height, height).unwrap().0)
+        .collect();
+
+    for authority in [Alice, Bob, Charlie] {
+        let won = winners.iter().filter(|w| **w == authority).count();
+        assert!((10..=30).contains(&won), "{authority:?} won {won} times");
+    }
+}
@@ -0,0 +1,267 @@
+//! In the PoA engines so far a single authority seals each block, so one compromised key is
+//! enough to seal whatever it likes. A threshold engine asks for more. Each block must be signed
+//! by at least `m` of the `n` authorities, so an attacker must compromise `m` keys, and the chain
+//! keeps going as long as `m` authorities are online. Bitcoin's m-of-n multisig addresses and
+//! the federations that run many sidechains work this way, and BFT engines like Tendermint are
+//! the same idea with `m` just above two thirds of `n` and a protocol for gathering the votes.
+//!
+//! Authorities usually sign on different machines, so their signatures have to be gathered and
+//! combined into a single digest before the block can be sealed. The `MultiSigDigest` utilities
+//! do that. As elsewhere in this chapter the signatures are simulated: a signature names its
+//! signer and the hash of the header it signs, so it cannot be moved to another header, but
+//! anybody could make one up in anybody's name.
+
+use super::{Consensus, ConsensusAuthority, Hash, Header};
+use crate::hash;
+
+/// An authority's signature on a header
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Signature {
+    pub signer: ConsensusAuthority,
+    /// The hash of the header being signed, without its digest
+    pub message: Hash,
+}
+
+impl Signature {
+    /// The given authority's signature on the given header.
+    pub fn sign(signer: ConsensusAuthority, partial_header: &Header<()>) -> Self {
+        Signature {
+            signer,
+            message: hash(partial_header),
+        }
+    }
+
+    /// Whether this is a signature on the given header.
+    pub fn verify(&self, partial_header: &Header<()>) -> bool {
+        self.message == hash(partial_header)
+    }
+}
+
+/// The digest of a block under threshold PoA: the signatures on it, at most one per signer
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MultiSigDigest {
+    pub signatures: Vec<Signature>,
+}
+
+impl MultiSigDigest {
+    /// A digest with no signatures, as carried by genesis.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the given signature. Returns false, leaving the digest unchanged, if its signer
+    /// already signed.
+    pub fn add(&mut self, signature: Signature) -> bool {
+        if self.signers().contains(&signature.signer) {
+            return false;
+        }
+        self.signatures.push(signature);
+        true
+    }
+
+    /// Combine the signatures gathered in several digests into one. When a signer appears more
+    /// than once, its first signature is kept.
+    pub fn aggregate(digests: impl IntoIterator<Item = MultiSigDigest>) -> Self {
+        let mut aggregate = MultiSigDigest::new();
+        for signature in digests.into_iter().flat_map(|d| d.signatures) {
+            aggregate.add(signature);
+        }
+        aggregate
+    }
+
+    /// Everyone who signed, in the order their signatures were added.
+    pub fn signers(&self) -> Vec<ConsensusAuthority> {
+        self.signatures.iter().map(|s| s.signer).collect()
+    }
+}
+
+/// The header the authorities sign
+fn unsigned<D>(header: &Header<D>) -> Header<()> {
+    Header {
+        parent: header.parent,
+        height: header.height,
+        state_root: header.state_root,
+        extrinsics_root: header.extrinsics_root,
+        consensus_digest: (),
+    }
+}
+
+/// A Proof of Authority engine in which every block must be signed by at least `threshold` of
+/// the authorities.
+pub struct ThresholdPoa {
+    authorities: Vec<ConsensusAuthority>,
+    /// How many distinct authorities must sign. Always at least one.
+    threshold: usize,
+    /// The authorities this node signs as when sealing
+    local_signers: Vec<ConsensusAuthority>,
+}
+
+impl ThresholdPoa {
+    /// An engine requiring `threshold` of the given authorities to sign, whose node signs as
+    /// all of them.
+    pub fn new(authorities: Vec<ConsensusAuthority>, threshold: usize) -> Self {
+        ThresholdPoa {
+            local_signers: authorities.clone(),
+            authorities,
+            threshold: threshold.max(1),
+        }
+    }
+
+    /// Sign only as the given authorities when sealing.
+    pub fn signing_as(mut self, local_signers: Vec<ConsensusAuthority>) -> Self {
+        self.local_signers = local_signers;
+        self
+    }
+
+    /// This node's share of the signatures on the given header, to be aggregated with the shares
+    /// of the other authorities.
+    pub fn sign(&self, partial_header: &Header<()>) -> MultiSigDigest {
+        let mut share = MultiSigDigest::new();
+        for signer in &self.local_signers {
+            if self.authorities.contains(signer) {
+                share.add(Signature::sign(*signer, partial_header));
+            }
+        }
+        share
+    }
+
+    /// Seal the given header with the given aggregated signatures, if they are enough.
+    pub fn seal_with_signatures(
+        &self,
+        partial_header: Header<()>,
+        signatures: MultiSigDigest,
+    ) -> Option<Header<MultiSigDigest>> {
+        let header = Header {
+            parent: partial_header.parent,
+            height: partial_header.height,
+            state_root: partial_header.state_root,
+            extrinsics_root: partial_header.extrinsics_root,
+            consensus_digest: signatures,
+        };
+        self.is_signed(&header).then_some(header)
+    }
+
+    /// Whether the header carries genuine signatures from at least `threshold` distinct
+    /// authorities, and no others.
+    fn is_signed(&self, header: &Header<MultiSigDigest>) -> bool {
+        let digest = &header.consensus_digest;
+        let partial = unsigned(header);
+        let signers = digest.signers();
+        let distinct = signers
+            .iter()
+            .enumerate()
+            .all(|(i, signer)| !signers[..i].contains(signer));
+        distinct
+            && signers.len() >= self.threshold
+            && signers.iter().all(|s| self.authorities.contains(s))
+            && digest.signatures.iter().all(|s| s.verify(&partial))
+    }
+}
+
+impl Consensus for ThresholdPoa {
+    type Digest = MultiSigDigest;
+
+    /// Check that enough distinct authorities signed the header, and nobody else.
+    fn validate(&self, _: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        self.is_signed(header)
+    }
+
+    /// Seal the header with this node's signatures alone, if they are enough. Otherwise gather
+    /// the other authorities' shares and use `seal_with_signatures`.
+    fn seal(&self, _: &Self::Digest, partial_header: Header<()>) -> Option<Header<Self::Digest>> {
+        let share = self.sign(&partial_header);
+        self.seal_with_signatures(partial_header, share)
+    }
+
+    fn human_name() -> String {
+        "Threshold PoA".into()
+    }
+
+    /// Two of Alice, Bob and Charlie.
+    fn create_default_instance() -> Self {
+        ThresholdPoa::new(
+            vec![
+                ConsensusAuthority::Alice,
+                ConsensusAuthority::Bob,
+                ConsensusAuthority::Charlie,
+            ],
+            2,
+        )
+    }
+}
+
+#[cfg(test)]
+use ConsensusAuthority::{Alice, Bob, Charlie};
+
+#[cfg(test)]
+fn two_of_three() -> ThresholdPoa {
+    ThresholdPoa::new(vec![Alice, Bob, Charlie], 2)
+}
+
+#[cfg(test)]
+fn partial_header() -> Header<()> {
+    Header {
+        parent: 0,
+        height: 1,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: (),
+    }
+}
+
+#[test]
+fn test_shares_from_several_nodes_seal_together() {
+    let alice = two_of_three().signing_as(vec![Alice]);
+    let charlie = two_of_three().signing_as(vec![Charlie]);
+
+    assert_eq!(alice.seal(&MultiSigDigest::new(), partial_header()), None);
+
+    let signatures = MultiSigDigest::aggregate([
+        alice.sign(&partial_header()),
+        charlie.sign(&partial_header()),
+    ]);
+    let header = alice
+        .seal_with_signatures(partial_header(), signatures)
+        .unwrap();
+    assert_eq!(header.consensus_digest.signers(), vec![Alice, Charlie]);
+    assert!(two_of_three().validate(&MultiSigDigest::new(), &header));
+}
+
+#[test]
+fn test_aggregation_keeps_one_signature_per_signer() {
+    let alice = two_of_three().signing_as(vec![Alice]);
+    let share = alice.sign(&partial_header());
+
+    let aggregate = MultiSigDigest::aggregate([share.clone(), share.clone()]);
+    assert_eq!(aggregate, share);
+
+    let mut digest = share;
+    assert!(!digest.add(Signature::sign(Alice, &partial_header())));
+    assert!(digest.add(Signature::sign(Bob, &partial_header())));
+}
+
+#[test]
+fn test_bad_signatures_are_rejected() {
+    let engine = two_of_three();
+    let header = engine
+        .seal(&MultiSigDigest::new(), partial_header())
+        .unwrap();
+    assert_eq!(header.consensus_digest.signers(), vec![Alice, Bob, Charlie]);
+
+    // Alice signing twice counts once.
+    let alice = Signature::sign(Alice, &partial_header());
+    let mut repeated = header.clone();
+    repeated.consensus_digest.signatures = vec![alice, alice];
+    assert!(!engine.validate(&MultiSigDigest::new(), &repeated));
+
+    // Signatures on another header cannot be reused.
+    let mut moved = header.clone();
+    moved.height = 2;
+    assert!(!engine.validate(&MultiSigDigest::new(), &moved));
+
+    // Signers must be authorities.
+    let outsiders = ThresholdPoa::new(vec![Alice, Bob], 2);
+    assert!(!outsiders.validate(&MultiSigDigest::new(), &header));
+}
@@ -3,58 +3,263 @@
 //!
 //! This is the same logic we implemented previously. Here we re-implement it in the
 //! generic consensus framework that we will use throughout the rest of the chapter.
+//!
+//! Unlike the previous chapter, headers are hashed the way real chains hash them: the header is
+//! canonically encoded, the bytes are hashed with a cryptographic hash function, and the 256 bit
+//! result, read as a big endian number, must not exceed a 256 bit target. Which hash function is
+//! used is up to the engine's `PowHasher` type parameter.
+
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use blake2::digest::consts::U32;
+use blake2::Digest;
+
+use crate::c1_state_machine::p9_governance::{Parameter, ParameterChange};
+use crate::codec::Encode;
+use super::{CancelToken, Configurable, Consensus, Header};
+
+/// A cryptographic hash function that PoW headers can be hashed with.
+pub trait PowHasher {
+	/// Hash the given bytes into 256 bits.
+	fn hash(data: &[u8]) -> [u8; 32];
+}
+
+/// SHA-256, as used by Bitcoin.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Sha256;
+
+impl PowHasher for Sha256 {
+	fn hash(data: &[u8]) -> [u8; 32] {
+		sha2::Sha256::digest(data).into()
+	}
+}
+
+/// BLAKE2b with a 256 bit output, as used by Substrate and Zcash.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Blake2b256;
+
+impl PowHasher for Blake2b256 {
+	fn hash(data: &[u8]) -> [u8; 32] {
+		blake2::Blake2b::<U32>::digest(data).into()
+	}
+}
+
+/// A 256 bit number that a header's hash must not exceed, stored big endian so that comparing
+/// targets and hashes byte by byte compares them as numbers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Target(pub [u8; 32]);
+
+impl Target {
+	/// The easiest target, met by every hash.
+	pub const MAX: Target = Target([0xff; 32]);
+
+	/// The target met by roughly `threshold + 1` in every 2^64 hashes, the same fraction of headers
+	/// that met a `u64` threshold in the previous chapter.
+	pub fn from_threshold(threshold: u64) -> Target {
+		let mut target = Target::MAX;
+		target.0[..8].copy_from_slice(&threshold.to_be_bytes());
+		target
+	}
 
-use crate::hash;
-use super::{Consensus, Header};
+	/// The target that takes about `difficulty` times as many hashes to meet as the easiest one.
+	/// Difficulties of zero and one both give the easiest target.
+	pub fn from_difficulty(difficulty: u64) -> Target {
+		let divisor = u128::from(difficulty.max(1));
+		let mut remainder = 0u128;
+		let mut target = [0u8; 32];
+		for (byte, quotient) in Target::MAX.0.iter().zip(target.iter_mut()) {
+			let dividend = remainder << 8 | u128::from(*byte);
+			// The remainder is below the divisor, so the dividend is below 256 times the divisor.
+			*quotient = (dividend / divisor) as u8;
+			remainder = dividend % divisor;
+		}
+		Target(target)
+	}
+
+	/// The most significant 64 bits of the target.
+	pub fn threshold(&self) -> u64 {
+		u64::from_be_bytes(self.0[..8].try_into().expect("a target is longer than 8 bytes"))
+	}
+
+	/// Whether the given hash, read as a big endian number, is at most this target.
+	pub fn is_met_by(&self, hash: &[u8; 32]) -> bool {
+		hash <= &self.0
+	}
+}
 
 /// A Proof of Work consensus engine. This is the same consensus logic that we
 /// implemented in the previous chapter. Here we simply re-implement it in the
 /// consensus framework that will be used throughout this chapter.
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
-pub struct PoW {
-	pub(crate) threshold: u64,
+pub struct PoW<H = Sha256> {
+	pub(crate) target: Target,
+	/// How many threads mine at once when sealing. Always at least one.
+	pub(crate) threads: usize,
+	hasher: PhantomData<fn() -> H>,
 }
 
 impl PoW {
+	/// A SHA-256 engine whose target is met as often as the given `u64` threshold was.
 	pub fn new(t:u64) -> PoW {
-		PoW{ threshold:t}
+		PoW::with_target(Target::from_threshold(t))
+	}
+}
+
+impl<H> PoW<H> {
+	/// An engine that hashes with `H` and requires hashes to be at most the given target.
+	pub fn with_target(target: Target) -> PoW<H> {
+		PoW { target, threads: 1, hasher: PhantomData }
+	}
+
+	/// The same engine, hashing with another function.
+	pub fn with_hasher<Other>(self) -> PoW<Other> {
+		PoW { target: self.target, threads: self.threads, hasher: PhantomData }
 	}
+
+	/// The most significant 64 bits of the target.
 	pub fn get_threashold(&self) -> u64 {
-		return self.threshold;
+		self.target.threshold()
 	}
-}
 
+	pub fn target(&self) -> Target {
+		self.target
+	}
 
-impl Consensus for PoW {
-	type Digest = u64;
+	/// Mine on the given number of threads when sealing. Zero is treated as one.
+	pub fn with_threads(mut self, threads: usize) -> PoW<H> {
+		self.threads = threads.max(1);
+		self
+	}
 
-	/// Check that the provided header's hash is below the required threshold.
-	/// This does not rely on the parent digest at all.
-	fn validate(&self, _: &Self::Digest, header: &Header<Self::Digest>) -> bool {
-		hash(header) < self.threshold
+	/// How many threads mine at once when sealing.
+	pub fn threads(&self) -> usize {
+		self.threads
 	}
+}
 
-	/// Mine a new PoW seal for the partial header provided.
-	/// This does not rely on the parent digest at all.
-	fn seal(&self, _: &Self::Digest, partial_header: Header<()>) -> Option<Header<Self::Digest>> {
-		let mut h:Header<u64> =  Header{
+impl<H: PowHasher> PoW<H> {
+	/// The cryptographic hash of the header's canonical encoding.
+	pub fn pow_hash(&self, header: &Header<u64>) -> [u8; 32] {
+		H::hash(&header.encode())
+	}
+
+	fn is_sealed(&self, header: &Header<u64>) -> bool {
+		self.target.is_met_by(&self.pow_hash(header))
+	}
+
+	/// Like `seal`, but gives up and returns None once `max_attempts` nonces have been tried or
+	/// the token is cancelled, whichever comes first. Sealing against an unreachable target would
+	/// otherwise never return. With several threads, each tries its share of the attempts.
+	pub fn seal_interruptible(
+		&self,
+		partial_header: Header<()>,
+		max_attempts: u64,
+		cancel: &CancelToken,
+	) -> Option<Header<u64>> {
+		if self.threads > 1 {
+			return self.seal_parallel(partial_header, max_attempts, cancel);
+		}
+
+		let mut h: Header<u64> = Header {
 			parent: partial_header.parent,
 			height: partial_header.height,
 			state_root: partial_header.state_root,
 			extrinsics_root: partial_header.extrinsics_root,
 			consensus_digest: 10,
 		};
-
-		while hash(&h) > self.threshold {
-			h.consensus_digest += 1;
+		for _ in 0..max_attempts {
+			if cancel.is_cancelled() {
+				return None;
+			}
+			if self.is_sealed(&h) {
+				return Some(h);
+			}
+			h.consensus_digest = h.consensus_digest.checked_add(1)?;
 		}
+		None
+	}
+
+	/// Mine on several threads at once, each searching its own range of nonces. The first thread
+	/// to find a valid seal tells the others to stop, so which seal is returned depends on how
+	/// the threads happen to be scheduled.
+	fn seal_parallel(&self, partial_header: Header<()>, max_attempts: u64, cancel: &CancelToken) -> Option<Header<u64>> {
+		let threads = self.threads as u64;
+		let range_len = u64::MAX / threads;
+		let attempts_per_thread = max_attempts.div_ceil(threads);
+		let found = AtomicBool::new(false);
+		let seal = Mutex::new(None);
+
+		std::thread::scope(|scope| {
+			for i in 0..threads {
+				let first = i * range_len;
+				let last = if i + 1 == threads { u64::MAX } else { first + range_len - 1 };
+				let mut h = Header {
+					parent: partial_header.parent,
+					height: partial_header.height,
+					state_root: partial_header.state_root,
+					extrinsics_root: partial_header.extrinsics_root,
+					consensus_digest: first,
+				};
+				let (found, seal) = (&found, &seal);
+				scope.spawn(move || {
+					for _ in 0..attempts_per_thread {
+						if found.load(Ordering::Relaxed) || cancel.is_cancelled() {
+							return;
+						}
+						if self.is_sealed(&h) {
+							if !found.swap(true, Ordering::Relaxed) {
+								*seal.lock().unwrap() = Some(h);
+							}
+							return;
+						}
+						if h.consensus_digest == last {
+							return;
+						}
+						h.consensus_digest += 1;
+					}
+				});
+			}
+		});
+
+		seal.into_inner().unwrap()
+	}
+}
+
+
+impl<H: PowHasher> Consensus for PoW<H> {
+	type Digest = u64;
+
+	/// Check that the provided header's hash does not exceed the target.
+	/// This does not rely on the parent digest at all.
+	fn validate(&self, _: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+		self.is_sealed(header)
+	}
 
-		return Option::Some(h);
+	/// Mine a new PoW seal for the partial header provided, on as many threads as configured.
+	/// This does not rely on the parent digest at all. Returns None only if no nonce at all is
+	/// valid, which may take practically forever to find out. Use `seal_interruptible` to give
+	/// up sooner.
+	fn seal(&self, _: &Self::Digest, partial_header: Header<()>) -> Option<Header<Self::Digest>> {
+		self.seal_interruptible(partial_header, u64::MAX, &CancelToken::new())
 	}
 
 	fn create_default_instance() -> Self{
-		return Self {
-			threshold:u64::max_value() / 100,
+		Self::with_target(Target::from_difficulty(100))
+	}
+}
+
+/// Governance can make mining easier or harder by changing the threshold, which becomes the most
+/// significant 64 bits of the new target.
+impl<H: PowHasher> Configurable for PoW<H> {
+	fn apply_parameter_change(&mut self, change: &ParameterChange) -> bool {
+		match change.parameter {
+			Parameter::PowThreshold => {
+				self.target = Target::from_threshold(change.value);
+				true
+			}
+			_ => false,
 		}
 	}
 }
@@ -63,8 +268,114 @@ impl Consensus for PoW {
 /// with randomly drawn nonces will be valid. That is: the threshold should be u64::max_value() /
 /// 100.
 pub fn moderate_difficulty_pow() -> impl Consensus {
-	let pow = PoW {
-		threshold: u64::max_value() / 100
-	};
+	let pow = PoW::new(u64::MAX / 100);
 	return pow;
 }
+
+#[cfg(test)]
+fn partial_header(height: u64) -> Header<()> {
+	Header { parent: 0, height, state_root: 7, extrinsics_root: 8, consensus_digest: () }
+}
+
+#[test]
+fn test_parallel_seal_is_valid() {
+	let pow = PoW::new(u64::MAX / 1000).with_threads(4);
+
+	for height in 1..5 {
+		let header = pow.seal(&0, partial_header(height)).unwrap();
+		assert!(pow.validate(&0, &header));
+		assert_eq!(header.state_root, 7);
+	}
+}
+
+#[test]
+fn test_parallel_seal_searches_every_range() {
+	// Only a tiny fraction of nonces is valid, so most threads' ranges have none near their
+	// start and the seal usually comes from further in.
+	let pow = PoW::new(u64::MAX / 50_000).with_threads(8);
+	let header = pow.seal(&0, partial_header(1)).unwrap();
+
+	assert!(pow.validate(&0, &header));
+}
+
+#[test]
+fn test_thread_count_is_at_least_one() {
+	assert_eq!(PoW::new(1).threads(), 1);
+	assert_eq!(PoW::new(1).with_threads(0).threads(), 1);
+	assert_eq!(PoW::new(1).with_threads(3).threads(), 3);
+}
+
+#[test]
+fn test_target_from_difficulty() {
+	assert_eq!(Target::from_difficulty(0), Target::MAX);
+	assert_eq!(Target::from_difficulty(1), Target::MAX);
+
+	let mut half = Target::MAX;
+	half.0[0] = 0x7f;
+	assert_eq!(Target::from_difficulty(2), half);
+	assert_eq!(Target::from_difficulty(256).0[0], 0);
+	assert_eq!(Target::from_difficulty(100).threshold(), u64::MAX / 100);
+	assert!(Target::from_difficulty(1000) < Target::from_difficulty(999));
+}
+
+#[test]
+fn test_target_compares_hashes_as_numbers() {
+	let target = Target::from_threshold(1 << 56);
+	let mut hash = [0u8; 32];
+
+	hash[0] = 1;
+	assert!(target.is_met_by(&hash));
+	hash[1] = 1;
+	assert!(!target.is_met_by(&hash));
+	assert!(target.is_met_by(&target.0));
+}
+
+#[test]
+fn test_hasher_is_pluggable() {
+	let sha = PoW::new(u64::MAX / 20);
+	let blake = sha.clone().with_hasher::<Blake2b256>();
+	let header = sha.seal(&0, partial_header(1)).unwrap();
+
+	assert_ne!(sha.pow_hash(&header), blake.pow_hash(&header));
+	assert!(sha.validate(&0, &header));
+	let header = blake.seal(&0, partial_header(1)).unwrap();
+	assert!(blake.validate(&0, &header));
+}
+
+#[test]
+fn test_pow_hash_covers_every_field() {
+	let pow = PoW::new(u64::MAX);
+	let header = pow.seal(&0, partial_header(1)).unwrap();
+	let mut changed = header.clone();
+	changed.extrinsics_root += 1;
+
+	assert_ne!(pow.pow_hash(&header), pow.pow_hash(&changed));
+	// The hash is SHA-256 of the canonical encoding, not something the platform may vary.
+	assert_eq!(pow.pow_hash(&header), Sha256::hash(&header.encode()));
+}
+
+#[test]
+fn test_seal_gives_up_after_max_attempts() {
+	let unreachable = PoW::<Sha256>::with_target(Target([0; 32]));
+
+	assert_eq!(unreachable.seal_interruptible(partial_header(1), 1000, &CancelToken::new()), None);
+	assert_eq!(unreachable.with_threads(4).seal_interruptible(partial_header(1), 1000, &CancelToken::new()), None);
+	let easy = PoW::new(u64::MAX / 10);
+	assert!(easy.seal_interruptible(partial_header(1), 10_000, &CancelToken::new()).is_some());
+}
+
+#[test]
+fn test_seal_stops_when_cancelled() {
+	for threads in [1, 4] {
+		let unreachable = PoW::<Sha256>::with_target(Target([0; 32])).with_threads(threads);
+		let cancel = CancelToken::new();
+		let canceller = cancel.clone();
+		let timer = std::thread::spawn(move || {
+			std::thread::sleep(std::time::Duration::from_millis(20));
+			canceller.cancel();
+		});
+
+		assert_eq!(unreachable.seal_interruptible(partial_header(1), u64::MAX, &cancel), None);
+		timer.join().unwrap();
+	}
+}
@@ -9,77 +9,75 @@
 //! Even when using the Proof of Stake configuration, the underlying consensus logic is identical to
 //! the proof of authority we are writing here.
 
-use super::{Consensus, ConsensusAuthority, Header};
+use super::envelope::DigestEnvelope;
+use super::equivocation::SlotAuthorship;
+use super::{Consensus, ConsensusAuthority, Header, SystemClock, TimeProvider};
+#[cfg(test)]
+use super::MockClock;
 
 /// A Proof of Authority consensus engine. If any of the authorities have signed the block, it is
 /// valid.
 pub struct SimplePoa {
 	pub authorities: Vec<ConsensusAuthority>,
+	/// The authority this node seals as, if it is one
+	local_authority: Option<ConsensusAuthority>,
 }
 
+impl SimplePoa {
+	pub fn new(authorities: Vec<ConsensusAuthority>) -> Self {
+		SimplePoa { authorities, local_authority: None }
+	}
+
+	/// Seal as the given authority, and only if it is one of the authorities. Without one, blocks
+	/// are sealed by the last authority, or by the first if the last sealed the parent.
+	pub fn sealing_as(mut self, authority: ConsensusAuthority) -> Self {
+		self.local_authority = Some(authority);
+		self
+	}
 
+	/// Who seals the child of a block sealed by the given authority, if anyone.
+	fn author_after(&self, parent_digest: &ConsensusAuthority) -> Option<ConsensusAuthority> {
+		match self.local_authority {
+			Some(local) => self.authorities.contains(&local).then_some(local),
+			None => {
+				let last = *self.authorities.last()?;
+				if last == *parent_digest { Some(self.authorities[0]) } else { Some(last) }
+			}
+		}
+	}
+}
 
 impl Consensus for SimplePoa {
 	type Digest = ConsensusAuthority;
 
-	fn validate(&self, parent_digest: &Self::Digest, header: &Header<Self::Digest>) -> bool {
-		(header.consensus_digest == ConsensusAuthority::Charlie 
-		||
-		header.consensus_digest == ConsensusAuthority::Bob 
-		||
-		header.consensus_digest == ConsensusAuthority::Alice )
-
-		&&
-
-		(*parent_digest == ConsensusAuthority::Charlie 
-		||
-		*parent_digest == ConsensusAuthority::Bob 
-		||
-		*parent_digest == ConsensusAuthority::Alice )
-
-		
+	/// Check that the header is signed by one of the authorities.
+	fn validate(&self, _: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+		self.authorities.contains(&header.consensus_digest)
 	}
 
+	/// Sign the given partial header as this node's authority.
 	fn seal(
 		&self,
 		parent_digest: &Self::Digest,
 		partial_header: Header<()>,
 	) -> Option<Header<Self::Digest>> {
-	
-		let author = self.authorities.last();
-		
-		match author {
-			Some(mut a) =>{
-				if a == parent_digest {
-					a = &self.authorities[0];
-				}
-				let h= Header::<Self::Digest> {
-					parent: partial_header.parent,
-					height: partial_header.height,
-					state_root: partial_header.state_root,
-					extrinsics_root: partial_header.extrinsics_root,
-					consensus_digest: *a,
-				};
-				return Some(h)
-			}
-			_ => {
-				return None;
-			}
-
-		}
-		
+		Some(Header {
+			parent: partial_header.parent,
+			height: partial_header.height,
+			state_root: partial_header.state_root,
+			extrinsics_root: partial_header.extrinsics_root,
+			consensus_digest: self.author_after(parent_digest)?,
+		})
 	}
-	
+
 	fn create_default_instance() -> Self {
-			SimplePoa{
-				authorities : vec![ConsensusAuthority::Alice,ConsensusAuthority::Bob,ConsensusAuthority::Charlie],
-			}
+		SimplePoa::new(vec![ConsensusAuthority::Alice, ConsensusAuthority::Bob, ConsensusAuthority::Charlie])
 	}
 }
 
 /// A Proof of Authority consensus engine. Only one authority is valid at each block height.
 /// As ever, the genesis block does not require a seal. After that the authorities take turns
-/// in order.
+/// in order, however many there are. Genesis is taken to be signed by the first authority.
 struct PoaRoundRobinByHeight {
 	authorities: Vec<ConsensusAuthority>,
 }
@@ -93,60 +91,50 @@ impl From<SimplePoa> for PoaRoundRobinByHeight {
 	}
 }
 
+impl PoaRoundRobinByHeight {
+	/// The authority whose turn it is at the given height, or None if there are no authorities.
+	fn author_at(&self, height: u64) -> Option<ConsensusAuthority> {
+		if self.authorities.is_empty() {
+			return None;
+		}
+		// The remainder is less than the number of authorities, so it fits in a usize.
+		Some(self.authorities[(height % self.authorities.len() as u64) as usize])
+	}
+
+	/// Whether the given digest is that of the parent of a block at the given height.
+	fn follows_parent(&self, parent_digest: &ConsensusAuthority, height: u64) -> bool {
+		height > 0 && self.author_at(height - 1) == Some(*parent_digest)
+	}
+}
+
 impl Consensus for PoaRoundRobinByHeight {
 	type Digest = ConsensusAuthority;
 
+	/// Check that the header and its parent are signed by the authorities whose turns they are.
 	fn validate(&self, parent_digest: &Self::Digest, header: &Header<Self::Digest>) -> bool {
-		 if header.height % 3 == 1 {
-			return header.consensus_digest == ConsensusAuthority::Bob && *parent_digest == ConsensusAuthority::Alice
-		 }
-		 else if header.height % 3 == 0 {
-			return header.consensus_digest == ConsensusAuthority::Alice && *parent_digest == ConsensusAuthority::Charlie
-		 }
-		 else {
-			 return  header.consensus_digest == ConsensusAuthority::Charlie && *parent_digest == ConsensusAuthority::Bob
-		 }
+		self.follows_parent(parent_digest, header.height)
+			&& self.author_at(header.height) == Some(header.consensus_digest)
 	}
 
+	/// Sign the given partial header by the authority whose turn it is, if the parent was signed
+	/// in turn.
 	fn seal(
 		&self,
 		parent_digest: &Self::Digest,
 		partial_header: Header<()>,
 	) -> Option<Header<Self::Digest>> {
-		
-		let a = match partial_header.height % 3 {
-			0 => ConsensusAuthority::Alice,
-			1 => ConsensusAuthority::Bob,
-			2 => ConsensusAuthority::Charlie,
-			_ => {
-				return None;
-			}
-		};
-
-		let parent_is_correct = match a {
-			ConsensusAuthority::Alice => { *parent_digest == ConsensusAuthority::Charlie},
-			ConsensusAuthority::Bob   => { *parent_digest == ConsensusAuthority::Alice},
-			ConsensusAuthority::Charlie => { *parent_digest == ConsensusAuthority::Bob},
-		};
-
-		match parent_is_correct {
-			true => {
-				let h= Header::<Self::Digest> {
-					parent: partial_header.parent,
-					height: partial_header.height,
-					state_root: partial_header.state_root,
-					extrinsics_root: partial_header.extrinsics_root,
-					consensus_digest: a,
-				};
-			    Some(h)
-			},
-			_ => {
-				None
-			}
-
+		if !self.follows_parent(parent_digest, partial_header.height) {
+			return None;
 		}
+		Some(Header {
+			parent: partial_header.parent,
+			height: partial_header.height,
+			state_root: partial_header.state_root,
+			extrinsics_root: partial_header.extrinsics_root,
+			consensus_digest: self.author_at(partial_header.height)?,
+		})
 	}
-	
+
 	fn create_default_instance() -> Self {
 		SimplePoa::create_default_instance().into()
 	}
@@ -162,66 +150,294 @@ impl Consensus for PoaRoundRobinByHeight {
 ///
 /// A common PoA scheme that works around these weaknesses is to divide time into slots, and then do
 /// a round robin by slot instead of by height
-struct PoaRoundRobinBySlot {
+///
+/// Slots are derived from wall-clock time: the digest carries the time the block was sealed, and
+/// its slot is that time divided by the slot duration.
+struct PoaRoundRobinBySlot<Clock = SystemClock> {
 	authorities: Vec<ConsensusAuthority>,
+	/// The length of a slot in milliseconds. Always at least one.
+	slot_duration: u64,
+	clock: Clock,
 }
 
-/// A digest used for PoaRoundRobinBySlot. The digest contains the slot number as well as the
-/// signature. In addition to checking that the right signer has signed for the slot, you must check
-/// that the slot is always strictly increasing. But remember that slots may be skipped.
-#[derive(Hash, Debug, PartialEq, Eq, Clone, Copy)]
-struct SlotDigest {
-	slot: u64,
-	signature: ConsensusAuthority,
+impl<Clock> PoaRoundRobinBySlot<Clock> {
+	fn new(authorities: Vec<ConsensusAuthority>, slot_duration: u64, clock: Clock) -> Self {
+		PoaRoundRobinBySlot { authorities, slot_duration: slot_duration.max(1), clock }
+	}
+
+	/// The slot a block sealed at the given time belongs to.
+	fn slot_at(&self, timestamp: u64) -> u64 {
+		timestamp / self.slot_duration
+	}
+
+	/// The authority whose turn it is in the given slot, or None if there are no authorities.
+	fn author_of(&self, slot: u64) -> Option<ConsensusAuthority> {
+		if self.authorities.is_empty() {
+			return None;
+		}
+		// The remainder is less than the number of authorities, so it fits in a usize.
+		Some(self.authorities[(slot % self.authorities.len() as u64) as usize])
+	}
 }
 
-impl Consensus for PoaRoundRobinBySlot {
+/// A digest used for PoaRoundRobinBySlot. The digest contains the time the block was sealed, from
+/// which its slot is derived, as well as the signature, which is the envelope's author. Blocks
+/// missing either are invalid. In addition to checking that the right signer has signed for the
+/// slot, you must check that the slot is always strictly increasing. But remember that slots may
+/// be skipped.
+type SlotDigest = DigestEnvelope<()>;
+
+impl<Clock: TimeProvider + Default> Consensus for PoaRoundRobinBySlot<Clock> {
 	type Digest = SlotDigest;
 
+	/// Check that the block was not sealed in the future, that its slot comes after its parent's,
+	/// and that it is signed by the slot's authority.
 	fn validate(&self, parent_digest: &Self::Digest, header: &Header<Self::Digest>) -> bool {
-		if header.consensus_digest.slot % 3 == 1 {
-			return header.consensus_digest.signature == ConsensusAuthority::Bob && parent_digest.slot < header.consensus_digest.slot
-		 }
-		 else if header.height % 3 == 0 {
-			return header.consensus_digest.signature == ConsensusAuthority::Alice && parent_digest.slot < header.consensus_digest.slot
-		 }
-		 else {
-			 return  header.consensus_digest.signature == ConsensusAuthority::Charlie && parent_digest.slot < header.consensus_digest.slot
-		 }
+		let digest = &header.consensus_digest;
+		let (Some(timestamp), Some(signature)) = (digest.timestamp, digest.author) else {
+			return false;
+		};
+		let slot = self.slot_at(timestamp);
+
+		timestamp <= self.clock.now()
+			&& slot > self.slot_at(parent_digest.timestamp.unwrap_or(0))
+			&& self.author_of(slot) == Some(signature)
 	}
 
+	/// Sign the block by the authority of the current slot. Returns None if the parent was sealed
+	/// in the current slot or later, in which case the caller must wait for the next slot.
 	fn seal(
 		&self,
 		parent_digest: &Self::Digest,
 		partial_header: Header<()>,
 	) -> Option<Header<Self::Digest>> {
-		let a = match (parent_digest.slot+1) % 3 {
-			0 => ConsensusAuthority::Alice,
-			1 => ConsensusAuthority::Bob,
-			2 => ConsensusAuthority::Charlie,
-			_ => {
-				return None;
-			}
-		};      
+		let timestamp = self.clock.now();
+		let slot = self.slot_at(timestamp);
+		if slot <= self.slot_at(parent_digest.timestamp.unwrap_or(0)) {
+			return None;
+		}
 
 		let h= Header::<Self::Digest> {
 			parent: partial_header.parent,
 			height: partial_header.height,
 			state_root: partial_header.state_root,
 			extrinsics_root: partial_header.extrinsics_root,
-			consensus_digest: SlotDigest{ 
-						slot:parent_digest.slot+1, 
-						signature:a 
-					}
+			consensus_digest: DigestEnvelope::new(())
+				.with_author(self.author_of(slot)?)
+				.with_timestamp(timestamp),
 			};
 
 		Some(h)
-			
 	}
 
+	/// Alice, Bob and Charlie take turns in slots of six seconds.
 	fn create_default_instance() -> Self{
-		return Self {
-			authorities: vec![ConsensusAuthority::Alice,ConsensusAuthority::Bob,ConsensusAuthority::Charlie]
-		}
+		Self::new(
+			vec![ConsensusAuthority::Alice,ConsensusAuthority::Bob,ConsensusAuthority::Charlie],
+			6000,
+			Clock::default(),
+		)
+	}
+}
+
+/// Sealing two blocks in the same slot is an offence, which the slot's author can be slashed for.
+impl<Clock> SlotAuthorship<SlotDigest> for PoaRoundRobinBySlot<Clock> {
+	fn slot_author(&self, header: &Header<SlotDigest>) -> Option<(u64, ConsensusAuthority)> {
+		let digest = &header.consensus_digest;
+		let (timestamp, signature) = (digest.timestamp?, digest.author?);
+		let slot = self.slot_at(timestamp);
+		(self.author_of(slot) == Some(signature)).then_some((slot, signature))
 	}
 }
+
+#[cfg(test)]
+fn slot_engine(clock: &MockClock) -> PoaRoundRobinBySlot<MockClock> {
+	PoaRoundRobinBySlot::new(
+		vec![ConsensusAuthority::Alice, ConsensusAuthority::Bob, ConsensusAuthority::Charlie],
+		1000,
+		clock.clone(),
+	)
+}
+
+#[cfg(test)]
+fn partial_header(height: u64) -> Header<()> {
+	Header { parent: 0, height, state_root: 0, extrinsics_root: 0, consensus_digest: () }
+}
+
+#[cfg(test)]
+const SLOT_GENESIS: SlotDigest = slot_digest(0, ConsensusAuthority::Alice);
+
+#[cfg(test)]
+const fn slot_digest(timestamp: u64, signature: ConsensusAuthority) -> SlotDigest {
+	DigestEnvelope { inner: (), author: Some(signature), timestamp: Some(timestamp) }
+}
+
+#[test]
+fn test_slot_authority_follows_the_clock() {
+	let clock = MockClock::new(4500);
+	let poa = slot_engine(&clock);
+
+	let first = poa.seal(&SLOT_GENESIS, partial_header(1)).unwrap();
+	assert_eq!(first.consensus_digest, slot_digest(4500, ConsensusAuthority::Bob));
+	assert!(poa.validate(&SLOT_GENESIS, &first));
+
+	// Slot 5 is skipped, nobody having sealed in it.
+	clock.advance(2000);
+	let second = poa.seal(&first.consensus_digest, partial_header(2)).unwrap();
+	assert_eq!(second.consensus_digest.author, Some(ConsensusAuthority::Alice));
+	assert!(poa.validate(&first.consensus_digest, &second));
+}
+
+#[test]
+fn test_one_block_per_slot() {
+	let clock = MockClock::new(1000);
+	let poa = slot_engine(&clock);
+	let first = poa.seal(&SLOT_GENESIS, partial_header(1)).unwrap();
+
+	clock.advance(999);
+	assert_eq!(poa.seal(&first.consensus_digest, partial_header(2)), None);
+
+	// Another block in the same slot, even by the right authority, is invalid.
+	let same_slot = Header { consensus_digest: DigestEnvelope { timestamp: Some(1999), ..first.consensus_digest }, ..first.clone() };
+	assert!(!poa.validate(&first.consensus_digest, &same_slot));
+
+	clock.advance(1);
+	assert!(poa.seal(&first.consensus_digest, partial_header(2)).is_some());
+}
+
+#[test]
+fn test_future_blocks_are_rejected() {
+	let clock = MockClock::new(5000);
+	let poa = slot_engine(&clock);
+	let future = Header {
+		parent: 0,
+		height: 1,
+		state_root: 0,
+		extrinsics_root: 0,
+		consensus_digest: slot_digest(6000, ConsensusAuthority::Alice),
+	};
+
+	assert!(!poa.validate(&SLOT_GENESIS, &future));
+	clock.set(6000);
+	assert!(poa.validate(&SLOT_GENESIS, &future));
+}
+
+#[test]
+fn test_wrong_slot_authority_is_rejected() {
+	let clock = MockClock::new(3000);
+	let poa = slot_engine(&clock);
+	let mut header = poa.seal(&SLOT_GENESIS, partial_header(1)).unwrap();
+
+	assert_eq!(header.consensus_digest.author, Some(ConsensusAuthority::Alice));
+	header.consensus_digest.author = Some(ConsensusAuthority::Charlie);
+	assert!(!poa.validate(&SLOT_GENESIS, &header));
+}
+
+#[test]
+fn test_sealing_twice_in_a_slot_is_provable() {
+	use super::equivocation::{EquivocationDetector, Offence};
+
+	let clock = MockClock::new(3000);
+	let poa = slot_engine(&clock);
+	let first = poa.seal(&SLOT_GENESIS, partial_header(1)).unwrap();
+	clock.advance(500);
+	let second = poa.seal(&SLOT_GENESIS, partial_header(1)).unwrap();
+	let mut detector = EquivocationDetector::new();
+
+	assert_eq!(detector.observe(&poa, &first), None);
+	let proof = detector.observe(&poa, &second).unwrap();
+	assert_eq!(proof.verify(&poa), Some(Offence { offender: ConsensusAuthority::Alice, slot: 3 }));
+}
+
+#[cfg(test)]
+fn five_authorities() -> Vec<ConsensusAuthority> {
+	use ConsensusAuthority::*;
+	vec![Alice, Bob, Charlie, Dave, Eve]
+}
+
+#[test]
+fn test_round_robin_by_height_with_five_authorities() {
+	let poa = PoaRoundRobinByHeight { authorities: five_authorities() };
+	let mut chain = vec![Header { parent: 0, height: 0, state_root: 0, extrinsics_root: 0, consensus_digest: ConsensusAuthority::Alice }];
+	for height in 1..12 {
+		let parent = chain.last().unwrap();
+		let partial = Header { parent: crate::hash(parent), ..partial_header(height) };
+		chain.push(poa.seal(&parent.consensus_digest, partial).unwrap());
+	}
+
+	let authors: Vec<_> = chain.iter().map(|h| h.consensus_digest).collect();
+	assert_eq!(authors[5..10], five_authorities()[..]);
+	assert_eq!(authors[11], ConsensusAuthority::Bob);
+	assert!(poa.verify_sub_chain(&chain[0].consensus_digest, &chain));
+
+	// Eve signs height 4, so she cannot also sign height 5, nor can height 5 follow Dave.
+	let mut out_of_turn = chain[5].clone();
+	out_of_turn.consensus_digest = ConsensusAuthority::Eve;
+	assert!(!poa.validate(&chain[4].consensus_digest, &out_of_turn));
+	assert!(!poa.validate(&ConsensusAuthority::Dave, &chain[5]));
+	assert_eq!(poa.seal(&ConsensusAuthority::Dave, partial_header(5)), None);
+}
+
+#[test]
+fn test_round_robin_by_slot_with_five_authorities() {
+	let clock = MockClock::new(3000);
+	let poa = PoaRoundRobinBySlot::new(five_authorities(), 1000, clock.clone());
+	let first = poa.seal(&SLOT_GENESIS, partial_header(1)).unwrap();
+	assert_eq!(first.consensus_digest.author, Some(ConsensusAuthority::Dave));
+
+	// Slots 4 to 8 are skipped. Slot 9 is Eve's, since 9 % 5 = 4.
+	clock.set(9500);
+	let second = poa.seal(&first.consensus_digest, partial_header(2)).unwrap();
+	assert_eq!(second.consensus_digest, slot_digest(9500, ConsensusAuthority::Eve));
+	assert!(poa.validate(&first.consensus_digest, &second));
+
+	let wrong_signer = Header { consensus_digest: slot_digest(9500, ConsensusAuthority::Charlie), ..second.clone() };
+	assert!(!poa.validate(&first.consensus_digest, &wrong_signer));
+	// A block in slot 8 cannot follow one in slot 9, although slot 8 is Dave's.
+	let backwards = Header { consensus_digest: slot_digest(8000, ConsensusAuthority::Dave), ..second.clone() };
+	assert!(!poa.validate(&second.consensus_digest, &backwards));
+	assert!(poa.validate(&first.consensus_digest, &backwards));
+}
+
+#[test]
+fn test_simple_poa_only_accepts_its_authorities() {
+	let poa = SimplePoa::new(vec![ConsensusAuthority::Alice, ConsensusAuthority::Bob]);
+	let header = poa.seal(&ConsensusAuthority::Alice, partial_header(1)).unwrap();
+	assert!(poa.validate(&ConsensusAuthority::Alice, &header));
+
+	let mut outsider = header.clone();
+	outsider.consensus_digest = ConsensusAuthority::Charlie;
+	assert!(!poa.validate(&ConsensusAuthority::Alice, &outsider));
+	outsider.consensus_digest = ConsensusAuthority::Eve;
+	assert!(!poa.validate(&ConsensusAuthority::Alice, &outsider));
+}
+
+#[test]
+fn test_simple_poa_seals_as_its_local_authority() {
+	let authorities = vec![ConsensusAuthority::Alice, ConsensusAuthority::Bob, ConsensusAuthority::Charlie];
+	let alice = SimplePoa::new(authorities.clone()).sealing_as(ConsensusAuthority::Alice);
+	for parent in [ConsensusAuthority::Alice, ConsensusAuthority::Charlie] {
+		let header = alice.seal(&parent, partial_header(1)).unwrap();
+		assert_eq!(header.consensus_digest, ConsensusAuthority::Alice);
+		assert!(alice.validate(&parent, &header));
+	}
+
+	// A node that is not an authority cannot seal.
+	let dave = SimplePoa::new(authorities).sealing_as(ConsensusAuthority::Dave);
+	assert_eq!(dave.seal(&ConsensusAuthority::Alice, partial_header(1)), None);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_poa_header_round_trips_through_json() {
+	let header = Header {
+		parent: 1,
+		height: 2,
+		state_root: 3,
+		extrinsics_root: 4,
+		consensus_digest: ConsensusAuthority::Bob,
+	};
+	let json = serde_json::to_string(&header).unwrap();
+
+	assert_eq!(serde_json::from_str::<Header<ConsensusAuthority>>(&json).unwrap(), header);
+}
@@ -2,219 +2,306 @@
 //! one of them. But other chains would like consensus properties that fall in between. To achieve
 //! this we could consider interleaving PoW blocks with PoA blocks. Some very early designs of
 //! Ethereum considered this approach as a way to transition away from PoW.
+//!
+//! Nothing about interleaving is specific to PoW and PoA, so the engine here interleaves any two
+//! engines, following a repeating pattern of turns. Each engine checks a block against the digest
+//! of the last block it sealed itself, which need not be the parent, so every digest carries the
+//! latest digest of the other engine along with its own.
 
-use crate::hash;
+use super::{p1_pow::PoW, p3_poa::SimplePoa, Consensus, Header};
+
+/// Which of the two interleaved engines seals a block
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Turn {
+    A,
+    B,
+}
 
-use super::{p1_pow::PoW, p3_poa::SimplePoa, Consensus, ConsensusAuthority,Header};
+impl Turn {
+    /// Parse a pattern of turns written like `"A,A,B"`. Returns None if any turn is neither `A`
+    /// nor `B`, or there are none.
+    pub fn parse_pattern(pattern: &str) -> Option<Vec<Turn>> {
+        let turns = pattern
+            .split(',')
+            .map(|turn| match turn.trim() {
+                "A" => Some(Turn::A),
+                "B" => Some(Turn::B),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        (!turns.is_empty()).then_some(turns)
+    }
+}
 
-/// A Consensus engine that alternates back and forth between PoW and PoA sealed blocks.
-struct AlternatingPowPoa{
-    inner_pow   : PoW,
-    inner_poa   : SimplePoa,
+/// The digest of an interleaved block: the digest of the engine that sealed it, and the latest
+/// digest of the other engine
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum InterleavedDigest<DA, DB> {
+    A { digest: DA, last_b: DB },
+    B { digest: DB, last_a: DA },
 }
 
-#[derive(Hash, Debug, PartialEq, Eq, Clone, Copy)]
-struct AlternatingPowPoaDigest {
-    authority: Option<ConsensusAuthority>,
-    digest_for_threshold: Option<u64>
+impl<DA, DB> InterleavedDigest<DA, DB> {
+    /// The digest of a genesis block, which stands in for the last block of both engines.
+    pub fn genesis(a: DA, b: DB) -> Self {
+        InterleavedDigest::A {
+            digest: a,
+            last_b: b,
+        }
+    }
+
+    /// The digest of the latest block sealed by engine A.
+    pub fn a(&self) -> &DA {
+        match self {
+            InterleavedDigest::A { digest, .. } => digest,
+            InterleavedDigest::B { last_a, .. } => last_a,
+        }
+    }
+
+    /// The digest of the latest block sealed by engine B.
+    pub fn b(&self) -> &DB {
+        match self {
+            InterleavedDigest::A { last_b, .. } => last_b,
+            InterleavedDigest::B { digest, .. } => digest,
+        }
+    }
+
+    /// The engine that sealed the block.
+    pub fn turn(&self) -> Turn {
+        match self {
+            InterleavedDigest::A { .. } => Turn::A,
+            InterleavedDigest::B { .. } => Turn::B,
+        }
+    }
 }
 
-impl Consensus for AlternatingPowPoa{
-    type Digest = AlternatingPowPoaDigest;
+/// The same header with another digest
+fn with_digest<X, Y>(header: &Header<X>, consensus_digest: Y) -> Header<Y> {
+    Header {
+        parent: header.parent,
+        height: header.height,
+        state_root: header.state_root,
+        extrinsics_root: header.extrinsics_root,
+        consensus_digest,
+    }
+}
 
+/// A Consensus engine that interleaves blocks sealed by two engines. The block at each height is
+/// sealed by the engine whose turn it is in the repeating pattern.
+pub struct Interleaved<A, B> {
+    pub a: A,
+    pub b: B,
+    /// Never empty
+    pattern: Vec<Turn>,
+}
+
+impl<A, B> Interleaved<A, B> {
+    /// Interleave the given engines following the given pattern, starting at genesis. An empty
+    /// pattern alternates, starting with A.
+    pub fn new(a: A, b: B, pattern: Vec<Turn>) -> Self {
+        let pattern = if pattern.is_empty() {
+            vec![Turn::A, Turn::B]
+        } else {
+            pattern
+        };
+        Interleaved { a, b, pattern }
+    }
+
+    /// The engine whose turn it is to seal the block at the given height.
+    pub fn turn_at(&self, height: u64) -> Turn {
+        // The remainder is less than the pattern's length, so it fits in a usize.
+        self.pattern[(height % self.pattern.len() as u64) as usize]
+    }
+}
+
+impl<A: Consensus, B: Consensus> Consensus for Interleaved<A, B> {
+    type Digest = InterleavedDigest<A::Digest, B::Digest>;
+
+    /// Check that the block is sealed by the engine whose turn it is, according to that engine's
+    /// rules, and that it carries the other engine's latest digest forward.
     fn validate(&self, parent_digest: &Self::Digest, header: &Header<Self::Digest>) -> bool {
-		match header.height % 2 {
-
-            1 => {
-                 let parent_digest_pow = parent_digest.digest_for_threshold;
-                 match parent_digest_pow {
-                    Some(parent_digest_pow_value) => {
-                        let pow_header =  Header::<u64>{
-                            parent: header.parent,
-                            height: header.height,
-                            state_root: header.state_root,
-                            extrinsics_root: header.extrinsics_root,
-                            consensus_digest: header.consensus_digest.digest_for_threshold.unwrap(),
-                         };
-                         return self.inner_pow.validate( &0, &pow_header);
-                    }
-                    None => {
-                        return false;
-                    },
-                 }       
-            },
-
-            _ => {
-                let parent_digest_poa = parent_digest.authority;
-                match parent_digest_poa {
-                    Some(parent_digest_poa_value) => {
-                        let poa_header =  Header::<ConsensusAuthority>{
-                            parent: header.parent,
-                            height: header.height,
-                            state_root: header.state_root,
-                            extrinsics_root: header.extrinsics_root,
-                            consensus_digest: header.consensus_digest.authority.unwrap(),
-                            };
-                            return self.inner_poa.validate(&parent_digest_poa_value, &poa_header);
-                    },
-                    None => {
-                        return false;
-                    },   
-                }
+        match (self.turn_at(header.height), &header.consensus_digest) {
+            (Turn::A, InterleavedDigest::A { digest, last_b }) => {
+                last_b == parent_digest.b()
+                    && self
+                        .a
+                        .validate(parent_digest.a(), &with_digest(header, digest.clone()))
             }
+            (Turn::B, InterleavedDigest::B { digest, last_a }) => {
+                last_a == parent_digest.a()
+                    && self
+                        .b
+                        .validate(parent_digest.b(), &with_digest(header, digest.clone()))
+            }
+            _ => false,
         }
-	}
-
-
-	fn seal(
-		&self,
-		parent_digest: &Self::Digest,
-		partial_header: Header<()>,
-	) -> Option<Header<Self::Digest>> {
-
-        match partial_header.height % 2 {
-
-            1 => {
-                let parent_digest_pow = parent_digest.digest_for_threshold;
-                match parent_digest_pow {
-                    Some(parent_digest_pow_value) => {
-                        let pow_header = self.inner_pow.seal(&parent_digest_pow_value, partial_header);
-                        return match pow_header {
-                            Some (pw) => {
-                                let header = Header::<Self::Digest> {
-                                    parent: pw.parent,
-                                    height: pw.height,
-                                    state_root: pw.state_root,
-                                    extrinsics_root: pw.extrinsics_root,
-                                    consensus_digest: AlternatingPowPoaDigest {
-                                            authority: None,
-                                            digest_for_threshold : Some(pw.consensus_digest),
-                                    }
-                                };
-                                Some(header)
-                            },
-                            None => { 
-                                None 
-                            }
-                         }
-
-                    }
-                    None => {
-                        None
-                    }
-                }
-            },
-
-            _ => {
-                let parent_digest_poa = parent_digest.authority;
-                match parent_digest_poa {
-                    Some(parent_digets_pa_value) => {
-                        let poa_header = self.inner_poa.seal(&parent_digets_pa_value, partial_header);
-                        return match poa_header {
-                            Some (pa) => {
-                                let header = Header::<Self::Digest> {
-                                    parent: pa.parent,
-                                    height: pa.height,
-                                    state_root: pa.state_root,
-                                    extrinsics_root: pa.extrinsics_root,
-                                    consensus_digest: AlternatingPowPoaDigest {
-                                            authority: Some(pa.consensus_digest),
-                                            digest_for_threshold : None,
-                                    }
-                                };
-                                Some(header)
-                            },
-                            None => { 
-                                None 
-                            }
-                        };
-                    },
-                    None => {
-                        None
-                    }
-                }
+    }
+
+    /// Seal the block with the engine whose turn it is.
+    fn seal(
+        &self,
+        parent_digest: &Self::Digest,
+        partial_header: Header<()>,
+    ) -> Option<Header<Self::Digest>> {
+        match self.turn_at(partial_header.height) {
+            Turn::A => {
+                let sealed = self.a.seal(parent_digest.a(), partial_header)?;
+                let digest = InterleavedDigest::A {
+                    digest: sealed.consensus_digest.clone(),
+                    last_b: parent_digest.b().clone(),
+                };
+                Some(with_digest(&sealed, digest))
+            }
+            Turn::B => {
+                let sealed = self.b.seal(parent_digest.b(), partial_header)?;
+                let digest = InterleavedDigest::B {
+                    digest: sealed.consensus_digest.clone(),
+                    last_a: parent_digest.a().clone(),
+                };
+                Some(with_digest(&sealed, digest))
             }
         }
     }
 
-    fn create_default_instance() -> Self{
-		return Self {
-            inner_poa : SimplePoa::create_default_instance(),
-            inner_pow : PoW::create_default_instance(),
-		}
-	}
+    fn human_name() -> String {
+        format!("{} interleaved with {}", A::human_name(), B::human_name())
+    }
 
+    /// The default instances of both engines, taking turns.
+    fn create_default_instance() -> Self {
+        Interleaved::new(
+            A::create_default_instance(),
+            B::create_default_instance(),
+            vec![Turn::A, Turn::B],
+        )
+    }
 }
 
+/// A Consensus engine that alternates back and forth between PoA and PoW sealed blocks, starting
+/// with PoA at genesis.
+pub type AlternatingPowPoa = Interleaved<SimplePoa, PoW>;
 
-#[test]
-
+#[cfg(test)]
+use super::ConsensusAuthority;
+#[cfg(test)]
+use crate::hash;
 
+#[test]
 fn test_consensus_for_alternate_pow_poa() {
+    type PowPoaDigest = <AlternatingPowPoa as Consensus>::Digest;
 
-    type  PowPoaDigest = <AlternatingPowPoa as Consensus>::Digest;
-    let mut chain:Vec< Header< PowPoaDigest > > = vec![];
-
-    let genensis = Header::< PowPoaDigest > {
+    let pow_poa_consensus = AlternatingPowPoa::new(
+        SimplePoa::new(vec![
+            ConsensusAuthority::Alice,
+            ConsensusAuthority::Bob,
+            ConsensusAuthority::Charlie,
+        ]),
+        PoW::new(u64::MAX / 100),
+        vec![Turn::A, Turn::B],
+    );
+    let mut chain = vec![Header::<PowPoaDigest> {
         parent: 0,
         height: 0,
         state_root: 0,
         extrinsics_root: 0,
-        consensus_digest: PowPoaDigest {
-                authority: Some(ConsensusAuthority::Bob),
-                digest_for_threshold: Some(0),
-            }
+        consensus_digest: InterleavedDigest::genesis(ConsensusAuthority::Bob, 0),
+    }];
+
+    for i in 1..10 {
+        let partial_header = Header::<()> {
+            parent: hash(&chain[i - 1]),
+            height: i as u64,
+            state_root: i as u64,
+            extrinsics_root: hash(&vec![1 + i, 2 + i, 3 + i]),
+            consensus_digest: (),
         };
+        let h = pow_poa_consensus
+            .seal(&chain[i - 1].consensus_digest, partial_header)
+            .unwrap();
+        chain.push(h);
+    }
+
+    let turns: Vec<_> = chain[1..5]
+        .iter()
+        .map(|h| h.consensus_digest.turn())
+        .collect();
+    assert_eq!(turns, vec![Turn::B, Turn::A, Turn::B, Turn::A]);
+    assert!(pow_poa_consensus.verify_sub_chain(&chain[0].consensus_digest, &chain));
+}
 
-    let genensis2 = Header::< PowPoaDigest > {
-        parent: hash(&genensis),
-        height: 1,
-        state_root: 1,
+#[test]
+fn test_interleaving_follows_the_pattern() {
+    let engine = Interleaved::new((), (), Turn::parse_pattern("A, A,B").unwrap());
+    let mut chain = vec![Header {
+        parent: 0,
+        height: 0,
+        state_root: 0,
         extrinsics_root: 0,
-        consensus_digest: PowPoaDigest {
-                authority: None,
-                digest_for_threshold: Some(0),
-            }
+        consensus_digest: InterleavedDigest::genesis((), ()),
+    }];
+    for height in 1..7 {
+        let parent = chain.last().unwrap();
+        let partial = Header {
+            parent: hash(parent),
+            height,
+            state_root: 0,
+            extrinsics_root: 0,
+            consensus_digest: (),
         };
+        chain.push(engine.seal(&parent.consensus_digest, partial).unwrap());
+    }
 
-    chain.push(genensis);
-    chain.push(genensis2);
-    
-    let pow_poa_consensus = AlternatingPowPoa {
-        inner_poa : SimplePoa {
-                    authorities: vec![ConsensusAuthority::Alice,ConsensusAuthority::Bob,ConsensusAuthority::Charlie]
-                },
-        inner_pow : PoW {
-            threshold: u64::max_value() / 100,
-        }
-    };
-
-    for i in 2..10 {
-        
+    let turns: Vec<_> = chain[1..]
+        .iter()
+        .map(|h| h.consensus_digest.turn())
+        .collect();
+    assert_eq!(
+        turns,
+        vec![Turn::A, Turn::B, Turn::A, Turn::A, Turn::B, Turn::A]
+    );
 
-        let partial_header =  Header::<()>{
-            parent: hash(&chain[i-2]),
-            height: i as u64,
-            state_root: i as u64,
-            extrinsics_root: hash(&vec![1+i,2+i,3+i]),
-            consensus_digest: ()
-        };
+    // A block sealed by the engine whose turn it is not is rejected.
+    let mut out_of_turn = chain[2].clone();
+    out_of_turn.consensus_digest = InterleavedDigest::A {
+        digest: (),
+        last_b: (),
+    };
+    assert!(!engine.validate(&chain[1].consensus_digest, &out_of_turn));
+    assert_eq!(Turn::parse_pattern("A,C"), None);
+    assert_eq!(Turn::parse_pattern(""), None);
+}
 
-        let h = pow_poa_consensus.seal(&chain[i-2].consensus_digest, partial_header);
-        match h {
-            Some(h) => {
-                chain.push(h);
-                println!("{:?}",chain[i]);
-            }
-            None => {println!("{:?}",chain[0]);}
-        }
-        
-    }
+#[test]
+fn test_each_engine_sees_its_own_latest_digest() {
+    let engine = Interleaved::new(
+        SimplePoa::new(vec![ConsensusAuthority::Alice, ConsensusAuthority::Bob]),
+        PoW::new(u64::MAX / 4),
+        vec![Turn::B, Turn::A],
+    );
+    let genesis = InterleavedDigest::genesis(ConsensusAuthority::Alice, 0);
+    let partial = |height| Header {
+        parent: 0,
+        height,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: (),
+    };
+    let b1 = engine.seal(&genesis, partial(1)).unwrap();
+    let b2 = engine.seal(&b1.consensus_digest, partial(2)).unwrap();
 
-    let mut check = true;
-    for i in 2..10 { 
-        check &= pow_poa_consensus.validate(&chain[i-2].consensus_digest, &chain[i])
-    }
-    assert!(check);
+    // The PoW block carries the PoA digest of the block before it forward.
+    assert_eq!(b1.consensus_digest.turn(), Turn::A);
+    assert_eq!(b2.consensus_digest.turn(), Turn::B);
+    assert_eq!(b2.consensus_digest.a(), b1.consensus_digest.a());
+    assert!(engine.validate(&b1.consensus_digest, &b2));
 
-}
\ No newline at end of file
+    // Dropping the carried digest is caught.
+    let mut forgetful = b2.clone();
+    forgetful.consensus_digest = InterleavedDigest::B {
+        digest: *b2.consensus_digest.b(),
+        last_a: ConsensusAuthority::Charlie,
+    };
+    assert!(!engine.validate(&b1.consensus_digest, &forgetful));
+}
@@ -8,7 +8,12 @@
 
 use std::{any::TypeId, marker::PhantomData};
 
+use super::envelope::DigestMetadata;
 use super::{p4_even_only::EvenOnly, p1_pow::PoW, p3_poa::SimplePoa, Consensus, ConsensusAuthority, Header};
+use super::spec::{wrong_engine, ConsensusSpec, FromSpec, SpecError};
+
+/// The fork height of engines built without a spec
+const DEFAULT_FORK_HEIGHT: u64 = 10;
 
 /// A Higher-order consensus engine that represents a change from one set of consensus rules
 /// (Before) to another set (After) at a specific block height
@@ -23,7 +28,7 @@ struct Forked<D, Before, After> {
 
 impl<D, B, A> Consensus for Forked<D, B, A>
 where
-	D: Clone + core::fmt::Debug + Eq + PartialEq + std::hash::Hash,
+	D: Clone + core::fmt::Debug + Eq + PartialEq + std::hash::Hash + DigestMetadata,
 	B: Consensus,
 	A: Consensus,
 	B::Digest: Into<D>,
@@ -107,7 +112,7 @@ where
 
 	fn create_default_instance() -> Self {
 		Self { 
-				fork_height:10, 
+				fork_height: DEFAULT_FORK_HEIGHT, 
 				phdata: PhantomData::<D>{},
 			 	inner_c_after: A::create_default_instance(),
 			  	inner_c_before: B::create_default_instance() 
@@ -143,6 +148,26 @@ fn human_name() -> String {
 
 }
 
+/// The engines on either side of the fork are built from their own specs.
+impl<D, B, A> FromSpec for Forked<D, B, A>
+where
+	Forked<D, B, A>: Consensus,
+	B: FromSpec,
+	A: FromSpec,
+{
+	fn from_spec(spec: &ConsensusSpec) -> Result<Self, SpecError> {
+		match spec {
+			ConsensusSpec::Forked { fork_height, before, after } => Ok(Forked {
+				fork_height: *fork_height,
+				phdata: PhantomData,
+				inner_c_after: A::from_spec(after)?,
+				inner_c_before: B::from_spec(before)?,
+			}),
+			other => Err(wrong_engine("forked", other)),
+		}
+	}
+}
+
 /// Create a PoA consensus engine that changes authorities part way through the chain's history.
 /// Given the initial authorities, the authorities after the fork, and the height at which the fork
 /// occurs.
@@ -153,9 +178,9 @@ fn change_authorities(
 ) -> impl Consensus {
 
 	Forked::<ConsensusAuthority,SimplePoa,SimplePoa>{
-		fork_height : 10,
-		inner_c_after : SimplePoa{ authorities:final_authorities},
-		inner_c_before: SimplePoa{ authorities:initial_authorities},
+		fork_height,
+		inner_c_after : SimplePoa::new(final_authorities),
+		inner_c_before: SimplePoa::new(initial_authorities),
 		phdata: PhantomData::<ConsensusAuthority>{},
 
 	}
@@ -169,9 +194,9 @@ fn change_difficulty(
 	final_difficulty: u64,
 ) -> impl Consensus {
 	Forked::<u64,PoW,PoW>{
-		fork_height : 10,
-		inner_c_after : PoW{ threshold:final_difficulty},
-		inner_c_before: PoW{ threshold:initial_difficulty},
+		fork_height,
+		inner_c_after : PoW::new(final_difficulty),
+		inner_c_before: PoW::new(initial_difficulty),
 		phdata: PhantomData::<u64>{},
 
 	}
@@ -238,6 +263,15 @@ impl From<PowOrPoaDigest> for u64 {
     }
 }
 
+impl DigestMetadata for PowOrPoaDigest {
+	fn author(&self) -> Option<ConsensusAuthority> {
+		match self {
+			PowOrPoaDigest::Pow(_) => None,
+			PowOrPoaDigest::Poa(authority) => Some(*authority),
+		}
+	}
+}
+
 /// In the spirit of Ethereum's recent switch from PoW to PoA, let us model a similar
 /// switch in our consensus framework. It should go without saying that the real-world ethereum
 /// handoff was considerably more complex than it may appear in our simplified example, although
@@ -253,12 +287,8 @@ fn pow_to_poa(
 
 	return ForkedPoaPow {
 		fork_height : fork_height,
-		inner_c_before: PoW{ 
-			threshold: difficulty 
-		},
-		inner_c_after: SimplePoa{
-			authorities:authorities
-		},
+		inner_c_before: PoW::new(difficulty),
+		inner_c_after: SimplePoa::new(authorities),
 		phdata: PhantomData::<PowOrPoaDigest>{},
 	}
 }
@@ -328,3 +358,25 @@ fn test_consensus_pow_to_poa(){
 	
 
 }
+
+#[test]
+fn test_forked_engine_from_spec(){
+	let spec = ConsensusSpec::Forked {
+		fork_height: 20,
+		before: Box::new(ConsensusSpec::Pow { threshold: u64::MAX / 4 }),
+		after: Box::new(ConsensusSpec::Poa { authorities: vec![ConsensusAuthority::Bob] }),
+	};
+	let consensus = ForkedPoaPow::from_spec(&spec).unwrap();
+	assert_eq!(consensus.fork_height, 20);
+	assert_eq!(consensus.inner_c_before, PoW::new(u64::MAX / 4));
+
+	let swapped = ConsensusSpec::Forked {
+		fork_height: 20,
+		before: Box::new(ConsensusSpec::Poa { authorities: vec![ConsensusAuthority::Bob] }),
+		after: Box::new(ConsensusSpec::Poa { authorities: vec![ConsensusAuthority::Bob] }),
+	};
+	assert_eq!(
+		ForkedPoaPow::from_spec(&swapped).err(),
+		Some(SpecError::WrongEngine { expected: "pow", found: "poa" })
+	);
+}
@@ -0,0 +1,233 @@
+//! The Proof of Authority engines so far are given a fixed list of authorities when they are
+//! created. In a Proof of Stake chain the authorities are instead whoever the staking system says
+//! they are, and that changes from block to block as users bond and unbond.
+//!
+//! Here we write a PoA engine that looks its authorities up in the chain's state. Since the state
+//! is not part of the header, it implements `StatefulConsensus` rather than `Consensus`, and the
+//! caller passes in the post-state of the parent block. Every authority set is read from the
+//! state at the block's parent, so importers and authors of the same block always agree on it.
+
+use crate::c1_state_machine::p8_staking::StakingState;
+
+use super::{ConsensusAuthority, Header, StatefulConsensus};
+
+/// A Proof of Authority engine whose authorities are read from the chain's state by the given
+/// lookup function. The authorities take turns by height, in the order the lookup returns them.
+pub struct DynamicPoa<Lookup> {
+    pub authorities_of: Lookup,
+}
+
+impl<Lookup> DynamicPoa<Lookup> {
+    pub fn new(authorities_of: Lookup) -> Self {
+        DynamicPoa { authorities_of }
+    }
+}
+
+impl DynamicPoa<fn(&StakingState) -> Vec<ConsensusAuthority>> {
+    /// A Proof of Stake engine in which every validator with stake takes a turn, most staked
+    /// first.
+    pub fn staked() -> Self {
+        DynamicPoa::new(|state: &StakingState| staked_authorities(state, usize::MAX))
+    }
+}
+
+/// The `n` validators with the most stake in the given staking state, most staked first.
+pub fn staked_authorities(state: &StakingState, n: usize) -> Vec<ConsensusAuthority> {
+    state
+        .top_stakers(n)
+        .into_iter()
+        .map(|(validator, _)| validator.into())
+        .collect()
+}
+
+impl<Lookup> DynamicPoa<Lookup> {
+    /// The authority expected to seal the block at the given height on top of the given parent
+    /// state, or None if the state names no authorities at all.
+    pub fn author_at<State>(&self, parent_state: &State, height: u64) -> Option<ConsensusAuthority>
+    where
+        Lookup: Fn(&State) -> Vec<ConsensusAuthority>,
+    {
+        let authorities = (self.authorities_of)(parent_state);
+        if authorities.is_empty() {
+            return None;
+        }
+        // The remainder is less than the number of authorities, so it fits in a usize.
+        let turn = height % authorities.len() as u64;
+        Some(authorities[turn as usize])
+    }
+}
+
+impl<State, Lookup> StatefulConsensus<State> for DynamicPoa<Lookup>
+where
+    Lookup: Fn(&State) -> Vec<ConsensusAuthority>,
+{
+    type Digest = ConsensusAuthority;
+
+    /// Check that the header is signed by the authority whose turn it is according to the
+    /// parent state.
+    fn validate_with_state(
+        &self,
+        parent_state: &State,
+        _: &Self::Digest,
+        header: &Header<Self::Digest>,
+    ) -> bool {
+        self.author_at(parent_state, header.height) == Some(header.consensus_digest)
+    }
+
+    /// Sign the given partial header by the authority whose turn it is according to the parent
+    /// state.
+    fn seal_with_state(
+        &self,
+        parent_state: &State,
+        _: &Self::Digest,
+        partial_header: Header<()>,
+    ) -> Option<Header<Self::Digest>> {
+        let author = self.author_at(parent_state, partial_header.height)?;
+        Some(Header {
+            parent: partial_header.parent,
+            height: partial_header.height,
+            state_root: partial_header.state_root,
+            extrinsics_root: partial_header.extrinsics_root,
+            consensus_digest: author,
+        })
+    }
+}
+
+#[cfg(test)]
+use crate::c1_state_machine::{p8_staking::Staking, StateMachine, User};
+
+#[cfg(test)]
+fn partial_header(height: u64) -> Header<()> {
+    Header {
+        parent: 0,
+        height,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: (),
+    }
+}
+
+/// Everyone endowed with 100, Alice bonding 50 and Bob 30.
+#[cfg(test)]
+fn two_validators() -> StakingState {
+    use crate::c1_state_machine::p8_staking::StakingTransaction::Bond;
+
+    let genesis = Staking::genesis_state(vec![
+        (User::Alice, 100),
+        (User::Bob, 100),
+        (User::Charlie, 100),
+    ]);
+    Staking::apply_all(
+        &genesis,
+        &[
+            Bond {
+                who: User::Bob,
+                amount: 30,
+            },
+            Bond {
+                who: User::Alice,
+                amount: 50,
+            },
+        ],
+    )
+}
+
+#[test]
+fn test_dynamic_poa_takes_turns_by_stake() {
+    let poa = DynamicPoa::staked();
+    let state = two_validators();
+
+    let authors: Vec<_> = (0..4)
+        .map(|h| {
+            poa.seal_with_state(&state, &ConsensusAuthority::Alice, partial_header(h))
+                .unwrap()
+                .consensus_digest
+        })
+        .collect();
+
+    assert_eq!(
+        authors,
+        vec![
+            ConsensusAuthority::Alice,
+            ConsensusAuthority::Bob,
+            ConsensusAuthority::Alice,
+            ConsensusAuthority::Bob,
+        ]
+    );
+}
+
+#[test]
+fn test_dynamic_poa_follows_the_staking_state() {
+    use crate::c1_state_machine::p8_staking::StakingTransaction::Bond;
+
+    let poa = DynamicPoa::staked();
+    let before = two_validators();
+    let after = Staking::next_state(
+        &before,
+        &Bond {
+            who: User::Charlie,
+            amount: 80,
+        },
+    );
+    let mut header = partial_header(3);
+    let charlie_sealed = Header {
+        parent: header.parent,
+        height: header.height,
+        state_root: header.state_root,
+        extrinsics_root: header.extrinsics_root,
+        consensus_digest: ConsensusAuthority::Charlie,
+    };
+
+    // Charlie is not an authority until they bond.
+    assert!(!poa.validate_with_state(&before, &ConsensusAuthority::Bob, &charlie_sealed));
+    // Then they are the largest staker, so height 3 of 3 authorities is theirs.
+    assert!(poa.validate_with_state(&after, &ConsensusAuthority::Bob, &charlie_sealed));
+
+    header.height = 4;
+    let sealed = poa.seal_with_state(&after, &ConsensusAuthority::Charlie, header);
+    assert_eq!(sealed.unwrap().consensus_digest, ConsensusAuthority::Alice);
+}
+
+#[test]
+fn test_dynamic_poa_cannot_seal_without_authorities() {
+    let poa = DynamicPoa::staked();
+    let nobody_staked = Staking::genesis_state(vec![(User::Alice, 100)]);
+
+    assert_eq!(
+        poa.seal_with_state(
+            &nobody_staked,
+            &ConsensusAuthority::Alice,
+            partial_header(1)
+        ),
+        None
+    );
+}
+
+#[test]
+fn test_dynamic_poa_with_custom_lookup() {
+    let poa = DynamicPoa::new(|dictator: &ConsensusAuthority| vec![*dictator]);
+    let header = poa
+        .seal_with_state(
+            &ConsensusAuthority::Bob,
+            &ConsensusAuthority::Bob,
+            partial_header(7),
+        )
+        .unwrap();
+
+    assert_eq!(header.consensus_digest, ConsensusAuthority::Bob);
+    assert!(!poa.validate_with_state(
+        &ConsensusAuthority::Charlie,
+        &ConsensusAuthority::Bob,
+        &header
+    ));
+}
+
+#[test]
+fn test_plain_consensus_is_stateful() {
+    use super::p1_pow::PoW;
+
+    let pow = PoW::new(u64::MAX / 4);
+    let header = pow.seal_with_state(&(), &0, partial_header(1)).unwrap();
+
+    assert!(pow.validate_with_state(&two_validators(), &0, &header));
+}
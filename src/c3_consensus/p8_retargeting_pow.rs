@@ -0,0 +1,317 @@
+//! The Proof of Work engine of part 1 has a fixed threshold. But the rate at which blocks are
+//! found depends on how much hashing power the miners bring, and that changes all the time. As
+//! miners join, blocks come faster and faster. Real-world PoW chains like Bitcoin therefore
+//! retarget their difficulty periodically, aiming for a constant time between blocks.
+//!
+//! Every `retarget_interval` blocks, this engine compares how long the last window of blocks
+//! actually took with how long it should have taken, and scales the threshold by the same ratio.
+//! Blocks that came too fast lower the threshold, making mining harder. Each adjustment is
+//! bounded, so that a handful of miners with skewed clocks cannot swing the difficulty wildly.
+//!
+//! Everything needed to check an adjustment is carried in the digest, so importers can validate
+//! headers knowing only the parent's digest, like with every other engine.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{Consensus, Header};
+use crate::hash;
+
+/// The most the threshold can change by in a single retarget, as a factor in either direction
+pub const MAX_ADJUSTMENT: u64 = 4;
+
+/// A Proof of Work engine that adjusts its threshold to keep the time between blocks steady.
+/// Timestamps are in milliseconds since the unix epoch, like the block context's.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RetargetingPoW {
+    /// The threshold of the blocks in the first window
+    pub initial_threshold: u64,
+    /// How many blocks make up a window. The threshold changes at heights that are multiples of
+    /// this.
+    pub retarget_interval: u64,
+    /// The desired time between blocks
+    pub target_block_time: u64,
+}
+
+/// The digest of a retargeting PoW block
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RetargetDigest {
+    /// Varied by the miner until the header's hash is below the threshold
+    pub nonce: u64,
+    /// When the block was mined. Must be later than the parent's.
+    pub timestamp: u64,
+    /// The threshold this block was mined against
+    pub threshold: u64,
+    /// The timestamp of the last block before the current window began
+    pub window_start: u64,
+}
+
+impl RetargetingPoW {
+    /// The digest of a genesis block mined at the given time. Genesis is never validated, but
+    /// its digest starts the first window.
+    pub fn genesis_digest(&self, timestamp: u64) -> RetargetDigest {
+        RetargetDigest {
+            nonce: 0,
+            timestamp,
+            threshold: self.initial_threshold,
+            window_start: timestamp,
+        }
+    }
+
+    /// The threshold and window start a block at the given height must carry, given its
+    /// parent's digest.
+    fn expected_target(&self, parent: &RetargetDigest, height: u64) -> (u64, u64) {
+        if self.retarget_interval == 0 || !height.is_multiple_of(self.retarget_interval) {
+            return (parent.threshold, parent.window_start);
+        }
+        // The first window starts at genesis rather than after it, so it is a block short.
+        let intervals = if height == self.retarget_interval {
+            self.retarget_interval - 1
+        } else {
+            self.retarget_interval
+        };
+        if intervals == 0 {
+            return (parent.threshold, parent.timestamp);
+        }
+        let expected = u128::from(intervals) * u128::from(self.target_block_time);
+        let actual = u128::from(parent.timestamp.saturating_sub(parent.window_start));
+        let old = u128::from(parent.threshold);
+        let new = (old * actual / expected.max(1)).clamp(
+            old / u128::from(MAX_ADJUSTMENT),
+            old * u128::from(MAX_ADJUSTMENT),
+        );
+        // A threshold of zero could never be met, halting the chain for good.
+        let new = u64::try_from(new).unwrap_or(u64::MAX).max(1);
+        (new, parent.timestamp)
+    }
+
+    /// Mine a block at the given time. Returns None if the time is not after the parent's.
+    pub fn seal_at(
+        &self,
+        parent_digest: &RetargetDigest,
+        partial_header: Header<()>,
+        timestamp: u64,
+    ) -> Option<Header<RetargetDigest>> {
+        if timestamp <= parent_digest.timestamp {
+            return None;
+        }
+        let (threshold, window_start) = self.expected_target(parent_digest, partial_header.height);
+        let mut header = Header {
+            parent: partial_header.parent,
+            height: partial_header.height,
+            state_root: partial_header.state_root,
+            extrinsics_root: partial_header.extrinsics_root,
+            consensus_digest: RetargetDigest {
+                nonce: 0,
+                timestamp,
+                threshold,
+                window_start,
+            },
+        };
+        while hash(&header) >= threshold {
+            header.consensus_digest.nonce = header.consensus_digest.nonce.checked_add(1)?;
+        }
+        Some(header)
+    }
+}
+
+impl Consensus for RetargetingPoW {
+    type Digest = RetargetDigest;
+
+    /// Check that the timestamp moves forward, that the threshold was adjusted exactly as the
+    /// retargeting rule demands, and that the header's hash is below it.
+    fn validate(&self, parent_digest: &Self::Digest, header: &Header<Self::Digest>) -> bool {
+        let digest = &header.consensus_digest;
+        digest.timestamp > parent_digest.timestamp
+            && (digest.threshold, digest.window_start)
+                == self.expected_target(parent_digest, header.height)
+            && hash(header) < digest.threshold
+    }
+
+    /// Mine a block stamped with the current system time, or just after the parent if the
+    /// clock is behind it.
+    fn seal(
+        &self,
+        parent_digest: &Self::Digest,
+        partial_header: Header<()>,
+    ) -> Option<Header<Self::Digest>> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| u64::try_from(d.as_millis()).unwrap_or(u64::MAX));
+        let timestamp = now.max(parent_digest.timestamp.checked_add(1)?);
+        self.seal_at(parent_digest, partial_header, timestamp)
+    }
+
+    fn human_name() -> String {
+        "Retargeting Proof of Work".into()
+    }
+
+    /// Aim for a block every ten seconds, retargeting every ten blocks.
+    fn create_default_instance() -> Self {
+        RetargetingPoW {
+            initial_threshold: u64::MAX / 100,
+            retarget_interval: 10,
+            target_block_time: 10_000,
+        }
+    }
+}
+
+/// Mine `n` blocks on top of the given parent, `gap` milliseconds apart, returning them in order.
+#[cfg(test)]
+fn mine(
+    pow: &RetargetingPoW,
+    parent: &Header<RetargetDigest>,
+    n: u64,
+    gap: u64,
+) -> Vec<Header<RetargetDigest>> {
+    let mut chain = vec![parent.clone()];
+    for _ in 0..n {
+        let last = chain.last().unwrap();
+        let partial = Header {
+            parent: hash(last),
+            height: last.height + 1,
+            state_root: 0,
+            extrinsics_root: 0,
+            consensus_digest: (),
+        };
+        let timestamp = last.consensus_digest.timestamp + gap;
+        let header = pow
+            .seal_at(&last.consensus_digest, partial, timestamp)
+            .unwrap();
+        chain.push(header);
+    }
+    chain.split_off(1)
+}
+
+#[cfg(test)]
+fn genesis(pow: &RetargetingPoW) -> Header<RetargetDigest> {
+    Header {
+        parent: 0,
+        height: 0,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: pow.genesis_digest(1_000_000),
+    }
+}
+
+#[cfg(test)]
+fn easy_pow() -> RetargetingPoW {
+    RetargetingPoW {
+        initial_threshold: u64::MAX / 8,
+        retarget_interval: 5,
+        target_block_time: 1000,
+    }
+}
+
+#[test]
+fn test_retarget_on_schedule_keeps_threshold() {
+    let pow = easy_pow();
+    let genesis = genesis(&pow);
+    let chain = mine(&pow, &genesis, 12, 1000);
+
+    assert!(chain
+        .iter()
+        .all(|h| h.consensus_digest.threshold == pow.initial_threshold));
+    assert!(pow.verify_sub_chain(
+        &genesis.consensus_digest,
+        &[vec![genesis.clone()], chain].concat()
+    ));
+}
+
+#[test]
+fn test_fast_blocks_make_mining_harder() {
+    let pow = easy_pow();
+    let genesis = genesis(&pow);
+    let chain = mine(&pow, &genesis, 6, 500);
+
+    // Blocks 1 to 4 keep the initial threshold. They took 2000ms instead of 4000ms, so block 5
+    // gets half the threshold.
+    assert_eq!(chain[3].consensus_digest.threshold, pow.initial_threshold);
+    assert_eq!(
+        chain[4].consensus_digest.threshold,
+        pow.initial_threshold / 2
+    );
+    assert!(chain
+        .windows(2)
+        .all(|w| pow.validate(&w[0].consensus_digest, &w[1])));
+}
+
+#[test]
+fn test_adjustment_is_bounded() {
+    let pow = easy_pow();
+    let genesis = genesis(&pow);
+
+    let slow = mine(&pow, &genesis, 5, 1_000_000);
+    assert_eq!(
+        slow[4].consensus_digest.threshold,
+        pow.initial_threshold * MAX_ADJUSTMENT
+    );
+
+    let fast = mine(&pow, &genesis, 5, 1);
+    assert_eq!(
+        fast[4].consensus_digest.threshold,
+        pow.initial_threshold / MAX_ADJUSTMENT
+    );
+}
+
+#[test]
+fn test_wrong_adjustment_is_rejected() {
+    let pow = easy_pow();
+    let genesis = genesis(&pow);
+    let chain = mine(&pow, &genesis, 5, 500);
+    let parent = &chain[3].consensus_digest;
+
+    // Keeping the old, easier threshold at a retarget height is not allowed, even if the
+    // hash happens to meet it.
+    let mut kept = chain[4].clone();
+    kept.consensus_digest.threshold = pow.initial_threshold;
+    assert!(!pow.validate(parent, &kept));
+
+    // Nor is changing the threshold between retarget heights.
+    let mut changed = chain[3].clone();
+    changed.consensus_digest.threshold = u64::MAX;
+    assert!(!pow.validate(&chain[2].consensus_digest, &changed));
+}
+
+#[test]
+fn test_timestamps_must_increase() {
+    let pow = easy_pow();
+    let genesis = genesis(&pow);
+    let partial = Header {
+        parent: hash(&genesis),
+        height: 1,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: (),
+    };
+    let now = genesis.consensus_digest.timestamp;
+
+    assert_eq!(
+        pow.seal_at(&genesis.consensus_digest, partial.clone(), now),
+        None
+    );
+
+    let mut header = pow
+        .seal_at(&genesis.consensus_digest, partial, now + 1)
+        .unwrap();
+    assert!(pow.validate(&genesis.consensus_digest, &header));
+    header.consensus_digest.timestamp = now;
+    assert!(!pow.validate(&genesis.consensus_digest, &header));
+}
+
+#[test]
+fn test_seal_uses_clock() {
+    let pow = easy_pow();
+    let genesis = genesis(&pow);
+    let partial = Header {
+        parent: hash(&genesis),
+        height: 1,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: (),
+    };
+    let header = pow.seal(&genesis.consensus_digest, partial).unwrap();
+
+    assert!(header.consensus_digest.timestamp > genesis.consensus_digest.timestamp);
+    assert!(pow.validate(&genesis.consensus_digest, &header));
+}
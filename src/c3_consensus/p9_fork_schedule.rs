@@ -0,0 +1,228 @@
+//! The `Forked` engine of part 6 switches from one set of consensus rules to another exactly once.
+//! Long-lived chains fork many times: a difficulty change here, a new signature scheme there, and
+//! eventually a move from PoW to PoA. Nesting `Forked` engines inside each other models that, but
+//! the type grows with every fork and has to change whenever another fork is scheduled.
+//!
+//! A `ForkSchedule` is instead a flat list of eras, each starting at an activation height and
+//! governed by its own engine. The engines are boxed, so eras can use engines of different types,
+//! as long as their digests convert to and from the schedule's digest type.
+
+use super::{envelope::DigestMetadata, Consensus, Header};
+
+/// The part of `Consensus` that an era's engine needs, in a form that can be boxed. Every
+/// consensus engine whose digest converts to and from `D` is one.
+pub trait EraEngine<D> {
+    /// Validate the header by the engine's own rules.
+    fn validate_era(&self, parent_digest: &D, header: &Header<D>) -> bool;
+
+    /// Seal the header by the engine's own rules.
+    fn seal_era(&self, parent_digest: &D, partial_header: Header<()>) -> Option<Header<D>>;
+}
+
+impl<D, C> EraEngine<D> for C
+where
+    C: Consensus,
+    D: Clone + Into<C::Digest>,
+    C::Digest: Into<D>,
+{
+    fn validate_era(&self, parent_digest: &D, header: &Header<D>) -> bool {
+        let inner = Header {
+            parent: header.parent,
+            height: header.height,
+            state_root: header.state_root,
+            extrinsics_root: header.extrinsics_root,
+            consensus_digest: header.consensus_digest.clone().into(),
+        };
+        self.validate(&parent_digest.clone().into(), &inner)
+    }
+
+    fn seal_era(&self, parent_digest: &D, partial_header: Header<()>) -> Option<Header<D>> {
+        let sealed = self.seal(&parent_digest.clone().into(), partial_header)?;
+        Some(Header {
+            parent: sealed.parent,
+            height: sealed.height,
+            state_root: sealed.state_root,
+            extrinsics_root: sealed.extrinsics_root,
+            consensus_digest: sealed.consensus_digest.into(),
+        })
+    }
+}
+
+/// The reasons a list of eras may not make a schedule
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ForkScheduleError {
+    /// An era activates at or before the height of the era listed before it.
+    OutOfOrder { activation_height: u64 },
+}
+
+/// A consensus engine made of several eras, each governed by its own engine. An era applies from
+/// its activation height up to, but not including, the activation height of the next one. Blocks
+/// below the first activation height belong to no era and are never valid.
+pub struct ForkSchedule<D> {
+    eras: Vec<(u64, Box<dyn EraEngine<D>>)>,
+}
+
+impl<D> ForkSchedule<D> {
+    /// A schedule of the given eras, which must be listed in order of strictly increasing
+    /// activation height.
+    pub fn new(eras: Vec<(u64, Box<dyn EraEngine<D>>)>) -> Result<Self, ForkScheduleError> {
+        if let Some(pair) = eras.windows(2).find(|pair| pair[0].0 >= pair[1].0) {
+            return Err(ForkScheduleError::OutOfOrder {
+                activation_height: pair[1].0,
+            });
+        }
+        Ok(ForkSchedule { eras })
+    }
+
+    /// The activation heights of the eras, in order.
+    pub fn activation_heights(&self) -> impl Iterator<Item = u64> + '_ {
+        self.eras.iter().map(|(height, _)| *height)
+    }
+
+    /// The index of the era the block at the given height belongs to, if any.
+    pub fn era_at(&self, height: u64) -> Option<usize> {
+        // The eras are sorted, so the ones that have activated by this height come first.
+        self.eras
+            .partition_point(|(activation, _)| *activation <= height)
+            .checked_sub(1)
+    }
+
+    /// The engine governing the block at the given height, if any.
+    pub fn engine_at(&self, height: u64) -> Option<&dyn EraEngine<D>> {
+        self.era_at(height).map(|i| self.eras[i].1.as_ref())
+    }
+}
+
+impl<D> Consensus for ForkSchedule<D>
+where
+    D: Clone + core::fmt::Debug + Eq + PartialEq + std::hash::Hash + DigestMetadata,
+{
+    type Digest = D;
+
+    /// Validate the header by the rules of its height's era. The parent may belong to an earlier
+    /// era, in which case its digest is converted to the new era's engine as is.
+    fn validate(&self, parent_digest: &D, header: &Header<D>) -> bool {
+        self.engine_at(header.height)
+            .is_some_and(|engine| engine.validate_era(parent_digest, header))
+    }
+
+    /// Seal the header by the rules of its height's era.
+    fn seal(&self, parent_digest: &D, partial_header: Header<()>) -> Option<Header<D>> {
+        self.engine_at(partial_header.height)?
+            .seal_era(parent_digest, partial_header)
+    }
+
+    fn human_name() -> String {
+        "Fork Schedule".into()
+    }
+
+    /// A schedule without any eras, under which no block is valid.
+    fn create_default_instance() -> Self {
+        ForkSchedule { eras: Vec::new() }
+    }
+}
+
+#[cfg(test)]
+use super::{p1_pow::PoW, p4_even_only::EvenOnly};
+
+/// Anything goes until height 3, then state roots must be even, then from height 6 a quarter of
+/// hashes meet the PoW target.
+#[cfg(test)]
+fn three_eras() -> ForkSchedule<u64> {
+    ForkSchedule::new(vec![
+        (0, Box::new(PoW::new(u64::MAX))),
+        (
+            3,
+            Box::new(EvenOnly {
+                inner_c: PoW::new(u64::MAX),
+            }),
+        ),
+        (6, Box::new(PoW::new(u64::MAX / 4))),
+    ])
+    .unwrap()
+}
+
+#[cfg(test)]
+fn header(height: u64, state_root: u64) -> Header<u64> {
+    Header {
+        parent: 0,
+        height,
+        state_root,
+        extrinsics_root: 0,
+        consensus_digest: 0,
+    }
+}
+
+#[test]
+fn test_schedule_dispatches_by_height() {
+    let schedule = three_eras();
+
+    assert_eq!(schedule.era_at(0), Some(0));
+    assert_eq!(schedule.era_at(2), Some(0));
+    assert_eq!(schedule.era_at(3), Some(1));
+    assert_eq!(schedule.era_at(5), Some(1));
+    assert_eq!(schedule.era_at(6), Some(2));
+    assert_eq!(schedule.era_at(u64::MAX), Some(2));
+
+    // An odd state root is only a problem in the middle era.
+    assert!(schedule.validate(&0, &header(2, 1)));
+    assert!(!schedule.validate(&0, &header(3, 1)));
+    assert!(schedule.validate(&0, &header(3, 2)));
+}
+
+#[test]
+fn test_schedule_seals_by_each_era() {
+    let schedule = three_eras();
+    let mut parent = header(0, 0);
+
+    for height in 1..10 {
+        let partial = Header {
+            parent: crate::hash(&parent),
+            height,
+            state_root: 1,
+            extrinsics_root: 0,
+            consensus_digest: (),
+        };
+        let sealed = schedule.seal(&parent.consensus_digest, partial).unwrap();
+
+        assert!(schedule.validate(&parent.consensus_digest, &sealed));
+        assert_eq!(sealed.state_root % 2 == 0, (3..6).contains(&height));
+        if height >= 6 {
+            assert!(PoW::new(u64::MAX / 4).validate(&0, &sealed));
+        }
+        parent = sealed;
+    }
+}
+
+#[test]
+fn test_blocks_before_first_era_are_invalid() {
+    let schedule = ForkSchedule::<u64>::new(vec![(5, Box::new(PoW::new(u64::MAX)))]).unwrap();
+
+    assert!(!schedule.validate(&0, &header(4, 0)));
+    let partial = Header {
+        parent: 0,
+        height: 4,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: (),
+    };
+    assert!(schedule.seal(&0, partial).is_none());
+    assert!(schedule.validate(&0, &header(5, 0)));
+    assert!(!ForkSchedule::<u64>::create_default_instance().validate(&0, &header(0, 0)));
+}
+
+#[test]
+fn test_eras_must_be_in_order() {
+    let result = ForkSchedule::<u64>::new(vec![
+        (0, Box::new(PoW::new(u64::MAX))),
+        (4, Box::new(PoW::new(u64::MAX))),
+        (4, Box::new(PoW::new(u64::MAX))),
+    ]);
+
+    assert_eq!(
+        result.err(),
+        Some(ForkScheduleError::OutOfOrder {
+            activation_height: 4
+        })
+    );
+}
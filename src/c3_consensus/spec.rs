@@ -0,0 +1,196 @@
+//! Every engine in this chapter has a `create_default_instance`, and the defaults are fine for
+//! tests. A real chain needs its parameters written down somewhere: the PoW threshold, who the
+//! authorities are, the height at which a fork happens. Every node on the chain must use the same
+//! parameters, so they are shipped in the chain's spec rather than compiled into the engines.
+//!
+//! A `ConsensusSpec` describes an engine and its parameters. Higher-order engines like `Forked`
+//! and `Interleaved` describe the engines they wrap as well, so the spec is a tree. Engines that
+//! can be built from a spec implement `FromSpec`.
+
+use super::p19_threshold_poa::ThresholdPoa;
+use super::p1_pow::{PoW, PowHasher, Target};
+use super::p3_poa::SimplePoa;
+use super::p5_interleave::{Interleaved, Turn};
+use super::{Consensus, ConsensusAuthority};
+
+/// A consensus engine and its parameters
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "engine", rename_all = "snake_case"))]
+pub enum ConsensusSpec {
+    /// The trivial engine, which accepts every block
+    Trivial,
+    /// Proof of work, with the most significant 64 bits of the target
+    Pow { threshold: u64 },
+    /// Proof of authority, in which any of the authorities may seal a block
+    Poa {
+        authorities: Vec<ConsensusAuthority>,
+    },
+    /// Proof of authority, in which `threshold` of the authorities must sign every block
+    ThresholdPoa {
+        authorities: Vec<ConsensusAuthority>,
+        threshold: usize,
+    },
+    /// Two engines taking turns following a repeating pattern
+    Interleaved {
+        pattern: Vec<Turn>,
+        a: Box<ConsensusSpec>,
+        b: Box<ConsensusSpec>,
+    },
+    /// One engine until `fork_height`, and another after it
+    Forked {
+        fork_height: u64,
+        before: Box<ConsensusSpec>,
+        after: Box<ConsensusSpec>,
+    },
+}
+
+impl ConsensusSpec {
+    /// The name of the engine, as written in spec files.
+    pub fn engine(&self) -> &'static str {
+        match self {
+            ConsensusSpec::Trivial => "trivial",
+            ConsensusSpec::Pow { .. } => "pow",
+            ConsensusSpec::Poa { .. } => "poa",
+            ConsensusSpec::ThresholdPoa { .. } => "threshold_poa",
+            ConsensusSpec::Interleaved { .. } => "interleaved",
+            ConsensusSpec::Forked { .. } => "forked",
+        }
+    }
+}
+
+/// The reasons an engine may not be built from a spec
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SpecError {
+    /// The spec describes another engine than the one being built
+    WrongEngine {
+        expected: &'static str,
+        found: &'static str,
+    },
+    /// A parameter of the engine is out of range, for example an empty set of authorities
+    InvalidParameter(&'static str),
+}
+
+/// A consensus engine that can be built from a spec
+pub trait FromSpec: Consensus + Sized {
+    /// Build the engine the given spec describes.
+    fn from_spec(spec: &ConsensusSpec) -> Result<Self, SpecError>;
+}
+
+/// The error for building an engine from a spec describing another one.
+pub(super) fn wrong_engine(expected: &'static str, spec: &ConsensusSpec) -> SpecError {
+    SpecError::WrongEngine {
+        expected,
+        found: spec.engine(),
+    }
+}
+
+impl FromSpec for () {
+    fn from_spec(spec: &ConsensusSpec) -> Result<Self, SpecError> {
+        match spec {
+            ConsensusSpec::Trivial => Ok(()),
+            other => Err(wrong_engine("trivial", other)),
+        }
+    }
+}
+
+impl<H: PowHasher> FromSpec for PoW<H> {
+    fn from_spec(spec: &ConsensusSpec) -> Result<Self, SpecError> {
+        match spec {
+            ConsensusSpec::Pow { threshold: 0 } => Err(SpecError::InvalidParameter("threshold")),
+            ConsensusSpec::Pow { threshold } => {
+                Ok(PoW::with_target(Target::from_threshold(*threshold)))
+            }
+            other => Err(wrong_engine("pow", other)),
+        }
+    }
+}
+
+impl FromSpec for SimplePoa {
+    fn from_spec(spec: &ConsensusSpec) -> Result<Self, SpecError> {
+        match spec {
+            ConsensusSpec::Poa { authorities } if authorities.is_empty() => {
+                Err(SpecError::InvalidParameter("authorities"))
+            }
+            ConsensusSpec::Poa { authorities } => Ok(SimplePoa::new(authorities.clone())),
+            other => Err(wrong_engine("poa", other)),
+        }
+    }
+}
+
+impl FromSpec for ThresholdPoa {
+    fn from_spec(spec: &ConsensusSpec) -> Result<Self, SpecError> {
+        match spec {
+            ConsensusSpec::ThresholdPoa { authorities, .. } if authorities.is_empty() => {
+                Err(SpecError::InvalidParameter("authorities"))
+            }
+            ConsensusSpec::ThresholdPoa {
+                authorities,
+                threshold,
+            } if *threshold == 0 || *threshold > authorities.len() => {
+                Err(SpecError::InvalidParameter("threshold"))
+            }
+            ConsensusSpec::ThresholdPoa {
+                authorities,
+                threshold,
+            } => Ok(ThresholdPoa::new(authorities.clone(), *threshold)),
+            other => Err(wrong_engine("threshold_poa", other)),
+        }
+    }
+}
+
+impl<A: FromSpec, B: FromSpec> FromSpec for Interleaved<A, B> {
+    fn from_spec(spec: &ConsensusSpec) -> Result<Self, SpecError> {
+        match spec {
+            ConsensusSpec::Interleaved { pattern, a, b } => Ok(Interleaved::new(
+                A::from_spec(a)?,
+                B::from_spec(b)?,
+                pattern.clone(),
+            )),
+            other => Err(wrong_engine("interleaved", other)),
+        }
+    }
+}
+
+#[test]
+fn test_spec_builds_nested_engines() {
+    let spec = ConsensusSpec::Interleaved {
+        pattern: vec![Turn::A, Turn::A, Turn::B],
+        a: Box::new(ConsensusSpec::Pow {
+            threshold: u64::MAX / 4,
+        }),
+        b: Box::new(ConsensusSpec::Poa {
+            authorities: vec![ConsensusAuthority::Alice],
+        }),
+    };
+    let engine = Interleaved::<PoW, SimplePoa>::from_spec(&spec).unwrap();
+    assert_eq!(engine.a, PoW::new(u64::MAX / 4));
+    assert_eq!(engine.turn_at(2), Turn::B);
+
+    assert_eq!(
+        Interleaved::<SimplePoa, PoW>::from_spec(&spec).err(),
+        Some(SpecError::WrongEngine {
+            expected: "poa",
+            found: "pow"
+        })
+    );
+}
+
+#[test]
+fn test_spec_rejects_out_of_range_parameters() {
+    let spec = ConsensusSpec::ThresholdPoa {
+        authorities: vec![ConsensusAuthority::Alice, ConsensusAuthority::Bob],
+        threshold: 3,
+    };
+    assert_eq!(
+        ThresholdPoa::from_spec(&spec).err(),
+        Some(SpecError::InvalidParameter("threshold"))
+    );
+    assert_eq!(
+        SimplePoa::from_spec(&ConsensusSpec::Poa {
+            authorities: vec![]
+        })
+        .err(),
+        Some(SpecError::InvalidParameter("authorities"))
+    );
+}
@@ -0,0 +1,304 @@
+//! So far blocks have been built by hand, one call to `Block::child` at a time. A node that
+//! authors blocks does the same thing in a loop: it takes whatever transitions are waiting to be
+//! included, builds a block out of them on top of its best head, seals it, and imports it like any
+//! other block. The new block becomes the best head, and the next round builds on top of it.
+//!
+//! Transitions that the state machine rejects are dropped from the batch, and from the pool they
+//! came from, rather than holding up the block. Transitions that make it into a block are removed
+//! from the pool too, so that they are not included twice.
+
+use std::collections::HashMap;
+
+use super::chain_spec::ChainSpec;
+use super::p3_fork_choice::{ForkChoice, HeaderTree};
+use super::{import_blocks, Block, BlockBuildError, Hash};
+use crate::c1_state_machine::{BlockContext, ContextualStateMachine, StateMachine, User, Weighted};
+use crate::c3_consensus::spec::{FromSpec, SpecError};
+use crate::c3_consensus::{Consensus, SystemClock, TimeProvider};
+use crate::codec::Encode;
+use crate::hash;
+use num::traits::{One, Zero};
+
+/// Somewhere the author can find transitions waiting to be included in a block
+pub trait TransitionSource<T> {
+    /// The transitions ready for the next block, in the order they should be included.
+    fn ready(&self) -> Vec<T>;
+
+    /// Forget the given transitions, because they were included in a block.
+    fn remove(&mut self, transitions: &[T]);
+
+    /// Forget the given transitions, because the state machine rejected them. The provided
+    /// implementation forgets them as if they were included.
+    fn reject(&mut self, transitions: &[T]) {
+        self.remove(transitions);
+    }
+
+    /// The transitions for a block whose total weight may not exceed `max_weight`. The provided
+    /// implementation takes those that are ready in order, skipping any that no longer fit.
+    fn ready_within(&self, max_weight: u64, weight: fn(&T) -> u64) -> Vec<T> {
+        let mut total = 0u64;
+        self.ready()
+            .into_iter()
+            .filter(|t| match total.checked_add(weight(t)) {
+                Some(w) if w <= max_weight => {
+                    total = w;
+                    true
+                }
+                _ => false,
+            })
+            .collect()
+    }
+}
+
+/// The simplest source, which hands out its transitions first come, first served.
+impl<T: Clone + PartialEq> TransitionSource<T> for Vec<T> {
+    fn ready(&self) -> Vec<T> {
+        self.clone()
+    }
+
+    fn remove(&mut self, transitions: &[T]) {
+        self.retain(|t| !transitions.contains(t));
+    }
+}
+
+/// The reasons authoring a block may fail
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AuthorError {
+    /// The block could not be built on top of the best head
+    Build(BlockBuildError),
+    /// The block was built, but importing it failed, for example because the fork choice rule
+    /// preferred another head
+    NotImported(Hash),
+}
+
+impl From<BlockBuildError> for AuthorError {
+    fn from(e: BlockBuildError) -> Self {
+        AuthorError::Build(e)
+    }
+}
+
+/// How to weigh a transition
+type WeightFn<T> = fn(&T) -> u64;
+
+/// A single node that authors blocks on top of its best head and imports them.
+pub struct Author<C: Consensus, SM: StateMachine, FC, Clock = SystemClock> {
+    consensus: C,
+    fork_choice: FC,
+    tree: HeaderTree<C::Digest>,
+    /// Every imported block, by hash
+    blocks: HashMap<Hash, Block<C, SM>>,
+    /// The state after every imported block, by hash
+    states: HashMap<Hash, SM::State>,
+    best: Hash,
+    /// Who the node's blocks say authored them
+    author: Option<User>,
+    /// The most a block may weigh, and how to weigh a transition
+    weight_limit: Option<(u64, WeightFn<SM::Transition>)>,
+    clock: Clock,
+}
+
+impl<C, SM, FC, Clock> Author<C, SM, FC, Clock>
+where
+    C: Consensus,
+    C::Digest: Zero + One + core::hash::Hash,
+    SM: ContextualStateMachine,
+    SM::State: core::hash::Hash + Clone,
+    SM::Transition: core::hash::Hash + Encode + Clone,
+    FC: ForkChoice<C::Digest>,
+    Clock: TimeProvider,
+{
+    /// A node with nothing but the genesis block built from the given state.
+    pub fn new(consensus: C, fork_choice: FC, genesis_state: SM::State, clock: Clock) -> Self {
+        let genesis = Block::<C, SM>::genesis(&genesis_state);
+        let best = hash(&genesis.header);
+        Author {
+            consensus,
+            fork_choice,
+            tree: HeaderTree::new(genesis.header.clone()),
+            blocks: HashMap::from([(best, genesis)]),
+            states: HashMap::from([(best, genesis_state)]),
+            best,
+            author: None,
+            weight_limit: None,
+            clock,
+        }
+    }
+
+    /// A node with nothing but the genesis block of the chain the given spec describes, sealing
+    /// blocks with the consensus engine it describes.
+    pub fn from_spec(
+        spec: &ChainSpec<SM::GenesisConfig>,
+        fork_choice: FC,
+        clock: Clock,
+    ) -> Result<Self, SpecError>
+    where
+        C: FromSpec,
+        SM::GenesisConfig: Clone,
+    {
+        Ok(Self::new(
+            spec.consensus()?,
+            fork_choice,
+            spec.genesis_state::<SM>(),
+            clock,
+        ))
+    }
+
+    /// Say in every block's context that it was authored by the given user.
+    pub fn authoring_as(mut self, author: User) -> Self {
+        self.author = Some(author);
+        self
+    }
+
+    /// The hash of the block new blocks are built on.
+    pub fn best_head(&self) -> Hash {
+        self.best
+    }
+
+    /// The height of the best head.
+    pub fn best_height(&self) -> u64 {
+        self.blocks[&self.best].header.height
+    }
+
+    /// The state after the given block, if it has been imported.
+    pub fn state_at(&self, block_hash: Hash) -> Option<&SM::State> {
+        self.states.get(&block_hash)
+    }
+
+    /// The transitions in the body of the given block, if it has been imported.
+    pub fn body_of(&self, block_hash: Hash) -> Option<&[SM::Transition]> {
+        self.blocks.get(&block_hash).map(|b| b.body.as_slice())
+    }
+
+    /// Build a block on top of the best head out of the transitions ready in the pool, up to the
+    /// weight limit if there is one, seal it, and import it. Transitions the state machine
+    /// rejects are dropped from the pool along with those included in the block. Returns the
+    /// hash of the new block.
+    pub fn author_block(
+        &mut self,
+        pool: &mut impl TransitionSource<SM::Transition>,
+    ) -> Result<Hash, AuthorError> {
+        let parent = &self.blocks[&self.best];
+        let pre_state = &self.states[&self.best];
+        let context = BlockContext {
+            height: parent.header.height + 1,
+            timestamp: self.clock.now(),
+            author: self.author,
+            parent_hash: self.best,
+        };
+
+        let mut batch = match self.weight_limit {
+            Some((max_weight, weight)) => pool.ready_within(max_weight, weight),
+            None => pool.ready(),
+        };
+        let block = loop {
+            let built =
+                parent.child_sealed_by(&self.consensus, pre_state, batch.clone(), context.clone());
+            match built {
+                Ok(block) => break block,
+                Err(BlockBuildError::StateExecutionFailed { index, .. }) => {
+                    let rejected = batch.remove(index);
+                    pool.reject(&[rejected]);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        let block_hash = hash(&block.header);
+        let post_state = SM::apply_all_in_context(pre_state, &block.body, &block.context);
+        self.best = import_blocks(
+            &self.consensus,
+            &mut self.tree,
+            std::slice::from_ref(&block),
+            &self.fork_choice,
+        );
+        if self.best != block_hash {
+            return Err(AuthorError::NotImported(block_hash));
+        }
+        pool.remove(&block.body);
+        self.states.insert(block_hash, post_state);
+        self.blocks.insert(block_hash, block);
+        Ok(block_hash)
+    }
+
+    /// Author the given number of blocks one after another, stopping at the first failure.
+    /// Returns the hashes of the new blocks.
+    pub fn run(
+        &mut self,
+        pool: &mut impl TransitionSource<SM::Transition>,
+        blocks: usize,
+    ) -> Result<Vec<Hash>, AuthorError> {
+        (0..blocks).map(|_| self.author_block(pool)).collect()
+    }
+}
+
+impl<C: Consensus, SM: Weighted, FC, Clock> Author<C, SM, FC, Clock> {
+    /// Fill blocks with no more than the given weight of transitions.
+    pub fn with_max_weight(mut self, max_weight: u64) -> Self {
+        self.weight_limit = Some((max_weight, SM::weight));
+        self
+    }
+}
+
+#[cfg(test)]
+use super::p3_fork_choice::LongestChain;
+#[cfg(test)]
+use super::Withdrawals;
+#[cfg(test)]
+use crate::c3_consensus::p1_pow::PoW;
+#[cfg(test)]
+use crate::c3_consensus::MockClock;
+
+#[cfg(test)]
+fn node(clock: &MockClock) -> Author<PoW, Withdrawals, LongestChain, MockClock> {
+    Author::new(PoW::new(u64::MAX / 4), LongestChain, 100, clock.clone())
+}
+
+#[test]
+fn cl_author_includes_the_pool_and_builds_on_its_own_blocks() {
+    let clock = MockClock::new(6_000);
+    let mut node = node(&clock).authoring_as(User::Alice);
+    let mut pool = vec![10, 20, 5];
+
+    let first = node.author_block(&mut pool).unwrap();
+    assert_eq!(node.best_head(), first);
+    assert_eq!(node.body_of(first), Some(&[10, 20, 5][..]));
+    assert_eq!(node.state_at(first), Some(&65));
+    assert!(pool.is_empty());
+
+    clock.advance(6_000);
+    pool.push(15);
+    let later = node.run(&mut pool, 2).unwrap();
+    assert_eq!(node.best_height(), 3);
+    assert_eq!(node.state_at(later[1]), Some(&50));
+    assert_eq!(node.blocks[&later[0]].context.timestamp, 12_000);
+    assert_eq!(node.blocks[&later[0]].context.author, Some(User::Alice));
+}
+
+#[test]
+fn cl_author_drops_rejected_transitions() {
+    let mut node = node(&MockClock::new(0));
+    let mut pool = vec![60, 70, 30, 20, 10];
+
+    let block = node.author_block(&mut pool).unwrap();
+    assert_eq!(node.body_of(block), Some(&[60, 30, 10][..]));
+    assert!(pool.is_empty());
+}
+
+#[test]
+fn cl_author_reports_seal_failures() {
+    use crate::c3_consensus::p15_max_extrinsics::MaxExtrinsics;
+
+    let engine = MaxExtrinsics {
+        inner: PoW::new(u64::MAX / 4),
+        max: 1,
+    };
+    let mut node =
+        Author::<_, Withdrawals, _, _>::new(engine, LongestChain, 100, MockClock::new(0));
+    let mut pool = vec![1, 2];
+
+    assert_eq!(
+        node.author_block(&mut pool),
+        Err(AuthorError::Build(BlockBuildError::SealFailed))
+    );
+    assert_eq!(pool.len(), 2);
+}
@@ -0,0 +1,1000 @@
+//! A client hears about blocks on every fork, not just the one it considers best. The
+//! `HeaderTree` keeps track of their headers, but a client also needs their bodies, and the
+//! state after them. Keeping a copy of the state after every block on every fork is simple, but
+//! the state may be large, and most forks are soon abandoned.
+//!
+//! The `BlockTree` keeps the state after the best head, and snapshots of the state after every
+//! few blocks. The state after any other block is reconstructed by re-executing the blocks since
+//! its nearest snapshotted ancestor. That is what happens on a reorg. The state after the blocks
+//! being retracted is abandoned, the state at the nearest snapshot before the fork is restored,
+//! and the blocks from there to the new best head are executed again.
+//!
+//! An archive node keeps every block it ever imported. A long-lived node rarely needs the bodies
+//! of old blocks, or the state after them, so it can run pruned instead: it keeps the bodies of
+//! the best head and its last few ancestors only. For older blocks of the best chain it keeps
+//! just the headers, which are enough to tell other nodes what the chain is. Forks branching off
+//! before the oldest block it keeps can no longer be executed, so they are forgotten.
+//!
+//! Importing a long chain at once, when syncing or loading it from disk, is dominated by checking
+//! seals, which for proof of work means hashing every header. Unlike executing the blocks, this
+//! does not depend on the state, so a batch of blocks has all its bodies and seals checked up
+//! front, on every core when the `parallel` feature is enabled, before it is executed in order.
+//!
+//! The tree also tracks the latest finalized block, as decided by a finality gadget or
+//! checkpoints. A finalized block is never reverted, so when a block is finalized every fork
+//! branching off before it is forgotten, and blocks that would start a new one are refused.
+//!
+//! A tree for a state machine that pays block authors can be given the block reward, and then
+//! refuses blocks whose coinbase does not pay their author exactly the reward plus their fees.
+//! Given no reward, it refuses blocks holding any coinbase at all.
+
+use std::collections::HashMap;
+
+use super::chain_spec::ChainSpec;
+use super::p3_fork_choice::{ForkChoice, HeaderTree};
+use super::{Block, BlockVerificationError, Hash, Header};
+use crate::c1_state_machine::{ContextualStateMachine, Rewarded, StateMachine};
+use crate::c3_consensus::spec::{FromSpec, SpecError};
+use crate::c3_consensus::Consensus;
+use crate::codec::Encode;
+use crate::hash;
+use num::traits::{One, Zero};
+
+/// How many blocks apart the tree snapshots the state, unless told otherwise
+pub const DEFAULT_SNAPSHOT_INTERVAL: u64 = 8;
+
+/// How many blocks callers importing a long chain hand to `import_batch` at once. Enough to keep
+/// every core busy checking seals, without holding the whole chain in memory.
+pub const IMPORT_BATCH_LEN: usize = 256;
+
+/// How much history the tree keeps
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HistoryMode {
+    /// Keep the body of every block, so the state after any of them can be rebuilt
+    #[default]
+    Archive,
+    /// Keep the bodies of the best head and the given number of its ancestors. Older blocks of
+    /// the best chain keep their headers only.
+    Pruned(u64),
+}
+
+/// Where the best chain and the finalized chain end
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChainInfo {
+    pub best_hash: Hash,
+    pub best_height: u64,
+    pub finalized_hash: Hash,
+    pub finalized_height: u64,
+}
+
+/// The reasons the tree may refuse a block
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ImportError {
+    /// The block is already in the tree
+    AlreadyKnown,
+    /// The block's parent is not in the tree
+    UnknownParent,
+    /// The block's parent was pruned, so the block forks off the best chain too long ago to be
+    /// executed
+    PrunedParent,
+    /// The block's parent is below the finalized block, so the block would start a fork that
+    /// reverts finalized blocks
+    BelowFinalized,
+    /// The block failed full verification on top of its parent
+    Invalid(BlockVerificationError),
+}
+
+/// The stages of importing a block, cheapest first
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ImportStage {
+    /// Checking the header is new and follows a block in the tree
+    Header,
+    /// Checking the header's extrinsics root commits to the body
+    Body,
+    /// Checking the seal with the consensus engine
+    Consensus,
+    /// Checking the block's context and coinbase, executing the body and checking the state root
+    Execution,
+}
+
+impl ImportError {
+    /// The stage of importing at which the block was refused.
+    pub fn stage(&self) -> ImportStage {
+        match self {
+            ImportError::AlreadyKnown
+            | ImportError::UnknownParent
+            | ImportError::PrunedParent
+            | ImportError::BelowFinalized
+            | ImportError::Invalid(BlockVerificationError::NotAChild) => ImportStage::Header,
+            ImportError::Invalid(BlockVerificationError::ExtrinsicsRootMismatch { .. }) => {
+                ImportStage::Body
+            }
+            ImportError::Invalid(BlockVerificationError::InvalidSeal) => ImportStage::Consensus,
+            ImportError::Invalid(
+                BlockVerificationError::ContextMismatch
+                | BlockVerificationError::StateExecutionFailed { .. }
+                | BlockVerificationError::StateRootMismatch { .. }
+                | BlockVerificationError::CoinbaseMismatch
+                | BlockVerificationError::MisplacedCoinbase { .. },
+            ) => ImportStage::Execution,
+        }
+    }
+}
+
+impl From<BlockVerificationError> for ImportError {
+    fn from(e: BlockVerificationError) -> Self {
+        ImportError::Invalid(e)
+    }
+}
+
+/// How importing a block moved the best head. Both lists are empty if it did not move.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HeadChange {
+    /// The blocks that are no longer on the best chain, starting with the old best head
+    pub retracted: Vec<Hash>,
+    /// The blocks newly on the best chain, ending with the new best head
+    pub enacted: Vec<Hash>,
+}
+
+impl HeadChange {
+    /// Whether the best chain lost any blocks, rather than just growing.
+    pub fn is_reorg(&self) -> bool {
+        !self.retracted.is_empty()
+    }
+}
+
+/// How to check a block's coinbase against the block reward, if there is one
+type CoinbaseCheck<C, SM> = fn(&Block<C, SM>, Option<u64>) -> Result<(), BlockVerificationError>;
+
+/// Every block a client knows about, on every fork, along with the state after the best head.
+pub struct BlockTree<C: Consensus, SM: StateMachine, FC> {
+    consensus: C,
+    fork_choice: FC,
+    tree: HeaderTree<C::Digest>,
+    /// Every block in the tree, by hash
+    blocks: HashMap<Hash, Block<C, SM>>,
+    best: Hash,
+    best_state: SM::State,
+    /// The latest finalized block. It is always on the best chain.
+    finalized: Hash,
+    /// The state after the root, and after every block whose height is a multiple of the
+    /// snapshot interval, by hash
+    snapshots: HashMap<Hash, SM::State>,
+    snapshot_interval: u64,
+    history: HistoryMode,
+    /// The best chain's blocks that were pruned, oldest first. Only their headers are left.
+    pruned_chain: Vec<Hash>,
+    pruned_headers: HashMap<Hash, Header<C::Digest>>,
+    /// The block reward, if blocks pay their authors, and how to check blocks' coinbases, if
+    /// the state machine has them
+    coinbase: Option<(Option<u64>, CoinbaseCheck<C, SM>)>,
+}
+
+impl<C: Consensus, SM: StateMachine, FC> BlockTree<C, SM, FC> {
+    pub fn history(&self) -> HistoryMode {
+        self.history
+    }
+
+    /// Whether the given block's body and state were pruned, leaving only its header.
+    pub fn is_pruned(&self, block_hash: Hash) -> bool {
+        self.pruned_headers.contains_key(&block_hash)
+    }
+
+    pub(super) fn block(&self, block_hash: Hash) -> Option<&Block<C, SM>> {
+        self.blocks.get(&block_hash)
+    }
+
+    /// The headers of the best chain's pruned blocks, oldest first.
+    pub(super) fn pruned_headers(&self) -> Vec<&Header<C::Digest>> {
+        self.pruned_chain
+            .iter()
+            .map(|h| &self.pruned_headers[h])
+            .collect()
+    }
+
+    /// Every block in the tree, parents before their children.
+    pub(super) fn blocks_in_order(&self) -> Vec<&Block<C, SM>> {
+        self.tree.hashes().iter().map(|h| &self.blocks[h]).collect()
+    }
+}
+
+impl<C, SM, FC> BlockTree<C, SM, FC>
+where
+    C: Consensus,
+    C::Digest: Zero + One + core::hash::Hash,
+    SM: ContextualStateMachine,
+    SM::State: core::hash::Hash + Clone,
+    SM::Transition: core::hash::Hash + Encode + Clone,
+    FC: ForkChoice<C::Digest>,
+{
+    /// A tree holding only the genesis block built from the given state.
+    pub fn new(consensus: C, fork_choice: FC, genesis_state: SM::State) -> Self {
+        let genesis = Block::<C, SM>::genesis(&genesis_state);
+        let root = hash(&genesis.header);
+        BlockTree {
+            consensus,
+            fork_choice,
+            tree: HeaderTree::new(genesis.header.clone()),
+            blocks: HashMap::from([(root, genesis)]),
+            best: root,
+            best_state: genesis_state.clone(),
+            finalized: root,
+            snapshots: HashMap::from([(root, genesis_state)]),
+            snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+            history: HistoryMode::Archive,
+            pruned_chain: Vec::new(),
+            pruned_headers: HashMap::new(),
+            coinbase: None,
+        }
+    }
+
+    /// A tree holding only the genesis block of the chain the given spec describes, checking
+    /// blocks with the consensus engine it describes.
+    pub fn from_spec(
+        spec: &ChainSpec<SM::GenesisConfig>,
+        fork_choice: FC,
+    ) -> Result<Self, SpecError>
+    where
+        C: FromSpec,
+        SM::GenesisConfig: Clone,
+    {
+        Ok(Self::new(
+            spec.consensus()?,
+            fork_choice,
+            spec.genesis_state::<SM>(),
+        ))
+    }
+
+    /// Snapshot the state every `interval` blocks. Snapshotting every block makes reorgs cheap,
+    /// at the cost of keeping the state after every block in memory.
+    pub fn with_snapshot_interval(mut self, interval: u64) -> Self {
+        self.snapshot_interval = interval.max(1);
+        self
+    }
+
+    /// Keep as much history as the given mode says. Pruning happens as blocks are imported.
+    pub fn with_history(mut self, history: HistoryMode) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// The block reward, if the tree checks that blocks pay their authors.
+    pub fn block_reward(&self) -> Option<u64> {
+        self.coinbase.and_then(|(reward, _)| reward)
+    }
+
+    /// The hash of the best head according to the fork choice rule.
+    pub fn best_head(&self) -> Hash {
+        self.best
+    }
+
+    pub fn best_height(&self) -> u64 {
+        self.blocks[&self.best].header.height
+    }
+
+    /// Where the best chain and the finalized chain end.
+    pub fn chain_info(&self) -> ChainInfo {
+        ChainInfo {
+            best_hash: self.best,
+            best_height: self.best_height(),
+            finalized_hash: self.finalized,
+            finalized_height: self.finalized_height(),
+        }
+    }
+
+    fn finalized_height(&self) -> u64 {
+        self.header(self.finalized)
+            .expect("the finalized block is never forgotten")
+            .height
+    }
+
+    /// The hashes of the best chain, from genesis to the best head, including pruned blocks.
+    pub fn best_chain(&self) -> Vec<Hash> {
+        let mut chain = self.pruned_chain.clone();
+        chain.extend(self.tree.route_from_root(self.best));
+        chain
+    }
+
+    /// The state after the best head.
+    pub fn best_state(&self) -> &SM::State {
+        &self.best_state
+    }
+
+    /// Whether the given block is in the tree, pruned or not.
+    pub fn contains(&self, block_hash: Hash) -> bool {
+        self.blocks.contains_key(&block_hash) || self.is_pruned(block_hash)
+    }
+
+    pub fn header(&self, block_hash: Hash) -> Option<&Header<C::Digest>> {
+        self.tree
+            .get(block_hash)
+            .or_else(|| self.pruned_headers.get(&block_hash))
+    }
+
+    /// The transitions in the body of the given block, if it is in the tree and not pruned.
+    pub fn body_of(&self, block_hash: Hash) -> Option<&[SM::Transition]> {
+        self.blocks.get(&block_hash).map(|b| b.body.as_slice())
+    }
+
+    /// The bodies of the given blocks that are in the tree, in the same order. Handy for telling
+    /// a transaction pool what a reorg retracted and enacted.
+    pub fn bodies(&self, block_hashes: &[Hash]) -> Vec<Vec<SM::Transition>> {
+        block_hashes
+            .iter()
+            .filter_map(|h| self.body_of(*h).map(<[_]>::to_vec))
+            .collect()
+    }
+
+    /// The state after the given block, if it is in the tree and not pruned. Unless the block is
+    /// the best head or was snapshotted, this re-executes the blocks since its nearest
+    /// snapshotted ancestor.
+    pub fn state_at(&self, block_hash: Hash) -> Option<SM::State> {
+        if block_hash == self.best {
+            return Some(self.best_state.clone());
+        }
+        let route = self.tree.route_from_root(block_hash);
+        // The root is always snapshotted, so there is a snapshot along any route.
+        let start = route.iter().rposition(|h| self.snapshots.contains_key(h))?;
+        let state = route[start + 1..]
+            .iter()
+            .map(|h| &self.blocks[h])
+            .fold(self.snapshots[&route[start]].clone(), |s, b| {
+                SM::apply_all_in_context(&s, &b.body, &b.context)
+            });
+        Some(state)
+    }
+
+    /// Fully verify the given block on top of its parent, which may be on any fork, and add it
+    /// to the tree. If the fork choice rule then prefers another head, the state is moved over
+    /// to it. Returns how the best head moved.
+    ///
+    /// The checks run in the order of `ImportStage`, so no state is executed for a block whose
+    /// header, body or seal is bad.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(height = block.header.height))
+    )]
+    pub(super) fn import(&mut self, block: Block<C, SM>) -> Result<HeadChange, ImportError> {
+        self.check_header(&block.header)?;
+        let encoded = block.check_extrinsics_root()?;
+        self.check_seal(&block, &encoded)?;
+        let post_state = self.execute(&block)?;
+        Ok(self.commit(block, post_state))
+    }
+
+    /// Finalize the given block, forgetting every fork that branches off before it. If the best
+    /// head was on one of them, the best head moves to the best block descending from the given
+    /// one. Finalizing a block that is already final does nothing. Returns how the best head
+    /// moved, or None if the block is not in the tree.
+    pub fn finalize(&mut self, block_hash: Hash) -> Option<HeadChange> {
+        if self.header(block_hash)?.height <= self.finalized_height() {
+            // The only blocks left at or below the finalized height are its ancestors.
+            return Some(HeadChange::default());
+        }
+        self.finalized = block_hash;
+        let mut tree = self.tree.clone();
+        if !tree.prune_forks_of(block_hash) {
+            // The block was pruned, and forks branching off before it with it.
+            return Some(HeadChange::default());
+        }
+        let new_best = self.fork_choice.best_head(&tree);
+        let change = self.head_change(new_best);
+        if new_best != self.best {
+            self.best_state = self
+                .state_at(new_best)
+                .expect("the fork choice picks a block in the tree");
+            self.best = new_best;
+        }
+        self.tree = tree;
+        let tree = &self.tree;
+        self.blocks.retain(|h, _| tree.contains(*h));
+        self.snapshots.retain(|h, _| tree.contains(*h));
+        Some(change)
+    }
+
+    /// Start the tree over from a pruned chain: the given headers, oldest first, followed by the
+    /// given root block, with the given state after it. This is how a pruned node resumes, since
+    /// it no longer has the bodies needed to import its chain from genesis. Any other block in
+    /// the tree is forgotten. Fails, leaving the tree unchanged, unless the headers lead from the
+    /// tree's genesis to the root.
+    pub(super) fn resume_pruned(
+        &mut self,
+        pruned: Vec<Header<C::Digest>>,
+        root: Block<C, SM>,
+        root_state: SM::State,
+    ) -> Result<(), ImportError> {
+        let chain: Vec<&Header<C::Digest>> = pruned.iter().chain([&root.header]).collect();
+        let leads_from_genesis = hash(chain[0]) == self.best_chain()[0]
+            && chain
+                .windows(2)
+                .all(|w| w[1].parent == hash(w[0]) && w[1].height == w[0].height + 1);
+        if !leads_from_genesis {
+            return Err(ImportError::UnknownParent);
+        }
+        if pruned.is_empty() {
+            // The root is genesis, which the tree already starts from.
+            return Ok(());
+        }
+
+        let root_hash = hash(&root.header);
+        self.tree = HeaderTree::new(root.header.clone());
+        self.blocks = HashMap::from([(root_hash, root)]);
+        self.best = root_hash;
+        self.best_state = root_state.clone();
+        self.snapshots = HashMap::from([(root_hash, root_state)]);
+        self.pruned_chain = pruned.iter().map(hash).collect();
+        self.pruned_headers = pruned.into_iter().map(|h| (hash(&h), h)).collect();
+        Ok(())
+    }
+
+    /// Import the given blocks in order, with the same result for each as importing them one at
+    /// a time. A block's parent may be in the tree or earlier in the batch. Returns how the best
+    /// head moved, or why the block was refused, for each block.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(blocks = blocks.len()))
+    )]
+    pub(super) fn import_batch(
+        &mut self,
+        blocks: Vec<Block<C, SM>>,
+    ) -> Vec<Result<HeadChange, ImportError>>
+    where
+        C: Sync,
+        C::Digest: Sync,
+        SM::Transition: Sync,
+    {
+        let prechecks = self.precheck(&blocks);
+        blocks
+            .into_iter()
+            .zip(prechecks)
+            .map(|(block, precheck)| {
+                self.check_header(&block.header)?;
+                match precheck {
+                    Some(precheck) => precheck?,
+                    // The parent was not known when the batch was prechecked, so the block is
+                    // checked in full.
+                    None => {
+                        let encoded = block.check_extrinsics_root()?;
+                        self.check_seal(&block, &encoded)?;
+                    }
+                }
+                let post_state = self.execute(&block)?;
+                Ok(self.commit(block, post_state))
+            })
+            .collect()
+    }
+
+    /// Check the extrinsics root and seal of each of the given blocks whose parent is in the
+    /// tree or earlier in the batch. These checks do not depend on any state, so they run in
+    /// parallel.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(blocks = blocks.len()))
+    )]
+    fn precheck(&self, blocks: &[Block<C, SM>]) -> Vec<Option<Result<(), ImportError>>>
+    where
+        C: Sync,
+        C::Digest: Sync,
+        SM::Transition: Sync,
+    {
+        let in_batch: HashMap<Hash, &Header<C::Digest>> = blocks
+            .iter()
+            .map(|b| (hash(&b.header), &b.header))
+            .collect();
+        let (consensus, tree) = (&self.consensus, &self.tree);
+        let check = |block: &Block<C, SM>| {
+            let parent = in_batch
+                .get(&block.header.parent)
+                .copied()
+                .or_else(|| tree.get(block.header.parent))?;
+            let checked = block.check_extrinsics_root().and_then(|encoded| {
+                block.check_seal(consensus, &parent.consensus_digest, &encoded)
+            });
+            Some(checked.map_err(ImportError::from))
+        };
+        #[cfg(feature = "parallel")]
+        {
+            use rayon::prelude::*;
+            blocks.par_iter().map(check).collect()
+        }
+        #[cfg(not(feature = "parallel"))]
+        {
+            blocks.iter().map(check).collect()
+        }
+    }
+
+    /// Check that the header is new and follows a block in the tree.
+    fn check_header(&self, header: &Header<C::Digest>) -> Result<(), ImportError> {
+        if self.contains(hash(header)) {
+            return Err(ImportError::AlreadyKnown);
+        }
+        let Some(parent) = self.tree.get(header.parent) else {
+            return Err(if self.is_pruned(header.parent) {
+                ImportError::PrunedParent
+            } else {
+                ImportError::UnknownParent
+            });
+        };
+        if parent.height + 1 != header.height {
+            return Err(BlockVerificationError::NotAChild.into());
+        }
+        if parent.height < self.finalized_height() {
+            return Err(ImportError::BelowFinalized);
+        }
+        Ok(())
+    }
+
+    /// Check the seal of a block whose header passed `check_header`, given its encoded body.
+    fn check_seal(
+        &self,
+        block: &Block<C, SM>,
+        encoded_body: &[Vec<u8>],
+    ) -> Result<(), ImportError> {
+        let parent = self
+            .tree
+            .get(block.header.parent)
+            .expect("checked by check_header");
+        Ok(block.check_seal(&self.consensus, &parent.consensus_digest, encoded_body)?)
+    }
+
+    /// Check the coinbase of a block whose header passed `check_header`, if the state machine
+    /// has coinbases, then execute it on top of its parent's state, and check its state root.
+    /// Returns the state after it.
+    fn execute(&self, block: &Block<C, SM>) -> Result<SM::State, ImportError> {
+        if let Some((reward, check)) = self.coinbase {
+            check(block, reward)?;
+        }
+        let pre_state = self
+            .state_at(block.header.parent)
+            .expect("every block in the tree has a state");
+        Ok(block.execute_checked(&pre_state)?)
+    }
+
+    /// Add a fully verified block to the tree, given the state after it, and follow the fork
+    /// choice rule to the best head. Returns how the best head moved.
+    fn commit(&mut self, block: Block<C, SM>, post_state: SM::State) -> HeadChange {
+        let block_hash = hash(&block.header);
+        if block.header.height.is_multiple_of(self.snapshot_interval) {
+            self.snapshots.insert(block_hash, post_state.clone());
+        }
+        self.tree.insert(block.header.clone());
+        self.blocks.insert(block_hash, block);
+
+        let new_best = self.fork_choice.best_head(&self.tree);
+        if new_best == self.best {
+            return HeadChange::default();
+        }
+        let change = self.head_change(new_best);
+        self.best_state = if new_best == block_hash {
+            post_state
+        } else {
+            self.state_at(new_best)
+                .expect("the fork choice picks a block in the tree")
+        };
+        self.best = new_best;
+        self.prune();
+        change
+    }
+
+    /// In pruned mode, make the oldest block to keep the root of the header tree, with its state
+    /// snapshotted. The best chain's blocks before it keep their headers only, and forks
+    /// branching off before it are forgotten.
+    fn prune(&mut self) {
+        let HistoryMode::Pruned(keep) = self.history else {
+            return;
+        };
+        let route = self.tree.route_from_root(self.best);
+        let Some(new_root) = route.len().checked_sub(keep as usize + 1) else {
+            return;
+        };
+        if new_root == 0 {
+            return;
+        }
+        let state = self
+            .state_at(route[new_root])
+            .expect("the best chain has states");
+        self.snapshots.insert(route[new_root], state);
+        for block_hash in &route[..new_root] {
+            let header = self.tree.get(*block_hash).expect("on the route").clone();
+            self.pruned_headers.insert(*block_hash, header);
+            self.pruned_chain.push(*block_hash);
+        }
+        self.tree.prune_to(route[new_root]);
+        let tree = &self.tree;
+        self.blocks.retain(|h, _| tree.contains(*h));
+        self.snapshots.retain(|h, _| tree.contains(*h));
+    }
+
+    /// How the best head would move from where it is to the given block.
+    fn head_change(&self, new_best: Hash) -> HeadChange {
+        let old_route = self.tree.route_from_root(self.best);
+        let new_route = self.tree.route_from_root(new_best);
+        let common = old_route
+            .iter()
+            .zip(&new_route)
+            .take_while(|(old, new)| old == new)
+            .count();
+        HeadChange {
+            retracted: old_route[common..].iter().rev().copied().collect(),
+            enacted: new_route[common..].to_vec(),
+        }
+    }
+}
+
+impl<C, SM, FC> BlockTree<C, SM, FC>
+where
+    C: Consensus,
+    C::Digest: Zero + One + core::hash::Hash,
+    SM: Rewarded,
+    SM::State: core::hash::Hash + Clone,
+    SM::Transition: core::hash::Hash + Encode + Clone + PartialEq,
+    FC: ForkChoice<C::Digest>,
+{
+    /// Refuse blocks that do not start with a coinbase paying their author the given reward plus
+    /// the fees in their body.
+    pub fn with_block_reward(mut self, reward: u64) -> Self {
+        self.coinbase = Some((Some(reward), Block::check_coinbase));
+        self
+    }
+
+    /// Refuse blocks holding a coinbase, as blocks do not pay their authors.
+    pub fn without_block_reward(mut self) -> Self {
+        self.coinbase = Some((None, Block::check_coinbase));
+        self
+    }
+}
+
+#[cfg(test)]
+use super::p3_fork_choice::LongestChain;
+#[cfg(test)]
+use super::Withdrawals;
+#[cfg(test)]
+use crate::c1_state_machine::BlockContext;
+#[cfg(test)]
+use crate::c3_consensus::p1_pow::PoW;
+
+#[cfg(test)]
+type WithdrawalsTree = BlockTree<PoW, Withdrawals, LongestChain>;
+
+#[cfg(test)]
+fn withdrawals_tree() -> WithdrawalsTree {
+    BlockTree::new(PoW::new(u64::MAX / 4), LongestChain, 100)
+}
+
+/// A child of the given block withdrawing the given amounts, built on the state the tree has
+/// for its parent.
+#[cfg(test)]
+fn child(tree: &WithdrawalsTree, parent: Hash, body: Vec<u64>) -> Block<PoW, Withdrawals> {
+    let parent_block = &tree.blocks[&parent];
+    let context = BlockContext {
+        height: parent_block.header.height + 1,
+        ..BlockContext::default()
+    };
+    let pre_state = tree.state_at(parent).unwrap();
+    parent_block.child(&pre_state, body, context).unwrap()
+}
+
+/// Import a child of the given block withdrawing the given amounts, and return its hash.
+#[cfg(test)]
+fn extend(tree: &mut WithdrawalsTree, parent: Hash, body: Vec<u64>) -> (Hash, HeadChange) {
+    let block = child(tree, parent, body);
+    let block_hash = hash(&block.header);
+    (block_hash, tree.import(block).unwrap())
+}
+
+#[test]
+fn cl_block_tree_reorgs_to_the_longer_fork() {
+    use super::tx_pool::TxPool;
+
+    let mut tree = withdrawals_tree().with_snapshot_interval(2);
+    let root = tree.best_head();
+    let (a1, grew) = extend(&mut tree, root, vec![10]);
+    assert_eq!(grew.enacted, vec![a1]);
+    assert!(!grew.is_reorg());
+    let (a2, _) = extend(&mut tree, a1, vec![20]);
+    let (b1, _) = extend(&mut tree, root, vec![50]);
+    let (b2, tie) = extend(&mut tree, b1, vec![1]);
+
+    // A tie goes to the fork seen first.
+    assert_eq!(tie, HeadChange::default());
+    assert_eq!((tree.best_head(), tree.best_state()), (a2, &70));
+
+    let mut pool = TxPool::<Withdrawals>::new();
+    pool.submit(1, tree.best_state()).unwrap();
+    pool.submit(60, tree.best_state()).unwrap();
+    let (b3, reorg) = extend(&mut tree, b2, vec![]);
+    assert_eq!(reorg.retracted, vec![a2, a1]);
+    assert_eq!(reorg.enacted, vec![b1, b2, b3]);
+    assert_eq!((tree.best_head(), tree.best_height()), (b3, 3));
+    assert_eq!(tree.best_state(), &49);
+
+    // The retracted withdrawals go back in the pool, the one B includes leaves it, and the one
+    // B's balance cannot cover is dropped.
+    let dropped = pool.on_reorg(
+        &tree.bodies(&reorg.retracted),
+        &tree.bodies(&reorg.enacted),
+        tree.best_state(),
+    );
+    assert_eq!(dropped, 1);
+    assert_eq!(pool.batch(usize::MAX), vec![20, 10]);
+
+    // The abandoned fork's state can still be reconstructed.
+    assert_eq!(tree.state_at(a1), Some(90));
+    assert_eq!(tree.state_at(a2), Some(70));
+}
+
+#[test]
+fn cl_block_tree_imports_batches_like_blocks_one_at_a_time() {
+    // A chain, built on a scratch tree so that the trees under test start from genesis.
+    let mut scratch = withdrawals_tree();
+    let mut chain = vec![scratch.best_head()];
+    for amount in [10, 20, 30, 5] {
+        let (block_hash, _) = extend(&mut scratch, *chain.last().unwrap(), vec![amount]);
+        chain.push(block_hash);
+    }
+    let block = |h: &Hash| scratch.block(*h).unwrap().clone();
+    let mut forged = child(&scratch, chain[2], vec![1]);
+    forged.body = vec![2];
+    // Its child is fine, but has no parent to go on.
+    let context = BlockContext {
+        height: 4,
+        ..BlockContext::default()
+    };
+    let orphan = forged.child(&69, vec![], context).unwrap();
+    let mut unsealed = child(&scratch, chain[2], vec![3]);
+    let engine = PoW::new(u64::MAX / 4);
+    while engine.validate(&0, &unsealed.header) {
+        unsealed.header.consensus_digest += 1;
+    }
+    let batch = vec![
+        block(&chain[1]),
+        block(&chain[2]),
+        forged.clone(),
+        orphan,
+        unsealed,
+        block(&chain[3]),
+        block(&chain[1]),
+        block(&chain[4]),
+    ];
+
+    let mut one_at_a_time = withdrawals_tree();
+    let expected: Vec<_> = batch
+        .iter()
+        .map(|b| one_at_a_time.import(b.clone()))
+        .collect();
+    let mut batched = withdrawals_tree();
+    let results = batched.import_batch(batch);
+
+    assert_eq!(results, expected);
+    let errors: Vec<_> = results.into_iter().filter_map(Result::err).collect();
+    assert!(matches!(
+        errors[..],
+        [
+            ImportError::Invalid(BlockVerificationError::ExtrinsicsRootMismatch { .. }),
+            ImportError::UnknownParent,
+            ImportError::Invalid(BlockVerificationError::InvalidSeal),
+            ImportError::AlreadyKnown,
+        ]
+    ));
+    assert_eq!(batched.best_head(), chain[4]);
+    assert_eq!(batched.best_state(), &35);
+}
+
+#[test]
+fn cl_block_tree_never_reorgs_below_the_finalized_block() {
+    let mut tree = withdrawals_tree();
+    let root = tree.best_head();
+    let (a1, _) = extend(&mut tree, root, vec![10]);
+    let (a2, _) = extend(&mut tree, a1, vec![20]);
+    let (b1, _) = extend(&mut tree, root, vec![50]);
+    let (c2, _) = extend(&mut tree, a1, vec![5]);
+    let late = [child(&tree, root, vec![1]), child(&tree, a1, vec![2])];
+    let orphaned = child(&tree, b1, vec![]);
+    assert_eq!(tree.chain_info().finalized_hash, root);
+
+    // Finalizing a block off the best chain moves the best head onto it.
+    let change = tree.finalize(c2).unwrap();
+    assert_eq!((change.retracted, change.enacted), (vec![a2], vec![c2]));
+    assert_eq!(
+        tree.chain_info(),
+        ChainInfo {
+            best_hash: c2,
+            best_height: 2,
+            finalized_hash: c2,
+            finalized_height: 2,
+        }
+    );
+    assert_eq!(tree.best_state(), &85);
+    assert!(!tree.contains(a2) && !tree.contains(b1));
+
+    for block in late {
+        assert_eq!(tree.import(block), Err(ImportError::BelowFinalized));
+    }
+    assert_eq!(tree.import(orphaned), Err(ImportError::UnknownParent));
+    assert_eq!(tree.finalize(a1), Some(HeadChange::default()));
+    assert_eq!(tree.finalize(a2), None);
+    let (c3, _) = extend(&mut tree, c2, vec![]);
+    assert_eq!(tree.chain_info().best_hash, c3);
+    assert_eq!(tree.chain_info().finalized_hash, c2);
+}
+
+#[test]
+fn cl_block_tree_prunes_old_bodies_but_keeps_headers() {
+    let mut tree = withdrawals_tree().with_history(HistoryMode::Pruned(2));
+    assert_eq!(withdrawals_tree().history(), HistoryMode::Archive);
+    let root = tree.best_head();
+    let (a1, _) = extend(&mut tree, root, vec![10]);
+    let a1_again = tree.block(a1).unwrap().clone();
+    let (b2, _) = extend(&mut tree, a1, vec![1]);
+    let (a2, _) = extend(&mut tree, a1, vec![20]);
+    let late = child(&tree, a1, vec![2]);
+    let orphan = child(&tree, b2, vec![2]);
+    let (a3, _) = extend(&mut tree, a2, vec![30]);
+    // Only genesis is far enough behind to be pruned so far, so B's fork survives.
+    assert!(tree.is_pruned(root) && !tree.is_pruned(a1));
+    assert!(tree.contains(b2));
+
+    let (a4, _) = extend(&mut tree, a3, vec![5]);
+    assert_eq!(tree.best_chain(), vec![root, a1, a2, a3, a4]);
+    assert!(tree.is_pruned(a1) && tree.header(a1).is_some());
+    assert_eq!((tree.body_of(a1), tree.state_at(a1)), (None, None));
+    assert!(!tree.contains(b2));
+    assert_eq!(tree.state_at(a2), Some(70));
+    assert_eq!(tree.best_state(), &35);
+
+    assert_eq!(tree.import(a1_again), Err(ImportError::AlreadyKnown));
+    assert_eq!(tree.import(late), Err(ImportError::PrunedParent));
+    assert_eq!(tree.import(orphan), Err(ImportError::UnknownParent));
+}
+
+#[test]
+fn cl_block_tree_rejects_bad_blocks() {
+    let mut tree = withdrawals_tree();
+    let root = tree.best_head();
+    let block = child(&tree, root, vec![10]);
+    tree.import(block.clone()).unwrap();
+    assert_eq!(tree.import(block.clone()), Err(ImportError::AlreadyKnown));
+
+    let orphan = child(&tree, hash(&block.header), vec![]);
+    let mut other = withdrawals_tree();
+    assert_eq!(other.import(orphan), Err(ImportError::UnknownParent));
+
+    // A block built on the wrong pre-state commits to the wrong state root.
+    let genesis = &tree.blocks[&root];
+    let wrong = genesis
+        .child(&50, vec![10], BlockContext::default())
+        .unwrap();
+    assert_eq!(
+        tree.import(wrong),
+        Err(ImportError::Invalid(
+            BlockVerificationError::StateRootMismatch {
+                expected: hash(&90u64),
+                found: hash(&40u64),
+            }
+        ))
+    );
+    assert_eq!(tree.best_state(), &90);
+}
+
+#[test]
+fn cl_block_tree_checks_that_blocks_pay_their_author() {
+    use super::BlockBuildError;
+    use crate::c1_state_machine::p7_multiasset::{AssetTransaction, MultiAsset, NATIVE_ASSET};
+    use crate::c1_state_machine::{StateMachine, User};
+
+    let genesis_state =
+        MultiAsset::genesis_state(vec![(NATIVE_ASSET, User::Alice, vec![(User::Alice, 100)])]);
+    let mut tree = BlockTree::<PoW, MultiAsset, LongestChain>::new(
+        PoW::new(u64::MAX / 4),
+        LongestChain,
+        genesis_state.clone(),
+    )
+    .with_block_reward(10);
+    assert_eq!(tree.block_reward(), Some(10));
+    let genesis = Block::<PoW, MultiAsset>::genesis(&genesis_state);
+    let context = |author| BlockContext {
+        height: 1,
+        author,
+        ..BlockContext::default()
+    };
+    let transfer = AssetTransaction::Transfer {
+        asset: NATIVE_ASSET,
+        sender: User::Alice,
+        receiver: User::Bob,
+        amount: 30,
+    };
+    let coinbase = MultiAsset::coinbase(User::Charlie, 10);
+    let invalid = |error| Err(ImportError::Invalid(error));
+
+    // Only the builder adds a coinbase, at the start of the block.
+    assert!(matches!(
+        genesis.child_with_coinbase(
+            &genesis_state,
+            vec![transfer.clone(), coinbase.clone()],
+            context(Some(User::Charlie)),
+            10
+        ),
+        Err(BlockBuildError::StateExecutionFailed { index: 1, .. })
+    ));
+
+    let underpaid = genesis
+        .child_with_coinbase(
+            &genesis_state,
+            vec![transfer.clone()],
+            context(Some(User::Charlie)),
+            9,
+        )
+        .unwrap();
+    assert_eq!(
+        tree.import(underpaid),
+        invalid(BlockVerificationError::CoinbaseMismatch)
+    );
+    let unpaid = genesis
+        .child(
+            &genesis_state,
+            vec![transfer.clone()],
+            context(Some(User::Charlie)),
+        )
+        .unwrap();
+    assert_eq!(
+        tree.import(unpaid),
+        invalid(BlockVerificationError::CoinbaseMismatch)
+    );
+    let paid_twice = genesis
+        .child(
+            &genesis_state,
+            vec![coinbase.clone(), coinbase.clone()],
+            context(Some(User::Charlie)),
+        )
+        .unwrap();
+    assert_eq!(
+        tree.import(paid_twice),
+        invalid(BlockVerificationError::MisplacedCoinbase { index: 1 })
+    );
+
+    let paid = genesis
+        .child_with_coinbase(
+            &genesis_state,
+            vec![transfer.clone()],
+            context(Some(User::Charlie)),
+            10,
+        )
+        .unwrap();
+    assert_eq!(paid.body, vec![coinbase, transfer.clone()]);
+    tree.import(paid).unwrap();
+    assert_eq!(tree.best_state().balance(NATIVE_ASSET, User::Charlie), 10);
+    assert_eq!(tree.best_state().total_supply(NATIVE_ASSET), Some(110));
+
+    // Nobody is owed anything for a block without an author.
+    let anonymous = genesis
+        .child_with_coinbase(&genesis_state, vec![transfer.clone()], context(None), 10)
+        .unwrap();
+    assert_eq!(anonymous.body, vec![transfer.clone()]);
+    tree.import(anonymous).unwrap();
+
+    // A chain without a block reward pays nobody, not even authors who pay themselves.
+    let mut unrewarded = BlockTree::<PoW, MultiAsset, LongestChain>::new(
+        PoW::new(u64::MAX / 4),
+        LongestChain,
+        genesis_state.clone(),
+    )
+    .without_block_reward();
+    assert_eq!(unrewarded.block_reward(), None);
+    let self_paid = genesis
+        .child_with_coinbase(
+            &genesis_state,
+            vec![transfer.clone()],
+            context(Some(User::Charlie)),
+            0,
+        )
+        .unwrap();
+    assert_eq!(
+        unrewarded.import(self_paid),
+        invalid(BlockVerificationError::CoinbaseMismatch)
+    );
+    let unpaid = genesis
+        .child(&genesis_state, vec![transfer], context(Some(User::Charlie)))
+        .unwrap();
+    unrewarded.import(unpaid).unwrap();
+}
@@ -0,0 +1,218 @@
+//! Every node on a chain has to start from the same genesis block and follow the same consensus
+//! rules, or their chains part ways at the first block. Rather than compiling the engine's
+//! parameters and the genesis state into the node, a chain is described by a spec file that every
+//! node loads at startup: a name and id for the chain, the consensus engine and its parameters,
+//! the state machine's genesis configuration, the addresses of a few nodes to connect to first,
+//! for chains that pay block authors the block reward, and the checkpoints new nodes may start
+//! from.
+//!
+//! Specs can be written in JSON or TOML, so loading them requires the `serde` feature. A spec
+//! built in code works without it.
+
+use crate::c1_state_machine::StateMachine;
+use crate::c3_consensus::finality::BlockId;
+use crate::c3_consensus::spec::{ConsensusSpec, FromSpec, SpecError};
+
+/// Everything a node needs to know to join a chain
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ChainSpec<GenesisConfig> {
+    /// A name for people to read
+    pub name: String,
+    /// A short identifier, for example to tell apart the databases of different chains
+    pub id: String,
+    pub consensus: ConsensusSpec,
+    /// How the state machine builds its genesis state
+    pub genesis: GenesisConfig,
+    /// The addresses of nodes to connect to when starting up
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub boot_nodes: Vec<String>,
+    /// What the coinbase starting every block pays its author besides fees, if blocks pay their
+    /// authors at all
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub block_reward: Option<u64>,
+    /// Blocks of the chain everyone agrees on, learned out of band. A new node can warp sync to
+    /// the latest of them rather than execute every block since genesis.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Vec::is_empty")
+    )]
+    pub checkpoints: Vec<BlockId>,
+}
+
+impl<GenesisConfig: Clone> ChainSpec<GenesisConfig> {
+    /// The consensus engine the spec describes.
+    pub fn consensus<C: FromSpec>(&self) -> Result<C, SpecError> {
+        C::from_spec(&self.consensus)
+    }
+
+    /// The genesis state the spec describes.
+    pub fn genesis_state<SM>(&self) -> SM::State
+    where
+        SM: StateMachine<GenesisConfig = GenesisConfig>,
+    {
+        SM::genesis_state(self.genesis.clone())
+    }
+}
+
+/// The ways loading a spec can fail
+#[cfg(feature = "serde")]
+#[derive(Debug)]
+pub enum ChainSpecError {
+    /// The spec file could not be read
+    Io(std::io::Error),
+    /// The spec is not valid JSON, or does not describe a chain
+    Json(serde_json::Error),
+    /// The spec is not valid TOML, or does not describe a chain
+    Toml(toml::de::Error),
+    /// The spec file's extension is neither `.json` nor `.toml`
+    UnknownFormat(std::path::PathBuf),
+}
+
+#[cfg(feature = "serde")]
+impl From<std::io::Error> for ChainSpecError {
+    fn from(e: std::io::Error) -> Self {
+        ChainSpecError::Io(e)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<serde_json::Error> for ChainSpecError {
+    fn from(e: serde_json::Error) -> Self {
+        ChainSpecError::Json(e)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl From<toml::de::Error> for ChainSpecError {
+    fn from(e: toml::de::Error) -> Self {
+        ChainSpecError::Toml(e)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<GenesisConfig: serde::de::DeserializeOwned> ChainSpec<GenesisConfig> {
+    /// Parse a spec written in JSON.
+    pub fn from_json(json: &str) -> Result<Self, ChainSpecError> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// Parse a spec written in TOML.
+    pub fn from_toml(toml: &str) -> Result<Self, ChainSpecError> {
+        Ok(toml::from_str(toml)?)
+    }
+
+    /// Load the spec in the given file, which is parsed as JSON or TOML depending on its
+    /// extension.
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Self, ChainSpecError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("json") => Self::from_json(&contents),
+            Some("toml") => Self::from_toml(&contents),
+            _ => Err(ChainSpecError::UnknownFormat(path.to_owned())),
+        }
+    }
+}
+
+#[cfg(test)]
+use super::block_tree::BlockTree;
+#[cfg(test)]
+use super::p3_fork_choice::LongestChain;
+#[cfg(test)]
+use super::{Block, Withdrawals};
+#[cfg(test)]
+use crate::c1_state_machine::BlockContext;
+#[cfg(test)]
+use crate::c3_consensus::p1_pow::PoW;
+#[cfg(test)]
+use crate::hash;
+
+#[cfg(test)]
+fn test_spec() -> ChainSpec<u64> {
+    ChainSpec {
+        name: "Test Chain".into(),
+        id: "test".into(),
+        consensus: ConsensusSpec::Pow {
+            threshold: u64::MAX / 4,
+        },
+        genesis: 100,
+        boot_nodes: vec!["127.0.0.1:30333".into()],
+        block_reward: None,
+        checkpoints: Vec::new(),
+    }
+}
+
+#[test]
+fn cl_chain_spec_builds_the_genesis_block_and_the_tree() {
+    let spec = test_spec();
+    let (genesis, state) = Block::<PoW, Withdrawals>::genesis_from_spec(&spec).unwrap();
+    assert_eq!(state, 100);
+    assert_eq!(genesis.consensus, PoW::new(u64::MAX / 4));
+
+    let mut tree = BlockTree::<PoW, Withdrawals, _>::from_spec(&spec, LongestChain).unwrap();
+    assert_eq!(tree.best_head(), hash(&genesis.header));
+    let context = BlockContext {
+        height: 1,
+        ..BlockContext::default()
+    };
+    let child = genesis.child(&state, vec![30], context).unwrap();
+    assert!(tree.import(child).is_ok());
+    assert_eq!(tree.best_state(), &70);
+
+    let trivial = ChainSpec {
+        consensus: ConsensusSpec::Trivial,
+        ..spec
+    };
+    assert!(BlockTree::<PoW, Withdrawals, _>::from_spec(&trivial, LongestChain).is_err());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn cl_chain_spec_loads_json_and_toml() {
+    let json = r#"{
+        "name": "Test Chain",
+        "id": "test",
+        "consensus": { "engine": "pow", "threshold": 4611686018427387903 },
+        "genesis": 100,
+        "boot_nodes": ["127.0.0.1:30333"]
+    }"#;
+    assert_eq!(ChainSpec::from_json(json).unwrap(), test_spec());
+
+    let toml = r#"
+        name = "Forked Chain"
+        id = "forked"
+        genesis = 100
+
+        [consensus]
+        engine = "forked"
+        fork_height = 20
+
+        [consensus.before]
+        engine = "pow"
+        threshold = 4611686018427387903
+
+        [consensus.after]
+        engine = "poa"
+        authorities = ["Alice", "Bob"]
+    "#;
+    let spec = ChainSpec::<u64>::from_toml(toml).unwrap();
+    assert!(spec.boot_nodes.is_empty());
+    assert_eq!(spec.consensus.engine(), "forked");
+    assert!(ChainSpec::<u64>::from_toml("name = \"no id\"").is_err());
+
+    let dir = std::env::temp_dir().join(format!("diy-blockchain-spec-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("forked.toml"), toml).unwrap();
+    std::fs::write(dir.join("forked.yaml"), toml).unwrap();
+    assert_eq!(ChainSpec::load(dir.join("forked.toml")).unwrap(), spec);
+    assert!(matches!(
+        ChainSpec::<u64>::load(dir.join("forked.yaml")),
+        Err(ChainSpecError::UnknownFormat(_))
+    ));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,891 @@
+//! A node that keeps its blocks only in memory must download the whole chain again every time it
+//! restarts. The chain database keeps them on disk instead.
+//!
+//! A database is a directory holding three files:
+//!
+//! - `VERSION`, the version of the format the other files are written in.
+//! - `bodies.jsonl`, every block's body and context, one JSON record per line.
+//! - `headers.jsonl`, every block's header, one JSON record per line, along with where its body
+//!   is in `bodies.jsonl`. Together these records are the block index, which is read into memory
+//!   when the database is opened, while bodies are only read when they are needed.
+//!
+//! Both files are only ever appended to, and a body is written before the header pointing at
+//! it. If the node stops part way through writing a block, the worst that can be left behind is
+//! an unfinished last line of `headers.jsonl`, which is dropped when the database is next opened.
+//!
+//! The database of an archive node keeps every block. The database of a pruned node is compacted
+//! every so often, down to the blocks its block tree still has. The headers of the best chain's
+//! older blocks then move to a fourth file, `pruned.json`, along with the state after the oldest
+//! block still stored, which is where the chain resumes from. Compacting writes the new files
+//! next to the old ones first, and marks the database as `COMPACTING` before moving them in, so
+//! that a compaction interrupted by the node stopping is finished when the database is next
+//! opened.
+//!
+//! Disks and people editing files by hand can damage a database in ways an unfinished write
+//! cannot. `ChainDb::check` walks the stored chain without opening the database, importing every
+//! block into a fresh block tree so that its hash, extrinsics root and state root are all worked
+//! out again, and reports the blocks that are corrupt. `ChainDb::repair` then truncates the files
+//! back to the last block before the first corrupt one.
+//!
+//! When the format changes, `FORMAT_VERSION` goes up, and databases written in an older format
+//! are upgraded by migrations when they are opened.
+//!
+//! The records are JSON, so the database requires the `serde` feature.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use super::block_tree::{BlockTree, ImportError, IMPORT_BATCH_LEN};
+use super::p3_fork_choice::ForkChoice;
+use super::{Block, Hash, Header};
+use crate::c1_state_machine::{
+    BlockContext, ContextualStateMachine, SerdeStateMachine, StateMachine,
+};
+use crate::c3_consensus::Consensus;
+use crate::codec::Encode;
+use crate::hash;
+use num::traits::{One, Zero};
+
+/// The version of the on-disk format written by this code
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Upgrades the database in the given directory from one version of the format to the next.
+/// Opening the database records the new version once the migration succeeds.
+pub type Migration = fn(dir: &Path) -> Result<(), DbError>;
+
+/// The ways opening, reading or writing the database can fail
+#[derive(Debug)]
+pub enum DbError {
+    /// A file could not be read or written
+    Io(std::io::Error),
+    /// A record could not be encoded or decoded
+    Codec(serde_json::Error),
+    /// The database was written in a newer format than this code understands
+    UnsupportedVersion { found: u32 },
+    /// The database was written in an older format, and no migration upgrades it from the given
+    /// version
+    MissingMigration { from: u32 },
+    /// The `VERSION` file does not hold a version
+    BadVersion(String),
+    /// A stored block was refused when loading it into a block tree
+    Rejected {
+        block_hash: Hash,
+        error: ImportError,
+    },
+}
+
+impl From<std::io::Error> for DbError {
+    fn from(e: std::io::Error) -> Self {
+        DbError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for DbError {
+    fn from(e: serde_json::Error) -> Self {
+        DbError::Codec(e)
+    }
+}
+
+/// What checking a database found
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DbCheck {
+    /// How many stored blocks come before the first corrupt one
+    pub consistent: usize,
+    /// Every corrupt block, in the order they are stored
+    pub corrupt: Vec<CorruptBlock>,
+}
+
+impl DbCheck {
+    /// Whether every stored block is fine.
+    pub fn is_consistent(&self) -> bool {
+        self.corrupt.is_empty()
+    }
+}
+
+/// A stored block that is not what it should be
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CorruptBlock {
+    /// The line of `headers.jsonl` the block is on, counting from 0
+    pub position: usize,
+    pub error: Corruption,
+}
+
+/// What is wrong with a corrupt block
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Corruption {
+    /// The line of `headers.jsonl` is not a header record
+    UnreadableHeader,
+    /// The body the header points at is not a body record
+    UnreadableBody { block_hash: Hash },
+    /// The block tree refused the block. A block whose header was changed no longer has the
+    /// hash its children name as their parent, so they are refused as having an unknown parent.
+    Rejected {
+        block_hash: Hash,
+        error: ImportError,
+    },
+}
+
+/// A line of `headers.jsonl`
+#[derive(serde::Serialize, serde::Deserialize)]
+struct HeaderRecord<Digest> {
+    header: Header<Digest>,
+    /// Where the body's record starts in `bodies.jsonl`, in bytes
+    body_offset: u64,
+    /// The length of the body's record, in bytes, without the newline
+    body_len: u64,
+}
+
+/// The contents of `pruned.json`
+#[derive(serde::Serialize, serde::Deserialize)]
+struct PrunedRecord<Digest, State> {
+    /// The headers of the best chain's pruned blocks, oldest first
+    headers: Vec<Header<Digest>>,
+    /// The state after the oldest stored block
+    state: State,
+}
+
+/// A line of `bodies.jsonl`
+#[derive(serde::Serialize, serde::Deserialize)]
+struct BodyRecord<Transition> {
+    context: BlockContext,
+    body: Vec<Transition>,
+}
+
+/// The blocks of a chain, stored on disk
+pub struct ChainDb<C: Consensus, SM: StateMachine> {
+    dir: PathBuf,
+    headers_file: File,
+    bodies_file: File,
+    /// Every stored header, in the order it was written, so parents come before their children
+    headers: Vec<Header<C::Digest>>,
+    /// Where each block's body is in `bodies.jsonl`, by hash
+    index: HashMap<Hash, (u64, u64)>,
+    /// What the chain resumes from, if the database was compacted
+    pruned: Option<PrunedRecord<C::Digest, SM::State>>,
+    state_machine: PhantomData<SM>,
+}
+
+impl<C, SM> ChainDb<C, SM>
+where
+    C: Consensus,
+    C::Digest: core::hash::Hash + serde::Serialize + serde::de::DeserializeOwned,
+    SM: SerdeStateMachine,
+    SM::Transition: Clone,
+{
+    /// Open the database in the given directory, creating it if there is none. Fails if the
+    /// database was written in any other version of the format.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self, DbError> {
+        Self::open_with_migrations(dir, &[])
+    }
+
+    /// Open the database in the given directory, creating it if there is none. A database
+    /// written in an older format is first upgraded by the given migrations, each of which
+    /// upgrades from the version it is paired with to the next.
+    pub fn open_with_migrations(
+        dir: impl AsRef<Path>,
+        migrations: &[(u32, Migration)],
+    ) -> Result<Self, DbError> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+        let version_path = dir.join("VERSION");
+        let mut version = match std::fs::read_to_string(&version_path) {
+            Ok(text) => text.trim().parse().map_err(|_| DbError::BadVersion(text))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                std::fs::write(&version_path, format!("{FORMAT_VERSION}\n"))?;
+                FORMAT_VERSION
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if version > FORMAT_VERSION {
+            return Err(DbError::UnsupportedVersion { found: version });
+        }
+        while version < FORMAT_VERSION {
+            let (_, migrate) = migrations
+                .iter()
+                .find(|(from, _)| *from == version)
+                .ok_or(DbError::MissingMigration { from: version })?;
+            migrate(&dir)?;
+            version += 1;
+            std::fs::write(&version_path, format!("{version}\n"))?;
+        }
+        if dir.join("COMPACTING").exists() {
+            finish_compaction(&dir)?;
+        }
+        for name in COMPACTED_FILES {
+            // Left behind by a compaction that did not get as far as being marked.
+            let _ = std::fs::remove_file(dir.join(format!("{name}.new")));
+        }
+
+        let append = |name| {
+            OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(dir.join(name))
+        };
+        let mut headers_file = append("headers.jsonl")?;
+        let bodies_file = append("bodies.jsonl")?;
+
+        let mut text = String::new();
+        headers_file.read_to_string(&mut text)?;
+        let mut headers = Vec::new();
+        let mut index = HashMap::new();
+        let mut complete = 0;
+        for line in text.split_inclusive('\n') {
+            if !line.ends_with('\n') {
+                break;
+            }
+            let record: HeaderRecord<C::Digest> = serde_json::from_str(line)?;
+            index.insert(hash(&record.header), (record.body_offset, record.body_len));
+            headers.push(record.header);
+            complete += line.len();
+        }
+        // Drop an unfinished last line, so that the next record starts on a line of its own.
+        headers_file.set_len(complete as u64)?;
+        let pruned = match std::fs::read(dir.join("pruned.json")) {
+            Ok(bytes) => Some(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(ChainDb {
+            dir,
+            headers_file,
+            bodies_file,
+            headers,
+            index,
+            pruned,
+            state_machine: PhantomData,
+        })
+    }
+
+    /// The directory the database is in.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// How many blocks are stored.
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+
+    pub fn contains(&self, block_hash: Hash) -> bool {
+        self.index.contains_key(&block_hash)
+    }
+
+    /// Every stored header, parents before their children.
+    pub fn headers(&self) -> &[Header<C::Digest>] {
+        &self.headers
+    }
+
+    /// How many of the best chain's oldest blocks have only their headers stored.
+    pub fn pruned_len(&self) -> usize {
+        self.pruned.as_ref().map_or(0, |p| p.headers.len())
+    }
+
+    /// The transitions in the body of the given block, if it is stored.
+    pub fn body_of(&self, block_hash: Hash) -> Result<Option<Vec<SM::Transition>>, DbError> {
+        Ok(self.read_body(block_hash)?.map(|record| record.body))
+    }
+
+    /// Store the given block, unless it is already stored. Its parent should be stored first,
+    /// so that the database can be loaded back in order. Returns whether it was stored.
+    pub(super) fn put(&mut self, block: &Block<C, SM>) -> Result<bool, DbError> {
+        let block_hash = hash(&block.header);
+        if self.contains(block_hash) {
+            return Ok(false);
+        }
+        let body = serde_json::to_vec(&BodyRecord {
+            context: block.context.clone(),
+            body: block.body.clone(),
+        })?;
+        let body_offset = self.bodies_file.seek(SeekFrom::End(0))?;
+        self.bodies_file.write_all(&body)?;
+        self.bodies_file.write_all(b"\n")?;
+
+        let mut header = serde_json::to_vec(&HeaderRecord {
+            header: block.header.clone(),
+            body_offset,
+            body_len: body.len() as u64,
+        })?;
+        header.push(b'\n');
+        self.headers_file.write_all(&header)?;
+
+        self.index
+            .insert(block_hash, (body_offset, body.len() as u64));
+        self.headers.push(block.header.clone());
+        Ok(true)
+    }
+
+    /// The given block, if it is stored.
+    pub(super) fn block(&self, block_hash: Hash) -> Result<Option<Block<C, SM>>, DbError> {
+        let Some(record) = self.read_body(block_hash)? else {
+            return Ok(None);
+        };
+        let header = self
+            .headers
+            .iter()
+            .find(|h| hash(*h) == block_hash)
+            .expect("every indexed block has a header")
+            .clone();
+        Ok(Some(Block {
+            header,
+            body: record.body,
+            context: record.context,
+            consensus: C::create_default_instance(),
+        }))
+    }
+
+    /// Store every block in the given tree that is not stored yet, parents before their
+    /// children. Returns how many blocks were stored.
+    pub fn store_tree<FC>(&mut self, tree: &BlockTree<C, SM, FC>) -> Result<usize, DbError> {
+        let mut stored = 0;
+        for block in tree.blocks_in_order() {
+            if self.put(block)? {
+                stored += 1;
+            }
+        }
+        Ok(stored)
+    }
+
+    /// Compact the database down to the blocks the given pruned tree has, once it stores bodies
+    /// for at least twice as many blocks as the tree has, so that compacting after every block
+    /// costs little, or straight away if the database could not load the tree otherwise. The
+    /// tree should have every stored block it has. Returns whether the database was compacted.
+    pub fn compact<FC>(&mut self, tree: &BlockTree<C, SM, FC>) -> Result<bool, DbError>
+    where
+        C::Digest: Zero + One,
+        SM: ContextualStateMachine,
+        SM::State: core::hash::Hash + Clone,
+        SM::Transition: core::hash::Hash + Encode,
+        FC: ForkChoice<C::Digest>,
+    {
+        let blocks = tree.blocks_in_order();
+        let pruned = tree.pruned_headers();
+        // A tree that warp synced never had the blocks before its root, so the database cannot
+        // load the root until it is compacted, however few blocks it stores.
+        let loadable = self.pruned.is_some() || self.index.contains_key(&blocks[0].header.parent);
+        if pruned.is_empty() || (loadable && self.len() < 2 * blocks.len()) {
+            return Ok(false);
+        }
+        let record = PrunedRecord {
+            headers: pruned.into_iter().cloned().collect(),
+            state: tree
+                .state_at(hash(&blocks[0].header))
+                .expect("the root has a state"),
+        };
+        std::fs::write(
+            self.dir.join("pruned.json.new"),
+            serde_json::to_vec(&record)?,
+        )?;
+        let mut headers = Vec::new();
+        let mut bodies = Vec::new();
+        for block in blocks {
+            let body = serde_json::to_vec(&BodyRecord {
+                context: block.context.clone(),
+                body: block.body.clone(),
+            })?;
+            serde_json::to_writer(
+                &mut headers,
+                &HeaderRecord {
+                    header: block.header.clone(),
+                    body_offset: bodies.len() as u64,
+                    body_len: body.len() as u64,
+                },
+            )?;
+            headers.push(b'\n');
+            bodies.extend(body);
+            bodies.push(b'\n');
+        }
+        std::fs::write(self.dir.join("headers.jsonl.new"), headers)?;
+        std::fs::write(self.dir.join("bodies.jsonl.new"), bodies)?;
+
+        File::create(self.dir.join("COMPACTING"))?;
+        finish_compaction(&self.dir)?;
+        *self = Self::open(&self.dir)?;
+        Ok(true)
+    }
+
+    /// Import every stored block into the given tree, parents before their children. Blocks the
+    /// tree already has, like genesis, are skipped. The tree executes every block again, so the
+    /// state after its best head is rebuilt too. A compacted database starts the tree over from
+    /// the state after its oldest stored block. The blocks are imported in batches, whose seals
+    /// the tree checks in parallel. Returns how many blocks were imported.
+    pub fn load_into<FC>(&self, tree: &mut BlockTree<C, SM, FC>) -> Result<usize, DbError>
+    where
+        C: Sync,
+        C::Digest: Zero + One + Sync,
+        SM: ContextualStateMachine,
+        SM::State: core::hash::Hash + Clone,
+        SM::Transition: core::hash::Hash + Encode + Sync,
+        FC: ForkChoice<C::Digest>,
+    {
+        if let (Some(pruned), Some(root)) = (&self.pruned, self.headers.first()) {
+            let block_hash = hash(root);
+            let root = self
+                .block(block_hash)?
+                .expect("every stored header is indexed");
+            tree.resume_pruned(pruned.headers.clone(), root, pruned.state.clone())
+                .map_err(|error| DbError::Rejected { block_hash, error })?;
+        }
+        let mut imported = 0;
+        for headers in self.headers.chunks(IMPORT_BATCH_LEN) {
+            let hashes: Vec<Hash> = headers
+                .iter()
+                .map(hash)
+                .filter(|h| !tree.contains(*h))
+                .collect();
+            let blocks = hashes
+                .iter()
+                .map(|h| Ok(self.block(*h)?.expect("every stored header is indexed")))
+                .collect::<Result<Vec<_>, DbError>>()?;
+            for (block_hash, result) in hashes.into_iter().zip(tree.import_batch(blocks)) {
+                result.map_err(|error| DbError::Rejected { block_hash, error })?;
+                imported += 1;
+            }
+        }
+        Ok(imported)
+    }
+
+    /// Check every block stored in the database in the given directory, without opening it, so
+    /// that a database too damaged to open can be checked too. Every block is imported into the
+    /// given tree, which recomputes its hash and checks its seal, its extrinsics root and the
+    /// state root after executing it. The database must be in the current format.
+    pub fn check<FC>(
+        dir: impl AsRef<Path>,
+        tree: &mut BlockTree<C, SM, FC>,
+    ) -> Result<DbCheck, DbError>
+    where
+        C::Digest: Zero + One,
+        SM: ContextualStateMachine,
+        SM::State: core::hash::Hash + Clone,
+        SM::Transition: core::hash::Hash + Encode,
+        FC: ForkChoice<C::Digest>,
+    {
+        Ok(Self::scan(dir.as_ref(), tree)?.0)
+    }
+
+    /// Check the database in the given directory like `check`, then truncate it back to the
+    /// blocks before the first corrupt one, which the given tree then has. Everything after
+    /// that block goes, even blocks that were fine, because a database only ever stores a block
+    /// after its parent.
+    pub fn repair<FC>(
+        dir: impl AsRef<Path>,
+        tree: &mut BlockTree<C, SM, FC>,
+    ) -> Result<DbCheck, DbError>
+    where
+        C::Digest: Zero + One,
+        SM: ContextualStateMachine,
+        SM::State: core::hash::Hash + Clone,
+        SM::Transition: core::hash::Hash + Encode,
+        FC: ForkChoice<C::Digest>,
+    {
+        let dir = dir.as_ref();
+        let (report, (headers_len, bodies_len)) = Self::scan(dir, tree)?;
+        if !report.is_consistent() {
+            let truncate = |name, len| {
+                OpenOptions::new()
+                    .write(true)
+                    .open(dir.join(name))?
+                    .set_len(len)
+            };
+            truncate("headers.jsonl", headers_len)?;
+            truncate("bodies.jsonl", bodies_len)?;
+        }
+        Ok(report)
+    }
+
+    /// Import every stored block into the given tree, noting those that are corrupt. Returns
+    /// the report, along with how long each file would be with only the blocks before the first
+    /// corrupt one.
+    fn scan<FC>(
+        dir: &Path,
+        tree: &mut BlockTree<C, SM, FC>,
+    ) -> Result<(DbCheck, (u64, u64)), DbError>
+    where
+        C::Digest: Zero + One,
+        SM: ContextualStateMachine,
+        SM::State: core::hash::Hash + Clone,
+        SM::Transition: core::hash::Hash + Encode,
+        FC: ForkChoice<C::Digest>,
+    {
+        let text = std::fs::read_to_string(dir.join("VERSION"))?;
+        let version: u32 = text.trim().parse().map_err(|_| DbError::BadVersion(text))?;
+        if version > FORMAT_VERSION {
+            return Err(DbError::UnsupportedVersion { found: version });
+        }
+        if version < FORMAT_VERSION {
+            return Err(DbError::MissingMigration { from: version });
+        }
+        if dir.join("COMPACTING").exists() {
+            finish_compaction(dir)?;
+        }
+        let text = std::fs::read_to_string(dir.join("headers.jsonl"))?;
+        let mut bodies = File::open(dir.join("bodies.jsonl"))?;
+        let mut pruned: Option<PrunedRecord<C::Digest, SM::State>> =
+            match std::fs::read(dir.join("pruned.json")) {
+                Ok(bytes) => Some(serde_json::from_slice(&bytes)?),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e.into()),
+            };
+
+        let mut report = DbCheck::default();
+        let (mut headers_len, mut bodies_len) = (0, 0);
+        // An unfinished last line is left for opening the database to drop.
+        let lines = text
+            .split_inclusive('\n')
+            .take_while(|line| line.ends_with('\n'));
+        for (position, line) in lines.enumerate() {
+            let Ok(record) = serde_json::from_str::<HeaderRecord<C::Digest>>(line) else {
+                report.corrupt.push(CorruptBlock {
+                    position,
+                    error: Corruption::UnreadableHeader,
+                });
+                continue;
+            };
+            let block_hash = hash(&record.header);
+            let body = read_body_at(&mut bodies, record.body_offset, record.body_len);
+            let Ok(body) = body else {
+                report.corrupt.push(CorruptBlock {
+                    position,
+                    error: Corruption::UnreadableBody { block_hash },
+                });
+                continue;
+            };
+            let block = Block {
+                header: record.header,
+                body: body.body,
+                context: body.context,
+                consensus: C::create_default_instance(),
+            };
+            // A compacted database resumes from its oldest stored block.
+            let imported = match pruned.take() {
+                Some(pruned) => tree.resume_pruned(pruned.headers, block, pruned.state),
+                None if tree.contains(block_hash) => Ok(()),
+                None => tree.import(block).map(drop),
+            };
+            if let Err(error) = imported {
+                report.corrupt.push(CorruptBlock {
+                    position,
+                    error: Corruption::Rejected { block_hash, error },
+                });
+            } else if report.corrupt.is_empty() {
+                report.consistent += 1;
+                headers_len += line.len() as u64;
+                bodies_len = bodies_len.max(record.body_offset + record.body_len + 1);
+            }
+        }
+        Ok((report, (headers_len, bodies_len)))
+    }
+
+    /// Read the record holding the given block's body, if it is stored.
+    fn read_body(&self, block_hash: Hash) -> Result<Option<BodyRecord<SM::Transition>>, DbError> {
+        let Some(&(offset, len)) = self.index.get(&block_hash) else {
+            return Ok(None);
+        };
+        let mut file = File::open(self.dir.join("bodies.jsonl"))?;
+        read_body_at(&mut file, offset, len).map(Some)
+    }
+}
+
+/// Read the body record with the given offset and length from `bodies.jsonl`.
+fn read_body_at<Transition: serde::de::DeserializeOwned>(
+    file: &mut File,
+    offset: u64,
+    len: u64,
+) -> Result<BodyRecord<Transition>, DbError> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut bytes = vec![0; len as usize];
+    file.read_exact(&mut bytes)?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+/// The files a compaction replaces
+const COMPACTED_FILES: [&str; 3] = ["pruned.json", "bodies.jsonl", "headers.jsonl"];
+
+/// Move the files written by a compaction in over the old ones, then unmark the database.
+/// Moving a file in is atomic, and a file already moved in is not there to move again, so this
+/// can be started over as often as it takes.
+fn finish_compaction(dir: &Path) -> Result<(), DbError> {
+    for name in COMPACTED_FILES {
+        match std::fs::rename(dir.join(format!("{name}.new")), dir.join(name)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    std::fs::remove_file(dir.join("COMPACTING"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+use super::block_tree::HistoryMode;
+#[cfg(test)]
+use super::p3_fork_choice::LongestChain;
+#[cfg(test)]
+use super::Withdrawals;
+#[cfg(test)]
+use crate::c3_consensus::p1_pow::PoW;
+
+#[cfg(test)]
+fn temp_db_dir(name: &str) -> PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("diy-blockchain-db-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+/// A tree with a fork: one side withdraws 10 then 20, the other 50, then 1, then nothing.
+#[cfg(test)]
+fn forked_tree() -> BlockTree<PoW, Withdrawals, LongestChain> {
+    let mut tree = BlockTree::new(PoW::new(u64::MAX / 4), LongestChain, 100);
+    let root = tree.best_head();
+    fn extend(
+        tree: &mut BlockTree<PoW, Withdrawals, LongestChain>,
+        parent: Hash,
+        body: Vec<u64>,
+    ) -> Hash {
+        let parent_block = tree.block(parent).unwrap();
+        let context = BlockContext {
+            height: parent_block.header.height + 1,
+            ..BlockContext::default()
+        };
+        let block = parent_block
+            .child(&tree.state_at(parent).unwrap(), body, context)
+            .unwrap();
+        let block_hash = hash(&block.header);
+        tree.import(block).unwrap();
+        block_hash
+    }
+    let a1 = extend(&mut tree, root, vec![10]);
+    extend(&mut tree, a1, vec![20]);
+    let b1 = extend(&mut tree, root, vec![50]);
+    let b2 = extend(&mut tree, b1, vec![1]);
+    extend(&mut tree, b2, vec![]);
+    tree
+}
+
+#[test]
+fn cl_db_resumes_a_chain_after_a_restart() {
+    let dir = temp_db_dir("resume");
+    let tree = forked_tree();
+    {
+        let mut db = ChainDb::<PoW, Withdrawals>::open(&dir).unwrap();
+        assert_eq!(db.store_tree(&tree).unwrap(), 6);
+        assert_eq!(db.store_tree(&tree).unwrap(), 0);
+    }
+
+    let db = ChainDb::<PoW, Withdrawals>::open(&dir).unwrap();
+    assert_eq!(db.len(), 6);
+    assert_eq!(db.body_of(tree.best_head()).unwrap(), Some(vec![]));
+    let mut resumed = BlockTree::new(PoW::new(u64::MAX / 4), LongestChain, 100);
+    assert_eq!(db.load_into(&mut resumed).unwrap(), 5);
+    assert_eq!(resumed.best_head(), tree.best_head());
+    assert_eq!(resumed.best_state(), &49);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn cl_db_compacts_down_to_a_pruned_tree() {
+    let dir = temp_db_dir("compact");
+    let mut tree = BlockTree::new(PoW::new(u64::MAX / 4), LongestChain, 100)
+        .with_history(HistoryMode::Pruned(1));
+    let mut db = ChainDb::<PoW, Withdrawals>::open(&dir).unwrap();
+    let mut compactions = 0;
+    for amount in [10, 20, 30, 5, 1] {
+        let parent = tree.block(tree.best_head()).unwrap();
+        let context = BlockContext {
+            height: parent.header.height + 1,
+            ..BlockContext::default()
+        };
+        let block = parent
+            .child(tree.best_state(), vec![amount], context)
+            .unwrap();
+        tree.import(block).unwrap();
+        db.store_tree(&tree).unwrap();
+        compactions += db.compact(&tree).unwrap() as usize;
+    }
+    // Every compaction leaves the two blocks the tree keeps, so the next one is two blocks later.
+    assert_eq!(compactions, 2);
+    assert_eq!((db.pruned_len(), db.len()), (4, 2));
+
+    // A compaction the node stopped part way through is finished on opening.
+    std::fs::rename(dir.join("pruned.json"), dir.join("pruned.json.new")).unwrap();
+    File::create(dir.join("COMPACTING")).unwrap();
+    let db = ChainDb::<PoW, Withdrawals>::open(&dir).unwrap();
+    assert!(!dir.join("COMPACTING").exists());
+    let mut resumed = BlockTree::new(PoW::new(u64::MAX / 4), LongestChain, 100);
+    assert_eq!(db.load_into(&mut resumed).unwrap(), 1);
+    assert_eq!(resumed.best_chain(), tree.best_chain());
+    assert_eq!(resumed.best_state(), &34);
+    assert!(resumed.is_pruned(tree.best_chain()[2]));
+
+    let mut elsewhere = BlockTree::new(PoW::new(u64::MAX / 4), LongestChain, 99);
+    assert!(matches!(
+        db.load_into(&mut elsewhere),
+        Err(DbError::Rejected {
+            error: ImportError::UnknownParent,
+            ..
+        })
+    ));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn cl_db_compacts_a_warp_synced_tree_straight_away() {
+    let dir = temp_db_dir("warped");
+    let mut archive = BlockTree::new(PoW::new(u64::MAX / 4), LongestChain, 100);
+    for amount in [10, 20, 30] {
+        let parent = archive.block(archive.best_head()).unwrap();
+        let context = BlockContext {
+            height: parent.header.height + 1,
+            ..BlockContext::default()
+        };
+        let block = parent
+            .child(archive.best_state(), vec![amount], context)
+            .unwrap();
+        archive.import(block).unwrap();
+    }
+    // A tree that starts from the block at height 2, like one that warp synced to it.
+    let chain = archive.best_chain();
+    let mut warped = BlockTree::new(PoW::new(u64::MAX / 4), LongestChain, 100);
+    let headers = chain[..2]
+        .iter()
+        .map(|h| archive.header(*h).unwrap().clone())
+        .collect();
+    let root = archive.block(chain[2]).unwrap().clone();
+    warped
+        .resume_pruned(headers, root, archive.state_at(chain[2]).unwrap())
+        .unwrap();
+    warped
+        .import(archive.block(chain[3]).unwrap().clone())
+        .unwrap();
+
+    let mut db = ChainDb::<PoW, Withdrawals>::open(&dir).unwrap();
+    db.store_tree(&warped).unwrap();
+    assert!(db.compact(&warped).unwrap());
+    let mut resumed = BlockTree::new(PoW::new(u64::MAX / 4), LongestChain, 100);
+    assert_eq!(db.load_into(&mut resumed).unwrap(), 1);
+    assert_eq!(resumed.best_chain(), chain);
+    assert_eq!(resumed.best_state(), &40);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn cl_db_drops_an_unfinished_write() {
+    let dir = temp_db_dir("torn");
+    let tree = forked_tree();
+    let blocks = tree.blocks_in_order();
+    {
+        let mut db = ChainDb::<PoW, Withdrawals>::open(&dir).unwrap();
+        db.put(blocks[0]).unwrap();
+        db.put(blocks[1]).unwrap();
+    }
+    let mut headers = OpenOptions::new()
+        .append(true)
+        .open(dir.join("headers.jsonl"))
+        .unwrap();
+    headers.write_all(b"{\"header\":{\"par").unwrap();
+
+    let mut db = ChainDb::<PoW, Withdrawals>::open(&dir).unwrap();
+    assert_eq!(db.len(), 2);
+    db.put(blocks[2]).unwrap();
+    drop(db);
+    let db = ChainDb::<PoW, Withdrawals>::open(&dir).unwrap();
+    assert_eq!(db.headers(), &[0, 1, 2].map(|i| blocks[i].header.clone()));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn cl_db_migrates_older_formats() {
+    let dir = temp_db_dir("migrate");
+    std::fs::create_dir_all(&dir).unwrap();
+    // A made up version 0 kept the whole chain in a single file.
+    std::fs::write(dir.join("VERSION"), "0\n").unwrap();
+    std::fs::write(dir.join("chain.jsonl"), "").unwrap();
+    assert!(matches!(
+        ChainDb::<PoW, Withdrawals>::open(&dir),
+        Err(DbError::MissingMigration { from: 0 })
+    ));
+
+    fn drop_chain_file(dir: &Path) -> Result<(), DbError> {
+        std::fs::remove_file(dir.join("chain.jsonl"))?;
+        Ok(())
+    }
+    let db =
+        ChainDb::<PoW, Withdrawals>::open_with_migrations(&dir, &[(0, drop_chain_file)]).unwrap();
+    assert!(db.is_empty());
+    assert!(!dir.join("chain.jsonl").exists());
+    assert_eq!(std::fs::read_to_string(dir.join("VERSION")).unwrap(), "1\n");
+
+    std::fs::write(dir.join("VERSION"), "2\n").unwrap();
+    assert!(matches!(
+        ChainDb::<PoW, Withdrawals>::open(&dir),
+        Err(DbError::UnsupportedVersion { found: 2 })
+    ));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn cl_db_check_finds_corruption_and_repair_truncates_it() {
+    let dir = temp_db_dir("check");
+    let tree = forked_tree();
+    let blocks = tree.blocks_in_order();
+    ChainDb::<PoW, Withdrawals>::open(&dir)
+        .unwrap()
+        .store_tree(&tree)
+        .unwrap();
+    let fresh = || BlockTree::new(PoW::new(u64::MAX / 4), LongestChain, 100);
+    let report = ChainDb::<PoW, Withdrawals>::check(&dir, &mut fresh()).unwrap();
+    assert!(report.is_consistent());
+    assert_eq!(report.consistent, 6);
+
+    // The body of the block withdrawing 1 now withdraws 2, and a line of garbage follows.
+    let bodies = std::fs::read_to_string(dir.join("bodies.jsonl")).unwrap();
+    assert_eq!(bodies.matches("\"body\":[1]}").count(), 1);
+    std::fs::write(
+        dir.join("bodies.jsonl"),
+        bodies.replace("\"body\":[1]}", "\"body\":[2]}"),
+    )
+    .unwrap();
+    let mut headers = OpenOptions::new()
+        .append(true)
+        .open(dir.join("headers.jsonl"))
+        .unwrap();
+    headers.write_all(b"not a header\n").unwrap();
+    assert!(matches!(
+        ChainDb::<PoW, Withdrawals>::open(&dir),
+        Err(DbError::Codec(_))
+    ));
+
+    let report = ChainDb::<PoW, Withdrawals>::repair(&dir, &mut fresh()).unwrap();
+    assert_eq!(report.consistent, 4);
+    let errors: Vec<_> = report
+        .corrupt
+        .iter()
+        .map(|c| (c.position, c.error.clone()))
+        .collect();
+    assert!(matches!(
+        &errors[..],
+        [
+            (4, Corruption::Rejected { block_hash: b2, error: ImportError::Invalid(_) }),
+            (5, Corruption::Rejected { error: ImportError::UnknownParent, .. }),
+            (6, Corruption::UnreadableHeader),
+        ] if *b2 == hash(&blocks[4].header)
+    ));
+
+    let db = ChainDb::<PoW, Withdrawals>::open(&dir).unwrap();
+    assert_eq!(db.len(), 4);
+    let mut resumed = fresh();
+    assert_eq!(db.load_into(&mut resumed).unwrap(), 3);
+    assert_eq!(resumed.best_state(), &70);
+    let report = ChainDb::<PoW, Withdrawals>::check(&dir, &mut fresh()).unwrap();
+    assert_eq!((report.is_consistent(), report.consistent), (true, 4));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,175 @@
+//! The consensus engines with slots catch equivocation from headers alone, since their digests
+//! say who sealed each block. Blocks sealed by proof of work say nothing of the sort, but their
+//! context still names the author who is paid for them, and an author has no business authoring
+//! two different blocks at the same height. Each may be valid, and they may well be on different
+//! forks, so no single import notices.
+//!
+//! `EquivocationMonitor` watches what a block tree actually imported, on every fork, and
+//! remembers every block each author authored at each height. As soon as it sees a second, it
+//! produces an `AuthorEquivocation` holding both headers. Its `Offence` turns into a `Slash` for
+//! the staking state machine, like the offences of the slot-based engines, and the node logs it
+//! for its operator.
+
+use std::collections::HashMap;
+
+use super::block_tree::BlockTree;
+use super::Hash;
+use crate::c1_state_machine::p8_staking::StakingTransaction;
+use crate::c1_state_machine::{StateMachine, User};
+use crate::c3_consensus::equivocation::Offence;
+use crate::c3_consensus::{Consensus, Header};
+use crate::hash;
+
+/// Two different blocks of a block tree that the same author authored at the same height
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AuthorEquivocation<Digest> {
+    pub author: User,
+    pub first: Header<Digest>,
+    pub second: Header<Digest>,
+}
+
+impl<Digest> AuthorEquivocation<Digest> {
+    /// The offence, with the height standing in for the slot.
+    pub fn offence(&self) -> Offence {
+        Offence {
+            offender: self.author.into(),
+            slot: self.first.height,
+        }
+    }
+
+    /// The staking transaction that punishes the offence.
+    pub fn slashing_transaction(&self) -> StakingTransaction {
+        self.offence().slash()
+    }
+}
+
+/// Remembers the blocks each author authored at each height, across every fork of a block tree
+#[derive(Clone, Debug)]
+pub struct EquivocationMonitor<Digest> {
+    /// The headers of the blocks each author authored at each height, in the order they were
+    /// seen
+    seen: HashMap<(User, u64), Vec<Header<Digest>>>,
+}
+
+impl<Digest> Default for EquivocationMonitor<Digest> {
+    fn default() -> Self {
+        EquivocationMonitor {
+            seen: HashMap::new(),
+        }
+    }
+}
+
+impl<Digest: Clone + core::hash::Hash> EquivocationMonitor<Digest> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the given block of the tree, returning proof of equivocation if its author already
+    /// authored a different block at its height. Blocks without an author, blocks the tree does
+    /// not have the body of, and blocks already recorded are ignored.
+    pub fn observe<C, SM, FC>(
+        &mut self,
+        tree: &BlockTree<C, SM, FC>,
+        block_hash: Hash,
+    ) -> Option<AuthorEquivocation<Digest>>
+    where
+        C: Consensus<Digest = Digest>,
+        SM: StateMachine,
+    {
+        let block = tree.block(block_hash)?;
+        let author = block.context.author?;
+        let headers = self.seen.entry((author, block.header.height)).or_default();
+        if headers.iter().any(|h| hash(h) == block_hash) {
+            return None;
+        }
+        headers.push(block.header.clone());
+        (headers.len() > 1).then(|| AuthorEquivocation {
+            author,
+            first: headers[0].clone(),
+            second: block.header.clone(),
+        })
+    }
+
+    /// Record every block of the tree, on every fork, returning proof of every equivocation
+    /// among blocks not recorded before. Parents come before their children.
+    pub fn scan<C, SM, FC>(
+        &mut self,
+        tree: &BlockTree<C, SM, FC>,
+    ) -> Vec<AuthorEquivocation<Digest>>
+    where
+        C: Consensus<Digest = Digest>,
+        SM: StateMachine,
+    {
+        tree.blocks_in_order()
+            .into_iter()
+            .filter_map(|block| self.observe(tree, hash(&block.header)))
+            .collect()
+    }
+
+    /// Forget the blocks below the given height, once offences there can no longer be punished,
+    /// or once finality means no fork can reach them any more.
+    pub fn forget_below(&mut self, height: u64) {
+        self.seen.retain(|(_, h), _| *h >= height);
+    }
+}
+
+#[cfg(test)]
+use super::p3_fork_choice::LongestChain;
+#[cfg(test)]
+use super::Withdrawals;
+#[cfg(test)]
+use crate::c1_state_machine::BlockContext;
+#[cfg(test)]
+use crate::c3_consensus::p1_pow::PoW;
+
+#[test]
+fn cl_equivocation_monitor_catches_authors_on_both_sides_of_a_fork() {
+    let mut tree =
+        BlockTree::<PoW, Withdrawals, LongestChain>::new(PoW::new(u64::MAX / 4), LongestChain, 100);
+    let genesis = tree.block(tree.best_head()).unwrap().clone();
+    let context = |height, author| BlockContext {
+        height,
+        author: Some(author),
+        ..BlockContext::default()
+    };
+    let a1 = genesis
+        .child(&100, vec![10], context(1, User::Alice))
+        .unwrap();
+    let b1 = genesis
+        .child(&100, vec![20], context(1, User::Bob))
+        .unwrap();
+    let a1_again = genesis
+        .child(&100, vec![30], context(1, User::Alice))
+        .unwrap();
+    let mut monitor = EquivocationMonitor::new();
+    for block in [a1.clone(), b1, a1_again.clone()] {
+        tree.import(block).unwrap();
+    }
+
+    assert_eq!(monitor.observe(&tree, hash(&a1.header)), None);
+    assert_eq!(monitor.observe(&tree, hash(&a1.header)), None);
+    assert_eq!(monitor.scan(&tree).len(), 1);
+    // Scanning again finds nothing new.
+    assert_eq!(monitor.scan(&tree), vec![]);
+
+    let proof = EquivocationMonitor::new().scan(&tree).remove(0);
+    assert_eq!(
+        proof,
+        AuthorEquivocation {
+            author: User::Alice,
+            first: a1.header,
+            second: a1_again.header,
+        }
+    );
+    assert_eq!(
+        proof.slashing_transaction(),
+        StakingTransaction::Slash {
+            offender: User::Alice,
+            slot: 1
+        }
+    );
+
+    monitor.forget_below(2);
+    assert!(monitor.seen.is_empty());
+}
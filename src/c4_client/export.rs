@@ -0,0 +1,336 @@
+//! A chain built in one place is often wanted in another: to check an exercise's chain on a
+//! grader's machine, or to carry on extending it somewhere else. The database is tied to the node
+//! that wrote it, so chains travel as export files instead.
+//!
+//! An export is a JSON-lines file. The first line says what the file is, the version of its
+//! format, and how many blocks follow, so that a file cut short is noticed. Then comes one line
+//! per block of the best chain, genesis first, holding the block's header, context and body.
+//! Optionally, every few blocks the line also holds the state after the block.
+//!
+//! Importing a chain runs every block through the block tree, which checks them like any other
+//! block. The blocks are read and imported in batches, so that the tree can check the seals of a
+//! whole batch in parallel. The states in the file are compared with the states the tree computes, which catches a
+//! file that was produced by a different state machine, or by a different version of the same one.
+//!
+//! Exports are JSON, so they require the `serde` feature.
+
+use std::io::{BufRead, Write};
+
+use super::block_tree::{BlockTree, ImportError, IMPORT_BATCH_LEN};
+use super::p3_fork_choice::ForkChoice;
+use super::{Block, Hash, Header};
+use crate::c1_state_machine::{BlockContext, ContextualStateMachine, SerdeStateMachine};
+use crate::c3_consensus::Consensus;
+use crate::codec::Encode;
+use crate::hash;
+use num::traits::{One, Zero};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// The version of the export format written by this code
+pub const EXPORT_VERSION: u32 = 1;
+
+/// What the first line of every export says it is
+const FORMAT: &str = "diy-blockchain-chain";
+
+/// The ways exporting or importing a chain can fail
+#[derive(Debug)]
+pub enum ExportError {
+    /// The file could not be read or written
+    Io(std::io::Error),
+    /// A line could not be encoded or decoded
+    Codec(serde_json::Error),
+    /// The file does not start by saying it is an export
+    NotAnExport,
+    /// The file was written in a newer format than this code understands
+    UnsupportedVersion { found: u32 },
+    /// The file holds fewer blocks than its first line says
+    Truncated { expected: usize, found: usize },
+    /// The chain starts from another genesis block than the tree's
+    GenesisMismatch { ours: Hash, theirs: Hash },
+    /// The tree refused a block in the file
+    Rejected {
+        block_hash: Hash,
+        error: ImportError,
+    },
+    /// The state after the given block differs from the state the file holds for it
+    StateMismatch(Hash),
+    /// The tree keeps only the header of the given block of its best chain, so the chain cannot
+    /// be exported in full
+    Pruned(Hash),
+}
+
+impl From<std::io::Error> for ExportError {
+    fn from(e: std::io::Error) -> Self {
+        ExportError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for ExportError {
+    fn from(e: serde_json::Error) -> Self {
+        ExportError::Codec(e)
+    }
+}
+
+/// The first line of an export
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Preamble {
+    format: String,
+    version: u32,
+    /// How many block lines follow
+    blocks: usize,
+}
+
+/// A block line of an export
+#[derive(serde::Serialize, serde::Deserialize)]
+struct BlockRecord<Digest, Transition, State> {
+    header: Header<Digest>,
+    context: BlockContext,
+    body: Vec<Transition>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state: Option<State>,
+}
+
+/// Write the best chain of the given tree to `out`, genesis first. With a snapshot interval,
+/// the state after every block whose height is a multiple of it is written too. Returns how
+/// many blocks were written. A pruned tree cannot export its chain, because it no longer has the
+/// bodies of the older blocks.
+pub fn export_chain<C, SM, FC>(
+    tree: &BlockTree<C, SM, FC>,
+    mut out: impl Write,
+    snapshot_interval: Option<u64>,
+) -> Result<usize, ExportError>
+where
+    C: Consensus,
+    C::Digest: Zero + One + core::hash::Hash + Serialize,
+    SM: ContextualStateMachine + SerdeStateMachine,
+    SM::State: core::hash::Hash + Clone,
+    SM::Transition: core::hash::Hash + Encode + Clone,
+    FC: ForkChoice<C::Digest>,
+{
+    let chain = tree.best_chain();
+    if let Some(block_hash) = chain.iter().find(|h| tree.block(**h).is_none()) {
+        return Err(ExportError::Pruned(*block_hash));
+    }
+    let preamble = Preamble {
+        format: FORMAT.into(),
+        version: EXPORT_VERSION,
+        blocks: chain.len(),
+    };
+    serde_json::to_writer(&mut out, &preamble)?;
+    out.write_all(b"\n")?;
+    for block_hash in &chain {
+        let block = tree
+            .block(*block_hash)
+            .expect("the best chain was checked to be unpruned");
+        let state = snapshot_interval
+            .filter(|interval| block.header.height.is_multiple_of((*interval).max(1)))
+            .map(|_| tree.state_at(*block_hash).expect("every block has a state"));
+        let record = BlockRecord {
+            header: block.header.clone(),
+            context: block.context.clone(),
+            body: block.body.clone(),
+            state,
+        };
+        serde_json::to_writer(&mut out, &record)?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()?;
+    Ok(chain.len())
+}
+
+/// Import the chain in the given export into the tree. Blocks the tree already has are skipped,
+/// and the states in the file are checked against the tree's. Returns how many blocks were
+/// imported.
+pub fn import_chain<C, SM, FC>(
+    tree: &mut BlockTree<C, SM, FC>,
+    input: impl BufRead,
+) -> Result<usize, ExportError>
+where
+    C: Consensus + Sync,
+    C::Digest: Zero + One + core::hash::Hash + DeserializeOwned + Sync,
+    SM: ContextualStateMachine + SerdeStateMachine,
+    SM::State: core::hash::Hash + Clone + PartialEq,
+    SM::Transition: core::hash::Hash + Encode + Clone + Sync,
+    FC: ForkChoice<C::Digest>,
+{
+    let mut lines = input.lines();
+    let preamble: Preamble = match lines.next() {
+        Some(line) => serde_json::from_str(&line?).map_err(|_| ExportError::NotAnExport)?,
+        None => return Err(ExportError::NotAnExport),
+    };
+    if preamble.format != FORMAT {
+        return Err(ExportError::NotAnExport);
+    }
+    if preamble.version > EXPORT_VERSION {
+        return Err(ExportError::UnsupportedVersion {
+            found: preamble.version,
+        });
+    }
+
+    let genesis_hash = tree.best_chain()[0];
+    let (mut found, mut imported) = (0, 0);
+    let mut batch = Vec::with_capacity(IMPORT_BATCH_LEN);
+    for line in lines {
+        let record: BlockRecord<C::Digest, SM::Transition, SM::State> =
+            serde_json::from_str(&line?)?;
+        if found == 0 && hash(&record.header) != genesis_hash {
+            return Err(ExportError::GenesisMismatch {
+                ours: genesis_hash,
+                theirs: hash(&record.header),
+            });
+        }
+        found += 1;
+        batch.push(record);
+        if batch.len() == IMPORT_BATCH_LEN {
+            imported += import_records(tree, std::mem::take(&mut batch))?;
+        }
+    }
+    imported += import_records(tree, batch)?;
+    if found < preamble.blocks {
+        return Err(ExportError::Truncated {
+            expected: preamble.blocks,
+            found,
+        });
+    }
+    Ok(imported)
+}
+
+/// Import the blocks of the given records that the tree does not have yet, and check the states
+/// they hold. Returns how many blocks were imported.
+fn import_records<C, SM, FC>(
+    tree: &mut BlockTree<C, SM, FC>,
+    records: Vec<BlockRecord<C::Digest, SM::Transition, SM::State>>,
+) -> Result<usize, ExportError>
+where
+    C: Consensus + Sync,
+    C::Digest: Zero + One + core::hash::Hash + Sync,
+    SM: ContextualStateMachine,
+    SM::State: core::hash::Hash + Clone + PartialEq,
+    SM::Transition: core::hash::Hash + Encode + Clone + Sync,
+    FC: ForkChoice<C::Digest>,
+{
+    let mut states = Vec::new();
+    let mut blocks = Vec::new();
+    for record in records {
+        let block_hash = hash(&record.header);
+        if let Some(state) = record.state {
+            states.push((block_hash, state));
+        }
+        if !tree.contains(block_hash) {
+            blocks.push(Block {
+                header: record.header,
+                body: record.body,
+                context: record.context,
+                consensus: C::create_default_instance(),
+            });
+        }
+    }
+    let hashes: Vec<Hash> = blocks.iter().map(|b| hash(&b.header)).collect();
+    let imported = hashes.len();
+    for (block_hash, result) in hashes.into_iter().zip(tree.import_batch(blocks)) {
+        result.map_err(|error| ExportError::Rejected { block_hash, error })?;
+    }
+    for (block_hash, state) in states {
+        if tree.state_at(block_hash).as_ref() != Some(&state) {
+            return Err(ExportError::StateMismatch(block_hash));
+        }
+    }
+    Ok(imported)
+}
+
+#[cfg(test)]
+use super::block_tree::HistoryMode;
+#[cfg(test)]
+use super::p3_fork_choice::LongestChain;
+#[cfg(test)]
+use super::Withdrawals;
+#[cfg(test)]
+use crate::c3_consensus::p1_pow::PoW;
+
+/// A tree with the given starting balance and a chain withdrawing the given amounts, one block
+/// per amount.
+#[cfg(test)]
+fn withdrawing(balance: u64, amounts: &[u64]) -> BlockTree<PoW, Withdrawals, LongestChain> {
+    let mut tree = BlockTree::new(PoW::new(u64::MAX / 4), LongestChain, balance);
+    for amount in amounts {
+        let parent = tree.block(tree.best_head()).unwrap();
+        let context = BlockContext {
+            height: parent.header.height + 1,
+            ..BlockContext::default()
+        };
+        let block = parent
+            .child(tree.best_state(), vec![*amount], context)
+            .unwrap();
+        tree.import(block).unwrap();
+    }
+    tree
+}
+
+#[test]
+fn cl_export_round_trips_the_best_chain() {
+    let tree = withdrawing(100, &[10, 20, 30, 5]);
+    let mut file = Vec::new();
+    assert_eq!(export_chain(&tree, &mut file, Some(2)).unwrap(), 5);
+    let text = String::from_utf8(file.clone()).unwrap();
+    assert_eq!(text.lines().count(), 6);
+    // Genesis and the blocks at heights 2 and 4 carry their state.
+    assert_eq!(text.matches("\"state\"").count(), 3);
+
+    let mut copy = withdrawing(100, &[10]);
+    assert_eq!(import_chain(&mut copy, file.as_slice()).unwrap(), 3);
+    assert_eq!(copy.best_head(), tree.best_head());
+    assert_eq!(copy.best_state(), &35);
+    assert_eq!(import_chain(&mut copy, file.as_slice()).unwrap(), 0);
+}
+
+#[test]
+fn cl_export_refuses_files_that_do_not_match() {
+    let tree = withdrawing(100, &[10, 20]);
+    let mut file = Vec::new();
+    export_chain(&tree, &mut file, Some(1)).unwrap();
+    let text = String::from_utf8(file).unwrap();
+    let import = |text: &str| import_chain(&mut withdrawing(100, &[]), text.as_bytes());
+
+    assert!(matches!(
+        import_chain(&mut withdrawing(99, &[]), text.as_bytes()),
+        Err(ExportError::GenesisMismatch { .. })
+    ));
+    let cut: Vec<&str> = text.lines().take(2).collect();
+    assert!(matches!(
+        import(&cut.join("\n")),
+        Err(ExportError::Truncated {
+            expected: 3,
+            found: 1
+        })
+    ));
+    assert!(matches!(
+        import(&text.replace("\"state\":70", "\"state\":71")),
+        Err(ExportError::StateMismatch(_))
+    ));
+    assert!(matches!(
+        import(&text.replace("\"version\":1", "\"version\":2")),
+        Err(ExportError::UnsupportedVersion { found: 2 })
+    ));
+    assert!(matches!(import(""), Err(ExportError::NotAnExport)));
+    assert!(matches!(
+        import("{\"format\":\"something else\",\"version\":1,\"blocks\":0}"),
+        Err(ExportError::NotAnExport)
+    ));
+}
+
+#[test]
+fn cl_export_refuses_pruned_chains() {
+    let archive = withdrawing(100, &[10, 20, 30]);
+    let mut pruned = BlockTree::new(PoW::new(u64::MAX / 4), LongestChain, 100)
+        .with_history(HistoryMode::Pruned(1));
+    for block_hash in &archive.best_chain()[1..] {
+        pruned
+            .import(archive.block(*block_hash).unwrap().clone())
+            .unwrap();
+    }
+    assert!(matches!(
+        export_chain(&pruned, Vec::new(), None),
+        Err(ExportError::Pruned(h)) if h == archive.best_chain()[0]
+    ));
+}
@@ -0,0 +1,193 @@
+//! Blocks authored by the node itself are built on its own best head, so they can be imported
+//! as soon as they are sealed. Blocks received from other nodes are another matter. They arrive
+//! in whatever order the network delivers them, sometimes more than once, and sometimes faster
+//! than the node can execute them.
+//!
+//! The import queue is where received blocks wait. It holds a bounded number of them, ignoring
+//! duplicates, and imports them into the block tree in batches. Within a batch, blocks are
+//! imported in order of height, so a parent received after its child is still imported first.
+//! Every block gets a result saying whether it was imported, and if not, at which stage of
+//! importing it was refused.
+
+use std::collections::HashSet;
+
+use super::block_tree::{BlockTree, HeadChange, ImportError};
+use super::p3_fork_choice::ForkChoice;
+use super::{Block, Hash};
+use crate::c1_state_machine::{ContextualStateMachine, StateMachine};
+use crate::c3_consensus::Consensus;
+use crate::codec::Encode;
+use crate::hash;
+use num::traits::{One, Zero};
+
+/// What became of a block the queue imported or refused
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockImportResult {
+    pub block_hash: Hash,
+    pub height: u64,
+    /// How the best head moved, or why the block was refused
+    pub result: Result<HeadChange, ImportError>,
+}
+
+/// Blocks received from other nodes, waiting to be imported
+pub struct ImportQueue<C: Consensus, SM: StateMachine> {
+    /// In the order they were received
+    queue: Vec<Block<C, SM>>,
+    /// The hashes of the queued blocks
+    queued: HashSet<Hash>,
+    /// The most blocks the queue holds
+    capacity: usize,
+}
+
+impl<C: Consensus, SM: StateMachine> ImportQueue<C, SM> {
+    /// An empty queue holding at most `capacity` blocks.
+    pub fn new(capacity: usize) -> Self {
+        ImportQueue {
+            queue: Vec::new(),
+            queued: HashSet::new(),
+            capacity,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Queue the given block for import. Returns false, dropping the block, if it is already
+    /// queued or the queue is full. The sender may try again once the queue has been drained.
+    pub(super) fn push(&mut self, block: Block<C, SM>) -> bool
+    where
+        C::Digest: core::hash::Hash,
+    {
+        if self.queue.len() >= self.capacity || !self.queued.insert(hash(&block.header)) {
+            return false;
+        }
+        self.queue.push(block);
+        true
+    }
+
+    /// Import every queued block into the given tree, in order of height, and empty the queue.
+    /// Blocks whose parent is neither in the tree nor imported earlier in the batch are refused,
+    /// as are the descendants of refused blocks. Returns a result for every block, in the order
+    /// they were imported.
+    pub fn import_all<FC>(&mut self, tree: &mut BlockTree<C, SM, FC>) -> Vec<BlockImportResult>
+    where
+        C::Digest: Zero + One + core::hash::Hash,
+        SM: ContextualStateMachine,
+        SM::State: core::hash::Hash + Clone,
+        SM::Transition: core::hash::Hash + Encode + Clone,
+        FC: ForkChoice<C::Digest>,
+    {
+        let mut batch = std::mem::take(&mut self.queue);
+        self.queued.clear();
+        // The sort is stable, so blocks of the same height keep the order they were received in.
+        batch.sort_by_key(|b| b.header.height);
+        batch
+            .into_iter()
+            .map(|block| BlockImportResult {
+                block_hash: hash(&block.header),
+                height: block.header.height,
+                result: tree.import(block),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+use super::block_tree::ImportStage;
+#[cfg(test)]
+use super::p3_fork_choice::LongestChain;
+#[cfg(test)]
+use super::Withdrawals;
+#[cfg(test)]
+use crate::c1_state_machine::BlockContext;
+#[cfg(test)]
+use crate::c3_consensus::p1_pow::PoW;
+
+/// A chain withdrawing the given amounts from a balance of 100, one block per amount, starting
+/// with genesis.
+#[cfg(test)]
+fn chain_of(amounts: &[u64]) -> Vec<Block<PoW, Withdrawals>> {
+    let mut chain = vec![Block::genesis(&100)];
+    let mut state = 100;
+    for amount in amounts {
+        let parent = chain.last().unwrap();
+        let context = BlockContext {
+            height: parent.header.height + 1,
+            ..BlockContext::default()
+        };
+        let block = parent.child(&state, vec![*amount], context).unwrap();
+        state -= amount;
+        chain.push(block);
+    }
+    chain
+}
+
+#[test]
+fn cl_import_queue_imports_out_of_order_blocks() {
+    let chain = chain_of(&[10, 20, 30]);
+    let mut tree = BlockTree::new(PoW::new(u64::MAX / 4), LongestChain, 100);
+    let mut queue = ImportQueue::new(10);
+    for block in [&chain[3], &chain[1], &chain[2]] {
+        assert!(queue.push(block.clone()));
+    }
+    assert!(!queue.push(chain[1].clone()));
+
+    let results = queue.import_all(&mut tree);
+    assert!(queue.is_empty());
+    let heights: Vec<_> = results.iter().map(|r| r.height).collect();
+    assert_eq!(heights, vec![1, 2, 3]);
+    assert!(results.iter().all(|r| r.result.is_ok()));
+    assert_eq!(tree.best_head(), hash(&chain[3].header));
+    assert_eq!(tree.best_state(), &40);
+}
+
+#[test]
+fn cl_import_queue_reports_the_stage_each_block_failed_at() {
+    let chain = chain_of(&[10, 20]);
+    let mut tree = BlockTree::new(PoW::new(u64::MAX / 4), LongestChain, 100);
+    let mut queue = ImportQueue::new(10);
+
+    // The first block's body is swapped for another, so the second block has no parent either.
+    let mut tampered = chain[1].clone();
+    tampered.body = vec![5];
+    queue.push(tampered);
+    queue.push(chain[2].clone());
+    let stages: Vec<_> = queue
+        .import_all(&mut tree)
+        .into_iter()
+        .map(|r| r.result.unwrap_err().stage())
+        .collect();
+    assert_eq!(stages, vec![ImportStage::Body, ImportStage::Header]);
+
+    queue.push(chain[1].clone());
+    queue.push(chain[1].clone());
+    let results = queue.import_all(&mut tree);
+    assert_eq!(results.len(), 1);
+    assert_eq!(
+        results[0].result.as_ref().unwrap().enacted,
+        vec![hash(&chain[1].header)]
+    );
+
+    // Blocks already imported are refused at the first stage.
+    queue.push(chain[1].clone());
+    assert_eq!(
+        queue.import_all(&mut tree)[0].result,
+        Err(ImportError::AlreadyKnown)
+    );
+}
+
+#[test]
+fn cl_import_queue_is_bounded() {
+    let chain = chain_of(&[1, 2, 3]);
+    let mut queue = ImportQueue::<PoW, Withdrawals>::new(2);
+
+    assert!(queue.push(chain[1].clone()));
+    assert!(queue.push(chain[2].clone()));
+    assert!(!queue.push(chain[3].clone()));
+    assert_eq!(queue.len(), 2);
+}
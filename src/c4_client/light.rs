@@ -0,0 +1,363 @@
+//! A full node downloads and executes every block, which takes more bandwidth, storage and time
+//! than a phone or a browser can spare. A light client downloads only the headers. It checks each
+//! one with the consensus engine and follows the best chain just like a full node, but it never
+//! sees a body or executes a transition, so it trusts that the blocks the consensus engine
+//! accepted also execute correctly.
+//!
+//! That is still useful. Headers are small, and each one commits to its block's state and body,
+//! so once the light client knows which headers are canonical, it can check proofs about the
+//! state and the transactions against them. This module covers the first half: following the
+//! chain of headers, and answering whether a header is in it.
+
+use super::block_tree::ImportError;
+use super::chain_spec::ChainSpec;
+use super::p3_fork_choice::{ForkChoice, HeaderTree};
+use super::{Block, BlockVerificationError, Hash, Header};
+use crate::c1_state_machine::{ContextualStateMachine, StateProof};
+use crate::c3_consensus::spec::{FromSpec, SpecError};
+use crate::c3_consensus::Consensus;
+use crate::codec::Encode;
+use crate::hash;
+use num::traits::{One, Zero};
+
+/// A client that follows the best chain by its headers alone
+pub struct LightClient<C: Consensus, FC> {
+    consensus: C,
+    fork_choice: FC,
+    tree: HeaderTree<C::Digest>,
+    /// The hashes of the best chain's headers, by height
+    canonical: Vec<Hash>,
+}
+
+impl<C, FC> LightClient<C, FC>
+where
+    C: Consensus,
+    C::Digest: core::hash::Hash,
+    FC: ForkChoice<C::Digest>,
+{
+    /// A client that knows only the given genesis header.
+    pub fn new(consensus: C, fork_choice: FC, genesis: Header<C::Digest>) -> Self {
+        let root = hash(&genesis);
+        LightClient {
+            consensus,
+            fork_choice,
+            tree: HeaderTree::new(genesis),
+            canonical: vec![root],
+        }
+    }
+
+    /// A client that knows only the genesis header of the chain the given spec describes.
+    pub fn from_spec<SM>(
+        spec: &ChainSpec<SM::GenesisConfig>,
+        fork_choice: FC,
+    ) -> Result<Self, SpecError>
+    where
+        C: FromSpec,
+        C::Digest: Zero + One,
+        SM: ContextualStateMachine,
+        SM::State: core::hash::Hash + Clone,
+        SM::Transition: core::hash::Hash + Encode,
+        SM::GenesisConfig: Clone,
+    {
+        let (genesis, _) = Block::<C, SM>::genesis_from_spec(spec)?;
+        Ok(Self::new(genesis.consensus, fork_choice, genesis.header))
+    }
+
+    /// The hash of the best header according to the fork choice rule.
+    pub fn best_hash(&self) -> Hash {
+        *self
+            .canonical
+            .last()
+            .expect("the canonical chain starts at genesis")
+    }
+
+    pub fn best_header(&self) -> &Header<C::Digest> {
+        self.header(self.best_hash())
+            .expect("the best header is in the tree")
+    }
+
+    pub fn best_height(&self) -> u64 {
+        self.best_header().height
+    }
+
+    /// The header with the given hash, if the client has imported it.
+    pub fn header(&self, block_hash: Hash) -> Option<&Header<C::Digest>> {
+        self.tree.get(block_hash)
+    }
+
+    /// The hash of the best chain's header at the given height, if it is that long.
+    pub fn canonical_hash(&self, height: u64) -> Option<Hash> {
+        let root_height = self.tree.get(self.tree.root())?.height;
+        let index = usize::try_from(height.checked_sub(root_height)?).ok()?;
+        self.canonical.get(index).copied()
+    }
+
+    /// Whether the header with the given hash is part of the best chain.
+    pub fn is_canonical(&self, block_hash: Hash) -> bool {
+        self.header(block_hash)
+            .is_some_and(|header| self.canonical_hash(header.height) == Some(block_hash))
+    }
+
+    /// Whether the given proof shows its key having its value in the state after the given
+    /// block. False if the block's header has not been imported. This only makes sense for
+    /// chains whose headers commit to the Merkle root of the state, as checked by
+    /// `Block::verify_sub_chain_merkle`.
+    pub fn verify_state_proof<K, V>(&self, block_hash: Hash, proof: &StateProof<K, V>) -> bool
+    where
+        K: core::hash::Hash,
+        V: core::hash::Hash,
+    {
+        self.header(block_hash)
+            .is_some_and(|header| proof.verify(header.state_root))
+    }
+
+    /// Check the given header against its parent and add it to the tree, moving the best header
+    /// if the fork choice rule prefers its chain.
+    pub fn import_header(&mut self, header: Header<C::Digest>) -> Result<(), ImportError> {
+        let header_hash = hash(&header);
+        if self.tree.contains(header_hash) {
+            return Err(ImportError::AlreadyKnown);
+        }
+        let parent = self
+            .tree
+            .get(header.parent)
+            .ok_or(ImportError::UnknownParent)?;
+        if parent.height + 1 != header.height {
+            return Err(BlockVerificationError::NotAChild.into());
+        }
+        let window = [parent.clone(), header];
+        if !self
+            .consensus
+            .verify_sub_chain(&window[0].consensus_digest, &window)
+        {
+            return Err(BlockVerificationError::InvalidSeal.into());
+        }
+        let [_, header] = window;
+        self.tree.insert(header);
+        self.follow_best();
+        Ok(())
+    }
+
+    /// Import headers one at a time as they arrive, for example while they are being
+    /// downloaded, stopping at the first one refused. Headers already known are skipped, so
+    /// overlapping batches may be streamed in. Returns how many new headers were imported;
+    /// those before a refused header stay imported.
+    pub fn import_headers(
+        &mut self,
+        headers: impl IntoIterator<Item = Header<C::Digest>>,
+    ) -> Result<usize, ImportError> {
+        let mut imported = 0;
+        for header in headers {
+            match self.import_header(header) {
+                Ok(()) => imported += 1,
+                Err(ImportError::AlreadyKnown) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(imported)
+    }
+
+    /// Bring the canonical chain in line with the fork choice rule's best head. Only the
+    /// headers since the fork from the previous best chain are looked at.
+    fn follow_best(&mut self) {
+        let best = self.fork_choice.best_head(&self.tree);
+        if best == self.best_hash() {
+            return;
+        }
+        let root_height = self.tree.get(self.tree.root()).map_or(0, |h| h.height);
+        let mut route = Vec::new();
+        let mut current = best;
+        while !self.is_canonical(current) {
+            let header = self.tree.get(current).expect("ancestors are in the tree");
+            route.push(current);
+            current = header.parent;
+        }
+        let fork_point = self
+            .header(current)
+            .expect("ancestors are in the tree")
+            .height;
+        self.canonical
+            .truncate((fork_point - root_height) as usize + 1);
+        self.canonical.extend(route.into_iter().rev());
+    }
+}
+
+#[cfg(test)]
+use super::p3_fork_choice::LongestChain;
+#[cfg(test)]
+use super::Withdrawals;
+#[cfg(test)]
+use crate::c1_state_machine::p4_accounted_currency::AccountedCurrency;
+#[cfg(test)]
+use crate::c1_state_machine::{BlockContext, MerkleState, User};
+#[cfg(test)]
+use crate::c3_consensus::p1_pow::PoW;
+#[cfg(test)]
+use crate::codec::Decode;
+#[cfg(test)]
+use std::collections::HashMap;
+
+/// The headers of a chain withdrawing the given amounts from a balance of 100, built on the
+/// given block, which has the given state.
+#[cfg(test)]
+fn headers_after(
+    parent: &Block<PoW, Withdrawals>,
+    state: u64,
+    amounts: &[u64],
+) -> Vec<Header<u64>> {
+    let mut parent = parent.clone();
+    let mut state = state;
+    let mut headers = Vec::new();
+    for amount in amounts {
+        let context = BlockContext {
+            height: parent.header.height + 1,
+            ..BlockContext::default()
+        };
+        parent = parent.child(&state, vec![*amount], context).unwrap();
+        state -= amount;
+        headers.push(parent.header.clone());
+    }
+    headers
+}
+
+#[cfg(test)]
+fn light_client() -> (LightClient<PoW, LongestChain>, Block<PoW, Withdrawals>) {
+    let genesis = Block::<PoW, Withdrawals>::genesis(&100);
+    let client = LightClient::new(
+        genesis.consensus.clone(),
+        LongestChain,
+        genesis.header.clone(),
+    );
+    (client, genesis)
+}
+
+/// The given header with a nonce that does not meet the default PoW target.
+#[cfg(test)]
+fn unsealed(header: &Header<u64>) -> Header<u64> {
+    let engine = <PoW>::create_default_instance();
+    (0..)
+        .map(|nonce| Header {
+            consensus_digest: nonce,
+            ..header.clone()
+        })
+        .find(|h| !engine.validate(&0, h))
+        .unwrap()
+}
+
+#[test]
+fn cl_light_client_follows_the_best_chain() {
+    let (mut client, genesis) = light_client();
+    let short = headers_after(&genesis, 100, &[10, 20]);
+    let long = headers_after(&genesis, 100, &[5, 5, 5]);
+
+    assert_eq!(client.import_headers(short.clone()), Ok(2));
+    assert_eq!(client.best_hash(), hash(&short[1]));
+    assert!(client.is_canonical(hash(&short[0])));
+
+    // Overlapping batches are fine, and the longer fork takes over once it is longer.
+    assert_eq!(client.import_headers(long[..2].to_vec()), Ok(2));
+    assert_eq!(client.best_hash(), hash(&short[1]));
+    assert_eq!(client.import_headers(long.clone()), Ok(1));
+    assert_eq!(client.best_height(), 3);
+    assert!(client.is_canonical(hash(&genesis.header)));
+    assert!(client.is_canonical(hash(&long[0])));
+    assert!(!client.is_canonical(hash(&short[0])));
+    assert!(!client.is_canonical(hash(&short[1])));
+    assert_eq!(client.canonical_hash(2), Some(hash(&long[1])));
+    assert_eq!(client.canonical_hash(4), None);
+}
+
+#[test]
+fn cl_light_client_rejects_bad_headers() {
+    let (mut client, genesis) = light_client();
+    let headers = headers_after(&genesis, 100, &[10, 20, 30]);
+
+    assert_eq!(
+        client.import_header(headers[1].clone()),
+        Err(ImportError::UnknownParent)
+    );
+
+    let mut skipping = headers[0].clone();
+    skipping.height = 2;
+    assert_eq!(
+        client.import_headers([unsealed(&headers[0])]),
+        Err(ImportError::Invalid(BlockVerificationError::InvalidSeal))
+    );
+    assert_eq!(
+        client.import_headers([skipping]),
+        Err(ImportError::Invalid(BlockVerificationError::NotAChild))
+    );
+
+    // Headers before the refused one stay imported.
+    let mut stream = headers.clone();
+    stream[2] = unsealed(&headers[2]);
+    assert!(client.import_headers(stream).is_err());
+    assert_eq!(client.best_hash(), hash(&headers[1]));
+}
+
+/// A chain of unsealed headers committing to the Merkle roots of the given states, genesis first.
+#[cfg(test)]
+fn merkle_headers(states: &[HashMap<User, u64>]) -> Vec<Header<()>> {
+    let mut headers: Vec<Header<()>> = Vec::new();
+    for (height, state) in states.iter().enumerate() {
+        headers.push(Header {
+            parent: headers.last().map_or(0, hash),
+            height: height as u64,
+            state_root: AccountedCurrency::state_root(state),
+            extrinsics_root: 0,
+            consensus_digest: (),
+        });
+    }
+    headers
+}
+
+#[test]
+fn cl_light_client_verifies_state_proofs() {
+    let before = HashMap::from([(User::Alice, 20), (User::Bob, 5), (User::Charlie, 1)]);
+    let after = HashMap::from([(User::Alice, 10), (User::Bob, 15), (User::Charlie, 1)]);
+    let headers = merkle_headers(&[before, after.clone()]);
+    let mut client = LightClient::new((), LongestChain, headers[0].clone());
+    client.import_header(headers[1].clone()).unwrap();
+    let (genesis, block) = (hash(&headers[0]), hash(&headers[1]));
+
+    // The full node proves Bob's balance, and the light client checks it against the header.
+    let proof = AccountedCurrency::prove(&after, &User::Bob).unwrap();
+    assert_eq!(proof.value, 15);
+    assert!(client.verify_state_proof(block, &proof));
+    assert!(!client.verify_state_proof(genesis, &proof));
+    assert!(!client.verify_state_proof(0, &proof));
+    assert!(AccountedCurrency::prove(&after, &User::Dave).is_none());
+
+    let mut tampered = proof.clone();
+    tampered.value = 50;
+    assert!(!client.verify_state_proof(block, &tampered));
+    let mut tampered = proof.clone();
+    tampered.key = User::Alice;
+    assert!(!client.verify_state_proof(block, &tampered));
+    let mut tampered = proof.clone();
+    tampered.proof.siblings[0] ^= 1;
+    assert!(!client.verify_state_proof(block, &tampered));
+    let mut tampered = proof.clone();
+    tampered.proof.siblings.push(0);
+    assert!(!client.verify_state_proof(block, &tampered));
+}
+
+#[test]
+fn cl_state_proofs_survive_serialization() {
+    let state = HashMap::from([(User::Alice, 10), (User::Bob, 15), (User::Eve, 3)]);
+    let root = AccountedCurrency::state_root(&state);
+    let proof = AccountedCurrency::prove(&state, &User::Eve).unwrap();
+
+    let bytes = proof.encode();
+    let decoded = StateProof::<User, u64>::decode(&bytes).unwrap();
+    assert_eq!(decoded, proof);
+    assert!(decoded.verify(root));
+    assert_eq!(StateProof::<User, u64>::decode(&bytes[1..]), None);
+
+    #[cfg(feature = "serde")]
+    {
+        let json = serde_json::to_string(&proof).unwrap();
+        let decoded: StateProof<User, u64> = serde_json::from_str(&json).unwrap();
+        assert!(decoded.verify(root));
+    }
+}
@@ -10,10 +10,59 @@
 ///
 /// Let's refactor our blockchain to take advantage of these two abstractions
 /// In doing so, we create a blockchain framework
-use crate::c1_state_machine::StateMachine;
-use crate::c3_consensus::{Consensus, Header};
+use crate::c1_state_machine::{
+	BlockContext, ContextualStateMachine, DiffStateMachine, EventfulStateMachine,
+	InvertibleStateMachine, MerkleState, Rewarded, StateMachine, User, Weighted,
+};
+use crate::c1_state_machine::parallel::{try_apply_all_parallel, ParallelStateMachine};
+use crate::c1_state_machine::randomness::{block_seed, Randomness};
+use crate::c1_state_machine::p9_governance::GovernanceEvent;
+use crate::c3_consensus::{Configurable, Consensus, Header};
+use crate::c3_consensus::envelope::DigestMetadata;
+use crate::c3_consensus::finality::{BlockId, FinalityError, FinalityGadget};
+use crate::c3_consensus::p14_checkpoints::{CheckpointDigest, Checkpointed};
+use crate::c3_consensus::p1_pow::PowHasher;
+use crate::c3_consensus::p17_uncles::{UncleDigest, UnclePow};
+use crate::c3_consensus::spec::{FromSpec, SpecError};
+use chain_spec::ChainSpec;
+use p3_fork_choice::{uncles_are_valid, ForkChoice, HeaderTree};
+use crate::codec::Encode;
 use crate::hash;
+use crate::merkle::{MerkleProof, MerkleTree};
+use crate::snapshots::Snapshot;
 type Hash = u64;
+
+pub mod author;
+pub mod block_tree;
+pub mod chain_spec;
+#[cfg(feature = "serde")]
+pub mod db;
+pub mod equivocation_monitor;
+#[cfg(feature = "serde")]
+pub mod export;
+pub mod import_queue;
+pub mod light;
+pub mod network;
+#[cfg(feature = "serde")]
+pub mod node;
+pub mod p3_fork_choice;
+#[cfg(feature = "serde")]
+pub mod pool_store;
+#[cfg(feature = "serde")]
+pub mod rpc;
+pub mod runtime;
+pub mod simulator;
+#[cfg(feature = "serde")]
+pub mod state_db;
+#[cfg(feature = "serde")]
+pub mod subscriptions;
+pub mod sync;
+pub mod tx_pool;
+pub mod tx_status;
+
+/// The state machine the client runs unless told otherwise. Its state is interesting enough to
+/// exercise every part of the client, and it commits to its state with a Merkle root.
+pub type DefaultStateMachine = crate::c1_state_machine::p7_multiasset::MultiAsset;
 use  num::traits::{Zero,One};
 
 impl<Digest> Header<Digest>  
@@ -66,16 +115,103 @@ impl<Digest> Header<Digest>
 	}
 }
 
+/// The reasons building a child block may fail
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BlockBuildError {
+	/// The consensus engine would not seal the block, for example because its rules limit what a
+	/// body may contain
+	SealFailed,
+	/// The sealed header commits to the empty extrinsics root, 0, although the body is not empty
+	EmptyExtrinsicsRoot,
+	/// The state machine rejected the transition at the given position in the body
+	StateExecutionFailed { index: usize, error: String },
+	/// The state machine rejected the coinbase paying the block's author
+	CoinbaseFailed { error: String },
+	/// The consensus engine sealed the block as another author than the one its context names, or
+	/// named an author where the context names none, so its coinbase would pay the wrong account
+	AuthorMismatch,
+}
+
+/// The Merkle leaf committing to an encoded transition
+pub fn extrinsic_leaf(encoded: &[u8]) -> Hash {
+	hash(&encoded)
+}
+
+/// The Merkle tree over a block's encoded transitions, in order. Its root is the block's
+/// extrinsics root, which is 0 for an empty body.
+fn extrinsics_tree(encoded: &[Vec<u8>]) -> MerkleTree {
+	MerkleTree::from_leaves(encoded.iter().map(|e| extrinsic_leaf(e)).collect())
+}
+
+/// Execute a block's body on top of the given pre-state, in the given context, as a whole. This
+/// is how the state machine executes blocks, so it runs whatever the machine does at the start of
+/// a block, such as an upgrade's migration, even for an empty body. On failure, returns the
+/// position of the rejected transition along with why, found by executing the body again one
+/// transition at a time.
+fn execute_body<SM: ContextualStateMachine<State: Clone>>(
+	pre_state: &SM::State,
+	body: &[SM::Transition],
+	context: &BlockContext,
+) -> Result<SM::State, (usize, SM::Error)> {
+	SM::try_apply_all_in_context(pre_state, body, context).map_err(|error| {
+		let Ok(mut state) = SM::try_apply_all_in_context(pre_state, &[], context) else {
+			return (0, error);
+		};
+		for (index, t) in body.iter().enumerate() {
+			match SM::try_next_state_in_context(&state, t, context) {
+				Ok(next) => state = next,
+				Err(e) => return (index, e),
+			}
+		}
+		(body.len().saturating_sub(1), error)
+	})
+}
+
+/// The reasons a block may fail full verification
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BlockVerificationError {
+	/// The block's parent hash or height does not follow the block before it
+	NotAChild,
+	/// The header's extrinsics root is not the root of the block's body
+	ExtrinsicsRootMismatch { expected: Hash, found: Hash },
+	/// The consensus engine rejected the header
+	InvalidSeal,
+	/// The state machine rejected the transition at the given position in the body
+	StateExecutionFailed { index: usize, error: String },
+	/// The header's state root is not that of the state the body leads to
+	StateRootMismatch { expected: Hash, found: Hash },
+	/// The body does not start with the coinbase paying the block's author the block reward
+	/// plus the fees the rest of the body pays, or starts with a coinbase although the block pays
+	/// nobody
+	CoinbaseMismatch,
+	/// The transition at the given position is a coinbase, although only the first may be
+	MisplacedCoinbase { index: usize },
+	/// The context the body executes in names another height, parent or author than the header
+	ContextMismatch,
+}
+
+/// The first block of a chain to fail full verification, and why
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VerificationFailure {
+	/// The block's position in the chain
+	pub position: usize,
+	pub block_hash: Hash,
+	pub error: BlockVerificationError,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
-struct Block<C: Consensus, SM: StateMachine> {
+pub struct Block<C: Consensus, SM: StateMachine> {
 	header: Header<C::Digest>,
 	body: Vec<SM::Transition>,
+	/// The context the body was executed in. Importers must execute it in the same context.
+	context: BlockContext,
 	consensus : C,
 }
 
-impl<C: Consensus, SM: StateMachine> Block<C, SM>  
+impl<C: Consensus, SM: ContextualStateMachine> Block<C, SM>  
 	where 
 	SM::State :core::hash::Hash + Clone,
+	SM::Transition: core::hash::Hash + Encode,
 	<C as Consensus>::Digest: Zero+One+core::hash::Hash {
 
 	
@@ -85,102 +221,1594 @@ impl<C: Consensus, SM: StateMachine> Block<C, SM>
 		 Block::<C,SM>{
 			header: Header::<C::Digest>::genesis(hash(genesis_state)),
 			body : vec![],
+			context : BlockContext::default(),
 			consensus  : C::create_default_instance(),
 		 }
 	}
 
-	/// Create and return a valid child block.
-	pub fn child(&self, pre_state: &SM::State, extrinsics: Vec<u8>) -> Self {
+	/// Returns the genesis block and state described by the given chain spec. The block's
+	/// consensus engine is the one the spec describes, rather than the default instance.
+	pub fn genesis_from_spec(
+		spec: &ChainSpec<SM::GenesisConfig>,
+	) -> Result<(Self, SM::State), SpecError>
+	where
+		C: FromSpec,
+		SM::GenesisConfig: Clone,
+	{
+		let genesis_state = spec.genesis_state::<SM>();
+		let block = Block::<C,SM>{
+			consensus: spec.consensus()?,
+			..Block::genesis(&genesis_state)
+		};
+		Ok((block, genesis_state))
+	}
 
-		let mut s  = pre_state.clone();
-		for t in self.body.iter() {
-			s = SM::next_state(&s, &t).clone();
-		}
+	/// Create and return a valid child block that executes the given transitions on top of the
+	/// given pre-state, which is the state after executing this block. The transitions become the
+	/// block's body, and are shown to the consensus engine encoded while sealing. They are
+	/// executed in the given block context, whose parent hash and height are always set to this
+	/// block's hash and the next height.
+	pub fn child(
+		&self,
+		pre_state: &SM::State,
+		transitions: Vec<SM::Transition>,
+		context: BlockContext,
+	) -> Result<Self, BlockBuildError> {
+		self.child_sealed_by(&self.consensus, pre_state, transitions, context)
+	}
 
-		
+	/// Like `child`, but sealed by the given consensus engine rather than this block's.
+	fn child_sealed_by(
+		&self,
+		consensus: &C,
+		pre_state: &SM::State,
+		transitions: Vec<SM::Transition>,
+		mut context: BlockContext,
+	) -> Result<Self, BlockBuildError> {
+		context.parent_hash = hash(&self.header);
+		context.height = self.header.height + 1;
+
+		let s = execute_body::<SM>(pre_state, &transitions, &context)
+			.map_err(|(index, e)| BlockBuildError::StateExecutionFailed { index, error: format!("{e:?}") })?;
+
+		let encoded: Vec<Vec<u8>> = transitions.iter().map(Encode::encode).collect();
 		let h = Header::<()>{
 			parent : hash(&self.header),
 			state_root : hash(&s),
-			height : self.header.height,
-			extrinsics_root : hash(&extrinsics[0]),
+			height : self.header.height + 1,
+			extrinsics_root : extrinsics_tree(&encoded).root(),
 			consensus_digest : (),
 		};
 
-		let ch = self.consensus.seal(&C::Digest::one(), h);
-		match ch {
-			Some(ch) => {
-				Block::<C,SM>{
-					header: ch,
-					body : vec![], // TODO : how can I define the block transition !???? 
-					consensus:  C::create_default_instance()
-				 }
-			}
-			None => {
-				let hh = Header::<C::Digest>{
-					parent : hash(&self.header),
-					state_root : hash(&s),
-					height : self.header.height,
-					extrinsics_root : hash(&extrinsics[0]),
-					consensus_digest : C::Digest::one(), // we return a defult block with just one digest .. we should retunr None and change the return type of this function to Option .. 
-				};
-				Block::<C,SM>{
-					header: hh,
-					body : vec![], // TODO : how can I define the block transition !???? 
-					consensus:  C::create_default_instance()
-				 }
-			}
+		// Sealing is where proof of work spends its time, so it gets a span of its own.
+		#[cfg(feature = "tracing")]
+		let seal_span = tracing::debug_span!("seal", height = h.height).entered();
+		let header = consensus
+			.seal_with_body(&self.header.consensus_digest, h, &encoded)
+			.ok_or(BlockBuildError::SealFailed)?;
+		#[cfg(feature = "tracing")]
+		drop(seal_span);
+		if header.extrinsics_root == 0 && !transitions.is_empty() {
+			return Err(BlockBuildError::EmptyExtrinsicsRoot);
 		}
-		
+		let block = Block::<C,SM>{
+			header,
+			body : transitions,
+			context,
+			consensus:  C::create_default_instance()
+		};
+		if !block.context_matches_header() {
+			return Err(BlockBuildError::AuthorMismatch);
+		}
+		Ok(block)
+	}
+}
 
+impl<C: Consensus, SM: StateMachine> Block<C, SM>
+	where SM::Transition: Encode {
+
+	/// Prove that the transition at the given position in this block's body is committed to by
+	/// the header's extrinsics root. Returns None if there is no such transition.
+	pub fn extrinsic_proof(&self, index: usize) -> Option<MerkleProof> {
+		let encoded: Vec<Vec<u8>> = self.body.iter().map(Encode::encode).collect();
+		extrinsics_tree(&encoded).prove(index)
 	}
+}
 
-	/// Verify that all the given blocks form a valid chain from this block to the tip.
-	pub fn verify_sub_chain(&self, pre_state: &SM::State, chain: &[Self]) -> bool {
+impl<C: Consensus, SM: StateMachine> Block<C, SM> {
+	/// Whether the block's context names the height and parent its header does, and no other
+	/// author than its consensus digest does.
+	fn context_matches_header(&self) -> bool {
+		let digest_author = self.header.consensus_digest.author().map(User::from);
+		self.context.height == self.header.height
+			&& self.context.parent_hash == self.header.parent
+			&& (self.context.author.is_none() || digest_author.is_none() || self.context.author == digest_author)
+	}
+
+	/// Who authored the block: the author the consensus digest names, or failing that, the one
+	/// the context names. This is who the block's coinbase pays.
+	fn author(&self) -> Option<User> {
+		self.header.consensus_digest.author().map(User::from).or(self.context.author)
+	}
+}
+
+/// A block draws from the parent and height its header commits to. Its body sees the same seed
+/// through its context, as blocks whose context disagrees with their header are invalid.
+impl<C: Consensus, SM: StateMachine> Randomness for Block<C, SM> {
+	fn random_seed(&self) -> u64 {
+		block_seed(self.header.parent, self.header.height)
+	}
+}
+
+impl<C: Consensus, SM: ContextualStateMachine> Block<C, SM>
+	where SM::State: Clone {
+
+	/// Verify that all the given blocks form a valid chain from this block to the tip, using
+	/// the given function to calculate the state root each header should commit to.
+	fn verify_sub_chain_with(pre_state: &SM::State, chain: &[Self], state_root: impl Fn(&SM::State) -> u64) -> bool {
 		let mut s  = pre_state.clone();
 		let mut check = true;
 		
 		for i  in 1..chain.len() {
-			for t in chain[i-1].body.iter() {
-				s = SM::next_state(&s, &t).clone();
-			} 
-			check &= chain[i-1].header.state_root == hash(&s);
+			if !chain[i-1].context_matches_header() {
+				return false;
+			}
+			match SM::try_apply_all_in_context(&s, &chain[i-1].body, &chain[i-1].context) {
+				Ok(next) => s = next,
+				Err(_) => return false, // a block containing a rejected transition is invalid
+			}
+			check &= chain[i-1].header.state_root == state_root(&s);
 			check &= hash(&chain[i-1].header) == chain[i].header.parent;
 		}	
 		check
 	}
 }
 
+impl<C: Consensus, SM: MerkleState + ContextualStateMachine> Block<C, SM>
+	where SM::State: Clone {
+
+	/// Like `verify_sub_chain`, but for chains whose headers commit to the Merkle root of the
+	/// state rather than to a hash of the whole state.
+	pub fn verify_sub_chain_merkle(&self, pre_state: &SM::State, chain: &[Self]) -> bool {
+		Self::verify_sub_chain_with(pre_state, chain, SM::state_root)
+	}
+}
+
+impl<C: Consensus, SM: ContextualStateMachine> Block<C, SM>
+	where SM::State: core::hash::Hash + Clone {
+
+	/// Verify that all the given blocks form a valid chain from this block to the tip.
+	pub fn verify_sub_chain(&self, pre_state: &SM::State, chain: &[Self]) -> bool {
+		Self::verify_sub_chain_with(pre_state, chain, hash)
+	}
+
+	/// Capture the given post-state of this block as a snapshot that later verification can
+	/// start from.
+	pub fn snapshot(&self, post_state: SM::State) -> Snapshot<SM::State> {
+		Snapshot::capture(self.header.height, hash(&self.header), post_state)
+	}
+
+	/// Verify that all the given blocks form a valid chain built on top of the block the
+	/// snapshot was taken at, executing from the snapshot's state instead of from genesis.
+	pub fn verify_sub_chain_from_snapshot(snapshot: &Snapshot<SM::State>, chain: &[Self]) -> bool {
+		let Some(first) = chain.first() else {
+			return true;
+		};
+		snapshot.check().is_ok()
+			&& first.header.parent == snapshot.block_hash
+			&& first.header.height == snapshot.height + 1
+			&& first.verify_sub_chain(&snapshot.state, chain)
+	}
+}
+
+impl<C: Consensus, SM: ContextualStateMachine> Block<C, SM>
+	where
+	SM::State: core::hash::Hash + Clone,
+	SM::Transition: Encode {
+
+	/// Fully verify the given chain of blocks, built on top of a block with the given post-state
+	/// and digest. Every block must follow the one before it, be sealed according to this block's
+	/// consensus engine, commit to its body in its extrinsics root, and commit to the state its
+	/// body leads to. Returns the first block that does not, and why. Chains of state machines
+	/// with coinbases are verified with `verify_full_chain_with_reward`, which checks them too.
+	pub fn verify_full_chain(
+		&self,
+		pre_state: &SM::State,
+		parent_digest: &C::Digest,
+		chain: &[Self],
+	) -> Result<(), VerificationFailure> {
+		self.verify_full_chain_with(pre_state, parent_digest, chain, |_| Ok(()))
+	}
+
+	/// Like `verify_full_chain`, but every block must also pass the given check before its body
+	/// executes.
+	fn verify_full_chain_with(
+		&self,
+		pre_state: &SM::State,
+		parent_digest: &C::Digest,
+		chain: &[Self],
+		check: impl Fn(&Self) -> Result<(), BlockVerificationError>,
+	) -> Result<(), VerificationFailure> {
+		let mut state = pre_state.clone();
+		let mut parent_digest = parent_digest;
+		for (position, block) in chain.iter().enumerate() {
+			let fail = |error| VerificationFailure { position, block_hash: hash(&block.header), error };
+			let header = &block.header;
+
+			if let Some(parent) = position.checked_sub(1).map(|p| &chain[p].header) {
+				if header.parent != hash(parent) || header.height != parent.height + 1 {
+					return Err(fail(BlockVerificationError::NotAChild));
+				}
+			}
+			let encoded = block.check_extrinsics_root().map_err(fail)?;
+			block.check_seal(&self.consensus, parent_digest, &encoded).map_err(fail)?;
+			check(block).map_err(fail)?;
+			state = block.execute_checked(&state).map_err(fail)?;
+			parent_digest = &header.consensus_digest;
+		}
+		Ok(())
+	}
+
+	/// Check that the header's extrinsics root commits to the body, and return the encoded body.
+	fn check_extrinsics_root(&self) -> Result<Vec<Vec<u8>>, BlockVerificationError> {
+		let encoded: Vec<Vec<u8>> = self.body.iter().map(Encode::encode).collect();
+		let extrinsics_root = extrinsics_tree(&encoded).root();
+		if self.header.extrinsics_root != extrinsics_root {
+			return Err(BlockVerificationError::ExtrinsicsRootMismatch {
+				expected: extrinsics_root,
+				found: self.header.extrinsics_root,
+			});
+		}
+		Ok(encoded)
+	}
+
+	/// Check that the header is sealed according to the given consensus engine.
+	fn check_seal(
+		&self,
+		consensus: &C,
+		parent_digest: &C::Digest,
+		encoded_body: &[Vec<u8>],
+	) -> Result<(), BlockVerificationError> {
+		if !consensus.validate_with_body(parent_digest, &self.header, encoded_body) {
+			return Err(BlockVerificationError::InvalidSeal);
+		}
+		Ok(())
+	}
+
+	/// Execute the body on top of the given pre-state, in the block's context, and check that the
+	/// header commits to the resulting state. Returns that state. The context must name the same
+	/// height and parent as the header, or the body could execute as if it were somewhere else in
+	/// the chain.
+	#[cfg_attr(feature = "tracing", tracing::instrument(
+		name = "execute", level = "debug", skip_all,
+		fields(height = self.header.height, transitions = self.body.len()),
+	))]
+	fn execute_checked(&self, pre_state: &SM::State) -> Result<SM::State, BlockVerificationError> {
+		if !self.context_matches_header() {
+			return Err(BlockVerificationError::ContextMismatch);
+		}
+		let state = execute_body::<SM>(pre_state, &self.body, &self.context).map_err(|(index, e)| {
+			BlockVerificationError::StateExecutionFailed { index, error: format!("{e:?}") }
+		})?;
+		if self.header.state_root != hash(&state) {
+			return Err(BlockVerificationError::StateRootMismatch {
+				expected: hash(&state),
+				found: self.header.state_root,
+			});
+		}
+		Ok(state)
+	}
+}
+
+impl<C: Consensus, SM: Rewarded> Block<C, SM>
+	where
+	SM::State: core::hash::Hash + Clone,
+	SM::Transition: core::hash::Hash + Encode + PartialEq,
+	C::Digest: Zero + One + core::hash::Hash {
+
+	/// Like `child`, but the body starts with a coinbase paying the context's author the given
+	/// block reward plus the fees the transitions pay. Without an author there is no coinbase.
+	/// A coinbase among the given transitions is rejected like any transition the state machine
+	/// refuses, at its position among them.
+	pub fn child_with_coinbase(
+		&self,
+		pre_state: &SM::State,
+		transitions: Vec<SM::Transition>,
+		context: BlockContext,
+		reward: u64,
+	) -> Result<Self, BlockBuildError> {
+		self.child_with_coinbase_sealed_by(&self.consensus, pre_state, transitions, context, reward)
+	}
+
+	/// Like `child_with_coinbase`, but sealed by the given consensus engine rather than this
+	/// block's.
+	fn child_with_coinbase_sealed_by(
+		&self,
+		consensus: &C,
+		pre_state: &SM::State,
+		transitions: Vec<SM::Transition>,
+		context: BlockContext,
+		reward: u64,
+	) -> Result<Self, BlockBuildError> {
+		if let Some(index) = transitions.iter().position(SM::is_coinbase) {
+			return Err(BlockBuildError::StateExecutionFailed {
+				index,
+				error: "only the block builder may add a coinbase".into(),
+			});
+		}
+		let author = context.author;
+		let block = match author {
+			Some(author) => {
+				let coinbase = SM::coinbase(author, SM::coinbase_amount(&transitions, reward));
+				let body = std::iter::once(coinbase).chain(transitions).collect();
+				self.child_sealed_by(consensus, pre_state, body, context).map_err(|e| match e {
+					BlockBuildError::StateExecutionFailed { index: 0, error } => {
+						BlockBuildError::CoinbaseFailed { error }
+					}
+					BlockBuildError::StateExecutionFailed { index, error } => {
+						BlockBuildError::StateExecutionFailed { index: index - 1, error }
+					}
+					e => e,
+				})?
+			}
+			None => self.child_sealed_by(consensus, pre_state, transitions, context)?,
+		};
+		if block.author() != author {
+			return Err(BlockBuildError::AuthorMismatch);
+		}
+		Ok(block)
+	}
+
+	/// Like `verify_full_chain`, but every block must also start with the coinbase paying its
+	/// author the given block reward, as `check_coinbase` checks. Without a block reward, no block
+	/// may have a coinbase.
+	pub fn verify_full_chain_with_reward(
+		&self,
+		pre_state: &SM::State,
+		parent_digest: &C::Digest,
+		chain: &[Self],
+		reward: Option<u64>,
+	) -> Result<(), VerificationFailure> {
+		self.verify_full_chain_with(pre_state, parent_digest, chain, |block| block.check_coinbase(reward))
+	}
+
+	/// Check that the body starts with the coinbase paying the block's author the given reward
+	/// plus the fees the rest of the body pays, and holds no other coinbase. A block whose author
+	/// is unknown, or on a chain without a block reward, must have no coinbase at all.
+	fn check_coinbase(&self, reward: Option<u64>) -> Result<(), BlockVerificationError> {
+		if let Some(index) = self.body.iter().skip(1).position(SM::is_coinbase) {
+			return Err(BlockVerificationError::MisplacedCoinbase { index: index + 1 });
+		}
+		let expected = reward
+			.zip(self.author())
+			.map(|(reward, author)| SM::coinbase(author, SM::coinbase_amount(&self.body, reward)));
+		let found = self.body.first().filter(|t| SM::is_coinbase(t));
+		if found != expected.as_ref() {
+			return Err(BlockVerificationError::CoinbaseMismatch);
+		}
+		Ok(())
+	}
+}
+
+/// A block built by `Block::child_with_weight_limit`, and what became of the pending transitions
+/// it left out
+pub struct WeightLimitedChild<C: Consensus, SM: StateMachine> {
+	pub block: Block<C, SM>,
+	/// The transitions that did not fit, left for later blocks
+	pub rest: Vec<SM::Transition>,
+	/// The transitions too heavy for any block, which the caller should drop
+	pub oversized: Vec<SM::Transition>,
+}
+
+impl<C: Consensus, SM: Weighted + ContextualStateMachine> Block<C, SM>
+	where
+	SM::State: core::hash::Hash + Clone,
+	SM::Transition: core::hash::Hash + Encode {
+
+	/// Build a child block from the pending transitions, taking them in order for as long as
+	/// the block's total weight stays within `max_weight`. The given pre-state is the state
+	/// after executing this block, and the transitions are executed in the given context. The
+	/// context's parent hash and height are always set to this block's hash and the next height.
+	///
+	/// Returns the new block, the transitions that did not fit, which are left for later blocks,
+	/// and the transitions heavier than `max_weight` on their own. No block could ever include
+	/// those, so they are set aside rather than left to hold back the transitions behind them.
+	///
+	/// Fails like `child` if the state machine rejects a transition, giving its position among
+	/// the pending transitions, or if the consensus engine could not seal the block, for example
+	/// because its rules limit what a body may contain.
+	pub fn child_with_weight_limit(
+		&self,
+		pre_state: &SM::State,
+		pending: Vec<SM::Transition>,
+		max_weight: u64,
+		mut context: BlockContext,
+	) -> Result<WeightLimitedChild<C, SM>, BlockBuildError> {
+		context.parent_hash = hash(&self.header);
+		context.height = self.header.height + 1;
+		let (oversized, mut candidates): (Vec<_>, Vec<_>) = pending
+			.into_iter()
+			.enumerate()
+			.partition(|(_, t)| SM::weight(t) > max_weight);
+		let mut weight = 0u64;
+		let fitting = candidates
+			.iter()
+			.take_while(|(_, t)| {
+				weight = weight.saturating_add(SM::weight(t));
+				weight <= max_weight
+			})
+			.count();
+		let rest = candidates.split_off(fitting);
+		let (positions, body): (Vec<usize>, Vec<_>) = candidates.into_iter().unzip();
+
+		let post_state = execute_body::<SM>(pre_state, &body, &context).map_err(|(index, e)| {
+			BlockBuildError::StateExecutionFailed { index: positions[index], error: format!("{e:?}") }
+		})?;
+		let encoded: Vec<Vec<u8>> = body.iter().map(Encode::encode).collect();
+		let partial_header = Header::<()> {
+			parent: hash(&self.header),
+			height: self.header.height + 1,
+			state_root: hash(&post_state),
+			extrinsics_root: extrinsics_tree(&encoded).root(),
+			consensus_digest: (),
+		};
+		let header = self
+			.consensus
+			.seal_with_body(&self.header.consensus_digest, partial_header, &encoded)
+			.ok_or(BlockBuildError::SealFailed)?;
+
+		let transitions = |ts: Vec<(usize, SM::Transition)>| ts.into_iter().map(|(_, t)| t).collect();
+		Ok(WeightLimitedChild {
+			block: Block { header, body, context, consensus: C::create_default_instance() },
+			rest: transitions(rest),
+			oversized: transitions(oversized),
+		})
+	}
+}
+
+impl<C: Consensus, SM: Weighted> Block<C, SM> {
+	/// Check that this block's body does not exceed the given weight limit. Importers should
+	/// reject blocks that fail this check, as honest builders never produce them.
+	pub fn within_weight_limit(&self, max_weight: u64) -> bool {
+		SM::total_weight(&self.body) <= max_weight
+	}
+}
+
+impl<C: Consensus, SM: DiffStateMachine> Block<C, SM>
+	where SM::State: Clone {
+
+	/// Calculate the diffs this block's body makes to the given pre-state, one per transition.
+	///
+	/// A client can store these diffs instead of the complete post-state of every block, and
+	/// later reconstruct any historical state with `reconstruct_state`.
+	pub fn state_diffs(&self, pre_state: &SM::State) -> Result<Vec<SM::StateDiff>, SM::Error> {
+		let mut s = pre_state.clone();
+		let mut diffs = Vec::with_capacity(self.body.len());
+		for t in self.body.iter() {
+			let d = SM::state_diff(&s, t)?;
+			SM::apply_diff(&mut s, &d);
+			diffs.push(d);
+		}
+		Ok(diffs)
+	}
+}
+
+impl<C: Consensus, SM: ParallelStateMachine> Block<C, SM> {
+	/// Execute this block's body on top of the given pre-state, running transitions that touch
+	/// unrelated parts of the state in parallel. Returns the post-state, or the reason the first
+	/// rejected transition was rejected.
+	///
+	/// This ignores the block context, so it is only suitable for machines that do not depend
+	/// on it. Those, like the accounted currency, execute every block this way when building,
+	/// importing and verifying it, by running their `try_apply_all_in_context` through the same
+	/// executor.
+	pub fn execute_parallel(&self, pre_state: &SM::State) -> Result<SM::State, SM::Error> {
+		try_apply_all_parallel::<SM>(pre_state, &self.body)
+	}
+}
+
+impl<C: Consensus, SM: InvertibleStateMachine> Block<C, SM>
+	where SM::State: Clone {
+
+	/// Roll the given post-state of this block back to the block's pre-state.
+	pub fn revert(&self, post_state: &SM::State) -> SM::State {
+		SM::undo_all(post_state, &self.body)
+	}
+}
+
+/// An event emitted while executing a block, along with where it came from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EventRecord<Event> {
+	/// The height of the block that emitted the event
+	pub block_height: u64,
+	/// The hash of the header of the block that emitted the event
+	pub block_hash: Hash,
+	/// The position in the block's body of the transition that emitted the event
+	pub transition_index: usize,
+	pub event: Event,
+}
+
+impl<C: Consensus, SM: EventfulStateMachine> Block<C, SM>
+	where SM::State: Clone {
+
+	/// Execute this block's body on top of the given pre-state, in the block's context. Returns
+	/// the post-state along with every event emitted during execution, in order.
+	pub fn execute_with_events(&self, pre_state: &SM::State) -> (SM::State, Vec<EventRecord<SM::Event>>) {
+		let block_hash = hash(&self.header);
+		let mut s = pre_state.clone();
+		let mut records = Vec::new();
+		for (transition_index, t) in self.body.iter().enumerate() {
+			let (next, events) = SM::next_state_with_events_in_context(&s, t, &self.context);
+			s = next;
+			records.extend(events.into_iter().map(|event| EventRecord {
+				block_height: self.header.height,
+				block_hash,
+				transition_index,
+				event,
+			}));
+		}
+		(s, records)
+	}
+}
+
+/// Feed the parameter changes enacted by on-chain governance into the consensus engine, in
+/// order. Returns how many of them the engine applied.
+fn apply_governance_events<'a, C: Configurable>(
+	consensus: &mut C,
+	events: impl IntoIterator<Item = &'a GovernanceEvent>,
+) -> usize {
+	events
+		.into_iter()
+		.filter(|event| match event {
+			GovernanceEvent::ParameterChanged(change) => consensus.apply_parameter_change(change),
+			_ => false,
+		})
+		.count()
+}
+
+/// The events emitted by every block a client has executed, kept for querying.
+struct EventLog<Event> {
+	records: Vec<EventRecord<Event>>,
+}
+
+impl<Event: Clone> EventLog<Event> {
+	fn new() -> Self {
+		EventLog { records: Vec::new() }
+	}
+
+	/// Execute the given block on top of its pre-state, log the events it emits, and return
+	/// its post-state.
+	fn execute_block<C, SM>(&mut self, block: &Block<C, SM>, pre_state: &SM::State) -> SM::State
+	where
+		C: Consensus,
+		SM: EventfulStateMachine<Event = Event, State: Clone>,
+	{
+		let (post_state, mut records) = block.execute_with_events(pre_state);
+		self.records.append(&mut records);
+		post_state
+	}
+
+	/// All events emitted by the block with the given header hash.
+	fn events_in_block(&self, block_hash: Hash) -> Vec<&EventRecord<Event>> {
+		self.records.iter().filter(|r| r.block_hash == block_hash).collect()
+	}
+
+	/// All events emitted by blocks in the given range of heights, matching the given filter.
+	fn query(
+		&self,
+		heights: std::ops::RangeInclusive<u64>,
+		filter: impl Fn(&Event) -> bool,
+	) -> Vec<&EventRecord<Event>> {
+		self.records
+			.iter()
+			.filter(|r| heights.contains(&r.block_height) && filter(&r.event))
+			.collect()
+	}
+
+	/// Forget the events of the block with the given header hash, as when it is retracted in
+	/// a re-org.
+	fn forget_block(&mut self, block_hash: Hash) {
+		self.records.retain(|r| r.block_hash != block_hash);
+	}
+}
+
+/// Roll the state at the tip of the given blocks back to the state before the first of them.
+/// This is what a client does to the retracted side of a re-org before executing the
+/// enacted side.
+fn revert_blocks<C: Consensus, SM: InvertibleStateMachine>(
+	tip_state: &SM::State,
+	blocks: &[Block<C, SM>],
+) -> SM::State
+where
+SM::State: Clone {
+	blocks.iter().rev().fold(tip_state.clone(), |s, b| b.revert(&s))
+}
 
-/// Create and return a block chain that is n blocks long starting from the given genesis state.
-/// The blocks should not contain any transactions.
-fn create_empty_chain<C: Consensus, SM: StateMachine>(
+/// Like `revert_blocks`, but refuses to roll back past the block the finality gadget has
+/// finalized. Finalized blocks are never reverted, whatever fork comes along.
+fn revert_unfinalized_blocks<C: Consensus, SM: InvertibleStateMachine, F>(
+	tip_state: &SM::State,
+	blocks: &[Block<C, SM>],
+	finality: &FinalityGadget<F>,
+) -> Result<SM::State, FinalityError>
+where
+SM::State: Clone {
+	if let Some(first) = blocks.first() {
+		finality.ensure_revertible(first.header.height)?;
+	}
+	Ok(revert_blocks(tip_state, blocks))
+}
+
+/// Import the given blocks into the tree of headers the client knows about, and return the best
+/// head according to the fork choice rule. Blocks may build on any known block, so competing
+/// forks are imported side by side. Blocks whose parent is unknown, or that the consensus engine
+/// rejects, are skipped.
+fn import_blocks<C: Consensus, SM: StateMachine, FC: ForkChoice<C::Digest>>(
+	consensus: &C,
+	tree: &mut HeaderTree<C::Digest>,
+	blocks: &[Block<C, SM>],
+	fork_choice: &FC,
+) -> Hash
+where
+C::Digest: core::hash::Hash {
+	for block in blocks {
+		let valid = tree.get(block.header.parent)
+			.is_some_and(|parent| consensus.validate(&parent.consensus_digest, &block.header));
+		if valid {
+			tree.insert(block.header.clone());
+		}
+	}
+	fork_choice.best_head(tree)
+}
+
+/// Like `import_blocks`, but also skips blocks including uncles the tree cannot vouch for, so
+/// that the fork choice and the rewards only ever count genuine uncles.
+fn import_blocks_with_uncles<H: PowHasher, SM: StateMachine, FC: ForkChoice<UncleDigest>>(
+	engine: &UnclePow<H>,
+	tree: &mut HeaderTree<UncleDigest>,
+	blocks: &[Block<UnclePow<H>, SM>],
+	fork_choice: &FC,
+) -> Hash {
+	for block in blocks {
+		if uncles_are_valid(tree, &block.header, engine.max_depth()) {
+			import_blocks(engine, tree, std::slice::from_ref(block), fork_choice);
+		}
+	}
+	fork_choice.best_head(tree)
+}
+
+/// Prune every fork that branches off before the latest checkpoint on the chain ending with the
+/// given head, and return that checkpoint. The tree is left unchanged if there is none.
+fn prune_to_latest_checkpoint<Inner: Consensus>(
+	engine: &Checkpointed<Inner>,
+	tree: &mut HeaderTree<CheckpointDigest<Inner::Digest>>,
+	head: Hash,
+) -> Option<BlockId> {
+	let route = tree.route_from_root(head);
+	let headers: Vec<_> = route
+		.iter()
+		.map(|h| tree.get(*h).expect("the route only holds blocks in the tree").clone())
+		.collect();
+	let checkpoint = engine.latest_checkpoint(&headers)?;
+	let index = headers.iter().rposition(|h| h.height == checkpoint.height)?;
+	tree.prune_to(route[index]);
+	Some(checkpoint)
+}
+
+/// Reconstruct a later state from a known earlier state and the diffs of every block
+/// executed since then, in order.
+fn reconstruct_state<SM: DiffStateMachine>(
+	known_state: &SM::State,
+	block_diffs: &[Vec<SM::StateDiff>],
+) -> SM::State
+where
+SM::State: Clone {
+	let mut s = known_state.clone();
+	for d in block_diffs.iter().flatten() {
+		SM::apply_diff(&mut s, d);
+	}
+	s
+}
+
+/// Create and return a block chain that is n blocks long starting from the genesis state built
+/// from the given configuration. The blocks should not contain any transactions. Fails if any
+/// of the blocks cannot be built.
+fn create_empty_chain<C: Consensus, SM: ContextualStateMachine>(
 	n: u64,
-	genesis_state: &SM::State,
-) -> Vec<Block<C, SM>> 
+	genesis_config: SM::GenesisConfig,
+) -> Result<Vec<Block<C, SM>>, BlockBuildError> 
 where 
 SM::State : core::hash::Hash + Clone,
+SM::Transition: core::hash::Hash + Encode,
 <C as Consensus>::Digest: Zero+One+core::hash::Hash {
 
+	let genesis_state = SM::genesis_state(genesis_config);
 	let mut chain:Vec<Block<C, SM>> = vec![];
-	let mut b = Block::<C, SM>::genesis(genesis_state);
-	let mut pre_state = genesis_state.clone();
+	let b = Block::<C, SM>::genesis(&genesis_state);
+	let mut pre_state = genesis_state;
 	chain.push(b);
 	for i in 1..n as usize {
 		
-		let tb = chain[i-1].child(&pre_state, vec![]);
+		let context = BlockContext { height: i as u64, ..BlockContext::default() };
+		let tb = chain[i-1].child(&pre_state, vec![], context)?;
 
 		chain.push(tb);
-		let mut s  = pre_state.clone();
-		for t in chain[i].body.iter() {
-			s = SM::next_state(&s, &t).clone();
-		}
-		pre_state = s;
+		pre_state = SM::apply_all_in_context(&pre_state, &chain[i].body, &chain[i].context);
 		
 	}
+	Ok(chain)
+}
+
+#[cfg(test)]
+use crate::c1_state_machine::{p1_switches::LightSwitch, p4_accounted_currency::{AccountedCurrency, AccountingTransaction, CurrencyError, CurrencyEvent}};
+#[cfg(test)]
+use std::collections::HashMap;
+
+#[test]
+fn cl_reconstruct_state_from_block_diffs() {
+	let header = Header { parent: 0, height: 0, state_root: 0, extrinsics_root: 0, consensus_digest: () };
+	let b1 = Block::<(), AccountedCurrency> {
+		header: header.clone(),
+		body: vec![
+			AccountingTransaction::Mint { minter: User::Alice, amount: 100 },
+			AccountingTransaction::Transfer { sender: User::Alice, receiver: User::Bob, amount: 40 },
+		],
+		context: BlockContext::default(),
+		consensus: (),
+	};
+	let b2 = Block::<(), AccountedCurrency> {
+		header,
+		body: vec![
+			AccountingTransaction::Burn { burner: User::Bob, amount: 40 },
+		],
+		context: BlockContext::default(),
+		consensus: (),
+	};
+
+	let genesis_state = HashMap::new();
+	let d1 = b1.state_diffs(&genesis_state).unwrap();
+	let s1 = reconstruct_state::<AccountedCurrency>(&genesis_state, std::slice::from_ref(&d1));
+	assert_eq!(s1, AccountedCurrency::apply_all(&genesis_state, &b1.body));
+
+	let d2 = b2.state_diffs(&s1).unwrap();
+	let s2 = reconstruct_state::<AccountedCurrency>(&genesis_state, &[d1, d2]);
+	assert_eq!(s2, HashMap::from([(User::Alice, 60)]));
+}
+
+#[test]
+fn cl_state_diffs_of_invalid_block_fail() {
+	let header = Header { parent: 0, height: 0, state_root: 0, extrinsics_root: 0, consensus_digest: () };
+	let b1 = Block::<(), AccountedCurrency> {
+		header,
+		body: vec![
+			AccountingTransaction::Transfer { sender: User::Alice, receiver: User::Bob, amount: 40 },
+		],
+		context: BlockContext::default(),
+		consensus: (),
+	};
+
+	assert!(b1.state_diffs(&HashMap::new()).is_err());
+}
+
+#[test]
+fn cl_revert_blocks_restores_fork_point_state() {
+	let header = Header { parent: 0, height: 0, state_root: 0, extrinsics_root: 0, consensus_digest: () };
+	let b1 = Block::<(), AccountedCurrency> {
+		header: header.clone(),
+		body: vec![
+			AccountingTransaction::Mint { minter: User::Alice, amount: 100 },
+			AccountingTransaction::Transfer { sender: User::Alice, receiver: User::Bob, amount: 40 },
+		],
+		context: BlockContext::default(),
+		consensus: (),
+	};
+	let b2 = Block::<(), AccountedCurrency> {
+		header,
+		body: vec![
+			AccountingTransaction::Transfer { sender: User::Bob, receiver: User::Charlie, amount: 40 },
+		],
+		context: BlockContext::default(),
+		consensus: (),
+	};
+
+	let fork_point_state = HashMap::from([(User::Charlie, 1)]);
+	let s1 = AccountedCurrency::apply_all(&fork_point_state, &b1.body);
+	let s2 = AccountedCurrency::apply_all(&s1, &b2.body);
+
+	assert_eq!(b2.revert(&s2), s1);
+	assert_eq!(revert_blocks(&s2, &[b1, b2]), fork_point_state);
+}
+
+#[test]
+fn cl_finalized_blocks_are_not_reverted() {
+	use crate::c3_consensus::{ConsensusAuthority, finality::{Vote, VoteKind}};
+
+	let genesis = Header { parent: 0, height: 0, state_root: 0, extrinsics_root: 0, consensus_digest: () };
+	let h1 = Header { parent: hash(&genesis), height: 1, ..genesis.clone() };
+	let h2 = Header { parent: hash(&h1), height: 2, ..genesis.clone() };
+	let block = |header, body| Block::<(), AccountedCurrency> { header, body, context: BlockContext::default(), consensus: () };
+	let b1 = block(h1.clone(), vec![AccountingTransaction::Mint { minter: User::Alice, amount: 100 }]);
+	let b2 = block(h2.clone(), vec![AccountingTransaction::Mint { minter: User::Bob, amount: 50 }]);
+
+	let mut finality = FinalityGadget::new((), vec![ConsensusAuthority::Alice, ConsensusAuthority::Bob]);
+	for h in [&genesis, &h1, &h2] {
+		finality.import_header(h).unwrap();
+	}
+	for kind in [VoteKind::Prevote, VoteKind::Precommit] {
+		for voter in [ConsensusAuthority::Alice, ConsensusAuthority::Bob] {
+			finality.import_vote(Vote { round: 1, kind, block: hash(&h1), voter }).unwrap();
+		}
+	}
+
+	let s1 = AccountedCurrency::apply_all(&HashMap::new(), &b1.body);
+	let s2 = AccountedCurrency::apply_all(&s1, &b2.body);
+	let finalized = finality.finalized().unwrap();
+	assert_eq!(finalized.hash, hash(&h1));
+	let blocks = [b1, b2];
+	assert_eq!(revert_unfinalized_blocks(&s2, &blocks, &finality), Err(FinalityError::WouldRevertFinalized(finalized)));
+	assert_eq!(revert_unfinalized_blocks(&s2, &blocks[1..], &finality), Ok(s1));
+}
+
+#[test]
+fn cl_import_follows_the_best_fork() {
+	use p3_fork_choice::LongestChain;
+
+	let genesis = Header { parent: 0, height: 0, state_root: 0, extrinsics_root: 0, consensus_digest: () };
+	let child = |parent: &Header<()>, extrinsics_root| Header { parent: hash(parent), height: parent.height + 1, extrinsics_root, ..genesis.clone() };
+	let block = |header| Block::<(), LightSwitch> { header, body: vec![], context: BlockContext::default(), consensus: () };
+	let a1 = child(&genesis, 1);
+	let b1 = child(&genesis, 2);
+	let b2 = child(&b1, 2);
+	let mut tree = HeaderTree::new(genesis.clone());
+
+	let best = import_blocks(&(), &mut tree, &[block(a1.clone()), block(b1.clone())], &LongestChain);
+	assert_eq!(best, hash(&a1));
+
+	// The competing fork overtakes, and a block whose parent was never seen is skipped.
+	let orphan = child(&child(&a1, 3), 3);
+	let best = import_blocks(&(), &mut tree, &[block(b2.clone()), block(orphan.clone())], &LongestChain);
+	assert_eq!(best, hash(&b2));
+	assert!(!tree.contains(hash(&orphan)));
+}
+
+#[test]
+fn cl_forks_before_the_latest_checkpoint_are_pruned() {
+	use crate::c3_consensus::ConsensusAuthority;
+	use p3_fork_choice::LongestChain;
+
+	let engine = Checkpointed::new((), 2, vec![ConsensusAuthority::Alice]);
+	let genesis = Header { parent: 0, height: 0, state_root: 0, extrinsics_root: 0, consensus_digest: CheckpointDigest::genesis(()) };
+	let seal = |parent: &Header<CheckpointDigest<()>>, extrinsics_root| {
+		let partial = Header { parent: hash(parent), height: parent.height + 1, state_root: 0, extrinsics_root, consensus_digest: () };
+		engine.seal(&parent.consensus_digest, partial).unwrap()
+	};
+	let block = |header| Block::<Checkpointed<()>, LightSwitch> { header, body: vec![], context: BlockContext::default(), consensus: Checkpointed::new((), 2, vec![]) };
+	let a1 = seal(&genesis, 1);
+	let b1 = seal(&genesis, 2);
+	let b2 = seal(&b1, 2);
+	let b3 = seal(&b2, 2);
+	let mut tree = HeaderTree::new(genesis.clone());
+
+	let best = import_blocks(&engine, &mut tree, &[block(a1.clone()), block(b1), block(b2.clone()), block(b3)], &LongestChain);
+	let checkpoint = prune_to_latest_checkpoint(&engine, &mut tree, best);
+
+	assert_eq!(checkpoint.map(|c| c.height), Some(2));
+	assert_eq!(tree.root(), hash(&b2));
+	assert!(!tree.contains(hash(&a1)));
+	assert!(tree.contains(best));
+}
+
+#[test]
+fn cl_blocks_with_bogus_uncles_are_skipped() {
+	use crate::c3_consensus::ConsensusAuthority::{Alice, Bob};
+	use crate::c3_consensus::p1_pow::PoW;
+	use crate::c3_consensus::p17_uncles::UncleRef;
+	use p3_fork_choice::HeaviestChain;
+
+	let alice = UnclePow::new(PoW::new(u64::MAX / 10), Alice, 2, 2);
+	let bob = UnclePow::new(PoW::new(u64::MAX / 10), Bob, 2, 2);
+	let genesis = Header { parent: 0, height: 0, state_root: 0, extrinsics_root: 0, consensus_digest: UncleDigest::genesis() };
+	let partial = |parent: &Header<UncleDigest>| Header { parent: hash(parent), height: parent.height + 1, state_root: 0, extrinsics_root: 0, consensus_digest: () };
+	let block = |header| Block::<UnclePow, LightSwitch> { header, body: vec![], context: BlockContext::default(), consensus: alice.clone() };
+	let a1 = alice.seal(&genesis.consensus_digest, partial(&genesis)).unwrap();
+	let a2 = alice.seal(&a1.consensus_digest, partial(&a1)).unwrap();
+	let b1 = bob.seal(&genesis.consensus_digest, partial(&genesis)).unwrap();
+	let uncle = |header: &Header<UncleDigest>| UncleRef { hash: hash(header), height: header.height, miner: header.consensus_digest.miner };
+	let b2 = bob.seal_with_uncles(partial(&b1), vec![uncle(&a1)]).unwrap();
+	let mut forged = uncle(&a1);
+	forged.miner = Bob;
+	let bogus = bob.seal_with_uncles(partial(&b1), vec![forged]).unwrap();
+	let mut tree = HeaderTree::new(genesis.clone());
+
+	let best = import_blocks_with_uncles(&alice, &mut tree, &[block(a1), block(a2.clone()), block(b1), block(bogus.clone())], &HeaviestChain::with_uncles());
+	assert_eq!(best, hash(&a2));
+	assert!(!tree.contains(hash(&bogus)));
+
+	// Bob's fork is no longer, but counting a1 as its uncle puts it ahead.
+	let best = import_blocks_with_uncles(&alice, &mut tree, &[block(b2.clone())], &HeaviestChain::with_uncles());
+	assert_eq!(best, hash(&b2));
+	assert_eq!(bob.rewards(&b2), vec![(Bob, 33), (Alice, 24)]);
+}
+
+#[test]
+fn cl_verify_sub_chain_from_snapshot() {
+	let genesis = Block::<(), LightSwitch> {
+		header: Header { parent: 0, height: 0, state_root: hash(&true), extrinsics_root: 0, consensus_digest: () },
+		body: vec![()],
+		context: BlockContext::default(),
+		consensus: (),
+	};
+	let snapshot = genesis.snapshot(true);
+
+	let b1 = Block::<(), LightSwitch> {
+		header: Header { parent: hash(&genesis.header), height: 1, state_root: hash(&false), extrinsics_root: 0, consensus_digest: () },
+		body: vec![()],
+		context: BlockContext { height: 1, parent_hash: hash(&genesis.header), ..BlockContext::default() },
+		consensus: (),
+	};
+	let b2 = Block::<(), LightSwitch> {
+		header: Header { parent: hash(&b1.header), height: 2, state_root: hash(&false), extrinsics_root: 0, consensus_digest: () },
+		body: vec![],
+		context: BlockContext { height: 2, parent_hash: hash(&b1.header), ..BlockContext::default() },
+		consensus: (),
+	};
+	let chain = [b1, b2];
+
+	assert!(Block::verify_sub_chain_from_snapshot(&snapshot, &chain));
+
+	// A snapshot taken at some other block is not a valid starting point for this chain.
+	let unrelated = chain[1].snapshot(false);
+	assert!(!Block::verify_sub_chain_from_snapshot(&unrelated, &chain));
+
+	// Neither is one whose state doesn't lead to the state roots in the chain.
+	let wrong_state = genesis.snapshot(false);
+	assert!(!Block::verify_sub_chain_from_snapshot(&wrong_state, &chain));
+}
+
+#[test]
+fn cl_child_with_weight_limit_leaves_overflow_pending() {
+	let genesis = Block::<(), LightSwitch> {
+		header: Header { parent: 0, height: 0, state_root: hash(&false), extrinsics_root: 0, consensus_digest: () },
+		body: vec![],
+		context: BlockContext::default(),
+		consensus: (),
+	};
+
+	let WeightLimitedChild { block: b1, rest, .. } = genesis.child_with_weight_limit(&false, vec![(); 5], 3, BlockContext::default()).unwrap();
+	assert_eq!(b1.body.len(), 3);
+	assert_eq!(rest.len(), 2);
+	assert!(b1.within_weight_limit(3));
+	assert_eq!(b1.header.state_root, hash(&true));
+
+	let WeightLimitedChild { block: b2, rest, .. } = b1.child_with_weight_limit(&true, rest, 3, BlockContext::default()).unwrap();
+	assert_eq!(b2.body.len(), 2);
+	assert!(rest.is_empty());
+	assert_eq!(b2.header.parent, hash(&b1.header));
+	assert_eq!(b2.header.height, 2);
+
+	// Transitions that are too heavy for any block are handed back to be dropped, rather than
+	// left pending forever.
+	let WeightLimitedChild { block: b3, rest, oversized } = b2.child_with_weight_limit(&true, vec![(); 2], 0, BlockContext::default()).unwrap();
+	assert!(b3.body.is_empty());
+	assert!(rest.is_empty());
+	assert_eq!(oversized.len(), 2);
+}
+
+#[test]
+fn cl_child_with_weight_limit_reports_rejected_transitions() {
+	use crate::c1_state_machine::p7_multiasset::{AssetTransaction, MultiAsset, NATIVE_ASSET};
+
+	let genesis_state = MultiAsset::genesis_state(vec![(NATIVE_ASSET, User::Alice, vec![(User::Alice, 100)])]);
+	let genesis = Block::<(), MultiAsset> {
+		header: Header { parent: 0, height: 0, state_root: hash(&genesis_state), extrinsics_root: 0, consensus_digest: () },
+		body: vec![],
+		context: BlockContext::default(),
+		consensus: (),
+	};
+	// Transfers weigh 2, so this one is too heavy, and Bob has nothing to burn.
+	let transfer = AssetTransaction::Transfer { asset: NATIVE_ASSET, sender: User::Alice, receiver: User::Bob, amount: 1 };
+	let burn = AssetTransaction::Burn { burner: User::Bob, asset: NATIVE_ASSET, amount: 1 };
+
+	let built = genesis.child_with_weight_limit(&genesis_state, vec![transfer, burn], 1, BlockContext::default());
+	assert!(matches!(built, Err(BlockBuildError::StateExecutionFailed { index: 1, .. })));
+}
+
+#[test]
+fn cl_child_blocks_carry_their_transitions() {
+	use crate::c3_consensus::p1_pow::PoW;
+
+	let genesis = Block::<PoW, LightSwitch>::genesis(&false);
+	let b1 = genesis.child(&false, vec![(), ()], BlockContext { height: 1, ..BlockContext::default() }).unwrap();
+	let b2 = b1.child(&false, vec![()], BlockContext { height: 2, ..BlockContext::default() }).unwrap();
+	let b3 = b2.child(&true, vec![], BlockContext { height: 3, ..BlockContext::default() }).unwrap();
+	assert_eq!((b1.body.len(), b2.body.len()), (2, 1));
+	assert_eq!((b2.header.height, b2.header.state_root), (2, hash(&true)));
+	assert!(genesis.consensus.validate(&b1.header.consensus_digest, &b2.header));
+
+	let mut chain = vec![genesis, b1, b2, b3];
+	assert!(chain[0].verify_sub_chain(&false, &chain));
+
+	// Re-execution catches a body that does not match the state root.
+	chain[2].body.push(());
+	assert!(!chain[0].verify_sub_chain(&false, &chain));
+
+	let empty = create_empty_chain::<PoW, LightSwitch>(4, ()).unwrap();
+	assert_eq!(empty[1].header.extrinsics_root, 0);
+	assert!(empty.iter().all(|b| b.body.is_empty()));
+	assert!(empty[0].verify_sub_chain(&false, &empty));
+}
+
+/// Withdraws from a single balance, refusing to overdraw it.
+#[cfg(test)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Withdrawals;
+
+#[cfg(test)]
+impl StateMachine for Withdrawals {
+	type State = u64;
+	type Transition = u64;
+	type Error = &'static str;
+	type GenesisConfig = u64;
+
+	fn genesis_state(balance: u64) -> u64 {
+		balance
+	}
+
+	fn next_state(balance: &u64, amount: &u64) -> u64 {
+		balance.saturating_sub(*amount)
+	}
+
+	fn try_next_state(balance: &u64, amount: &u64) -> Result<u64, Self::Error> {
+		balance.checked_sub(*amount).ok_or("overdrawn")
+	}
+}
+
+#[cfg(test)]
+impl ContextualStateMachine for Withdrawals {}
+
+#[test]
+fn cl_child_reports_why_it_could_not_be_built() {
+	use crate::c3_consensus::p1_pow::PoW;
+	use crate::c3_consensus::p15_max_extrinsics::MaxExtrinsics;
+
+	let genesis = Block::<PoW, Withdrawals>::genesis(&10);
+	assert_eq!(
+		genesis.child(&10, vec![4, 7, 1], BlockContext::default()).err(),
+		Some(BlockBuildError::StateExecutionFailed { index: 1, error: "\"overdrawn\"".into() })
+	);
+
+	let limited = Block::<MaxExtrinsics<PoW>, Withdrawals> {
+		header: genesis.header.clone(),
+		body: vec![],
+		context: BlockContext::default(),
+		consensus: MaxExtrinsics { inner: PoW::create_default_instance(), max: 2 },
+	};
+	assert_eq!(limited.child(&10, vec![1, 1, 1], BlockContext::default()).err(), Some(BlockBuildError::SealFailed));
+	assert!(limited.child(&10, vec![1, 1], BlockContext::default()).is_ok());
+}
+
+#[test]
+fn cl_extrinsics_root_commits_to_every_transition() {
+	use crate::c3_consensus::p1_pow::PoW;
+
+	let genesis = Block::<PoW, Withdrawals>::genesis(&100);
+	let block = genesis.child(&100, vec![5, 10, 20], BlockContext::default()).unwrap();
+	let root = block.header.extrinsics_root;
+
+	for (i, amount) in block.body.iter().enumerate() {
+		let proof = block.extrinsic_proof(i).unwrap();
+		assert!(proof.verify(root, extrinsic_leaf(&amount.encode())));
+		assert!(!proof.verify(root, extrinsic_leaf(&(amount + 1).encode())));
+	}
+	assert_eq!(block.extrinsic_proof(3), None);
+
+	// Reordering the body changes the root.
+	let reordered = genesis.child(&100, vec![10, 5, 20], BlockContext::default()).unwrap();
+	assert_ne!(reordered.header.extrinsics_root, root);
+}
+
+#[cfg(test)]
+fn withdrawals_chain() -> Vec<Block<crate::c3_consensus::p1_pow::PoW, Withdrawals>> {
+	let mut chain = vec![Block::genesis(&100)];
+	let mut state = 100;
+	for (height, body) in [vec![10], vec![20, 5], vec![]].into_iter().enumerate() {
+		let context = BlockContext { height: height as u64 + 1, ..BlockContext::default() };
+		let block = chain[height].child(&state, body, context).unwrap();
+		state = Withdrawals::apply_all(&state, &block.body);
+		chain.push(block);
+	}
 	chain
 }
 
-//TODO tests
+#[test]
+fn cl_verify_full_chain_reports_the_first_failure() {
+	let chain = withdrawals_chain();
+	let genesis = &chain[0];
+	let verify = |blocks: &[Block<_, Withdrawals>]| genesis.verify_full_chain(&100, &genesis.header.consensus_digest, blocks);
+	assert_eq!(verify(&chain[1..]), Ok(()));
+
+	let mut swapped = chain.clone();
+	swapped[2].body.reverse();
+	let failure = verify(&swapped[1..]).unwrap_err();
+	assert_eq!((failure.position, failure.block_hash), (1, hash(&chain[2].header)));
+	assert!(matches!(failure.error, BlockVerificationError::ExtrinsicsRootMismatch { .. }));
+
+	let mut skipped = chain.clone();
+	skipped.remove(2);
+	assert_eq!(verify(&skipped[1..]).unwrap_err().error, BlockVerificationError::NotAChild);
+
+	// Starting from the wrong state, the first block's state root is off.
+	let failure = genesis.verify_full_chain(&90, &genesis.header.consensus_digest, &chain[1..]).unwrap_err();
+	assert_eq!(failure.position, 0);
+	assert!(matches!(failure.error, BlockVerificationError::StateRootMismatch { .. }));
+	let failure = genesis.verify_full_chain(&20, &genesis.header.consensus_digest, &chain[1..]).unwrap_err();
+	assert_eq!(failure.error, BlockVerificationError::StateRootMismatch { expected: hash(&10u64), found: chain[1].header.state_root });
+	let failure = genesis.verify_full_chain(&5, &genesis.header.consensus_digest, &chain[1..]).unwrap_err();
+	assert_eq!(failure.error, BlockVerificationError::StateExecutionFailed { index: 0, error: "\"overdrawn\"".into() });
+}
+
+#[test]
+fn cl_verify_full_chain_refuses_forged_contexts() {
+	let chain = withdrawals_chain();
+	let genesis = &chain[0];
+	let verify = |blocks: &[Block<_, Withdrawals>]| genesis.verify_full_chain(&100, &genesis.header.consensus_digest, blocks);
+
+	// The header says height 1, but the body would execute as if far down the chain.
+	let mut far_ahead = chain.clone();
+	far_ahead[1].context.height = 999_999;
+	let failure = verify(&far_ahead[1..]).unwrap_err();
+	assert_eq!((failure.position, failure.error), (0, BlockVerificationError::ContextMismatch));
+	assert!(!genesis.verify_sub_chain(&100, &far_ahead));
+
+	let mut other_parent = chain.clone();
+	other_parent[2].context.parent_hash = hash(&genesis.header);
+	let failure = verify(&other_parent[1..]).unwrap_err();
+	assert_eq!((failure.position, failure.error), (1, BlockVerificationError::ContextMismatch));
+
+	// So a valid block's body draws the randomness its header commits to.
+	assert_eq!(chain[2].context.random_seed(), chain[2].random_seed());
+}
+
+#[test]
+fn cl_empty_blocks_at_the_fork_height_migrate_the_state() {
+	use crate::c1_state_machine::upgrade::{Migrate, Upgrade, Versioned};
+	use crate::c3_consensus::p1_pow::PoW;
+
+	// The new rules are the same as the old, but the migration flips the switch on at height 3.
+	struct FlipAtThree;
+	impl Migrate<LightSwitch, LightSwitch> for FlipAtThree {
+		const FORK_HEIGHT: u64 = 3;
+
+		fn migrate(state: &bool) -> bool {
+			!state
+		}
+	}
+	type Upgraded = Upgrade<LightSwitch, LightSwitch, FlipAtThree>;
+
+	// Blocks 3 and 4 are empty, so only the migration changes the state.
+	let chain = create_empty_chain::<PoW, Upgraded>(5, ()).unwrap();
+	let genesis = &chain[0];
+	let genesis_state = Upgraded::genesis_state(());
+	assert_eq!(genesis.verify_full_chain(&genesis_state, &genesis.header.consensus_digest, &chain[1..]), Ok(()));
+	assert!(genesis.verify_sub_chain(&genesis_state, &chain));
+
+	let tip_state = chain[1..].iter().fold(genesis_state, |s, b| Upgraded::apply_all_in_context(&s, &b.body, &b.context));
+	assert_eq!(tip_state, Versioned::New(true));
+	assert_eq!(chain[4].header.state_root, hash(&tip_state));
+}
+
+#[test]
+fn cl_verify_full_chain_checks_seals() {
+	let mut chain = withdrawals_chain();
+	let genesis = chain[0].clone();
+	let unsealed = (0..).find(|nonce| {
+		let mut header = chain[3].header.clone();
+		header.consensus_digest = *nonce;
+		!genesis.consensus.validate(&chain[2].header.consensus_digest, &header)
+	});
+	chain[3].header.consensus_digest = unsealed.unwrap();
+
+	let failure = genesis.verify_full_chain(&100, &genesis.header.consensus_digest, &chain[1..]).unwrap_err();
+	assert_eq!((failure.position, failure.error), (2, BlockVerificationError::InvalidSeal));
+	// The state-only check does not notice.
+	assert!(genesis.verify_sub_chain(&100, &chain));
+}
+
+#[test]
+fn cl_verify_full_chain_with_reward_checks_coinbases() {
+	use crate::c1_state_machine::p7_multiasset::{AssetTransaction, MultiAsset, NATIVE_ASSET};
+	use crate::c3_consensus::p1_pow::PoW;
+
+	let genesis_state = MultiAsset::genesis_state(vec![(NATIVE_ASSET, User::Alice, vec![(User::Alice, 100)])]);
+	let genesis = Block::<PoW, MultiAsset>::genesis(&genesis_state);
+	let context = BlockContext { author: Some(User::Charlie), ..BlockContext::default() };
+	let transfer = AssetTransaction::Transfer { asset: NATIVE_ASSET, sender: User::Alice, receiver: User::Bob, amount: 30 };
+	let coinbase = MultiAsset::coinbase(User::Charlie, 10);
+	let verify = |block: Block<PoW, MultiAsset>, reward| {
+		genesis
+			.verify_full_chain_with_reward(&genesis_state, &genesis.header.consensus_digest, &[block], reward)
+			.map_err(|failure| failure.error)
+	};
+	let paid = || genesis.child_with_coinbase(&genesis_state, vec![transfer.clone()], context.clone(), 10).unwrap();
+
+	assert_eq!(verify(paid(), Some(10)), Ok(()));
+	assert_eq!(verify(paid(), Some(9)), Err(BlockVerificationError::CoinbaseMismatch));
+	assert_eq!(verify(paid(), None), Err(BlockVerificationError::CoinbaseMismatch));
+	// The plain check executes the coinbase without asking how much it should have paid.
+	assert_eq!(genesis.verify_full_chain(&genesis_state, &genesis.header.consensus_digest, &[paid()]), Ok(()));
+
+	let overpaid = genesis.child_with_coinbase(&genesis_state, vec![transfer.clone()], context.clone(), 1_000).unwrap();
+	assert_eq!(verify(overpaid, Some(10)), Err(BlockVerificationError::CoinbaseMismatch));
+	let paid_twice = genesis.child(&genesis_state, vec![coinbase.clone(), transfer, coinbase], context).unwrap();
+	assert_eq!(verify(paid_twice, Some(10)), Err(BlockVerificationError::MisplacedCoinbase { index: 2 }));
+}
+
+#[test]
+fn cl_verify_full_chain_refuses_contexts_naming_another_author() {
+	use crate::c3_consensus::ConsensusAuthority;
+
+	// Any authority may seal, and the digest names which one did.
+	struct Signed;
+	impl Consensus for Signed {
+		type Digest = ConsensusAuthority;
+
+		fn validate(&self, _: &ConsensusAuthority, _: &Header<ConsensusAuthority>) -> bool {
+			true
+		}
+
+		fn seal(&self, _: &ConsensusAuthority, _: Header<()>) -> Option<Header<ConsensusAuthority>> {
+			None
+		}
+
+		fn create_default_instance() -> Self {
+			Signed
+		}
+	}
+
+	let genesis = Block::<Signed, Withdrawals> {
+		header: Header { parent: 0, height: 0, state_root: hash(&100u64), extrinsics_root: 0, consensus_digest: ConsensusAuthority::Alice },
+		body: vec![],
+		context: BlockContext::default(),
+		consensus: Signed,
+	};
+	// An empty block sealed by Bob, whose context says who authored it.
+	let sealed_by_bob = |author| Block::<Signed, Withdrawals> {
+		header: Header {
+			parent: hash(&genesis.header),
+			height: 1,
+			state_root: hash(&100u64),
+			extrinsics_root: extrinsics_tree(&[]).root(),
+			consensus_digest: ConsensusAuthority::Bob,
+		},
+		body: vec![],
+		context: BlockContext { height: 1, parent_hash: hash(&genesis.header), author, ..BlockContext::default() },
+		consensus: Signed,
+	};
+	let verify = |author| {
+		genesis
+			.verify_full_chain(&100, &genesis.header.consensus_digest, &[sealed_by_bob(author)])
+			.map_err(|failure| failure.error)
+	};
+
+	assert_eq!(verify(Some(User::Bob)), Ok(()));
+	assert_eq!(verify(None), Ok(()));
+	assert_eq!(verify(Some(User::Charlie)), Err(BlockVerificationError::ContextMismatch));
+	assert_eq!(sealed_by_bob(None).author(), Some(User::Bob));
+}
+
+#[test]
+fn cl_consensus_sees_the_body_when_sealing() {
+	use crate::c3_consensus::p15_max_extrinsics::MaxExtrinsics;
+
+	let genesis = Block::<MaxExtrinsics<()>, LightSwitch> {
+		header: Header { parent: 0, height: 0, state_root: 0, extrinsics_root: 0, consensus_digest: () },
+		body: vec![],
+		context: BlockContext::default(),
+		consensus: MaxExtrinsics { inner: (), max: 2 },
+	};
+
+	let WeightLimitedChild { block: b1, rest, .. } = genesis.child_with_weight_limit(&false, vec![(); 2], 10, BlockContext::default()).unwrap();
+	assert_eq!((b1.body.len(), rest.len()), (2, 0));
+	assert_eq!(
+		genesis.child_with_weight_limit(&false, vec![(); 3], 10, BlockContext::default()).map(drop),
+		Err(BlockBuildError::SealFailed)
+	);
+}
+
+#[test]
+fn cl_consensus_engine_chosen_at_runtime() {
+	use crate::c3_consensus::dynamic::{engine_by_name, BoxedConsensus};
+
+	let genesis_digest = crate::c3_consensus::ConsensusAuthority::Bob.encode();
+	let genesis = Block::<BoxedConsensus, LightSwitch> {
+		header: Header { parent: 0, height: 0, state_root: 0, extrinsics_root: 0, consensus_digest: genesis_digest.clone() },
+		body: vec![],
+		context: BlockContext::default(),
+		consensus: engine_by_name("poa").unwrap(),
+	};
+
+	let b1 = genesis.child_with_weight_limit(&false, vec![()], 10, BlockContext::default()).unwrap().block;
+	assert!(genesis.consensus.validate(&genesis_digest, &b1.header));
+}
+
+#[test]
+fn cl_event_log_collects_events_per_block() {
+	let b1 = Block::<(), AccountedCurrency> {
+		header: Header { parent: 0, height: 1, state_root: 0, extrinsics_root: 0, consensus_digest: () },
+		body: vec![
+			AccountingTransaction::Mint { minter: User::Alice, amount: 100 },
+			AccountingTransaction::Transfer { sender: User::Alice, receiver: User::Bob, amount: 40 },
+		],
+		context: BlockContext::default(),
+		consensus: (),
+	};
+	let b2 = Block::<(), AccountedCurrency> {
+		header: Header { parent: hash(&b1.header), height: 2, state_root: 0, extrinsics_root: 0, consensus_digest: () },
+		body: vec![
+			AccountingTransaction::Transfer { sender: User::Bob, receiver: User::Charlie, amount: 10 },
+		],
+		context: BlockContext::default(),
+		consensus: (),
+	};
+
+	let mut log = EventLog::new();
+	let s1 = log.execute_block(&b1, &HashMap::new());
+	let s2 = log.execute_block(&b2, &s1);
+	assert_eq!(s2, HashMap::from([(User::Alice, 60), (User::Bob, 30), (User::Charlie, 10)]));
+
+	let in_b1 = log.events_in_block(hash(&b1.header));
+	assert_eq!(in_b1.len(), 2);
+	assert_eq!(in_b1[1].transition_index, 1);
+
+	let transfers = log.query(1..=2, |e| matches!(e, CurrencyEvent::Transferred { .. }));
+	assert_eq!(transfers.len(), 2);
+	assert_eq!(transfers[1].event, CurrencyEvent::Transferred { from: User::Bob, to: User::Charlie, amount: 10 });
+
+	log.forget_block(hash(&b2.header));
+	assert!(log.query(2..=2, |_| true).is_empty());
+}
+
+/// Records the context of every block it is executed in.
+#[cfg(test)]
+struct ContextLog;
+
+#[cfg(test)]
+impl StateMachine for ContextLog {
+	type State = Vec<BlockContext>;
+	type Transition = ();
+	type Error = core::convert::Infallible;
+	type GenesisConfig = ();
+
+	fn genesis_state(_: ()) -> Self::State {
+		Vec::new()
+	}
+
+	fn next_state(starting_state: &Self::State, _: &()) -> Self::State {
+		starting_state.clone()
+	}
+}
+
+#[cfg(test)]
+impl ContextualStateMachine for ContextLog {
+	fn next_state_in_context(starting_state: &Self::State, _: &(), context: &BlockContext) -> Self::State {
+		let mut s = starting_state.clone();
+		s.push(context.clone());
+		s
+	}
+
+	fn try_next_state_in_context(
+		starting_state: &Self::State,
+		t: &(),
+		context: &BlockContext,
+	) -> Result<Self::State, Self::Error> {
+		Ok(Self::next_state_in_context(starting_state, t, context))
+	}
+}
+
+#[cfg(test)]
+impl Weighted for ContextLog {
+	fn weight(_: &()) -> u64 {
+		1
+	}
+}
+
+#[test]
+fn cl_blocks_execute_in_their_context() {
+	let genesis = Block::<(), ContextLog> {
+		header: Header { parent: 0, height: 0, state_root: hash(&Vec::<BlockContext>::new()), extrinsics_root: 0, consensus_digest: () },
+		body: vec![],
+		context: BlockContext::default(),
+		consensus: (),
+	};
+	let context = BlockContext { height: 1, timestamp: 6_000, author: Some(User::Alice), parent_hash: hash(&genesis.header) };
+
+	let b1 = genesis.child_with_weight_limit(&Vec::new(), vec![()], 10, context.clone()).unwrap().block;
+	assert_eq!(b1.header.state_root, hash(&vec![context.clone()]));
+
+	let b2 = Block::<(), ContextLog> {
+		header: Header { parent: hash(&b1.header), height: 2, state_root: 0, extrinsics_root: 0, consensus_digest: () },
+		body: vec![],
+		context: BlockContext::default(),
+		consensus: (),
+	};
+	let mut chain = vec![b1, b2];
+	assert!(genesis.verify_sub_chain(&Vec::new(), &chain));
+
+	// Executing the same body in a different context leads to a different state root.
+	chain[0].context.author = Some(User::Bob);
+	assert!(!genesis.verify_sub_chain(&Vec::new(), &chain));
+}
+
+#[test]
+fn cl_child_takes_its_height_from_the_parent() {
+	let genesis = Block::<crate::c3_consensus::p1_pow::PoW, ContextLog>::genesis(&Vec::new());
+	let context = BlockContext { height: 999_999, ..BlockContext::default() };
+
+	let b1 = genesis.child(&Vec::new(), vec![()], context.clone()).unwrap();
+	let expected = BlockContext { height: 1, parent_hash: hash(&genesis.header), ..context.clone() };
+	assert_eq!(b1.context, expected);
+	assert_eq!(b1.header.state_root, hash(&vec![expected]));
+
+	let b2 = b1.child_with_weight_limit(&Vec::new(), vec![], 10, context).unwrap().block;
+	assert_eq!(b2.context.height, 2);
+}
+
+#[test]
+fn cl_execute_currency_block_in_parallel() {
+	let block = Block::<(), AccountedCurrency> {
+		header: Header { parent: 0, height: 1, state_root: 0, extrinsics_root: 0, consensus_digest: () },
+		body: vec![
+			AccountingTransaction::Transfer { sender: User::Alice, receiver: User::Bob, amount: 10 },
+			AccountingTransaction::Mint { minter: User::Charlie, amount: 7 },
+			AccountingTransaction::Transfer { sender: User::Bob, receiver: User::Charlie, amount: 15 },
+		],
+		context: BlockContext::default(),
+		consensus: (),
+	};
+	let pre_state = HashMap::from([(User::Alice, 20), (User::Bob, 5)]);
+
+	assert_eq!(
+		block.execute_parallel(&pre_state),
+		Ok(HashMap::from([(User::Alice, 10), (User::Charlie, 22)]))
+	);
+	assert_eq!(block.execute_parallel(&HashMap::new()), Err(CurrencyError::UnknownAccount(User::Alice)));
+}
+
+#[test]
+fn cl_currency_blocks_execute_through_the_parallel_executor() {
+	let pre_state = HashMap::from([(User::Alice, 20), (User::Bob, 5)]);
+	let body = vec![
+		AccountingTransaction::Transfer { sender: User::Alice, receiver: User::Bob, amount: 10 },
+		AccountingTransaction::Mint { minter: User::Charlie, amount: 7 },
+		AccountingTransaction::Transfer { sender: User::Dave, receiver: User::Charlie, amount: 15 },
+		AccountingTransaction::Burn { burner: User::Bob, amount: 1 },
+	];
+	let context = BlockContext::default();
+
+	// Building and importing find the rejected transition the way sequential execution does.
+	assert_eq!(
+		execute_body::<AccountedCurrency>(&pre_state, &body, &context),
+		Err((2, CurrencyError::UnknownAccount(User::Dave)))
+	);
+	assert_eq!(
+		execute_body::<AccountedCurrency>(&pre_state, &body[..2], &context),
+		Ok(HashMap::from([(User::Alice, 10), (User::Bob, 15), (User::Charlie, 7)]))
+	);
+	// A body the executor rejects runs one transition at a time instead, skipping what fails.
+	assert_eq!(
+		AccountedCurrency::apply_all_in_context(&pre_state, &body, &context),
+		AccountedCurrency::apply_all(&pre_state, &body)
+	);
+}
+
+#[test]
+fn cl_verify_sub_chain_against_merkle_state_roots() {
+	let pre_state = HashMap::from([(User::Alice, 20)]);
+	let body = vec![AccountingTransaction::Transfer { sender: User::Alice, receiver: User::Bob, amount: 10 }];
+	let post_state = AccountedCurrency::apply_all(&pre_state, &body);
+	let first = Block::<(), AccountedCurrency> {
+		header: Header { parent: 0, height: 1, state_root: AccountedCurrency::state_root(&post_state), extrinsics_root: 0, consensus_digest: () },
+		body,
+		context: BlockContext { height: 1, ..BlockContext::default() },
+		consensus: (),
+	};
+	let second = Block::<(), AccountedCurrency> {
+		header: Header { parent: hash(&first.header), height: 2, state_root: 0, extrinsics_root: 0, consensus_digest: () },
+		body: vec![],
+		context: BlockContext::default(),
+		consensus: (),
+	};
+	let mut chain = vec![first, second];
+
+	assert!(chain[0].verify_sub_chain_merkle(&pre_state, &chain));
+
+	chain[0].header.state_root = AccountedCurrency::state_root(&pre_state);
+	assert!(!chain[0].verify_sub_chain_merkle(&pre_state, &chain));
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn cl_instrumented_machine_records_rejected_blocks() {
+	use crate::c1_state_machine::instrumented::{Instrumented, Registry};
+
+	let block = |parent, height, body| Block::<(), Instrumented<AccountedCurrency>> {
+		header: Header { parent, height, state_root: 0, extrinsics_root: 0, consensus_digest: () },
+		body,
+		context: BlockContext { height, parent_hash: parent, ..BlockContext::default() },
+		consensus: (),
+	};
+	let first = block(0, 1, vec![AccountingTransaction::Burn { burner: User::Charlie, amount: 1 }]);
+	let second = block(hash(&first.header), 2, vec![]);
+	let name = core::any::type_name::<AccountedCurrency>();
+	let before = Registry::global().get(name, "burn");
+
+	let chain = [first, second];
+
+	assert!(!chain[0].verify_sub_chain_merkle(&HashMap::new(), &chain));
+	assert_eq!(Registry::global().get(name, "burn").rejected - before.rejected, 1);
+}
+
+#[test]
+fn cl_default_state_machine_chain() {
+	use crate::c1_state_machine::p7_multiasset::AssetTransaction;
+
+	let genesis_state = DefaultStateMachine::genesis_state(vec![(1, User::Alice, vec![(User::Alice, 10)])]);
+	let body = vec![
+		AssetTransaction::CreateAsset { creator: User::Bob, asset: 2 },
+		AssetTransaction::Mint { issuer: User::Bob, asset: 2, amount: 5 },
+		AssetTransaction::Transfer { asset: 1, sender: User::Alice, receiver: User::Bob, amount: 3 },
+	];
+	let post_state = DefaultStateMachine::apply_all(&genesis_state, &body);
+	let b1 = Block::<(), DefaultStateMachine> {
+		header: Header { parent: 0, height: 1, state_root: DefaultStateMachine::state_root(&post_state), extrinsics_root: 0, consensus_digest: () },
+		body,
+		context: BlockContext { height: 1, ..BlockContext::default() },
+		consensus: (),
+	};
+	let b2 = Block::<(), DefaultStateMachine> {
+		header: Header { parent: hash(&b1.header), height: 2, state_root: 0, extrinsics_root: 0, consensus_digest: () },
+		body: vec![],
+		context: BlockContext::default(),
+		consensus: (),
+	};
+	let chain = [b1, b2];
+
+	assert!(chain[0].verify_sub_chain_merkle(&genesis_state, &chain));
+	assert!(post_state.invariants_hold());
+	assert_eq!(post_state.balance(1, User::Bob), 3);
+}
+
+#[test]
+fn cl_governance_reconfigures_consensus() {
+	use crate::c1_state_machine::p9_governance::{Governance, GovernanceTransaction, Parameter, ParameterChange, VOTING_PERIOD};
+	use crate::c3_consensus::p1_pow::PoW;
+
+	let easier = ParameterChange { parameter: Parameter::PowThreshold, value: u64::MAX / 2 };
+	let block = |height, body| Block::<(), Governance> {
+		header: Header { parent: 0, height, state_root: 0, extrinsics_root: 0, consensus_digest: () },
+		body,
+		context: BlockContext { height, ..BlockContext::default() },
+		consensus: (),
+	};
+	let proposal = block(1, vec![
+		GovernanceTransaction::Propose { proposer: User::Alice, change: easier },
+		GovernanceTransaction::Vote { voter: User::Alice, referendum: 0, aye: true },
+	]);
+	let enactment = block(1 + VOTING_PERIOD, vec![
+		GovernanceTransaction::Close { referendum: 0 },
+		GovernanceTransaction::Enact { referendum: 0 },
+	]);
+
+	let mut log = EventLog::new();
+	let genesis_state = Governance::genesis_state((vec![(User::Alice, 1)], vec![]));
+	let voting = log.execute_block(&proposal, &genesis_state);
+	log.execute_block(&enactment, &voting);
+
+	let mut pow = PoW::new(u64::MAX / 100);
+	let applied = apply_governance_events(&mut pow, log.query(0..=u64::MAX, |_| true).iter().map(|r| &r.event));
+	assert_eq!(applied, 1);
+	assert_eq!(pow.get_threashold(), u64::MAX / 2);
+}
 
 //TODO maybe this shouldn't be a whole chapter. Maybe it is the first
 // section in the chapter on building a client
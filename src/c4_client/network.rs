@@ -0,0 +1,846 @@
+//! A node learns about new blocks and transitions from the nodes it is connected to, its peers,
+//! and tells them about the ones it learns of first. This is gossip: every node relays what is
+//! new to it to every peer that does not have it yet, so news spreads across the whole network
+//! without any node being connected to all the others.
+//!
+//! Nodes talk over TCP. Every message is the codec encoding of a `Message`, prefixed with its
+//! length as a little endian `u32`. The first message each side sends is a handshake naming the
+//! chain's genesis block, and nodes following different chains hang up on each other straight
+//! away. After that, either side may send blocks and transitions at any time.
+//!
+//! Received blocks go into the import queue and received transitions into the transaction pool.
+//! Transitions the pool accepts are relayed at once. Blocks are only relayed once they have been
+//! imported, so invalid blocks do not spread any further than the first node that checks them.
+//!
+//! Gossip only carries what is new. A node that was offline, or has just started, asks a peer
+//! for the blocks it missed instead: `GetHeaders` asks for a stretch of the peer's best chain by
+//! height, and `GetBlocks` for whole blocks by hash. A node that would rather not execute the
+//! whole chain asks for a block along with the state after it with `GetSnapshot`, and starts from
+//! there. Nodes answer these requests while polling, and the `sync` module uses them to catch up.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant};
+
+use super::block_tree::BlockTree;
+use super::import_queue::{BlockImportResult, ImportQueue};
+use super::p3_fork_choice::ForkChoice;
+use super::tx_pool::{PrioritizedTransition, TxPool};
+use super::{Block, Hash, Header};
+use crate::c1_state_machine::{BlockContext, ContextualStateMachine, StateMachine};
+use crate::c3_consensus::Consensus;
+use crate::codec::{Decode, Encode};
+use crate::hash;
+use num::traits::{One, Zero};
+
+/// The largest message a node accepts
+pub const MAX_MESSAGE_LEN: usize = 1 << 24;
+
+/// The most headers a node sends in answer to one `GetHeaders`
+pub const MAX_HEADERS: u64 = 128;
+
+/// The most blocks a node sends in answer to one `GetBlocks`
+pub const MAX_BLOCKS: usize = 128;
+
+/// How long a node waits for a new peer's handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a node waits for a peer to answer a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Identifies a peer for as long as it is connected
+pub type PeerId = u64;
+
+/// What nodes send each other
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Message<Digest, Transition> {
+    /// The first message on every connection, describing the sender's chain
+    Handshake {
+        genesis_hash: Hash,
+        best_hash: Hash,
+        best_height: u64,
+    },
+    /// A new block
+    Block(BlockData<Digest, Transition>),
+    /// A transition waiting to be included in a block
+    Transition(Transition),
+    /// Asks for the headers of up to `count` blocks of the best chain, starting at height `from`
+    GetHeaders { from: u64, count: u64 },
+    /// Answers `GetHeaders`, lowest first. There are fewer than asked for if the best chain ends
+    /// sooner.
+    Headers(Vec<Header<Digest>>),
+    /// Asks for the blocks with the given hashes
+    GetBlocks { hashes: Vec<Hash> },
+    /// Answers `GetBlocks` with the requested blocks the sender has, in the order they were
+    /// asked for
+    Blocks(Vec<BlockData<Digest, Transition>>),
+    /// Asks for the block with the given hash and the state after it
+    GetSnapshot { block_hash: Hash },
+    /// Answers `GetSnapshot`, with nothing if the sender does not have the block's state
+    Snapshot(Option<SnapshotData<Digest, Transition>>),
+}
+
+#[cfg(feature = "tracing")]
+impl<Digest, Transition> Message<Digest, Transition> {
+    /// The name of the variant, to tell messages apart in spans.
+    fn name(&self) -> &'static str {
+        match self {
+            Message::Handshake { .. } => "handshake",
+            Message::Block(_) => "block",
+            Message::Transition(_) => "transition",
+            Message::GetHeaders { .. } => "get_headers",
+            Message::Headers(_) => "headers",
+            Message::GetBlocks { .. } => "get_blocks",
+            Message::Blocks(_) => "blocks",
+            Message::GetSnapshot { .. } => "get_snapshot",
+            Message::Snapshot(_) => "snapshot",
+        }
+    }
+}
+
+/// A block as it travels between nodes. The receiver seals it with its own consensus engine.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockData<Digest, Transition> {
+    pub header: Header<Digest>,
+    pub body: Vec<Transition>,
+    /// The context the body executes in
+    pub context: BlockContext,
+}
+
+impl<Digest: Clone, Transition: Clone> BlockData<Digest, Transition> {
+    pub(super) fn of<C: Consensus<Digest = Digest>, SM: StateMachine<Transition = Transition>>(
+        block: &Block<C, SM>,
+    ) -> Self {
+        BlockData {
+            header: block.header.clone(),
+            body: block.body.clone(),
+            context: block.context.clone(),
+        }
+    }
+
+    pub(super) fn into_block<C, SM>(self) -> Block<C, SM>
+    where
+        C: Consensus<Digest = Digest>,
+        SM: StateMachine<Transition = Transition>,
+    {
+        Block {
+            header: self.header,
+            body: self.body,
+            context: self.context,
+            consensus: C::create_default_instance(),
+        }
+    }
+}
+
+/// A block and the state after it, as it travels between nodes. Messages are not generic over
+/// the state machine's state, so the state travels encoded, and the receiver decodes it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotData<Digest, Transition> {
+    pub block: BlockData<Digest, Transition>,
+    pub state: Vec<u8>,
+}
+
+/// The fields in the order they are declared.
+impl<Digest: Encode, Transition: Encode> Encode for BlockData<Digest, Transition> {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.header.encode_to(dest);
+        self.body.encode_to(dest);
+        self.context.encode_to(dest);
+    }
+}
+
+impl<Digest: Decode, Transition: Decode> Decode for BlockData<Digest, Transition> {
+    fn decode_from(input: &mut &[u8]) -> Option<Self> {
+        Some(BlockData {
+            header: Header::decode_from(input)?,
+            body: Vec::decode_from(input)?,
+            context: BlockContext::decode_from(input)?,
+        })
+    }
+}
+
+/// The fields in the order they are declared.
+impl<Digest: Encode, Transition: Encode> Encode for SnapshotData<Digest, Transition> {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.block.encode_to(dest);
+        self.state.encode_to(dest);
+    }
+}
+
+impl<Digest: Decode, Transition: Decode> Decode for SnapshotData<Digest, Transition> {
+    fn decode_from(input: &mut &[u8]) -> Option<Self> {
+        Some(SnapshotData {
+            block: BlockData::decode_from(input)?,
+            state: Vec::decode_from(input)?,
+        })
+    }
+}
+
+/// A tag byte for the variant, followed by its fields in the order they are declared.
+impl<Digest: Encode, Transition: Encode> Encode for Message<Digest, Transition> {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        match self {
+            Message::Handshake {
+                genesis_hash,
+                best_hash,
+                best_height,
+            } => {
+                dest.push(0);
+                genesis_hash.encode_to(dest);
+                best_hash.encode_to(dest);
+                best_height.encode_to(dest);
+            }
+            Message::Block(block) => {
+                dest.push(1);
+                block.encode_to(dest);
+            }
+            Message::Transition(t) => {
+                dest.push(2);
+                t.encode_to(dest);
+            }
+            Message::GetHeaders { from, count } => {
+                dest.push(3);
+                from.encode_to(dest);
+                count.encode_to(dest);
+            }
+            Message::Headers(headers) => {
+                dest.push(4);
+                headers.encode_to(dest);
+            }
+            Message::GetBlocks { hashes } => {
+                dest.push(5);
+                hashes.encode_to(dest);
+            }
+            Message::Blocks(blocks) => {
+                dest.push(6);
+                blocks.encode_to(dest);
+            }
+            Message::GetSnapshot { block_hash } => {
+                dest.push(7);
+                block_hash.encode_to(dest);
+            }
+            Message::Snapshot(snapshot) => {
+                dest.push(8);
+                snapshot.encode_to(dest);
+            }
+        }
+    }
+}
+
+impl<Digest: Decode, Transition: Decode> Decode for Message<Digest, Transition> {
+    fn decode_from(input: &mut &[u8]) -> Option<Self> {
+        match u8::decode_from(input)? {
+            0 => Some(Message::Handshake {
+                genesis_hash: Hash::decode_from(input)?,
+                best_hash: Hash::decode_from(input)?,
+                best_height: u64::decode_from(input)?,
+            }),
+            1 => Some(Message::Block(BlockData::decode_from(input)?)),
+            2 => Some(Message::Transition(Transition::decode_from(input)?)),
+            3 => Some(Message::GetHeaders {
+                from: u64::decode_from(input)?,
+                count: u64::decode_from(input)?,
+            }),
+            4 => Some(Message::Headers(Vec::decode_from(input)?)),
+            5 => Some(Message::GetBlocks {
+                hashes: Vec::decode_from(input)?,
+            }),
+            6 => Some(Message::Blocks(Vec::decode_from(input)?)),
+            7 => Some(Message::GetSnapshot {
+                block_hash: Hash::decode_from(input)?,
+            }),
+            8 => Some(Message::Snapshot(Option::decode_from(input)?)),
+            _ => None,
+        }
+    }
+}
+
+/// The ways talking to a peer can fail
+#[derive(Debug)]
+pub enum NetworkError {
+    /// The connection failed
+    Io(std::io::Error),
+    /// The peer sent bytes that do not decode to a message
+    Malformed,
+    /// The peer announced a message longer than `MAX_MESSAGE_LEN`
+    TooLong(usize),
+    /// The peer follows a chain with another genesis block
+    GenesisMismatch { ours: Hash, theirs: Hash },
+    /// The peer's first message was not a handshake
+    NoHandshake,
+    /// No peer with the given id is connected
+    UnknownPeer(PeerId),
+    /// The peer hung up before answering a request
+    Disconnected,
+    /// The peer did not answer a request in time
+    Timeout,
+}
+
+impl From<std::io::Error> for NetworkError {
+    fn from(e: std::io::Error) -> Self {
+        NetworkError::Io(e)
+    }
+}
+
+/// Write one length-prefixed message.
+pub fn write_message<D: Encode, T: Encode>(
+    mut stream: &TcpStream,
+    message: &Message<D, T>,
+) -> Result<(), NetworkError> {
+    let encoded = message.encode();
+    if encoded.len() > MAX_MESSAGE_LEN {
+        return Err(NetworkError::TooLong(encoded.len()));
+    }
+    let mut bytes = (encoded.len() as u32).to_le_bytes().to_vec();
+    bytes.extend(encoded);
+    stream.write_all(&bytes)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Read one length-prefixed message.
+pub fn read_message<D: Decode, T: Decode>(
+    reader: &mut impl Read,
+) -> Result<Message<D, T>, NetworkError> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(NetworkError::TooLong(len));
+    }
+    let mut encoded = vec![0; len];
+    reader.read_exact(&mut encoded)?;
+    Message::decode(&encoded).ok_or(NetworkError::Malformed)
+}
+
+/// What a peer said about its chain when it connected
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PeerInfo {
+    pub addr: SocketAddr,
+    pub best_hash: Hash,
+    pub best_height: u64,
+}
+
+struct Peer {
+    info: PeerInfo,
+    stream: TcpStream,
+    /// The blocks and transitions the peer is known to have, because it sent them to us or we
+    /// sent them to it
+    known: HashSet<Hash>,
+}
+
+/// A message from a peer's connection, or `None` once the connection is gone
+type Incoming<D, T> = (PeerId, Option<Message<D, T>>);
+
+/// A node's connections to its peers
+pub struct Network<C: Consensus, SM: StateMachine> {
+    genesis_hash: Hash,
+    peers: HashMap<PeerId, Peer>,
+    next_peer: PeerId,
+    /// Every peer's reader thread sends what it reads here
+    incoming: Receiver<Incoming<C::Digest, SM::Transition>>,
+    sender: Sender<Incoming<C::Digest, SM::Transition>>,
+    /// Messages received while waiting for the answer to a request, to be handled by the next
+    /// poll
+    deferred: VecDeque<Incoming<C::Digest, SM::Transition>>,
+}
+
+impl<C, SM> Network<C, SM>
+where
+    C: Consensus,
+    C::Digest: Zero + One + core::hash::Hash + Encode + Decode + Send + 'static,
+    SM: ContextualStateMachine,
+    SM::State: core::hash::Hash + Clone,
+    SM::Transition: core::hash::Hash + Encode + Decode + Clone + Send + 'static,
+{
+    /// A node with no peers yet, following the chain with the given genesis block.
+    pub fn new(genesis_hash: Hash) -> Self {
+        let (sender, incoming) = mpsc::channel();
+        Network {
+            genesis_hash,
+            peers: HashMap::new(),
+            next_peer: 0,
+            incoming,
+            sender,
+            deferred: VecDeque::new(),
+        }
+    }
+
+    /// The connected peers, and what they said about their chain when they connected.
+    pub fn peers(&self) -> impl Iterator<Item = (PeerId, &PeerInfo)> {
+        self.peers.iter().map(|(id, peer)| (*id, &peer.info))
+    }
+
+    /// What the given peer said about its chain when it connected.
+    pub fn peer(&self, peer: PeerId) -> Option<&PeerInfo> {
+        self.peers.get(&peer).map(|p| &p.info)
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// Connect to the node at the given address, and exchange handshakes with it.
+    pub fn connect<FC: ForkChoice<C::Digest>>(
+        &mut self,
+        addr: impl ToSocketAddrs,
+        tree: &BlockTree<C, SM, FC>,
+    ) -> Result<PeerId, NetworkError> {
+        let stream = TcpStream::connect(addr)?;
+        self.handshake(stream, tree)
+    }
+
+    /// Accept a connection from another node on the given listener, and exchange handshakes with
+    /// it. If the listener is nonblocking and nobody is connecting, this fails with an
+    /// `ErrorKind::WouldBlock` error.
+    pub fn accept<FC: ForkChoice<C::Digest>>(
+        &mut self,
+        listener: &TcpListener,
+        tree: &BlockTree<C, SM, FC>,
+    ) -> Result<PeerId, NetworkError> {
+        let (stream, _) = listener.accept()?;
+        stream.set_nonblocking(false)?;
+        self.handshake(stream, tree)
+    }
+
+    /// Hang up on the given peer.
+    pub fn disconnect(&mut self, peer: PeerId) {
+        if let Some(peer) = self.peers.remove(&peer) {
+            let _ = peer.stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    /// Send our handshake on a new connection and check the peer's, then start reading its
+    /// messages on a thread of their own.
+    fn handshake<FC: ForkChoice<C::Digest>>(
+        &mut self,
+        stream: TcpStream,
+        tree: &BlockTree<C, SM, FC>,
+    ) -> Result<PeerId, NetworkError> {
+        let ours = Message::<C::Digest, SM::Transition>::Handshake {
+            genesis_hash: self.genesis_hash,
+            best_hash: tree.best_head(),
+            best_height: tree.best_height(),
+        };
+        write_message(&stream, &ours)?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let Message::Handshake {
+            genesis_hash,
+            best_hash,
+            best_height,
+        } = read_message::<C::Digest, SM::Transition>(&mut reader)?
+        else {
+            return Err(NetworkError::NoHandshake);
+        };
+        if genesis_hash != self.genesis_hash {
+            let _ = stream.shutdown(Shutdown::Both);
+            return Err(NetworkError::GenesisMismatch {
+                ours: self.genesis_hash,
+                theirs: genesis_hash,
+            });
+        }
+        stream.set_read_timeout(None)?;
+
+        let id = self.next_peer;
+        self.next_peer += 1;
+        let sender = self.sender.clone();
+        std::thread::spawn(move || {
+            while let Ok(message) = read_message(&mut reader) {
+                if sender.send((id, Some(message))).is_err() {
+                    // The network is gone.
+                    return;
+                }
+            }
+            // The connection is broken, or the peer sent something that is not a message.
+            let _ = sender.send((id, None));
+        });
+        let info = PeerInfo {
+            addr: stream.peer_addr()?,
+            best_hash,
+            best_height,
+        };
+        self.peers.insert(
+            id,
+            Peer {
+                info,
+                stream,
+                known: HashSet::from([best_hash]),
+            },
+        );
+        Ok(id)
+    }
+
+    /// Handle every message received since the last poll, without waiting for more. Blocks go
+    /// into the import queue, and transitions into the pool, which checks them against the
+    /// tree's best state. Transitions the pool accepts are relayed to the other peers, and
+    /// requests are answered from the tree. Snapshots are only given of blocks the tree has the
+    /// body of, since it cannot rebuild the state after any other. Peers that hung up or sent something that is not a
+    /// message are forgotten, as are peers sending a second handshake. Returns how many messages
+    /// were handled.
+    pub fn poll<FC: ForkChoice<C::Digest>>(
+        &mut self,
+        tree: &BlockTree<C, SM, FC>,
+        queue: &mut ImportQueue<C, SM>,
+        pool: &mut TxPool<SM>,
+    ) -> usize
+    where
+        SM::State: Encode,
+        SM::Transition: PrioritizedTransition,
+    {
+        let mut handled = 0;
+        while let Some((id, message)) = self
+            .deferred
+            .pop_front()
+            .or_else(|| self.incoming.try_recv().ok())
+        {
+            let Some(peer) = self.peers.get_mut(&id) else {
+                continue;
+            };
+            handled += 1;
+            #[cfg(feature = "tracing")]
+            let _span = tracing::debug_span!(
+                "handle",
+                peer = id,
+                message = message.as_ref().map_or("disconnected", Message::name)
+            )
+            .entered();
+            match message {
+                Some(Message::Block(block)) => {
+                    peer.known.insert(hash(&block.header));
+                    queue.push(block.into_block());
+                }
+                Some(Message::Transition(t)) => {
+                    peer.known.insert(hash(&t));
+                    if pool.submit(t.clone(), tree.best_state()).is_ok() {
+                        self.broadcast(hash(&t), Message::Transition(t));
+                    }
+                }
+                Some(Message::GetHeaders { from, count }) => {
+                    let headers = best_chain_headers(tree, from, count.min(MAX_HEADERS));
+                    self.send(id, &Message::Headers(headers));
+                }
+                Some(Message::GetBlocks { hashes }) => {
+                    let blocks = hashes
+                        .iter()
+                        .take(MAX_BLOCKS)
+                        .filter_map(|h| tree.block(*h).map(BlockData::of))
+                        .collect();
+                    self.send(id, &Message::Blocks(blocks));
+                }
+                Some(Message::GetSnapshot { block_hash }) => {
+                    let snapshot = tree.block(block_hash).and_then(|block| {
+                        Some(SnapshotData {
+                            block: BlockData::of(block),
+                            state: tree.state_at(block_hash)?.encode(),
+                        })
+                    });
+                    self.send(id, &Message::Snapshot(snapshot));
+                }
+                // Answers that arrived after their request timed out.
+                Some(Message::Headers(_) | Message::Blocks(_) | Message::Snapshot(_)) => {}
+                Some(Message::Handshake { .. }) | None => self.disconnect(id),
+            }
+        }
+        handled
+    }
+
+    /// Send a request to the given peer and wait for its answer. Whatever else arrives in the
+    /// meantime is left for the next poll.
+    pub(super) fn request(
+        &mut self,
+        peer: PeerId,
+        request: &Message<C::Digest, SM::Transition>,
+    ) -> Result<Message<C::Digest, SM::Transition>, NetworkError> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("request", peer, message = request.name()).entered();
+        let stream = &self
+            .peers
+            .get(&peer)
+            .ok_or(NetworkError::UnknownPeer(peer))?
+            .stream;
+        if let Err(e) = write_message(stream, request) {
+            self.disconnect(peer);
+            return Err(e);
+        }
+        let deadline = Instant::now() + REQUEST_TIMEOUT;
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.incoming.recv_timeout(timeout) {
+                Ok((
+                    id,
+                    Some(
+                        answer @ (Message::Headers(_) | Message::Blocks(_) | Message::Snapshot(_)),
+                    ),
+                )) if id == peer => return Ok(answer),
+                Ok((id, None)) if id == peer => {
+                    self.disconnect(peer);
+                    return Err(NetworkError::Disconnected);
+                }
+                Ok(other) => self.deferred.push_back(other),
+                Err(_) => return Err(NetworkError::Timeout),
+            }
+        }
+    }
+
+    /// Send the given block in the tree to every peer that does not have it yet. Returns how
+    /// many peers it was sent to.
+    pub fn announce<FC>(&mut self, tree: &BlockTree<C, SM, FC>, block_hash: Hash) -> usize {
+        let Some(block) = tree.block(block_hash) else {
+            return 0;
+        };
+        self.broadcast(block_hash, Message::Block(BlockData::of(block)))
+    }
+
+    /// Relay the blocks an import queue imported to every peer that does not have them yet.
+    pub fn announce_imported<FC>(
+        &mut self,
+        tree: &BlockTree<C, SM, FC>,
+        results: &[BlockImportResult],
+    ) {
+        for result in results.iter().filter(|r| r.result.is_ok()) {
+            self.announce(tree, result.block_hash);
+        }
+    }
+
+    /// Send the given transition to every peer that does not have it yet. Returns how many
+    /// peers it was sent to.
+    pub fn announce_transition(&mut self, t: SM::Transition) -> usize {
+        self.broadcast(hash(&t), Message::Transition(t))
+    }
+
+    /// Send a message to the given peer, forgetting it if the message cannot be sent.
+    fn send(&mut self, peer: PeerId, message: &Message<C::Digest, SM::Transition>) {
+        let sent = self
+            .peers
+            .get(&peer)
+            .is_some_and(|p| write_message(&p.stream, message).is_ok());
+        if !sent {
+            self.disconnect(peer);
+        }
+    }
+
+    /// Send a message about the item with the given hash to every peer not known to have it.
+    /// Peers the message cannot be sent to are forgotten.
+    fn broadcast(&mut self, item: Hash, message: Message<C::Digest, SM::Transition>) -> usize {
+        let mut sent = 0;
+        let mut broken = Vec::new();
+        for (id, peer) in self.peers.iter_mut() {
+            if !peer.known.insert(item) {
+                continue;
+            }
+            match write_message(&peer.stream, &message) {
+                Ok(()) => sent += 1,
+                Err(_) => broken.push(*id),
+            }
+        }
+        for id in broken {
+            self.disconnect(id);
+        }
+        sent
+    }
+}
+
+/// The headers of up to `count` blocks of the tree's best chain, starting at height `from`.
+fn best_chain_headers<C, SM, FC>(
+    tree: &BlockTree<C, SM, FC>,
+    from: u64,
+    count: u64,
+) -> Vec<Header<C::Digest>>
+where
+    C: Consensus,
+    C::Digest: Zero + One + core::hash::Hash,
+    SM: ContextualStateMachine,
+    SM::State: core::hash::Hash + Clone,
+    SM::Transition: core::hash::Hash + Encode + Clone,
+    FC: ForkChoice<C::Digest>,
+{
+    let chain = tree.best_chain();
+    let root_height = tree
+        .header(chain[0])
+        .expect("the root is in the tree")
+        .height;
+    // Blocks below the root have been pruned.
+    let Some(skip) = from.checked_sub(root_height) else {
+        return Vec::new();
+    };
+    chain
+        .iter()
+        .skip(skip as usize)
+        .take(count as usize)
+        .filter_map(|h| tree.header(*h).cloned())
+        .collect()
+}
+
+/// Hanging up on every peer also stops their reader threads.
+impl<C: Consensus, SM: StateMachine> Drop for Network<C, SM> {
+    fn drop(&mut self) {
+        for peer in self.peers.values() {
+            let _ = peer.stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+#[cfg(test)]
+use super::p3_fork_choice::LongestChain;
+#[cfg(test)]
+use super::Withdrawals;
+#[cfg(test)]
+use crate::c3_consensus::p1_pow::PoW;
+
+#[cfg(test)]
+type TestTree = BlockTree<PoW, Withdrawals, LongestChain>;
+
+/// A node of the test chain, withdrawing from a balance of 100
+#[cfg(test)]
+pub(super) struct TestNode {
+    pub(super) tree: TestTree,
+    pub(super) queue: ImportQueue<PoW, Withdrawals>,
+    pub(super) pool: TxPool<Withdrawals>,
+    pub(super) network: Network<PoW, Withdrawals>,
+}
+
+#[cfg(test)]
+impl TestNode {
+    pub(super) fn new(genesis_state: u64) -> Self {
+        let genesis = Block::<PoW, Withdrawals>::genesis(&genesis_state);
+        TestNode {
+            tree: BlockTree::new(genesis.consensus.clone(), LongestChain, genesis_state),
+            queue: ImportQueue::new(16),
+            pool: TxPool::new(),
+            network: Network::new(hash(&genesis.header)),
+        }
+    }
+
+    /// Poll until at least one message arrives, then import whatever was received and relay
+    /// what was imported.
+    fn receive(&mut self) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while self
+            .network
+            .poll(&self.tree, &mut self.queue, &mut self.pool)
+            == 0
+        {
+            assert!(Instant::now() < deadline, "nothing received");
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        let results = self.queue.import_all(&mut self.tree);
+        self.network.announce_imported(&self.tree, &results);
+    }
+}
+
+#[test]
+fn cl_network_messages_round_trip() {
+    let block = BlockData {
+        header: Block::<PoW, Withdrawals>::genesis(&100).header,
+        body: vec![10, 20],
+        context: BlockContext {
+            height: 1,
+            author: Some(crate::c1_state_machine::User::Bob),
+            ..BlockContext::default()
+        },
+    };
+    let message = Message::<u64, u64>::Block(block.clone());
+    assert_eq!(Message::decode(&message.encode()), Some(message));
+    let request = Message::<u64, u64>::GetBlocks { hashes: vec![1, 2] };
+    assert_eq!(Message::decode(&request.encode()), Some(request));
+    let snapshot = Message::<u64, u64>::Snapshot(Some(SnapshotData {
+        block,
+        state: 70u64.encode(),
+    }));
+    assert_eq!(Message::decode(&snapshot.encode()), Some(snapshot));
+
+    let mut framed = Vec::new();
+    framed.extend_from_slice(&u32::MAX.to_le_bytes());
+    assert!(matches!(
+        read_message::<u64, u64>(&mut framed.as_slice()),
+        Err(NetworkError::TooLong(_))
+    ));
+    let mut framed = 1u32.to_le_bytes().to_vec();
+    framed.push(7);
+    assert!(matches!(
+        read_message::<u64, u64>(&mut framed.as_slice()),
+        Err(NetworkError::Malformed)
+    ));
+}
+
+#[test]
+fn cl_network_gossips_blocks_and_transitions() {
+    // A line of three nodes: alice - bob - carol.
+    let mut alice = TestNode::new(100);
+    let mut bob = TestNode::new(100);
+    let mut carol = TestNode::new(100);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::scope(|scope| {
+        let accepting = scope.spawn(|| {
+            let first = bob.network.accept(&listener, &bob.tree).unwrap();
+            let second = bob.network.accept(&listener, &bob.tree).unwrap();
+            assert_ne!(first, second);
+        });
+        alice.network.connect(addr, &alice.tree).unwrap();
+        carol.network.connect(addr, &carol.tree).unwrap();
+        accepting.join().unwrap();
+    });
+    assert_eq!(bob.network.len(), 2);
+    let genesis_hash = alice.tree.best_head();
+    assert!(bob
+        .network
+        .peers()
+        .all(|(_, info)| info.best_hash == genesis_hash && info.best_height == 0));
+
+    // Alice authors a block. Bob imports it and relays it to Carol, but not back to Alice.
+    let genesis = Block::<PoW, Withdrawals>::genesis(&100);
+    let context = BlockContext {
+        height: 1,
+        ..BlockContext::default()
+    };
+    let block = genesis.child(&100, vec![30], context).unwrap();
+    let block_hash = hash(&block.header);
+    alice.tree.import(block).unwrap();
+    assert_eq!(alice.network.announce(&alice.tree, block_hash), 1);
+    assert_eq!(alice.network.announce(&alice.tree, block_hash), 0);
+    bob.receive();
+    assert_eq!(bob.tree.best_head(), block_hash);
+    carol.receive();
+    assert_eq!(carol.tree.best_state(), &70);
+
+    // Carol's transition reaches Alice through Bob's pool.
+    assert_eq!(carol.network.announce_transition(50), 1);
+    bob.receive();
+    assert!(bob.pool.contains(&50));
+    alice.receive();
+    assert!(alice.pool.contains(&50));
+
+    // Dropping Carol's network hangs up on Bob.
+    drop(carol);
+    bob.receive();
+    assert_eq!(bob.network.len(), 1);
+}
+
+#[test]
+fn cl_network_refuses_peers_on_other_chains() {
+    let mut ours = TestNode::new(100);
+    let mut theirs = TestNode::new(200);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::scope(|scope| {
+        let accepting = scope.spawn(|| ours.network.accept(&listener, &ours.tree));
+        let connecting = theirs.network.connect(addr, &theirs.tree);
+        assert!(matches!(
+            connecting,
+            Err(NetworkError::GenesisMismatch { .. })
+        ));
+        assert!(matches!(
+            accepting.join().unwrap(),
+            Err(NetworkError::GenesisMismatch { .. })
+        ));
+    });
+    assert!(ours.network.is_empty() && theirs.network.is_empty());
+}
@@ -0,0 +1,1093 @@
+//! Every part of a node is library code: the block tree, the pool, the network, the RPC server and
+//! the database. The node ties them together into something that can actually run a chain, and
+//! the `blockchain-node` binary puts it on the command line with four subcommands:
+//!
+//! - `run` follows the chain a spec file describes. It syncs from the spec's boot nodes and any
+//!   peers it is given, gossips with whoever connects, answers RPC requests, pushes new heads,
+//!   finalized heads and transition statuses to WebSocket subscribers, stores blocks in its
+//!   database, and authors a block every so often if it is told who it authors as. It keeps
+//!   every block unless it is told to run pruned, keeping the bodies of only the last few. A
+//!   new node can warp sync, starting from the latest checkpoint in the spec rather than from
+//!   genesis. A node with a database keeps the transitions waiting in its pool there when it
+//!   stops, and reloads those that are still valid, and no older than a maximum age, when it
+//!   starts again. Transitions found invalid are banned from its pool for a while, so that
+//!   nodes and users sending the same junk again are turned away without checking it.
+//! - `submit` sends a JSON-encoded transition to a running node over RPC.
+//! - `inspect` prints the header and body of a block in a node's database, by hash or by height
+//!   on the best chain.
+//! - `export-chain` writes the best chain in a node's database to an export file, and
+//!   `import-chain` imports the chain in an export file into a node's database.
+//! - `check-db` checks every block in a node's database, recomputing its hash, extrinsics root
+//!   and state root, and `repair-db` truncates the database back to the last block before the
+//!   first corrupt one.
+//! - `purge-db` deletes a node's database, so that it starts from genesis next time.
+//!
+//! The node runs the client's default state machine. The block tree needs integer consensus
+//! digests, so it only runs proof-of-work chains.
+//!
+//! The database and RPC speak JSON, so the node requires the `serde` feature.
+//!
+//! Built with the `tracing` feature, the binary logs spans around sealing, executing and
+//! importing blocks and handling network messages to stderr, with their durations, at the level
+//! `RUST_LOG` asks for. `RUST_LOG=diy_blockchain=debug` shows them all. At the `warn` level, the
+//! node also reports authors who authored two blocks at the same height, on whichever forks they
+//! are.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use super::author::AuthorError;
+use super::block_tree::{BlockTree, HeadChange, HistoryMode};
+use super::chain_spec::{ChainSpec, ChainSpecError};
+use super::db::{ChainDb, DbCheck, DbError};
+use super::equivocation_monitor::{AuthorEquivocation, EquivocationMonitor};
+use super::export::{self, ExportError};
+use super::import_queue::ImportQueue;
+use super::network::Network;
+use super::p3_fork_choice::LongestChain;
+use super::pool_store::PoolStore;
+use super::rpc::{self, RpcClientError, RpcHandler};
+use super::subscriptions::Subscriptions;
+use super::tx_pool::TxPool;
+use super::tx_status::TxStatuses;
+use super::{BlockBuildError, DefaultStateMachine, Hash};
+use crate::c1_state_machine::{BlockContext, StateMachine, User};
+use crate::c3_consensus::p1_pow::PoW;
+use crate::c3_consensus::spec::SpecError;
+use crate::c3_consensus::{CancelToken, SystemClock, TimeProvider};
+use crate::hash;
+
+/// The consensus engine the node runs
+pub type NodeConsensus = PoW;
+
+/// The state machine the node runs
+pub type NodeStateMachine = DefaultStateMachine;
+
+type NodeTree = BlockTree<NodeConsensus, NodeStateMachine, LongestChain>;
+type NodeDb = ChainDb<NodeConsensus, NodeStateMachine>;
+type NodePool = TxPool<NodeStateMachine>;
+
+/// How to use the binary, printed when the command line does not make sense
+pub const USAGE: &str = "\
+Usage: blockchain-node <command> [options]
+
+Commands:
+  run --spec <file> [--db <dir>] [--rpc <addr>] [--ws <addr>] [--listen <addr>]
+      [--peer <addr>]... [--author <name>] [--block-time <ms>] [--pruned <blocks>] [--sync full|warp]
+      [--pool-max-age <seconds>] [--ban-window <seconds>]
+      Follow the chain the spec describes, authoring blocks if an author is given. A pruned
+      node keeps the bodies of its best block and the given number before it only. A node
+      that warp syncs starts from the state after the spec's latest checkpoint. On restart,
+      transitions that waited in the pool longer than the maximum age are not reloaded.
+      Transitions found invalid are turned away for the ban window; 0 bans nothing.
+  submit --rpc <addr> <transition>
+      Submit a JSON-encoded transition to the node answering RPC requests at the address.
+  inspect --spec <file> --db <dir> (--hash <hash> | --height <height>)
+      Print the header and body of a block in the database.
+  export-chain --spec <file> --db <dir> [--snapshots <interval>] <export>
+      Write the best chain in the database to a file, with the state every so many blocks.
+  import-chain --spec <file> --db <dir> <export>
+      Import the chain in a file into the database.
+  check-db --spec <file> --db <dir>
+      Check every block in the database, and report those that are corrupt.
+  repair-db --spec <file> --db <dir>
+      Check the database, then truncate it back to the blocks before the first corrupt one.
+  purge-db --db <dir>
+      Delete the database.";
+
+/// How long an author waits between blocks unless told otherwise
+pub const DEFAULT_BLOCK_TIME: Duration = Duration::from_secs(6);
+
+/// How long a transition may have waited in the pool and still be reloaded on restart, unless
+/// told otherwise
+pub const DEFAULT_POOL_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// How long the pool turns away transitions found invalid, unless told otherwise
+pub const DEFAULT_BAN_WINDOW: Duration = Duration::from_secs(30);
+
+/// How long the node sleeps when there is nothing to do
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How many blocks received from peers may wait for import at once
+const QUEUE_CAPACITY: usize = 1024;
+
+/// The most transitions an authored block includes
+const MAX_BLOCK_TRANSITIONS: usize = 1024;
+
+/// A block, named by its hash or by its height on the best chain
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockRef {
+    Hash(Hash),
+    Height(u64),
+}
+
+/// How a node that only has the genesis block catches up with its peers
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Download and execute every block since genesis
+    #[default]
+    Full,
+    /// Download the state after the latest checkpoint in the spec, and only execute the blocks
+    /// after it
+    Warp,
+}
+
+/// How to run a node
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RunOptions {
+    /// The spec file of the chain to follow
+    pub spec: PathBuf,
+    /// Where to store blocks. Without a database the node starts from genesis every time.
+    pub db: Option<PathBuf>,
+    /// Where to answer RPC requests, if anywhere
+    pub rpc: Option<String>,
+    /// Where to accept WebSocket subscriptions, if anywhere. Other RPC requests are answered
+    /// there too.
+    pub ws: Option<String>,
+    /// Where to accept connections from other nodes, if anywhere
+    pub listen: Option<String>,
+    /// Nodes to connect to besides the spec's boot nodes
+    pub peers: Vec<String>,
+    /// Who the node authors blocks as. A node with no author only follows the chain.
+    pub author: Option<User>,
+    /// How long to wait between authoring blocks
+    pub block_time: Duration,
+    /// How much history to keep, in memory and in the database
+    pub history: HistoryMode,
+    /// How to catch up when the node only has the genesis block
+    pub sync: SyncMode,
+    /// How long a transition may have waited in the pool and still be reloaded when the node
+    /// restarts. Only a node with a database keeps its pool.
+    pub pool_max_age: Duration,
+    /// How long the pool turns away transitions found invalid, without checking them again
+    pub ban_window: Duration,
+}
+
+impl RunOptions {
+    /// Follow the chain the given spec describes, with nothing else configured.
+    pub fn new(spec: impl Into<PathBuf>) -> Self {
+        RunOptions {
+            spec: spec.into(),
+            db: None,
+            rpc: None,
+            ws: None,
+            listen: None,
+            peers: Vec::new(),
+            author: None,
+            block_time: DEFAULT_BLOCK_TIME,
+            history: HistoryMode::Archive,
+            sync: SyncMode::Full,
+            pool_max_age: DEFAULT_POOL_MAX_AGE,
+            ban_window: DEFAULT_BAN_WINDOW,
+        }
+    }
+}
+
+/// What the binary was asked to do
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Run(RunOptions),
+    Submit {
+        rpc: String,
+        transition: String,
+    },
+    Inspect {
+        spec: PathBuf,
+        db: PathBuf,
+        block: BlockRef,
+    },
+    ExportChain {
+        spec: PathBuf,
+        db: PathBuf,
+        file: PathBuf,
+        snapshot_interval: Option<u64>,
+    },
+    ImportChain {
+        spec: PathBuf,
+        db: PathBuf,
+        file: PathBuf,
+    },
+    CheckDb {
+        spec: PathBuf,
+        db: PathBuf,
+        repair: bool,
+    },
+    PurgeDb {
+        db: PathBuf,
+    },
+}
+
+/// The ways running a command can fail
+#[derive(Debug)]
+pub enum NodeError {
+    /// The command line does not make sense, for the given reason
+    Usage(String),
+    /// The spec file could not be loaded
+    Spec(ChainSpecError),
+    /// The spec does not describe a chain the node can run
+    Consensus(SpecError),
+    /// The database could not be read or written
+    Db(DbError),
+    /// The given directory does not hold a database
+    NotADatabase(PathBuf),
+    /// A chain could not be exported, or the chain in an export could not be imported
+    Export(ExportError),
+    /// A listener could not be opened or served
+    Io(std::io::Error),
+    /// The node answering RPC requests could not be reached, or refused the request
+    Rpc(RpcClientError),
+    /// The transition to submit is not valid JSON
+    BadTransition(serde_json::Error),
+    /// The node could not author a block
+    Author(AuthorError),
+    /// There is no such block in the database
+    UnknownBlock(BlockRef),
+    /// Only the header of the given block is left in the database
+    Pruned(Hash),
+}
+
+impl From<ChainSpecError> for NodeError {
+    fn from(e: ChainSpecError) -> Self {
+        NodeError::Spec(e)
+    }
+}
+
+impl From<SpecError> for NodeError {
+    fn from(e: SpecError) -> Self {
+        NodeError::Consensus(e)
+    }
+}
+
+impl From<DbError> for NodeError {
+    fn from(e: DbError) -> Self {
+        NodeError::Db(e)
+    }
+}
+
+impl From<ExportError> for NodeError {
+    fn from(e: ExportError) -> Self {
+        NodeError::Export(e)
+    }
+}
+
+impl From<std::io::Error> for NodeError {
+    fn from(e: std::io::Error) -> Self {
+        NodeError::Io(e)
+    }
+}
+
+impl From<RpcClientError> for NodeError {
+    fn from(e: RpcClientError) -> Self {
+        NodeError::Rpc(e)
+    }
+}
+
+impl From<AuthorError> for NodeError {
+    fn from(e: AuthorError) -> Self {
+        NodeError::Author(e)
+    }
+}
+
+impl Command {
+    /// Parse a command line, without the name of the binary. Options are given as `--name value`
+    /// pairs, in any order.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, NodeError> {
+        let mut args = args.into_iter();
+        let name = args.next().ok_or_else(|| usage("no command given"))?;
+        let mut args = Args::parse(args)?;
+        let command = match name.as_str() {
+            "run" => Command::Run(RunOptions {
+                spec: args.required("spec")?.into(),
+                db: args.optional("db").map(PathBuf::from),
+                rpc: args.optional("rpc"),
+                ws: args.optional("ws"),
+                listen: args.optional("listen"),
+                peers: args.all("peer"),
+                author: args
+                    .optional("author")
+                    .map(|a| parse_user(&a))
+                    .transpose()?,
+                block_time: match args.optional("block-time") {
+                    Some(ms) => Duration::from_millis(parse_number(&ms)?),
+                    None => DEFAULT_BLOCK_TIME,
+                },
+                history: match args.optional("pruned") {
+                    Some(keep) => HistoryMode::Pruned(parse_number(&keep)?),
+                    None => HistoryMode::Archive,
+                },
+                sync: match args.optional("sync").as_deref() {
+                    None | Some("full") => SyncMode::Full,
+                    Some("warp") => SyncMode::Warp,
+                    Some(other) => return Err(usage(format!("no sync mode named {other}"))),
+                },
+                pool_max_age: match args.optional("pool-max-age") {
+                    Some(secs) => Duration::from_secs(parse_number(&secs)?),
+                    None => DEFAULT_POOL_MAX_AGE,
+                },
+                ban_window: match args.optional("ban-window") {
+                    Some(secs) => Duration::from_secs(parse_number(&secs)?),
+                    None => DEFAULT_BAN_WINDOW,
+                },
+            }),
+            "submit" => Command::Submit {
+                rpc: args.required("rpc")?,
+                transition: args.positional("a transition")?,
+            },
+            "inspect" => Command::Inspect {
+                spec: args.required("spec")?.into(),
+                db: args.required("db")?.into(),
+                block: match (args.optional("hash"), args.optional("height")) {
+                    (Some(h), None) => BlockRef::Hash(parse_number(&h)?),
+                    (None, Some(h)) => BlockRef::Height(parse_number(&h)?),
+                    _ => return Err(usage("give either --hash or --height")),
+                },
+            },
+            "export-chain" => Command::ExportChain {
+                spec: args.required("spec")?.into(),
+                db: args.required("db")?.into(),
+                snapshot_interval: args
+                    .optional("snapshots")
+                    .map(|n| parse_number(&n))
+                    .transpose()?,
+                file: args.positional("an export file")?.into(),
+            },
+            "import-chain" => Command::ImportChain {
+                spec: args.required("spec")?.into(),
+                db: args.required("db")?.into(),
+                file: args.positional("an export file")?.into(),
+            },
+            "check-db" | "repair-db" => Command::CheckDb {
+                spec: args.required("spec")?.into(),
+                db: args.required("db")?.into(),
+                repair: name == "repair-db",
+            },
+            "purge-db" => Command::PurgeDb {
+                db: args.required("db")?.into(),
+            },
+            other => return Err(usage(format!("no command named {other}"))),
+        };
+        args.finish()?;
+        Ok(command)
+    }
+
+    /// Run the command, writing whatever it prints to `out`. A running node stops once the
+    /// token is cancelled.
+    pub fn execute(self, cancel: &CancelToken, mut out: impl Write) -> Result<(), NodeError> {
+        match self {
+            Command::Run(options) => run(&options, cancel),
+            Command::Submit { rpc, transition } => {
+                let t_hash = submit(&rpc, &transition)?;
+                Ok(writeln!(out, "{t_hash}")?)
+            }
+            Command::Inspect { spec, db, block } => {
+                let block = inspect(spec, db, block)?;
+                let pretty = serde_json::to_string_pretty(&block).expect("JSON values print");
+                Ok(writeln!(out, "{pretty}")?)
+            }
+            Command::ExportChain {
+                spec,
+                db,
+                file,
+                snapshot_interval,
+            } => {
+                let exported = export_chain(spec, db, file, snapshot_interval)?;
+                Ok(writeln!(out, "exported {exported} blocks")?)
+            }
+            Command::ImportChain { spec, db, file } => {
+                let imported = import_chain(spec, db, file)?;
+                Ok(writeln!(out, "imported {imported} blocks")?)
+            }
+            Command::CheckDb { spec, db, repair } => {
+                let report = check_db(spec, db, repair)?;
+                writeln!(out, "{} blocks are consistent", report.consistent)?;
+                for corrupt in &report.corrupt {
+                    let line = corrupt.position + 1;
+                    writeln!(out, "line {line} of headers.jsonl: {:?}", corrupt.error)?;
+                }
+                match (report.is_consistent(), repair) {
+                    (true, _) => Ok(()),
+                    (false, true) => Ok(writeln!(out, "truncated the database to them")?),
+                    (false, false) => Ok(writeln!(
+                        out,
+                        "run repair-db to truncate the database to them"
+                    )?),
+                }
+            }
+            Command::PurgeDb { db } => {
+                purge_db(&db)?;
+                Ok(writeln!(out, "removed {}", db.display())?)
+            }
+        }
+    }
+}
+
+/// Tell the operator about authors who authored two blocks at the same height. Only a node built
+/// with the `tracing` feature has a log to tell them in.
+fn report_equivocations<Digest: core::hash::Hash>(
+    proofs: impl IntoIterator<Item = AuthorEquivocation<Digest>>,
+) {
+    for proof in proofs {
+        #[cfg(feature = "tracing")]
+        tracing::warn!(
+            author = ?proof.author,
+            height = proof.first.height,
+            first = hash(&proof.first),
+            second = hash(&proof.second),
+            "author equivocated"
+        );
+        #[cfg(not(feature = "tracing"))]
+        let _ = proof;
+    }
+}
+
+/// Follow the chain until the token is cancelled.
+pub fn run(options: &RunOptions, cancel: &CancelToken) -> Result<(), NodeError> {
+    let spec = ChainSpec::load(&options.spec)?;
+    let engine: NodeConsensus = spec.consensus()?;
+    let mut tree = genesis_tree(&spec)?.with_history(options.history);
+    let mut db = match &options.db {
+        Some(dir) => {
+            let db = NodeDb::open(dir)?;
+            db.load_into(&mut tree)?;
+            Some(db)
+        }
+        None => None,
+    };
+
+    let mut network = Network::new(tree.best_chain()[0]);
+    for addr in spec.boot_nodes.iter().chain(&options.peers) {
+        // A peer that is down, or on another chain, does not stop the node from starting.
+        if let Ok(peer) = network.connect(addr.as_str(), &tree) {
+            let synced = match options.sync {
+                SyncMode::Full => network.sync(peer, &mut tree),
+                SyncMode::Warp => network.warp_sync(peer, &mut tree, &spec.checkpoints),
+            };
+            if synced.is_err() {
+                network.disconnect(peer);
+            }
+        }
+    }
+    if let Some(db) = &mut db {
+        db.store_tree(&tree)?;
+        db.compact(&tree)?;
+    }
+    let mut monitor = EquivocationMonitor::new();
+    report_equivocations(monitor.scan(&tree));
+    let listener = options
+        .listen
+        .as_deref()
+        .map(TcpListener::bind)
+        .transpose()?;
+    if let Some(listener) = &listener {
+        listener.set_nonblocking(true)?;
+    }
+    let rpc_listener = options.rpc.as_deref().map(TcpListener::bind).transpose()?;
+    let ws_listener = options.ws.as_deref().map(TcpListener::bind).transpose()?;
+
+    let mut pool = NodePool::new().with_ban_window(options.ban_window);
+    let mut pool_store = options
+        .db
+        .as_ref()
+        .map(|dir| PoolStore::new(dir, options.pool_max_age));
+    if let Some(store) = &mut pool_store {
+        let reload = store.load(&mut pool, tree.best_state(), SystemClock.now())?;
+        #[cfg(feature = "tracing")]
+        tracing::info!(
+            restored = reload.restored,
+            expired = reload.expired,
+            invalid = reload.invalid,
+            "reloaded the pool"
+        );
+        #[cfg(not(feature = "tracing"))]
+        let _ = reload;
+    }
+
+    let tree = Arc::new(Mutex::new(tree));
+    let pool = Arc::new(Mutex::new(pool));
+    let statuses = Arc::new(Mutex::new(TxStatuses::new()));
+    let subscriptions = Subscriptions::new();
+    let stop_rpc = CancelToken::new();
+    let result = std::thread::scope(|scope| {
+        let handler =
+            || RpcHandler::new(tree.clone(), pool.clone()).with_tx_statuses(statuses.clone());
+        let server = rpc_listener.map(|listener| {
+            let handler = handler();
+            let stop_rpc = &stop_rpc;
+            scope.spawn(move || handler.serve(&listener, stop_rpc))
+        });
+        let ws_server = ws_listener.map(|listener| {
+            let handler = handler();
+            let (subscriptions, stop_rpc) = (&subscriptions, &stop_rpc);
+            scope.spawn(move || handler.serve_ws(subscriptions, &listener, stop_rpc))
+        });
+        let mut finalized = tree.lock().unwrap().chain_info().finalized_hash;
+
+        let mut queue = ImportQueue::new(QUEUE_CAPACITY);
+        let mut next_block = Instant::now() + options.block_time;
+        let result = loop {
+            if cancel.is_cancelled() {
+                break Ok(());
+            }
+            let mut tree = tree.lock().unwrap();
+            let mut pool = pool.lock().unwrap();
+            let mut statuses = statuses.lock().unwrap();
+            if let Some(listener) = &listener {
+                // Nobody connecting, or a peer on another chain, is no reason to stop.
+                let _ = network.accept(listener, &tree);
+            }
+            network.poll(&tree, &mut queue, &mut pool);
+            let results = queue.import_all(&mut tree);
+            network.announce_imported(&tree, &results);
+            for imported in results.iter().filter(|r| r.result.is_ok()) {
+                report_equivocations(monitor.observe(&tree, imported.block_hash));
+            }
+            let mut changed = false;
+            for change in results.into_iter().filter_map(|r| r.result.ok()) {
+                follow(&mut pool, &mut statuses, &tree, &change);
+                subscriptions.publish_head_change(&tree, &change);
+                changed = true;
+            }
+
+            if let Some(author) = options.author {
+                if Instant::now() >= next_block {
+                    let change = match author_block(&engine, &mut tree, &mut pool, author) {
+                        Ok(change) => change,
+                        Err(e) => break Err(e),
+                    };
+                    network.announce(&tree, tree.best_head());
+                    follow(&mut pool, &mut statuses, &tree, &change);
+                    subscriptions.publish_head_change(&tree, &change);
+                    changed = true;
+                    next_block = Instant::now() + options.block_time;
+                }
+            }
+            if changed {
+                monitor.forget_below(tree.chain_info().finalized_height);
+                let now_finalized = tree.chain_info().finalized_hash;
+                if now_finalized != finalized {
+                    if let Some(header) = tree.header(now_finalized) {
+                        subscriptions.publish_finalized(header);
+                    }
+                    finalized = now_finalized;
+                }
+            }
+            if let (true, Some(db)) = (changed, &mut db) {
+                if let Err(e) = db.store_tree(&tree).and_then(|_| db.compact(&tree)) {
+                    break Err(e.into());
+                }
+            }
+            statuses.follow_pool(&pool);
+            if let Some(store) = &mut pool_store {
+                store.track(&pool, SystemClock.now());
+            }
+            subscriptions.publish_status_changes(&statuses.take_changes());
+            // The node's state machine emits no events, so events subscribers are pushed nothing.
+            // Peers that already have a transition are not sent it again.
+            for t in pool.batch(usize::MAX) {
+                network.announce_transition(t);
+            }
+
+            drop((tree, pool, statuses));
+            std::thread::sleep(POLL_INTERVAL);
+        };
+
+        stop_rpc.cancel();
+        if let Some(server) = server {
+            server.join().expect("the RPC server does not panic")?;
+        }
+        if let Some(server) = ws_server {
+            server
+                .join()
+                .expect("the WebSocket server does not panic")?;
+        }
+        result
+    });
+
+    // Whatever stopped the node, what is waiting in its pool is kept for next time.
+    let saved = match &mut pool_store {
+        Some(store) => store.save(&pool.lock().unwrap(), SystemClock.now()),
+        None => Ok(0),
+    };
+    result.and(saved.map(drop).map_err(NodeError::from))
+}
+
+/// Submit the given JSON-encoded transition to the node answering RPC requests at the given
+/// address. Returns the transition's hash.
+pub fn submit(rpc: &str, transition: &str) -> Result<Value, NodeError> {
+    let transition: Value = serde_json::from_str(transition).map_err(NodeError::BadTransition)?;
+    Ok(rpc::call(rpc, "author_submitTransition", vec![transition])?)
+}
+
+/// The hash, header and body of the given block in the database, as JSON. The blocks are
+/// executed again on their way into a block tree, which finds the best chain.
+pub fn inspect(
+    spec: impl AsRef<Path>,
+    db: impl AsRef<Path>,
+    block: BlockRef,
+) -> Result<Value, NodeError> {
+    let tree = load_tree(spec, &open_existing(db.as_ref())?)?;
+    let block_hash = match block {
+        BlockRef::Hash(block_hash) => block_hash,
+        BlockRef::Height(height) => *usize::try_from(height)
+            .ok()
+            .and_then(|height| tree.best_chain().get(height).copied())
+            .as_ref()
+            .ok_or(NodeError::UnknownBlock(block))?,
+    };
+    if tree.is_pruned(block_hash) {
+        return Err(NodeError::Pruned(block_hash));
+    }
+    let (Some(header), Some(body)) = (tree.header(block_hash), tree.body_of(block_hash)) else {
+        return Err(NodeError::UnknownBlock(block));
+    };
+    Ok(json!({ "hash": block_hash, "header": header, "body": body }))
+}
+
+/// Write the best chain in the database to the given file, with the state after every block
+/// whose height is a multiple of the snapshot interval if there is one. Returns how many blocks
+/// were written.
+pub fn export_chain(
+    spec: impl AsRef<Path>,
+    db: impl AsRef<Path>,
+    file: impl AsRef<Path>,
+    snapshot_interval: Option<u64>,
+) -> Result<usize, NodeError> {
+    let tree = load_tree(spec, &open_existing(db.as_ref())?)?;
+    let file = BufWriter::new(File::create(file)?);
+    Ok(export::export_chain(&tree, file, snapshot_interval)?)
+}
+
+/// Import the chain in the given export file into the database, creating the database if there
+/// is none. Returns how many blocks were imported.
+pub fn import_chain(
+    spec: impl AsRef<Path>,
+    db: impl AsRef<Path>,
+    file: impl AsRef<Path>,
+) -> Result<usize, NodeError> {
+    let mut db = NodeDb::open(db)?;
+    let mut tree = load_tree(spec, &db)?;
+    let imported = export::import_chain(&mut tree, BufReader::new(File::open(file)?))?;
+    db.store_tree(&tree)?;
+    Ok(imported)
+}
+
+/// Check every block in the database against the chain the spec describes, and with `repair`,
+/// truncate the database back to the blocks before the first corrupt one.
+pub fn check_db(
+    spec: impl AsRef<Path>,
+    db: impl AsRef<Path>,
+    repair: bool,
+) -> Result<DbCheck, NodeError> {
+    let db = db.as_ref();
+    if !is_database(db) {
+        return Err(NodeError::NotADatabase(db.to_owned()));
+    }
+    let mut tree = genesis_tree(&ChainSpec::load(spec)?)?;
+    let report = if repair {
+        NodeDb::repair(db, &mut tree)?
+    } else {
+        NodeDb::check(db, &mut tree)?
+    };
+    Ok(report)
+}
+
+/// Delete the database in the given directory. Refuses to delete a directory that does not
+/// look like a database.
+pub fn purge_db(dir: impl AsRef<Path>) -> Result<(), NodeError> {
+    let dir = dir.as_ref();
+    if !is_database(dir) {
+        return Err(NodeError::NotADatabase(dir.to_owned()));
+    }
+    Ok(std::fs::remove_dir_all(dir)?)
+}
+
+/// The tree of the chain the spec describes, with every block in the database imported.
+fn load_tree(spec: impl AsRef<Path>, db: &NodeDb) -> Result<NodeTree, NodeError> {
+    let mut tree = genesis_tree(&ChainSpec::load(spec)?)?;
+    db.load_into(&mut tree)?;
+    Ok(tree)
+}
+
+/// The tree of the chain the given spec describes, holding only its genesis block. If the spec
+/// has a block reward, the tree checks that blocks pay it, and otherwise that they pay nothing.
+fn genesis_tree(
+    spec: &ChainSpec<<NodeStateMachine as StateMachine>::GenesisConfig>,
+) -> Result<NodeTree, NodeError> {
+    let tree = NodeTree::from_spec(spec, LongestChain)?;
+    Ok(match spec.block_reward {
+        Some(reward) => tree.with_block_reward(reward),
+        None => tree.without_block_reward(),
+    })
+}
+
+/// Every database has a `VERSION` file.
+fn is_database(dir: &Path) -> bool {
+    dir.join("VERSION").is_file()
+}
+
+/// Open the database in the given directory, without creating one if there is none.
+fn open_existing(dir: &Path) -> Result<NodeDb, NodeError> {
+    if !is_database(dir) {
+        return Err(NodeError::NotADatabase(dir.to_owned()));
+    }
+    Ok(NodeDb::open(dir)?)
+}
+
+/// Build a block on top of the best head out of the transitions waiting in the pool, seal it,
+/// and import it. If blocks pay their authors, the block starts with the coinbase paying this
+/// node. Transitions the state machine rejects are dropped from the pool.
+fn author_block(
+    engine: &NodeConsensus,
+    tree: &mut NodeTree,
+    pool: &mut NodePool,
+    author: User,
+) -> Result<HeadChange, NodeError> {
+    let parent_hash = tree.best_head();
+    let parent = tree
+        .block(parent_hash)
+        .expect("the best head is in the tree");
+    let context = BlockContext {
+        height: parent.header.height + 1,
+        timestamp: SystemClock.now(),
+        author: Some(author),
+        parent_hash,
+    };
+    let mut batch = pool.batch(MAX_BLOCK_TRANSITIONS);
+    let block = loop {
+        let built = match tree.block_reward() {
+            Some(reward) => parent.child_with_coinbase_sealed_by(
+                engine,
+                tree.best_state(),
+                batch.clone(),
+                context.clone(),
+                reward,
+            ),
+            None => {
+                parent.child_sealed_by(engine, tree.best_state(), batch.clone(), context.clone())
+            }
+        };
+        match built {
+            Ok(block) => break block,
+            Err(BlockBuildError::StateExecutionFailed { index, .. }) => {
+                let rejected = batch.remove(index);
+                pool.reject(&[rejected]);
+            }
+            Err(e) => return Err(AuthorError::from(e).into()),
+        }
+    };
+    let block_hash = hash(&block.header);
+    tree.import(block)
+        .map_err(|_| AuthorError::NotImported(block_hash).into())
+}
+
+/// Bring the pool, and the statuses of the transitions in it, up to date after the best head
+/// moved.
+fn follow(pool: &mut NodePool, statuses: &mut TxStatuses, tree: &NodeTree, change: &HeadChange) {
+    pool.on_reorg(
+        &tree.bodies(&change.retracted),
+        &tree.bodies(&change.enacted),
+        tree.best_state(),
+    );
+    statuses.follow_head_change(tree, change);
+}
+
+/// The options and positional arguments of a command line, taken one by one as they are parsed
+struct Args {
+    options: Vec<(String, String)>,
+    positional: Vec<String>,
+}
+
+impl Args {
+    fn parse(args: impl Iterator<Item = String>) -> Result<Self, NodeError> {
+        let mut parsed = Args {
+            options: Vec::new(),
+            positional: Vec::new(),
+        };
+        let mut args = args.peekable();
+        while let Some(arg) = args.next() {
+            match arg.strip_prefix("--") {
+                Some(name) => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| usage(format!("--{name} needs a value")))?;
+                    parsed.options.push((name.to_owned(), value));
+                }
+                None => parsed.positional.push(arg),
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// Every value given for the option, in order.
+    fn all(&mut self, name: &str) -> Vec<String> {
+        let (taken, rest) = std::mem::take(&mut self.options)
+            .into_iter()
+            .partition(|(n, _)| n == name);
+        self.options = rest;
+        taken.into_iter().map(|(_, value)| value).collect()
+    }
+
+    /// The value of the option, if it was given. The last one wins.
+    fn optional(&mut self, name: &str) -> Option<String> {
+        self.all(name).pop()
+    }
+
+    fn required(&mut self, name: &str) -> Result<String, NodeError> {
+        self.optional(name)
+            .ok_or_else(|| usage(format!("--{name} is required")))
+    }
+
+    /// The single positional argument, described as `what` if it is missing.
+    fn positional(&mut self, what: &str) -> Result<String, NodeError> {
+        match self.positional.len() {
+            1 => Ok(self.positional.remove(0)),
+            _ => Err(usage(format!("expected {what}"))),
+        }
+    }
+
+    /// Fail if anything was given that the command does not take.
+    fn finish(self) -> Result<(), NodeError> {
+        if let Some((name, _)) = self.options.first() {
+            return Err(usage(format!("unknown option --{name}")));
+        }
+        if let Some(arg) = self.positional.first() {
+            return Err(usage(format!("unexpected argument {arg}")));
+        }
+        Ok(())
+    }
+}
+
+fn usage(reason: impl Into<String>) -> NodeError {
+    NodeError::Usage(reason.into())
+}
+
+fn parse_number(s: &str) -> Result<u64, NodeError> {
+    s.parse().map_err(|_| usage(format!("{s} is not a number")))
+}
+
+/// Users are named as they are in JSON, like `Alice`.
+fn parse_user(name: &str) -> Result<User, NodeError> {
+    serde_json::from_value(json!(name)).map_err(|_| usage(format!("no user named {name}")))
+}
+
+#[cfg(test)]
+fn args(line: &str) -> Vec<String> {
+    line.split_whitespace().map(String::from).collect()
+}
+
+#[test]
+fn cl_node_parses_command_lines() {
+    let run = Command::parse(args(
+        "run --spec chain.json --author Alice --peer a:1 --peer b:2 --block-time 500 --pruned 64 \
+         --sync warp --pool-max-age 600 --ban-window 0 --ws 127.0.0.1:9944",
+    ))
+    .unwrap();
+    assert_eq!(
+        run,
+        Command::Run(RunOptions {
+            ws: Some("127.0.0.1:9944".into()),
+            peers: vec!["a:1".into(), "b:2".into()],
+            author: Some(User::Alice),
+            block_time: Duration::from_millis(500),
+            history: HistoryMode::Pruned(64),
+            sync: SyncMode::Warp,
+            pool_max_age: Duration::from_secs(600),
+            ban_window: Duration::ZERO,
+            ..RunOptions::new("chain.json")
+        })
+    );
+    assert_eq!(
+        Command::parse(args("inspect --db db --height 3 --spec chain.toml")).unwrap(),
+        Command::Inspect {
+            spec: "chain.toml".into(),
+            db: "db".into(),
+            block: BlockRef::Height(3),
+        }
+    );
+    assert_eq!(
+        Command::parse(args(
+            "export-chain --spec c.json --db db --snapshots 10 chain.jsonl"
+        ))
+        .unwrap(),
+        Command::ExportChain {
+            spec: "c.json".into(),
+            db: "db".into(),
+            file: "chain.jsonl".into(),
+            snapshot_interval: Some(10),
+        }
+    );
+    assert_eq!(
+        Command::parse(args("repair-db --spec chain.json --db db")).unwrap(),
+        Command::CheckDb {
+            spec: "chain.json".into(),
+            db: "db".into(),
+            repair: true,
+        }
+    );
+    assert_eq!(
+        Command::parse(args("submit --rpc 127.0.0.1:9933 {}")).unwrap(),
+        Command::Submit {
+            rpc: "127.0.0.1:9933".into(),
+            transition: "{}".into(),
+        }
+    );
+
+    for bad in [
+        "",
+        "mine",
+        "run",
+        "run --spec",
+        "run --spec chain.json --author Mallory",
+        "run --spec chain.json --verbose yes",
+        "run --spec chain.json --sync fast",
+        "purge-db --db db extra",
+        "check-db --db db",
+        "import-chain --spec chain.json --db db",
+        "inspect --spec chain.json --db db --hash 1 --height 1",
+    ] {
+        assert!(
+            matches!(Command::parse(args(bad)), Err(NodeError::Usage(_))),
+            "{bad}"
+        );
+    }
+}
+
+#[test]
+fn cl_node_runs_a_chain_and_keeps_it_on_disk() {
+    use super::subscriptions::TestClient;
+    use crate::c1_state_machine::p7_multiasset::AssetTransaction;
+
+    let dir = std::env::temp_dir().join(format!("diy-blockchain-node-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let spec = dir.join("chain.json");
+    std::fs::write(
+        &spec,
+        r#"{
+            "name": "Test Chain",
+            "id": "test",
+            "consensus": { "engine": "pow", "threshold": 4611686018427387903 },
+            "genesis": [[1, "Alice", [["Alice", 100]]]]
+        }"#,
+    )
+    .unwrap();
+    let db = dir.join("db");
+    // Find free ports for the RPC and WebSocket servers.
+    let free_addr = || {
+        TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    };
+    let rpc_addr = free_addr().to_string();
+    let ws_addr = free_addr();
+
+    let options = RunOptions {
+        db: Some(db.clone()),
+        rpc: Some(rpc_addr.clone()),
+        ws: Some(ws_addr.to_string()),
+        author: Some(User::Alice),
+        block_time: Duration::from_millis(20),
+        ..RunOptions::new(&spec)
+    };
+    let cancel = CancelToken::new();
+    let transfer = AssetTransaction::Transfer {
+        asset: 1,
+        sender: User::Alice,
+        receiver: User::Bob,
+        amount: 30,
+    };
+    std::thread::scope(|scope| {
+        let node = scope.spawn(|| run(&options, &cancel));
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let submitted = loop {
+            match submit(&rpc_addr, &serde_json::to_string(&transfer).unwrap()) {
+                Ok(t_hash) => break t_hash,
+                Err(_) if Instant::now() < deadline => std::thread::sleep(POLL_INTERVAL),
+                Err(e) => panic!("{e:?}"),
+            }
+        };
+        assert_eq!(submitted, json!(hash(&transfer)));
+        // Subscribers are pushed the blocks the node authors.
+        let mut client = TestClient::connect(ws_addr);
+        let subscription = client.request("subscribe_newHeads", json!([]))["result"].clone();
+        let pushed = client.receive();
+        assert_eq!(pushed["params"]["subscription"], subscription);
+        assert!(pushed["params"]["result"]["height"].is_u64(), "{pushed}");
+        // Wait until a block includes the transfer.
+        while rpc::call(&rpc_addr, "state_query", vec![]).unwrap()["balances"]
+            .as_array()
+            .is_none_or(|balances| balances.len() < 2)
+        {
+            assert!(Instant::now() < deadline, "the transfer was never included");
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        let status = rpc::call(&rpc_addr, "author_transitionStatus", vec![submitted]).unwrap();
+        assert!(status["InBlock"].is_u64(), "{status}");
+        cancel.cancel();
+        node.join().unwrap().unwrap();
+    });
+    // The node kept its pool for next time, although a block took everything in it.
+    let kept = std::fs::read_to_string(db.join(super::pool_store::POOL_FILE)).unwrap();
+    assert_eq!(kept, "");
+
+    let included = (1..)
+        .map(|height| inspect(&spec, &db, BlockRef::Height(height)).unwrap())
+        .find(|block| block["body"] != json!([]))
+        .unwrap();
+    assert_eq!(included["body"], json!([transfer]));
+    let by_hash = inspect(
+        &spec,
+        &db,
+        BlockRef::Hash(included["hash"].as_u64().unwrap()),
+    )
+    .unwrap();
+    assert_eq!(by_hash, included);
+    assert!(matches!(
+        inspect(&spec, &db, BlockRef::Height(u64::MAX)),
+        Err(NodeError::UnknownBlock(_))
+    ));
+
+    // The chain moves to another database through an export file.
+    let exported = dir.join("chain.jsonl");
+    let copy = dir.join("copy");
+    let blocks = export_chain(&spec, &db, &exported, Some(1)).unwrap();
+    assert_eq!(import_chain(&spec, &copy, &exported).unwrap(), blocks - 1);
+    assert_eq!(
+        inspect(
+            &spec,
+            &copy,
+            BlockRef::Hash(by_hash["hash"].as_u64().unwrap())
+        )
+        .unwrap(),
+        included
+    );
+
+    // The copy checks out. Once the header at height 1 is mangled, it no longer does, and
+    // repairing it leaves only genesis.
+    assert!(check_db(&spec, &copy, false).unwrap().is_consistent());
+    let headers = std::fs::read_to_string(copy.join("headers.jsonl")).unwrap();
+    std::fs::write(
+        copy.join("headers.jsonl"),
+        headers.replacen("\"height\":1,", "\"height\":\"one\",", 1),
+    )
+    .unwrap();
+    let report = check_db(&spec, &copy, true).unwrap();
+    assert_eq!((report.consistent, report.corrupt.len()), (1, blocks - 1));
+    assert!(matches!(
+        inspect(&spec, &copy, BlockRef::Height(1)),
+        Err(NodeError::UnknownBlock(_))
+    ));
+
+    // Purging deletes the database, and only the database.
+    assert!(matches!(purge_db(&dir), Err(NodeError::NotADatabase(_))));
+    purge_db(&db).unwrap();
+    assert!(!db.exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,11 +1,152 @@
 //! As a blockchain node watches the chain evolve, it must constantly be assessing which chain
 //! is currently the best chain. We explored the concept of fork-choice briefly in the Blockchain chapter.
 //!
-//! The concepts are identical here, but now that we have a client tracking a proper block database,
-//! we can explore more advanced fork choice algorithms. In particular, we can now explore GHOST.
+//! The concepts are identical here, but now that we have a client tracking every fork it hears
+//! about, we can explore more advanced fork choice algorithms. In particular, we can now explore
+//! GHOST.
 
-use super::{Header, FullClient, Consensus};
-use crate::c3_consensus::{Pow, SimplePoa, ConsensusAuthority};
+use std::collections::{HashMap, HashSet};
+
+use super::{Hash, Header};
+use crate::c3_consensus::p17_uncles::UncleDigest;
+use crate::c3_consensus::p8_retargeting_pow::RetargetDigest;
+use crate::hash;
+
+/// Every header a client knows about, arranged by parent. All of them descend from the root the
+/// tree was created with.
+#[derive(Clone, Debug)]
+pub struct HeaderTree<Digest> {
+    root: Hash,
+    headers: HashMap<Hash, Header<Digest>>,
+    children: HashMap<Hash, Vec<Hash>>,
+    /// Every header's hash, in the order the headers were inserted. Parents come before their
+    /// children.
+    order: Vec<Hash>,
+}
+
+impl<Digest: core::hash::Hash> HeaderTree<Digest> {
+    /// A tree holding only the given header, usually genesis.
+    pub fn new(root: Header<Digest>) -> Self {
+        let root_hash = hash(&root);
+        HeaderTree {
+            root: root_hash,
+            headers: HashMap::from([(root_hash, root)]),
+            children: HashMap::new(),
+            order: vec![root_hash],
+        }
+    }
+
+    /// Add the given header below its parent. Returns false, leaving the tree unchanged, if the
+    /// header is already known, its parent is not, or its height does not follow its parent's.
+    pub fn insert(&mut self, header: Header<Digest>) -> bool {
+        let header_hash = hash(&header);
+        let follows_parent = self
+            .headers
+            .get(&header.parent)
+            .is_some_and(|parent| parent.height + 1 == header.height);
+        if !follows_parent || self.headers.contains_key(&header_hash) {
+            return false;
+        }
+        self.children
+            .entry(header.parent)
+            .or_default()
+            .push(header_hash);
+        self.headers.insert(header_hash, header);
+        self.order.push(header_hash);
+        true
+    }
+}
+
+impl<Digest> HeaderTree<Digest> {
+    pub fn root(&self) -> Hash {
+        self.root
+    }
+
+    pub fn get(&self, block_hash: Hash) -> Option<&Header<Digest>> {
+        self.headers.get(&block_hash)
+    }
+
+    pub fn contains(&self, block_hash: Hash) -> bool {
+        self.headers.contains_key(&block_hash)
+    }
+
+    /// The children of the given block, in the order they were inserted.
+    pub fn children(&self, block_hash: Hash) -> &[Hash] {
+        self.children.get(&block_hash).map_or(&[], Vec::as_slice)
+    }
+
+    /// Every block without children, in the order they were inserted.
+    pub fn leaves(&self) -> Vec<Hash> {
+        self.order
+            .iter()
+            .copied()
+            .filter(|h| self.children(*h).is_empty())
+            .collect()
+    }
+
+    /// Every block's hash, parents before their children.
+    pub fn hashes(&self) -> &[Hash] {
+        &self.order
+    }
+
+    /// Make the given block the root, forgetting every block that does not descend from it.
+    /// Clients do this once a block is final, since forks branching off before it can never
+    /// become best. Returns false, leaving the tree unchanged, if the block is not known.
+    pub fn prune_to(&mut self, new_root: Hash) -> bool {
+        if !self.contains(new_root) {
+            return false;
+        }
+        let mut kept = HashSet::from([new_root]);
+        let mut pending = vec![new_root];
+        while let Some(h) = pending.pop() {
+            for child in self.children(h) {
+                kept.insert(*child);
+                pending.push(*child);
+            }
+        }
+        self.headers.retain(|h, _| kept.contains(h));
+        self.children.retain(|h, _| kept.contains(h));
+        self.order.retain(|h| kept.contains(h));
+        self.root = new_root;
+        true
+    }
+
+    /// Forget every block that is neither an ancestor of the given block nor descends from it.
+    /// Clients do this when a block is finalized. Unlike `prune_to`, the blocks before it are
+    /// kept. Returns false, leaving the tree unchanged, if the block is not known.
+    pub fn prune_forks_of(&mut self, block_hash: Hash) -> bool {
+        if !self.contains(block_hash) {
+            return false;
+        }
+        let mut kept: HashSet<Hash> = self.route_from_root(block_hash).into_iter().collect();
+        let mut pending = vec![block_hash];
+        while let Some(h) = pending.pop() {
+            for child in self.children(h) {
+                kept.insert(*child);
+                pending.push(*child);
+            }
+        }
+        self.headers.retain(|h, _| kept.contains(h));
+        self.children.retain(|h, _| kept.contains(h));
+        for children in self.children.values_mut() {
+            children.retain(|h| kept.contains(h));
+        }
+        self.order.retain(|h| kept.contains(h));
+        true
+    }
+
+    /// The given block and its ancestors back to the root, starting with the root.
+    pub fn route_from_root(&self, block_hash: Hash) -> Vec<Hash> {
+        let mut route = Vec::new();
+        let mut current = Some(block_hash);
+        while let Some(h) = current.filter(|h| self.contains(*h)) {
+            route.push(h);
+            current = (h != self.root).then(|| self.headers[&h].parent);
+        }
+        route.reverse();
+        route
+    }
+}
 
 /// A means for a blockchain client to decide which chain is best among the many
 /// that it potentially knows about.
@@ -14,91 +155,423 @@ use crate::c3_consensus::{Pow, SimplePoa, ConsensusAuthority};
 /// consensus
 ///
 /// Some implementations are light and just make a quick comparison, like the longest chain rule.
-/// Others are more complex and associate additional logic with block import, like GHOST.
-pub trait ForkChoice<C: Consensus> {
-    /// Return the hash of the best block currently known according to this fork choice rule.
-    fn best_block(&self, header: Header<C::Digest>) -> Option<u64>;
+/// Others look at the shape of the whole tree, like GHOST.
+pub trait ForkChoice<Digest> {
+    /// Return the hash of the best head in the given tree. When several heads are equally good,
+    /// the one inserted first wins, so a client does not switch forks on a tie.
+    fn best_head(&self, tree: &HeaderTree<Digest>) -> Hash;
+}
 
-    /// Perform some bookkeeping activities when importing a new block.
-    fn import_hook(&mut self, header: Header<C::Digest>);
+/// The leaf for which the given score is highest, preferring earlier leaves on ties
+fn best_leaf<Digest>(tree: &HeaderTree<Digest>, score: impl Fn(Hash) -> u128) -> Hash {
+    tree.leaves()
+        .into_iter()
+        .map(|h| (score(h), h))
+        .reduce(|best, next| if next.0 > best.0 { next } else { best })
+        .map_or(tree.root(), |(_, h)| h)
 }
 
 /// The chain with the highest block height is the best
-pub struct LongestChain {
-    // You may add fields here if you need to.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LongestChain;
+
+impl<Digest> ForkChoice<Digest> for LongestChain {
+    fn best_head(&self, tree: &HeaderTree<Digest>) -> Hash {
+        best_leaf(tree, |h| {
+            u128::from(tree.get(h).map_or(0, |header| header.height))
+        })
+    }
+}
+
+/// The chain with the most accumulated proof of work is the best. How much work went into each
+/// block is read from its header by the given function.
+pub struct HeaviestChain<Work> {
+    pub work: Work,
 }
 
-impl<C: Consensus> ForkChoice<C> for LongestChain {
-    fn best_block(&self, header: Header<C::Digest>) -> Option<u64> {
-        todo!("Exercise 1")
+impl<Work> HeaviestChain<Work> {
+    pub fn new(work: Work) -> Self {
+        HeaviestChain { work }
     }
+}
 
-    fn import_hook(&mut self, header: Header<C::Digest>) {
-        todo!("Exercise 2")
+impl HeaviestChain<fn(&Header<RetargetDigest>) -> u128> {
+    /// The heaviest chain of blocks mined by the retargeting PoW engine, whose digests carry the
+    /// threshold they were mined against.
+    pub fn retargeting() -> Self {
+        HeaviestChain::new(retarget_work)
     }
 }
 
-/// The chain with the most accumulated proof of work is the best.
-/// This fork choice rule only makes sense with the PoW consensus engine
-/// and the generics reflect that.
-pub struct HeaviestChain {
-    // You may add fields here if you need to.
+/// The number of hashes it takes on average to mine a block against the header's threshold.
+pub fn retarget_work(header: &Header<RetargetDigest>) -> u128 {
+    (1 << 64) / (u128::from(header.consensus_digest.threshold) + 1)
 }
 
-impl ForkChoice<Pow> for HeaviestChain {
-    fn best_block(&self, header: Header<u64>) -> Option<u64> {
-        todo!("Exercise 3")
+impl HeaviestChain<fn(&Header<UncleDigest>) -> u128> {
+    /// The heaviest chain of blocks mined by the uncle-including PoW engine, counting the work
+    /// of the uncles each block includes.
+    pub fn with_uncles() -> Self {
+        HeaviestChain::new(work_with_uncles)
     }
+}
 
-    fn import_hook(&mut self, header: Header<u64>) {
-        todo!("Exercise 4")
-    }
+/// Blocks mined against the same target each count once, along with every uncle they include.
+/// This only counts genuine work if clients check the uncles with `uncles_are_valid` before
+/// importing the block.
+pub fn work_with_uncles(header: &Header<UncleDigest>) -> u128 {
+    1 + header.consensus_digest.uncles.len() as u128
 }
 
-/// The chain with the most signatures from the Alice authority is the best.
-/// This fork choice rule only makes sense with the PoA consensus engine
-/// and the generics reflect that.
-pub struct MostAliceSigs {
-    // You may add fields here if you need to.
+/// Whether every uncle the given header includes is a known block, branching off the header's
+/// ancestry, that no ancestor in the last `max_depth` blocks included already. The engine checks
+/// how old the uncles are, so only that many ancestors need to be looked at.
+pub fn uncles_are_valid(
+    tree: &HeaderTree<UncleDigest>,
+    header: &Header<UncleDigest>,
+    max_depth: u64,
+) -> bool {
+    let mut ancestors = HashSet::new();
+    let mut included = HashSet::new();
+    let mut current = header.parent;
+    while let Some(ancestor) = tree
+        .get(current)
+        .filter(|a| a.height + max_depth + 1 >= header.height)
+    {
+        ancestors.insert(current);
+        included.extend(ancestor.consensus_digest.uncles.iter().map(|u| u.hash));
+        current = ancestor.parent;
+    }
+
+    header.consensus_digest.uncles.iter().all(|uncle| {
+        let branches_off = tree.get(uncle.hash).is_some_and(|known| {
+            known.height == uncle.height
+                && known.consensus_digest.miner == uncle.miner
+                && ancestors.contains(&known.parent)
+        });
+        branches_off && !ancestors.contains(&uncle.hash) && !included.contains(&uncle.hash)
+    })
 }
 
-impl ForkChoice<SimplePoa> for MostAliceSigs {
-    fn best_block(&self, header: Header<ConsensusAuthority>) -> Option<u64> {
-        todo!("Exercise 5")
+/// The work of every block plus that of its ancestors, excluding the root's own
+fn accumulated_work<Digest>(
+    tree: &HeaderTree<Digest>,
+    work: impl Fn(&Header<Digest>) -> u128,
+) -> HashMap<Hash, u128> {
+    let mut total = HashMap::from([(tree.root(), 0)]);
+    for h in &tree.hashes()[1..] {
+        let header = &tree.headers[h];
+        total.insert(*h, total[&header.parent] + work(header));
     }
+    total
+}
 
-    fn import_hook(&mut self, header: Header<ConsensusAuthority>) {
-        todo!("Exercise 6")
+impl<Digest, Work> ForkChoice<Digest> for HeaviestChain<Work>
+where
+    Work: Fn(&Header<Digest>) -> u128,
+{
+    fn best_head(&self, tree: &HeaderTree<Digest>) -> Hash {
+        let total = accumulated_work(tree, &self.work);
+        best_leaf(tree, |h| total[&h])
     }
 }
 
 /// In the Greedy Heaviest Observed Subtree rule, the fork choice is iterative.
 /// You start from the genesis block, and at each fork, you choose the side of the fork
-/// that has the most accumulated proof of work on _all_ of its descendants.
-pub struct Ghost {
-    // You may add fields here if you need to.
+/// that has the most accumulated proof of work on _all_ of its descendants. Blocks that lost
+/// a race still count towards the work of the subtree they were mined in.
+pub struct Ghost<Work> {
+    pub work: Work,
 }
 
-impl ForkChoice<Pow> for Ghost {
-    fn best_block(&self, header: Header<u64>) -> Option<u64> {
-        todo!("Exercise 7")
+impl<Work> Ghost<Work> {
+    pub fn new(work: Work) -> Self {
+        Ghost { work }
     }
+}
 
-    fn import_hook(&mut self, header: Header<u64>) {
-        todo!("Exercise 8")
+impl Ghost<fn(&Header<RetargetDigest>) -> u128> {
+    /// GHOST over blocks mined by the retargeting PoW engine.
+    pub fn retargeting() -> Self {
+        Ghost::new(retarget_work)
     }
 }
 
-// Finally, we will provide a convenience method directly on our client that simply calls
-// into the corresponding method on the ForkChoice rule. You may need to add some trait
-// bounds to make this work.
-impl<C, SM, FC, P> FullClient<C, SM, FC, P> {
-    /// Return the hash of the best block currently known to the client
-    fn best_block(&self) -> u64 {
-        todo!("Exercise 9")
+impl<Digest, Work> ForkChoice<Digest> for Ghost<Work>
+where
+    Work: Fn(&Header<Digest>) -> u128,
+{
+    fn best_head(&self, tree: &HeaderTree<Digest>) -> Hash {
+        // Children come after their parents, so walking backwards finishes every subtree
+        // before adding it to its parent's.
+        let mut subtree_work: HashMap<Hash, u128> = HashMap::new();
+        for h in tree.hashes()[1..].iter().rev() {
+            let header = &tree.headers[h];
+            let own = (self.work)(header) + subtree_work.get(h).copied().unwrap_or(0);
+            subtree_work.insert(*h, own);
+            *subtree_work.entry(header.parent).or_default() += own;
+        }
+
+        let mut head = tree.root();
+        while let Some(heaviest) = tree.children(head).iter().copied().reduce(|best, next| {
+            if subtree_work[&next] > subtree_work[&best] {
+                next
+            } else {
+                best
+            }
+        }) {
+            head = heaviest;
+        }
+        head
     }
 }
 
-//TODO lots of tests for all the algos.
-// Especially a subtle one in Ghost, where importing a new
-// header causes a re-org to a different header than the one that was imported.
+#[cfg(test)]
+fn genesis() -> Header<u64> {
+    Header {
+        parent: 0,
+        height: 0,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: 0,
+    }
+}
+
+/// Insert a child of the given block, whose digest says how much work went into it, and
+/// return its hash.
+#[cfg(test)]
+fn extend(tree: &mut HeaderTree<u64>, parent: Hash, work: u64) -> Hash {
+    let header = Header {
+        parent,
+        height: tree.get(parent).unwrap().height + 1,
+        state_root: 0,
+        extrinsics_root: tree.hashes().len() as u64,
+        consensus_digest: work,
+    };
+    let h = hash(&header);
+    assert!(tree.insert(header));
+    h
+}
+
+#[cfg(test)]
+fn digest_work(header: &Header<u64>) -> u128 {
+    u128::from(header.consensus_digest)
+}
+
+#[test]
+fn cl_header_tree_rejects_orphans_and_duplicates() {
+    let mut tree = HeaderTree::new(genesis());
+    let root = tree.root();
+    let a = extend(&mut tree, root, 1);
+    let b = extend(&mut tree, root, 1);
+
+    assert_eq!(tree.children(root), &[a, b]);
+    assert_eq!(tree.leaves(), vec![a, b]);
+    assert!(!tree.insert(tree.get(a).unwrap().clone()));
+    let orphan = Header {
+        parent: 42,
+        height: 1,
+        ..genesis()
+    };
+    assert!(!tree.insert(orphan));
+    let wrong_height = Header {
+        parent: a,
+        height: 5,
+        ..genesis()
+    };
+    assert!(!tree.insert(wrong_height));
+    assert_eq!(tree.route_from_root(a), vec![root, a]);
+}
+
+#[test]
+fn cl_pruning_forgets_other_forks() {
+    let mut tree = HeaderTree::new(genesis());
+    let root = tree.root();
+    let a1 = extend(&mut tree, root, 1);
+    let a2 = extend(&mut tree, a1, 1);
+    let b1 = extend(&mut tree, root, 1);
+
+    assert!(tree.prune_to(a1));
+    assert_eq!(tree.root(), a1);
+    assert_eq!(tree.hashes(), &[a1, a2]);
+    assert!(!tree.contains(b1) && !tree.contains(root));
+    assert!(!tree.prune_to(b1));
+}
+
+#[test]
+fn cl_pruning_the_forks_of_a_block_keeps_its_ancestors() {
+    let mut tree = HeaderTree::new(genesis());
+    let root = tree.root();
+    let a1 = extend(&mut tree, root, 1);
+    let a2 = extend(&mut tree, a1, 1);
+    let a3 = extend(&mut tree, a2, 1);
+    let b2 = extend(&mut tree, a1, 1);
+    let c1 = extend(&mut tree, root, 1);
+
+    assert!(tree.prune_forks_of(a2));
+    assert_eq!(tree.hashes(), &[root, a1, a2, a3]);
+    assert_eq!(tree.children(a1), &[a2]);
+    assert!(!tree.contains(b2) && !tree.contains(c1));
+    assert!(!tree.prune_forks_of(b2));
+}
+
+#[test]
+fn cl_longest_chain_prefers_height_then_first_seen() {
+    let mut tree = HeaderTree::new(genesis());
+    let root = tree.root();
+    let a1 = extend(&mut tree, root, 1);
+    let b1 = extend(&mut tree, root, 1);
+    assert_eq!(LongestChain.best_head(&tree), a1);
+
+    let b2 = extend(&mut tree, b1, 1);
+    assert_eq!(LongestChain.best_head(&tree), b2);
+}
+
+#[test]
+fn cl_heaviest_chain_can_be_shorter() {
+    let mut tree = HeaderTree::new(genesis());
+    let root = tree.root();
+    let a1 = extend(&mut tree, root, 1);
+    let a2 = extend(&mut tree, a1, 1);
+    let b1 = extend(&mut tree, root, 5);
+
+    assert_eq!(LongestChain.best_head(&tree), a2);
+    assert_eq!(HeaviestChain::new(digest_work).best_head(&tree), b1);
+}
+
+#[test]
+fn cl_ghost_counts_the_whole_subtree() {
+    // A has a long chain, but B's side of the fork has more blocks in total.
+    //        /- a1 - a2 - a3
+    // root -
+    //        \- b1 - b2
+    //              \- b2'
+    //              \- b2''
+    let mut tree = HeaderTree::new(genesis());
+    let root = tree.root();
+    let a1 = extend(&mut tree, root, 1);
+    let a2 = extend(&mut tree, a1, 1);
+    let a3 = extend(&mut tree, a2, 1);
+    let b1 = extend(&mut tree, root, 1);
+    let b2 = extend(&mut tree, b1, 1);
+    extend(&mut tree, b1, 1);
+    extend(&mut tree, b1, 1);
+
+    assert_eq!(HeaviestChain::new(digest_work).best_head(&tree), a3);
+    assert_eq!(Ghost::new(digest_work).best_head(&tree), b2);
+}
+
+#[test]
+fn cl_ghost_reorgs_to_a_block_it_did_not_just_import() {
+    let mut tree = HeaderTree::new(genesis());
+    let root = tree.root();
+    let a1 = extend(&mut tree, root, 1);
+    let a2 = extend(&mut tree, a1, 1);
+    let b1 = extend(&mut tree, root, 1);
+    let b2 = extend(&mut tree, b1, 1);
+    let ghost = Ghost::new(digest_work);
+    assert_eq!(ghost.best_head(&tree), a2);
+
+    // An uncle of b2 tips the balance towards B, whose best head is b2, not the uncle.
+    extend(&mut tree, b1, 1);
+    assert_eq!(ghost.best_head(&tree), b2);
+}
+
+#[test]
+fn cl_retarget_work_grows_as_threshold_falls() {
+    let header = |threshold| Header {
+        parent: 0,
+        height: 1,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: RetargetDigest {
+            nonce: 0,
+            timestamp: 0,
+            threshold,
+            window_start: 0,
+        },
+    };
+
+    assert_eq!(retarget_work(&header(u64::MAX)), 1);
+    assert_eq!(retarget_work(&header(u64::MAX / 4)), 4);
+}
+
+#[cfg(test)]
+fn uncle_tree() -> HeaderTree<UncleDigest> {
+    HeaderTree::new(Header {
+        parent: 0,
+        height: 0,
+        state_root: 0,
+        extrinsics_root: 0,
+        consensus_digest: UncleDigest::genesis(),
+    })
+}
+
+/// Insert a child of the given block including the given uncles, and return its hash.
+#[cfg(test)]
+fn extend_with_uncles(tree: &mut HeaderTree<UncleDigest>, parent: Hash, uncles: &[Hash]) -> Hash {
+    use crate::c3_consensus::p17_uncles::UncleRef;
+
+    let uncles = uncles
+        .iter()
+        .map(|h| UncleRef {
+            hash: *h,
+            height: tree.get(*h).map_or(0, |u| u.height),
+            miner: UncleDigest::genesis().miner,
+        })
+        .collect();
+    let header = Header {
+        parent,
+        height: tree.get(parent).unwrap().height + 1,
+        state_root: 0,
+        extrinsics_root: tree.hashes().len() as u64,
+        consensus_digest: UncleDigest {
+            uncles,
+            ..UncleDigest::genesis()
+        },
+    };
+    let h = hash(&header);
+    assert!(tree.insert(header));
+    h
+}
+
+#[test]
+fn cl_uncles_outweigh_a_longer_chain() {
+    //        /- a1 - a2
+    // root - -- c1
+    //        \- b1 - b2 (includes c1)
+    let mut tree = uncle_tree();
+    let root = tree.root();
+    let a1 = extend_with_uncles(&mut tree, root, &[]);
+    let a2 = extend_with_uncles(&mut tree, a1, &[]);
+    let c1 = extend_with_uncles(&mut tree, root, &[]);
+    let b1 = extend_with_uncles(&mut tree, root, &[]);
+    let b2 = extend_with_uncles(&mut tree, b1, &[c1]);
+
+    assert!(uncles_are_valid(&tree, tree.get(b2).unwrap(), 2));
+    assert_eq!(LongestChain.best_head(&tree), a2);
+    assert_eq!(HeaviestChain::with_uncles().best_head(&tree), b2);
+}
+
+#[test]
+fn cl_only_genuine_uncles_are_valid() {
+    let mut tree = uncle_tree();
+    let root = tree.root();
+    let a1 = extend_with_uncles(&mut tree, root, &[]);
+    let a2 = extend_with_uncles(&mut tree, a1, &[]);
+    let b1 = extend_with_uncles(&mut tree, root, &[]);
+    let b2 = extend_with_uncles(&mut tree, b1, &[a1]);
+    let valid = |tree: &HeaderTree<UncleDigest>, uncles: &[Hash]| {
+        let mut candidate = tree.clone();
+        let h = extend_with_uncles(&mut candidate, b2, uncles);
+        uncles_are_valid(&candidate, candidate.get(h).unwrap(), 2)
+    };
+
+    // a1 was already included by b2, and b1 is an ancestor.
+    assert!(!valid(&tree, &[a1]));
+    assert!(!valid(&tree, &[b1]));
+    // a2 branches off at a1, which is not an ancestor, so it is a cousin rather than an uncle.
+    assert!(!valid(&tree, &[a2]));
+    assert!(!valid(&tree, &[12345]));
+    assert!(valid(&tree, &[]));
+}
@@ -0,0 +1,198 @@
+//! The pool lives in memory, so a node that restarts forgets every transition that was waiting in
+//! it. The users who submitted them were told they were in the pool, and nothing tells them
+//! otherwise, so their transitions are silently lost.
+//!
+//! A node with a database keeps its pool in it too. When the node stops, `PoolStore` writes every
+//! pending transition to `pool.jsonl` in the database's directory, one JSON record per line, in
+//! the order the pool would include them, so that each sender's transitions come in nonce order.
+//! The file is written next to the old one first and then moved in, so a node that stops part way
+//! through leaves the previous file behind rather than half of a new one.
+//!
+//! When the node starts again, the transitions are submitted to its new pool, which checks them
+//! against the best state as it is now. The chain may have moved on while the node was down.
+//! Transitions can also go stale: one that has waited longer than the maximum age is unlikely to
+//! be what its sender still wants, so it is dropped rather than reloaded. Ages count from when
+//! the node first saw each transition, across restarts, so the store follows the pool as the node
+//! runs to note when each transition arrived.
+//!
+//! Records are JSON, so the store requires the `serde` feature.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::db::DbError;
+use super::tx_pool::{PoolError, PrioritizedTransition, TxPool};
+use super::Hash;
+use crate::c1_state_machine::SerdeStateMachine;
+use crate::hash;
+
+/// The file in the database's directory that holds the pool
+pub const POOL_FILE: &str = "pool.jsonl";
+
+/// A line of `pool.jsonl`
+#[derive(serde::Serialize, serde::Deserialize)]
+struct PoolRecord<Transition> {
+    transition: Transition,
+    /// When the node first saw the transition, in milliseconds since the unix epoch
+    first_seen: u64,
+}
+
+/// What became of the transitions in the file when they were reloaded
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolReload {
+    /// Back in the pool
+    pub restored: usize,
+    /// Dropped for being older than the maximum age
+    pub expired: usize,
+    /// Dropped because the pool turned them away on top of the best state
+    pub invalid: usize,
+}
+
+/// A node's pool, kept on disk while the node is down
+#[derive(Clone, Debug)]
+pub struct PoolStore {
+    path: PathBuf,
+    max_age: Duration,
+    /// When the node first saw each pending transition, by hash
+    first_seen: HashMap<Hash, u64>,
+}
+
+impl PoolStore {
+    /// A store for the pool of the node whose database is in the given directory, reloading
+    /// transitions no older than the given age.
+    pub fn new(dir: impl AsRef<Path>, max_age: Duration) -> Self {
+        PoolStore {
+            path: dir.as_ref().join(POOL_FILE),
+            max_age,
+            first_seen: HashMap::new(),
+        }
+    }
+
+    /// Note the given time as when the pool's new transitions arrived, and forget those that
+    /// left it.
+    pub fn track<SM>(&mut self, pool: &TxPool<SM>, now: u64)
+    where
+        SM: SerdeStateMachine,
+        SM::State: Clone,
+        SM::Transition: PrioritizedTransition + core::hash::Hash + Clone,
+    {
+        self.first_seen
+            .retain(|t_hash, _| pool.contains_hash(*t_hash));
+        for t_hash in pool.pending_hashes() {
+            self.first_seen.entry(t_hash).or_insert(now);
+        }
+    }
+
+    /// Write every pending transition of the given pool to disk. Returns how many were written.
+    pub fn save<SM>(&mut self, pool: &TxPool<SM>, now: u64) -> Result<usize, DbError>
+    where
+        SM: SerdeStateMachine,
+        SM::State: Clone,
+        SM::Transition: PrioritizedTransition + core::hash::Hash + Clone,
+    {
+        self.track(pool, now);
+        let pending = pool.batch(usize::MAX);
+        let mut lines = Vec::new();
+        for transition in &pending {
+            let record = PoolRecord {
+                first_seen: self.first_seen[&hash(transition)],
+                transition,
+            };
+            serde_json::to_writer(&mut lines, &record)?;
+            lines.write_all(b"\n")?;
+        }
+        let new = self.path.with_extension("jsonl.new");
+        std::fs::write(&new, lines)?;
+        std::fs::rename(&new, &self.path)?;
+        Ok(pending.len())
+    }
+
+    /// Submit the transitions on disk to the given pool, unless they are older than the maximum
+    /// age at the given time. The pool checks them against the given best state. Reloading when
+    /// nothing was saved reloads nothing.
+    pub fn load<SM>(
+        &mut self,
+        pool: &mut TxPool<SM>,
+        best_state: &SM::State,
+        now: u64,
+    ) -> Result<PoolReload, DbError>
+    where
+        SM: SerdeStateMachine,
+        SM::State: Clone,
+        SM::Transition: PrioritizedTransition + core::hash::Hash + Clone,
+    {
+        let text = match std::fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut reload = PoolReload::default();
+        for line in text.lines() {
+            let record: PoolRecord<SM::Transition> = serde_json::from_str(line)?;
+            if now.saturating_sub(record.first_seen) > self.max_age.as_millis() as u64 {
+                reload.expired += 1;
+                continue;
+            }
+            let t_hash = hash(&record.transition);
+            match pool.submit(record.transition, best_state) {
+                Ok(()) => {
+                    self.first_seen.insert(t_hash, record.first_seen);
+                    reload.restored += 1;
+                }
+                Err(PoolError::AlreadyKnown) => {}
+                Err(PoolError::Invalid | PoolError::Banned) => reload.invalid += 1,
+            }
+        }
+        Ok(reload)
+    }
+}
+
+#[cfg(test)]
+use super::Withdrawals;
+
+#[test]
+fn cl_pool_store_reloads_what_is_still_fresh_and_valid() {
+    let dir = std::env::temp_dir().join(format!("diy-blockchain-pool-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let max_age = Duration::from_millis(1500);
+
+    let mut pool = TxPool::<Withdrawals>::new();
+    let mut store = PoolStore::new(&dir, max_age);
+    pool.submit(10, &100).unwrap();
+    store.track(&pool, 0);
+    pool.submit(20, &100).unwrap();
+    pool.submit(5, &100).unwrap();
+    assert_eq!(store.save(&pool, 1000).unwrap(), 3);
+
+    // By the time the node is back, the 10 is too old, and only 15 is left to withdraw from.
+    let mut reloaded = TxPool::<Withdrawals>::new();
+    let mut store = PoolStore::new(&dir, max_age);
+    assert_eq!(
+        store.load(&mut reloaded, &15, 2000).unwrap(),
+        PoolReload {
+            restored: 1,
+            expired: 1,
+            invalid: 1,
+        }
+    );
+    assert_eq!(reloaded.batch(usize::MAX), vec![5]);
+
+    // The 5 keeps its age across the next restart.
+    store.save(&reloaded, 2400).unwrap();
+    let mut again = TxPool::<Withdrawals>::new();
+    let reload = PoolStore::new(&dir, max_age)
+        .load(&mut again, &15, 2600)
+        .unwrap();
+    assert_eq!((reload.restored, reload.expired), (0, 1));
+
+    // A node that never saved its pool has nothing to reload.
+    std::fs::remove_file(dir.join(POOL_FILE)).unwrap();
+    assert_eq!(
+        store.load(&mut again, &15, 2600).unwrap(),
+        PoolReload::default()
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
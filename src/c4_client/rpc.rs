@@ -0,0 +1,597 @@
+//! A node that only talks to other nodes is of little use to its users. Wallets, explorers and
+//! scripts need to ask it about the chain and hand it transitions to include. Most blockchain
+//! nodes answer such requests with JSON-RPC over HTTP: each request is a small JSON object naming
+//! a method and its parameters, POSTed to the node, and the answer is a JSON object holding either
+//! the result or an error.
+//!
+//! The methods are named after the part of the node they talk to, as in Substrate:
+//!
+//! - `chain_getHead` returns the hash of the best block.
+//! - `chain_getInfo` returns the hashes and heights of the best and the finalized blocks.
+//! - `chain_getHeader` and `chain_getBlock` return the header, or the header and body, of the
+//!   block with the given hash, or of the best block if no hash is given.
+//! - `state_query` returns the state after the block with the given hash, or after the best block.
+//! - `author_submitTransition` submits a transition to the node's pool, and returns its hash.
+//! - `author_transitionStatus` returns the status of the transition with the given hash, or null
+//!   if the node has not seen it. It needs the node to follow transition statuses.
+//!
+//! Parameters are passed by position. The HTTP server is deliberately minimal. It answers one
+//! request per connection, which is all that tools calling the node now and then need.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use super::block_tree::{BlockTree, HistoryMode};
+use super::p3_fork_choice::ForkChoice;
+use super::tx_pool::{PoolError, PrioritizedTransition, TxPool};
+use super::tx_status::TxStatuses;
+use super::Hash;
+use crate::c1_state_machine::{ContextualStateMachine, StateMachine};
+use crate::c3_consensus::{CancelToken, Consensus};
+use crate::codec::Encode;
+use crate::hash;
+use num::traits::{One, Zero};
+
+/// The request is not valid JSON
+pub const PARSE_ERROR: i64 = -32700;
+/// The request is JSON, but not a JSON-RPC request
+pub const INVALID_REQUEST: i64 = -32600;
+/// The node has no method with the requested name
+pub const METHOD_NOT_FOUND: i64 = -32601;
+/// The parameters do not fit the method
+pub const INVALID_PARAMS: i64 = -32602;
+/// The requested block is not known to the node
+pub const UNKNOWN_BLOCK: i64 = 1;
+/// The pool turned the submitted transition away
+pub const TRANSITION_REJECTED: i64 = 2;
+/// The node runs pruned, and no longer has the requested block's body or state
+pub const BLOCK_PRUNED: i64 = 3;
+
+/// The largest request body the server reads
+const MAX_REQUEST_LEN: usize = 1 << 20;
+
+/// How often the server checks whether it should stop while no requests are arriving
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Why a request failed
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+/// A JSON-RPC request
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Request {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Vec<Value>,
+    #[serde(default)]
+    id: Value,
+}
+
+/// A JSON-RPC response, holding either a result or an error
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Response {
+    jsonrpc: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+    id: Value,
+}
+
+/// Answers JSON-RPC requests about a node's block tree and transaction pool. Both are shared
+/// with the rest of the node, which keeps importing blocks and draining the pool while requests
+/// are being answered.
+pub struct RpcHandler<C: Consensus, SM: ContextualStateMachine, FC> {
+    tree: Arc<Mutex<BlockTree<C, SM, FC>>>,
+    pool: Arc<Mutex<TxPool<SM>>>,
+    /// The statuses of the transitions the node has seen, if it follows them
+    statuses: Option<Arc<Mutex<TxStatuses>>>,
+}
+
+impl<C, SM, FC> RpcHandler<C, SM, FC>
+where
+    C: Consensus,
+    C::Digest: Zero + One + core::hash::Hash + Serialize,
+    SM: ContextualStateMachine,
+    SM::State: core::hash::Hash + Clone + Serialize,
+    SM::Transition:
+        core::hash::Hash + Encode + Clone + PrioritizedTransition + Serialize + DeserializeOwned,
+    FC: ForkChoice<C::Digest>,
+{
+    pub fn new(tree: Arc<Mutex<BlockTree<C, SM, FC>>>, pool: Arc<Mutex<TxPool<SM>>>) -> Self {
+        RpcHandler {
+            tree,
+            pool,
+            statuses: None,
+        }
+    }
+
+    /// Record the outcome of every submission in the given statuses, and answer queries about
+    /// them.
+    pub fn with_tx_statuses(mut self, statuses: Arc<Mutex<TxStatuses>>) -> Self {
+        self.statuses = Some(statuses);
+        self
+    }
+
+    /// Answer the given JSON-RPC request, returning the JSON-encoded response.
+    pub fn handle(&self, request: &str) -> String {
+        let response = match serde_json::from_str::<Value>(request) {
+            Err(e) => error_response(Value::Null, RpcError::new(PARSE_ERROR, e.to_string())),
+            Ok(value) => match serde_json::from_value::<Request>(value) {
+                Err(e) => {
+                    error_response(Value::Null, RpcError::new(INVALID_REQUEST, e.to_string()))
+                }
+                Ok(request) if request.jsonrpc != "2.0" => error_response(
+                    request.id,
+                    RpcError::new(INVALID_REQUEST, "only JSON-RPC 2.0 is supported"),
+                ),
+                Ok(request) => match self.call(&request.method, &request.params) {
+                    Ok(result) => Response {
+                        jsonrpc: "2.0".into(),
+                        result: Some(result),
+                        error: None,
+                        id: request.id,
+                    },
+                    Err(error) => error_response(request.id, error),
+                },
+            },
+        };
+        serde_json::to_string(&response).expect("responses are always valid JSON")
+    }
+
+    /// Call the given method with the given positional parameters.
+    fn call(&self, method: &str, params: &[Value]) -> Result<Value, RpcError> {
+        match method {
+            "chain_getHead" => Ok(json!(self.tree.lock().unwrap().best_head())),
+            "chain_getInfo" => Ok(to_value(&self.tree.lock().unwrap().chain_info())),
+            "chain_getHeader" => {
+                let tree = self.tree.lock().unwrap();
+                let block_hash = block_param(params, tree.best_head())?;
+                let header = tree.header(block_hash).ok_or_else(|| unknown(block_hash))?;
+                Ok(to_value(header))
+            }
+            "chain_getBlock" => {
+                let tree = self.tree.lock().unwrap();
+                let block_hash = block_param(params, tree.best_head())?;
+                let header = tree.header(block_hash).ok_or_else(|| unknown(block_hash))?;
+                let body = tree
+                    .body_of(block_hash)
+                    .ok_or_else(|| missing(&tree, block_hash))?;
+                Ok(json!({ "header": to_value(header), "body": to_value(body) }))
+            }
+            "state_query" => {
+                let tree = self.tree.lock().unwrap();
+                let block_hash = block_param(params, tree.best_head())?;
+                let state = tree
+                    .state_at(block_hash)
+                    .ok_or_else(|| missing(&tree, block_hash))?;
+                Ok(to_value(&state))
+            }
+            "author_submitTransition" => {
+                let [t] = params else {
+                    return Err(RpcError::new(INVALID_PARAMS, "expected one transition"));
+                };
+                let t: SM::Transition = serde_json::from_value(t.clone())
+                    .map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))?;
+                let t_hash = hash(&t);
+                let tree = self.tree.lock().unwrap();
+                let submitted = self.pool.lock().unwrap().submit(t, tree.best_state());
+                if let Some(statuses) = &self.statuses {
+                    statuses.lock().unwrap().submitted(t_hash, &submitted);
+                }
+                match submitted {
+                    Ok(()) => Ok(json!(t_hash)),
+                    Err(PoolError::AlreadyKnown) => Err(RpcError::new(
+                        TRANSITION_REJECTED,
+                        "the transition is already in the pool",
+                    )),
+                    Err(PoolError::Invalid) => Err(RpcError::new(
+                        TRANSITION_REJECTED,
+                        "the transition is invalid on top of the best block",
+                    )),
+                    Err(PoolError::Banned) => Err(RpcError::new(
+                        TRANSITION_REJECTED,
+                        "the transition was found invalid recently, and is banned for now",
+                    )),
+                }
+            }
+            "author_transitionStatus" => {
+                let Some(t_hash) = params.first().and_then(Value::as_u64) else {
+                    return Err(RpcError::new(INVALID_PARAMS, "expected a transition hash"));
+                };
+                let status = self
+                    .statuses
+                    .as_ref()
+                    .and_then(|statuses| statuses.lock().unwrap().status(t_hash));
+                Ok(to_value(&status))
+            }
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("no method named {method}"),
+            )),
+        }
+    }
+
+    /// Answer requests POSTed to the given listener until the token is cancelled. Each
+    /// connection carries one request.
+    pub fn serve(&self, listener: &TcpListener, cancel: &CancelToken) -> std::io::Result<()> {
+        listener.set_nonblocking(true)?;
+        while !cancel.is_cancelled() {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(false)?;
+                    // A client that goes away mid-request is its own problem, not the server's.
+                    let _ = self.answer(stream);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(POLL_INTERVAL)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Read one HTTP request from the stream and write the response.
+    fn answer(&self, stream: TcpStream) -> std::io::Result<()> {
+        let mut reader = BufReader::new(&stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut content_len = 0;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    content_len = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+
+        if !request_line.starts_with("POST ") {
+            return write_http(&stream, "405 Method Not Allowed", "");
+        }
+        if content_len > MAX_REQUEST_LEN {
+            return write_http(&stream, "413 Payload Too Large", "");
+        }
+        let mut body = vec![0; content_len];
+        reader.read_exact(&mut body)?;
+        let response = self.handle(&String::from_utf8_lossy(&body));
+        write_http(&stream, "200 OK", &response)
+    }
+}
+
+fn error_response(id: Value, error: RpcError) -> Response {
+    Response {
+        jsonrpc: "2.0".into(),
+        result: None,
+        error: Some(error),
+        id,
+    }
+}
+
+fn unknown(block_hash: Hash) -> RpcError {
+    RpcError::new(UNKNOWN_BLOCK, format!("no block with hash {block_hash}"))
+}
+
+/// Why the tree has no body or state for the given block.
+fn missing<C, SM, FC>(tree: &BlockTree<C, SM, FC>, block_hash: Hash) -> RpcError
+where
+    C: Consensus,
+    SM: StateMachine,
+{
+    match tree.history() {
+        HistoryMode::Pruned(keep) if tree.is_pruned(block_hash) => RpcError::new(
+            BLOCK_PRUNED,
+            format!(
+                "block {block_hash} was pruned: this node only keeps the bodies and states of \
+                 its best block and the {keep} before it, ask an archive node instead"
+            ),
+        ),
+        _ => unknown(block_hash),
+    }
+}
+
+fn to_value(value: &(impl Serialize + ?Sized)) -> Value {
+    serde_json::to_value(value).expect("chain data is always valid JSON")
+}
+
+/// The block hash given as the first parameter, or the given default if there is none.
+fn block_param(params: &[Value], default: Hash) -> Result<Hash, RpcError> {
+    match params.first() {
+        None | Some(Value::Null) => Ok(default),
+        Some(value) => value
+            .as_u64()
+            .ok_or_else(|| RpcError::new(INVALID_PARAMS, "a block hash is a number")),
+    }
+}
+
+fn write_http(mut stream: &TcpStream, status: &str, body: &str) -> std::io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()
+}
+
+/// The ways calling a node can fail
+#[derive(Debug)]
+pub enum RpcClientError {
+    /// The node could not be reached, or hung up
+    Io(std::io::Error),
+    /// The node's response is not a JSON-RPC response
+    Codec(serde_json::Error),
+    /// The node answered with an error
+    Rpc(RpcError),
+}
+
+impl From<std::io::Error> for RpcClientError {
+    fn from(e: std::io::Error) -> Self {
+        RpcClientError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for RpcClientError {
+    fn from(e: serde_json::Error) -> Self {
+        RpcClientError::Codec(e)
+    }
+}
+
+/// Call the given method on the node listening at the given address, and return the result.
+pub fn call(
+    addr: impl ToSocketAddrs,
+    method: &str,
+    params: Vec<Value>,
+) -> Result<Value, RpcClientError> {
+    let request = serde_json::to_string(&Request {
+        jsonrpc: "2.0".into(),
+        method: method.into(),
+        params,
+        id: json!(1),
+    })?;
+    let mut stream = TcpStream::connect(addr)?;
+    write!(
+        stream,
+        "POST / HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{request}",
+        request.len()
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let body = response.split_once("\r\n\r\n").map_or("", |(_, body)| body);
+    let response: Response = serde_json::from_str(body)?;
+    match (response.result, response.error) {
+        (_, Some(error)) => Err(RpcClientError::Rpc(error)),
+        (result, None) => Ok(result.unwrap_or(Value::Null)),
+    }
+}
+
+#[cfg(test)]
+use super::block_tree::HeadChange;
+#[cfg(test)]
+use super::p3_fork_choice::LongestChain;
+#[cfg(test)]
+use super::{Block, Withdrawals};
+#[cfg(test)]
+use crate::c1_state_machine::BlockContext;
+#[cfg(test)]
+use crate::c3_consensus::p1_pow::PoW;
+
+#[cfg(test)]
+type TestHandler = RpcHandler<PoW, Withdrawals, LongestChain>;
+
+/// A handler for a node whose chain withdrew 10 and then 20 from a balance of 100, and the
+/// hashes of the chain's blocks, genesis first.
+#[cfg(test)]
+fn handler() -> (TestHandler, Vec<Hash>) {
+    let mut tree = BlockTree::new(PoW::new(u64::MAX / 4), LongestChain, 100);
+    let mut parent = Block::<PoW, Withdrawals>::genesis(&100);
+    let mut hashes = vec![hash(&parent.header)];
+    let mut state = 100;
+    for amount in [10, 20] {
+        let context = BlockContext {
+            height: parent.header.height + 1,
+            ..BlockContext::default()
+        };
+        parent = parent.child(&state, vec![amount], context).unwrap();
+        state -= amount;
+        hashes.push(hash(&parent.header));
+        tree.import(parent.clone()).unwrap();
+    }
+    let handler = RpcHandler::new(
+        Arc::new(Mutex::new(tree)),
+        Arc::new(Mutex::new(TxPool::new())),
+    );
+    (handler, hashes)
+}
+
+/// Call the given method on the handler, and return the response as JSON.
+#[cfg(test)]
+fn request(handler: &TestHandler, method: &str, params: Value) -> Value {
+    let request = json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 7 });
+    serde_json::from_str(&handler.handle(&request.to_string())).unwrap()
+}
+
+#[test]
+fn cl_rpc_answers_queries_about_the_chain() {
+    let (handler, hashes) = handler();
+
+    assert_eq!(
+        request(&handler, "chain_getHead", json!([]))["result"],
+        hashes[2]
+    );
+    handler.tree.lock().unwrap().finalize(hashes[1]).unwrap();
+    assert_eq!(
+        request(&handler, "chain_getInfo", json!([]))["result"],
+        json!({
+            "best_hash": hashes[2],
+            "best_height": 2,
+            "finalized_hash": hashes[1],
+            "finalized_height": 1,
+        })
+    );
+    let header = request(&handler, "chain_getHeader", json!([hashes[1]]));
+    assert_eq!(header["id"], 7);
+    assert_eq!(header["result"]["parent"], hashes[0]);
+    let block = request(&handler, "chain_getBlock", json!([]));
+    assert_eq!(block["result"]["header"]["height"], 2);
+    assert_eq!(block["result"]["body"], json!([20]));
+    assert_eq!(
+        request(&handler, "state_query", json!([hashes[1]]))["result"],
+        90
+    );
+    assert_eq!(
+        request(&handler, "state_query", json!([null]))["result"],
+        70
+    );
+
+    let unknown = request(&handler, "chain_getHeader", json!([12345]));
+    assert_eq!(unknown["error"]["code"], UNKNOWN_BLOCK);
+    assert!(unknown.get("result").is_none());
+    let bad_hash = request(&handler, "state_query", json!(["best"]));
+    assert_eq!(bad_hash["error"]["code"], INVALID_PARAMS);
+    let no_method = request(&handler, "chain_getUncles", json!([]));
+    assert_eq!(no_method["error"]["code"], METHOD_NOT_FOUND);
+
+    let garbled: Value = serde_json::from_str(&handler.handle("{\"jsonrpc\":")).unwrap();
+    assert_eq!(garbled["error"]["code"], PARSE_ERROR);
+    let not_rpc: Value = serde_json::from_str(&handler.handle("[1, 2]")).unwrap();
+    assert_eq!(not_rpc["error"]["code"], INVALID_REQUEST);
+}
+
+#[test]
+fn cl_rpc_says_when_a_block_was_pruned() {
+    let (archive, hashes) = handler();
+    let mut tree = BlockTree::new(PoW::new(u64::MAX / 4), LongestChain, 100)
+        .with_history(HistoryMode::Pruned(1));
+    for block_hash in &hashes[1..] {
+        let block = archive
+            .tree
+            .lock()
+            .unwrap()
+            .block(*block_hash)
+            .unwrap()
+            .clone();
+        tree.import(block).unwrap();
+    }
+    let handler = RpcHandler::new(
+        Arc::new(Mutex::new(tree)),
+        Arc::new(Mutex::new(TxPool::new())),
+    );
+
+    // The header of a pruned block is still served, its body and state are not.
+    let header = request(&handler, "chain_getHeader", json!([hashes[0]]));
+    assert_eq!(header["result"]["height"], 0);
+    let block = request(&handler, "chain_getBlock", json!([hashes[0]]));
+    assert_eq!(block["error"]["code"], BLOCK_PRUNED);
+    let state = request(&handler, "state_query", json!([hashes[0]]));
+    assert_eq!(state["error"]["code"], BLOCK_PRUNED);
+    assert_eq!(
+        request(&handler, "state_query", json!([hashes[1]]))["result"],
+        90
+    );
+    let unknown = request(&handler, "state_query", json!([12345]));
+    assert_eq!(unknown["error"]["code"], UNKNOWN_BLOCK);
+}
+
+#[test]
+fn cl_rpc_submits_transitions_to_the_pool() {
+    let (handler, _) = handler();
+
+    let submitted = request(&handler, "author_submitTransition", json!([30]));
+    assert_eq!(submitted["result"], hash(&30u64));
+    assert!(handler.pool.lock().unwrap().contains(&30));
+
+    let again = request(&handler, "author_submitTransition", json!([30]));
+    assert_eq!(again["error"]["code"], TRANSITION_REJECTED);
+    let overdrawn = request(&handler, "author_submitTransition", json!([500]));
+    assert_eq!(overdrawn["error"]["code"], TRANSITION_REJECTED);
+    let malformed = request(&handler, "author_submitTransition", json!(["thirty"]));
+    assert_eq!(malformed["error"]["code"], INVALID_PARAMS);
+    assert_eq!(handler.pool.lock().unwrap().len(), 1);
+}
+
+#[test]
+fn cl_rpc_reports_transition_statuses() {
+    let (handler, hashes) = handler();
+    let statuses = Arc::new(Mutex::new(TxStatuses::new()));
+    let handler = handler.with_tx_statuses(statuses.clone());
+
+    request(&handler, "author_submitTransition", json!([30]));
+    request(&handler, "author_submitTransition", json!([500]));
+    let status = |t: u64| request(&handler, "author_transitionStatus", json!([hash(&t)]));
+    assert_eq!(status(30)["result"], json!("InPool"));
+    assert_eq!(status(500)["result"], json!("Invalid"));
+    assert_eq!(status(40)["result"], Value::Null);
+    let malformed = request(&handler, "author_transitionStatus", json!([]));
+    assert_eq!(malformed["error"]["code"], INVALID_PARAMS);
+
+    // Transitions in blocks say which block.
+    let change = HeadChange {
+        retracted: vec![],
+        enacted: hashes[1..].to_vec(),
+    };
+    let tree = handler.tree.lock().unwrap();
+    let mut statuses = statuses.lock().unwrap();
+    statuses.submitted(hash(&20u64), &Ok(()));
+    statuses.follow_head_change(&tree, &change);
+    drop((tree, statuses));
+    assert_eq!(status(20)["result"], json!({ "InBlock": hashes[2] }));
+}
+
+#[test]
+fn cl_rpc_serves_requests_over_http() {
+    let (handler, hashes) = handler();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let cancel = CancelToken::new();
+
+    std::thread::scope(|scope| {
+        let server = scope.spawn(|| handler.serve(&listener, &cancel));
+
+        assert_eq!(call(addr, "chain_getHead", vec![]).unwrap(), hashes[2]);
+        assert_eq!(
+            call(addr, "author_submitTransition", vec![json!(5)]).unwrap(),
+            hash(&5u64)
+        );
+        assert!(matches!(
+            call(addr, "state_query", vec![json!(1)]),
+            Err(RpcClientError::Rpc(RpcError {
+                code: UNKNOWN_BLOCK,
+                ..
+            }))
+        ));
+
+        let mut get = TcpStream::connect(addr).unwrap();
+        write!(get, "GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        get.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 405"));
+
+        cancel.cancel();
+        server.join().unwrap().unwrap();
+    });
+}
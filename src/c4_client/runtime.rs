@@ -0,0 +1,386 @@
+//! Every chain so far has run a single state machine. Real chains run many side by side: a
+//! currency, governance, a name service and so on, each with its own rules and its own part of
+//! the state. Frameworks call such a collection of modules a runtime.
+//!
+//! `Runtime` is a state machine made of three modules: the multi-asset currency, governance and
+//! the name service. Every extrinsic is a `Call` naming the module it is for, and the runtime
+//! dispatches it to that module alone, which sees only its own part of the state. A rejected
+//! call is reported along with the module that rejected it.
+//!
+//! The runtime's state is committed to through the roots of its modules' states. Hashing it, as
+//! the block tree does for state roots, hashes the modules' roots, and `Runtime::state_root` is a
+//! Merkle root over them, for chains whose headers commit to Merkle roots. Proving a single
+//! module's root to a light client then takes a proof of three leaves, however large the other
+//! modules' states are.
+//!
+//! Adding a module means adding a variant to `Module`, `Call` and `RuntimeError`, a field to
+//! `RuntimeState` and `RuntimeGenesis`, and an arm to each dispatch.
+//!
+//! Blocks pay their authors through the currency module, whose coinbase is the runtime's.
+
+use super::Hash;
+use crate::c1_state_machine::p11_name_service::{
+    NameError, NameService, NameServiceState, NameTransaction,
+};
+use crate::c1_state_machine::p7_multiasset::{
+    AssetError, AssetTransaction, MultiAsset, MultiAssetState,
+};
+use crate::c1_state_machine::p9_governance::{
+    Governance, GovernanceError, GovernanceState, GovernanceTransaction,
+};
+use crate::c1_state_machine::{
+    BlockContext, ContextualStateMachine, MerkleState, Rewarded, StateMachine, User, Weighted,
+};
+use crate::codec::{Decode, Encode};
+use crate::hash;
+
+/// A state machine that dispatches every call to one of its modules
+pub struct Runtime;
+
+/// The modules of the runtime
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Module {
+    Currency,
+    Governance,
+    Names,
+}
+
+impl Module {
+    pub const ALL: [Module; 3] = [Module::Currency, Module::Governance, Module::Names];
+}
+
+/// An extrinsic of the runtime: a transaction for one of its modules
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Call {
+    Currency(AssetTransaction),
+    Governance(GovernanceTransaction),
+    Names(NameTransaction),
+}
+
+impl Call {
+    /// The module the call is dispatched to.
+    pub fn module(&self) -> Module {
+        match self {
+            Call::Currency(_) => Module::Currency,
+            Call::Governance(_) => Module::Governance,
+            Call::Names(_) => Module::Names,
+        }
+    }
+}
+
+/// Why a module rejected a call
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RuntimeError {
+    Currency(AssetError),
+    Governance(GovernanceError),
+    Names(NameError),
+}
+
+/// The state of every module
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RuntimeState {
+    pub currency: MultiAssetState,
+    pub governance: GovernanceState,
+    pub names: NameServiceState,
+}
+
+impl RuntimeState {
+    /// The root of the given module's state. The currency commits to its state with a Merkle
+    /// root of its own, and the other modules with a hash of theirs.
+    pub fn module_root(&self, module: Module) -> Hash {
+        match module {
+            Module::Currency => MultiAsset::state_root(&self.currency),
+            Module::Governance => hash(&self.governance),
+            Module::Names => hash(&self.names),
+        }
+    }
+}
+
+/// The roots of the modules' states, in the order of `Module::ALL`.
+impl core::hash::Hash for RuntimeState {
+    fn hash<H: core::hash::Hasher>(&self, hasher: &mut H) {
+        Module::ALL
+            .map(|module| self.module_root(module))
+            .hash(hasher);
+    }
+}
+
+/// The genesis configuration of every module
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RuntimeGenesis {
+    pub currency: <MultiAsset as StateMachine>::GenesisConfig,
+    pub governance: <Governance as StateMachine>::GenesisConfig,
+    pub names: <NameService as StateMachine>::GenesisConfig,
+}
+
+/// A tag byte for the module, followed by the module's encoding of the call.
+impl Encode for Call {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        match self {
+            Call::Currency(t) => {
+                dest.push(0);
+                t.encode_to(dest);
+            }
+            Call::Governance(t) => {
+                dest.push(1);
+                t.encode_to(dest);
+            }
+            Call::Names(t) => {
+                dest.push(2);
+                t.encode_to(dest);
+            }
+        }
+    }
+}
+
+impl Decode for Call {
+    fn decode_from(input: &mut &[u8]) -> Option<Self> {
+        match u8::decode_from(input)? {
+            0 => Some(Call::Currency(Decode::decode_from(input)?)),
+            1 => Some(Call::Governance(Decode::decode_from(input)?)),
+            2 => Some(Call::Names(Decode::decode_from(input)?)),
+            _ => None,
+        }
+    }
+}
+
+impl StateMachine for Runtime {
+    type State = RuntimeState;
+    type Transition = Call;
+    type Error = RuntimeError;
+    type GenesisConfig = RuntimeGenesis;
+
+    fn genesis_state(config: RuntimeGenesis) -> RuntimeState {
+        RuntimeState {
+            currency: MultiAsset::genesis_state(config.currency),
+            governance: Governance::genesis_state(config.governance),
+            names: NameService::genesis_state(config.names),
+        }
+    }
+
+    fn next_state(starting_state: &RuntimeState, t: &Call) -> RuntimeState {
+        Self::next_state_in_context(starting_state, t, &BlockContext::default())
+    }
+
+    fn try_next_state(
+        starting_state: &RuntimeState,
+        t: &Call,
+    ) -> Result<RuntimeState, RuntimeError> {
+        Self::try_next_state_in_context(starting_state, t, &BlockContext::default())
+    }
+
+    fn human_name() -> String {
+        "Runtime".into()
+    }
+}
+
+/// Every module sees the context of the block it executes in.
+impl ContextualStateMachine for Runtime {
+    fn next_state_in_context(
+        starting_state: &RuntimeState,
+        t: &Call,
+        context: &BlockContext,
+    ) -> RuntimeState {
+        let mut s = starting_state.clone();
+        match t {
+            Call::Currency(t) => {
+                s.currency = MultiAsset::next_state_in_context(&s.currency, t, context)
+            }
+            Call::Governance(t) => {
+                s.governance = Governance::next_state_in_context(&s.governance, t, context)
+            }
+            Call::Names(t) => s.names = NameService::next_state_in_context(&s.names, t, context),
+        }
+        s
+    }
+
+    fn try_next_state_in_context(
+        starting_state: &RuntimeState,
+        t: &Call,
+        context: &BlockContext,
+    ) -> Result<RuntimeState, RuntimeError> {
+        let mut s = starting_state.clone();
+        match t {
+            Call::Currency(t) => {
+                s.currency = MultiAsset::try_next_state_in_context(&s.currency, t, context)
+                    .map_err(RuntimeError::Currency)?
+            }
+            Call::Governance(t) => {
+                s.governance = Governance::try_next_state_in_context(&s.governance, t, context)
+                    .map_err(RuntimeError::Governance)?
+            }
+            Call::Names(t) => {
+                s.names = NameService::try_next_state_in_context(&s.names, t, context)
+                    .map_err(RuntimeError::Names)?
+            }
+        }
+        Ok(s)
+    }
+}
+
+impl Weighted for Runtime {
+    fn weight(t: &Call) -> u64 {
+        match t {
+            Call::Currency(t) => MultiAsset::weight(t),
+            Call::Governance(t) => Governance::weight(t),
+            Call::Names(t) => NameService::weight(t),
+        }
+    }
+}
+
+/// Authors are paid by the currency module.
+impl Rewarded for Runtime {
+    fn coinbase(author: User, amount: u64) -> Call {
+        Call::Currency(MultiAsset::coinbase(author, amount))
+    }
+
+    fn is_coinbase(t: &Call) -> bool {
+        matches!(t, Call::Currency(t) if MultiAsset::is_coinbase(t))
+    }
+
+    fn fee(t: &Call) -> u64 {
+        match t {
+            Call::Currency(t) => MultiAsset::fee(t),
+            _ => 0,
+        }
+    }
+}
+
+/// One entry per module, holding the root of its state.
+impl MerkleState for Runtime {
+    type Key = Module;
+    type Value = Hash;
+
+    fn entries(state: &RuntimeState) -> Vec<(Module, Hash)> {
+        Module::ALL
+            .into_iter()
+            .map(|module| (module, state.module_root(module)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+use super::block_tree::BlockTree;
+#[cfg(test)]
+use super::p3_fork_choice::LongestChain;
+#[cfg(test)]
+use crate::c1_state_machine::p7_multiasset::NATIVE_ASSET;
+#[cfg(test)]
+use crate::c1_state_machine::p9_governance::{Parameter, ParameterChange};
+#[cfg(test)]
+use crate::c3_consensus::p1_pow::PoW;
+
+/// Alice holds 100 of the currency, 50 governance tokens, and 30 to spend on names.
+#[cfg(test)]
+fn genesis() -> RuntimeState {
+    Runtime::genesis_state(RuntimeGenesis {
+        currency: vec![(NATIVE_ASSET, User::Alice, vec![(User::Alice, 100)])],
+        governance: (vec![(User::Alice, 50)], vec![]),
+        names: vec![(User::Alice, 30)],
+    })
+}
+
+#[cfg(test)]
+fn calls() -> Vec<Call> {
+    vec![
+        Call::Currency(AssetTransaction::Transfer {
+            asset: NATIVE_ASSET,
+            sender: User::Alice,
+            receiver: User::Bob,
+            amount: 40,
+        }),
+        Call::Governance(GovernanceTransaction::Propose {
+            proposer: User::Alice,
+            change: ParameterChange {
+                parameter: Parameter::MaxBlockWeight,
+                value: 10,
+            },
+        }),
+        Call::Names(NameTransaction::Register {
+            who: User::Alice,
+            name: "alice".into(),
+        }),
+    ]
+}
+
+#[test]
+fn cl_runtime_dispatches_each_call_to_its_module() {
+    let start = genesis();
+    let calls = calls();
+
+    let after_transfer = Runtime::try_next_state(&start, &calls[0]).unwrap();
+    assert_eq!(after_transfer.currency.balance(NATIVE_ASSET, User::Bob), 40);
+    assert_eq!(
+        (&after_transfer.governance, &after_transfer.names),
+        (&start.governance, &start.names)
+    );
+
+    let end = Runtime::try_apply_all(&start, &calls).unwrap();
+    assert_eq!(end.governance.referenda.len(), 1);
+    assert_eq!(end.names.resolve("alice", 0), Some(User::Alice));
+    assert_eq!(
+        Runtime::weight(&calls[0]),
+        MultiAsset::weight(&AssetTransaction::Transfer {
+            asset: NATIVE_ASSET,
+            sender: User::Alice,
+            receiver: User::Bob,
+            amount: 40,
+        })
+    );
+
+    let squatting = Call::Names(NameTransaction::Register {
+        who: User::Bob,
+        name: "alice".into(),
+    });
+    assert!(matches!(
+        Runtime::try_next_state(&end, &squatting),
+        Err(RuntimeError::Names(NameError::NameTaken { .. }))
+    ));
+    assert_eq!(Runtime::next_state(&end, &squatting), end);
+
+    let encoded = calls.encode();
+    assert_eq!(Vec::<Call>::decode(&encoded), Some(calls));
+    assert_eq!(Call::decode(&[3]), None);
+}
+
+#[test]
+fn cl_runtime_commits_to_every_module_in_its_blocks() {
+    let start = genesis();
+    let mut tree = BlockTree::<PoW, Runtime, LongestChain>::new(
+        PoW::new(u64::MAX / 4),
+        LongestChain,
+        start.clone(),
+    );
+    let parent = tree.block(tree.best_head()).unwrap();
+    let context = BlockContext {
+        height: 1,
+        ..BlockContext::default()
+    };
+    let block = parent.child(&start, calls(), context.clone()).unwrap();
+    tree.import(block).unwrap();
+    let end = tree.best_state().clone();
+    assert_eq!(
+        end,
+        Runtime::try_apply_all_in_context(&start, &calls(), &context).unwrap()
+    );
+
+    // Only the root of the module a call is for moves.
+    let after_names = Runtime::try_next_state(&start, &calls()[2]).unwrap();
+    for module in Module::ALL {
+        assert_eq!(
+            after_names.module_root(module) == start.module_root(module),
+            module != Module::Names
+        );
+    }
+
+    // Anyone who knows the runtime's root can check a module's root.
+    let root = Runtime::state_root(&end);
+    let proof = Runtime::prove(&end, &Module::Governance).unwrap();
+    assert_eq!(proof.value, end.module_root(Module::Governance));
+    assert!(proof.verify(root));
+    assert!(!proof.verify(Runtime::state_root(&start)));
+}
@@ -0,0 +1,802 @@
+//! Forks, authority rotation and finality only show up when several nodes author and exchange
+//! blocks, and they are hard to reproduce with real sockets, where timing is up to the operating
+//! system. The simulator runs a whole network of nodes in one process instead, in lock step.
+//!
+//! Each node has its own authority identity, consensus engine, block tree and finality gadget.
+//! The nodes are connected by an in-memory message bus, which delivers every message a fixed
+//! number of steps after it was sent. In each step, every node first handles the messages that
+//! arrived, then the nodes the schedule picks try to author a block on their best head, and
+//! every so often all nodes vote for their best head. The engine may refuse to seal a block,
+//! for example when it is not the node's turn, in which case the node simply does not author.
+//!
+//! The bus can be partitioned, so that nodes in different groups cannot reach each other. The
+//! messages between them are held back rather than lost, and delivered once the partition heals,
+//! like a real network catching up after a split. Partitions and heals can also be scripted to
+//! happen at given steps.
+//!
+//! Real links are not all alike, so each link between two nodes can have its own latency, drawn
+//! from a distribution for every message, and its own chance of losing messages altogether. The
+//! draws are derived from a seed, so a simulation with the same seed, schedule and script plays
+//! out the same way every time it is run.
+
+use std::collections::HashMap;
+
+use super::block_tree::BlockTree;
+use super::network::BlockData;
+use super::p3_fork_choice::ForkChoice;
+use super::{Block, Hash};
+use crate::c1_state_machine::{BlockContext, ContextualStateMachine, StateMachine};
+use crate::c3_consensus::finality::{BlockId, FinalityError, FinalityGadget, Vote, VoteKind};
+use crate::c3_consensus::{Consensus, ConsensusAuthority};
+use crate::codec::Encode;
+use crate::hash;
+use num::traits::{One, Zero};
+
+/// What nodes send each other over the bus
+#[derive(Clone)]
+enum Gossip<Digest, Transition> {
+    Block(BlockData<Digest, Transition>),
+    Vote(Vote),
+}
+
+/// A message in flight
+struct Envelope<Digest, Transition> {
+    from: usize,
+    to: usize,
+    /// The step the message is delivered in
+    arrives: u64,
+    message: Gossip<Digest, Transition>,
+}
+
+/// How many steps messages on a link take to arrive. Every message takes at least one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Latency {
+    /// Always the same number of steps
+    Fixed(u64),
+    /// Anywhere from `min` to `max` steps, inclusive, with every number equally likely
+    Uniform { min: u64, max: u64 },
+}
+
+/// How messages from one node to another travel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Link {
+    pub latency: Latency,
+    /// The chance of a message being lost, from 0 for never to 1 for always
+    pub drop_probability: f64,
+}
+
+impl Default for Link {
+    /// A reliable link delivering every message in the next step.
+    fn default() -> Self {
+        Link {
+            latency: Latency::Fixed(1),
+            drop_probability: 0.0,
+        }
+    }
+}
+
+/// A change to the bus, scripted to happen at the start of a step
+#[derive(Clone, Debug, PartialEq)]
+pub enum BusEvent {
+    /// Split the bus into the given groups of nodes, as `Simulation::partition` does
+    Partition(Vec<Vec<usize>>),
+    /// Join the bus back together
+    Heal,
+    /// Change how messages from one node to another travel
+    SetLink { from: usize, to: usize, link: Link },
+}
+
+/// A node taking part in a simulation
+pub struct SimNode<C: Consensus, SM: StateMachine, FC> {
+    authority: ConsensusAuthority,
+    /// Seals the blocks this node authors
+    engine: C,
+    tree: BlockTree<C, SM, FC>,
+    finality: FinalityGadget<()>,
+    /// Received blocks whose parent has not been imported yet
+    orphans: Vec<Block<C, SM>>,
+    /// Received votes for blocks that have not been imported yet
+    early_votes: Vec<Vote>,
+    /// The hashes of the blocks this node authored, oldest first
+    authored: Vec<Hash>,
+}
+
+impl<C, SM, FC> SimNode<C, SM, FC>
+where
+    C: Consensus,
+    C::Digest: Zero + One + core::hash::Hash,
+    SM: ContextualStateMachine,
+    SM::State: core::hash::Hash + Clone,
+    SM::Transition: core::hash::Hash + Encode + Clone,
+    FC: ForkChoice<C::Digest>,
+{
+    pub fn authority(&self) -> ConsensusAuthority {
+        self.authority
+    }
+
+    pub fn tree(&self) -> &BlockTree<C, SM, FC> {
+        &self.tree
+    }
+
+    /// The highest block this node knows to be final.
+    pub fn finalized(&self) -> Option<BlockId> {
+        self.finality.finalized()
+    }
+
+    pub fn authored(&self) -> &[Hash] {
+        &self.authored
+    }
+
+    /// Import the given block, or keep it until its parent arrives. Importing a block may let
+    /// orphans waiting for it be imported too.
+    fn import(&mut self, block: Block<C, SM>) {
+        if !self.tree.contains(block.header.parent) {
+            self.orphans.push(block);
+            return;
+        }
+        let header = block.header.clone();
+        if self.tree.import(block).is_err() {
+            return;
+        }
+        // A block on a fork the gadget already ruled out stays out of the vote count.
+        let _ = self.finality.import_header(&header);
+        for vote in std::mem::take(&mut self.early_votes) {
+            self.count_vote(vote);
+        }
+        let block_hash = hash(&header);
+        while let Some(i) = self
+            .orphans
+            .iter()
+            .position(|b| b.header.parent == block_hash)
+        {
+            let orphan = self.orphans.remove(i);
+            self.import(orphan);
+        }
+    }
+
+    fn receive(&mut self, message: Gossip<C::Digest, SM::Transition>) {
+        match message {
+            Gossip::Block(block) => self.import(block.into_block()),
+            Gossip::Vote(vote) => self.count_vote(vote),
+        }
+    }
+
+    /// Count the given vote, or keep it until its block arrives. Votes for blocks on forks the
+    /// gadget refused are not counted.
+    fn count_vote(&mut self, vote: Vote) {
+        match self.finality.import_vote(vote) {
+            Err(FinalityError::UnknownBlock(_)) => self.early_votes.push(vote),
+            _ => self.follow_finality(),
+        }
+    }
+
+    /// Finalize whatever the gadget last finalized in the tree too, so that the tree never
+    /// reorgs below it.
+    fn follow_finality(&mut self) {
+        if let Some(finalized) = self.finality.finalized() {
+            self.tree.finalize(finalized.hash);
+        }
+    }
+
+    /// Author a block with the given body on the best head, if the engine seals it and the
+    /// state machine accepts the body. Returns the new block.
+    fn author(
+        &mut self,
+        step: u64,
+        body: Vec<SM::Transition>,
+    ) -> Option<BlockData<C::Digest, SM::Transition>> {
+        let parent = self.tree.block(self.tree.best_head())?;
+        let context = BlockContext {
+            height: parent.header.height + 1,
+            timestamp: step,
+            author: Some(self.authority.into()),
+            ..BlockContext::default()
+        };
+        let block = parent
+            .child_sealed_by(&self.engine, self.tree.best_state(), body, context)
+            .ok()?;
+        let data = BlockData::of(&block);
+        self.authored.push(hash(&block.header));
+        self.import(block);
+        Some(data)
+    }
+}
+
+/// A network of nodes run in lock step
+pub struct Simulation<C: Consensus, SM: StateMachine, FC> {
+    nodes: Vec<SimNode<C, SM, FC>>,
+    in_flight: Vec<Envelope<C::Digest, SM::Transition>>,
+    /// How many steps have run
+    step: u64,
+    /// How messages travel on links without one of their own
+    default_link: Link,
+    /// How messages travel from one node to another, by sender and receiver
+    links: HashMap<(usize, usize), Link>,
+    /// What the random draws for latencies and drops are derived from
+    seed: u64,
+    /// How many draws have been made
+    draws: u64,
+    /// How many messages were lost
+    dropped: usize,
+    /// Changes to the bus, by the step they happen at
+    script: Vec<(u64, BusEvent)>,
+    /// The nodes that try to author a block in the given step
+    schedule: Box<dyn Fn(u64) -> Vec<usize>>,
+    /// The body of the block the given node authors in the given step
+    workload: Box<dyn FnMut(usize, u64) -> Vec<SM::Transition>>,
+    /// How many steps apart the nodes vote on finality, if they do
+    finality_interval: Option<u64>,
+    /// The group each node is in, while the bus is partitioned
+    groups: Option<Vec<usize>>,
+}
+
+impl<C, SM, FC> Simulation<C, SM, FC>
+where
+    C: Consensus,
+    C::Digest: Zero + One + core::hash::Hash,
+    SM: ContextualStateMachine,
+    SM::State: core::hash::Hash + Clone,
+    SM::Transition: core::hash::Hash + Encode + Clone,
+    FC: ForkChoice<C::Digest> + Clone,
+{
+    /// A node for each of the given authorities, all starting from the same genesis state. Each
+    /// node's engine is built for its authority. By default the nodes take turns authoring one
+    /// block per step, in the order the authorities are given, with empty bodies. Messages
+    /// arrive in the step after they were sent, and nobody votes on finality.
+    pub fn new(
+        authorities: &[ConsensusAuthority],
+        engine: impl Fn(ConsensusAuthority) -> C,
+        fork_choice: FC,
+        genesis_state: SM::State,
+    ) -> Self {
+        let nodes: Vec<_> = authorities
+            .iter()
+            .map(|authority| {
+                let tree = BlockTree::new(
+                    engine(*authority),
+                    fork_choice.clone(),
+                    genesis_state.clone(),
+                );
+                let mut finality = FinalityGadget::new((), authorities.to_vec());
+                let genesis = tree.header(tree.best_head()).expect("the tree has genesis");
+                finality
+                    .import_header(genesis)
+                    .expect("the first header is always accepted");
+                SimNode {
+                    authority: *authority,
+                    engine: engine(*authority),
+                    tree,
+                    finality,
+                    orphans: Vec::new(),
+                    early_votes: Vec::new(),
+                    authored: Vec::new(),
+                }
+            })
+            .collect();
+        let n = nodes.len() as u64;
+        Simulation {
+            nodes,
+            in_flight: Vec::new(),
+            step: 0,
+            default_link: Link::default(),
+            links: HashMap::new(),
+            seed: 0,
+            draws: 0,
+            dropped: 0,
+            script: Vec::new(),
+            schedule: Box::new(move |step| vec![(step % n.max(1)) as usize]),
+            workload: Box::new(|_, _| Vec::new()),
+            finality_interval: None,
+            groups: None,
+        }
+    }
+
+    /// Deliver messages the given number of steps after they are sent, on every link that has
+    /// no link of its own.
+    pub fn with_latency(mut self, steps: u64) -> Self {
+        self.default_link.latency = Latency::Fixed(steps);
+        self
+    }
+
+    /// Send messages as the given link does, on every link that has no link of its own.
+    pub fn with_default_link(mut self, link: Link) -> Self {
+        self.default_link = link;
+        self
+    }
+
+    /// Send messages from one node to the other as the given link does. Links go one way, so
+    /// the way back keeps its own.
+    pub fn with_link(mut self, from: usize, to: usize, link: Link) -> Self {
+        self.links.insert((from, to), link);
+        self
+    }
+
+    /// Derive the random latencies and drops from the given seed.
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Change the bus as the given events say, at the start of the steps they are paired with.
+    pub fn with_script(mut self, script: Vec<(u64, BusEvent)>) -> Self {
+        self.script = script;
+        self
+    }
+
+    /// Let the given schedule pick the nodes, by index, that try to author in each step.
+    pub fn with_schedule(mut self, schedule: impl Fn(u64) -> Vec<usize> + 'static) -> Self {
+        self.schedule = Box::new(schedule);
+        self
+    }
+
+    /// Fill the block the given node authors in the given step with the transitions the workload
+    /// returns.
+    pub fn with_workload(
+        mut self,
+        workload: impl FnMut(usize, u64) -> Vec<SM::Transition> + 'static,
+    ) -> Self {
+        self.workload = Box::new(workload);
+        self
+    }
+
+    /// Have every node vote for its best head every `interval` steps.
+    pub fn with_finality(mut self, interval: u64) -> Self {
+        self.finality_interval = Some(interval.max(1));
+        self
+    }
+
+    pub fn nodes(&self) -> &[SimNode<C, SM, FC>] {
+        &self.nodes
+    }
+
+    /// How many steps have run.
+    pub fn steps(&self) -> u64 {
+        self.step
+    }
+
+    /// How many messages were lost so far.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// Whether every node has the same best head.
+    pub fn converged(&self) -> bool {
+        let best = self.nodes.first().map(|n| n.tree.best_head());
+        self.nodes.iter().all(|n| Some(n.tree.best_head()) == best)
+    }
+
+    /// Split the bus so that only nodes in the same group reach each other. Nodes in no group
+    /// are on their own.
+    pub fn partition<G: AsRef<[usize]>>(&mut self, groups: &[G]) {
+        let mut of = (0..self.nodes.len())
+            .map(|i| groups.len() + i)
+            .collect::<Vec<_>>();
+        for (group, members) in groups.iter().enumerate() {
+            for member in members.as_ref() {
+                of[*member] = group;
+            }
+        }
+        self.groups = Some(of);
+    }
+
+    /// Join the bus back together. Messages held back by the partition arrive in the next step.
+    pub fn heal(&mut self) {
+        self.groups = None;
+    }
+
+    /// Run the given number of steps.
+    pub fn run(&mut self, steps: u64) {
+        for _ in 0..steps {
+            self.step();
+        }
+    }
+
+    /// Apply the bus events scripted for this step, deliver the messages that arrived, let the
+    /// scheduled nodes author, and vote if it is time to.
+    pub fn step(&mut self) {
+        let step = self.step;
+        let script = std::mem::take(&mut self.script);
+        let (now, later) = script.into_iter().partition(|(at, _)| *at == step);
+        self.script = later;
+        for (_, event) in now {
+            match event {
+                BusEvent::Partition(groups) => self.partition(&groups),
+                BusEvent::Heal => self.heal(),
+                BusEvent::SetLink { from, to, link } => {
+                    self.links.insert((from, to), link);
+                }
+            }
+        }
+
+        let groups = &self.groups;
+        let (due, waiting) = std::mem::take(&mut self.in_flight)
+            .into_iter()
+            .partition::<Vec<_>, _>(|e| {
+                e.arrives <= step && groups.as_ref().is_none_or(|g| g[e.from] == g[e.to])
+            });
+        self.in_flight = waiting;
+        for envelope in due {
+            self.nodes[envelope.to].receive(envelope.message);
+        }
+
+        for author in (self.schedule)(step) {
+            let body = (self.workload)(author, step);
+            if let Some(block) = self.nodes[author].author(step, body) {
+                self.broadcast(author, Gossip::Block(block));
+            }
+        }
+
+        if let Some(interval) = self.finality_interval {
+            if step > 0 && step.is_multiple_of(interval) {
+                self.vote(step / interval);
+            }
+        }
+        self.step += 1;
+    }
+
+    /// Every node prevotes and precommits for its best head in the given round.
+    fn vote(&mut self, round: u64) {
+        for voter in 0..self.nodes.len() {
+            let node = &self.nodes[voter];
+            let (block, authority) = (node.tree.best_head(), node.authority);
+            for kind in [VoteKind::Prevote, VoteKind::Precommit] {
+                let vote = Vote {
+                    round,
+                    kind,
+                    block,
+                    voter: authority,
+                };
+                self.nodes[voter].count_vote(vote);
+                self.broadcast(voter, Gossip::Vote(vote));
+            }
+        }
+    }
+
+    /// Send the given message from the given node to every other node, over their links.
+    fn broadcast(&mut self, from: usize, message: Gossip<C::Digest, SM::Transition>) {
+        for to in (0..self.nodes.len()).filter(|to| *to != from) {
+            let link = self
+                .links
+                .get(&(from, to))
+                .copied()
+                .unwrap_or(self.default_link);
+            // Compared in the same units as the draw, so that a probability of 1 drops everything.
+            if link.drop_probability > 0.0
+                && self.draw() as f64 <= link.drop_probability * u64::MAX as f64
+            {
+                self.dropped += 1;
+                continue;
+            }
+            let latency = match link.latency {
+                Latency::Fixed(steps) => steps,
+                Latency::Uniform { min, max } => {
+                    min + self.draw() % (max.saturating_sub(min).saturating_add(1))
+                }
+            };
+            self.in_flight.push(Envelope {
+                from,
+                to,
+                arrives: self.step + latency.max(1),
+                message: message.clone(),
+            });
+        }
+    }
+
+    /// The next of the random numbers derived from the seed.
+    fn draw(&mut self) -> u64 {
+        self.draws += 1;
+        hash(&(self.seed, self.draws))
+    }
+}
+
+#[cfg(test)]
+use super::p3_fork_choice::LongestChain;
+#[cfg(test)]
+use super::Header;
+#[cfg(test)]
+use super::Withdrawals;
+#[cfg(test)]
+use crate::c1_state_machine::pair::{Either, Pair};
+#[cfg(test)]
+use crate::c1_state_machine::upgrade::{Migrate, Upgrade, Versioned};
+#[cfg(test)]
+use crate::c3_consensus::p1_pow::PoW;
+#[cfg(test)]
+use ConsensusAuthority::{Alice, Bob, Charlie, Dave};
+
+/// Authorities taking turns by height, whose digest is the position of the authority that
+/// sealed the block. Genesis counts as sealed by the first authority.
+#[cfg(test)]
+struct TakeTurns {
+    authorities: Vec<ConsensusAuthority>,
+    /// The authority this node seals as
+    local: ConsensusAuthority,
+}
+
+#[cfg(test)]
+impl Consensus for TakeTurns {
+    type Digest = u64;
+
+    fn validate(&self, _: &u64, header: &Header<u64>) -> bool {
+        header.consensus_digest == header.height % self.authorities.len() as u64
+    }
+
+    fn seal(&self, _: &u64, partial_header: Header<()>) -> Option<Header<u64>> {
+        let turn = partial_header.height % self.authorities.len() as u64;
+        (self.authorities[turn as usize] == self.local).then_some(Header {
+            parent: partial_header.parent,
+            height: partial_header.height,
+            state_root: partial_header.state_root,
+            extrinsics_root: partial_header.extrinsics_root,
+            consensus_digest: turn,
+        })
+    }
+
+    fn create_default_instance() -> Self {
+        TakeTurns {
+            authorities: vec![Alice],
+            local: Alice,
+        }
+    }
+}
+
+#[test]
+fn cl_simulator_rotates_authorities() {
+    let authorities = [Alice, Bob, Charlie];
+    let engine = |local| TakeTurns {
+        authorities: authorities.to_vec(),
+        local,
+    };
+    // Everybody tries to author in every step, and the engine only lets whoever's turn it is.
+    // Nobody authors in the last step, so that the last block arrives everywhere.
+    let mut sim = Simulation::<_, Withdrawals, _>::new(&authorities, engine, LongestChain, 100)
+        .with_schedule(|step| if step < 9 { vec![0, 1, 2] } else { vec![] });
+    sim.run(10);
+
+    assert!(sim.converged());
+    let tree = sim.nodes()[0].tree();
+    assert_eq!(tree.best_height(), 9);
+    for node in sim.nodes() {
+        assert_eq!(node.authored().len(), 3);
+    }
+    let chain = tree.best_chain();
+    for (height, block_hash) in chain.iter().enumerate() {
+        assert_eq!(
+            tree.header(*block_hash).unwrap().consensus_digest,
+            height as u64 % 3
+        );
+    }
+}
+
+#[test]
+fn cl_simulator_resolves_forks_after_a_partition() {
+    let authorities = [Alice, Bob, Charlie, Dave];
+    // Distinct bodies, so that blocks authored at the same height differ.
+    let mut sim = Simulation::<PoW, Withdrawals, _>::new(
+        &authorities,
+        |_| PoW::new(u64::MAX / 4),
+        LongestChain,
+        1_000,
+    )
+    .with_workload(|author, _| vec![author as u64 + 1])
+    .with_schedule(|step| {
+        if step < 9 {
+            vec![(step % 4) as usize]
+        } else {
+            vec![]
+        }
+    });
+    sim.partition(&[&[0, 1], &[2, 3]]);
+    sim.run(8);
+    // Dave authored last, so his block has not reached Charlie yet.
+    let left = sim.nodes()[0].tree().best_head();
+    let right = sim.nodes()[3].tree().best_head();
+    assert_eq!(sim.nodes()[1].tree().best_head(), left);
+    assert_eq!(sim.nodes()[0].tree().best_height(), 4);
+    assert_eq!(sim.nodes()[3].tree().best_height(), 4);
+    assert!(!sim.nodes()[0].tree().contains(right));
+
+    sim.heal();
+    sim.run(3);
+    assert!(sim.converged());
+    // Every node knows both forks, and one of them lost.
+    let tree = sim.nodes()[0].tree();
+    assert!(tree.contains(left) && tree.contains(right));
+    let best_chain = tree.best_chain();
+    assert!(best_chain.contains(&left) != best_chain.contains(&right));
+}
+
+#[test]
+fn cl_simulator_finalizes_the_agreed_chain() {
+    let authorities = [Alice, Bob, Charlie];
+    let mut sim = Simulation::<PoW, Withdrawals, _>::new(
+        &authorities,
+        |_| PoW::new(u64::MAX / 4),
+        LongestChain,
+        100,
+    )
+    .with_schedule(|step| {
+        if step < 10 {
+            vec![(step % 3) as usize]
+        } else {
+            vec![]
+        }
+    })
+    .with_finality(2);
+    sim.run(8);
+    // Votes lag behind authoring.
+    let finalized = sim.nodes()[0].finalized().unwrap();
+    assert!(finalized.height > 0 && finalized.height < 8);
+
+    // Once authoring stops, the nodes finalize their common best head, in their trees too.
+    sim.run(6);
+    let best = sim.nodes()[0].tree().best_head();
+    for node in sim.nodes() {
+        assert_eq!(
+            node.finalized(),
+            Some(BlockId {
+                height: 10,
+                hash: best
+            })
+        );
+        assert_eq!(node.tree().chain_info().finalized_hash, best);
+    }
+}
+
+/// Four PoW nodes split into Alice, Bob and Charlie on one side and Dave on the other from the
+/// start, and joined back together at step 12, over links taking one to three steps. Alice
+/// authors every other step and Dave every fourth, until step 12.
+#[cfg(test)]
+fn split_then_healed(seed: u64) -> Simulation<PoW, Withdrawals, LongestChain> {
+    Simulation::new(
+        &[Alice, Bob, Charlie, Dave],
+        |_| PoW::new(u64::MAX / 4),
+        LongestChain,
+        1_000,
+    )
+    .with_workload(|author, _| vec![author as u64 + 1])
+    .with_schedule(|step| match step {
+        12.. => vec![],
+        _ if step.is_multiple_of(2) => vec![0],
+        _ if step % 4 == 1 => vec![3],
+        _ => vec![],
+    })
+    .with_default_link(Link {
+        latency: Latency::Uniform { min: 1, max: 3 },
+        drop_probability: 0.0,
+    })
+    .with_seed(seed)
+    .with_script(vec![
+        (0, BusEvent::Partition(vec![vec![0, 1, 2], vec![3]])),
+        (12, BusEvent::Heal),
+    ])
+}
+
+#[test]
+fn cl_simulator_replays_scripted_partitions() {
+    let mut sim = split_then_healed(7);
+    sim.run(12);
+    let alice = sim.nodes()[0].tree().best_head();
+    let dave = sim.nodes()[3].tree().best_head();
+    assert_eq!(sim.nodes()[0].tree().best_height(), 6);
+    assert_eq!(sim.nodes()[3].tree().best_height(), 3);
+    assert!(!sim.nodes()[0].tree().contains(dave));
+
+    // On heal, Dave reorgs onto the longer chain of the other side.
+    sim.run(8);
+    assert!(sim.converged());
+    let tree = sim.nodes()[3].tree();
+    assert_eq!(tree.best_head(), alice);
+    assert!(tree.contains(dave) && !tree.best_chain().contains(&dave));
+
+    // The same seed plays out the same way, down to when each message arrives.
+    let arrivals = |seed| {
+        let mut sim = split_then_healed(seed);
+        sim.run(10);
+        sim.in_flight
+            .iter()
+            .map(|e| (e.from, e.to, e.arrives))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(arrivals(7), arrivals(7));
+    assert_ne!(arrivals(7), arrivals(8));
+}
+
+#[test]
+fn cl_simulator_loses_messages_on_lossy_links() {
+    let lossy = |seed| {
+        Simulation::<PoW, Withdrawals, _>::new(
+            &[Alice, Bob, Charlie],
+            |_| PoW::new(u64::MAX / 4),
+            LongestChain,
+            100,
+        )
+        .with_schedule(|step| if step < 20 { vec![0] } else { vec![] })
+        .with_default_link(Link {
+            drop_probability: 0.5,
+            ..Link::default()
+        })
+        .with_seed(seed)
+    };
+    let mut sim = lossy(3);
+    sim.run(21);
+    // Alice sent two messages per block.
+    assert!(sim.dropped() > 0 && sim.dropped() < 40);
+    let mut again = lossy(3);
+    again.run(21);
+    assert_eq!(again.dropped(), sim.dropped());
+
+    // A link that loses everything cuts Alice off from Bob only, and only one way.
+    let mut sim = Simulation::<PoW, Withdrawals, _>::new(
+        &[Alice, Bob, Charlie],
+        |_| PoW::new(u64::MAX / 4),
+        LongestChain,
+        100,
+    )
+    .with_schedule(|step| if step < 3 { vec![0] } else { vec![] })
+    .with_link(
+        0,
+        1,
+        Link {
+            drop_probability: 1.0,
+            ..Link::default()
+        },
+    );
+    sim.run(4);
+    assert_eq!(sim.dropped(), 3);
+    assert_eq!(sim.nodes()[1].tree().best_height(), 0);
+    assert_eq!(sim.nodes()[2].tree().best_height(), 3);
+}
+
+/// Splits the balance in two at the fork. From then on either half can be withdrawn from.
+#[cfg(test)]
+struct SplitBalance;
+
+#[cfg(test)]
+impl Migrate<Withdrawals, Pair<Withdrawals, Withdrawals>> for SplitBalance {
+    const FORK_HEIGHT: u64 = 4;
+
+    fn migrate(balance: &u64) -> (u64, u64) {
+        (balance / 2, balance - balance / 2)
+    }
+}
+
+#[test]
+fn cl_simulator_runs_a_chain_through_a_runtime_upgrade() {
+    type Upgraded = Upgrade<Withdrawals, Pair<Withdrawals, Withdrawals>, SplitBalance>;
+    let authorities = [Alice, Bob, Charlie];
+    // One node authors in each step, and its block reaches the others by the next step, so the
+    // block authored in a step is at the height after it.
+    let mut sim = Simulation::<PoW, Upgraded, _>::new(
+        &authorities,
+        |_| PoW::new(u64::MAX / 4),
+        LongestChain,
+        Versioned::Old(100),
+    )
+    .with_workload(|_, step| {
+        if step + 1 < SplitBalance::FORK_HEIGHT {
+            vec![Either::Left(10)]
+        } else {
+            vec![Either::Right(Either::Right(5))]
+        }
+    })
+    .with_schedule(|step| {
+        if step < 6 {
+            vec![(step % 3) as usize]
+        } else {
+            vec![]
+        }
+    });
+    sim.run(7);
+
+    assert!(sim.converged());
+    let tree = sim.nodes()[2].tree();
+    let chain = tree.best_chain();
+    assert_eq!(chain.len(), 7);
+    // Three old withdrawals leave 70, which is split, and then three new withdrawals take 15
+    // from the second half.
+    assert_eq!(tree.state_at(chain[3]), Some(Versioned::Old(70)));
+    assert_eq!(tree.best_state(), &Versioned::New((35, 20)));
+    // Re-executing from the genesis snapshot migrates the state on the way.
+    assert_eq!(tree.state_at(chain[4]), Some(Versioned::New((35, 30))));
+}
@@ -0,0 +1,448 @@
+//! The block tree keeps the state after its best head in memory, along with a few snapshots, and
+//! re-executes blocks to get at any other state. That is fine for a short-lived test chain, but a
+//! long chain has far too many historical states to keep, and re-executing every block since
+//! genesis to answer a query about an old one takes far too long.
+//!
+//! The state database keeps history on disk instead, in the compact form of state diffs. It
+//! records every block's state root and the diffs its body made to the state. Every few blocks it
+//! also writes a snapshot of the whole state. The state after any block is then the state in the
+//! nearest snapshot before it, with the diffs of the blocks since applied on top. Each block's
+//! state root is checked along the way, so a corrupted diff is caught.
+//!
+//! Even diffs add up, so a node chooses how much history to keep with a pruning policy. Pruning
+//! snapshots the state at the oldest block it keeps, and forgets everything before it, along with
+//! every fork that branches off before it.
+//!
+//! The records and snapshots are JSON, so the database requires the `serde` feature.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use super::Hash;
+use crate::c1_state_machine::{DiffStateMachine, SerdeStateMachine};
+use crate::hash;
+use crate::snapshots::{Snapshot, SnapshotError};
+
+/// How many blocks apart the database snapshots the state, unless told otherwise
+pub const DEFAULT_SNAPSHOT_INTERVAL: u64 = 64;
+
+/// How much history the database keeps
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pruning {
+    /// Keep the state after every block ever inserted
+    ArchiveAll,
+    /// Keep the state after the best head and the given number of its ancestors
+    KeepLast(u64),
+    /// Keep the state after the latest finalized block and its descendants
+    FinalizedOnly,
+}
+
+/// The ways using the state database can fail
+#[derive(Debug)]
+pub enum StateDbError {
+    /// The diffs file could not be read or written
+    Io(std::io::Error),
+    /// A record could not be encoded or decoded
+    Codec(serde_json::Error),
+    /// A snapshot could not be written or restored
+    Snapshot(SnapshotError),
+    /// The parent of an inserted block is not in the database
+    UnknownParent(Hash),
+    /// Applying a block's diffs did not lead to the state root recorded for it
+    StateRootMismatch {
+        block_hash: Hash,
+        expected: Hash,
+        found: Hash,
+    },
+}
+
+impl From<std::io::Error> for StateDbError {
+    fn from(e: std::io::Error) -> Self {
+        StateDbError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for StateDbError {
+    fn from(e: serde_json::Error) -> Self {
+        StateDbError::Codec(e)
+    }
+}
+
+impl From<SnapshotError> for StateDbError {
+    fn from(e: SnapshotError) -> Self {
+        StateDbError::Snapshot(e)
+    }
+}
+
+/// A line of `state_diffs.jsonl`: what a block did to the state
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+struct DiffRecord<Diff> {
+    block_hash: Hash,
+    /// 0 for a block whose state was inserted whole, like genesis
+    parent: Hash,
+    height: u64,
+    state_root: Hash,
+    diffs: Vec<Diff>,
+}
+
+/// The state after every block a node keeps history for, stored on disk as diffs and snapshots
+pub struct StateDb<SM: DiffStateMachine> {
+    dir: PathBuf,
+    diffs_file: File,
+    /// Every block's record, by hash. The diffs are small, so they are kept in memory too.
+    records: HashMap<Hash, DiffRecord<SM::StateDiff>>,
+    pruning: Pruning,
+    snapshot_interval: u64,
+}
+
+impl<SM> StateDb<SM>
+where
+    SM: DiffStateMachine + SerdeStateMachine,
+    SM::State: core::hash::Hash + Clone,
+    SM::StateDiff: serde::Serialize + serde::de::DeserializeOwned,
+{
+    /// Open the database in the given directory, creating it if there is none.
+    pub fn open(dir: impl AsRef<Path>, pruning: Pruning) -> Result<Self, StateDbError> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(dir.join("snapshots"))?;
+        let diffs_path = dir.join("state_diffs.jsonl");
+        let text = match std::fs::read_to_string(&diffs_path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut records = HashMap::new();
+        for line in text.lines() {
+            let record: DiffRecord<SM::StateDiff> = serde_json::from_str(line)?;
+            records.insert(record.block_hash, record);
+        }
+        let diffs_file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&diffs_path)?;
+        Ok(StateDb {
+            dir,
+            diffs_file,
+            records,
+            pruning,
+            snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+        })
+    }
+
+    /// Snapshot the state every `interval` blocks.
+    pub fn with_snapshot_interval(mut self, interval: u64) -> Self {
+        self.snapshot_interval = interval.max(1);
+        self
+    }
+
+    pub fn contains(&self, block_hash: Hash) -> bool {
+        self.records.contains_key(&block_hash)
+    }
+
+    /// How many blocks the database has the state after.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// The state root recorded for the given block, if the database has its state.
+    pub fn state_root(&self, block_hash: Hash) -> Option<Hash> {
+        self.records.get(&block_hash).map(|r| r.state_root)
+    }
+
+    /// Store the whole state after the given block, which history starts from, like genesis.
+    pub fn insert_root(
+        &mut self,
+        block_hash: Hash,
+        height: u64,
+        state: &SM::State,
+    ) -> Result<(), StateDbError> {
+        self.write_snapshot(block_hash, height, state.clone())?;
+        self.append(DiffRecord {
+            block_hash,
+            parent: 0,
+            height,
+            state_root: hash(state),
+            diffs: Vec::new(),
+        })
+    }
+
+    /// Store the state after the given block, as the diffs its body made to its parent's state.
+    /// The state after the block is needed to snapshot it every so often.
+    pub fn insert(
+        &mut self,
+        block_hash: Hash,
+        parent: Hash,
+        diffs: Vec<SM::StateDiff>,
+        post_state: &SM::State,
+    ) -> Result<(), StateDbError> {
+        let height = self
+            .records
+            .get(&parent)
+            .ok_or(StateDbError::UnknownParent(parent))?
+            .height
+            + 1;
+        if height.is_multiple_of(self.snapshot_interval) {
+            self.write_snapshot(block_hash, height, post_state.clone())?;
+        }
+        self.append(DiffRecord {
+            block_hash,
+            parent,
+            height,
+            state_root: hash(post_state),
+            diffs,
+        })
+    }
+
+    /// The state after the given block, if the database has it, reconstructed from the nearest
+    /// snapshot before it and the diffs of the blocks since.
+    pub fn state_at(&self, block_hash: Hash) -> Result<Option<SM::State>, StateDbError> {
+        let mut route = Vec::new();
+        let mut current = block_hash;
+        let snapshot = loop {
+            let Some(record) = self.records.get(&current) else {
+                return Ok(None);
+            };
+            let path = self.snapshot_path(current);
+            if path.exists() {
+                break Snapshot::<SM::State>::read_from(path)?;
+            }
+            route.push(record);
+            current = record.parent;
+        };
+
+        let mut state = snapshot.state;
+        for record in route.into_iter().rev() {
+            for diff in &record.diffs {
+                SM::apply_diff(&mut state, diff);
+            }
+            if hash(&state) != record.state_root {
+                return Err(StateDbError::StateRootMismatch {
+                    block_hash: record.block_hash,
+                    expected: record.state_root,
+                    found: hash(&state),
+                });
+            }
+        }
+        Ok(Some(state))
+    }
+
+    /// Forget the history the pruning policy does not keep, given the best head and the latest
+    /// finalized block. Returns how many blocks' states were forgotten.
+    pub fn prune(&mut self, best: Hash, finalized: Option<Hash>) -> Result<usize, StateDbError> {
+        let base = match self.pruning {
+            Pruning::ArchiveAll => None,
+            Pruning::KeepLast(n) => self.ancestor(best, n),
+            Pruning::FinalizedOnly => finalized.filter(|h| self.contains(*h)),
+        };
+        let Some(base) = base else {
+            return Ok(0);
+        };
+        if !self.snapshot_path(base).exists() {
+            let state = self.state_at(base)?.expect("the base is in the database");
+            self.write_snapshot(base, self.records[&base].height, state)?;
+        }
+
+        let mut children: HashMap<Hash, Vec<Hash>> = HashMap::new();
+        for record in self.records.values() {
+            children
+                .entry(record.parent)
+                .or_default()
+                .push(record.block_hash);
+        }
+        let mut kept = HashSet::from([base]);
+        let mut pending = vec![base];
+        while let Some(h) = pending.pop() {
+            for child in children.get(&h).into_iter().flatten() {
+                kept.insert(*child);
+                pending.push(*child);
+            }
+        }
+
+        let forgotten: Vec<Hash> = self
+            .records
+            .keys()
+            .copied()
+            .filter(|h| !kept.contains(h))
+            .collect();
+        for h in &forgotten {
+            self.records.remove(h);
+            let path = self.snapshot_path(*h);
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+        }
+        if !forgotten.is_empty() {
+            self.rewrite_diffs()?;
+        }
+        Ok(forgotten.len())
+    }
+
+    /// The ancestor of the given block `depth` blocks before it, or the oldest one the database
+    /// has if it has fewer.
+    fn ancestor(&self, block_hash: Hash, depth: u64) -> Option<Hash> {
+        let mut current = self.records.get(&block_hash)?;
+        for _ in 0..depth {
+            match self.records.get(&current.parent) {
+                Some(parent) => current = parent,
+                None => break,
+            }
+        }
+        Some(current.block_hash)
+    }
+
+    fn snapshot_path(&self, block_hash: Hash) -> PathBuf {
+        self.dir
+            .join("snapshots")
+            .join(format!("{block_hash:016x}.json"))
+    }
+
+    fn write_snapshot(
+        &self,
+        block_hash: Hash,
+        height: u64,
+        state: SM::State,
+    ) -> Result<(), StateDbError> {
+        Snapshot::capture(height, block_hash, state).write_to(self.snapshot_path(block_hash))?;
+        Ok(())
+    }
+
+    fn append(&mut self, record: DiffRecord<SM::StateDiff>) -> Result<(), StateDbError> {
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        self.diffs_file.write_all(&line)?;
+        self.records.insert(record.block_hash, record);
+        Ok(())
+    }
+
+    /// Replace the diffs file with one holding only the records still kept. The new file is
+    /// written next to the old one and renamed into place, so a crash leaves one or the other.
+    fn rewrite_diffs(&mut self) -> Result<(), StateDbError> {
+        let path = self.dir.join("state_diffs.jsonl");
+        let tmp = self.dir.join("state_diffs.jsonl.tmp");
+        let mut records: Vec<_> = self.records.values().collect();
+        records.sort_by_key(|r| r.height);
+        let mut text = Vec::new();
+        for record in records {
+            serde_json::to_writer(&mut text, record)?;
+            text.push(b'\n');
+        }
+        std::fs::write(&tmp, text)?;
+        std::fs::rename(&tmp, &path)?;
+        self.diffs_file = OpenOptions::new().append(true).open(&path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+use super::Withdrawals;
+#[cfg(test)]
+use crate::c1_state_machine::StateMachine;
+
+/// A withdrawal's diff is the amount withdrawn.
+#[cfg(test)]
+impl DiffStateMachine for Withdrawals {
+    type StateDiff = u64;
+
+    fn state_diff(balance: &u64, amount: &u64) -> Result<u64, &'static str> {
+        Withdrawals::try_next_state(balance, amount).map(|_| *amount)
+    }
+
+    fn apply_diff(balance: &mut u64, amount: &u64) {
+        *balance -= amount;
+    }
+}
+
+#[cfg(test)]
+fn temp_state_db_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "diy-blockchain-state-db-{}-{}",
+        std::process::id(),
+        name
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+/// Insert a chain of blocks on top of the given one, each withdrawing the given amount, with
+/// made up hashes starting from `first`. Returns the balance after the last block.
+#[cfg(test)]
+fn insert_chain(db: &mut StateDb<Withdrawals>, parent: Hash, first: Hash, amounts: &[u64]) -> u64 {
+    let mut state = db.state_at(parent).unwrap().unwrap();
+    let mut parent = parent;
+    for (block_hash, amount) in (first..).zip(amounts) {
+        state -= amount;
+        db.insert(block_hash, parent, vec![*amount], &state)
+            .unwrap();
+        parent = block_hash;
+    }
+    state
+}
+
+#[test]
+fn cl_state_db_reconstructs_from_the_nearest_snapshot() {
+    let dir = temp_state_db_dir("reconstruct");
+    let mut db = StateDb::<Withdrawals>::open(&dir, Pruning::ArchiveAll)
+        .unwrap()
+        .with_snapshot_interval(4);
+    db.insert_root(100, 0, &100).unwrap();
+    assert_eq!(insert_chain(&mut db, 100, 1, &[1, 2, 3, 4, 5, 6]), 79);
+
+    // Block 6 is three blocks after the snapshot at block 4.
+    assert!(db.snapshot_path(4).exists() && !db.snapshot_path(6).exists());
+    let states: Vec<_> = (1..=6).map(|h| db.state_at(h).unwrap().unwrap()).collect();
+    assert_eq!(states, vec![99, 97, 94, 90, 85, 79]);
+    assert_eq!(db.prune(6, None).unwrap(), 0);
+
+    // The database is read back from disk when reopened.
+    drop(db);
+    let db = StateDb::<Withdrawals>::open(&dir, Pruning::ArchiveAll).unwrap();
+    assert_eq!(db.state_at(6).unwrap(), Some(79));
+    assert_eq!(db.state_at(42).unwrap(), None);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn cl_state_db_keeps_the_last_few_states() {
+    let dir = temp_state_db_dir("keep-last");
+    let mut db = StateDb::<Withdrawals>::open(&dir, Pruning::KeepLast(2)).unwrap();
+    db.insert_root(100, 0, &100).unwrap();
+    insert_chain(&mut db, 100, 1, &[1, 2, 3, 4, 5]);
+
+    // The root and blocks 1 and 2 go, and block 3 is snapshotted to start history from.
+    assert_eq!(db.prune(5, None).unwrap(), 3);
+    assert_eq!(db.state_at(2).unwrap(), None);
+    assert_eq!(db.state_at(3).unwrap(), Some(94));
+    drop(db);
+    let db = StateDb::<Withdrawals>::open(&dir, Pruning::KeepLast(2)).unwrap();
+    assert_eq!(db.len(), 3);
+    assert_eq!(db.state_at(5).unwrap(), Some(85));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn cl_state_db_forgets_forks_of_finalized_blocks() {
+    let dir = temp_state_db_dir("finalized");
+    let mut db = StateDb::<Withdrawals>::open(&dir, Pruning::FinalizedOnly).unwrap();
+    db.insert_root(100, 0, &100).unwrap();
+    insert_chain(&mut db, 100, 1, &[10, 10]);
+    insert_chain(&mut db, 100, 11, &[50, 1, 1]);
+    assert_eq!(db.prune(13, None).unwrap(), 0);
+
+    assert_eq!(db.prune(13, Some(12)).unwrap(), 4);
+    assert!(!db.contains(1) && !db.contains(11));
+    assert_eq!(db.state_at(13).unwrap(), Some(48));
+
+    // A diff that does not lead to the recorded state root is caught.
+    db.records.get_mut(&13).unwrap().diffs = vec![2];
+    assert!(matches!(
+        db.state_at(13),
+        Err(StateDbError::StateRootMismatch { block_hash: 13, .. })
+    ));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,671 @@
+//! Tools that follow the chain live, like block explorers and dashboards, could poll the node's
+//! RPC methods every second, but they would mostly be told what they already know. Instead they
+//! open a WebSocket connection to the node and subscribe to what they are interested in. The node
+//! pushes each new item down the connection as soon as it has it.
+//!
+//! Four things can be subscribed to:
+//!
+//! - `subscribe_newHeads` pushes the header of every block that becomes part of the best chain.
+//! - `subscribe_finalizedHeads` pushes the header of every block that is finalized.
+//! - `subscribe_events` pushes the events emitted by the transitions of every imported block.
+//! - `subscribe_transitionStatus` pushes every new status of the transition with the given hash,
+//!   so that a wallet can follow its submission into a finalized block.
+//!
+//! Each returns a subscription id, which `unsubscribe` takes to stop the pushes. Every pushed item
+//! is a JSON-RPC notification of the `subscription` method, naming the subscription it is for.
+//! Any other method is answered like it would be over HTTP.
+//!
+//! The node tells the subscriptions what happened. It publishes the head changes it gets from
+//! importing blocks, along with the events and finalized blocks its state machine and finality
+//! gadget tell it about, and the transition statuses that changed.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+
+use super::block_tree::{BlockTree, HeadChange};
+use super::p3_fork_choice::ForkChoice;
+use super::rpc::{RpcHandler, INVALID_PARAMS};
+use super::tx_pool::PrioritizedTransition;
+use super::tx_status::TxStatus;
+use super::{Hash, Header};
+use crate::c1_state_machine::ContextualStateMachine;
+use crate::c3_consensus::{CancelToken, Consensus};
+use crate::codec::Encode;
+use num::traits::{One, Zero};
+
+/// Appended to the client's key to prove the server speaks WebSocket, as RFC 6455 requires
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The largest message the server reads
+const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+/// How often connections check whether the server is stopping while nothing is being pushed
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+/// What a subscription follows
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Topic {
+    NewHeads,
+    FinalizedHeads,
+    Events,
+    /// The status of the transition with the given hash
+    TransitionStatus(Hash),
+}
+
+impl Topic {
+    /// The topic the given subscription method subscribes to, given the request's parameters.
+    /// Returns None for any other method.
+    fn of_request(method: &str, params: Option<&Value>) -> Option<Result<Topic, &'static str>> {
+        match method {
+            "subscribe_newHeads" => Some(Ok(Topic::NewHeads)),
+            "subscribe_finalizedHeads" => Some(Ok(Topic::FinalizedHeads)),
+            "subscribe_events" => Some(Ok(Topic::Events)),
+            "subscribe_transitionStatus" => Some(
+                params
+                    .and_then(|p| p.get(0))
+                    .and_then(Value::as_u64)
+                    .map(Topic::TransitionStatus)
+                    .ok_or("expected a transition hash"),
+            ),
+            _ => None,
+        }
+    }
+}
+
+/// A WebSocket message
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Frame {
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn text(text: String) -> Self {
+        Frame {
+            opcode: OPCODE_TEXT,
+            payload: text.into_bytes(),
+        }
+    }
+}
+
+struct Subscriber {
+    id: u64,
+    topic: Topic,
+    sink: Sender<Frame>,
+}
+
+#[derive(Default)]
+struct Hub {
+    next_id: u64,
+    subscribers: Vec<Subscriber>,
+}
+
+/// Everybody subscribed to the node's topics. Clones share the same subscribers, so the node
+/// keeps one to publish to while the WebSocket server adds and removes subscribers.
+#[derive(Clone, Default)]
+pub struct Subscriptions(Arc<Mutex<Hub>>);
+
+impl Subscriptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many subscriptions are open.
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().subscribers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Push the given item to every subscriber to the topic. Subscribers whose connection has
+    /// gone away are forgotten.
+    pub fn publish(&self, topic: Topic, item: &impl Serialize) {
+        let item = serde_json::to_value(item).expect("published items are always valid JSON");
+        self.0.lock().unwrap().subscribers.retain(|s| {
+            if s.topic != topic {
+                return true;
+            }
+            let notification = json!({
+                "jsonrpc": "2.0",
+                "method": "subscription",
+                "params": { "subscription": s.id, "result": item },
+            });
+            s.sink.send(Frame::text(notification.to_string())).is_ok()
+        });
+    }
+
+    /// Push the headers of the blocks an import enacted to the new heads subscribers, oldest
+    /// first.
+    pub fn publish_head_change<C, SM, FC>(&self, tree: &BlockTree<C, SM, FC>, change: &HeadChange)
+    where
+        C: Consensus,
+        C::Digest: Zero + One + core::hash::Hash + Serialize,
+        SM: ContextualStateMachine,
+        SM::State: core::hash::Hash + Clone,
+        SM::Transition: core::hash::Hash + Encode + Clone,
+        FC: ForkChoice<C::Digest>,
+    {
+        for header in change.enacted.iter().filter_map(|h| tree.header(*h)) {
+            self.publish(Topic::NewHeads, header);
+        }
+    }
+
+    /// Push the header of a newly finalized block to the finalized heads subscribers.
+    pub fn publish_finalized<Digest: Serialize>(&self, header: &Header<Digest>) {
+        self.publish(Topic::FinalizedHeads, header);
+    }
+
+    /// Push the events emitted by the given block's transitions to the events subscribers.
+    /// Nothing is pushed for a block without events.
+    pub fn publish_events<E: Serialize>(&self, block_hash: Hash, events: &[E]) {
+        if !events.is_empty() {
+            self.publish(
+                Topic::Events,
+                &json!({ "block_hash": block_hash, "events": events }),
+            );
+        }
+    }
+
+    /// Push each of the given status changes to the subscribers to its transition's status.
+    pub fn publish_status_changes(&self, changes: &[(Hash, TxStatus)]) {
+        for (t_hash, status) in changes {
+            self.publish(Topic::TransitionStatus(*t_hash), status);
+        }
+    }
+
+    fn subscribe(&self, topic: Topic, sink: Sender<Frame>) -> u64 {
+        let mut hub = self.0.lock().unwrap();
+        hub.next_id += 1;
+        let id = hub.next_id;
+        hub.subscribers.push(Subscriber { id, topic, sink });
+        id
+    }
+
+    /// Forget the given subscriptions. Returns how many there were.
+    fn unsubscribe(&self, ids: &[u64]) -> usize {
+        let mut hub = self.0.lock().unwrap();
+        let before = hub.subscribers.len();
+        hub.subscribers.retain(|s| !ids.contains(&s.id));
+        before - hub.subscribers.len()
+    }
+}
+
+impl<C, SM, FC> RpcHandler<C, SM, FC>
+where
+    C: Consensus,
+    C::Digest: Zero + One + core::hash::Hash + Serialize,
+    SM: ContextualStateMachine,
+    SM::State: core::hash::Hash + Clone + Serialize,
+    SM::Transition:
+        core::hash::Hash + Encode + Clone + PrioritizedTransition + Serialize + DeserializeOwned,
+    FC: ForkChoice<C::Digest>,
+    Self: Sync,
+{
+    /// Accept WebSocket connections on the given listener until the token is cancelled. Each
+    /// connection may subscribe to the given subscriptions, and call any other method too.
+    pub fn serve_ws(
+        &self,
+        subscriptions: &Subscriptions,
+        listener: &TcpListener,
+        cancel: &CancelToken,
+    ) -> std::io::Result<()> {
+        listener.set_nonblocking(true)?;
+        std::thread::scope(|scope| {
+            while !cancel.is_cancelled() {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        stream.set_nonblocking(false)?;
+                        scope.spawn(move || {
+                            // A connection that breaks only concerns its own client.
+                            let _ = self.ws_connection(stream, subscriptions, cancel);
+                        });
+                    }
+                    Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                        std::thread::sleep(POLL_INTERVAL)
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Complete the WebSocket handshake, then answer the client's messages until either side
+    /// closes the connection. Replies and pushed items share an outgoing queue, which a second
+    /// thread writes to the connection.
+    fn ws_connection(
+        &self,
+        stream: TcpStream,
+        subscriptions: &Subscriptions,
+        cancel: &CancelToken,
+    ) -> std::io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let Some(key) = read_handshake(&mut reader)? else {
+            return write_all(
+                &stream,
+                b"HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n",
+            );
+        };
+        write_all(
+            &stream,
+            format!(
+                "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                 Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+                accept_key(&key)
+            )
+            .as_bytes(),
+        )?;
+
+        let (outgoing, queue) = mpsc::channel();
+        let closed = AtomicBool::new(false);
+        let mut own = Vec::new();
+        std::thread::scope(|scope| {
+            scope.spawn(|| write_queue(&stream, queue, &closed, cancel));
+            while let Ok(frame) = read_frame(&mut reader) {
+                match frame.opcode {
+                    OPCODE_TEXT => {
+                        let text = String::from_utf8_lossy(&frame.payload);
+                        let reply = self.ws_request(&text, subscriptions, &outgoing, &mut own);
+                        let _ = outgoing.send(Frame::text(reply));
+                    }
+                    OPCODE_PING => {
+                        let _ = outgoing.send(Frame {
+                            opcode: OPCODE_PONG,
+                            ..frame
+                        });
+                    }
+                    OPCODE_CLOSE => {
+                        let _ = outgoing.send(frame);
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            subscriptions.unsubscribe(&own);
+            closed.store(true, Ordering::Relaxed);
+        });
+        Ok(())
+    }
+
+    /// Answer a request sent over a WebSocket connection. Subscriptions made are added to
+    /// `own`, so they can be dropped when the connection closes.
+    fn ws_request(
+        &self,
+        request: &str,
+        subscriptions: &Subscriptions,
+        outgoing: &Sender<Frame>,
+        own: &mut Vec<u64>,
+    ) -> String {
+        let Ok(value) = serde_json::from_str::<Value>(request) else {
+            return self.handle(request);
+        };
+        let id = value.get("id").cloned().unwrap_or(Value::Null);
+        let method = value.get("method").and_then(Value::as_str).unwrap_or("");
+        let invalid_params = |message: &str| {
+            let error = json!({ "code": INVALID_PARAMS, "message": message });
+            json!({ "jsonrpc": "2.0", "error": error, "id": id }).to_string()
+        };
+        let result = if let Some(topic) = Topic::of_request(method, value.get("params")) {
+            let topic = match topic {
+                Ok(topic) => topic,
+                Err(message) => return invalid_params(message),
+            };
+            let subscription = subscriptions.subscribe(topic, outgoing.clone());
+            own.push(subscription);
+            json!(subscription)
+        } else if method == "unsubscribe" {
+            match value.pointer("/params/0").and_then(Value::as_u64) {
+                Some(subscription) if own.contains(&subscription) => {
+                    own.retain(|s| *s != subscription);
+                    json!(subscriptions.unsubscribe(&[subscription]) == 1)
+                }
+                Some(_) => json!(false),
+                None => return invalid_params("expected a subscription id"),
+            }
+        } else {
+            return self.handle(request);
+        };
+        json!({ "jsonrpc": "2.0", "result": result, "id": id }).to_string()
+    }
+}
+
+/// Write the queued frames to the connection until it is closed or the server stops, then shut
+/// the connection down, which also wakes the reader.
+fn write_queue(
+    stream: &TcpStream,
+    queue: Receiver<Frame>,
+    closed: &AtomicBool,
+    cancel: &CancelToken,
+) {
+    while !cancel.is_cancelled() {
+        match queue.recv_timeout(POLL_INTERVAL) {
+            Ok(frame) => {
+                let is_close = frame.opcode == OPCODE_CLOSE;
+                if write_frame(stream, &frame, None).is_err() || is_close {
+                    break;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) if !closed.load(Ordering::Relaxed) => {}
+            Err(_) => break,
+        }
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+/// Read the client's opening handshake, and return its `Sec-WebSocket-Key`, if it sent one.
+fn read_handshake(reader: &mut impl BufRead) -> std::io::Result<Option<String>> {
+    let mut key = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            return Ok(key);
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("sec-websocket-key") {
+                key = Some(value.trim().to_string());
+            }
+        }
+    }
+}
+
+/// The `Sec-WebSocket-Accept` answering the given key.
+fn accept_key(key: &str) -> String {
+    base64(&Sha1::digest(format!("{key}{WEBSOCKET_GUID}")))
+}
+
+/// Standard base64 with padding.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = u32::from(b[0]) << 16 | u32::from(b[1]) << 8 | u32::from(b[2]);
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Read one frame. Fragmented messages are not supported, which no client sending short JSON
+/// requests needs.
+fn read_frame(reader: &mut impl Read) -> std::io::Result<Frame> {
+    let mut head = [0u8; 2];
+    reader.read_exact(&mut head)?;
+    let masked = head[1] & 0x80 != 0;
+    let len = match head[1] & 0x7F {
+        126 => {
+            let mut len = [0u8; 2];
+            reader.read_exact(&mut len)?;
+            u64::from(u16::from_be_bytes(len))
+        }
+        127 => {
+            let mut len = [0u8; 8];
+            reader.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => u64::from(len),
+    };
+    let len = usize::try_from(len)
+        .ok()
+        .filter(|len| *len <= MAX_MESSAGE_LEN)
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "frame too long"))?;
+    let mut mask = [0u8; 4];
+    if masked {
+        reader.read_exact(&mut mask)?;
+    }
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Frame {
+        opcode: head[0] & 0x0F,
+        payload,
+    })
+}
+
+/// Write one unfragmented frame, masked with the given key if there is one. Clients must mask
+/// their frames, and servers must not.
+fn write_frame(
+    mut stream: &TcpStream,
+    frame: &Frame,
+    mask: Option<[u8; 4]>,
+) -> std::io::Result<()> {
+    let mut bytes = vec![0x80 | frame.opcode];
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match frame.payload.len() {
+        len @ 0..=125 => bytes.push(mask_bit | len as u8),
+        len @ 126..=0xFFFF => {
+            bytes.push(mask_bit | 126);
+            bytes.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            bytes.push(mask_bit | 127);
+            bytes.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    let mask = mask.map_or([0; 4], |m| {
+        bytes.extend_from_slice(&m);
+        m
+    });
+    bytes.extend(
+        frame
+            .payload
+            .iter()
+            .enumerate()
+            .map(|(i, b)| b ^ mask[i % 4]),
+    );
+    stream.write_all(&bytes)?;
+    stream.flush()
+}
+
+fn write_all(mut stream: &TcpStream, bytes: &[u8]) -> std::io::Result<()> {
+    stream.write_all(bytes)?;
+    stream.flush()
+}
+
+#[cfg(test)]
+use super::p3_fork_choice::LongestChain;
+#[cfg(test)]
+use super::tx_pool::TxPool;
+#[cfg(test)]
+use super::{Block, Withdrawals};
+#[cfg(test)]
+use crate::c1_state_machine::BlockContext;
+#[cfg(test)]
+use crate::c3_consensus::p1_pow::PoW;
+#[cfg(test)]
+use crate::hash;
+
+/// A client's end of a WebSocket connection
+#[cfg(test)]
+pub(crate) struct TestClient(BufReader<TcpStream>);
+
+#[cfg(test)]
+impl TestClient {
+    pub(crate) fn connect(addr: std::net::SocketAddr) -> Self {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        write_all(
+            &stream,
+            b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n",
+        )
+        .unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut response = String::new();
+        while !response.ends_with("\r\n\r\n") {
+            reader.read_line(&mut response).unwrap();
+        }
+        assert!(response.starts_with("HTTP/1.1 101"));
+        // The example from RFC 6455.
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        TestClient(reader)
+    }
+
+    fn send(&mut self, frame: Frame) {
+        write_frame(self.0.get_ref(), &frame, Some([1, 2, 3, 4])).unwrap();
+    }
+
+    pub(crate) fn receive(&mut self) -> Value {
+        let frame = read_frame(&mut self.0).unwrap();
+        assert_eq!(frame.opcode, OPCODE_TEXT);
+        serde_json::from_slice(&frame.payload).unwrap()
+    }
+
+    pub(crate) fn request(&mut self, method: &str, params: Value) -> Value {
+        let request = json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 1 });
+        self.send(Frame::text(request.to_string()));
+        self.receive()
+    }
+}
+
+#[test]
+fn cl_subscriptions_push_new_heads_and_events_over_websocket() {
+    let tree = Arc::new(Mutex::new(BlockTree::new(
+        PoW::new(u64::MAX / 4),
+        LongestChain,
+        100,
+    )));
+    let handler: RpcHandler<PoW, Withdrawals, LongestChain> =
+        RpcHandler::new(tree.clone(), Arc::new(Mutex::new(TxPool::new())));
+    let subscriptions = Subscriptions::new();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let cancel = CancelToken::new();
+
+    std::thread::scope(|scope| {
+        let server = scope.spawn(|| handler.serve_ws(&subscriptions, &listener, &cancel));
+        let mut client = TestClient::connect(addr);
+        let heads = client.request("subscribe_newHeads", json!([]))["result"].clone();
+        let events = client.request("subscribe_events", json!([]))["result"].clone();
+        assert_ne!(heads, events);
+        assert_eq!(subscriptions.len(), 2);
+
+        let genesis = Block::<PoW, Withdrawals>::genesis(&100);
+        let context = BlockContext {
+            height: 1,
+            ..BlockContext::default()
+        };
+        let block = genesis.child(&100, vec![30], context).unwrap();
+        let change = tree.lock().unwrap().import(block.clone()).unwrap();
+        subscriptions.publish_head_change(&tree.lock().unwrap(), &change);
+        subscriptions.publish_events(hash(&block.header), &["withdrew 30"]);
+        subscriptions.publish_finalized(&block.header);
+
+        let pushed = client.receive();
+        assert_eq!(pushed["method"], "subscription");
+        assert_eq!(pushed["params"]["subscription"], heads);
+        assert_eq!(pushed["params"]["result"]["height"], 1);
+        let pushed = client.receive();
+        assert_eq!(pushed["params"]["subscription"], events);
+        assert_eq!(pushed["params"]["result"]["events"], json!(["withdrew 30"]));
+
+        // Other methods work over the same connection, and nothing was pushed for finality.
+        let head = client.request("chain_getHead", json!([]));
+        assert_eq!(head["result"], hash(&block.header));
+
+        assert_eq!(
+            client.request("unsubscribe", json!([heads]))["result"],
+            true
+        );
+        assert_eq!(
+            client.request("unsubscribe", json!([heads]))["result"],
+            false
+        );
+        assert_eq!(subscriptions.len(), 1);
+
+        // Closing the connection drops its remaining subscriptions.
+        client.send(Frame {
+            opcode: OPCODE_CLOSE,
+            payload: vec![],
+        });
+        assert_eq!(read_frame(&mut client.0).unwrap().opcode, OPCODE_CLOSE);
+        while !subscriptions.is_empty() {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+
+        cancel.cancel();
+        server.join().unwrap().unwrap();
+    });
+}
+
+#[test]
+fn cl_subscriptions_forget_subscribers_that_went_away() {
+    let subscriptions = Subscriptions::new();
+    let (sink, queue) = mpsc::channel();
+    let (gone, _) = mpsc::channel();
+    let id = subscriptions.subscribe(Topic::FinalizedHeads, sink);
+    subscriptions.subscribe(Topic::FinalizedHeads, gone);
+    subscriptions.subscribe(Topic::NewHeads, mpsc::channel().0);
+
+    subscriptions.publish(Topic::FinalizedHeads, &5);
+    assert_eq!(subscriptions.len(), 2);
+    let frame = queue.try_recv().unwrap();
+    let pushed: Value = serde_json::from_slice(&frame.payload).unwrap();
+    assert_eq!(pushed["params"], json!({ "subscription": id, "result": 5 }));
+
+    assert_eq!(base64(b"foob"), "Zm9vYg==");
+    assert_eq!(base64(b"fooba"), "Zm9vYmE=");
+    assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+}
+
+#[test]
+fn cl_subscriptions_follow_the_status_of_one_transition() {
+    assert_eq!(
+        Topic::of_request("subscribe_transitionStatus", Some(&json!([7]))),
+        Some(Ok(Topic::TransitionStatus(7)))
+    );
+    assert!(matches!(
+        Topic::of_request("subscribe_transitionStatus", Some(&json!(["seven"]))),
+        Some(Err(_))
+    ));
+    assert_eq!(Topic::of_request("chain_getHead", None), None);
+
+    let subscriptions = Subscriptions::new();
+    let (sink, queue) = mpsc::channel();
+    let id = subscriptions.subscribe(Topic::TransitionStatus(7), sink);
+    subscriptions.publish_status_changes(&[
+        (7, TxStatus::InPool),
+        (8, TxStatus::InPool),
+        (7, TxStatus::InBlock(42)),
+    ]);
+    let pushed: Vec<Value> = queue
+        .try_iter()
+        .map(|frame| serde_json::from_slice(&frame.payload).unwrap())
+        .collect();
+    assert_eq!(
+        pushed,
+        vec![
+            json!({ "jsonrpc": "2.0", "method": "subscription", "params": { "subscription": id, "result": "InPool" } }),
+            json!({ "jsonrpc": "2.0", "method": "subscription", "params": { "subscription": id, "result": { "InBlock": 42 } } }),
+        ]
+    );
+}
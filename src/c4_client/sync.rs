@@ -0,0 +1,427 @@
+//! Gossip tells a node about blocks as they are authored, which is enough for a node that has
+//! been online all along. A node that has just started, or was offline for a while, first has to
+//! catch up on everything it missed, by downloading it from a peer.
+//!
+//! The node finds out where its chain and the peer's best chain part ways, then downloads the
+//! peer's headers from there on in batches. Before asking for the bodies, it checks that each
+//! batch of headers forms a chain leading on from blocks it already has, so a peer cannot make
+//! it download blocks that could never be imported. The downloaded blocks are then imported into
+//! the block tree, which checks them like any other block. Each batch is imported at once, so
+//! the tree checks the seals of its blocks in parallel.
+//!
+//! The peer may be on another fork, so the point where the chains part is not necessarily the
+//! node's best head. Every block the node has comes with all of its ancestors, so the blocks of
+//! the peer's chain the node has are a prefix of that chain, and the node finds where it ends by
+//! binary search on height, asking for one header at a time.
+//!
+//! Executing every block since genesis takes a new node longer the longer the chain gets. A node
+//! that trusts the checkpoints in its chain spec can warp sync instead. It downloads the latest
+//! checkpoint the peer has, along with the state after it, and starts its tree from there, with
+//! only the headers of the blocks before it. The checkpoint's hash commits to its header, and so
+//! to its body and the state after it, as well as to every header before it, so all of these are
+//! checked against it rather than by executing anything. The rest of the chain is then synced as
+//! usual.
+
+use super::block_tree::{BlockTree, ImportError};
+use super::network::{
+    BlockData, Message, Network, NetworkError, PeerId, SnapshotData, MAX_BLOCKS, MAX_HEADERS,
+};
+use super::p3_fork_choice::ForkChoice;
+use super::{Block, Hash, Header};
+use crate::c1_state_machine::ContextualStateMachine;
+use crate::c3_consensus::finality::BlockId;
+use crate::c3_consensus::Consensus;
+use crate::codec::{Decode, Encode};
+use crate::hash;
+use num::traits::{One, Zero};
+
+/// The ways syncing from a peer can fail
+#[derive(Debug)]
+pub enum SyncError {
+    /// A request or its answer did not get through
+    Network(NetworkError),
+    /// The peer answered with something other than what was asked for, or with headers that do
+    /// not lead on from the node's chain
+    BadResponse,
+    /// The peer does not have the state after the checkpoint to warp sync from
+    NoSnapshot,
+    /// The block tree refused a downloaded block
+    Rejected {
+        block_hash: Hash,
+        error: ImportError,
+    },
+}
+
+impl From<NetworkError> for SyncError {
+    fn from(e: NetworkError) -> Self {
+        SyncError::Network(e)
+    }
+}
+
+impl<C, SM> Network<C, SM>
+where
+    C: Consensus,
+    C: Sync,
+    C::Digest: Zero + One + core::hash::Hash + Encode + Decode + Send + Sync + 'static,
+    SM: ContextualStateMachine,
+    SM::State: core::hash::Hash + Clone,
+    SM::Transition: core::hash::Hash + Encode + Decode + Clone + Send + Sync + 'static,
+{
+    /// Download the blocks of the given peer's best chain that the tree is missing, and import
+    /// them. Returns how many blocks were imported.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip(self, tree))
+    )]
+    pub fn sync<FC: ForkChoice<C::Digest>>(
+        &mut self,
+        peer: PeerId,
+        tree: &mut BlockTree<C, SM, FC>,
+    ) -> Result<usize, SyncError> {
+        let peer_height = self
+            .peer(peer)
+            .ok_or(NetworkError::UnknownPeer(peer))?
+            .best_height;
+        let mut from = self.common_ancestor(peer, tree, peer_height)? + 1;
+        let mut imported = 0;
+        loop {
+            let headers = self.headers(peer, from, MAX_HEADERS)?;
+            if !leads_on_from(tree, from, &headers) {
+                return Err(SyncError::BadResponse);
+            }
+            let missing: Vec<Hash> = headers
+                .iter()
+                .map(hash)
+                .filter(|h| !tree.contains(*h))
+                .collect();
+            let mut blocks = Vec::with_capacity(missing.len());
+            for hashes in missing.chunks(MAX_BLOCKS) {
+                let Message::Blocks(batch) = self.request(
+                    peer,
+                    &Message::GetBlocks {
+                        hashes: hashes.to_vec(),
+                    },
+                )?
+                else {
+                    return Err(SyncError::BadResponse);
+                };
+                if batch.len() != hashes.len()
+                    || batch.iter().zip(hashes).any(|(b, h)| hash(&b.header) != *h)
+                {
+                    return Err(SyncError::BadResponse);
+                }
+                blocks.extend(batch.into_iter().map(BlockData::into_block));
+            }
+            for (block_hash, result) in missing.into_iter().zip(tree.import_batch(blocks)) {
+                match result {
+                    Ok(_) => imported += 1,
+                    Err(ImportError::AlreadyKnown) => {}
+                    Err(error) => return Err(SyncError::Rejected { block_hash, error }),
+                }
+            }
+            // A short batch is the end of the peer's chain.
+            if (headers.len() as u64) < MAX_HEADERS {
+                return Ok(imported);
+            }
+            from += MAX_HEADERS;
+        }
+    }
+
+    /// Warp sync from the given peer to the latest of the given checkpoints its best chain
+    /// reaches, then sync the rest of its chain. A tree that holds more than its genesis block
+    /// already has a state to carry on from, and just syncs, as does any tree if the peer has
+    /// not reached a checkpoint yet. Returns how many blocks were imported, the checkpoint
+    /// included.
+    pub fn warp_sync<FC: ForkChoice<C::Digest>>(
+        &mut self,
+        peer: PeerId,
+        tree: &mut BlockTree<C, SM, FC>,
+        checkpoints: &[BlockId],
+    ) -> Result<usize, SyncError>
+    where
+        SM::State: Decode,
+    {
+        let peer_height = self
+            .peer(peer)
+            .ok_or(NetworkError::UnknownPeer(peer))?
+            .best_height;
+        let checkpoint = checkpoints
+            .iter()
+            .filter(|c| c.height > 0 && c.height <= peer_height)
+            .max_by_key(|c| c.height);
+        let Some(checkpoint) = checkpoint.filter(|_| tree.best_height() == 0) else {
+            return self.sync(peer, tree);
+        };
+
+        let Message::Snapshot(snapshot) = self.request(
+            peer,
+            &Message::GetSnapshot {
+                block_hash: checkpoint.hash,
+            },
+        )?
+        else {
+            return Err(SyncError::BadResponse);
+        };
+        let (root, state) = checked_snapshot(checkpoint, snapshot.ok_or(SyncError::NoSnapshot)?)?;
+        let mut pruned = Vec::with_capacity(checkpoint.height as usize);
+        while (pruned.len() as u64) < checkpoint.height {
+            let from = pruned.len() as u64;
+            let headers = self.headers(peer, from, MAX_HEADERS.min(checkpoint.height - from))?;
+            if headers.is_empty() {
+                return Err(SyncError::BadResponse);
+            }
+            pruned.extend(headers);
+        }
+        // The tree checks that the headers lead from its genesis block to the checkpoint.
+        tree.resume_pruned(pruned, root, state)
+            .map_err(|error| SyncError::Rejected {
+                block_hash: checkpoint.hash,
+                error,
+            })?;
+        Ok(1 + self.sync(peer, tree)?)
+    }
+
+    /// The height of the last block of the peer's best chain that is in the tree. The handshake
+    /// checked that the genesis blocks match, so there always is one.
+    fn common_ancestor<FC: ForkChoice<C::Digest>>(
+        &mut self,
+        peer: PeerId,
+        tree: &BlockTree<C, SM, FC>,
+        peer_height: u64,
+    ) -> Result<u64, SyncError> {
+        let (mut known, mut unknown) = (0, peer_height + 1);
+        while unknown - known > 1 {
+            let middle = known + (unknown - known) / 2;
+            match self.headers(peer, middle, 1)?.first() {
+                Some(header) if tree.contains(hash(header)) => known = middle,
+                // The peer's chain may have become shorter since it connected.
+                _ => unknown = middle,
+            }
+        }
+        Ok(known)
+    }
+
+    /// Ask the peer for up to `count` headers of its best chain, starting at height `from`.
+    fn headers(
+        &mut self,
+        peer: PeerId,
+        from: u64,
+        count: u64,
+    ) -> Result<Vec<Header<C::Digest>>, SyncError> {
+        match self.request(peer, &Message::GetHeaders { from, count })? {
+            Message::Headers(headers) if headers.len() as u64 <= count => Ok(headers),
+            _ => Err(SyncError::BadResponse),
+        }
+    }
+}
+
+/// The checkpoint block in the given snapshot and the state after it, if that is what they are:
+/// the block's header must be the checkpoint's, its body the one the header commits to, and the
+/// state the one the header commits to.
+fn checked_snapshot<C, SM>(
+    checkpoint: &BlockId,
+    snapshot: SnapshotData<C::Digest, SM::Transition>,
+) -> Result<(Block<C, SM>, SM::State), SyncError>
+where
+    C: Consensus,
+    C::Digest: Zero + One + core::hash::Hash,
+    SM: ContextualStateMachine,
+    SM::State: core::hash::Hash + Clone + Decode,
+    SM::Transition: core::hash::Hash + Encode + Clone,
+{
+    let block: Block<C, SM> = snapshot.block.into_block();
+    let state = SM::State::decode(&snapshot.state).ok_or(SyncError::BadResponse)?;
+    let committed = hash(&block.header) == checkpoint.hash
+        && block.header.height == checkpoint.height
+        && block.check_extrinsics_root().is_ok()
+        && hash(&state) == block.header.state_root;
+    if !committed {
+        return Err(SyncError::BadResponse);
+    }
+    Ok((block, state))
+}
+
+/// Whether the given headers form a chain starting at height `from`, whose first block's parent
+/// is in the tree.
+fn leads_on_from<C, SM, FC>(
+    tree: &BlockTree<C, SM, FC>,
+    from: u64,
+    headers: &[Header<C::Digest>],
+) -> bool
+where
+    C: Consensus,
+    C::Digest: Zero + One + core::hash::Hash,
+    SM: ContextualStateMachine,
+    SM::State: core::hash::Hash + Clone,
+    SM::Transition: core::hash::Hash + Encode + Clone,
+    FC: ForkChoice<C::Digest>,
+{
+    let Some(first) = headers.first() else {
+        return true;
+    };
+    first.height == from
+        && tree.contains(first.parent)
+        && headers
+            .windows(2)
+            .all(|w| w[1].parent == hash(&w[0]) && w[1].height == w[0].height + 1)
+}
+
+#[cfg(test)]
+use super::network::TestNode;
+#[cfg(test)]
+use super::Withdrawals;
+#[cfg(test)]
+use crate::c1_state_machine::BlockContext;
+#[cfg(test)]
+use crate::c3_consensus::p1_pow::PoW;
+#[cfg(test)]
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Blocks withdrawing the given amounts, one block per amount, each on top of the last, starting
+/// on top of the given block.
+#[cfg(test)]
+fn extend(
+    parent: &Block<PoW, Withdrawals>,
+    mut state: u64,
+    amounts: &[u64],
+) -> Vec<Block<PoW, Withdrawals>> {
+    let mut blocks: Vec<Block<PoW, Withdrawals>> = Vec::new();
+    for amount in amounts {
+        let parent = blocks.last().unwrap_or(parent);
+        let context = BlockContext {
+            height: parent.header.height + 1,
+            ..BlockContext::default()
+        };
+        let block = parent.child(&state, vec![*amount], context).unwrap();
+        state -= amount;
+        blocks.push(block);
+    }
+    blocks
+}
+
+#[test]
+fn cl_sync_downloads_the_peers_chain_from_the_common_ancestor() {
+    // Alice has a long chain. Bob has its first two blocks, then one of his own.
+    let mut alice = TestNode::new(1000);
+    let mut bob = TestNode::new(1000);
+    let genesis = Block::<PoW, Withdrawals>::genesis(&1000);
+    let chain = extend(&genesis, 1000, &[0; 2 * MAX_HEADERS as usize + 10]);
+    for block in &chain {
+        alice.tree.import(block.clone()).unwrap();
+    }
+    let fork = extend(&chain[1], 1000, &[5]);
+    for block in chain[..2].iter().chain(&fork) {
+        bob.tree.import(block.clone()).unwrap();
+    }
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let done = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            alice.network.accept(&listener, &alice.tree).unwrap();
+            while !done.load(Ordering::Relaxed) {
+                alice
+                    .network
+                    .poll(&alice.tree, &mut alice.queue, &mut alice.pool);
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+        });
+        let peer = bob.network.connect(addr, &bob.tree).unwrap();
+        let imported = bob.network.sync(peer, &mut bob.tree).unwrap();
+        assert_eq!(imported, chain.len() - 2);
+        assert_eq!(bob.tree.best_head(), hash(&chain.last().unwrap().header));
+        // Bob keeps his own block, but it is no longer on the best chain.
+        assert!(bob.tree.contains(hash(&fork[0].header)));
+        assert_eq!(bob.network.sync(peer, &mut bob.tree).unwrap(), 0);
+        done.store(true, Ordering::Relaxed);
+    });
+}
+
+#[test]
+fn cl_sync_warps_to_the_latest_checkpoint_the_peer_has() {
+    let mut alice = TestNode::new(1000);
+    let mut bob = TestNode::new(1000);
+    let genesis = Block::<PoW, Withdrawals>::genesis(&1000);
+    let chain = extend(&genesis, 1000, &[1; MAX_HEADERS as usize + 20]);
+    for block in &chain {
+        alice.tree.import(block.clone()).unwrap();
+    }
+    let checkpoint = |height: usize| BlockId {
+        height: height as u64,
+        hash: hash(&chain[height - 1].header),
+    };
+    // The latest checkpoint is past the end of Alice's chain, so Bob warps to the one before.
+    let checkpoints = [checkpoint(10), checkpoint(MAX_HEADERS as usize + 5)];
+    let beyond = BlockId {
+        height: chain.len() as u64 + 1,
+        hash: 0,
+    };
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let done = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        scope.spawn(|| {
+            alice.network.accept(&listener, &alice.tree).unwrap();
+            while !done.load(Ordering::Relaxed) {
+                alice
+                    .network
+                    .poll(&alice.tree, &mut alice.queue, &mut alice.pool);
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+        });
+        let peer = bob.network.connect(addr, &bob.tree).unwrap();
+        // A checkpoint Alice does not have leaves Bob's tree alone.
+        let unknown = BlockId {
+            hash: 1,
+            ..checkpoints[1]
+        };
+        assert!(matches!(
+            bob.network.warp_sync(peer, &mut bob.tree, &[unknown]),
+            Err(SyncError::NoSnapshot)
+        ));
+        assert_eq!(bob.tree.best_height(), 0);
+
+        let imported = bob
+            .network
+            .warp_sync(
+                peer,
+                &mut bob.tree,
+                &[checkpoints[0], checkpoints[1], beyond],
+            )
+            .unwrap();
+        assert_eq!(imported, 16);
+        assert_eq!(bob.tree.best_head(), alice.tree.best_head());
+        assert_eq!(bob.tree.best_state(), alice.tree.best_state());
+        // Bob never had the blocks before the checkpoint, only their headers.
+        assert!(bob.tree.is_pruned(checkpoints[0].hash));
+        assert_eq!(bob.tree.best_chain(), alice.tree.best_chain());
+        // A tree past genesis just syncs.
+        assert_eq!(
+            bob.network
+                .warp_sync(peer, &mut bob.tree, &checkpoints)
+                .unwrap(),
+            0
+        );
+        done.store(true, Ordering::Relaxed);
+    });
+}
+
+#[test]
+fn cl_sync_checks_that_headers_lead_on_from_the_tree() {
+    let node = TestNode::new(100);
+    let genesis = Block::<PoW, Withdrawals>::genesis(&100);
+    let chain = extend(&genesis, 100, &[10, 20, 30]);
+    let headers: Vec<_> = chain.iter().map(|b| b.header.clone()).collect();
+    assert!(leads_on_from(&node.tree, 1, &headers));
+    assert!(leads_on_from(&node.tree, 1, &[]));
+    // Not starting where they were asked to, not following a known block, or with a gap.
+    assert!(!leads_on_from(&node.tree, 2, &headers));
+    assert!(!leads_on_from(&node.tree, 2, &headers[1..]));
+    assert!(!leads_on_from(
+        &node.tree,
+        1,
+        &[headers[0].clone(), headers[2].clone()]
+    ));
+}
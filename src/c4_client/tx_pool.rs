@@ -0,0 +1,667 @@
+//! Transitions reach a node one at a time, from its users or from other nodes, long before any
+//! block includes them. The transaction pool is where they wait. It turns away transitions that
+//! could not execute on top of the best block as it stands, and transitions it already has, so
+//! that block builders are not handed junk or repeats.
+//!
+//! The pool has to keep up with the chain. When a block is imported its transitions are evicted,
+//! since they are no longer waiting for anything. When a reorg retracts blocks, their transitions
+//! are waiting again, and they go back in the pool, except for coinbases, which only the builder of
+//! a block may add. Whatever the chain does, transitions that were valid against the old best state
+//! may not be valid against the new one, so everything left in the pool is checked again.
+//!
+//! Block space is scarce, so the pool hands out the transitions paying the most first. Nonced
+//! transitions complicate this. A sender's transitions can only execute in nonce order, so a
+//! transition paying a lot still waits for its sender's earlier, cheaper ones, and a transition
+//! whose nonce comes after others still in the pool is checked as if they had executed.
+//!
+//! Checking a transition costs the node something, and a spammer can send the same junk over and
+//! over. A pool may be given a ban window, and then a transition it turned away, dropped as
+//! invalid, or saw a block builder reject is banned for that long: submitting it again is turned
+//! away without checking it. The chain may move on in a way that makes a banned transition
+//! valid, so bans expire rather than lasting forever.
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Duration;
+
+use super::author::TransitionSource;
+use super::Hash;
+use crate::c1_state_machine::p7_multiasset::{AssetTransaction, MultiAsset};
+use crate::c1_state_machine::pair::Either;
+use crate::c1_state_machine::with_nonces::Nonced;
+use crate::c1_state_machine::{Rewarded, StateMachine, User, Weighted};
+use crate::c3_consensus::{SystemClock, TimeProvider};
+use crate::hash;
+
+/// A transition that may pay to be included sooner, and may be one of a sequence of transitions
+/// from the same sender. The provided methods describe a transition that pays nothing and stands
+/// alone, which the pool includes in the order it arrived.
+pub trait PrioritizedTransition {
+    /// The fee or tip the transition pays. The pool includes those paying more first.
+    fn priority(&self) -> u64 {
+        0
+    }
+
+    /// Who sent the transition and its nonce, if it is one of a sequence. Transitions from the
+    /// same sender are included in nonce order whatever they pay.
+    fn sender_nonce(&self) -> Option<(User, u64)> {
+        None
+    }
+
+    /// Whether the transition is a block's coinbase. Only block builders add coinbases, so one
+    /// never goes back in the pool when its block is retracted.
+    fn is_coinbase(&self) -> bool {
+        false
+    }
+}
+
+/// A nonced call pays whatever the call pays.
+impl<T: PrioritizedTransition> PrioritizedTransition for Nonced<T> {
+    fn priority(&self) -> u64 {
+        self.call.priority()
+    }
+
+    fn sender_nonce(&self) -> Option<(User, u64)> {
+        Some((self.signer, self.nonce))
+    }
+
+    fn is_coinbase(&self) -> bool {
+        self.call.is_coinbase()
+    }
+}
+
+/// Asset transactions pay no fees, so the pool includes them in the order they arrive.
+impl PrioritizedTransition for AssetTransaction {
+    fn is_coinbase(&self) -> bool {
+        MultiAsset::is_coinbase(self)
+    }
+}
+
+/// A transition for one side of a pair, or one version of an upgraded machine, pays whatever
+/// that side's transition pays.
+impl<L: PrioritizedTransition, R: PrioritizedTransition> PrioritizedTransition for Either<L, R> {
+    fn priority(&self) -> u64 {
+        match self {
+            Either::Left(t) => t.priority(),
+            Either::Right(t) => t.priority(),
+        }
+    }
+
+    fn sender_nonce(&self) -> Option<(User, u64)> {
+        match self {
+            Either::Left(t) => t.sender_nonce(),
+            Either::Right(t) => t.sender_nonce(),
+        }
+    }
+
+    fn is_coinbase(&self) -> bool {
+        match self {
+            Either::Left(t) => t.is_coinbase(),
+            Either::Right(t) => t.is_coinbase(),
+        }
+    }
+}
+
+/// The reasons the pool may turn a transition away
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PoolError {
+    /// The transition is already in the pool
+    AlreadyKnown,
+    /// The transition would be rejected on top of the best state, after any of its sender's
+    /// transitions with lower nonces in the pool
+    Invalid,
+    /// The transition was found invalid within the ban window, and is not checked again until
+    /// its ban expires
+    Banned,
+}
+
+/// A pool of transitions waiting to be included in a block, in the order they arrived.
+pub struct TxPool<SM: StateMachine, Clock = SystemClock> {
+    pending: Vec<SM::Transition>,
+    /// The hashes of the pending transitions
+    known: HashSet<Hash>,
+    /// How long invalid transitions are banned for. Nothing is banned for a zero window.
+    ban_window: Duration,
+    /// When the ban on each banned transition expires, in milliseconds, by hash
+    banned: HashMap<Hash, u64>,
+    clock: Clock,
+}
+
+impl<SM: StateMachine, Clock: Default> Default for TxPool<SM, Clock> {
+    fn default() -> Self {
+        TxPool {
+            pending: Vec::new(),
+            known: HashSet::new(),
+            ban_window: Duration::ZERO,
+            banned: HashMap::new(),
+            clock: Clock::default(),
+        }
+    }
+}
+
+impl<SM: StateMachine, Clock: TimeProvider + Default> TxPool<SM, Clock>
+where
+    SM::State: Clone,
+    SM::Transition: PrioritizedTransition + core::hash::Hash + Clone,
+{
+    /// An empty pool that bans nothing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<SM: StateMachine, Clock: TimeProvider> TxPool<SM, Clock>
+where
+    SM::State: Clone,
+    SM::Transition: PrioritizedTransition + core::hash::Hash + Clone,
+{
+    /// Ban transitions found invalid for the given window.
+    pub fn with_ban_window(mut self, window: Duration) -> Self {
+        self.ban_window = window;
+        self
+    }
+
+    /// Time bans by the given clock rather than the system's.
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Accept the given transition, if it is new, not banned, and would be accepted on top of
+    /// the given best state once its sender's pending transitions with lower nonces have
+    /// executed. A transition that would not be accepted is banned.
+    pub fn submit(&mut self, t: SM::Transition, best_state: &SM::State) -> Result<(), PoolError> {
+        if self.contains(&t) {
+            return Err(PoolError::AlreadyKnown);
+        }
+        if self.is_banned(hash(&t)) {
+            return Err(PoolError::Banned);
+        }
+        let mut earlier: Vec<_> = match t.sender_nonce() {
+            Some((sender, nonce)) => self
+                .pending
+                .iter()
+                .filter(|p| {
+                    p.sender_nonce()
+                        .is_some_and(|(s, n)| s == sender && n < nonce)
+                })
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+        earlier.sort_by_key(|p| p.sender_nonce());
+        if !SM::validate_transition(&SM::apply_all(best_state, &earlier), &t) {
+            self.ban(hash(&t));
+            return Err(PoolError::Invalid);
+        }
+        self.known.insert(hash(&t));
+        self.pending.push(t);
+        Ok(())
+    }
+
+    pub fn contains(&self, t: &SM::Transition) -> bool {
+        self.known.contains(&hash(t))
+    }
+
+    /// Whether the transition with the given hash is pending.
+    pub fn contains_hash(&self, t_hash: Hash) -> bool {
+        self.known.contains(&t_hash)
+    }
+
+    /// The hashes of the pending transitions, in no particular order.
+    pub fn pending_hashes(&self) -> impl Iterator<Item = Hash> + '_ {
+        self.known.iter().copied()
+    }
+
+    /// Whether the transition with the given hash is banned.
+    pub fn is_banned(&self, t_hash: Hash) -> bool {
+        self.banned
+            .get(&t_hash)
+            .is_some_and(|until| *until > self.clock.now())
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Up to `max` pending transitions, in the order they should be included.
+    pub fn batch(&self, max: usize) -> Vec<SM::Transition> {
+        let mut ordered = self.ordered_within(u64::MAX, |_| 0);
+        ordered.truncate(max);
+        ordered
+    }
+
+    /// Evict the transitions of a block that has been imported.
+    pub fn evict_included(&mut self, body: &[SM::Transition]) {
+        let included: HashSet<Hash> = body.iter().map(hash).collect();
+        self.pending.retain(|t| !included.contains(&hash(t)));
+        self.known.retain(|h| !included.contains(h));
+    }
+
+    /// Evict the given transitions, which a block builder rejected, and ban them.
+    pub fn reject(&mut self, rejected: &[SM::Transition]) {
+        self.evict_included(rejected);
+        for t in rejected {
+            self.ban(hash(t));
+        }
+    }
+
+    /// Drop and ban every pending transition that would be rejected on top of the given best
+    /// state. Each sender's transitions are checked in nonce order, each on top of the state left
+    /// by the ones before it that were kept. Returns how many were dropped.
+    pub fn revalidate(&mut self, best_state: &SM::State) -> usize {
+        let mut in_nonce_order = self.pending.clone();
+        in_nonce_order.sort_by_key(|t| t.sender_nonce());
+        let mut sender_states: HashMap<User, SM::State> = HashMap::new();
+        let mut valid = HashSet::new();
+        for t in &in_nonce_order {
+            let Some((sender, _)) = t.sender_nonce() else {
+                if SM::validate_transition(best_state, t) {
+                    valid.insert(hash(t));
+                }
+                continue;
+            };
+            let state = sender_states
+                .entry(sender)
+                .or_insert_with(|| best_state.clone());
+            if SM::validate_transition(state, t) {
+                *state = SM::next_state(state, t);
+                valid.insert(hash(t));
+            }
+        }
+
+        let now = self.clock.now();
+        self.banned.retain(|_, until| *until > now);
+        let dropped: Vec<Hash> = self.known.difference(&valid).copied().collect();
+        for t_hash in &dropped {
+            self.ban(*t_hash);
+        }
+        self.pending.retain(|t| valid.contains(&hash(t)));
+        self.known = valid;
+        dropped.len()
+    }
+
+    /// Catch up with a reorg, given the bodies of the blocks it retracted and of those it
+    /// enacted, and the state of the new best block. Transitions of retracted blocks go back in
+    /// the pool, unless an enacted block includes them too, and then the whole pool is checked
+    /// again. Coinbases are not put back. Returns how many transitions were dropped as invalid.
+    pub fn on_reorg(
+        &mut self,
+        retracted: &[Vec<SM::Transition>],
+        enacted: &[Vec<SM::Transition>],
+        best_state: &SM::State,
+    ) -> usize {
+        for t in retracted.iter().flatten().filter(|t| !t.is_coinbase()) {
+            if self.known.insert(hash(t)) {
+                self.pending.push(t.clone());
+            }
+        }
+        for body in enacted {
+            self.evict_included(body);
+        }
+        self.revalidate(best_state)
+    }
+
+    /// Ban the transition with the given hash for the ban window, if there is one.
+    fn ban(&mut self, t_hash: Hash) {
+        if !self.ban_window.is_zero() {
+            let until = self
+                .clock
+                .now()
+                .saturating_add(self.ban_window.as_millis() as u64);
+            self.banned.insert(t_hash, until);
+        }
+    }
+
+    /// The pending transitions for a block weighing at most `max_weight`, filled greedily. Each
+    /// sender's next transition competes with the others on priority, ties going to whichever
+    /// sender's transitions arrived first. The winner goes in if it still fits. If it does not,
+    /// neither do the rest of its sender's transitions, which cannot execute without it.
+    fn ordered_within(
+        &self,
+        max_weight: u64,
+        weight: impl Fn(&SM::Transition) -> u64,
+    ) -> Vec<SM::Transition> {
+        // One queue per sender, in nonce order, and one for each transition without a sender
+        let mut queues: Vec<VecDeque<&SM::Transition>> = Vec::new();
+        let mut queue_of_sender = HashMap::new();
+        for t in &self.pending {
+            match t.sender_nonce() {
+                Some((sender, _)) => {
+                    let i = *queue_of_sender.entry(sender).or_insert_with(|| {
+                        queues.push(VecDeque::new());
+                        queues.len() - 1
+                    });
+                    queues[i].push_back(t);
+                }
+                None => queues.push(VecDeque::from([t])),
+            }
+        }
+        for queue in &mut queues {
+            queue.make_contiguous().sort_by_key(|t| t.sender_nonce());
+        }
+
+        let mut block = Vec::new();
+        let mut total = 0u64;
+        while let Some((i, _)) = queues
+            .iter()
+            .enumerate()
+            .filter_map(|(i, q)| q.front().map(|t| (i, t.priority())))
+            .max_by_key(|(i, priority)| (*priority, Reverse(*i)))
+        {
+            let t = queues[i].pop_front().expect("the queue has a front");
+            match total.checked_add(weight(t)) {
+                Some(w) if w <= max_weight => {
+                    total = w;
+                    block.push(t.clone());
+                }
+                _ => queues[i].clear(),
+            }
+        }
+        block
+    }
+}
+
+impl<SM: Weighted, Clock: TimeProvider> TxPool<SM, Clock>
+where
+    SM::State: Clone,
+    SM::Transition: PrioritizedTransition + core::hash::Hash + Clone,
+{
+    /// The pending transitions for a block weighing at most `max_weight`, highest priority
+    /// first, with each sender's transitions in nonce order.
+    pub fn fill_block(&self, max_weight: u64) -> Vec<SM::Transition> {
+        self.ordered_within(max_weight, SM::weight)
+    }
+}
+
+/// The pool hands block authors its pending transitions highest priority first.
+impl<SM: StateMachine, Clock: TimeProvider> TransitionSource<SM::Transition> for TxPool<SM, Clock>
+where
+    SM::State: Clone,
+    SM::Transition: PrioritizedTransition + core::hash::Hash + Clone,
+{
+    fn ready(&self) -> Vec<SM::Transition> {
+        self.ordered_within(u64::MAX, |_| 0)
+    }
+
+    fn remove(&mut self, transitions: &[SM::Transition]) {
+        self.evict_included(transitions);
+    }
+
+    fn reject(&mut self, transitions: &[SM::Transition]) {
+        TxPool::reject(self, transitions);
+    }
+
+    fn ready_within(
+        &self,
+        max_weight: u64,
+        weight: fn(&SM::Transition) -> u64,
+    ) -> Vec<SM::Transition> {
+        self.ordered_within(max_weight, weight)
+    }
+}
+
+#[cfg(test)]
+use super::Withdrawals;
+#[cfg(test)]
+use crate::c1_state_machine::with_nonces::WithNonces;
+
+/// Withdrawals pay nothing, so they are included in the order they arrive.
+#[cfg(test)]
+impl PrioritizedTransition for u64 {}
+
+/// A transition that does nothing but pay a fee
+#[cfg(test)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Paid {
+    fee: u64,
+    weight: u64,
+}
+
+#[cfg(test)]
+impl PrioritizedTransition for Paid {
+    fn priority(&self) -> u64 {
+        self.fee
+    }
+}
+
+/// Fees are paid by nobody in particular, so anyone may sign them.
+#[cfg(test)]
+impl crate::c1_state_machine::with_nonces::Owned for Paid {
+    fn owner(&self) -> Option<User> {
+        None
+    }
+}
+
+#[cfg(test)]
+struct Fees;
+
+#[cfg(test)]
+impl StateMachine for Fees {
+    type State = ();
+    type Transition = Paid;
+    type Error = core::convert::Infallible;
+    type GenesisConfig = ();
+
+    fn genesis_state(_: ()) {}
+
+    fn next_state(_: &(), _: &Paid) {}
+}
+
+#[cfg(test)]
+impl crate::c1_state_machine::ContextualStateMachine for Fees {}
+
+#[cfg(test)]
+impl crate::codec::Encode for Paid {
+    fn encode_to(&self, dest: &mut Vec<u8>) {
+        self.fee.encode_to(dest);
+        self.weight.encode_to(dest);
+    }
+}
+
+#[cfg(test)]
+impl Weighted for Fees {
+    fn weight(t: &Paid) -> u64 {
+        t.weight
+    }
+}
+
+#[cfg(test)]
+fn paid(fee: u64, weight: u64) -> Paid {
+    Paid { fee, weight }
+}
+
+#[cfg(test)]
+fn nonced(signer: User, nonce: u64, fee: u64) -> Nonced<Paid> {
+    Nonced {
+        signer,
+        nonce,
+        call: paid(fee, 1),
+    }
+}
+
+#[test]
+fn cl_pool_turns_away_repeats_and_invalid_transitions() {
+    let mut pool = TxPool::<Withdrawals>::new();
+
+    assert_eq!(pool.submit(30, &100), Ok(()));
+    assert_eq!(pool.submit(30, &100), Err(PoolError::AlreadyKnown));
+    assert_eq!(pool.submit(200, &100), Err(PoolError::Invalid));
+    assert_eq!(pool.submit(50, &100), Ok(()));
+
+    assert_eq!(pool.len(), 2);
+    assert_eq!(pool.batch(1), vec![30]);
+    assert!(pool.contains(&50));
+}
+
+#[test]
+fn cl_pool_bans_invalid_transitions_for_a_while() {
+    use crate::c3_consensus::MockClock;
+
+    let clock = MockClock::new(0);
+    let mut pool = TxPool::<Withdrawals, MockClock>::new()
+        .with_ban_window(Duration::from_millis(1000))
+        .with_clock(clock.clone());
+
+    assert_eq!(pool.submit(200, &100), Err(PoolError::Invalid));
+    // Even once the balance would cover it, the 200 is not checked again until its ban expires.
+    assert_eq!(pool.submit(200, &500), Err(PoolError::Banned));
+    clock.advance(1000);
+    assert!(!pool.is_banned(hash(&200u64)));
+    assert_eq!(pool.submit(200, &500), Ok(()));
+
+    // What the chain makes invalid, and what a block builder rejects, is banned too.
+    pool.submit(50, &500).unwrap();
+    assert_eq!(pool.revalidate(&100), 1);
+    assert_eq!(pool.submit(200, &500), Err(PoolError::Banned));
+    pool.reject(&[50]);
+    assert!(pool.is_empty());
+    assert_eq!(pool.submit(50, &500), Err(PoolError::Banned));
+
+    // A pool with no ban window bans nothing.
+    let mut pool = TxPool::<Withdrawals>::new();
+    assert_eq!(pool.submit(200, &100), Err(PoolError::Invalid));
+    assert_eq!(pool.submit(200, &500), Ok(()));
+}
+
+#[test]
+fn cl_pool_evicts_what_the_author_includes() {
+    use super::author::Author;
+    use super::p3_fork_choice::LongestChain;
+    use crate::c3_consensus::p1_pow::PoW;
+    use crate::c3_consensus::MockClock;
+
+    let mut node = Author::<_, Withdrawals, _, _>::new(
+        PoW::new(u64::MAX / 4),
+        LongestChain,
+        100,
+        MockClock::new(0),
+    );
+    let mut pool = TxPool::<Withdrawals>::new();
+    for amount in [10, 20, 30] {
+        pool.submit(amount, &100).unwrap();
+    }
+
+    let block = node.author_block(&mut pool).unwrap();
+    assert_eq!(node.body_of(block), Some(&[10, 20, 30][..]));
+    assert!(pool.is_empty());
+
+    // Once included, a transition may be submitted again, as a new transaction.
+    assert_eq!(pool.submit(10, node.state_at(block).unwrap()), Ok(()));
+}
+
+#[test]
+fn cl_pool_takes_back_retracted_transitions_after_a_reorg() {
+    let mut pool = TxPool::<Withdrawals>::new();
+    pool.submit(70, &100).unwrap();
+    pool.submit(5, &100).unwrap();
+
+    // The retracted fork spent 20 and 40, the enacted one spent 20 and then 50.
+    let dropped = pool.on_reorg(&[vec![20, 40]], &[vec![20], vec![50]], &30);
+
+    assert_eq!(dropped, 2);
+    assert_eq!(pool.batch(usize::MAX), vec![5]);
+    assert!(!pool.contains(&20));
+    assert!(!pool.contains(&40));
+
+    let dropped = pool.on_reorg(&[vec![20, 10]], &[], &30);
+    assert_eq!(dropped, 0);
+    assert_eq!(pool.batch(usize::MAX), vec![5, 20, 10]);
+}
+
+#[test]
+fn cl_pool_never_holds_coinbases() {
+    use crate::c1_state_machine::p7_multiasset::NATIVE_ASSET;
+
+    let state =
+        MultiAsset::genesis_state(vec![(NATIVE_ASSET, User::Alice, vec![(User::Alice, 100)])]);
+    let coinbase = MultiAsset::coinbase(User::Charlie, 10);
+    let transfer = AssetTransaction::Transfer {
+        asset: NATIVE_ASSET,
+        sender: User::Alice,
+        receiver: User::Bob,
+        amount: 30,
+    };
+    let mut pool = TxPool::<MultiAsset>::new();
+    assert_eq!(
+        pool.submit(coinbase.clone(), &state),
+        Err(PoolError::Invalid)
+    );
+
+    // A retracted block's transfer goes back in the pool, but its coinbase does not.
+    let dropped = pool.on_reorg(&[vec![coinbase.clone(), transfer.clone()]], &[], &state);
+    assert_eq!(dropped, 0);
+    assert_eq!(pool.batch(usize::MAX), vec![transfer]);
+    assert!(!pool.contains(&coinbase));
+}
+
+#[test]
+fn cl_pool_fills_blocks_greedily_by_fee() {
+    let mut pool = TxPool::<Fees>::new();
+    for (fee, weight) in [(1, 1), (5, 3), (3, 2), (4, 4)] {
+        pool.submit(paid(fee, weight), &()).unwrap();
+    }
+
+    assert_eq!(pool.fill_block(6), vec![paid(5, 3), paid(3, 2), paid(1, 1)]);
+    assert_eq!(pool.batch(2), vec![paid(5, 3), paid(4, 4)]);
+}
+
+#[test]
+fn cl_pool_keeps_each_senders_transitions_in_nonce_order() {
+    use crate::c1_state_machine::User::{Alice, Bob};
+
+    type NoncedFees = WithNonces<Fees>;
+    let genesis = NoncedFees::genesis_state(());
+    let mut pool = TxPool::<NoncedFees>::new();
+
+    // Alice's second transition is only valid after her first, which is still in the pool.
+    assert_eq!(
+        pool.submit(nonced(Alice, 1, 10), &genesis),
+        Err(PoolError::Invalid)
+    );
+    pool.submit(nonced(Alice, 0, 1), &genesis).unwrap();
+    pool.submit(nonced(Alice, 1, 10), &genesis).unwrap();
+    pool.submit(nonced(Bob, 0, 5), &genesis).unwrap();
+
+    // Alice's generous tip waits for her cheap first transition.
+    assert_eq!(
+        pool.fill_block(10),
+        vec![nonced(Bob, 0, 5), nonced(Alice, 0, 1), nonced(Alice, 1, 10)]
+    );
+    // When her first transition does not fit, neither does her second.
+    assert_eq!(pool.fill_block(1), vec![nonced(Bob, 0, 5)]);
+
+    // Once another node's block includes her first transition, only her second is left.
+    let mut after = genesis.clone();
+    after.nonces.insert(Alice, 1);
+    assert_eq!(pool.revalidate(&after), 1);
+    assert_eq!(
+        pool.batch(usize::MAX),
+        vec![nonced(Alice, 1, 10), nonced(Bob, 0, 5)]
+    );
+}
+
+#[test]
+fn cl_author_fills_blocks_up_to_the_weight_limit() {
+    use super::author::Author;
+    use super::p3_fork_choice::LongestChain;
+    use crate::c3_consensus::p1_pow::PoW;
+    use crate::c3_consensus::MockClock;
+
+    let mut node =
+        Author::<_, Fees, _, _>::new(PoW::new(u64::MAX / 4), LongestChain, (), MockClock::new(0))
+            .with_max_weight(5);
+    let mut pool = TxPool::<Fees>::new();
+    for (fee, weight) in [(1, 2), (2, 4), (3, 3)] {
+        pool.submit(paid(fee, weight), &()).unwrap();
+    }
+
+    let first = node.author_block(&mut pool).unwrap();
+    assert_eq!(node.body_of(first), Some(&[paid(3, 3), paid(1, 2)][..]));
+    let second = node.author_block(&mut pool).unwrap();
+    assert_eq!(node.body_of(second), Some(&[paid(2, 4)][..]));
+}
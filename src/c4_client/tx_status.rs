@@ -0,0 +1,306 @@
+//! Someone who submits a transition wants to know what became of it. It waits in the pool, a
+//! block includes it, and eventually that block is finalized. Along the way a reorg may retract
+//! the block and put the transition back in the pool, or the chain may move on in a way that
+//! makes it invalid, and the pool drops it.
+//!
+//! `TxStatuses` follows every transition the node's pool has held through these stages. It
+//! learns about them the way the node does: from the outcome of each submission, from the head
+//! changes importing blocks makes, and from what is left in the pool after the pool caught up
+//! with them. The statuses that changed since the node last asked are kept, so that the node can
+//! push them to subscribers.
+
+use std::collections::HashMap;
+
+use super::block_tree::{BlockTree, HeadChange};
+use super::p3_fork_choice::ForkChoice;
+use super::tx_pool::{PoolError, PrioritizedTransition, TxPool};
+use super::Hash;
+use crate::c1_state_machine::{ContextualStateMachine, StateMachine};
+use crate::c3_consensus::Consensus;
+use crate::codec::Encode;
+use crate::hash;
+use num::traits::{One, Zero};
+
+/// Where a transition is on its way into the chain
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum TxStatus {
+    /// The node received the transition, and has yet to check it
+    Submitted,
+    /// The transition is waiting in the pool
+    InPool,
+    /// The given block of the best chain includes the transition
+    InBlock(Hash),
+    /// The given finalized block includes the transition, so it is in the chain for good
+    Finalized(Hash),
+    /// The pool dropped the transition before a block included it, because it was no longer
+    /// valid once the chain moved on
+    Dropped,
+    /// The pool turned the transition away when it was submitted
+    Invalid,
+}
+
+/// The status of every transition the node's pool has held, by hash
+#[derive(Clone, Debug, Default)]
+pub struct TxStatuses {
+    statuses: HashMap<Hash, TxStatus>,
+    /// The statuses that changed since they were last taken, oldest first
+    changes: Vec<(Hash, TxStatus)>,
+}
+
+impl TxStatuses {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The status of the transition with the given hash, if the node has seen it.
+    pub fn status(&self, t_hash: Hash) -> Option<TxStatus> {
+        self.statuses.get(&t_hash).copied()
+    }
+
+    /// How many transitions are being followed.
+    pub fn len(&self) -> usize {
+        self.statuses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.statuses.is_empty()
+    }
+
+    /// The statuses that changed since the last call, oldest first. A transition that changed
+    /// twice appears twice.
+    pub fn take_changes(&mut self) -> Vec<(Hash, TxStatus)> {
+        std::mem::take(&mut self.changes)
+    }
+
+    /// Record what the pool made of the transition with the given hash when it was submitted. A
+    /// transition the pool already had keeps its status.
+    pub fn submitted(&mut self, t_hash: Hash, result: &Result<(), PoolError>) {
+        let outcome = match result {
+            Ok(()) => TxStatus::InPool,
+            Err(PoolError::Invalid | PoolError::Banned) => TxStatus::Invalid,
+            Err(PoolError::AlreadyKnown) if self.statuses.contains_key(&t_hash) => return,
+            Err(PoolError::AlreadyKnown) => TxStatus::InPool,
+        };
+        self.set(t_hash, TxStatus::Submitted);
+        self.set(t_hash, outcome);
+    }
+
+    /// Catch up with a head change of the given tree. Transitions of retracted blocks are
+    /// waiting in the pool again, and those of enacted blocks are in them. Then every
+    /// transition in a block that is now finalized is finalized.
+    pub fn follow_head_change<C, SM, FC>(
+        &mut self,
+        tree: &BlockTree<C, SM, FC>,
+        change: &HeadChange,
+    ) where
+        C: Consensus,
+        C::Digest: Zero + One + core::hash::Hash,
+        SM: ContextualStateMachine,
+        SM::State: core::hash::Hash + Clone,
+        SM::Transition: core::hash::Hash + Encode + Clone,
+        FC: ForkChoice<C::Digest>,
+    {
+        for block_hash in &change.retracted {
+            for t_hash in tree
+                .body_of(*block_hash)
+                .unwrap_or_default()
+                .iter()
+                .map(hash)
+            {
+                if self.status(t_hash) == Some(TxStatus::InBlock(*block_hash)) {
+                    self.set(t_hash, TxStatus::InPool);
+                }
+            }
+        }
+        for block_hash in &change.enacted {
+            for t_hash in tree
+                .body_of(*block_hash)
+                .unwrap_or_default()
+                .iter()
+                .map(hash)
+            {
+                if self.statuses.contains_key(&t_hash) {
+                    self.set(t_hash, TxStatus::InBlock(*block_hash));
+                }
+            }
+        }
+        self.follow_finality(tree);
+    }
+
+    /// Finalize every transition in a block of the given tree that is now finalized.
+    pub fn follow_finality<C, SM, FC>(&mut self, tree: &BlockTree<C, SM, FC>)
+    where
+        C: Consensus,
+        C::Digest: Zero + One + core::hash::Hash,
+        SM: ContextualStateMachine,
+        SM::State: core::hash::Hash + Clone,
+        SM::Transition: core::hash::Hash + Encode + Clone,
+        FC: ForkChoice<C::Digest>,
+    {
+        let finalized_height = tree.chain_info().finalized_height;
+        let best_chain = tree.best_chain();
+        let now_final: Vec<(Hash, Hash)> = self
+            .statuses
+            .iter()
+            .filter_map(|(t_hash, status)| match status {
+                TxStatus::InBlock(block_hash) => Some((*t_hash, *block_hash)),
+                _ => None,
+            })
+            .filter(|(_, block_hash)| {
+                tree.header(*block_hash).is_some_and(|header| {
+                    header.height <= finalized_height
+                        && best_chain.get(header.height as usize) == Some(block_hash)
+                })
+            })
+            .collect();
+        for (t_hash, block_hash) in now_final {
+            self.set(t_hash, TxStatus::Finalized(block_hash));
+        }
+    }
+
+    /// Catch up with the given pool. Every transition in it is waiting there, including those
+    /// it heard about from other nodes. Those that were waiting there and no longer are, without
+    /// a block including them, were dropped.
+    pub fn follow_pool<SM>(&mut self, pool: &TxPool<SM>)
+    where
+        SM: StateMachine,
+        SM::State: Clone,
+        SM::Transition: PrioritizedTransition + core::hash::Hash + Clone,
+    {
+        let arrived: Vec<Hash> = pool
+            .pending_hashes()
+            .filter(|t_hash| self.status(*t_hash) != Some(TxStatus::InPool))
+            .collect();
+        for t_hash in arrived {
+            self.set(t_hash, TxStatus::InPool);
+        }
+        let dropped: Vec<Hash> = self
+            .statuses
+            .iter()
+            .filter(|(t_hash, status)| {
+                **status == TxStatus::InPool && !pool.contains_hash(**t_hash)
+            })
+            .map(|(t_hash, _)| *t_hash)
+            .collect();
+        for t_hash in dropped {
+            self.set(t_hash, TxStatus::Dropped);
+        }
+    }
+
+    fn set(&mut self, t_hash: Hash, status: TxStatus) {
+        if self.statuses.insert(t_hash, status) != Some(status) {
+            self.changes.push((t_hash, status));
+        }
+    }
+}
+
+#[cfg(test)]
+use super::p3_fork_choice::LongestChain;
+#[cfg(test)]
+use super::Withdrawals;
+#[cfg(test)]
+use crate::c1_state_machine::BlockContext;
+#[cfg(test)]
+use crate::c3_consensus::p1_pow::PoW;
+
+#[test]
+fn cl_tx_status_follows_transitions_into_the_chain() {
+    let mut tree =
+        BlockTree::<PoW, Withdrawals, LongestChain>::new(PoW::new(u64::MAX / 4), LongestChain, 100);
+    let mut pool = TxPool::<Withdrawals>::new();
+    let mut statuses = TxStatuses::new();
+    let submit = |pool: &mut TxPool<Withdrawals>, statuses: &mut TxStatuses, t: u64| {
+        let result = pool.submit(t, &100);
+        statuses.submitted(hash(&t), &result);
+    };
+
+    submit(&mut pool, &mut statuses, 30);
+    submit(&mut pool, &mut statuses, 60);
+    submit(&mut pool, &mut statuses, 200);
+    submit(&mut pool, &mut statuses, 30);
+    assert_eq!(
+        statuses.take_changes(),
+        vec![
+            (hash(&30u64), TxStatus::Submitted),
+            (hash(&30u64), TxStatus::InPool),
+            (hash(&60u64), TxStatus::Submitted),
+            (hash(&60u64), TxStatus::InPool),
+            (hash(&200u64), TxStatus::Submitted),
+            (hash(&200u64), TxStatus::Invalid),
+        ]
+    );
+
+    // A block withdraws 50, which leaves too little for the 60.
+    let genesis = tree.block(tree.best_head()).unwrap();
+    let context = BlockContext {
+        height: 1,
+        ..BlockContext::default()
+    };
+    let block = genesis.child(&100, vec![30, 20], context).unwrap();
+    let block_hash = hash(&block.header);
+    let change = tree.import(block).unwrap();
+    pool.on_reorg(&[], &tree.bodies(&change.enacted), tree.best_state());
+    statuses.follow_head_change(&tree, &change);
+    statuses.follow_pool(&pool);
+    assert_eq!(
+        statuses.take_changes(),
+        vec![
+            (hash(&30u64), TxStatus::InBlock(block_hash)),
+            (hash(&60u64), TxStatus::Dropped),
+        ]
+    );
+    // Only transitions the node has seen are followed.
+    assert_eq!(statuses.status(hash(&20u64)), None);
+
+    tree.finalize(block_hash).unwrap();
+    statuses.follow_finality(&tree);
+    assert_eq!(
+        statuses.status(hash(&30u64)),
+        Some(TxStatus::Finalized(block_hash))
+    );
+    assert_eq!(statuses.len(), 3);
+}
+
+#[test]
+fn cl_tx_status_puts_retracted_transitions_back_in_the_pool() {
+    let mut tree =
+        BlockTree::<PoW, Withdrawals, LongestChain>::new(PoW::new(u64::MAX / 4), LongestChain, 100);
+    let mut pool = TxPool::<Withdrawals>::new();
+    let mut statuses = TxStatuses::new();
+    statuses.submitted(hash(&10u64), &pool.submit(10, &100));
+
+    let genesis = tree.block(tree.best_head()).unwrap().clone();
+    let context = |height| BlockContext {
+        height,
+        ..BlockContext::default()
+    };
+    let a1 = genesis.child(&100, vec![10], context(1)).unwrap();
+    let b1 = genesis.child(&100, vec![5], context(1)).unwrap();
+    let b2 = b1.child(&95, vec![], context(2)).unwrap();
+    let follow = |tree: &BlockTree<_, _, _>,
+                  pool: &mut TxPool<_>,
+                  statuses: &mut TxStatuses,
+                  change: &HeadChange| {
+        pool.on_reorg(
+            &tree.bodies(&change.retracted),
+            &tree.bodies(&change.enacted),
+            tree.best_state(),
+        );
+        statuses.follow_head_change(tree, change);
+        statuses.follow_pool(pool);
+    };
+    let change = tree.import(a1.clone()).unwrap();
+    follow(&tree, &mut pool, &mut statuses, &change);
+    assert_eq!(
+        statuses.status(hash(&10u64)),
+        Some(TxStatus::InBlock(hash(&a1.header)))
+    );
+
+    for block in [b1, b2] {
+        let change = tree.import(block).unwrap();
+        follow(&tree, &mut pool, &mut statuses, &change);
+    }
+    assert_eq!(statuses.status(hash(&10u64)), Some(TxStatus::InPool));
+    assert!(pool.contains(&10));
+}
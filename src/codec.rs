@@ -0,0 +1,262 @@
+//! `std::hash` is fine for hash maps, but its output is not specified and may change between
+//! compiler versions, so it cannot be what a chain's participants agree on. Anything that is hashed
+//! for consensus is therefore first turned into bytes by a canonical encoding, and those bytes are
+//! fed to a real cryptographic hash function.
+//!
+//! The encoding is deliberately simple. Integers are written little endian at their full width,
+//! sequences are prefixed with their length as a `u64`, and compound values are the encodings of
+//! their parts one after another. Two equal values always encode to the same bytes.
+//!
+//! Values that travel between nodes, rather than only being hashed, can also be decoded from
+//! their encoding.
+
+/// A type with a canonical byte encoding.
+pub trait Encode {
+	/// Append the encoding of this value to the given buffer.
+	fn encode_to(&self, dest: &mut Vec<u8>);
+
+	/// The encoding of this value.
+	fn encode(&self) -> Vec<u8> {
+		let mut dest = Vec::new();
+		self.encode_to(&mut dest);
+		dest
+	}
+}
+
+macro_rules! encode_int {
+	($($t:ty),*) => {
+		$(impl Encode for $t {
+			fn encode_to(&self, dest: &mut Vec<u8>) {
+				dest.extend_from_slice(&self.to_le_bytes());
+			}
+		})*
+	};
+}
+
+encode_int!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+/// Encoded as a `u64`, so that the encoding does not depend on the platform.
+impl Encode for usize {
+	fn encode_to(&self, dest: &mut Vec<u8>) {
+		(*self as u64).encode_to(dest);
+	}
+}
+
+impl Encode for bool {
+	fn encode_to(&self, dest: &mut Vec<u8>) {
+		dest.push(u8::from(*self));
+	}
+}
+
+/// Nothing at all.
+impl Encode for () {
+	fn encode_to(&self, _: &mut Vec<u8>) {}
+}
+
+/// A 0 byte for `None`, or a 1 byte followed by the value.
+impl<T: Encode> Encode for Option<T> {
+	fn encode_to(&self, dest: &mut Vec<u8>) {
+		match self {
+			None => dest.push(0),
+			Some(t) => {
+				dest.push(1);
+				t.encode_to(dest);
+			}
+		}
+	}
+}
+
+impl<T: Encode> Encode for [T] {
+	fn encode_to(&self, dest: &mut Vec<u8>) {
+		self.len().encode_to(dest);
+		for t in self {
+			t.encode_to(dest);
+		}
+	}
+}
+
+impl<T: Encode> Encode for Vec<T> {
+	fn encode_to(&self, dest: &mut Vec<u8>) {
+		self.as_slice().encode_to(dest);
+	}
+}
+
+/// The length of an array is part of its type, so it is not written.
+impl<T: Encode, const N: usize> Encode for [T; N] {
+	fn encode_to(&self, dest: &mut Vec<u8>) {
+		for t in self {
+			t.encode_to(dest);
+		}
+	}
+}
+
+impl<A: Encode, B: Encode> Encode for (A, B) {
+	fn encode_to(&self, dest: &mut Vec<u8>) {
+		self.0.encode_to(dest);
+		self.1.encode_to(dest);
+	}
+}
+
+impl<T: Encode + ?Sized> Encode for &T {
+	fn encode_to(&self, dest: &mut Vec<u8>) {
+		(**self).encode_to(dest);
+	}
+}
+
+/// A string is the sequence of its UTF-8 bytes.
+impl Encode for str {
+	fn encode_to(&self, dest: &mut Vec<u8>) {
+		self.as_bytes().encode_to(dest);
+	}
+}
+
+impl Encode for String {
+	fn encode_to(&self, dest: &mut Vec<u8>) {
+		self.as_str().encode_to(dest);
+	}
+}
+
+/// A type that can be read back from its canonical encoding.
+pub trait Decode: Sized {
+	/// Read a value from the start of the input, advancing the input past it. Returns None if
+	/// the input does not start with a valid encoding.
+	fn decode_from(input: &mut &[u8]) -> Option<Self>;
+
+	/// Decode a value from exactly the given bytes. Returns None if any bytes are left over.
+	fn decode(bytes: &[u8]) -> Option<Self> {
+		let mut input = bytes;
+		let value = Self::decode_from(&mut input)?;
+		input.is_empty().then_some(value)
+	}
+}
+
+/// Split the first `n` bytes off the input.
+fn take<'a>(input: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+	if input.len() < n {
+		return None;
+	}
+	let (taken, rest) = input.split_at(n);
+	*input = rest;
+	Some(taken)
+}
+
+macro_rules! decode_int {
+	($($t:ty),*) => {
+		$(impl Decode for $t {
+			fn decode_from(input: &mut &[u8]) -> Option<Self> {
+				let bytes = take(input, std::mem::size_of::<$t>())?;
+				Some(<$t>::from_le_bytes(bytes.try_into().ok()?))
+			}
+		})*
+	};
+}
+
+decode_int!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+/// Fails if the value does not fit the platform's `usize`.
+impl Decode for usize {
+	fn decode_from(input: &mut &[u8]) -> Option<Self> {
+		usize::try_from(u64::decode_from(input)?).ok()
+	}
+}
+
+/// Only 0 and 1 are valid.
+impl Decode for bool {
+	fn decode_from(input: &mut &[u8]) -> Option<Self> {
+		match u8::decode_from(input)? {
+			0 => Some(false),
+			1 => Some(true),
+			_ => None,
+		}
+	}
+}
+
+impl Decode for () {
+	fn decode_from(_: &mut &[u8]) -> Option<Self> {
+		Some(())
+	}
+}
+
+impl<T: Decode> Decode for Option<T> {
+	fn decode_from(input: &mut &[u8]) -> Option<Self> {
+		match u8::decode_from(input)? {
+			0 => Some(None),
+			1 => Some(Some(T::decode_from(input)?)),
+			_ => None,
+		}
+	}
+}
+
+/// The length prefix comes from the input, so it is not trusted for allocating up front.
+impl<T: Decode> Decode for Vec<T> {
+	fn decode_from(input: &mut &[u8]) -> Option<Self> {
+		let len = usize::decode_from(input)?;
+		let mut items = Vec::with_capacity(len.min(input.len()));
+		for _ in 0..len {
+			items.push(T::decode_from(input)?);
+		}
+		Some(items)
+	}
+}
+
+impl<T: Decode, const N: usize> Decode for [T; N] {
+	fn decode_from(input: &mut &[u8]) -> Option<Self> {
+		let items: Vec<T> = (0..N).map(|_| T::decode_from(input)).collect::<Option<_>>()?;
+		items.try_into().ok()
+	}
+}
+
+impl<A: Decode, B: Decode> Decode for (A, B) {
+	fn decode_from(input: &mut &[u8]) -> Option<Self> {
+		Some((A::decode_from(input)?, B::decode_from(input)?))
+	}
+}
+
+/// Only valid UTF-8 is accepted.
+impl Decode for String {
+	fn decode_from(input: &mut &[u8]) -> Option<Self> {
+		String::from_utf8(Vec::<u8>::decode_from(input)?).ok()
+	}
+}
+
+#[test]
+fn codec_integers_are_little_endian() {
+	assert_eq!(0x0102u16.encode(), vec![2, 1]);
+	assert_eq!(7usize.encode(), 7u64.encode());
+	assert_eq!(().encode(), Vec::<u8>::new());
+}
+
+#[test]
+fn codec_sequences_are_length_prefixed() {
+	let encoded = vec![1u8, 2].encode();
+
+	assert_eq!(encoded, vec![2, 0, 0, 0, 0, 0, 0, 0, 1, 2]);
+	assert_eq!([1u8, 2].encode(), vec![1, 2]);
+	// Without the prefix these two would encode the same.
+	assert_ne!((vec![1u8], vec![2u8]).encode(), (vec![1u8, 2], Vec::<u8>::new()).encode());
+}
+
+#[test]
+fn codec_options_are_tagged() {
+	assert_eq!(None::<u8>.encode(), vec![0]);
+	assert_eq!(Some(5u8).encode(), vec![1, 5]);
+}
+
+#[test]
+fn codec_decoding_inverts_encoding() {
+	let value = (Some(vec![1u64, u64::MAX]), ([7u8; 3], (true, -5i32)));
+
+	assert_eq!(Decode::decode(&value.encode()), Some(value));
+	assert_eq!(u64::decode(&[1, 2, 3]), None);
+	assert_eq!(bool::decode(&[2]), None);
+	// Trailing bytes are an error, and so is a length prefix promising more than there is.
+	assert_eq!(u8::decode(&[1, 2]), None);
+	assert_eq!(Vec::<u8>::decode(&u64::MAX.encode()), None);
+}
+
+#[test]
+fn codec_strings_are_utf8_bytes() {
+	assert_eq!("hi".encode(), b"hi".to_vec().encode());
+	assert_eq!(String::decode(&"ünï".encode()), Some("ünï".to_string()));
+	assert_eq!(String::decode(&vec![0xffu8].encode()), None);
+}
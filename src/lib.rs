@@ -1,10 +1,27 @@
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 
-mod c1_state_machine;
+pub mod c1_state_machine;
 mod c2_blockchain;
 mod c3_consensus;
 mod c4_client;
+pub mod codec;
+pub mod merkle;
+/// Blocks sealed by proof of work, for the benchmarks in `benches/`
+pub use c3_consensus::{p1_pow::PoW, Consensus, Header};
+/// Tendermint, whose validators agree on every block before it is added, run as a state machine
+pub use c3_consensus::p13_tendermint as tendermint;
+pub use c4_client::Block;
+/// The node the `blockchain-node` binary runs
+#[cfg(feature = "serde")]
+pub use c4_client::node;
+/// A state machine hosting the currency, governance and name service side by side
+pub use c4_client::runtime;
+/// A network of nodes run in one process, to watch forks, authority rotation and finality play out
+pub use c4_client::simulator;
+#[cfg(feature = "serde")]
+pub mod replay;
+mod snapshots;
 
 // Simple helper to do some hashing.
 fn hash<T: Hash>(t: &T) -> u64 {
@@ -0,0 +1,206 @@
+//! A Merkle tree commits to a list of leaves with a single root hash. Changing any leaf changes
+//! the root, yet updating one leaf only requires re-hashing the path from that leaf to the root,
+//! and proving that a leaf is part of the tree only requires the siblings along that path.
+//!
+//! Our trees are binary. When a level has an odd number of nodes, the last one is carried up to
+//! the next level unchanged rather than being paired with a copy of itself.
+
+use crate::codec::{Decode, Encode};
+use crate::hash;
+
+type Hash = u64;
+
+/// The root of a Merkle tree with the given leaves. The root of an empty tree is 0.
+pub fn merkle_root(leaves: &[Hash]) -> Hash {
+	MerkleTree::from_leaves(leaves.to_vec()).root()
+}
+
+/// Hash two sibling nodes into their parent.
+fn parent(left: Hash, right: Hash) -> Hash {
+	hash(&(left, right))
+}
+
+/// Calculate the level above the given one.
+fn next_level(level: &[Hash]) -> Vec<Hash> {
+	level
+		.chunks(2)
+		.map(|pair| match pair {
+			[left, right] => parent(*left, *right),
+			[only] => *only,
+			_ => unreachable!("chunks of two are never empty"),
+		})
+		.collect()
+}
+
+/// A Merkle tree that keeps every level in memory so that single leaves can be updated cheaply.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkleTree {
+	/// The leaves first and the root last.
+	levels: Vec<Vec<Hash>>,
+}
+
+impl MerkleTree {
+	pub fn from_leaves(leaves: Vec<Hash>) -> Self {
+		let mut levels = vec![leaves];
+		while levels.last().is_some_and(|l| l.len() > 1) {
+			let next = next_level(levels.last().unwrap());
+			levels.push(next);
+		}
+		MerkleTree { levels }
+	}
+
+	pub fn root(&self) -> Hash {
+		self.levels.last().and_then(|l| l.first()).copied().unwrap_or(0)
+	}
+
+	pub fn len(&self) -> usize {
+		self.levels[0].len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+
+	/// Prove that the leaf at the given index is part of this tree. Returns None if the index is
+	/// out of bounds.
+	pub fn prove(&self, index: usize) -> Option<MerkleProof> {
+		if index >= self.len() {
+			return None;
+		}
+		let mut siblings = Vec::new();
+		let mut i = index;
+		for level in &self.levels[..self.levels.len() - 1] {
+			if let Some(s) = level.get(i ^ 1) {
+				siblings.push(*s);
+			}
+			i /= 2;
+		}
+		Some(MerkleProof { index, leaf_count: self.len(), siblings })
+	}
+
+	/// Replace the leaf at the given index, re-hashing only the nodes on its path to the root.
+	///
+	/// Panics if the index is out of bounds.
+	pub fn update(&mut self, index: usize, leaf: Hash) {
+		self.levels[0][index] = leaf;
+		let mut i = index;
+		for l in 1..self.levels.len() {
+			let sibling = i ^ 1;
+			let below = &self.levels[l - 1];
+			let node = match below.get(sibling) {
+				Some(s) if sibling < i => parent(*s, below[i]),
+				Some(s) => parent(below[i], *s),
+				None => below[i],
+			};
+			i /= 2;
+			self.levels[l][i] = node;
+		}
+	}
+}
+
+/// A proof that a leaf is at a given position in a tree with a given number of leaves: the
+/// siblings along the leaf's path to the root, from the bottom up. Nodes carried up a level
+/// unchanged have no sibling there.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MerkleProof {
+	pub index: usize,
+	pub leaf_count: usize,
+	pub siblings: Vec<Hash>,
+}
+
+impl MerkleProof {
+	/// The root of the tree this proof places the given leaf in, or None if the proof does not
+	/// fit the shape of a tree with its number of leaves.
+	pub fn root_with(&self, leaf: Hash) -> Option<Hash> {
+		if self.index >= self.leaf_count {
+			return None;
+		}
+		let mut siblings = self.siblings.iter();
+		let (mut node, mut i, mut len) = (leaf, self.index, self.leaf_count);
+		while len > 1 {
+			if i ^ 1 < len {
+				let s = *siblings.next()?;
+				node = if i % 2 == 1 { parent(s, node) } else { parent(node, s) };
+			}
+			i /= 2;
+			len = len.div_ceil(2);
+		}
+		siblings.next().is_none().then_some(node)
+	}
+
+	/// Whether the given leaf is at the proof's position in the tree with the given root.
+	pub fn verify(&self, root: Hash, leaf: Hash) -> bool {
+		self.root_with(leaf) == Some(root)
+	}
+}
+
+/// The fields in the order they are declared.
+impl Encode for MerkleProof {
+	fn encode_to(&self, dest: &mut Vec<u8>) {
+		self.index.encode_to(dest);
+		self.leaf_count.encode_to(dest);
+		self.siblings.encode_to(dest);
+	}
+}
+
+impl Decode for MerkleProof {
+	fn decode_from(input: &mut &[u8]) -> Option<Self> {
+		Some(MerkleProof {
+			index: usize::decode_from(input)?,
+			leaf_count: usize::decode_from(input)?,
+			siblings: Vec::decode_from(input)?,
+		})
+	}
+}
+
+#[test]
+fn merkle_empty_and_single_leaf_roots() {
+	assert_eq!(merkle_root(&[]), 0);
+	assert_eq!(merkle_root(&[42]), 42);
+}
+
+#[test]
+fn merkle_odd_node_is_carried_up() {
+	let expected = parent(parent(1, 2), 3);
+	assert_eq!(merkle_root(&[1, 2, 3]), expected);
+}
+
+#[test]
+fn merkle_update_matches_rebuild() {
+	let mut leaves: Vec<Hash> = (0..7).map(|i| hash(&i)).collect();
+	let mut tree = MerkleTree::from_leaves(leaves.clone());
+
+	for (i, new_leaf) in [(0, 100), (6, 200), (3, 300)] {
+		tree.update(i, new_leaf);
+		leaves[i] = new_leaf;
+		assert_eq!(tree.root(), merkle_root(&leaves));
+	}
+}
+
+#[test]
+fn merkle_proofs_verify_every_leaf() {
+	for n in 1..10u64 {
+		let leaves: Vec<Hash> = (0..n).map(|i| hash(&i)).collect();
+		let tree = MerkleTree::from_leaves(leaves.clone());
+		for (i, leaf) in leaves.iter().enumerate() {
+			let proof = tree.prove(i).unwrap();
+			assert!(proof.verify(tree.root(), *leaf), "leaf {i} of {n}");
+			assert!(!proof.verify(tree.root(), leaf + 1));
+		}
+		assert_eq!(tree.prove(n as usize), None);
+	}
+}
+
+#[test]
+fn merkle_proof_is_bound_to_its_position() {
+	let leaves: Vec<Hash> = (0..5u64).map(|i| hash(&i)).collect();
+	let tree = MerkleTree::from_leaves(leaves.clone());
+	let mut proof = tree.prove(1).unwrap();
+
+	proof.index = 0;
+	assert!(!proof.verify(tree.root(), leaves[1]));
+	proof.index = 1;
+	proof.siblings.push(0);
+	assert_eq!(proof.root_with(leaves[1]), None);
+}
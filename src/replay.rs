@@ -0,0 +1,167 @@
+//! A journal is a plain text file recording a sequence of transitions, one JSON-encoded
+//! transition per line. Replaying a journal against a state machine prints the state after
+//! every step, which makes it easy to reproduce a bug someone reported against a machine, or
+//! to grade an exercise deterministically by comparing the printed states.
+//!
+//! States are printed as JSON too. Their `Debug` output lists hash map entries in whatever order
+//! the map happens to hold them, which differs from run to run, while JSON objects list their
+//! keys in sorted order.
+//!
+//! Empty lines and lines starting with `#` are ignored, so journals can be annotated.
+//!
+//! Journals are JSON, so replaying them requires the `serde` feature.
+
+use std::io::{BufRead, Write};
+
+use crate::c1_state_machine::SerdeStateMachine;
+
+/// The ways replaying a journal can fail.
+#[derive(Debug)]
+pub enum ReplayError {
+	/// The journal could not be read, or the output could not be written.
+	Io(std::io::Error),
+	/// The given line of the journal is not a valid transition for the chosen machine.
+	Decode { line: usize, source: serde_json::Error },
+	/// A state could not be written as JSON.
+	Encode(serde_json::Error),
+}
+
+impl From<std::io::Error> for ReplayError {
+	fn from(e: std::io::Error) -> Self {
+		ReplayError::Io(e)
+	}
+}
+
+/// Decode every transition in the journal, along with the line it appears on.
+pub fn read_journal<SM: SerdeStateMachine>(
+	journal: impl BufRead,
+) -> Result<Vec<(usize, SM::Transition)>, ReplayError> {
+	let mut transitions = Vec::new();
+	for (i, line) in journal.lines().enumerate() {
+		let line = line?;
+		let trimmed = line.trim();
+		if trimmed.is_empty() || trimmed.starts_with('#') {
+			continue;
+		}
+		let t = serde_json::from_str(trimmed)
+			.map_err(|source| ReplayError::Decode { line: i + 1, source })?;
+		transitions.push((i + 1, t));
+	}
+	Ok(transitions)
+}
+
+/// Write the given state as JSON, with the keys of every object in sorted order.
+fn print_state<S: serde::Serialize>(state: &S) -> Result<String, ReplayError> {
+	serde_json::to_value(state).map(|json| json.to_string()).map_err(ReplayError::Encode)
+}
+
+/// Apply every transition in the journal to the given starting state, writing the state after
+/// each step to `out`. Returns the final state.
+///
+/// Transitions are applied with `next_state`, exactly as a block would apply them. Transitions
+/// that `try_next_state` would reject are still applied, but are marked as rejected in the
+/// output along with the reason.
+pub fn replay<SM>(
+	starting_state: SM::State,
+	journal: impl BufRead,
+	mut out: impl Write,
+) -> Result<SM::State, ReplayError>
+where
+	SM: SerdeStateMachine,
+{
+	let transitions = read_journal::<SM>(journal)?;
+
+	writeln!(out, "{} starting at {}", SM::human_name(), print_state(&starting_state)?)?;
+	let mut state = starting_state;
+	for (step, (line, t)) in transitions.iter().enumerate() {
+		let rejection = SM::try_next_state(&state, t).err();
+		state = SM::next_state(&state, t);
+		let printed = print_state(&state)?;
+		match rejection {
+			None => writeln!(out, "step {} (line {}): {}", step + 1, line, printed)?,
+			Some(e) => writeln!(out, "step {} (line {}) rejected with {:?}: {}", step + 1, line, e, printed)?,
+		}
+	}
+	Ok(state)
+}
+
+/// Replay the journal stored in the given file, printing each step to standard output.
+pub fn replay_file<SM>(
+	starting_state: SM::State,
+	path: impl AsRef<std::path::Path>,
+) -> Result<SM::State, ReplayError>
+where
+	SM: SerdeStateMachine,
+{
+	let journal = std::io::BufReader::new(std::fs::File::open(path)?);
+	replay::<SM>(starting_state, journal, std::io::stdout().lock())
+}
+
+#[cfg(test)]
+use crate::c1_state_machine::{
+	p1_switches::LightSwitch,
+	p4_accounted_currency::{AccountedCurrency, AccountingTransaction},
+	User,
+};
+#[cfg(test)]
+use std::collections::HashMap;
+
+#[test]
+fn replay_prints_each_step() {
+	let journal = "# two toggles\nnull\n\nnull\n";
+	let mut out = Vec::new();
+
+	let end = replay::<LightSwitch>(false, journal.as_bytes(), &mut out).unwrap();
+
+	assert!(!end);
+	assert_eq!(
+		String::from_utf8(out).unwrap(),
+		"Unnamed state machine starting at false\nstep 1 (line 2): true\nstep 2 (line 4): false\n"
+	);
+}
+
+#[test]
+fn replay_marks_rejected_transitions() {
+	let journal = [
+		AccountingTransaction::Mint { minter: User::Alice, amount: 10 },
+		AccountingTransaction::Transfer { sender: User::Bob, receiver: User::Alice, amount: 5 },
+	]
+	.iter()
+	.map(|t| serde_json::to_string(t).unwrap())
+	.collect::<Vec<_>>()
+	.join("\n");
+	let mut out = Vec::new();
+
+	let end = replay::<AccountedCurrency>(HashMap::new(), journal.as_bytes(), &mut out).unwrap();
+
+	assert_eq!(end, HashMap::from([(User::Alice, 10)]));
+	let out = String::from_utf8(out).unwrap();
+	assert!(out.lines().nth(2).unwrap().starts_with("step 2 (line 2) rejected with UnknownAccount(Bob)"));
+}
+
+#[test]
+fn replay_prints_states_in_key_order() {
+	let journal = [User::Eve, User::Dave, User::Charlie, User::Bob, User::Alice]
+		.into_iter()
+		.map(|minter| serde_json::to_string(&AccountingTransaction::Mint { minter, amount: 1 }).unwrap())
+		.collect::<Vec<_>>()
+		.join("\n");
+	let mut out = Vec::new();
+
+	replay::<AccountedCurrency>(HashMap::new(), journal.as_bytes(), &mut out).unwrap();
+
+	let out = String::from_utf8(out).unwrap();
+	assert_eq!(
+		out.lines().last().unwrap(),
+		r#"step 5 (line 5): {"Alice":1,"Bob":1,"Charlie":1,"Dave":1,"Eve":1}"#
+	);
+}
+
+#[test]
+fn replay_reports_undecodable_line() {
+	let journal = "null\n{\"Toggle\": 1}\n";
+
+	let result = replay::<LightSwitch>(false, journal.as_bytes(), std::io::sink());
+
+	assert!(matches!(result, Err(ReplayError::Decode { line: 2, .. })));
+}
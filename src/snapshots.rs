@@ -0,0 +1,144 @@
+//! A snapshot is a copy of a state machine's state as it was after executing a particular block.
+//!
+//! Without snapshots, a node that wants to know the current state has no choice but to re-execute
+//! every block since genesis. With a snapshot, it can instead restore the state at some recent
+//! height and only execute the blocks that came after it.
+//!
+//! Snapshots are written to disk as JSON, so storing them requires the `serde` feature.
+
+use crate::hash;
+
+type Hash = u64;
+
+/// The state of a state machine after executing the block at the given height.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Snapshot<State> {
+	/// The height of the block after which this state was captured.
+	pub height: u64,
+	/// The hash of the header of that block. The first block executed on top of this snapshot
+	/// must have this as its parent.
+	pub block_hash: Hash,
+	/// The hash of the state. It is checked when the snapshot is restored to detect corruption.
+	pub state_root: Hash,
+	/// The captured state itself.
+	pub state: State,
+}
+
+/// The ways that storing or restoring a snapshot can fail.
+#[derive(Debug)]
+pub enum SnapshotError {
+	/// The snapshot file could not be read or written.
+	Io(std::io::Error),
+	/// The snapshot file could not be encoded or decoded.
+	#[cfg(feature = "serde")]
+	Codec(serde_json::Error),
+	/// The restored state does not hash to the state root recorded in the snapshot.
+	StateRootMismatch { expected: Hash, found: Hash },
+}
+
+impl From<std::io::Error> for SnapshotError {
+	fn from(e: std::io::Error) -> Self {
+		SnapshotError::Io(e)
+	}
+}
+
+#[cfg(feature = "serde")]
+impl From<serde_json::Error> for SnapshotError {
+	fn from(e: serde_json::Error) -> Self {
+		SnapshotError::Codec(e)
+	}
+}
+
+impl<State: core::hash::Hash> Snapshot<State> {
+	/// Capture the given state as it was after executing the block with the given height and hash.
+	pub fn capture(height: u64, block_hash: Hash, state: State) -> Self {
+		Snapshot {
+			height,
+			block_hash,
+			state_root: hash(&state),
+			state,
+		}
+	}
+
+	/// Check that the state still hashes to the state root recorded when it was captured.
+	pub fn check(&self) -> Result<(), SnapshotError> {
+		let found = hash(&self.state);
+		if found == self.state_root {
+			Ok(())
+		} else {
+			Err(SnapshotError::StateRootMismatch { expected: self.state_root, found })
+		}
+	}
+}
+
+#[cfg(feature = "serde")]
+impl<State> Snapshot<State>
+where
+	State: core::hash::Hash + serde::Serialize + serde::de::DeserializeOwned,
+{
+	/// Write this snapshot to the given path, replacing any file that is already there.
+	///
+	/// The snapshot is first written next to the destination and then renamed into place, so
+	/// a crash part way through never leaves a half-written snapshot behind.
+	pub fn write_to(&self, path: impl AsRef<std::path::Path>) -> Result<(), SnapshotError> {
+		let path = path.as_ref();
+		let mut tmp = path.as_os_str().to_owned();
+		tmp.push(".tmp");
+
+		std::fs::write(&tmp, serde_json::to_vec(self)?)?;
+		std::fs::rename(&tmp, path)?;
+		Ok(())
+	}
+
+	/// Restore a snapshot previously written with `write_to`.
+	pub fn read_from(path: impl AsRef<std::path::Path>) -> Result<Self, SnapshotError> {
+		let bytes = std::fs::read(path)?;
+		let snapshot: Self = serde_json::from_slice(&bytes)?;
+		snapshot.check()?;
+		Ok(snapshot)
+	}
+}
+
+#[cfg(all(test, feature = "serde"))]
+use crate::c1_state_machine::User;
+
+#[cfg(all(test, feature = "serde"))]
+fn temp_snapshot_path(name: &str) -> std::path::PathBuf {
+	std::env::temp_dir().join(format!("diy-blockchain-{}-{}.json", std::process::id(), name))
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn snap_write_and_restore() {
+	let path = temp_snapshot_path("restore");
+	let state = vec![(User::Alice, 10u64), (User::Bob, 3)];
+	let snapshot = Snapshot::capture(7, 1234, state);
+
+	snapshot.write_to(&path).unwrap();
+	let restored = Snapshot::<Vec<(User, u64)>>::read_from(&path).unwrap();
+	std::fs::remove_file(&path).unwrap();
+
+	assert_eq!(restored, snapshot);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn snap_restore_detects_corrupted_state() {
+	let path = temp_snapshot_path("corrupt");
+	let mut snapshot = Snapshot::capture(7, 1234, vec![1u64, 2, 3]);
+	snapshot.state.push(4);
+
+	snapshot.write_to(&path).unwrap();
+	let restored = Snapshot::<Vec<u64>>::read_from(&path);
+	std::fs::remove_file(&path).unwrap();
+
+	assert!(matches!(restored, Err(SnapshotError::StateRootMismatch { .. })));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn snap_restore_missing_file_fails() {
+	let restored = Snapshot::<Vec<u64>>::read_from(temp_snapshot_path("missing"));
+	assert!(matches!(restored, Err(SnapshotError::Io(_))));
+}